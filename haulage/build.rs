@@ -0,0 +1,7 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Only the servers are generated, since haulage calls neither service.
+    tonic_build::configure()
+        .build_client(false)
+        .compile(&["proto/control.proto", "proto/flows.proto"], &["proto"])?;
+    Ok(())
+}
@@ -0,0 +1,7 @@
+-- Remove DSCP remarking from access policies. Any rules already installed in
+-- the mangle table will be left in place until removed manually.
+ALTER TABLE "access_policies"
+DROP CONSTRAINT IF EXISTS "dscp_range";
+
+ALTER TABLE "access_policies"
+DROP COLUMN IF EXISTS "dscp";
@@ -0,0 +1,6 @@
+-- Add an optional DSCP value to access policies. When set, haulage remarks
+-- subscriber traffic with the value so that upstream shapers and WiFi WMM
+-- can honor the policy's service tier.
+ALTER TABLE "access_policies"
+ADD COLUMN "dscp" SMALLINT,
+ADD CONSTRAINT "dscp_range" CHECK ("dscp" >= 0 AND "dscp" <= 63);
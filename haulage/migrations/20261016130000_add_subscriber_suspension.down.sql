@@ -0,0 +1,12 @@
+-- Remove the administrative suspension state. Suspended subscribers will
+-- revert to their balance based policy.
+ALTER TABLE "subscribers"
+DROP CONSTRAINT IF EXISTS "fk_suspended_policy";
+
+ALTER TABLE "subscribers"
+DROP COLUMN IF EXISTS "suspended",
+DROP COLUMN IF EXISTS "suspended_policy";
+
+DELETE FROM "access_policies"
+WHERE "name"='Suspended'
+AND "id" NOT IN (SELECT "current_policy" FROM "subscribers");
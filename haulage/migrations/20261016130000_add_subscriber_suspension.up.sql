@@ -0,0 +1,34 @@
+-- Add an administrative suspension state for subscribers, separate from their
+-- balance. While suspended, the subscriber's suspended policy is applied
+-- regardless of their balance condition.
+INSERT INTO "access_policies"
+("name", "local_ul_policy_kind", "local_dl_policy_kind", "backhaul_ul_policy_kind", "backhaul_dl_policy_kind")
+VALUES
+('Suspended', 2, 2, 2, 2)
+ON CONFLICT ("name") DO NOTHING;
+
+ALTER TABLE "subscribers"
+ADD COLUMN "suspended" BOOLEAN NOT NULL DEFAULT false,
+ADD COLUMN "suspended_policy" INT;
+
+UPDATE "subscribers"
+SET "suspended_policy" = "access_policies"."id"
+FROM "access_policies"
+WHERE "access_policies"."name"='Suspended';
+
+-- External tools creating subscribers are not aware of the suspension
+-- policy, so default new subscribers to the Suspended policy.
+DO $$
+DECLARE suspended_policy_id INT;
+BEGIN
+  SELECT "id" INTO suspended_policy_id FROM "access_policies" WHERE "name"='Suspended';
+  EXECUTE format('ALTER TABLE "subscribers" ALTER COLUMN "suspended_policy" SET DEFAULT %s', suspended_policy_id);
+END $$;
+
+ALTER TABLE "subscribers"
+ALTER COLUMN "suspended_policy" SET NOT NULL;
+
+ALTER TABLE "subscribers"
+ADD CONSTRAINT "fk_suspended_policy"
+FOREIGN KEY ("suspended_policy")
+REFERENCES "access_policies" ("id");
@@ -0,0 +1,2 @@
+ALTER TABLE "subscribers"
+DROP COLUMN IF EXISTS "content_filter_categories";
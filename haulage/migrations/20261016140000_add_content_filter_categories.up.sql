@@ -0,0 +1,4 @@
+-- Add the set of content filter categories blocked for each subscriber. The
+-- domains in each category are configured in the haulage config file.
+ALTER TABLE "subscribers"
+ADD COLUMN "content_filter_categories" TEXT[] NOT NULL DEFAULT '{}';
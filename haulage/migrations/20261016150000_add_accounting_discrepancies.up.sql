@@ -0,0 +1,12 @@
+-- Record windows where the independent accounting sources disagree, as found
+-- by the periodic reconciliation job.
+CREATE TABLE "accounting_discrepancies" (
+  "id" SERIAL PRIMARY KEY,
+  "window_start" timestamptz NOT NULL,
+  "window_end" timestamptz NOT NULL,
+  "kind" TEXT NOT NULL,
+  "expected_bytes" bigint NOT NULL,
+  "measured_bytes" bigint NOT NULL
+);
+
+CREATE INDEX "accounting_discrepancies_window_end_idx" ON "accounting_discrepancies" ("window_end");
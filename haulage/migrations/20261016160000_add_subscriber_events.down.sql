@@ -0,0 +1,5 @@
+DROP TRIGGER IF EXISTS "subscriber_deleted_event" ON "subscribers";
+DROP TRIGGER IF EXISTS "subscriber_created_event" ON "subscribers";
+DROP FUNCTION IF EXISTS record_subscriber_deleted_event();
+DROP FUNCTION IF EXISTS record_subscriber_created_event();
+DROP TABLE IF EXISTS "subscriber_events";
@@ -0,0 +1,39 @@
+-- Add a change feed of subscriber lifecycle events. Events reference the
+-- subscriber by id and imsi without a foreign key so that they outlive the
+-- deletion of the subscriber.
+CREATE TABLE "subscriber_events" (
+  "id" BIGSERIAL PRIMARY KEY,
+  "time" timestamptz NOT NULL DEFAULT now(),
+  "subscriber" INT NOT NULL,
+  "imsi" TEXT NOT NULL,
+  "kind" TEXT NOT NULL,
+  "details" JSONB NOT NULL DEFAULT '{}'
+);
+
+CREATE INDEX "subscriber_events_subscriber_kind_idx" ON "subscriber_events" ("subscriber", "kind");
+
+-- Subscribers are created and deleted by external provisioning tools, so
+-- record those events with triggers rather than from haulage itself.
+CREATE FUNCTION record_subscriber_created_event() RETURNS trigger AS $$
+BEGIN
+  INSERT INTO "subscriber_events" ("subscriber", "imsi", "kind")
+  VALUES (NEW."internal_uid", NEW."imsi", 'created');
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE FUNCTION record_subscriber_deleted_event() RETURNS trigger AS $$
+BEGIN
+  INSERT INTO "subscriber_events" ("subscriber", "imsi", "kind")
+  VALUES (OLD."internal_uid", OLD."imsi", 'deleted');
+  RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER "subscriber_created_event"
+AFTER INSERT ON "subscribers"
+FOR EACH ROW EXECUTE PROCEDURE record_subscriber_created_event();
+
+CREATE TRIGGER "subscriber_deleted_event"
+AFTER DELETE ON "subscribers"
+FOR EACH ROW EXECUTE PROCEDURE record_subscriber_deleted_event();
@@ -0,0 +1,5 @@
+ALTER TABLE "subscriber_usage"
+  DROP COLUMN IF EXISTS "tcp_bytes",
+  DROP COLUMN IF EXISTS "udp_bytes",
+  DROP COLUMN IF EXISTS "icmp_bytes",
+  DROP COLUMN IF EXISTS "other_bytes";
@@ -0,0 +1,7 @@
+-- Break down each usage interval by transport protocol, counting the bytes in
+-- both directions. Existing rows predate the breakdown and are left at zero.
+ALTER TABLE "subscriber_usage"
+  ADD COLUMN "tcp_bytes" BIGINT NOT NULL DEFAULT 0,
+  ADD COLUMN "udp_bytes" BIGINT NOT NULL DEFAULT 0,
+  ADD COLUMN "icmp_bytes" BIGINT NOT NULL DEFAULT 0,
+  ADD COLUMN "other_bytes" BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,12 @@
+-- Remove the exemption window. Exempt subscribers will revert to their
+-- balance based policy.
+ALTER TABLE "subscribers"
+DROP CONSTRAINT IF EXISTS "fk_exempt_policy";
+
+ALTER TABLE "subscribers"
+DROP COLUMN IF EXISTS "exempt_until",
+DROP COLUMN IF EXISTS "exempt_policy";
+
+DELETE FROM "access_policies"
+WHERE "name"='Exempt'
+AND "id" NOT IN (SELECT "current_policy" FROM "subscribers");
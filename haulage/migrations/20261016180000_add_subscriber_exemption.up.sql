@@ -0,0 +1,33 @@
+-- Add a temporary exemption window for subscribers, e.g. to run a speed test
+-- during a support call. Until the window ends, the subscriber's exempt
+-- policy is applied regardless of their balance condition and usage is not
+-- debited from their balance. Administrative suspension takes precedence.
+INSERT INTO "access_policies"
+("name", "local_ul_policy_kind", "local_dl_policy_kind", "backhaul_ul_policy_kind", "backhaul_dl_policy_kind")
+VALUES
+('Exempt', 1, 1, 1, 1)
+ON CONFLICT ("name") DO NOTHING;
+
+ALTER TABLE "subscribers"
+ADD COLUMN "exempt_until" timestamptz,
+ADD COLUMN "exempt_policy" INT;
+
+UPDATE "subscribers"
+SET "exempt_policy" = "access_policies"."id"
+FROM "access_policies"
+WHERE "access_policies"."name"='Exempt';
+
+DO $$
+DECLARE exempt_policy_id INT;
+BEGIN
+  SELECT "id" INTO exempt_policy_id FROM "access_policies" WHERE "name"='Exempt';
+  EXECUTE format('ALTER TABLE "subscribers" ALTER COLUMN "exempt_policy" SET DEFAULT %s', exempt_policy_id);
+END $$;
+
+ALTER TABLE "subscribers"
+ALTER COLUMN "exempt_policy" SET NOT NULL;
+
+ALTER TABLE "subscribers"
+ADD CONSTRAINT "fk_exempt_policy"
+FOREIGN KEY ("exempt_policy")
+REFERENCES "access_policies" ("id");
@@ -0,0 +1,8 @@
+-- Remove balance pools. Members revert to drawing from their own balance.
+ALTER TABLE "subscribers"
+DROP CONSTRAINT IF EXISTS "fk_balance_pool";
+
+ALTER TABLE "subscribers"
+DROP COLUMN IF EXISTS "balance_pool";
+
+DROP TABLE IF EXISTS "balance_pools";
@@ -0,0 +1,19 @@
+-- Add balance pools shared by groups of subscribers, e.g. the devices of a
+-- school that purchases data together. Usage by members of a pool is debited
+-- from the pool rather than from their own balance, and members are subject
+-- to their zero balance policy once the pool is empty.
+CREATE TABLE "balance_pools" (
+  "id" INT GENERATED ALWAYS AS IDENTITY,
+  "name" varchar(100) UNIQUE NOT NULL,
+  "data_balance" BIGINT NOT NULL DEFAULT 0 CHECK ("data_balance" >= 0),
+  PRIMARY KEY ("id")
+);
+
+ALTER TABLE "subscribers"
+ADD COLUMN "balance_pool" INT;
+
+ALTER TABLE "subscribers"
+ADD CONSTRAINT "fk_balance_pool"
+FOREIGN KEY ("balance_pool")
+REFERENCES "balance_pools" ("id")
+ON DELETE SET NULL;
@@ -0,0 +1,9 @@
+-- Remove the subscriber journal along with its history.
+DROP TRIGGER IF EXISTS "balance_pool_journal_changes" ON "balance_pools";
+DROP TRIGGER IF EXISTS "subscriber_journal_changes" ON "subscribers";
+DROP FUNCTION IF EXISTS journal_balance_pool_changes;
+DROP FUNCTION IF EXISTS journal_subscriber_changes;
+DROP TABLE IF EXISTS "subscriber_journal";
+DROP FUNCTION IF EXISTS reject_subscriber_journal_modification;
+DROP FUNCTION IF EXISTS chain_subscriber_journal_entry;
+DROP FUNCTION IF EXISTS subscriber_journal_hash;
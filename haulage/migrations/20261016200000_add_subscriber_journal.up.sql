@@ -0,0 +1,145 @@
+-- Add an append-only journal of changes to subscriber balances and policy
+-- state, which can be replayed to reconstruct a subscriber's state at any
+-- past time for dispute resolution and audits. Each entry records only the
+-- columns that changed, and is chained to the previous entry by a hash so
+-- that later modification of the journal can be detected.
+CREATE TABLE "subscriber_journal" (
+  "id" BIGSERIAL PRIMARY KEY,
+  "time" timestamptz NOT NULL DEFAULT now(),
+  -- Entries describe either a subscriber or a balance pool.
+  "subscriber" INT,
+  "imsi" TEXT,
+  "balance_pool" INT,
+  "changes" JSONB NOT NULL,
+  "previous_hash" BYTEA,
+  "hash" BYTEA NOT NULL
+);
+
+CREATE INDEX "subscriber_journal_subscriber_idx" ON "subscriber_journal" ("subscriber", "id");
+CREATE INDEX "subscriber_journal_balance_pool_idx" ON "subscriber_journal" ("balance_pool", "id");
+
+-- The hash of an entry covers its contents and the hash of the entry before
+-- it. Verification recomputes hashes with the same function.
+CREATE FUNCTION subscriber_journal_hash(
+  previous_hash BYTEA,
+  id BIGINT,
+  "time" timestamptz,
+  subscriber INT,
+  imsi TEXT,
+  balance_pool INT,
+  changes JSONB
+) RETURNS BYTEA AS $$
+  SELECT sha256(
+    COALESCE(previous_hash, ''::bytea) ||
+    convert_to(concat_ws('|',
+      id,
+      (extract(epoch FROM "time") * 1000000)::bigint,
+      subscriber,
+      imsi,
+      balance_pool,
+      changes::text
+    ), 'UTF8')
+  );
+$$ LANGUAGE sql IMMUTABLE;
+
+-- Entries are chained in id order, so concurrent writers are serialized
+-- until commit.
+CREATE FUNCTION chain_subscriber_journal_entry() RETURNS trigger AS $$
+BEGIN
+  PERFORM pg_advisory_xact_lock(hashtext('subscriber_journal'));
+  NEW."id" := nextval(pg_get_serial_sequence('subscriber_journal', 'id'));
+  SELECT "hash" INTO NEW."previous_hash"
+  FROM "subscriber_journal"
+  ORDER BY "id" DESC
+  LIMIT 1;
+  NEW."hash" := subscriber_journal_hash(NEW."previous_hash", NEW."id", NEW."time", NEW."subscriber", NEW."imsi", NEW."balance_pool", NEW."changes");
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE FUNCTION reject_subscriber_journal_modification() RETURNS trigger AS $$
+BEGIN
+  RAISE EXCEPTION 'subscriber_journal is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER "subscriber_journal_chain"
+BEFORE INSERT ON "subscriber_journal"
+FOR EACH ROW EXECUTE PROCEDURE chain_subscriber_journal_entry();
+
+CREATE TRIGGER "subscriber_journal_append_only"
+BEFORE UPDATE OR DELETE ON "subscriber_journal"
+FOR EACH ROW EXECUTE PROCEDURE reject_subscriber_journal_modification();
+
+-- Balances are debited by haulage but topped up by external tools, so all
+-- changes are journaled by triggers rather than from haulage itself.
+CREATE FUNCTION journal_subscriber_changes() RETURNS trigger AS $$
+DECLARE
+  state JSONB;
+  previous_state JSONB := '{}';
+  changes JSONB;
+BEGIN
+  state := jsonb_build_object(
+    'data_balance', NEW."data_balance",
+    'current_policy', NEW."current_policy",
+    'suspended', NEW."suspended",
+    'exempt_until', NEW."exempt_until",
+    'balance_pool', NEW."balance_pool"
+  );
+  IF TG_OP = 'UPDATE' THEN
+    previous_state := jsonb_build_object(
+      'data_balance', OLD."data_balance",
+      'current_policy', OLD."current_policy",
+      'suspended', OLD."suspended",
+      'exempt_until', OLD."exempt_until",
+      'balance_pool', OLD."balance_pool"
+    );
+  END IF;
+
+  SELECT jsonb_object_agg(key, value) INTO changes
+  FROM jsonb_each(state)
+  WHERE TG_OP = 'INSERT' OR previous_state -> key IS DISTINCT FROM value;
+
+  IF changes IS NOT NULL THEN
+    INSERT INTO "subscriber_journal" ("subscriber", "imsi", "changes", "hash")
+    VALUES (NEW."internal_uid", NEW."imsi", changes, ''::bytea);
+  END IF;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE FUNCTION journal_balance_pool_changes() RETURNS trigger AS $$
+BEGIN
+  IF TG_OP = 'INSERT' OR OLD."data_balance" IS DISTINCT FROM NEW."data_balance" THEN
+    INSERT INTO "subscriber_journal" ("balance_pool", "changes", "hash")
+    VALUES (NEW."id", jsonb_build_object('data_balance', NEW."data_balance"), ''::bytea);
+  END IF;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER "subscriber_journal_changes"
+AFTER INSERT OR UPDATE ON "subscribers"
+FOR EACH ROW EXECUTE PROCEDURE journal_subscriber_changes();
+
+CREATE TRIGGER "balance_pool_journal_changes"
+AFTER INSERT OR UPDATE ON "balance_pools"
+FOR EACH ROW EXECUTE PROCEDURE journal_balance_pool_changes();
+
+-- Seed the journal with the current state of existing subscribers and pools,
+-- from which later changes are replayed.
+INSERT INTO "subscriber_journal" ("balance_pool", "changes", "hash")
+SELECT "id", jsonb_build_object('data_balance', "data_balance"), ''::bytea
+FROM "balance_pools"
+ORDER BY "id";
+
+INSERT INTO "subscriber_journal" ("subscriber", "imsi", "changes", "hash")
+SELECT "internal_uid", "imsi", jsonb_build_object(
+    'data_balance', "data_balance",
+    'current_policy', "current_policy",
+    'suspended', "suspended",
+    'exempt_until', "exempt_until",
+    'balance_pool', "balance_pool"
+  ), ''::bytea
+FROM "subscribers"
+ORDER BY "internal_uid";
@@ -0,0 +1,9 @@
+-- Remove report interval overrides. All subscribers revert to the configured
+-- userLogInterval once haulage is restarted.
+ALTER TABLE "subscribers"
+DROP CONSTRAINT IF EXISTS "report_interval_positive",
+DROP COLUMN IF EXISTS "report_interval";
+
+ALTER TABLE "access_policies"
+DROP CONSTRAINT IF EXISTS "report_interval_positive",
+DROP COLUMN IF EXISTS "report_interval";
@@ -0,0 +1,12 @@
+-- Add optional overrides of the usage report interval, per access policy for
+-- plans that warrant finer grained records and per subscriber, e.g. while an
+-- account is being actively debugged. The subscriber override takes
+-- precedence over the override of their positive balance policy, and
+-- subscribers without either use the configured userLogInterval.
+ALTER TABLE "access_policies"
+ADD COLUMN "report_interval" INTERVAL,
+ADD CONSTRAINT "report_interval_positive" CHECK ("report_interval" > INTERVAL '0');
+
+ALTER TABLE "subscribers"
+ADD COLUMN "report_interval" INTERVAL,
+ADD CONSTRAINT "report_interval_positive" CHECK ("report_interval" > INTERVAL '0');
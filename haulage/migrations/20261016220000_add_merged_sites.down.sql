@@ -0,0 +1,6 @@
+-- Remove the tables of usage and balances merged from other sites. Site
+-- databases are unaffected and can be merged again from scratch.
+DROP TABLE IF EXISTS "merged_balances";
+DROP TABLE IF EXISTS "merged_site_balances";
+DROP TABLE IF EXISTS "merged_subscriber_usage";
+DROP TABLE IF EXISTS "merged_sites";
@@ -0,0 +1,47 @@
+-- Add tables consolidating usage and balances merged from the databases of
+-- other sites by `haulage merge`, for operators backing many site networks
+-- from one central database. Merged subscribers are identified by IMSI, since
+-- internal ids are local to each site, and are independent of the
+-- subscribers table so that the central database need not run a gateway.
+CREATE TABLE "merged_sites" (
+  "name" TEXT PRIMARY KEY,
+  "usage_watermark" TIMESTAMPTZ,
+  "last_merge" TIMESTAMPTZ NOT NULL
+);
+
+CREATE TABLE "merged_subscriber_usage" (
+  "site" TEXT NOT NULL REFERENCES "merged_sites" ("name") ON DELETE CASCADE,
+  "imsi" VARCHAR(16) NOT NULL,
+  "start_time" TIMESTAMPTZ NOT NULL,
+  "end_time" TIMESTAMPTZ NOT NULL,
+  "ran_bytes_up" BIGINT NOT NULL,
+  "ran_bytes_down" BIGINT NOT NULL,
+  "wan_bytes_up" BIGINT NOT NULL,
+  "wan_bytes_down" BIGINT NOT NULL,
+  "tcp_bytes" BIGINT NOT NULL,
+  "udp_bytes" BIGINT NOT NULL,
+  "icmp_bytes" BIGINT NOT NULL,
+  "other_bytes" BIGINT NOT NULL,
+  PRIMARY KEY ("site", "imsi", "start_time")
+);
+
+CREATE INDEX "merged_subscriber_usage_imsi_idx" ON "merged_subscriber_usage" ("imsi", "start_time");
+
+-- The balance each site last reported for a subscriber, and when it last
+-- changed there.
+CREATE TABLE "merged_site_balances" (
+  "site" TEXT NOT NULL REFERENCES "merged_sites" ("name") ON DELETE CASCADE,
+  "imsi" VARCHAR(16) NOT NULL,
+  "data_balance" BIGINT NOT NULL,
+  "changed" TIMESTAMPTZ NOT NULL,
+  PRIMARY KEY ("site", "imsi")
+);
+
+-- The consolidated balance of each subscriber across all sites, resolved from
+-- the most recent change at any site.
+CREATE TABLE "merged_balances" (
+  "imsi" VARCHAR(16) PRIMARY KEY,
+  "data_balance" BIGINT NOT NULL,
+  "site" TEXT NOT NULL,
+  "changed" TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Remove the per-service usage breakdown. Interval usage records are kept.
+DROP TABLE IF EXISTS "subscriber_service_usage";
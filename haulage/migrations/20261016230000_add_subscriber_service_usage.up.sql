@@ -0,0 +1,15 @@
+-- Add a compact breakdown of each interval usage record by the service implied
+-- by the remote port, giving a view of the service mix without attributing
+-- traffic to domains. Bytes are counted on the RAN in both directions, and
+-- QUIC is UDP to port 443.
+CREATE TABLE "subscriber_service_usage" (
+  "subscriber" INT NOT NULL,
+  "start_time" TIMESTAMPTZ NOT NULL,
+  "https_bytes" BIGINT NOT NULL,
+  "http_bytes" BIGINT NOT NULL,
+  "dns_bytes" BIGINT NOT NULL,
+  "quic_bytes" BIGINT NOT NULL,
+  "other_bytes" BIGINT NOT NULL,
+  PRIMARY KEY ("subscriber", "start_time"),
+  CONSTRAINT "fk_subscriber" FOREIGN KEY ("subscriber") REFERENCES subscribers("internal_uid")
+);
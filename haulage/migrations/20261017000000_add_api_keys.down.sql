@@ -0,0 +1,3 @@
+-- Remove API keys. Authenticated APIs reject all requests until keys are
+-- created again.
+DROP TABLE IF EXISTS "api_keys";
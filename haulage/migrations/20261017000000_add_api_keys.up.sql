@@ -0,0 +1,13 @@
+-- Add keys authenticating requests to haulage's APIs, each granting one role.
+-- Read only keys can query usage and balances, cashier keys can additionally
+-- top up balances, and admin keys can perform any operation. Only a hash of
+-- each key is stored, so lost keys must be revoked and replaced.
+CREATE TABLE "api_keys" (
+  "id" INT GENERATED ALWAYS AS IDENTITY,
+  "name" varchar(100) UNIQUE NOT NULL,
+  "role" TEXT NOT NULL CHECK ("role" IN ('read_only', 'cashier', 'admin')),
+  "key_hash" TEXT UNIQUE NOT NULL,
+  "created" TIMESTAMPTZ NOT NULL DEFAULT now(),
+  "revoked" TIMESTAMPTZ,
+  PRIMARY KEY ("id")
+);
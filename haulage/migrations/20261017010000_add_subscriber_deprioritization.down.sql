@@ -0,0 +1,41 @@
+-- Remove subscriber deprioritization and stop journaling it.
+CREATE OR REPLACE FUNCTION journal_subscriber_changes() RETURNS trigger AS $$
+DECLARE
+  state JSONB;
+  previous_state JSONB := '{}';
+  changes JSONB;
+BEGIN
+  state := jsonb_build_object(
+    'data_balance', NEW."data_balance",
+    'current_policy', NEW."current_policy",
+    'suspended', NEW."suspended",
+    'exempt_until', NEW."exempt_until",
+    'balance_pool', NEW."balance_pool"
+  );
+  IF TG_OP = 'UPDATE' THEN
+    previous_state := jsonb_build_object(
+      'data_balance', OLD."data_balance",
+      'current_policy', OLD."current_policy",
+      'suspended', OLD."suspended",
+      'exempt_until', OLD."exempt_until",
+      'balance_pool', OLD."balance_pool"
+    );
+  END IF;
+
+  SELECT jsonb_object_agg(key, value) INTO changes
+  FROM jsonb_each(state)
+  WHERE TG_OP = 'INSERT' OR previous_state -> key IS DISTINCT FROM value;
+
+  IF changes IS NOT NULL THEN
+    INSERT INTO "subscriber_journal" ("subscriber", "imsi", "changes", "hash")
+    VALUES (NEW."internal_uid", NEW."imsi", changes, ''::bytea);
+  END IF;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+ALTER TABLE "subscribers"
+DROP CONSTRAINT IF EXISTS "fk_deprioritized_policy";
+
+ALTER TABLE "subscribers"
+DROP COLUMN IF EXISTS "deprioritized_policy";
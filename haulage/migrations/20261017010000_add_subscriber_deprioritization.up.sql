@@ -0,0 +1,50 @@
+-- Add a deprioritized policy for subscribers, set by haulage to move the
+-- heaviest users into a lower priority class while the backhaul is congested
+-- and cleared once congestion ends. While set, it replaces the subscriber's
+-- positive balance policy. Suspension and exemption take precedence.
+ALTER TABLE "subscribers"
+ADD COLUMN "deprioritized_policy" INT;
+
+ALTER TABLE "subscribers"
+ADD CONSTRAINT "fk_deprioritized_policy"
+FOREIGN KEY ("deprioritized_policy")
+REFERENCES "access_policies" ("id");
+
+-- Journal deprioritization along with the rest of the policy state, so that
+-- each transition can be audited.
+CREATE OR REPLACE FUNCTION journal_subscriber_changes() RETURNS trigger AS $$
+DECLARE
+  state JSONB;
+  previous_state JSONB := '{}';
+  changes JSONB;
+BEGIN
+  state := jsonb_build_object(
+    'data_balance', NEW."data_balance",
+    'current_policy', NEW."current_policy",
+    'suspended', NEW."suspended",
+    'exempt_until', NEW."exempt_until",
+    'balance_pool', NEW."balance_pool",
+    'deprioritized_policy', NEW."deprioritized_policy"
+  );
+  IF TG_OP = 'UPDATE' THEN
+    previous_state := jsonb_build_object(
+      'data_balance', OLD."data_balance",
+      'current_policy', OLD."current_policy",
+      'suspended', OLD."suspended",
+      'exempt_until', OLD."exempt_until",
+      'balance_pool', OLD."balance_pool",
+      'deprioritized_policy', OLD."deprioritized_policy"
+    );
+  END IF;
+
+  SELECT jsonb_object_agg(key, value) INTO changes
+  FROM jsonb_each(state)
+  WHERE TG_OP = 'INSERT' OR previous_state -> key IS DISTINCT FROM value;
+
+  IF changes IS NOT NULL THEN
+    INSERT INTO "subscriber_journal" ("subscriber", "imsi", "changes", "hash")
+    VALUES (NEW."internal_uid", NEW."imsi", changes, ''::bytea);
+  END IF;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
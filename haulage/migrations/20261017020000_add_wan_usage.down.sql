@@ -0,0 +1,2 @@
+-- Remove the per upstream interface usage records.
+DROP TABLE IF EXISTS "wan_usage";
@@ -0,0 +1,13 @@
+-- Record the traffic carried by each upstream interface per interval, so that
+-- deployments load balancing between several backhauls, e.g. satellite and
+-- LTE, can see how much each carried.
+CREATE TABLE "wan_usage" (
+  "id" BIGSERIAL PRIMARY KEY,
+  "interface" TEXT NOT NULL,
+  "start_time" timestamptz NOT NULL,
+  "end_time" timestamptz NOT NULL,
+  "bytes_up" bigint NOT NULL,
+  "bytes_down" bigint NOT NULL
+);
+
+CREATE INDEX "wan_usage_interface_start_time_idx" ON "wan_usage" ("interface", "start_time");
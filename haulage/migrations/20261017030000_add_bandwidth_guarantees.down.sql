@@ -0,0 +1,6 @@
+-- Remove bandwidth guarantee verification.
+DROP TABLE IF EXISTS "guarantee_checks";
+
+ALTER TABLE "access_policies"
+DROP CONSTRAINT IF EXISTS "guaranteed_kibps_positive",
+DROP COLUMN IF EXISTS "guaranteed_kibps";
@@ -0,0 +1,20 @@
+-- Add an optional guaranteed downlink rate to access policies, and a daily
+-- record of whether each subscriber on a guaranteed plan achieved it during
+-- the busy hours, so that operators can substantiate or refute complaints of
+-- poor service.
+ALTER TABLE "access_policies"
+ADD COLUMN "guaranteed_kibps" INT,
+ADD CONSTRAINT "guaranteed_kibps_positive" CHECK ("guaranteed_kibps" > 0);
+
+CREATE TABLE "guarantee_checks" (
+  "subscriber" INT NOT NULL,
+  "day" DATE NOT NULL,
+  "window_start" timestamptz NOT NULL,
+  "window_end" timestamptz NOT NULL,
+  "guaranteed_kibps" INT NOT NULL,
+  "peak_kibps" INT NOT NULL,
+  "busy_bytes" BIGINT NOT NULL,
+  "met" BOOLEAN NOT NULL,
+  PRIMARY KEY ("subscriber", "day"),
+  CONSTRAINT "fk_subscriber" FOREIGN KEY ("subscriber") REFERENCES subscribers("internal_uid") ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+-- Remove the record of dropped traffic. Refunded bytes remain credited.
+DROP TABLE IF EXISTS "subscriber_drops";
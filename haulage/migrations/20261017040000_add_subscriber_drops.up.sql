@@ -0,0 +1,18 @@
+-- Add a record of the traffic dropped by each subscriber's shaping queues, on
+-- the same intervals as subscriber_usage, so that operators can tell usage
+-- from traffic that was throttled away. The kernel only counts dropped
+-- packets, so the dropped bytes are estimated from the average size of the
+-- packets the queue sent. refunded_bytes holds any bytes credited back to the
+-- subscriber's balance for drops they were charged for.
+CREATE TABLE "subscriber_drops" (
+  "subscriber" INT NOT NULL,
+  "start_time" timestamptz NOT NULL,
+  "end_time" timestamptz NOT NULL,
+  "dropped_packets_up" BIGINT NOT NULL,
+  "dropped_bytes_up" BIGINT NOT NULL,
+  "dropped_packets_down" BIGINT NOT NULL,
+  "dropped_bytes_down" BIGINT NOT NULL,
+  "refunded_bytes" BIGINT NOT NULL DEFAULT 0,
+  PRIMARY KEY ("subscriber", "start_time"),
+  CONSTRAINT "fk_subscriber" FOREIGN KEY ("subscriber") REFERENCES subscribers("internal_uid")
+);
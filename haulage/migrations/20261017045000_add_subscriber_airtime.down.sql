@@ -0,0 +1,5 @@
+-- Remove the record of station airtime and the policy airtime weights.
+ALTER TABLE "access_policies"
+DROP CONSTRAINT IF EXISTS "airtime_weight_range",
+DROP COLUMN IF EXISTS "airtime_weight";
+DROP TABLE IF EXISTS "subscriber_airtime";
@@ -0,0 +1,23 @@
+-- Add a record of the bytes and airtime of each subscriber's WiFi stations, as
+-- reported by hostapd and the driver, on the same intervals as
+-- subscriber_usage. Airtime is the time the radio spent receiving from and
+-- transmitting to the station, which is the shared resource of a WiFi
+-- deployment rather than the bytes carried.
+CREATE TABLE "subscriber_airtime" (
+  "subscriber" INT NOT NULL,
+  "station" MACADDR NOT NULL,
+  "start_time" timestamptz NOT NULL,
+  "end_time" timestamptz NOT NULL,
+  "rx_bytes" BIGINT NOT NULL,
+  "tx_bytes" BIGINT NOT NULL,
+  "rx_airtime_us" BIGINT NOT NULL,
+  "tx_airtime_us" BIGINT NOT NULL,
+  PRIMARY KEY ("subscriber", "station", "start_time"),
+  CONSTRAINT "fk_subscriber" FOREIGN KEY ("subscriber") REFERENCES subscribers("internal_uid")
+);
+
+-- Add an optional airtime weight to access policies, applied to the stations
+-- of subscribers on the policy by the driver's airtime fairness scheduler.
+ALTER TABLE "access_policies"
+ADD COLUMN "airtime_weight" INT,
+ADD CONSTRAINT "airtime_weight_range" CHECK ("airtime_weight" > 0 AND "airtime_weight" <= 65535);
@@ -0,0 +1,3 @@
+-- Remove the NAT CPE device estimates and port breakdown.
+DROP TABLE IF EXISTS "subscriber_port_usage";
+DROP TABLE IF EXISTS "subscriber_devices";
@@ -0,0 +1,22 @@
+-- Add estimates of the devices behind subscribers' NAT CPE on the same
+-- intervals as subscriber_usage, along with an optional breakdown of their
+-- usage by blocks of the CPE's ports. port_block is the first port of each
+-- block.
+CREATE TABLE "subscriber_devices" (
+  "subscriber" INT NOT NULL,
+  "start_time" timestamptz NOT NULL,
+  "end_time" timestamptz NOT NULL,
+  "estimated_devices" INT NOT NULL,
+  PRIMARY KEY ("subscriber", "start_time"),
+  CONSTRAINT "fk_subscriber" FOREIGN KEY ("subscriber") REFERENCES subscribers("internal_uid")
+);
+
+CREATE TABLE "subscriber_port_usage" (
+  "subscriber" INT NOT NULL,
+  "start_time" timestamptz NOT NULL,
+  "port_block" INT NOT NULL,
+  "bytes_up" BIGINT NOT NULL,
+  "bytes_down" BIGINT NOT NULL,
+  PRIMARY KEY ("subscriber", "start_time", "port_block"),
+  CONSTRAINT "fk_subscriber" FOREIGN KEY ("subscriber") REFERENCES subscribers("internal_uid")
+);
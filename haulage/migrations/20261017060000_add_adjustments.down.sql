@@ -0,0 +1,6 @@
+-- Remove the usage and balance adjustments. Adjusted balances keep their
+-- adjusted values.
+DROP VIEW IF EXISTS "adjusted_subscriber_usage";
+DROP TABLE IF EXISTS "balance_adjustments";
+DROP TABLE IF EXISTS "usage_adjustments";
+DROP FUNCTION IF EXISTS reject_adjustment_modification;
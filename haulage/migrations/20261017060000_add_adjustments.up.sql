@@ -0,0 +1,60 @@
+-- Add append-only records of operator corrections to usage and balances, so
+-- that billing mistakes are fixed by signed adjustments alongside the original
+-- records rather than by editing them. Adjustments reference the subscriber by
+-- id and imsi without a foreign key so that they outlive the subscriber.
+CREATE TABLE "usage_adjustments" (
+  "id" BIGSERIAL PRIMARY KEY,
+  "time" timestamptz NOT NULL DEFAULT now(),
+  "subscriber" INT NOT NULL,
+  "imsi" TEXT NOT NULL,
+  "start_time" timestamptz NOT NULL,
+  "end_time" timestamptz NOT NULL,
+  "ran_bytes_up" BIGINT NOT NULL DEFAULT 0,
+  "ran_bytes_down" BIGINT NOT NULL DEFAULT 0,
+  "wan_bytes_up" BIGINT NOT NULL DEFAULT 0,
+  "wan_bytes_down" BIGINT NOT NULL DEFAULT 0,
+  "operator" TEXT NOT NULL,
+  "reason" TEXT NOT NULL CHECK ("reason" <> ''),
+  CHECK ("end_time" >= "start_time")
+);
+
+CREATE INDEX "usage_adjustments_subscriber_idx" ON "usage_adjustments" ("subscriber", "start_time");
+
+-- The balance adjusted is the balance of the subscriber's pool if they drew
+-- from one. balance_after is the balance once adjusted, which is floored at
+-- zero.
+CREATE TABLE "balance_adjustments" (
+  "id" BIGSERIAL PRIMARY KEY,
+  "time" timestamptz NOT NULL DEFAULT now(),
+  "subscriber" INT NOT NULL,
+  "imsi" TEXT NOT NULL,
+  "balance_pool" INT,
+  "bytes" BIGINT NOT NULL,
+  "balance_after" BIGINT NOT NULL,
+  "operator" TEXT NOT NULL,
+  "reason" TEXT NOT NULL CHECK ("reason" <> '')
+);
+
+CREATE INDEX "balance_adjustments_subscriber_idx" ON "balance_adjustments" ("subscriber", "id");
+
+CREATE FUNCTION reject_adjustment_modification() RETURNS trigger AS $$
+BEGIN
+  RAISE EXCEPTION '% is append-only', TG_TABLE_NAME;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER "usage_adjustments_append_only"
+BEFORE UPDATE OR DELETE ON "usage_adjustments"
+FOR EACH ROW EXECUTE PROCEDURE reject_adjustment_modification();
+
+CREATE TRIGGER "balance_adjustments_append_only"
+BEFORE UPDATE OR DELETE ON "balance_adjustments"
+FOR EACH ROW EXECUTE PROCEDURE reject_adjustment_modification();
+
+-- Usage as billed, with each adjustment as a record of its own.
+CREATE VIEW "adjusted_subscriber_usage" AS
+SELECT "subscriber", "start_time", "end_time", "ran_bytes_up", "ran_bytes_down", "wan_bytes_up", "wan_bytes_down", NULL::BIGINT AS "adjustment"
+FROM "subscriber_usage"
+UNION ALL
+SELECT "subscriber", "start_time", "end_time", "ran_bytes_up", "ran_bytes_down", "wan_bytes_up", "wan_bytes_down", "id" AS "adjustment"
+FROM "usage_adjustments";
@@ -0,0 +1,2 @@
+-- Remove the subscriber presence records.
+DROP TABLE IF EXISTS "subscriber_presence";
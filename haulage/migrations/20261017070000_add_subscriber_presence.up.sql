@@ -0,0 +1,14 @@
+-- Add when subscribers' devices were first and last seen within each interval
+-- of subscriber_usage, and whether by their ARP or neighbor discovery
+-- messages, their traffic, or both.
+CREATE TABLE "subscriber_presence" (
+  "subscriber" INT NOT NULL,
+  "start_time" timestamptz NOT NULL,
+  "end_time" timestamptz NOT NULL,
+  "first_seen" timestamptz NOT NULL,
+  "last_seen" timestamptz NOT NULL,
+  "by_neighbor" BOOLEAN NOT NULL,
+  "by_traffic" BOOLEAN NOT NULL,
+  PRIMARY KEY ("subscriber", "start_time"),
+  CONSTRAINT "fk_subscriber" FOREIGN KEY ("subscriber") REFERENCES subscribers("internal_uid")
+);
@@ -0,0 +1,4 @@
+-- Remove DNS redirection from access policies. Any rules already installed in
+-- the nat and filter tables will be left in place until removed manually.
+ALTER TABLE "access_policies"
+DROP COLUMN IF EXISTS "dns_redirect";
@@ -0,0 +1,5 @@
+-- Add an optional DNS resolver to access policies. When set, haulage redirects
+-- subscribers' DNS queries to the resolver and rejects DNS over TLS, so that
+-- the subsystems relying on DNS answers see every lookup.
+ALTER TABLE "access_policies"
+ADD COLUMN "dns_redirect" INET;
@@ -0,0 +1,2 @@
+-- Remove the flow usage records.
+DROP TABLE IF EXISTS "flow_logs";
@@ -0,0 +1,15 @@
+-- Add the usage of individual flows, recorded when flows are accounted
+-- without a ClickHouse export.
+CREATE TABLE "flow_logs" (
+  "start_time" timestamptz NOT NULL,
+  "end_time" timestamptz NOT NULL,
+  "user_addr" inet NOT NULL,
+  "remote_addr" inet NOT NULL,
+  "user_port" INT NOT NULL,
+  "remote_port" INT NOT NULL,
+  "protocol" SMALLINT NOT NULL,
+  "bytes_up" BIGINT NOT NULL,
+  "bytes_down" BIGINT NOT NULL,
+  "packets" BIGINT NOT NULL
+);
+CREATE INDEX "flow_logs_user_addr_start_time" ON "flow_logs" ("user_addr", "start_time");
@@ -0,0 +1,2 @@
+-- Remove the top destination records.
+DROP TABLE IF EXISTS "destination_usage";
@@ -0,0 +1,10 @@
+-- Add the remote hosts carrying the most backhaul traffic in each interval,
+-- across all subscribers. Only the top destinations of each interval are kept.
+CREATE TABLE "destination_usage" (
+  "destination" INET NOT NULL,
+  "start_time" timestamptz NOT NULL,
+  "end_time" timestamptz NOT NULL,
+  "bytes_up" BIGINT NOT NULL,
+  "bytes_down" BIGINT NOT NULL
+);
+CREATE INDEX "destination_usage_end_time" ON "destination_usage" ("end_time");
@@ -0,0 +1,5 @@
+-- Fold the voice and video calling bytes back into the other service bytes.
+UPDATE "subscriber_service_usage"
+SET "other_bytes" = "other_bytes" + "voip_bytes";
+ALTER TABLE "subscriber_service_usage"
+DROP COLUMN IF EXISTS "voip_bytes";
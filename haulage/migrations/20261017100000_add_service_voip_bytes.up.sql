@@ -0,0 +1,4 @@
+-- Break out voice and video calling from the other service bytes, counted by
+-- the SIP, IAX2, and STUN/TURN ports. Earlier records have none.
+ALTER TABLE "subscriber_service_usage"
+ADD COLUMN "voip_bytes" BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- Remove the subscriber usage forecasts.
+DROP TABLE IF EXISTS "subscriber_forecasts";
@@ -0,0 +1,9 @@
+-- Add the forecast of how many days each subscriber's balance lasts at their
+-- smoothed daily usage.
+CREATE TABLE "subscriber_forecasts" (
+  "subscriber" INT PRIMARY KEY,
+  "computed_at" timestamptz NOT NULL,
+  "daily_bytes" BIGINT NOT NULL,
+  "days_remaining" DOUBLE PRECISION,
+  CONSTRAINT "fk_subscriber" FOREIGN KEY ("subscriber") REFERENCES subscribers("internal_uid") ON DELETE CASCADE
+);
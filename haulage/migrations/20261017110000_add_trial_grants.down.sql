@@ -0,0 +1,2 @@
+-- Remove the trial grants. Trial subscribers are kept as ordinary subscribers.
+DROP TABLE IF EXISTS "trial_grants";
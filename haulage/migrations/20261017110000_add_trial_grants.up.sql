@@ -0,0 +1,17 @@
+-- Add the trials granted to unknown devices, identified by their link layer
+-- address when known and their IP address otherwise, so that each device is
+-- only granted a trial allowance once. Grants are kept after their subscriber
+-- is deleted, e.g. when replaced by a registered subscriber.
+CREATE TABLE "trial_grants" (
+  "id" INT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  "imsi" VARCHAR(16),
+  "ip" INET NOT NULL,
+  "mac" MACADDR,
+  "allowance" BIGINT NOT NULL,
+  "granted" timestamptz NOT NULL DEFAULT now(),
+  "expires" timestamptz NOT NULL,
+  "ended" BOOLEAN NOT NULL DEFAULT false,
+  CONSTRAINT "fk_imsi" FOREIGN KEY ("imsi") REFERENCES subscribers("imsi") ON DELETE SET NULL
+);
+CREATE INDEX "trial_grants_ip" ON "trial_grants" ("ip");
+CREATE INDEX "trial_grants_mac" ON "trial_grants" ("mac");
@@ -0,0 +1,7 @@
+-- Remove the tables of exported and imported bundles. Sites export from
+-- scratch afterwards, and credits already applied at a site may be applied
+-- again if imported again.
+DROP TABLE IF EXISTS "imported_credits";
+DROP TABLE IF EXISTS "bundled_credits";
+DROP TABLE IF EXISTS "merged_subscriber_events";
+DROP TABLE IF EXISTS "bundle_exports";
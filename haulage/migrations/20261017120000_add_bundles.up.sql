@@ -0,0 +1,41 @@
+-- Add tables for exchanging accounting data with sites without any network
+-- connection to the central database, by carrying signed bundles between
+-- them. Sites record what they have exported so that each bundle picks up
+-- where the last one left off.
+CREATE TABLE "bundle_exports" (
+  "id" INT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  "created" TIMESTAMPTZ NOT NULL DEFAULT now(),
+  "usage_watermark" TIMESTAMPTZ,
+  "event_watermark" BIGINT
+);
+
+-- Subscriber events imported from site bundles, identified by their id at the
+-- site so that importing a bundle again adds nothing.
+CREATE TABLE "merged_subscriber_events" (
+  "site" TEXT NOT NULL REFERENCES "merged_sites" ("name") ON DELETE CASCADE,
+  "id" BIGINT NOT NULL,
+  "time" TIMESTAMPTZ NOT NULL,
+  "imsi" TEXT NOT NULL,
+  "kind" TEXT NOT NULL,
+  "details" JSONB NOT NULL,
+  PRIMARY KEY ("site", "id")
+);
+
+CREATE INDEX "merged_subscriber_events_imsi_idx" ON "merged_subscriber_events" ("imsi", "time");
+
+-- The site each central balance adjustment was bundled for, so that a credit
+-- reaches only one site even if the subscriber moves between sites.
+CREATE TABLE "bundled_credits" (
+  "adjustment" BIGINT PRIMARY KEY REFERENCES "balance_adjustments" ("id"),
+  "site" TEXT NOT NULL,
+  "exported" TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Central balance adjustments applied at this site, by their central id, with
+-- the site adjustment applying each. Credits already included in the balance
+-- of a subscriber new to the site are recorded without an adjustment.
+CREATE TABLE "imported_credits" (
+  "id" BIGINT PRIMARY KEY,
+  "adjustment" BIGINT REFERENCES "balance_adjustments" ("id"),
+  "imported" TIMESTAMPTZ NOT NULL DEFAULT now()
+);
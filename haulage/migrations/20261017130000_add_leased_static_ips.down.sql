@@ -0,0 +1,2 @@
+DELETE FROM "static_ips" WHERE "leased";
+ALTER TABLE "static_ips" DROP COLUMN "leased";
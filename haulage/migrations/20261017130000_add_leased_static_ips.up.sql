@@ -0,0 +1,3 @@
+-- Mark the addresses assigned from DHCP leases, which the lease tracker adds
+-- and removes as leases change, apart from those assigned by operators.
+ALTER TABLE "static_ips" ADD COLUMN "leased" BOOLEAN NOT NULL DEFAULT false;
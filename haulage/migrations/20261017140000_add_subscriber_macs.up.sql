@@ -0,0 +1,9 @@
+-- Add the link layer addresses of subscriber devices, by which subscribers are
+-- identified when their IP addresses rotate, e.g. on Wi-Fi hotspots. Each
+-- device belongs to at most one subscriber.
+CREATE TABLE "subscriber_macs" (
+  "mac" MACADDR PRIMARY KEY,
+  "imsi" VARCHAR(16) NOT NULL,
+  CONSTRAINT "fk_imsi" FOREIGN KEY ("imsi") REFERENCES subscribers("imsi") ON DELETE CASCADE
+);
+CREATE INDEX "subscriber_macs_imsi" ON "subscriber_macs" ("imsi");
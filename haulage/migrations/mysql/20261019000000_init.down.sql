@@ -0,0 +1,9 @@
+DROP TABLE `subscriber_events`;
+DROP TABLE `destination_usage`;
+DROP TABLE `subscriber_service_usage`;
+DROP TABLE `subscriber_usage`;
+DROP TABLE `static_ips`;
+DROP TABLE `subscribers`;
+DROP TABLE `balance_pools`;
+DROP TABLE `access_policies`;
+DROP TABLE `link_policy_kinds`;
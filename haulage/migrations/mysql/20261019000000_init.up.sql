@@ -0,0 +1,159 @@
+-- The schema used by metering, accounting, and enforcement when running on a
+-- MySQL database rather than Postgres. Like the SQLite schema, it is created
+-- whole and leaves out the tables of the subsystems which require Postgres.
+
+-- Addresses are stored as text in CIDR notation, and times in UTC. Expression
+-- defaults and enforced checks require MySQL 8.0.16 or later.
+
+CREATE TABLE `link_policy_kinds` (
+  `id` INT PRIMARY KEY,
+  `name` VARCHAR(64) UNIQUE NOT NULL
+);
+INSERT INTO `link_policy_kinds` (`id`, `name`)
+VALUES
+(1, 'unlimited'),
+(2, 'block'),
+(3, 'token_bucket');
+
+CREATE TABLE `access_policies` (
+  `id` INT AUTO_INCREMENT PRIMARY KEY,
+  `name` VARCHAR(255) UNIQUE NOT NULL,
+  `local_ul_policy_kind` INT NOT NULL DEFAULT 1,
+  `local_ul_policy_parameters` JSON NOT NULL DEFAULT ('{}'),
+  `local_dl_policy_kind` INT NOT NULL DEFAULT 1,
+  `local_dl_policy_parameters` JSON NOT NULL DEFAULT ('{}'),
+  `backhaul_ul_policy_kind` INT NOT NULL DEFAULT 1,
+  `backhaul_ul_policy_parameters` JSON NOT NULL DEFAULT ('{}'),
+  `backhaul_dl_policy_kind` INT NOT NULL DEFAULT 1,
+  `backhaul_dl_policy_parameters` JSON NOT NULL DEFAULT ('{}'),
+  `dscp` SMALLINT CHECK (`dscp` >= 0 AND `dscp` <= 63),
+  `guaranteed_kibps` INT CHECK (`guaranteed_kibps` > 0),
+  `dns_redirect` VARCHAR(43),
+  -- In seconds.
+  `report_interval` INT CHECK (`report_interval` > 0),
+  FOREIGN KEY (`local_ul_policy_kind`) REFERENCES `link_policy_kinds` (`id`),
+  FOREIGN KEY (`local_dl_policy_kind`) REFERENCES `link_policy_kinds` (`id`),
+  FOREIGN KEY (`backhaul_ul_policy_kind`) REFERENCES `link_policy_kinds` (`id`),
+  FOREIGN KEY (`backhaul_dl_policy_kind`) REFERENCES `link_policy_kinds` (`id`)
+);
+
+-- The ids of these policies are the defaults of the subscriber policy
+-- columns below.
+INSERT INTO `access_policies`
+(`id`, `name`, `local_ul_policy_kind`, `local_dl_policy_kind`, `backhaul_ul_policy_kind`, `backhaul_dl_policy_kind`)
+VALUES
+(1, 'Unlimited', 1, 1, 1, 1),
+(2, 'Local Only', 1, 1, 2, 2);
+
+INSERT INTO `access_policies`
+(`id`, `name`, `local_ul_policy_kind`, `local_dl_policy_kind`,
+ `backhaul_ul_policy_kind`, `backhaul_dl_policy_kind`, `backhaul_ul_policy_parameters`, `backhaul_dl_policy_parameters`)
+VALUES
+(3, 'Limited Backhaul', 1, 1,
+ 3, 3, '{"rate_kibps": 100}', '{"rate_kibps": 100}');
+
+INSERT INTO `access_policies`
+(`id`, `name`, `local_ul_policy_kind`, `local_dl_policy_kind`, `backhaul_ul_policy_kind`, `backhaul_dl_policy_kind`)
+VALUES
+(4, 'Suspended', 2, 2, 2, 2),
+(5, 'Exempt', 1, 1, 1, 1);
+
+CREATE TABLE `balance_pools` (
+  `id` INT AUTO_INCREMENT PRIMARY KEY,
+  `name` VARCHAR(255) UNIQUE NOT NULL,
+  `data_balance` BIGINT NOT NULL DEFAULT 0 CHECK (`data_balance` >= 0)
+);
+
+CREATE TABLE `subscribers` (
+  `internal_uid` INT AUTO_INCREMENT PRIMARY KEY,
+  `imsi` VARCHAR(16) UNIQUE NOT NULL,
+  `data_balance` BIGINT DEFAULT 10000000,
+  `positive_balance_policy` INT NOT NULL DEFAULT 1,
+  `zero_balance_policy` INT NOT NULL DEFAULT 2,
+  `current_policy` INT NOT NULL DEFAULT 2,
+  `suspended` BOOLEAN NOT NULL DEFAULT FALSE,
+  `suspended_policy` INT NOT NULL DEFAULT 4,
+  `exempt_until` DATETIME(6),
+  `exempt_policy` INT NOT NULL DEFAULT 5,
+  `deprioritized_policy` INT,
+  `balance_pool` INT,
+  -- In seconds.
+  `report_interval` INT CHECK (`report_interval` > 0),
+  FOREIGN KEY (`positive_balance_policy`) REFERENCES `access_policies` (`id`),
+  FOREIGN KEY (`zero_balance_policy`) REFERENCES `access_policies` (`id`),
+  FOREIGN KEY (`current_policy`) REFERENCES `access_policies` (`id`),
+  FOREIGN KEY (`suspended_policy`) REFERENCES `access_policies` (`id`),
+  FOREIGN KEY (`exempt_policy`) REFERENCES `access_policies` (`id`),
+  FOREIGN KEY (`deprioritized_policy`) REFERENCES `access_policies` (`id`),
+  FOREIGN KEY (`balance_pool`) REFERENCES `balance_pools` (`id`) ON DELETE SET NULL
+);
+
+CREATE TABLE `static_ips` (
+  `ip` VARCHAR(43) PRIMARY KEY,
+  `imsi` VARCHAR(16) NOT NULL,
+  `leased` BOOLEAN NOT NULL DEFAULT FALSE,
+  FOREIGN KEY (`imsi`) REFERENCES `subscribers` (`imsi`)
+);
+
+CREATE TABLE `subscriber_usage` (
+  `subscriber` INT NOT NULL,
+  `start_time` DATETIME(6) NOT NULL,
+  `end_time` DATETIME(6) NOT NULL,
+  `ran_bytes_up` BIGINT NOT NULL,
+  `ran_bytes_down` BIGINT NOT NULL,
+  `wan_bytes_up` BIGINT NOT NULL,
+  `wan_bytes_down` BIGINT NOT NULL,
+  `tcp_bytes` BIGINT NOT NULL DEFAULT 0,
+  `udp_bytes` BIGINT NOT NULL DEFAULT 0,
+  `icmp_bytes` BIGINT NOT NULL DEFAULT 0,
+  `other_bytes` BIGINT NOT NULL DEFAULT 0,
+  PRIMARY KEY (`subscriber`, `start_time`),
+  FOREIGN KEY (`subscriber`) REFERENCES `subscribers` (`internal_uid`)
+);
+
+CREATE TABLE `subscriber_service_usage` (
+  `subscriber` INT NOT NULL,
+  `start_time` DATETIME(6) NOT NULL,
+  `https_bytes` BIGINT NOT NULL,
+  `http_bytes` BIGINT NOT NULL,
+  `dns_bytes` BIGINT NOT NULL,
+  `quic_bytes` BIGINT NOT NULL,
+  `voip_bytes` BIGINT NOT NULL DEFAULT 0,
+  `other_bytes` BIGINT NOT NULL,
+  PRIMARY KEY (`subscriber`, `start_time`),
+  FOREIGN KEY (`subscriber`) REFERENCES `subscribers` (`internal_uid`)
+);
+
+CREATE TABLE `destination_usage` (
+  `id` BIGINT AUTO_INCREMENT PRIMARY KEY,
+  `destination` VARCHAR(39) NOT NULL,
+  `start_time` DATETIME(6) NOT NULL,
+  `end_time` DATETIME(6) NOT NULL,
+  `bytes_up` BIGINT NOT NULL,
+  `bytes_down` BIGINT NOT NULL,
+  INDEX `destination_usage_end_time` (`end_time`)
+);
+
+-- Events reference the subscriber without a foreign key so that they outlive
+-- the deletion of the subscriber.
+CREATE TABLE `subscriber_events` (
+  `id` BIGINT AUTO_INCREMENT PRIMARY KEY,
+  `time` DATETIME(6) NOT NULL DEFAULT (UTC_TIMESTAMP(6)),
+  `subscriber` INT NOT NULL,
+  `imsi` VARCHAR(16) NOT NULL,
+  `kind` VARCHAR(64) NOT NULL,
+  `details` JSON NOT NULL DEFAULT ('{}'),
+  INDEX `subscriber_events_subscriber_kind_idx` (`subscriber`, `kind`)
+);
+
+CREATE TRIGGER `subscriber_created_event`
+AFTER INSERT ON `subscribers`
+FOR EACH ROW
+INSERT INTO `subscriber_events` (`subscriber`, `imsi`, `kind`)
+VALUES (NEW.`internal_uid`, NEW.`imsi`, 'created');
+
+CREATE TRIGGER `subscriber_deleted_event`
+AFTER DELETE ON `subscribers`
+FOR EACH ROW
+INSERT INTO `subscriber_events` (`subscriber`, `imsi`, `kind`)
+VALUES (OLD.`internal_uid`, OLD.`imsi`, 'deleted');
@@ -0,0 +1,147 @@
+-- The schema used by metering, accounting, and enforcement when running on a
+-- local SQLite database rather than Postgres. It is created whole rather than
+-- following the history of the Postgres migrations, and leaves out the tables
+-- of the subsystems which require Postgres.
+
+-- Addresses are stored as text in CIDR notation, and times as UTC text in the
+-- form written by sqlx, which orders the same as the times themselves.
+
+CREATE TABLE "link_policy_kinds" (
+  "id" INTEGER PRIMARY KEY,
+  "name" TEXT UNIQUE NOT NULL
+);
+INSERT INTO "link_policy_kinds" ("id", "name")
+VALUES
+(1, 'unlimited'),
+(2, 'block'),
+(3, 'token_bucket');
+
+CREATE TABLE "access_policies" (
+  "id" INTEGER PRIMARY KEY,
+  "name" TEXT UNIQUE NOT NULL,
+  "local_ul_policy_kind" INTEGER NOT NULL DEFAULT 1 REFERENCES "link_policy_kinds" ("id"),
+  "local_ul_policy_parameters" TEXT NOT NULL DEFAULT '{}',
+  "local_dl_policy_kind" INTEGER NOT NULL DEFAULT 1 REFERENCES "link_policy_kinds" ("id"),
+  "local_dl_policy_parameters" TEXT NOT NULL DEFAULT '{}',
+  "backhaul_ul_policy_kind" INTEGER NOT NULL DEFAULT 1 REFERENCES "link_policy_kinds" ("id"),
+  "backhaul_ul_policy_parameters" TEXT NOT NULL DEFAULT '{}',
+  "backhaul_dl_policy_kind" INTEGER NOT NULL DEFAULT 1 REFERENCES "link_policy_kinds" ("id"),
+  "backhaul_dl_policy_parameters" TEXT NOT NULL DEFAULT '{}',
+  "dscp" INTEGER CHECK ("dscp" >= 0 AND "dscp" <= 63),
+  "guaranteed_kibps" INTEGER CHECK ("guaranteed_kibps" > 0),
+  "dns_redirect" TEXT,
+  -- In seconds.
+  "report_interval" INTEGER CHECK ("report_interval" > 0)
+);
+
+-- The ids of these policies are the defaults of the subscriber policy
+-- columns below.
+INSERT INTO "access_policies"
+("id", "name", "local_ul_policy_kind", "local_dl_policy_kind", "backhaul_ul_policy_kind", "backhaul_dl_policy_kind")
+VALUES
+(1, 'Unlimited', 1, 1, 1, 1),
+(2, 'Local Only', 1, 1, 2, 2);
+
+INSERT INTO "access_policies"
+("id", "name", "local_ul_policy_kind", "local_dl_policy_kind",
+ "backhaul_ul_policy_kind", "backhaul_dl_policy_kind", "backhaul_ul_policy_parameters", "backhaul_dl_policy_parameters")
+VALUES
+(3, 'Limited Backhaul', 1, 1,
+ 3, 3, '{"rate_kibps": 100}', '{"rate_kibps": 100}');
+
+INSERT INTO "access_policies"
+("id", "name", "local_ul_policy_kind", "local_dl_policy_kind", "backhaul_ul_policy_kind", "backhaul_dl_policy_kind")
+VALUES
+(4, 'Suspended', 2, 2, 2, 2),
+(5, 'Exempt', 1, 1, 1, 1);
+
+CREATE TABLE "balance_pools" (
+  "id" INTEGER PRIMARY KEY,
+  "name" TEXT UNIQUE NOT NULL,
+  "data_balance" INTEGER NOT NULL DEFAULT 0 CHECK ("data_balance" >= 0)
+);
+
+CREATE TABLE "subscribers" (
+  "internal_uid" INTEGER PRIMARY KEY,
+  "imsi" TEXT UNIQUE NOT NULL,
+  "data_balance" INTEGER DEFAULT 10000000,
+  "positive_balance_policy" INTEGER NOT NULL DEFAULT 1 REFERENCES "access_policies" ("id"),
+  "zero_balance_policy" INTEGER NOT NULL DEFAULT 2 REFERENCES "access_policies" ("id"),
+  "current_policy" INTEGER NOT NULL DEFAULT 2 REFERENCES "access_policies" ("id"),
+  "suspended" BOOLEAN NOT NULL DEFAULT 0,
+  "suspended_policy" INTEGER NOT NULL DEFAULT 4 REFERENCES "access_policies" ("id"),
+  "exempt_until" TEXT,
+  "exempt_policy" INTEGER NOT NULL DEFAULT 5 REFERENCES "access_policies" ("id"),
+  "deprioritized_policy" INTEGER REFERENCES "access_policies" ("id"),
+  "balance_pool" INTEGER REFERENCES "balance_pools" ("id") ON DELETE SET NULL,
+  -- In seconds.
+  "report_interval" INTEGER CHECK ("report_interval" > 0)
+);
+
+CREATE TABLE "static_ips" (
+  "ip" TEXT PRIMARY KEY,
+  "imsi" TEXT NOT NULL REFERENCES "subscribers" ("imsi"),
+  "leased" BOOLEAN NOT NULL DEFAULT 0
+);
+
+CREATE TABLE "subscriber_usage" (
+  "subscriber" INTEGER NOT NULL REFERENCES "subscribers" ("internal_uid"),
+  "start_time" TEXT NOT NULL,
+  "end_time" TEXT NOT NULL,
+  "ran_bytes_up" INTEGER NOT NULL,
+  "ran_bytes_down" INTEGER NOT NULL,
+  "wan_bytes_up" INTEGER NOT NULL,
+  "wan_bytes_down" INTEGER NOT NULL,
+  "tcp_bytes" INTEGER NOT NULL DEFAULT 0,
+  "udp_bytes" INTEGER NOT NULL DEFAULT 0,
+  "icmp_bytes" INTEGER NOT NULL DEFAULT 0,
+  "other_bytes" INTEGER NOT NULL DEFAULT 0,
+  PRIMARY KEY ("subscriber", "start_time")
+);
+
+CREATE TABLE "subscriber_service_usage" (
+  "subscriber" INTEGER NOT NULL REFERENCES "subscribers" ("internal_uid"),
+  "start_time" TEXT NOT NULL,
+  "https_bytes" INTEGER NOT NULL,
+  "http_bytes" INTEGER NOT NULL,
+  "dns_bytes" INTEGER NOT NULL,
+  "quic_bytes" INTEGER NOT NULL,
+  "voip_bytes" INTEGER NOT NULL DEFAULT 0,
+  "other_bytes" INTEGER NOT NULL,
+  PRIMARY KEY ("subscriber", "start_time")
+);
+
+CREATE TABLE "destination_usage" (
+  "destination" TEXT NOT NULL,
+  "start_time" TEXT NOT NULL,
+  "end_time" TEXT NOT NULL,
+  "bytes_up" INTEGER NOT NULL,
+  "bytes_down" INTEGER NOT NULL
+);
+CREATE INDEX "destination_usage_end_time" ON "destination_usage" ("end_time");
+
+-- Events reference the subscriber without a foreign key so that they outlive
+-- the deletion of the subscriber.
+CREATE TABLE "subscriber_events" (
+  "id" INTEGER PRIMARY KEY,
+  "time" TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  "subscriber" INTEGER NOT NULL,
+  "imsi" TEXT NOT NULL,
+  "kind" TEXT NOT NULL,
+  "details" TEXT NOT NULL DEFAULT '{}'
+);
+CREATE INDEX "subscriber_events_subscriber_kind_idx" ON "subscriber_events" ("subscriber", "kind");
+
+CREATE TRIGGER "subscriber_created_event"
+AFTER INSERT ON "subscribers"
+BEGIN
+  INSERT INTO "subscriber_events" ("subscriber", "imsi", "kind")
+  VALUES (NEW."internal_uid", NEW."imsi", 'created');
+END;
+
+CREATE TRIGGER "subscriber_deleted_event"
+AFTER DELETE ON "subscribers"
+BEGIN
+  INSERT INTO "subscriber_events" ("subscriber", "imsi", "kind")
+  VALUES (OLD."internal_uid", OLD."imsi", 'deleted');
+END;
@@ -9,13 +9,13 @@ pub struct UserAccounter {
 impl UserAccounter {
     pub fn new(
         period: std::time::Duration,
-        db_pool: std::sync::Arc<sqlx::PgPool>,
-        enforcer: std::sync::Arc<crate::enforcer::Iptables>,
+        context: AccounterContext,
+        classifier: crate::charging::Classifier,
         log: slog::Logger,
     ) -> UserAccounter {
         let (sender, receiver) = tokio::sync::mpsc::channel(64);
         tokio::task::spawn(async move {
-            accounting_task_dispatcher(receiver, period, db_pool, enforcer, log).await;
+            accounting_task_dispatcher(receiver, period, context, classifier, log).await;
         });
         UserAccounter {
             dispatch_channel: sender,
@@ -27,38 +27,90 @@ impl UserAccounter {
 }
 
 pub enum Message {
-    Report { ip: std::net::IpAddr, amount: u64 },
+    // The bytes a subscriber exchanged with each remote address, which are
+    // weighted by the charging class of the remote.
+    Report {
+        ip: std::net::IpAddr,
+        amounts: HashMap<std::net::IpAddr, u64>,
+    },
+    // A DNS response observed on its way to a subscriber, used to attribute
+    // destinations to charging classes.
+    DnsAnswer {
+        response: crate::packet_parser::DnsResponse,
+    },
+    // Queries the live balance of a subscriber, including usage not yet
+    // synchronized to the database. Returns None if no worker is currently
+    // tracking the address.
+    GetBalance {
+        ip: std::net::IpAddr,
+        out_channel: tokio::sync::oneshot::Sender<Option<i64>>,
+    },
+    // Moves workers to follow subscriber address changes.
+    Readdress {
+        changes: Vec<crate::address_watcher::AddressChange>,
+    },
+    // Stops the worker of an address, if any, so that a new worker starts
+    // with the next report, e.g. once a subscriber is provisioned for an
+    // address whose worker found none.
+    Forget {
+        ip: std::net::IpAddr,
+    },
+    // Stops all workers, replying once each has charged its outstanding
+    // usage, e.g. before haulage exits.
+    Flush {
+        out_channel: tokio::sync::oneshot::Sender<()>,
+    },
 }
 
 async fn accounting_task_dispatcher(
     mut chan: tokio::sync::mpsc::Receiver<Message>,
     period: std::time::Duration,
-    db_pool: std::sync::Arc<sqlx::PgPool>,
-    enforcer: std::sync::Arc<crate::enforcer::Iptables>,
+    worker_context: AccounterContext,
+    mut classifier: crate::charging::Classifier,
     log: slog::Logger,
 ) -> () {
+    let stats = std::sync::Arc::clone(&worker_context.stats);
     let mut directory: HashMap<std::net::IpAddr, tokio::sync::mpsc::Sender<WorkerMessage>> =
         HashMap::new();
 
     while let Some(message) = chan.recv().await {
         match message {
-            Message::Report { ip: dest, amount } => {
+            Message::Report { ip: dest, amounts } => {
+                stats.accounter_reports.increment();
+                if let Some(metrics) = &worker_context.metrics {
+                    metrics.add_subscriber_bytes(dest, amounts.values().sum());
+                }
+                let amount = match classifier.is_empty() {
+                    true => amounts.values().sum(),
+                    false => {
+                        let now = std::time::Instant::now();
+                        amounts
+                            .iter()
+                            .map(|(remote, amount)| {
+                                crate::charging::weighted_bytes(
+                                    *amount,
+                                    classifier.weight(*remote, now),
+                                )
+                            })
+                            .sum()
+                    }
+                };
+                stats.user_bytes_weighted.add(amount);
                 if !directory.contains_key(&dest) {
                     let (worker_chan_send, worker_chan_recv) = tokio::sync::mpsc::channel(32);
                     let worker_log =
                         log.new(slog::o!("aggregation" => String::from(format!("{:?}", dest))));
 
-                    let db_pool = db_pool.clone();
-                    let enforcer = std::sync::Arc::clone(&enforcer);
+                    let worker_context = worker_context.clone();
 
                     directory.insert(dest.clone(), worker_chan_send);
+                    stats.accounter_workers_started.increment();
                     tokio::task::spawn(async move {
                         accounting_worker(
                             dest,
                             worker_chan_recv,
                             period,
-                            db_pool,
-                            enforcer,
+                            worker_context,
                             worker_log,
                         )
                         .await;
@@ -69,11 +121,66 @@ async fn accounting_task_dispatcher(
                     .unwrap()
                     .send(WorkerMessage::Report { amount: amount })
                     .await
-                    .unwrap_or_else(
-                        |e| slog::error!(log, "Failed to dispatch"; "error" => e.to_string()),
-                    );
+                    .unwrap_or_else(|e| {
+                        stats.accounter_dispatch_errors.increment();
+                        slog::error!(log, "Failed to dispatch"; "error" => e.to_string())
+                    });
                 slog::debug!(log, "Received at dispatch {:?} {}", dest, amount);
             }
+            Message::DnsAnswer { response } => {
+                classifier.learn(&response, std::time::Instant::now());
+            }
+            Message::GetBalance { ip, out_channel } => match directory.get(&ip) {
+                Some(worker_channel) => {
+                    worker_channel
+                        .send(WorkerMessage::GetBalance { out_channel })
+                        .await
+                        .unwrap_or_else(
+                            |e| slog::error!(log, "Failed to dispatch balance query"; "error" => e.to_string()),
+                        );
+                }
+                None => {
+                    // The requester may have already given up, which is fine.
+                    let _ = out_channel.send(None);
+                }
+            },
+            Message::Forget { ip } => {
+                directory.remove(&ip);
+            }
+            Message::Flush { out_channel } => {
+                let mut flushed = Vec::new();
+                for (_, worker_channel) in directory.drain() {
+                    let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+                    if worker_channel
+                        .send(WorkerMessage::Flush { done: done_tx })
+                        .await
+                        .is_ok()
+                    {
+                        flushed.push(done_rx);
+                    }
+                }
+                slog::info!(log, "Flushing outstanding usage"; "workers" => flushed.len());
+                for done in flushed {
+                    let _ = done.await;
+                }
+                let _ = out_channel.send(());
+                break;
+            }
+            Message::Readdress { changes } => {
+                for (old, new) in
+                    crate::address_watcher::readdress_workers(&mut directory, &changes)
+                {
+                    slog::info!(log, "Moved accounting worker"; "old" => old.to_string(), "new" => new.to_string());
+                    directory
+                        .get(&new)
+                        .unwrap()
+                        .send(WorkerMessage::Readdress { ip: new })
+                        .await
+                        .unwrap_or_else(
+                            |e| slog::error!(log, "Failed to dispatch readdress"; "error" => e.to_string()),
+                        );
+                }
+            }
         };
     }
 }
@@ -83,25 +190,91 @@ enum WorkerMessage {
     Report {
         amount: u64,
     },
-    _GetBalance {
-        out_channel: tokio::sync::oneshot::Sender<i64>,
+    GetBalance {
+        out_channel: tokio::sync::oneshot::Sender<Option<i64>>,
+    },
+    Readdress {
+        ip: std::net::IpAddr,
+    },
+    Flush {
+        done: tokio::sync::oneshot::Sender<()>,
     },
 }
 
+// Handles shared by all accounting workers.
+#[derive(Debug, Clone)]
+pub struct AccounterContext {
+    pub storage: std::sync::Arc<dyn crate::storage::Storage>,
+    pub usage_writer: tokio::sync::mpsc::Sender<crate::usage_writer::Message>,
+    pub enforcer: std::sync::Arc<dyn crate::enforcer::PolicyEnforcer>,
+    pub quota: Option<std::sync::Arc<crate::nft_quota::NftQuota>>,
+    pub stats: std::sync::Arc<crate::stats::Stats>,
+    pub metrics: Option<std::sync::Arc<crate::metrics::Metrics>>,
+    // Balances in bytes at which to record a balance threshold event.
+    pub balance_thresholds: std::sync::Arc<Vec<i64>>,
+}
+
 async fn accounting_worker(
-    ip: std::net::IpAddr,
+    mut ip: std::net::IpAddr,
     mut chan: tokio::sync::mpsc::Receiver<WorkerMessage>,
     db_change_poll_period: std::time::Duration,
-    db_pool: std::sync::Arc<sqlx::PgPool>,
-    enforcer: std::sync::Arc<crate::enforcer::Iptables>,
+    context: AccounterContext,
     log: slog::Logger,
 ) -> () {
+    let AccounterContext {
+        storage,
+        usage_writer,
+        enforcer,
+        quota,
+        stats,
+        metrics: _,
+        balance_thresholds,
+    } = context;
+
     // Lookup current balance from DB
-    let current_state = query_balance(&db_pool, ip, &log).await.unwrap();
+    let current_state = match query_balance(storage.as_ref(), ip, &log).await {
+        Ok(current_state) => current_state,
+        Err(e) => {
+            slog::warn!(log, "Failed to look up subscriber balance"; "ip" => ip.to_string(), "error" => e.to_string());
+            return;
+        }
+    };
     let subscriber_id = current_state.subscriber_id;
     let mut balance = current_state.data_balance;
     let mut bytes_aggregated: i64 = 0;
 
+    // Debits are written by the usage writer in the same transaction as the
+    // usage records, so the worker keeps handling reports while a debit is in
+    // flight and accounts for the in flight bytes in its balance estimate.
+    let mut bytes_in_flight: i64 = 0;
+    let mut debit_reply: Option<
+        tokio::sync::oneshot::Receiver<Option<crate::usage_writer::DebitResult>>,
+    > = None;
+    // When the in flight debit was requested, for measuring how long it takes
+    // to enforce a balance found to be exhausted.
+    let mut debit_requested_at = tokio::time::Instant::now();
+    // Usage is not charged while the subscriber is exempt, so there is no
+    // transition to zero balance to synchronize.
+    let mut exempt = false;
+
+    storage
+        .record_first_seen(subscriber_id, ip)
+        .await
+        .unwrap_or_else(
+            |e| slog::warn!(log, "Failed to record first seen event"; "error" => e.to_string()),
+        );
+    if let Some(quota) = &quota {
+        quota
+            .sync(subscriber_id, ip, balance, 0)
+            .await
+            .unwrap_or_else(|e| {
+                slog::error!(log, "Unable to install subscriber quota"; "error" => e.to_string());
+                0
+            });
+    }
+
+    // Signalled once the outstanding usage is charged, if asked to flush.
+    let mut flushed = None;
     let mut timer = tokio::time::interval_at(
         tokio::time::Instant::now() + db_change_poll_period,
         db_change_poll_period,
@@ -109,26 +282,70 @@ async fn accounting_worker(
     loop {
         tokio::select! {
             _ = timer.tick() => {
-                let update_result = update_balance(&db_pool, subscriber_id, -bytes_aggregated, &log).await;
-                match update_result {
-                    Ok(new_state) => {
-                        // Detect if the subscriber's balance has gone negative after synchronizing with the DB
-                        if (new_state.data_balance <= 0) && (balance > 0) {
-                            enforcer
-                                .update_policy(subscriber_id, crate::enforcer::SubscriberCondition::NoBalance)
-                                .await
-                                .unwrap_or_else(
-                                    |e| slog::error!(log, "Unable to update policy for zero balance sub"; "error" => e.to_string())
-                                );
+                // Debit even when no bytes were used to refresh the balance,
+                // which may have been topped up externally.
+                if debit_reply.is_none() {
+                    debit_reply = request_debit(&usage_writer, subscriber_id, bytes_aggregated, false, &log).await;
+                    debit_requested_at = tokio::time::Instant::now();
+                    bytes_in_flight = bytes_aggregated;
+                    bytes_aggregated = 0;
+                }
+            }
+            reply = async { debit_reply.as_mut().unwrap().await }, if debit_reply.is_some() => {
+                debit_reply = None;
+                match reply {
+                    Ok(Some(result)) => {
+                        let new_balance = result.balance;
+                        stats.balance_syncs.increment();
+                        stats.user_bytes_debited.add(bytes_in_flight as u64);
+                        if result.exempt {
+                            stats.user_bytes_exempt.add(bytes_in_flight as u64);
+                        }
+                        exempt = result.exempt;
+                        // Handle the transition to zero balance
+                        if (new_balance <= 0) && (balance > 0) {
+                            let written_at = tokio::time::Instant::now();
+                            match enforcer.update_policy(subscriber_id, crate::enforcer::SubscriberCondition::NoBalance).await {
+                                Ok(()) => {
+                                    // Traffic is unrestricted until the rules are
+                                    // applied, so this bounds the overshoot.
+                                    let latency = debit_requested_at.elapsed();
+                                    stats.record_enforcement_latency(latency);
+                                    slog::info!(log, "Enforced zero balance"; "subscriber" => subscriber_id, "latency_ms" => latency.as_millis() as u64, "write_ms" => (written_at - debit_requested_at).as_millis() as u64, "apply_ms" => written_at.elapsed().as_millis() as u64);
+                                }
+                                Err(e) => {
+                                    slog::error!(log, "Unable to update policy for zero balance sub"; "error" => e.to_string());
+                                }
+                            }
                         }
 
-                        balance = new_state.data_balance;
+                        crate::events::record_balance_thresholds(storage.as_ref(), subscriber_id, balance, new_balance, &balance_thresholds)
+                            .await
+                            .unwrap_or_else(|e| slog::warn!(log, "Failed to record balance threshold event"; "error" => e.to_string()));
+                        balance = new_balance;
+
+                        // Charge any traffic the kernel quota counted that was
+                        // missed by capture, and reset the quota to the new
+                        // balance.
+                        if let Some(quota) = &quota {
+                            match quota.sync(subscriber_id, ip, new_balance, bytes_in_flight).await {
+                                Ok(0) => {}
+                                Ok(unaccounted) => {
+                                    stats.quota_unaccounted_bytes.add(unaccounted);
+                                    request_debit(&usage_writer, subscriber_id, unaccounted as i64, false, &log).await;
+                                }
+                                Err(e) => {
+                                    slog::error!(log, "Unable to reset subscriber quota"; "error" => e.to_string());
+                                }
+                            }
+                        }
                     }
-                    Err(e) => {
-                        slog::warn!(log, "Failed to update balance"; "ip" => ip.to_string(), "error" => e.to_string());
+                    _ => {
+                        stats.balance_sync_errors.increment();
+                        slog::warn!(log, "Failed to update balance"; "ip" => ip.to_string());
                     }
                 }
-                bytes_aggregated = 0;
+                bytes_in_flight = 0;
             }
             message = chan.recv() => {
                 if message.is_none() {
@@ -140,75 +357,102 @@ async fn accounting_worker(
                         slog::debug!(log, "Aggregated {} bytes", bytes_aggregated);
 
                         // Synchronize datastore and rule state at the point of transition to zero balance
-                        if (bytes_aggregated >= balance) && (balance > 0) {
-                            let update_result = update_balance(&db_pool, subscriber_id, -bytes_aggregated, &log).await;
-                            match update_result {
-                                Ok(new_state) => {
-                                    // Handle the transition to zero balance
-                                    if (new_state.data_balance <= 0) && (balance > 0) {
-                                        enforcer
-                                            .update_policy(subscriber_id, crate::enforcer::SubscriberCondition::NoBalance)
-                                            .await
-                                            .unwrap_or_else(
-                                                |e| slog::error!(log, "Unable to update policy for zero balance sub"; "error" => e.to_string())
-                                            );
-                                    }
-
-                                    balance = new_state.data_balance;
-                                }
-                                Err(e) => {
-                                    slog::warn!(log, "Failed to update balance"; "ip" => ip.to_string(), "error" => e.to_string());
-                                }
-                            }
+                        if (bytes_aggregated + bytes_in_flight >= balance) && (balance > 0) && !exempt && debit_reply.is_none() {
+                            debit_reply = request_debit(&usage_writer, subscriber_id, bytes_aggregated, true, &log).await;
+                            debit_requested_at = tokio::time::Instant::now();
+                            bytes_in_flight = bytes_aggregated;
                             bytes_aggregated = 0;
                         }
                     }
-                    WorkerMessage::_GetBalance{out_channel} => {
-                        // ToDo(matt9j) This might panic during shutdown, if there is a
-                        // get request in flight as the dispatcher shuts down?
-
+                    WorkerMessage::GetBalance{out_channel} => {
                         // Account for the bytes aggregated but not sent to the
-                        // db yet when answering queries for the balance.
-                        out_channel.send(balance - bytes_aggregated).expect("Failed to send oneshot return");
+                        // db yet when answering queries for the balance. The
+                        // requester may have timed out and dropped the channel.
+                        if out_channel.send(Some(balance - bytes_in_flight - bytes_aggregated)).is_err() {
+                            slog::debug!(log, "Balance query requester went away");
+                        }
+                    }
+                    WorkerMessage::Readdress{ip: new_ip} => {
+                        slog::debug!(log, "Worker readdressed"; "old" => ip.to_string(), "new" => new_ip.to_string());
+                        ip = new_ip;
+                    }
+                    WorkerMessage::Flush{done} => {
+                        flushed = Some(done);
+                        break;
                     }
                 }
             }
         };
     }
+
+    // Charge any outstanding usage when the worker is retired.
+    if let Some(reply) = debit_reply {
+        let _ = reply.await;
+    }
+    if bytes_aggregated > 0 {
+        if let Some(reply) =
+            request_debit(&usage_writer, subscriber_id, bytes_aggregated, true, &log).await
+        {
+            match reply.await {
+                Ok(Some(_)) => {
+                    stats.balance_syncs.increment();
+                    stats.user_bytes_debited.add(bytes_aggregated as u64);
+                }
+                _ => {
+                    stats.balance_sync_errors.increment();
+                    slog::warn!(log, "Failed to update final balance"; "ip" => ip.to_string());
+                }
+            }
+        }
+    }
+    if let Some(done) = flushed {
+        let _ = done.send(());
+    }
     slog::debug!(log, "Shutting down worker {}", ip);
 }
 
+async fn request_debit(
+    usage_writer: &tokio::sync::mpsc::Sender<crate::usage_writer::Message>,
+    subscriber: UserId,
+    bytes: i64,
+    immediate: bool,
+    log: &slog::Logger,
+) -> Option<tokio::sync::oneshot::Receiver<Option<crate::usage_writer::DebitResult>>> {
+    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+    let send_result = usage_writer
+        .send(crate::usage_writer::Message::Debit {
+            subscriber,
+            bytes,
+            immediate,
+            out_channel: reply_tx,
+        })
+        .await;
+    match send_result {
+        Ok(_) => Some(reply_rx),
+        Err(e) => {
+            slog::error!(log, "Failed to send debit to the usage writer"; "error" => e.to_string());
+            None
+        }
+    }
+}
+
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum QueryError {
     #[error("Database operation failed: {0}")]
-    DatabaseError(#[from] sqlx::error::Error),
+    DatabaseError(#[from] crate::storage::StorageError),
     #[error("Failed to lookup user")]
     UserLookupError,
 }
 
 async fn query_balance(
-    db_pool: &sqlx::PgPool,
+    storage: &dyn crate::storage::Storage,
     ip: std::net::IpAddr,
     log: &slog::Logger,
-) -> Result<SubscriberBalanceInfo, QueryError> {
-    let mut transaction = db_pool.begin().await?;
+) -> Result<crate::storage::SubscriberBalance, QueryError> {
     slog::debug!(log, "Querying for balance"; "ip" => ip.to_string());
-
-    let balance_state_query = r#"
-        SELECT "internal_uid" AS "subscriber_id", "data_balance"
-        FROM subscribers
-        INNER JOIN static_ips ON static_ips.imsi = subscribers.imsi
-        WHERE static_ips.ip = $1
-    "#;
-
-    let rows: Vec<SubscriberBalanceInfo> = sqlx::query_as(balance_state_query)
-        .bind(ipnetwork::IpNetwork::from(ip))
-        .fetch_all(&mut transaction)
-        .await?;
-
-    transaction.commit().await?;
+    let rows = storage.subscribers_by_address(ip).await?;
 
     // Ensure the user is unique
     if rows.len() != 1 {
@@ -218,65 +462,3 @@ async fn query_balance(
 
     Ok(user_state.clone())
 }
-
-async fn update_balance(
-    db_pool: &sqlx::PgPool,
-    id: UserId,
-    balance_delta: i64,
-    log: &slog::Logger,
-) -> Result<SubscriberBalanceInfo, QueryError> {
-    let mut transaction = db_pool.begin().await?;
-    slog::debug!(log, "Updating balance"; "id" => id);
-
-    let subscriber_update_query = r#"
-        UPDATE subscribers
-        SET "data_balance" = "data_balance" + $1
-        WHERE "internal_uid" = $2
-        RETURNING "internal_uid" AS "subscriber_id", "data_balance";
-    "#;
-
-    let rows: Vec<SubscriberBalanceInfo> = sqlx::query_as(subscriber_update_query)
-        .bind(balance_delta)
-        .bind(id)
-        .fetch_all(&mut transaction)
-        .await?;
-
-    // Ensure the user is unique
-    if rows.len() != 1 {
-        return Err(QueryError::UserLookupError);
-    }
-    let mut user_state = rows.first().unwrap().clone();
-
-    // TODO(matt9j) Can we define a better behavior here?
-    // For now floor the data balance at zero
-    if user_state.data_balance < 0 {
-        slog::debug!(log, "Flooring data balance at 0"; "id" => id);
-        let update_zero_floor_query = r#"
-            UPDATE subscribers
-            SET "data_balance" = 0
-            WHERE "internal_uid" = $1
-            RETURNING "internal_uid" AS "subscriber_id", "data_balance";
-        "#;
-
-        let rows: Vec<SubscriberBalanceInfo> = sqlx::query_as(update_zero_floor_query)
-            .bind(id)
-            .fetch_all(&mut transaction)
-            .await?;
-
-        // Ensure the user is unique
-        if rows.len() != 1 {
-            return Err(QueryError::UserLookupError);
-        }
-
-        user_state = rows.first().unwrap().clone();
-    }
-
-    transaction.commit().await?;
-    Ok(user_state)
-}
-
-#[derive(Debug, Clone, sqlx::FromRow)]
-struct SubscriberBalanceInfo {
-    subscriber_id: i32,
-    data_balance: i64,
-}
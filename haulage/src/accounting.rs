@@ -0,0 +1,17 @@
+// How much detail is recorded about subscriber traffic, each level including
+// everything recorded by the levels before it. Usage records are kept in the
+// database, flows in the database or ClickHouse, and everything finer grained
+// is only exported to ClickHouse, so deployments at the usage level never
+// collect it at all. The level also gates the flow stream, NetFlow, and the
+// metrics exports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AccountingLevel {
+    // Interval usage records of each subscriber.
+    Usage,
+    // Usage of individual flows.
+    Flows,
+    // The domains that subscribers resolved remote addresses from.
+    Domains,
+    // Every DNS response sent to subscribers, including failed lookups.
+    Dns,
+}
@@ -0,0 +1,208 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::packet_parser::AddressClaim;
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // How long a claim to an address is remembered. Claims by different link
+    // layer addresses within the window are a collision.
+    pub window: std::time::Duration,
+    // Whether to suspend subscribers whose address is claimed by more than one
+    // device, until an operator investigates and resumes them.
+    pub block: bool,
+}
+
+// Watches the ARP and neighbor discovery traffic of subscribers for multiple
+// devices claiming the same address, which lets one device use and bill
+// traffic to another subscriber's account. Each collision is logged and
+// recorded as a subscriber event, and the subscriber optionally suspended.
+#[derive(Debug)]
+pub struct CollisionDetector {
+    dispatch_channel: tokio::sync::mpsc::Sender<Message>,
+}
+impl CollisionDetector {
+    pub fn new(
+        settings: Settings,
+        db_pool: std::sync::Arc<sqlx::PgPool>,
+        stats: std::sync::Arc<crate::stats::Stats>,
+        log: slog::Logger,
+    ) -> CollisionDetector {
+        let (sender, receiver) = tokio::sync::mpsc::channel(64);
+        tokio::task::spawn(async move {
+            detect_collisions(receiver, settings, db_pool, stats, log).await;
+        });
+        CollisionDetector {
+            dispatch_channel: sender,
+        }
+    }
+    pub fn clone_input_channel(&self) -> tokio::sync::mpsc::Sender<Message> {
+        self.dispatch_channel.clone()
+    }
+}
+
+pub enum Message {
+    Claims(Vec<AddressClaim>),
+}
+
+// The devices recently claiming each address.
+#[derive(Debug, Default)]
+struct Claims {
+    owners: HashMap<std::net::IpAddr, HashMap<pnet_datalink::MacAddr, std::time::Instant>>,
+    // Addresses with an ongoing collision, which are only reported once.
+    colliding: HashSet<std::net::IpAddr>,
+}
+impl Claims {
+    // Records a claim, returning all devices claiming the address if this
+    // claim starts a new collision.
+    fn observe(
+        &mut self,
+        claim: &AddressClaim,
+        now: std::time::Instant,
+        window: std::time::Duration,
+    ) -> Option<Vec<pnet_datalink::MacAddr>> {
+        let owners = self.owners.entry(claim.ip).or_default();
+        owners.retain(|_, seen| now.duration_since(*seen) < window);
+        owners.insert(claim.mac, now);
+
+        if owners.len() == 1 {
+            self.colliding.remove(&claim.ip);
+            return None;
+        }
+        if !self.colliding.insert(claim.ip) {
+            return None;
+        }
+        let mut macs: Vec<_> = owners.keys().copied().collect();
+        macs.sort();
+        Some(macs)
+    }
+
+    fn expire(&mut self, now: std::time::Instant, window: std::time::Duration) {
+        for owners in self.owners.values_mut() {
+            owners.retain(|_, seen| now.duration_since(*seen) < window);
+        }
+        self.owners.retain(|_, owners| !owners.is_empty());
+        let owners = &self.owners;
+        self.colliding
+            .retain(|ip| owners.get(ip).is_some_and(|owners| owners.len() > 1));
+    }
+}
+
+async fn detect_collisions(
+    mut chan: tokio::sync::mpsc::Receiver<Message>,
+    settings: Settings,
+    db_pool: std::sync::Arc<sqlx::PgPool>,
+    stats: std::sync::Arc<crate::stats::Stats>,
+    log: slog::Logger,
+) {
+    let mut claims = Claims::default();
+    let mut timer = tokio::time::interval(settings.window);
+    loop {
+        tokio::select! {
+            _ = timer.tick() => {
+                claims.expire(std::time::Instant::now(), settings.window);
+            }
+            message = chan.recv() => {
+                match message {
+                    Some(Message::Claims(batch)) => {
+                        let now = std::time::Instant::now();
+                        for claim in batch {
+                            if let Some(macs) = claims.observe(&claim, now, settings.window) {
+                                stats.address_collisions.increment();
+                                handle_collision(&db_pool, claim.ip, &macs, settings.block, &log)
+                                    .await
+                                    .unwrap_or_else(|e| slog::error!(log, "Failed to handle address collision"; "ip" => claim.ip.to_string(), "error" => e.to_string()));
+                            }
+                        }
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+}
+
+async fn handle_collision(
+    db_pool: &sqlx::PgPool,
+    ip: std::net::IpAddr,
+    macs: &[pnet_datalink::MacAddr],
+    block: bool,
+    log: &slog::Logger,
+) -> Result<(), crate::admin::AdminError> {
+    let macs: Vec<String> = macs.iter().map(|mac| mac.to_string()).collect();
+    slog::warn!(log, "Multiple devices claiming subscriber address"; "ip" => ip.to_string(), "macs" => macs.join(","));
+
+    let subscriber_query = r#"
+        SELECT "internal_uid", subscribers."imsi"
+        FROM subscribers
+        INNER JOIN static_ips ON static_ips.imsi = subscribers.imsi
+        WHERE static_ips.ip >>= $1
+    "#;
+    let subscriber: Option<(i32, String)> = sqlx::query_as(subscriber_query)
+        .bind(ipnetwork::IpNetwork::from(ip))
+        .fetch_optional(db_pool)
+        .await?;
+    let (subscriber, imsi) = match subscriber {
+        Some(subscriber) => subscriber,
+        None => {
+            slog::info!(log, "Colliding address is not assigned to a subscriber"; "ip" => ip.to_string());
+            return Ok(());
+        }
+    };
+
+    let mut connection = db_pool.acquire().await?;
+    crate::events::record_event(
+        &mut connection,
+        subscriber,
+        crate::events::EventKind::AddressCollision,
+        serde_json::json!({ "ip": ip, "macs": macs, "blocked": block }),
+    )
+    .await?;
+
+    if block {
+        crate::admin::set_subscriber_suspended(db_pool, &imsi, true, log).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collision_reported_once() {
+        let window = std::time::Duration::from_secs(600);
+        let now = std::time::Instant::now();
+        let ip: std::net::IpAddr = "10.45.0.2".parse().unwrap();
+        let first = pnet_datalink::MacAddr::new(2, 0, 0, 0, 0, 1);
+        let second = pnet_datalink::MacAddr::new(2, 0, 0, 0, 0, 2);
+        let mut claims = Claims::default();
+
+        assert_eq!(
+            claims.observe(&AddressClaim { ip, mac: first }, now, window),
+            None
+        );
+        assert_eq!(
+            claims.observe(&AddressClaim { ip, mac: first }, now, window),
+            None
+        );
+        assert_eq!(
+            claims.observe(&AddressClaim { ip, mac: second }, now, window),
+            Some(vec![first, second])
+        );
+        assert_eq!(
+            claims.observe(&AddressClaim { ip, mac: first }, now, window),
+            None
+        );
+
+        // Once the other device's claim expires, a new claim is a new collision.
+        let later = now + window;
+        assert_eq!(
+            claims.observe(&AddressClaim { ip, mac: first }, later, window),
+            None
+        );
+        assert_eq!(
+            claims.observe(&AddressClaim { ip, mac: second }, later, window),
+            Some(vec![first, second])
+        );
+    }
+}
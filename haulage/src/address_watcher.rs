@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+
+// A change to the address assigned to a subscriber. The new address is None if
+// the subscriber no longer has an address at all.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AddressChange {
+    pub subscriber: i32,
+    pub old: ipnetwork::IpNetwork,
+    pub new: Option<ipnetwork::IpNetwork>,
+}
+
+// Polls the subscriber address assignments, and moves the live aggregation,
+// accounting, and enforcement state of any subscriber whose address changed,
+// e.g. after a static_ips update or DHCP renewal. Without this the per-address
+// workers keep charging the subscriber resolved when they started until
+// haulage is restarted.
+pub async fn watch_addresses(
+    period: std::time::Duration,
+    storage: std::sync::Arc<dyn crate::storage::Storage>,
+    user_aggregator: tokio::sync::mpsc::Sender<crate::async_aggregator::Message>,
+    user_accounter: tokio::sync::mpsc::Sender<crate::accounter::Message>,
+    enforcer: Option<std::sync::Arc<crate::enforcer::Iptables>>,
+    log: slog::Logger,
+) {
+    let mut assignments = storage
+        .address_assignments()
+        .await
+        .expect("Unable to query initial subscriber addresses");
+
+    let mut timer = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        timer.tick().await;
+        let current = match storage.address_assignments().await {
+            Ok(current) => current,
+            Err(e) => {
+                slog::warn!(log, "Unable to query subscriber addresses"; "error" => e.to_string());
+                continue;
+            }
+        };
+
+        let changes = find_changes(&assignments, &current);
+        assignments = current;
+        if changes.is_empty() {
+            continue;
+        }
+        for change in &changes {
+            slog::info!(log, "Subscriber address changed"; "subscriber" => change.subscriber, "old" => change.old.to_string(), "new" => change.new.map(|ip| ip.to_string()));
+        }
+
+        user_aggregator
+            .send(crate::async_aggregator::Message::Readdress {
+                changes: changes.clone(),
+            })
+            .await
+            .unwrap_or_else(|e| slog::error!(log, "Failed to send address changes to aggregator"; "error" => e.to_string()));
+        user_accounter
+            .send(crate::accounter::Message::Readdress {
+                changes: changes.clone(),
+            })
+            .await
+            .unwrap_or_else(|e| slog::error!(log, "Failed to send address changes to accounter"; "error" => e.to_string()));
+
+        if let Some(enforcer) = &enforcer {
+            let new_addresses = changes
+                .iter()
+                .filter_map(|change| change.new.map(|new| (change.subscriber, new)))
+                .collect();
+            enforcer
+                .change_addresses(new_addresses)
+                .await
+                .unwrap_or_else(|e| slog::error!(log, "Failed to move enforcement to new addresses"; "error" => e.to_string()));
+        }
+    }
+}
+
+fn find_changes(
+    previous: &HashMap<i32, ipnetwork::IpNetwork>,
+    current: &HashMap<i32, ipnetwork::IpNetwork>,
+) -> Vec<AddressChange> {
+    let mut changes: Vec<AddressChange> = previous
+        .iter()
+        .filter(|(subscriber, old)| current.get(subscriber) != Some(old))
+        .map(|(subscriber, old)| AddressChange {
+            subscriber: *subscriber,
+            old: *old,
+            new: current.get(subscriber).copied(),
+        })
+        .collect();
+    changes.sort_by_key(|change| change.subscriber);
+    changes
+}
+
+// Moves the per-address workers in a dispatcher directory to follow address
+// changes, returning the (old, new) keys of the moved workers. All workers for
+// old addresses are removed before any are reinserted, so that addresses
+// swapped between subscribers are not attributed to the wrong one. Workers
+// that cannot be moved, e.g. because a worker already exists at the new
+// address, are dropped, which causes them to write out their state and exit.
+pub fn readdress_workers<T>(
+    directory: &mut HashMap<std::net::IpAddr, T>,
+    changes: &[AddressChange],
+) -> Vec<(std::net::IpAddr, std::net::IpAddr)> {
+    let mut removed = Vec::new();
+    for change in changes {
+        let old_keys: Vec<std::net::IpAddr> = directory
+            .keys()
+            .filter(|key| change.old.contains(**key))
+            .copied()
+            .collect();
+        for key in old_keys {
+            let worker = directory.remove(&key).unwrap();
+            removed.push((key, worker, change.new));
+        }
+    }
+
+    let mut moved = Vec::new();
+    for (old_key, worker, new) in removed {
+        // Only single address assignments can be followed, since there is no
+        // correspondence between addresses within different prefixes.
+        let new_key = match new {
+            Some(network) if network.prefix() == max_prefix(&network) => network.ip(),
+            _ => continue,
+        };
+        if let std::collections::hash_map::Entry::Vacant(entry) = directory.entry(new_key) {
+            entry.insert(worker);
+            moved.push((old_key, new_key));
+        }
+    }
+    moved
+}
+
+fn max_prefix(network: &ipnetwork::IpNetwork) -> u8 {
+    match network {
+        ipnetwork::IpNetwork::V4(_) => 32,
+        ipnetwork::IpNetwork::V6(_) => 128,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_network(address: &str) -> ipnetwork::IpNetwork {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn test_find_changes() {
+        let previous = HashMap::from([
+            (1, make_network("10.45.0.2/32")),
+            (2, make_network("10.45.0.3/32")),
+            (3, make_network("10.45.0.4/32")),
+        ]);
+        let current = HashMap::from([
+            (1, make_network("10.45.0.2/32")),
+            (2, make_network("10.45.0.9/32")),
+            (4, make_network("10.45.0.4/32")),
+        ]);
+        assert_eq!(
+            find_changes(&previous, &current),
+            vec![
+                AddressChange {
+                    subscriber: 2,
+                    old: make_network("10.45.0.3/32"),
+                    new: Some(make_network("10.45.0.9/32")),
+                },
+                AddressChange {
+                    subscriber: 3,
+                    old: make_network("10.45.0.4/32"),
+                    new: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_readdress_swapped_workers() {
+        let a: std::net::IpAddr = "10.45.0.2".parse().unwrap();
+        let b: std::net::IpAddr = "10.45.0.3".parse().unwrap();
+        let mut directory = HashMap::from([(a, 1), (b, 2)]);
+        let changes = vec![
+            AddressChange {
+                subscriber: 1,
+                old: make_network("10.45.0.2/32"),
+                new: Some(make_network("10.45.0.3/32")),
+            },
+            AddressChange {
+                subscriber: 2,
+                old: make_network("10.45.0.3/32"),
+                new: Some(make_network("10.45.0.2/32")),
+            },
+        ];
+        let mut moved = readdress_workers(&mut directory, &changes);
+        moved.sort();
+        assert_eq!(moved, vec![(a, b), (b, a)]);
+        assert_eq!(directory, HashMap::from([(a, 2), (b, 1)]));
+    }
+}
@@ -0,0 +1,386 @@
+use structopt::StructOpt;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AdjustError {
+    #[error("Database operation failed: {0}")]
+    DatabaseError(#[from] sqlx::error::Error),
+    #[error("No subscriber found with imsi {0}")]
+    UnknownSubscriber(String),
+    #[error("No operator given, and none found in SUDO_USER or USER")]
+    UnknownOperator,
+    #[error("A reason for the adjustment is required")]
+    MissingReason,
+    #[error("The adjustment does not change anything")]
+    EmptyAdjustment,
+    #[error("The adjusted period ends before it starts")]
+    InvalidPeriod,
+}
+
+/// Record signed corrections to usage and balances and exit. Corrections are
+/// kept alongside the records they correct, which are never modified, along
+/// with who made them and why.
+#[derive(Debug, StructOpt)]
+pub enum AdjustCommand {
+    /// Correct the usage recorded for a subscriber over a period, e.g. usage
+    /// lost to an outage or charged twice. Negative amounts remove usage.
+    Usage {
+        /// The IMSI of the subscriber whose usage to correct.
+        #[structopt(long = "imsi")]
+        imsi: String,
+        /// The start of the corrected period, e.g. "2026-10-01 12:00:00Z".
+        #[structopt(long = "start")]
+        start: humantime::Timestamp,
+        /// The end of the corrected period.
+        #[structopt(long = "end")]
+        end: humantime::Timestamp,
+        #[structopt(long = "ran-bytes-up", default_value = "0", allow_hyphen_values = true)]
+        ran_bytes_up: i64,
+        #[structopt(
+            long = "ran-bytes-down",
+            default_value = "0",
+            allow_hyphen_values = true
+        )]
+        ran_bytes_down: i64,
+        #[structopt(long = "wan-bytes-up", default_value = "0", allow_hyphen_values = true)]
+        wan_bytes_up: i64,
+        #[structopt(
+            long = "wan-bytes-down",
+            default_value = "0",
+            allow_hyphen_values = true
+        )]
+        wan_bytes_down: i64,
+        #[structopt(flatten)]
+        audit: Audit,
+    },
+    /// Credit or, with a negative amount, debit a subscriber's balance, or
+    /// their pool's balance if they draw from one. Balances are floored at
+    /// zero.
+    Balance {
+        /// The IMSI of the subscriber whose balance to correct.
+        #[structopt(long = "imsi")]
+        imsi: String,
+        /// The bytes to add to the balance.
+        #[structopt(long = "bytes", allow_hyphen_values = true)]
+        bytes: i64,
+        #[structopt(flatten)]
+        audit: Audit,
+    },
+    /// List the corrections made to a subscriber, oldest first.
+    List {
+        /// The IMSI of the subscriber whose corrections to list.
+        #[structopt(long = "imsi")]
+        imsi: String,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Audit {
+    /// Why the correction is needed, e.g. a ticket number.
+    #[structopt(long = "reason")]
+    reason: String,
+    /// Who is making the correction. Defaults to the invoking user.
+    #[structopt(long = "operator")]
+    operator: Option<String>,
+}
+impl Audit {
+    // The user running the command through sudo is the operator, rather than
+    // root.
+    fn operator(&self) -> Result<String, AdjustError> {
+        self.operator
+            .clone()
+            .or_else(|| std::env::var("SUDO_USER").ok())
+            .or_else(|| std::env::var("USER").ok())
+            .filter(|operator| !operator.is_empty())
+            .ok_or(AdjustError::UnknownOperator)
+    }
+
+    fn reason(&self) -> Result<&str, AdjustError> {
+        match self.reason.trim() {
+            "" => Err(AdjustError::MissingReason),
+            reason => Ok(reason),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, sqlx::FromRow, serde::Serialize)]
+pub struct UsageAdjustment {
+    id: i64,
+    time: chrono::DateTime<chrono::Utc>,
+    subscriber: i32,
+    imsi: String,
+    start_time: chrono::DateTime<chrono::Utc>,
+    end_time: chrono::DateTime<chrono::Utc>,
+    ran_bytes_up: i64,
+    ran_bytes_down: i64,
+    wan_bytes_up: i64,
+    wan_bytes_down: i64,
+    operator: String,
+    reason: String,
+}
+impl std::fmt::Display for UsageAdjustment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "usage adjustment {}: {}",
+            self.id,
+            serde_json::to_string_pretty(self).map_err(|_| std::fmt::Error)?
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, sqlx::FromRow, serde::Serialize)]
+pub struct BalanceAdjustment {
+    id: i64,
+    time: chrono::DateTime<chrono::Utc>,
+    subscriber: i32,
+    imsi: String,
+    balance_pool: Option<i32>,
+    bytes: i64,
+    balance_after: i64,
+    operator: String,
+    reason: String,
+}
+impl BalanceAdjustment {
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+}
+impl std::fmt::Display for BalanceAdjustment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "balance adjustment {}: {}",
+            self.id,
+            serde_json::to_string_pretty(self).map_err(|_| std::fmt::Error)?
+        )
+    }
+}
+
+pub async fn run(
+    command: AdjustCommand,
+    db_pool: &sqlx::PgPool,
+    log: &slog::Logger,
+) -> Result<(), AdjustError> {
+    match command {
+        AdjustCommand::Usage {
+            imsi,
+            start,
+            end,
+            ran_bytes_up,
+            ran_bytes_down,
+            wan_bytes_up,
+            wan_bytes_down,
+            audit,
+        } => {
+            let amounts = [ran_bytes_up, ran_bytes_down, wan_bytes_up, wan_bytes_down];
+            let period = (
+                chrono::DateTime::<chrono::Utc>::from(*start),
+                chrono::DateTime::<chrono::Utc>::from(*end),
+            );
+            let adjustment = adjust_usage(db_pool, &imsi, period, amounts, &audit, log).await?;
+            println!("{}", adjustment);
+        }
+        AdjustCommand::Balance { imsi, bytes, audit } => {
+            let adjustment = adjust_balance(db_pool, &imsi, bytes, &audit, log).await?;
+            println!("{}", adjustment);
+        }
+        AdjustCommand::List { imsi } => {
+            let (usage, balances) = list_adjustments(db_pool, &imsi).await?;
+            for adjustment in usage {
+                println!("{}", adjustment);
+            }
+            for adjustment in balances {
+                println!("{}", adjustment);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Records a correction to the usage of a subscriber over a period. Only the
+// record of usage is corrected, so balances are adjusted separately if the
+// subscriber was also charged for it.
+async fn adjust_usage(
+    db_pool: &sqlx::PgPool,
+    imsi: &str,
+    (start, end): (chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>),
+    [ran_bytes_up, ran_bytes_down, wan_bytes_up, wan_bytes_down]: [i64; 4],
+    audit: &Audit,
+    log: &slog::Logger,
+) -> Result<UsageAdjustment, AdjustError> {
+    let operator = audit.operator()?;
+    let reason = audit.reason()?;
+    if [ran_bytes_up, ran_bytes_down, wan_bytes_up, wan_bytes_down] == [0; 4] {
+        return Err(AdjustError::EmptyAdjustment);
+    }
+    if end < start {
+        return Err(AdjustError::InvalidPeriod);
+    }
+    slog::info!(log, "Adjusting subscriber usage"; "imsi" => imsi, "operator" => &operator, "reason" => reason);
+
+    let adjust_query = r#"
+        INSERT INTO usage_adjustments("subscriber", "imsi", "start_time", "end_time", "ran_bytes_up", "ran_bytes_down", "wan_bytes_up", "wan_bytes_down", "operator", "reason")
+        SELECT "internal_uid", "imsi", $2, $3, $4, $5, $6, $7, $8, $9
+        FROM subscribers
+        WHERE "imsi" = $1
+        RETURNING *
+    "#;
+    let adjustment: Option<UsageAdjustment> = sqlx::query_as(adjust_query)
+        .bind(imsi)
+        .bind(start)
+        .bind(end)
+        .bind(ran_bytes_up)
+        .bind(ran_bytes_down)
+        .bind(wan_bytes_up)
+        .bind(wan_bytes_down)
+        .bind(&operator)
+        .bind(reason)
+        .fetch_optional(db_pool)
+        .await?;
+    adjustment.ok_or_else(|| AdjustError::UnknownSubscriber(imsi.to_owned()))
+}
+
+// Credits or debits the balance a subscriber draws from, recording the
+// correction in the same transaction. The enforcer applies any resulting
+// policy change on its next poll of the database.
+async fn adjust_balance(
+    db_pool: &sqlx::PgPool,
+    imsi: &str,
+    bytes: i64,
+    audit: &Audit,
+    log: &slog::Logger,
+) -> Result<BalanceAdjustment, AdjustError> {
+    let operator = audit.operator()?;
+    let reason = audit.reason()?;
+    if bytes == 0 {
+        return Err(AdjustError::EmptyAdjustment);
+    }
+    slog::info!(log, "Adjusting subscriber balance"; "imsi" => imsi, "bytes" => bytes, "operator" => &operator, "reason" => reason);
+    let mut transaction = db_pool.begin().await?;
+    let adjustment =
+        apply_balance_adjustment(&mut transaction, imsi, bytes, &operator, reason).await?;
+    transaction.commit().await?;
+    Ok(adjustment)
+}
+
+// Adjusts a balance on the given connection, so that callers can record the
+// adjustment in the same transaction as their own changes.
+pub async fn apply_balance_adjustment(
+    connection: &mut sqlx::PgConnection,
+    imsi: &str,
+    bytes: i64,
+    operator: &str,
+    reason: &str,
+) -> Result<BalanceAdjustment, AdjustError> {
+    let balance_query = r#"
+        WITH target AS (
+            SELECT "internal_uid", "imsi", "balance_pool"
+            FROM subscribers
+            WHERE "imsi" = $1
+            FOR UPDATE
+        ), pool_adjustment AS (
+            UPDATE balance_pools
+            SET "data_balance" = GREATEST(balance_pools."data_balance" + $2, 0)
+            FROM target
+            WHERE balance_pools."id" = target."balance_pool"
+            RETURNING balance_pools."data_balance"
+        ), subscriber_adjustment AS (
+            UPDATE subscribers
+            SET "data_balance" = GREATEST(COALESCE(subscribers."data_balance", 0) + $2, 0)
+            FROM target
+            WHERE subscribers."internal_uid" = target."internal_uid" AND target."balance_pool" IS NULL
+            RETURNING subscribers."data_balance"
+        )
+        INSERT INTO balance_adjustments("subscriber", "imsi", "balance_pool", "bytes", "balance_after", "operator", "reason")
+        SELECT
+            target."internal_uid",
+            target."imsi",
+            target."balance_pool",
+            $2,
+            COALESCE((SELECT "data_balance" FROM pool_adjustment), (SELECT "data_balance" FROM subscriber_adjustment)),
+            $3,
+            $4
+        FROM target
+        RETURNING *
+    "#;
+    let adjustment: Option<BalanceAdjustment> = sqlx::query_as(balance_query)
+        .bind(imsi)
+        .bind(bytes)
+        .bind(operator)
+        .bind(reason)
+        .fetch_optional(&mut *connection)
+        .await?;
+    let adjustment = adjustment.ok_or_else(|| AdjustError::UnknownSubscriber(imsi.to_owned()))?;
+
+    crate::events::record_event(
+        &mut *connection,
+        adjustment.subscriber,
+        crate::events::EventKind::BalanceAdjusted,
+        serde_json::json!({
+            "adjustment": adjustment.id,
+            "bytes": bytes,
+            "balance_pool": adjustment.balance_pool,
+            "operator": operator,
+            "reason": reason,
+        }),
+    )
+    .await?;
+
+    Ok(adjustment)
+}
+
+async fn list_adjustments(
+    db_pool: &sqlx::PgPool,
+    imsi: &str,
+) -> Result<(Vec<UsageAdjustment>, Vec<BalanceAdjustment>), AdjustError> {
+    let usage: Vec<UsageAdjustment> =
+        sqlx::query_as(r#"SELECT * FROM usage_adjustments WHERE "imsi" = $1 ORDER BY "id""#)
+            .bind(imsi)
+            .fetch_all(db_pool)
+            .await?;
+    let balances: Vec<BalanceAdjustment> =
+        sqlx::query_as(r#"SELECT * FROM balance_adjustments WHERE "imsi" = $1 ORDER BY "id""#)
+            .bind(imsi)
+            .fetch_all(db_pool)
+            .await?;
+    Ok((usage, balances))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_requires_reason_and_operator() {
+        let audit = Audit {
+            reason: String::from(" ticket 42 "),
+            operator: Some(String::from("amina")),
+        };
+        assert_eq!(audit.operator().unwrap(), "amina");
+        assert_eq!(audit.reason().unwrap(), "ticket 42");
+
+        let audit = Audit {
+            reason: String::from("  "),
+            operator: None,
+        };
+        assert!(matches!(audit.reason(), Err(AdjustError::MissingReason)));
+
+        // Negative amounts are accepted as arguments.
+        let command = AdjustCommand::from_iter_safe([
+            "adjust",
+            "balance",
+            "--imsi",
+            "001010000000001",
+            "--bytes",
+            "-5000",
+            "--reason",
+            "double charge",
+        ])
+        .unwrap();
+        assert!(matches!(
+            command,
+            AdjustCommand::Balance { bytes: -5000, .. }
+        ));
+    }
+}
@@ -0,0 +1,772 @@
+use structopt::StructOpt;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AdminError {
+    #[error("Database operation failed: {0}")]
+    DatabaseError(#[from] sqlx::error::Error),
+    #[error("No subscriber found with imsi {0}")]
+    UnknownSubscriber(String),
+    #[error("No subscriber found with id {0}")]
+    UnknownSubscriberId(i32),
+    #[error("Invalid exemption duration: {0}")]
+    InvalidDuration(sqlx::error::BoxDynError),
+    #[error("Journal operation failed: {0}")]
+    JournalError(#[from] crate::journal::JournalError),
+    #[error("No journal entries found for imsi {0}")]
+    UnknownHistory(String),
+    #[error("Subscriber journal has been modified at entry {0}")]
+    JournalModified(i64),
+    #[error("API key operation failed: {0}")]
+    ApiKeyError(#[from] crate::api_keys::ApiKeyError),
+    #[error("No access policy found named {0}")]
+    UnknownPolicy(String),
+}
+
+#[derive(Debug, StructOpt)]
+pub enum AdminCommand {
+    /// Administratively suspend a subscriber, applying their suspended policy
+    /// regardless of balance.
+    Suspend {
+        /// The IMSI of the subscriber to suspend.
+        #[structopt(long = "imsi")]
+        imsi: String,
+    },
+    /// Lift an administrative suspension, returning the subscriber to their
+    /// balance based policy.
+    Resume {
+        /// The IMSI of the subscriber to resume.
+        #[structopt(long = "imsi")]
+        imsi: String,
+    },
+    /// Temporarily exempt a subscriber from charging and rate limits, e.g. to
+    /// run a speed test during a support call. The subscriber reverts to their
+    /// normal policy once the exemption ends.
+    Exempt {
+        /// The IMSI of the subscriber to exempt.
+        #[structopt(long = "imsi")]
+        imsi: String,
+        /// How long the exemption lasts, e.g. "15m".
+        #[structopt(long = "duration", parse(try_from_str = humantime::parse_duration))]
+        duration: std::time::Duration,
+    },
+    /// Reconstruct a subscriber's balance and policy state at a past time by
+    /// replaying the subscriber journal.
+    History {
+        /// The IMSI of the subscriber to reconstruct.
+        #[structopt(long = "imsi")]
+        imsi: String,
+        /// The time to reconstruct, e.g. "2026-10-01 12:00:00Z". Defaults to
+        /// now.
+        #[structopt(long = "at")]
+        at: Option<humantime::Timestamp>,
+    },
+    /// Check that no subscriber journal entries have been modified or removed.
+    VerifyJournal,
+    /// Move a subscriber to a different plan, converting their remaining
+    /// balance by the configured planChanges rules and applying the new policy
+    /// immediately.
+    ChangePlan {
+        /// The IMSI of the subscriber to move.
+        #[structopt(long = "imsi")]
+        imsi: String,
+        /// The name of the policy applied while the subscriber has a balance.
+        #[structopt(long = "policy")]
+        policy: String,
+        /// The name of the policy applied once their balance runs out. Defaults
+        /// to their current zero balance policy.
+        #[structopt(long = "zero-balance-policy")]
+        zero_balance_policy: Option<String>,
+    },
+    /// Credit a subscriber's balance, or their pool's balance if they draw
+    /// from one. A negative number of bytes debits the balance instead, e.g.
+    /// to correct a mistaken top up.
+    Topup {
+        /// The id of the subscriber to credit.
+        #[structopt(long = "subscriber")]
+        subscriber: i32,
+        /// The number of bytes to credit.
+        #[structopt(long = "bytes", allow_hyphen_values = true)]
+        bytes: i64,
+    },
+    /// Set the policies of a subscriber and apply them immediately. Unlike
+    /// change-plan, the balance is left unchanged.
+    SetPolicy {
+        /// The id of the subscriber to change.
+        #[structopt(long = "subscriber")]
+        subscriber: i32,
+        /// The name of the policy applied while the subscriber has a balance.
+        #[structopt(long = "policy")]
+        policy: String,
+        /// The name of the policy applied once their balance runs out. Defaults
+        /// to their current zero balance policy.
+        #[structopt(long = "zero-balance-policy")]
+        zero_balance_policy: Option<String>,
+    },
+    /// Show a subscriber's balance, policies, and addresses.
+    Show {
+        /// The id of the subscriber to show.
+        subscriber: i32,
+    },
+    /// Manage the keys authenticating requests to haulage's APIs.
+    ApiKey(ApiKeyCommand),
+}
+
+#[derive(Debug, StructOpt)]
+pub enum ApiKeyCommand {
+    /// Create a key and print it. The key cannot be retrieved again.
+    Create {
+        /// A unique name identifying the key's holder, e.g. "kiosk-market".
+        #[structopt(long = "name")]
+        name: String,
+        /// One of read_only, cashier, or admin.
+        #[structopt(long = "role")]
+        role: crate::api_keys::Role,
+    },
+    /// List all keys, including revoked keys.
+    List,
+    /// Revoke a key, immediately rejecting further requests made with it.
+    Revoke {
+        /// The name of the key to revoke.
+        #[structopt(long = "name")]
+        name: String,
+    },
+}
+
+pub async fn run(
+    command: AdminCommand,
+    db_pool: &sqlx::PgPool,
+    plan_change_rules: &[PlanChangeRule],
+    log: &slog::Logger,
+) -> Result<(), AdminError> {
+    match command {
+        AdminCommand::Suspend { imsi } => {
+            let state = set_subscriber_suspended(db_pool, &imsi, true, log).await?;
+            println!("{}", state);
+        }
+        AdminCommand::Resume { imsi } => {
+            let state = set_subscriber_suspended(db_pool, &imsi, false, log).await?;
+            println!("{}", state);
+        }
+        AdminCommand::Exempt { imsi, duration } => {
+            let state = exempt_subscriber(db_pool, &imsi, duration, log).await?;
+            println!("{}", state);
+        }
+        AdminCommand::History { imsi, at } => {
+            let at = at.map_or_else(chrono::Utc::now, |at| {
+                chrono::DateTime::<chrono::Utc>::from(*at)
+            });
+            let state = crate::journal::replay_subscriber(db_pool, &imsi, at)
+                .await?
+                .ok_or(AdminError::UnknownHistory(imsi))?;
+            println!("{}", state);
+        }
+        AdminCommand::VerifyJournal => {
+            let verification = crate::journal::verify(db_pool).await?;
+            println!("{}", verification);
+            if let Some(id) = verification.first_invalid_entry {
+                return Err(AdminError::JournalModified(id));
+            }
+        }
+        AdminCommand::ChangePlan {
+            imsi,
+            policy,
+            zero_balance_policy,
+        } => {
+            let plan = Plan {
+                policy,
+                zero_balance_policy,
+            };
+            let state =
+                change_subscriber_plan(db_pool, &imsi, &plan, plan_change_rules, "admin", log)
+                    .await?;
+            println!("{}", state);
+        }
+        AdminCommand::Topup { subscriber, bytes } => {
+            let imsi = query_imsi(db_pool, subscriber).await?;
+            let state = top_up_subscriber(db_pool, &imsi, bytes, "admin", log).await?;
+            println!("{}", state);
+        }
+        AdminCommand::SetPolicy {
+            subscriber,
+            policy,
+            zero_balance_policy,
+        } => {
+            let imsi = query_imsi(db_pool, subscriber).await?;
+            let plan = Plan {
+                policy,
+                zero_balance_policy,
+            };
+            // Without rules the balance is kept.
+            let state = change_subscriber_plan(db_pool, &imsi, &plan, &[], "admin", log).await?;
+            println!("{}", state);
+        }
+        AdminCommand::Show { subscriber } => {
+            println!("{}", query_subscriber(db_pool, subscriber).await?);
+        }
+        AdminCommand::ApiKey(ApiKeyCommand::Create { name, role }) => {
+            slog::info!(log, "Creating API key"; "name" => &name, "role" => role.as_str());
+            let (api_key, key) = crate::api_keys::create(db_pool, &name, role).await?;
+            println!("{}", api_key);
+            println!("key: {}", key);
+        }
+        AdminCommand::ApiKey(ApiKeyCommand::List) => {
+            for api_key in crate::api_keys::list(db_pool).await? {
+                println!("{}", api_key);
+            }
+        }
+        AdminCommand::ApiKey(ApiKeyCommand::Revoke { name }) => {
+            slog::info!(log, "Revoking API key"; "name" => &name);
+            let api_key = crate::api_keys::revoke(db_pool, &name).await?;
+            println!("{}", api_key);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, sqlx::FromRow, serde::Serialize)]
+pub struct SuspensionState {
+    subscriber_id: i32,
+    imsi: String,
+    suspended: bool,
+}
+impl std::fmt::Display for SuspensionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "subscriber {} (imsi {}) suspended: {}",
+            self.subscriber_id, self.imsi, self.suspended
+        )
+    }
+}
+
+// Sets the administrative suspension state of a subscriber. The enforcer
+// applies the resulting policy change on its next poll of the database.
+pub async fn set_subscriber_suspended(
+    db_pool: &sqlx::PgPool,
+    imsi: &str,
+    suspended: bool,
+    log: &slog::Logger,
+) -> Result<SuspensionState, AdminError> {
+    slog::info!(log, "Setting subscriber suspension"; "imsi" => imsi, "suspended" => suspended);
+    let mut transaction = db_pool.begin().await?;
+
+    let previous_query = r#"
+        SELECT "suspended"
+        FROM subscribers
+        WHERE "imsi" = $1
+        FOR UPDATE
+    "#;
+
+    let previous: Option<(bool,)> = sqlx::query_as(previous_query)
+        .bind(imsi)
+        .fetch_optional(&mut transaction)
+        .await?;
+    let previous = match previous {
+        Some((previous,)) => previous,
+        None => return Err(AdminError::UnknownSubscriber(imsi.to_owned())),
+    };
+
+    let suspend_query = r#"
+        UPDATE subscribers
+        SET "suspended" = $1
+        WHERE "imsi" = $2
+        RETURNING "internal_uid" AS "subscriber_id", "imsi", "suspended"
+    "#;
+
+    let state: SuspensionState = sqlx::query_as(suspend_query)
+        .bind(suspended)
+        .bind(imsi)
+        .fetch_one(&mut transaction)
+        .await?;
+
+    if previous != suspended {
+        let kind = match suspended {
+            true => crate::events::EventKind::Suspended,
+            false => crate::events::EventKind::Resumed,
+        };
+        crate::events::record_event(
+            &mut transaction,
+            state.subscriber_id,
+            kind,
+            serde_json::json!({}),
+        )
+        .await?;
+    }
+
+    transaction.commit().await?;
+    Ok(state)
+}
+
+#[derive(Debug, Clone, sqlx::FromRow, serde::Serialize)]
+pub struct ExemptionState {
+    subscriber_id: i32,
+    imsi: String,
+    exempt_until: chrono::DateTime<chrono::Utc>,
+}
+impl std::fmt::Display for ExemptionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "subscriber {} (imsi {}) exempt until: {}",
+            self.subscriber_id,
+            self.imsi,
+            self.exempt_until.to_rfc3339()
+        )
+    }
+}
+
+// Exempts a subscriber from charging and rate limits for the given duration,
+// replacing any exemption already in progress. The enforcer applies the
+// exempt policy on its next poll of the database, and reverts it on the first
+// poll after the exemption ends.
+pub async fn exempt_subscriber(
+    db_pool: &sqlx::PgPool,
+    imsi: &str,
+    duration: std::time::Duration,
+    log: &slog::Logger,
+) -> Result<ExemptionState, AdminError> {
+    slog::info!(log, "Exempting subscriber"; "imsi" => imsi, "duration" => humantime::format_duration(duration).to_string());
+    let mut transaction = db_pool.begin().await?;
+
+    let exempt_query = r#"
+        UPDATE subscribers
+        SET "exempt_until" = now() + $1
+        WHERE "imsi" = $2
+        RETURNING "internal_uid" AS "subscriber_id", "imsi", "exempt_until"
+    "#;
+
+    let state: Option<ExemptionState> = sqlx::query_as(exempt_query)
+        .bind(
+            sqlx::postgres::types::PgInterval::try_from(duration)
+                .map_err(AdminError::InvalidDuration)?,
+        )
+        .bind(imsi)
+        .fetch_optional(&mut transaction)
+        .await?;
+    let state = state.ok_or_else(|| AdminError::UnknownSubscriber(imsi.to_owned()))?;
+
+    crate::events::record_event(
+        &mut transaction,
+        state.subscriber_id,
+        crate::events::EventKind::Exempted,
+        serde_json::json!({
+            "duration_seconds": duration.as_secs(),
+            "exempt_until": state.exempt_until.to_rfc3339(),
+        }),
+    )
+    .await?;
+
+    transaction.commit().await?;
+    Ok(state)
+}
+
+#[derive(Debug, Clone, sqlx::FromRow, serde::Serialize)]
+pub struct TopUpState {
+    subscriber_id: i32,
+    imsi: String,
+    // The balance the top up was credited to, which is the balance of the
+    // subscriber's pool if they draw from one.
+    data_balance: i64,
+    balance_pool: Option<i32>,
+}
+impl std::fmt::Display for TopUpState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.balance_pool {
+            Some(pool) => write!(
+                f,
+                "subscriber {} (imsi {}) pool {} balance: {}",
+                self.subscriber_id, self.imsi, pool, self.data_balance
+            ),
+            None => write!(
+                f,
+                "subscriber {} (imsi {}) balance: {}",
+                self.subscriber_id, self.imsi, self.data_balance
+            ),
+        }
+    }
+}
+
+// Credits a subscriber's balance, or the balance of their pool if they draw
+// from one. The enforcer applies the resulting policy change on its next poll
+// of the database.
+pub async fn top_up_subscriber(
+    db_pool: &sqlx::PgPool,
+    imsi: &str,
+    bytes: i64,
+    credited_by: &str,
+    log: &slog::Logger,
+) -> Result<TopUpState, AdminError> {
+    slog::info!(log, "Topping up subscriber"; "imsi" => imsi, "bytes" => bytes, "credited_by" => credited_by);
+    let mut transaction = db_pool.begin().await?;
+
+    let top_up_query = r#"
+        WITH target AS (
+            SELECT "internal_uid", "imsi", "balance_pool"
+            FROM subscribers
+            WHERE "imsi" = $1
+            FOR UPDATE
+        ), pool_credit AS (
+            UPDATE balance_pools
+            SET "data_balance" = balance_pools."data_balance" + $2
+            FROM target
+            WHERE balance_pools."id" = target."balance_pool"
+            RETURNING balance_pools."data_balance"
+        ), subscriber_credit AS (
+            UPDATE subscribers
+            SET "data_balance" = COALESCE(subscribers."data_balance", 0) + $2
+            FROM target
+            WHERE subscribers."internal_uid" = target."internal_uid" AND target."balance_pool" IS NULL
+            RETURNING subscribers."data_balance"
+        )
+        SELECT
+            target."internal_uid" AS "subscriber_id",
+            target."imsi",
+            COALESCE((SELECT "data_balance" FROM pool_credit), (SELECT "data_balance" FROM subscriber_credit)) AS "data_balance",
+            target."balance_pool"
+        FROM target
+    "#;
+
+    let state: Option<TopUpState> = sqlx::query_as(top_up_query)
+        .bind(imsi)
+        .bind(bytes)
+        .fetch_optional(&mut transaction)
+        .await?;
+    let state = state.ok_or_else(|| AdminError::UnknownSubscriber(imsi.to_owned()))?;
+
+    crate::events::record_event(
+        &mut transaction,
+        state.subscriber_id,
+        crate::events::EventKind::ToppedUp,
+        serde_json::json!({
+            "bytes": bytes,
+            "credited_by": credited_by,
+            "balance_pool": state.balance_pool,
+        }),
+    )
+    .await?;
+
+    transaction.commit().await?;
+    Ok(state)
+}
+
+// How a subscriber's remaining balance carries over when they change plans.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BalanceRule {
+    Keep,
+    Forfeit,
+    // Converts the balance by a ratio, e.g. of the plans' prices per byte, so
+    // that the value the subscriber paid for is kept.
+    Scale(f64),
+}
+impl BalanceRule {
+    fn apply(&self, balance: i64) -> i64 {
+        match self {
+            BalanceRule::Keep => balance,
+            BalanceRule::Forfeit => 0,
+            BalanceRule::Scale(factor) => (balance.max(0) as f64 * factor).floor() as i64,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            BalanceRule::Keep => "keep",
+            BalanceRule::Forfeit => "forfeit",
+            BalanceRule::Scale(_) => "scale",
+        }
+    }
+}
+
+// The balance rule for changes between the named plans, either of which
+// matches any plan if not given.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlanChangeRule {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub balance: BalanceRule,
+}
+
+// The first rule matching a change between the named plans, or keeping the
+// balance if none match.
+fn balance_rule(rules: &[PlanChangeRule], from: &str, to: &str) -> BalanceRule {
+    let matches = |rule_plan: &Option<String>, plan: &str| {
+        rule_plan
+            .as_deref()
+            .is_none_or(|rule_plan| rule_plan == plan)
+    };
+    rules
+        .iter()
+        .find(|rule| matches(&rule.from, from) && matches(&rule.to, to))
+        .map_or(BalanceRule::Keep, |rule| rule.balance)
+}
+
+// A plan is named by the policy applied while the subscriber has a balance.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan {
+    pub policy: String,
+    pub zero_balance_policy: Option<String>,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow, serde::Serialize)]
+pub struct PlanState {
+    subscriber_id: i32,
+    imsi: String,
+    policy: String,
+    zero_balance_policy: String,
+    // The subscriber's own balance, or None if they draw from a pool, whose
+    // balance is shared and left unchanged.
+    data_balance: Option<i64>,
+    balance_pool: Option<i32>,
+}
+impl std::fmt::Display for PlanState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "subscriber {} (imsi {}) plan: {} (zero balance: {})",
+            self.subscriber_id, self.imsi, self.policy, self.zero_balance_policy
+        )?;
+        match (self.balance_pool, self.data_balance) {
+            (Some(pool), _) => write!(f, " pool: {}", pool),
+            (None, Some(balance)) => write!(f, " balance: {}", balance),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+// Moves a subscriber to a new plan and carries over their balance by the
+// first matching rule, in one transaction. The enforcer is notified to apply
+// the new policy as soon as the change commits.
+pub async fn change_subscriber_plan(
+    db_pool: &sqlx::PgPool,
+    imsi: &str,
+    plan: &Plan,
+    rules: &[PlanChangeRule],
+    changed_by: &str,
+    log: &slog::Logger,
+) -> Result<PlanState, AdminError> {
+    let mut transaction = db_pool.begin().await?;
+
+    let current_query = r#"
+        SELECT
+            subscribers."internal_uid" AS "subscriber_id",
+            subscribers."imsi",
+            positive."name" AS "policy",
+            zero."name" AS "zero_balance_policy",
+            subscribers."data_balance",
+            subscribers."balance_pool"
+        FROM subscribers
+        INNER JOIN access_policies AS positive ON positive."id" = subscribers."positive_balance_policy"
+        INNER JOIN access_policies AS zero ON zero."id" = subscribers."zero_balance_policy"
+        WHERE subscribers."imsi" = $1
+        FOR UPDATE OF subscribers
+    "#;
+    let current: Option<PlanState> = sqlx::query_as(current_query)
+        .bind(imsi)
+        .fetch_optional(&mut transaction)
+        .await?;
+    let current = current.ok_or_else(|| AdminError::UnknownSubscriber(imsi.to_owned()))?;
+
+    let zero_balance_policy = plan
+        .zero_balance_policy
+        .as_deref()
+        .unwrap_or(&current.zero_balance_policy);
+    let policy_id = query_policy_id(&mut transaction, &plan.policy).await?;
+    let zero_balance_policy_id = query_policy_id(&mut transaction, zero_balance_policy).await?;
+
+    let rule = balance_rule(rules, &current.policy, &plan.policy);
+    let data_balance = match current.balance_pool {
+        Some(_) => current.data_balance,
+        None => current.data_balance.map(|balance| rule.apply(balance)),
+    };
+    slog::info!(log, "Changing subscriber plan"; "imsi" => imsi, "from" => &current.policy, "to" => &plan.policy, "balance_rule" => rule.as_str(), "changed_by" => changed_by);
+
+    let change_query = r#"
+        UPDATE subscribers
+        SET "positive_balance_policy" = $2, "zero_balance_policy" = $3, "data_balance" = $4
+        WHERE "internal_uid" = $1
+    "#;
+    sqlx::query(change_query)
+        .bind(current.subscriber_id)
+        .bind(policy_id)
+        .bind(zero_balance_policy_id)
+        .bind(data_balance)
+        .execute(&mut transaction)
+        .await?;
+
+    crate::events::record_event(
+        &mut transaction,
+        current.subscriber_id,
+        crate::events::EventKind::PlanChanged,
+        serde_json::json!({
+            "from": { "policy": &current.policy, "zero_balance_policy": &current.zero_balance_policy },
+            "to": { "policy": &plan.policy, "zero_balance_policy": zero_balance_policy },
+            "balance_rule": rule.as_str(),
+            "previous_balance": current.data_balance,
+            "balance": data_balance,
+            "changed_by": changed_by,
+        }),
+    )
+    .await?;
+
+    // Delivered once the change commits.
+    sqlx::query("SELECT pg_notify($1, $2)")
+        .bind(crate::enforcer::APPLY_POLICY_CHANNEL)
+        .bind(imsi)
+        .execute(&mut transaction)
+        .await?;
+
+    transaction.commit().await?;
+    Ok(PlanState {
+        policy: plan.policy.clone(),
+        zero_balance_policy: zero_balance_policy.to_owned(),
+        data_balance,
+        ..current
+    })
+}
+
+pub async fn query_imsi(db_pool: &sqlx::PgPool, subscriber: i32) -> Result<String, AdminError> {
+    let imsi: Option<(String,)> =
+        sqlx::query_as(r#"SELECT "imsi" FROM subscribers WHERE "internal_uid" = $1"#)
+            .bind(subscriber)
+            .fetch_optional(db_pool)
+            .await?;
+    imsi.map(|(imsi,)| imsi)
+        .ok_or(AdminError::UnknownSubscriberId(subscriber))
+}
+
+#[derive(Debug, Clone, sqlx::FromRow, serde::Serialize)]
+pub struct SubscriberState {
+    pub subscriber_id: i32,
+    pub imsi: String,
+    // The balance the subscriber is limited by, which is the balance of their
+    // pool if they draw from one.
+    pub data_balance: Option<i64>,
+    pub balance_pool: Option<i32>,
+    pub policy: String,
+    pub zero_balance_policy: String,
+    pub current_policy: String,
+    pub suspended: bool,
+    pub exempt_until: Option<chrono::DateTime<chrono::Utc>>,
+    pub addresses: Vec<ipnetwork::IpNetwork>,
+}
+impl std::fmt::Display for SubscriberState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "subscriber {} (imsi {})", self.subscriber_id, self.imsi)?;
+        match self.data_balance {
+            Some(balance) => writeln!(f, "  balance: {}", balance)?,
+            None => writeln!(f, "  balance: none")?,
+        }
+        if let Some(pool) = self.balance_pool {
+            writeln!(f, "  pool: {}", pool)?;
+        }
+        writeln!(
+            f,
+            "  plan: {} (zero balance: {})",
+            self.policy, self.zero_balance_policy
+        )?;
+        writeln!(f, "  current policy: {}", self.current_policy)?;
+        writeln!(f, "  suspended: {}", self.suspended)?;
+        if let Some(exempt_until) = self
+            .exempt_until
+            .filter(|until| *until > chrono::Utc::now())
+        {
+            writeln!(f, "  exempt until: {}", exempt_until.to_rfc3339())?;
+        }
+        let addresses: Vec<String> = self.addresses.iter().map(|ip| ip.to_string()).collect();
+        write!(f, "  addresses: {}", addresses.join(", "))
+    }
+}
+
+// Reads the balance and policy state of a subscriber, as the accounter and
+// enforcer see it.
+pub async fn query_subscriber(
+    db_pool: &sqlx::PgPool,
+    subscriber: i32,
+) -> Result<SubscriberState, AdminError> {
+    query_subscribers(db_pool, Some(subscriber))
+        .await?
+        .pop()
+        .ok_or(AdminError::UnknownSubscriberId(subscriber))
+}
+
+// Reads the state of one subscriber, or of every subscriber if None, in order
+// of subscriber id.
+pub async fn query_subscribers(
+    db_pool: &sqlx::PgPool,
+    subscriber: Option<i32>,
+) -> Result<Vec<SubscriberState>, AdminError> {
+    let subscriber_query = r#"
+        SELECT
+            subscribers."internal_uid" AS "subscriber_id",
+            subscribers."imsi",
+            COALESCE(balance_pools."data_balance", subscribers."data_balance") AS "data_balance",
+            subscribers."balance_pool",
+            positive."name" AS "policy",
+            zero."name" AS "zero_balance_policy",
+            current."name" AS "current_policy",
+            subscribers."suspended",
+            subscribers."exempt_until",
+            ARRAY(SELECT "ip" FROM static_ips WHERE static_ips."imsi" = subscribers."imsi" ORDER BY "ip") AS "addresses"
+        FROM subscribers
+        INNER JOIN access_policies AS positive ON positive."id" = subscribers."positive_balance_policy"
+        INNER JOIN access_policies AS zero ON zero."id" = subscribers."zero_balance_policy"
+        INNER JOIN access_policies AS current ON current."id" = subscribers."current_policy"
+        LEFT JOIN balance_pools ON balance_pools."id" = subscribers."balance_pool"
+        WHERE $1::integer IS NULL OR subscribers."internal_uid" = $1
+        ORDER BY subscribers."internal_uid"
+    "#;
+    Ok(sqlx::query_as(subscriber_query)
+        .bind(subscriber)
+        .fetch_all(db_pool)
+        .await?)
+}
+
+async fn query_policy_id(
+    connection: &mut sqlx::PgConnection,
+    name: &str,
+) -> Result<i32, AdminError> {
+    let id: Option<(i32,)> =
+        sqlx::query_as(r#"SELECT "id" FROM access_policies WHERE "name" = $1"#)
+            .bind(name)
+            .fetch_optional(connection)
+            .await?;
+    id.map(|(id,)| id)
+        .ok_or_else(|| AdminError::UnknownPolicy(name.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_change_balance_rules() {
+        let rules = vec![
+            PlanChangeRule {
+                from: Some(String::from("Basic")),
+                to: Some(String::from("Premium")),
+                balance: BalanceRule::Scale(0.5),
+            },
+            PlanChangeRule {
+                from: None,
+                to: Some(String::from("Basic")),
+                balance: BalanceRule::Forfeit,
+            },
+        ];
+        assert_eq!(
+            balance_rule(&rules, "Basic", "Premium"),
+            BalanceRule::Scale(0.5)
+        );
+        assert_eq!(
+            balance_rule(&rules, "Premium", "Basic"),
+            BalanceRule::Forfeit
+        );
+        assert_eq!(
+            balance_rule(&rules, "Premium", "Unlimited"),
+            BalanceRule::Keep
+        );
+
+        assert_eq!(BalanceRule::Scale(0.5).apply(1_000_001), 500_000);
+        assert_eq!(BalanceRule::Forfeit.apply(1_000_001), 0);
+        assert_eq!(BalanceRule::Keep.apply(1_000_001), 1_000_001);
+    }
+}
@@ -0,0 +1,451 @@
+use std::collections::HashMap;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AirtimeError {
+    #[error("Database operation failed: {0}")]
+    Database(#[from] sqlx::error::Error),
+    #[error("Failed to query the access point: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Command failed: {0}")]
+    Command(String),
+    #[error("Unable to parse neighbor table: {0}")]
+    Parse(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // The hostapd control socket of each access point, e.g.
+    // /var/run/hostapd/wlan0, which is named for its wireless interface.
+    pub hostapd_sockets: Vec<std::path::PathBuf>,
+    // Whether to set each station's airtime weight from the airtime_weight of
+    // its subscriber's access policy.
+    pub apply_weights: bool,
+}
+
+// The counters of one associated station. Airtime is the time the radio spent
+// receiving from and transmitting to the station, in microseconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct StationCounters {
+    rx_bytes: u64,
+    tx_bytes: u64,
+    rx_airtime: u64,
+    tx_airtime: u64,
+}
+impl StationCounters {
+    // Counters restart from zero when a station reassociates, in which case
+    // everything counted since is new.
+    fn since(&self, previous: &StationCounters) -> StationCounters {
+        let delta = |current: u64, previous: u64| match current >= previous {
+            true => current - previous,
+            false => current,
+        };
+        StationCounters {
+            rx_bytes: delta(self.rx_bytes, previous.rx_bytes),
+            tx_bytes: delta(self.tx_bytes, previous.tx_bytes),
+            rx_airtime: delta(self.rx_airtime, previous.rx_airtime),
+            tx_airtime: delta(self.tx_airtime, previous.tx_airtime),
+        }
+    }
+}
+
+// Records the bytes and airtime of each WiFi station, from the hostapd of each
+// access point, on the same schedule as subscriber usage. Stations are
+// resolved to subscribers through the neighbor table of the subscriber
+// interface, so that deployments without a cellular core can see which
+// subscribers use the most of the shared channel. Optionally applies per
+// station airtime weights from the subscribers' policies, so that the
+// driver's airtime fairness scheduler favors the plans that pay for it.
+pub async fn record(
+    settings: Settings,
+    subscriber_interface: crate::netns::Interface,
+    schedule: crate::clock::Schedule,
+    db_pool: std::sync::Arc<sqlx::PgPool>,
+    log: slog::Logger,
+) {
+    let mut start = chrono::Utc::now();
+    let mut previous = read_all_stations(&settings, &log).await;
+    let mut applied_weights: HashMap<pnet_datalink::MacAddr, u16> = HashMap::new();
+
+    let mut timer = tokio::time::interval_at(
+        tokio::time::Instant::now() + schedule.first_delay(start),
+        schedule.period,
+    );
+    loop {
+        timer.tick().await;
+        let end = schedule.boundary(chrono::Utc::now());
+        let current = read_all_stations(&settings, &log).await;
+        let neighbors = match read_neighbors(&subscriber_interface).await {
+            Ok(neighbors) => neighbors,
+            Err(e) => {
+                slog::warn!(log, "Unable to read the neighbor table"; "interface" => subscriber_interface.to_string(), "error" => e.to_string());
+                HashMap::new()
+            }
+        };
+
+        for ((wireless_interface, mac), counters) in &current {
+            let usage = match previous.get(&(wireless_interface.clone(), *mac)) {
+                Some(previous) => counters.since(previous),
+                None => *counters,
+            };
+            let ip = match neighbors.get(mac) {
+                Some(ip) => *ip,
+                None => {
+                    slog::debug!(log, "Station has no known address"; "mac" => mac.to_string());
+                    continue;
+                }
+            };
+
+            let subscriber = match lookup_subscriber(&db_pool, ip).await {
+                Ok(Some(subscriber)) => subscriber,
+                Ok(None) => continue,
+                Err(e) => {
+                    slog::error!(log, "Failed to look up station subscriber"; "ip" => ip.to_string(), "error" => e.to_string());
+                    continue;
+                }
+            };
+            if usage != StationCounters::default() {
+                record_airtime(&db_pool, subscriber.internal_uid, *mac, (start, end), usage)
+                    .await
+                    .unwrap_or_else(|e| slog::error!(log, "Failed to record station airtime"; "mac" => mac.to_string(), "error" => e.to_string()));
+            }
+
+            if let (true, Some(weight)) = (settings.apply_weights, subscriber.airtime_weight) {
+                if applied_weights.get(mac) != Some(&weight) {
+                    match set_airtime_weight(wireless_interface, *mac, weight).await {
+                        Ok(()) => {
+                            slog::info!(log, "Applied station airtime weight"; "mac" => mac.to_string(), "weight" => weight);
+                            applied_weights.insert(*mac, weight);
+                        }
+                        Err(e) => {
+                            slog::warn!(log, "Failed to set station airtime weight"; "mac" => mac.to_string(), "error" => e.to_string());
+                        }
+                    }
+                }
+            }
+        }
+        // Stations are given the default weight again when they reassociate.
+        applied_weights.retain(|mac, _| current.keys().any(|(_, station)| station == mac));
+        previous = current;
+        start = end;
+    }
+}
+
+// Reads the stations of every access point, keyed by wireless interface and
+// station address. Access points that cannot be reached are skipped.
+async fn read_all_stations(
+    settings: &Settings,
+    log: &slog::Logger,
+) -> HashMap<(String, pnet_datalink::MacAddr), StationCounters> {
+    let mut stations = HashMap::new();
+    for socket_path in &settings.hostapd_sockets {
+        let wireless_interface = socket_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut counters = match read_stations(socket_path).await {
+            Ok(counters) => counters,
+            Err(e) => {
+                slog::warn!(log, "Unable to read hostapd stations"; "socket" => socket_path.display().to_string(), "error" => e.to_string());
+                continue;
+            }
+        };
+        // hostapd does not report airtime, which the driver counts per station.
+        match read_station_airtime(&wireless_interface).await {
+            Ok(airtime) => {
+                for (mac, (rx_airtime, tx_airtime)) in airtime {
+                    if let Some(station) = counters.get_mut(&mac) {
+                        station.rx_airtime = rx_airtime;
+                        station.tx_airtime = tx_airtime;
+                    }
+                }
+            }
+            Err(e) => {
+                slog::warn!(log, "Unable to read station airtime"; "interface" => &wireless_interface, "error" => e.to_string());
+            }
+        }
+        for (mac, station) in counters {
+            stations.insert((wireless_interface.clone(), mac), station);
+        }
+    }
+    stations
+}
+
+// Walks the station list of one access point with the STA-FIRST and STA-NEXT
+// control commands.
+async fn read_stations(
+    socket_path: &std::path::Path,
+) -> Result<HashMap<pnet_datalink::MacAddr, StationCounters>, AirtimeError> {
+    // hostapd replies to the address the request came from, so the client
+    // socket must be bound to a path of its own.
+    let local_path = std::env::temp_dir().join(format!(
+        "haulage-hostapd-{}-{}",
+        std::process::id(),
+        socket_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    ));
+    let _ = std::fs::remove_file(&local_path);
+    let socket = tokio::net::UnixDatagram::bind(&local_path)?;
+    let result = walk_stations(&socket, socket_path).await;
+    let _ = std::fs::remove_file(&local_path);
+    result
+}
+
+async fn walk_stations(
+    socket: &tokio::net::UnixDatagram,
+    socket_path: &std::path::Path,
+) -> Result<HashMap<pnet_datalink::MacAddr, StationCounters>, AirtimeError> {
+    socket.connect(socket_path)?;
+    let mut stations = HashMap::new();
+    let mut request = String::from("STA-FIRST");
+    let mut buffer = vec![0; 8192];
+    loop {
+        socket.send(request.as_bytes()).await?;
+        let length =
+            tokio::time::timeout(std::time::Duration::from_secs(2), socket.recv(&mut buffer))
+                .await
+                .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "no reply"))??;
+        let (mac, counters) = match parse_station(&String::from_utf8_lossy(&buffer[..length])) {
+            Some(station) => station,
+            None => break,
+        };
+        // The list is walked by address, so a repeated address is its end.
+        if stations.insert(mac, counters).is_some() {
+            break;
+        }
+        request = format!("STA-NEXT {}", mac);
+    }
+    Ok(stations)
+}
+
+// Parses a station reply, which is the station's address followed by one
+// key=value line per attribute. The end of the list is an empty reply.
+fn parse_station(reply: &str) -> Option<(pnet_datalink::MacAddr, StationCounters)> {
+    let mut lines = reply.lines();
+    let mac = lines.next()?.trim().parse().ok()?;
+    let mut counters = StationCounters::default();
+    for line in lines {
+        let (key, value) = match line.split_once('=') {
+            Some(field) => field,
+            None => continue,
+        };
+        let value = value.trim().parse().unwrap_or(0);
+        match key {
+            "rx_bytes" => counters.rx_bytes = value,
+            "tx_bytes" => counters.tx_bytes = value,
+            _ => {}
+        }
+    }
+    Some((mac, counters))
+}
+
+// Reads the receive and transmit airtime of each station from the driver.
+async fn read_station_airtime(
+    wireless_interface: &str,
+) -> Result<HashMap<pnet_datalink::MacAddr, (u64, u64)>, AirtimeError> {
+    let output = tokio::process::Command::new("iw")
+        .args(["dev", wireless_interface, "station", "dump"])
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(AirtimeError::Command(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+    Ok(parse_station_dump(&String::from_utf8_lossy(&output.stdout)))
+}
+
+fn parse_station_dump(dump: &str) -> HashMap<pnet_datalink::MacAddr, (u64, u64)> {
+    let mut airtime = HashMap::new();
+    let mut station = None;
+    for line in dump.lines() {
+        if let Some(rest) = line.strip_prefix("Station ") {
+            station = rest
+                .split_whitespace()
+                .next()
+                .and_then(|mac| mac.parse::<pnet_datalink::MacAddr>().ok());
+            continue;
+        }
+        let mac = match station {
+            Some(mac) => mac,
+            None => continue,
+        };
+        let (key, value) = match line.trim().split_once(':') {
+            Some(field) => field,
+            None => continue,
+        };
+        let micros = value
+            .split_whitespace()
+            .next()
+            .and_then(|micros| micros.parse::<u64>().ok());
+        let entry: &mut (u64, u64) = airtime.entry(mac).or_default();
+        match (key, micros) {
+            ("rx duration", Some(micros)) => entry.0 = micros,
+            ("tx duration", Some(micros)) => entry.1 = micros,
+            _ => {}
+        }
+    }
+    airtime
+}
+
+// Maps the link layer addresses known on the subscriber interface to their
+// addresses. Stations holding both IPv4 and IPv6 addresses are resolved by
+// whichever is listed last, since either identifies the subscriber.
+async fn read_neighbors(
+    interface: &crate::netns::Interface,
+) -> Result<HashMap<pnet_datalink::MacAddr, std::net::IpAddr>, AirtimeError> {
+    let output = interface
+        .command("ip")
+        .args(["-json", "neigh", "show", "dev", &interface.name])
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(AirtimeError::Command(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+    let neighbors: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout)?;
+    Ok(neighbors
+        .iter()
+        .filter_map(|neighbor| {
+            let ip = neighbor.get("dst")?.as_str()?.parse().ok()?;
+            let mac = neighbor.get("lladdr")?.as_str()?.parse().ok()?;
+            Some((mac, ip))
+        })
+        .collect())
+}
+
+async fn set_airtime_weight(
+    wireless_interface: &str,
+    mac: pnet_datalink::MacAddr,
+    weight: u16,
+) -> Result<(), AirtimeError> {
+    let output = tokio::process::Command::new("iw")
+        .args([
+            "dev",
+            wireless_interface,
+            "station",
+            "set",
+            &mac.to_string(),
+            "airtime_weight",
+            &weight.to_string(),
+        ])
+        .output()
+        .await?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(AirtimeError::Command(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        )),
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct StationSubscriber {
+    internal_uid: i32,
+    airtime_weight: Option<i32>,
+}
+
+struct Subscriber {
+    internal_uid: i32,
+    airtime_weight: Option<u16>,
+}
+
+async fn lookup_subscriber(
+    db_pool: &sqlx::PgPool,
+    ip: std::net::IpAddr,
+) -> Result<Option<Subscriber>, AirtimeError> {
+    let query = r#"
+        SELECT "internal_uid", access_policies."airtime_weight"
+        FROM subscribers
+        INNER JOIN static_ips ON static_ips.imsi = subscribers.imsi
+        LEFT JOIN access_policies ON access_policies.id = subscribers.current_policy
+        WHERE static_ips.ip >>= $1
+    "#;
+    let subscriber: Option<StationSubscriber> = sqlx::query_as(query)
+        .bind(ipnetwork::IpNetwork::from(ip))
+        .fetch_optional(db_pool)
+        .await?;
+    Ok(subscriber.map(|subscriber| Subscriber {
+        internal_uid: subscriber.internal_uid,
+        airtime_weight: subscriber
+            .airtime_weight
+            .and_then(|weight| u16::try_from(weight).ok()),
+    }))
+}
+
+async fn record_airtime(
+    db_pool: &sqlx::PgPool,
+    subscriber: i32,
+    mac: pnet_datalink::MacAddr,
+    (start, end): (chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>),
+    usage: StationCounters,
+) -> Result<(), AirtimeError> {
+    let insert_query = r#"
+        INSERT INTO subscriber_airtime("subscriber", "station", "start_time", "end_time", "rx_bytes", "tx_bytes", "rx_airtime_us", "tx_airtime_us")
+        VALUES ($1, $2::MACADDR, $3, $4, $5, $6, $7, $8)
+    "#;
+    sqlx::query(insert_query)
+        .bind(subscriber)
+        .bind(mac.to_string())
+        .bind(start)
+        .bind(end)
+        .bind(usage.rx_bytes as i64)
+        .bind(usage.tx_bytes as i64)
+        .bind(usage.rx_airtime as i64)
+        .bind(usage.tx_airtime as i64)
+        .execute(db_pool)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet_datalink::MacAddr;
+
+    #[test]
+    fn test_parse_station_stats() {
+        let reply = "\
+aa:bb:cc:dd:ee:01
+flags=[AUTH][ASSOC][AUTHORIZED][WMM]
+aid=1
+rx_packets=1200
+tx_packets=900
+rx_bytes=150000
+tx_bytes=2400000
+connected_time=320
+";
+        let (mac, counters) = parse_station(reply).unwrap();
+        assert_eq!(mac, MacAddr::new(0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0x01));
+        assert_eq!(counters.rx_bytes, 150_000);
+        assert_eq!(counters.tx_bytes, 2_400_000);
+        assert_eq!(parse_station(""), None);
+
+        let dump = "\
+Station aa:bb:cc:dd:ee:01 (on wlan0)
+\tinactive time:\t40 ms
+\trx bytes:\t150000
+\trx duration:\t52000 us
+\ttx duration:\t910000 us
+Station aa:bb:cc:dd:ee:02 (on wlan0)
+\trx duration:\t1000 us
+";
+        let airtime = parse_station_dump(dump);
+        assert_eq!(airtime[&mac], (52_000, 910_000));
+        assert_eq!(
+            airtime[&MacAddr::new(0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0x02)],
+            (1_000, 0)
+        );
+
+        // A reassociated station counts from zero.
+        let earlier = StationCounters {
+            rx_bytes: 200_000,
+            ..counters
+        };
+        assert_eq!(counters.since(&earlier).rx_bytes, 150_000);
+        assert_eq!(counters.since(&earlier).tx_bytes, 0);
+    }
+}
@@ -0,0 +1,187 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ApiKeyError {
+    #[error("Database operation failed: {0}")]
+    DatabaseError(#[from] sqlx::error::Error),
+    #[error("Failed to generate an API key")]
+    KeyGenerationFailed,
+    #[error("No active API key named {0}")]
+    UnknownKey(String),
+}
+
+// The roles granted by API keys, each permitting everything permitted by the
+// roles before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    ReadOnly,
+    Cashier,
+    Admin,
+}
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::ReadOnly => "read_only",
+            Role::Cashier => "cashier",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn permits(&self, required: Role) -> bool {
+        *self >= required
+    }
+}
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read_only" => Ok(Role::ReadOnly),
+            "cashier" => Ok(Role::Cashier),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!(
+                "unknown role {}, expected read_only, cashier, or admin",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ApiKey {
+    pub name: String,
+    pub role: Role,
+    pub created: chrono::DateTime<chrono::Utc>,
+    pub revoked: Option<chrono::DateTime<chrono::Utc>>,
+}
+impl std::fmt::Display for ApiKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({}) created {}",
+            self.name,
+            self.role.as_str(),
+            self.created.to_rfc3339()
+        )?;
+        if let Some(revoked) = self.revoked {
+            write!(f, ", revoked {}", revoked.to_rfc3339())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct ApiKeyRow {
+    name: String,
+    role: String,
+    created: chrono::DateTime<chrono::Utc>,
+    revoked: Option<chrono::DateTime<chrono::Utc>>,
+}
+impl From<ApiKeyRow> for ApiKey {
+    fn from(row: ApiKeyRow) -> ApiKey {
+        ApiKey {
+            name: row.name,
+            // The database constrains roles to the known values.
+            role: row.role.parse().unwrap_or(Role::ReadOnly),
+            created: row.created,
+            revoked: row.revoked,
+        }
+    }
+}
+
+// Keys are random, so a fast unsalted hash is sufficient to keep them from
+// being recovered from the database.
+fn hash_key(key: &str) -> String {
+    ring::digest::digest(&ring::digest::SHA256, key.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Creates a key with the given role, returning the key itself, which cannot be
+// retrieved again.
+pub async fn create(
+    db_pool: &sqlx::PgPool,
+    name: &str,
+    role: Role,
+) -> Result<(ApiKey, String), ApiKeyError> {
+    let mut secret = [0u8; 32];
+    ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut secret)
+        .map_err(|_| ApiKeyError::KeyGenerationFailed)?;
+    let key: String = secret.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    let create_query = r#"
+        INSERT INTO api_keys("name", "role", "key_hash")
+        VALUES ($1, $2, $3)
+        RETURNING "name", "role", "created", "revoked"
+    "#;
+    let row: ApiKeyRow = sqlx::query_as(create_query)
+        .bind(name)
+        .bind(role.as_str())
+        .bind(hash_key(&key))
+        .fetch_one(db_pool)
+        .await?;
+    Ok((row.into(), key))
+}
+
+pub async fn list(db_pool: &sqlx::PgPool) -> Result<Vec<ApiKey>, ApiKeyError> {
+    let rows: Vec<ApiKeyRow> = sqlx::query_as(
+        r#"SELECT "name", "role", "created", "revoked" FROM api_keys ORDER BY "name""#,
+    )
+    .fetch_all(db_pool)
+    .await?;
+    Ok(rows.into_iter().map(ApiKey::from).collect())
+}
+
+// Revokes a key, keeping its record for auditing.
+pub async fn revoke(db_pool: &sqlx::PgPool, name: &str) -> Result<ApiKey, ApiKeyError> {
+    let revoke_query = r#"
+        UPDATE api_keys
+        SET "revoked" = now()
+        WHERE "name" = $1 AND "revoked" IS NULL
+        RETURNING "name", "role", "created", "revoked"
+    "#;
+    let row: Option<ApiKeyRow> = sqlx::query_as(revoke_query)
+        .bind(name)
+        .fetch_optional(db_pool)
+        .await?;
+    row.map(ApiKey::from)
+        .ok_or_else(|| ApiKeyError::UnknownKey(name.to_owned()))
+}
+
+// Returns the active key matching the given key, if any.
+pub async fn authenticate(
+    db_pool: &sqlx::PgPool,
+    key: &str,
+) -> Result<Option<ApiKey>, ApiKeyError> {
+    let authenticate_query = r#"
+        SELECT "name", "role", "created", "revoked"
+        FROM api_keys
+        WHERE "key_hash" = $1 AND "revoked" IS NULL
+    "#;
+    let row: Option<ApiKeyRow> = sqlx::query_as(authenticate_query)
+        .bind(hash_key(key))
+        .fetch_optional(db_pool)
+        .await?;
+    Ok(row.map(ApiKey::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roles_permit_lesser_roles() {
+        assert!(Role::Admin.permits(Role::Cashier));
+        assert!(Role::Cashier.permits(Role::Cashier));
+        assert!(Role::Cashier.permits(Role::ReadOnly));
+        assert!(!Role::Cashier.permits(Role::Admin));
+        assert!(!Role::ReadOnly.permits(Role::Cashier));
+
+        for role in [Role::ReadOnly, Role::Cashier, Role::Admin] {
+            assert_eq!(role.as_str().parse::<Role>(), Ok(role));
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use std::io::Write;
+
+use futures_util::TryStreamExt;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ArchiveError {
+    #[error("Database operation failed: {0}")]
+    DatabaseError(#[from] sqlx::error::Error),
+    #[error("Failed to write archive: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Archive upload failed: {0}")]
+    HttpError(#[from] reqwest::Error),
+    #[error("Object storage rejected the upload: {0}")]
+    Rejected(String),
+    #[error("Invalid object storage endpoint: {0}")]
+    InvalidEndpoint(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // Rows are archived a whole UTC day at a time, once the entire day is
+    // older than this.
+    pub max_age: std::time::Duration,
+    // How often to check for rows old enough to archive.
+    pub interval: std::time::Duration,
+    pub destination: Destination,
+}
+
+#[derive(Debug, Clone)]
+pub enum Destination {
+    Directory(std::path::PathBuf),
+    S3(S3Settings),
+}
+
+// An S3 compatible bucket, e.g. on AWS or a MinIO server. Objects are
+// addressed by path rather than by virtual host, which all compatible
+// services support.
+#[derive(Debug, Clone)]
+pub struct S3Settings {
+    // The base url of the service, e.g. https://s3.us-west-2.amazonaws.com
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    // Prepended to every object key, e.g. "site-a/".
+    pub prefix: String,
+}
+
+// The tables of interval records archived, along with the columns their rows
+// are ordered by within each file. Keeping each subscriber's records together
+// in time order leaves consecutive rows differing only in their counters,
+// which the compression then stores as little more than the differences.
+const ARCHIVED_TABLES: [(&str, &str); 9] = [
+    ("subscriber_usage", r#""subscriber", "start_time""#),
+    ("subscriber_service_usage", r#""subscriber", "start_time""#),
+    (
+        "subscriber_port_usage",
+        r#""subscriber", "start_time", "port_block""#,
+    ),
+    ("subscriber_devices", r#""subscriber", "start_time""#),
+    ("subscriber_drops", r#""subscriber", "start_time""#),
+    ("subscriber_presence", r#""subscriber", "start_time""#),
+    ("wan_usage", r#""interface", "start_time""#),
+    ("destination_usage", r#""start_time", "destination""#),
+    ("flow_logs", r#""start_time", "user_addr""#),
+];
+
+// Periodically moves aged usage records out of the database into gzipped csv
+// files, one per table and day, e.g. subscriber_usage/2026-07-01-1790812800.csv.gz
+// The rows of each file are deleted in the same transaction they are read in,
+// which only commits once the file is written, so records are never lost.
+// Files are named for when they were archived as well as the day they cover,
+// so late records for an archived day land in a file of their own.
+pub async fn run(settings: Settings, db_pool: std::sync::Arc<sqlx::PgPool>, log: slog::Logger) {
+    let http = reqwest::Client::new();
+    let mut timer = tokio::time::interval(settings.interval);
+    loop {
+        timer.tick().await;
+        let cutoff = archive_cutoff(chrono::Utc::now(), settings.max_age);
+        for (table, order) in ARCHIVED_TABLES {
+            match archive_table(&db_pool, &http, &settings.destination, table, order, cutoff).await
+            {
+                Ok(0) => {}
+                Ok(days) => {
+                    slog::info!(log, "Archived usage records"; "table" => table, "days" => days, "before" => cutoff.to_rfc3339())
+                }
+                Err(e) => {
+                    slog::error!(log, "Failed to archive usage records"; "table" => table, "error" => e.to_string())
+                }
+            }
+        }
+    }
+}
+
+// The start of the latest day entirely older than the maximum age.
+fn archive_cutoff(
+    now: chrono::DateTime<chrono::Utc>,
+    max_age: std::time::Duration,
+) -> chrono::DateTime<chrono::Utc> {
+    let max_age = chrono::Duration::from_std(max_age).unwrap_or_else(|_| chrono::Duration::zero());
+    (now - max_age).date().and_hms(0, 0, 0)
+}
+
+fn object_key(
+    table: &str,
+    day: chrono::Date<chrono::Utc>,
+    archived: chrono::DateTime<chrono::Utc>,
+) -> String {
+    format!(
+        "{}/{}-{}.csv.gz",
+        table,
+        day.format("%Y-%m-%d"),
+        archived.timestamp()
+    )
+}
+
+// Archives the table a day at a time, returning the number of days archived.
+async fn archive_table(
+    db_pool: &sqlx::PgPool,
+    http: &reqwest::Client,
+    destination: &Destination,
+    table: &str,
+    order: &str,
+    cutoff: chrono::DateTime<chrono::Utc>,
+) -> Result<usize, ArchiveError> {
+    let mut days = 0;
+    loop {
+        let oldest_query = format!(
+            r#"SELECT MIN("start_time") FROM {} WHERE "start_time" < $1"#,
+            table
+        );
+        let (oldest,): (Option<chrono::DateTime<chrono::Utc>>,) = sqlx::query_as(&oldest_query)
+            .bind(cutoff)
+            .fetch_one(db_pool)
+            .await?;
+        let day = match oldest {
+            Some(oldest) => oldest.date(),
+            None => return Ok(days),
+        };
+        let start = day.and_hms(0, 0, 0);
+        let end = std::cmp::min(start + chrono::Duration::days(1), cutoff);
+
+        // COPY takes no parameters, so the bounds are formatted inline.
+        let copy_statement = format!(
+            r#"
+            COPY (
+                WITH archived AS (
+                    DELETE FROM {}
+                    WHERE "start_time" >= '{}' AND "start_time" < '{}'
+                    RETURNING *
+                )
+                SELECT * FROM archived ORDER BY {}
+            ) TO STDOUT WITH (FORMAT csv, HEADER)
+        "#,
+            table,
+            start.to_rfc3339(),
+            end.to_rfc3339(),
+            order
+        );
+        let mut transaction = db_pool.begin().await?;
+        // Times are archived in UTC whatever the server's time zone.
+        sqlx::query("SET LOCAL TIME ZONE 'UTC'")
+            .execute(&mut transaction)
+            .await?;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        {
+            let mut rows = transaction.copy_out_raw(&copy_statement).await?;
+            while let Some(chunk) = rows.try_next().await? {
+                encoder.write_all(&chunk)?;
+            }
+        }
+        let file = encoder.finish()?;
+
+        let key = object_key(table, day, chrono::Utc::now());
+        match destination {
+            Destination::Directory(directory) => write_file(directory, &key, &file).await?,
+            Destination::S3(s3) => upload_object(http, s3, &key, file).await?,
+        }
+        transaction.commit().await?;
+        days += 1;
+    }
+}
+
+async fn write_file(
+    directory: &std::path::Path,
+    key: &str,
+    file: &[u8],
+) -> Result<(), ArchiveError> {
+    let path = directory.join(key);
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    // Partially written archives are never left under their final name.
+    let partial_path = path.with_extension("gz.partial");
+    let mut partial = tokio::fs::File::create(&partial_path).await?;
+    tokio::io::AsyncWriteExt::write_all(&mut partial, file).await?;
+    partial.sync_all().await?;
+    tokio::fs::rename(&partial_path, &path).await?;
+    Ok(())
+}
+
+async fn upload_object(
+    http: &reqwest::Client,
+    s3: &S3Settings,
+    key: &str,
+    file: Vec<u8>,
+) -> Result<(), ArchiveError> {
+    let path = format!("/{}/{}{}", s3.bucket, s3.prefix, key);
+    let url = reqwest::Url::parse(&s3.endpoint)
+        .and_then(|endpoint| endpoint.join(&path))
+        .map_err(|e| ArchiveError::InvalidEndpoint(e.to_string()))?;
+    let host = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_owned(),
+        (None, _) => return Err(ArchiveError::InvalidEndpoint(s3.endpoint.clone())),
+    };
+
+    let payload_hash = hex(ring::digest::digest(&ring::digest::SHA256, &file).as_ref());
+    let now = chrono::Utc::now();
+    let authorization = sign_request(s3, "PUT", url.path(), &host, &payload_hash, now);
+    let response = http
+        .put(url)
+        .header("x-amz-content-sha256", &payload_hash)
+        .header("x-amz-date", now.format("%Y%m%dT%H%M%SZ").to_string())
+        .header("authorization", authorization)
+        .body(file)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(ArchiveError::Rejected(format!("{}: {}", status, body)));
+    }
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, key);
+    ring::hmac::sign(&key, data.as_bytes()).as_ref().to_vec()
+}
+
+fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let date_key = hmac(format!("AWS4{}", secret_key).as_bytes(), date);
+    let region_key = hmac(&date_key, region);
+    let service_key = hmac(&region_key, service);
+    hmac(&service_key, "aws4_request")
+}
+
+// The authorization header of a request without a query string, signed with
+// AWS signature version 4 over its host, payload hash, and date.
+fn sign_request(
+    s3: &S3Settings,
+    method: &str,
+    path: &str,
+    host: &str,
+    payload_hash: &str,
+    now: chrono::DateTime<chrono::Utc>,
+) -> String {
+    let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+    let canonical_request = format!(
+        "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+        method, path, host, payload_hash, timestamp, signed_headers, payload_hash
+    );
+    let scope = format!("{}/{}/s3/aws4_request", date, s3.region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        timestamp,
+        scope,
+        hex(ring::digest::digest(&ring::digest::SHA256, canonical_request.as_bytes()).as_ref())
+    );
+    let signature = hex(&hmac(
+        &signing_key(&s3.secret_key, &date, &s3.region, "s3"),
+        &string_to_sign,
+    ));
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        s3.access_key, scope, signed_headers, signature
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_archive_naming_and_signing() {
+        let now = chrono::Utc.ymd(2026, 10, 16).and_hms(14, 30, 0);
+        let cutoff = archive_cutoff(now, std::time::Duration::from_secs(90 * 24 * 60 * 60));
+        assert_eq!(cutoff, chrono::Utc.ymd(2026, 7, 18).and_hms(0, 0, 0));
+        assert_eq!(
+            object_key("wan_usage", cutoff.date(), now),
+            "wan_usage/2026-07-18-1792161000.csv.gz"
+        );
+
+        // The example signing key from the AWS signature version 4 docs.
+        assert_eq!(
+            hex(&signing_key(
+                "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+                "20120215",
+                "us-east-1",
+                "iam"
+            )),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+}
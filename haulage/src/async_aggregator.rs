@@ -1,4 +1,4 @@
-use crate::reporter::Reporter;
+use crate::reporter::{NewReporter, Reporter};
 use std::collections::HashMap;
 
 #[derive(Debug)]
@@ -7,16 +7,20 @@ pub struct AsyncAggregator {
 }
 impl AsyncAggregator {
     pub fn new<T>(
-        period: std::time::Duration,
-        db_pool: std::sync::Arc<sqlx::PgPool>,
+        schedule: crate::clock::Schedule,
+        storage: std::sync::Arc<dyn crate::storage::Storage>,
+        usage_writer: tokio::sync::mpsc::Sender<crate::usage_writer::Message>,
+        clock: std::sync::Arc<dyn crate::clock::Clock>,
+        stats: std::sync::Arc<crate::stats::Stats>,
         log: slog::Logger,
     ) -> AsyncAggregator
     where
-        T: Reporter + Send + Sync + Clone + 'static,
+        T: NewReporter + Send + Sync + Clone + 'static,
     {
         let (sender, receiver) = tokio::sync::mpsc::channel(64);
         tokio::task::spawn(async move {
-            aggregate_dispatcher::<T>(receiver, period, db_pool, log).await;
+            aggregate_dispatcher::<T>(receiver, schedule, storage, usage_writer, clock, stats, log)
+                .await;
         });
         AsyncAggregator {
             dispatch_channel: sender,
@@ -32,64 +36,343 @@ pub enum Message {
         id: std::net::IpAddr,
         amount: crate::NetResourceBundle,
     },
+    // Queries the resources aggregated so far in the current, not yet
+    // reported, interval. Returns None if no worker exists for the id.
+    GetTotal {
+        id: std::net::IpAddr,
+        out_channel: tokio::sync::oneshot::Sender<Option<crate::NetResourceBundle>>,
+    },
+    // Moves workers to follow subscriber address changes.
+    Readdress {
+        changes: Vec<crate::address_watcher::AddressChange>,
+    },
+    // Stops the worker of an id, if any, so that a new worker starts with the
+    // next report, e.g. once a subscriber is provisioned for an address whose
+    // worker found none.
+    Forget {
+        id: std::net::IpAddr,
+    },
+    // Asks all workers to look up their report interval again, e.g. after an
+    // operator changes the overrides in the database.
+    ReloadIntervals,
+    // Continues intervals handed off by a previous process.
+    Resume {
+        intervals: Vec<PartialInterval>,
+    },
+    // Stops all workers without reporting, returning their partial intervals
+    // so that a new process can continue them.
+    HandOff {
+        out_channel: tokio::sync::oneshot::Sender<Vec<PartialInterval>>,
+    },
+    // Replies once every report sent before it has been aggregated, e.g. so
+    // that replayed traffic is aggregated before replayed time moves on.
+    Sync {
+        out_channel: tokio::sync::oneshot::Sender<()>,
+    },
+    // Stops all workers, replying once each has reported its partial
+    // interval, e.g. before haulage exits.
+    Flush {
+        out_channel: tokio::sync::oneshot::Sender<()>,
+    },
+    // Subscribes to each report as it arrives, e.g. to stream live usage.
+    WatchUsage {
+        out_channel: tokio::sync::oneshot::Sender<tokio::sync::broadcast::Receiver<UsageUpdate>>,
+    },
 }
 
+// One report of usage by an id, as it arrives at the aggregator.
+#[derive(Debug, Clone)]
+pub struct UsageUpdate {
+    pub id: std::net::IpAddr,
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub amount: crate::NetResourceBundle,
+}
+
+// The usage aggregated so far in an interval not yet reported.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PartialInterval {
+    pub id: std::net::IpAddr,
+    pub start: chrono::DateTime<chrono::Utc>,
+    pub usage: crate::NetResourceBundle,
+}
+
+const MIN_PRUNE_THRESHOLD: usize = 1024;
+
+// Updates held for each watcher before the oldest are skipped, so slow
+// watchers miss usage rather than holding up aggregation.
+const USAGE_UPDATE_BACKLOG: usize = 1024;
+
 async fn aggregate_dispatcher<T>(
     mut chan: tokio::sync::mpsc::Receiver<Message>,
-    period: std::time::Duration,
-    db_pool: std::sync::Arc<sqlx::PgPool>,
+    schedule: crate::clock::Schedule,
+    storage: std::sync::Arc<dyn crate::storage::Storage>,
+    usage_writer: tokio::sync::mpsc::Sender<crate::usage_writer::Message>,
+    clock: std::sync::Arc<dyn crate::clock::Clock>,
+    stats: std::sync::Arc<crate::stats::Stats>,
     log: slog::Logger,
 ) -> ()
 where
-    T: Reporter + Send + Sync + Clone + 'static,
+    T: NewReporter + Send + Sync + Clone + 'static,
 {
     let mut directory: HashMap<std::net::IpAddr, tokio::sync::mpsc::Sender<WorkerMessage>> =
         HashMap::new();
+    // Retired workers are pruned from the directory as it grows, rather than
+    // scanning it for each new worker.
+    let mut prune_threshold = MIN_PRUNE_THRESHOLD;
+    let (usage_updates, _) = tokio::sync::broadcast::channel(USAGE_UPDATE_BACKLOG);
 
     while let Some(message) = chan.recv().await {
         match message {
             Message::Report { id: dest, amount } => {
+                stats.aggregator_reports.increment();
                 slog::debug!(
                     log,
                     "Received at aggregator dispatch {:?} {:?}",
                     dest,
                     amount
                 );
-                if !directory.contains_key(&dest) {
-                    let (worker_chan_send, worker_chan_recv) = tokio::sync::mpsc::channel(32);
-                    let worker_log =
-                        log.new(slog::o!("aggregation" => String::from(format!("{:?}", dest))));
-
-                    let new_reporter = T::new(db_pool.clone(), dest.clone());
-                    directory.insert(dest.clone(), worker_chan_send);
-                    tokio::task::spawn(async move {
-                        aggregate_worker(dest, worker_chan_recv, period, new_reporter, worker_log)
-                            .await;
+                if usage_updates.receiver_count() > 0 {
+                    let _ = usage_updates.send(UsageUpdate {
+                        id: dest,
+                        time: clock.now(),
+                        amount: amount.clone(),
                     });
                 }
-                directory
-                    .get(&dest)
-                    .unwrap()
+                // Idle workers which retired are replaced when their id is
+                // seen again.
+                let retired =
+                    T::RETIRES_WHEN_IDLE && directory.get(&dest).is_some_and(|w| w.is_closed());
+                if retired || !directory.contains_key(&dest) {
+                    if T::RETIRES_WHEN_IDLE && directory.len() >= prune_threshold {
+                        directory.retain(|_, worker| !worker.is_closed());
+                        prune_threshold = (directory.len() * 2).max(MIN_PRUNE_THRESHOLD);
+                    }
+                    directory.insert(
+                        dest,
+                        spawn_worker::<T>(
+                            dest,
+                            schedule,
+                            &storage,
+                            &usage_writer,
+                            &clock,
+                            &stats,
+                            &log,
+                        ),
+                    );
+                }
+                directory[&dest]
                     .send(WorkerMessage::Report { amount: amount })
                     .await
-                    .unwrap_or_else(
-                        |e| slog::error!(log, "Failed to dispatch"; "error" => e.to_string()),
-                    );
+                    .unwrap_or_else(|e| {
+                        stats.aggregator_dispatch_errors.increment();
+                        slog::error!(log, "Failed to dispatch"; "error" => e.to_string())
+                    });
+            }
+            Message::GetTotal { id, out_channel } => match directory.get(&id) {
+                Some(worker_channel) => {
+                    worker_channel
+                        .send(WorkerMessage::GetTotal { out_channel })
+                        .await
+                        .unwrap_or_else(
+                            |e| slog::error!(log, "Failed to dispatch total query"; "error" => e.to_string()),
+                        );
+                }
+                None => {
+                    let _ = out_channel.send(None);
+                }
+            },
+            Message::Readdress { changes } => {
+                for (old, new) in
+                    crate::address_watcher::readdress_workers(&mut directory, &changes)
+                {
+                    slog::info!(log, "Moved aggregation worker"; "old" => old.to_string(), "new" => new.to_string());
+                    directory
+                        .get(&new)
+                        .unwrap()
+                        .send(WorkerMessage::Readdress { id: new })
+                        .await
+                        .unwrap_or_else(
+                            |e| slog::error!(log, "Failed to dispatch readdress"; "error" => e.to_string()),
+                        );
+                }
+            }
+            Message::Forget { id } => {
+                // Dropping the channel retires the worker, which writes out
+                // any partial interval.
+                directory.remove(&id);
+            }
+            Message::ReloadIntervals => {
+                for worker_channel in directory.values() {
+                    worker_channel
+                        .send(WorkerMessage::ReloadInterval)
+                        .await
+                        .unwrap_or_else(
+                            |e| slog::error!(log, "Failed to dispatch interval reload"; "error" => e.to_string()),
+                        );
+                }
+            }
+            Message::Resume { intervals } => {
+                slog::info!(log, "Resuming handed off intervals"; "intervals" => intervals.len());
+                for interval in intervals {
+                    let worker_channel = directory.entry(interval.id).or_insert_with(|| {
+                        spawn_worker::<T>(
+                            interval.id,
+                            schedule,
+                            &storage,
+                            &usage_writer,
+                            &clock,
+                            &stats,
+                            &log,
+                        )
+                    });
+                    worker_channel
+                        .send(WorkerMessage::Resume {
+                            start: interval.start,
+                            amount: interval.usage,
+                        })
+                        .await
+                        .unwrap_or_else(
+                            |e| slog::error!(log, "Failed to dispatch resume"; "error" => e.to_string()),
+                        );
+                }
+            }
+            Message::HandOff { out_channel } => {
+                let mut intervals = Vec::new();
+                for (_, worker_channel) in directory.drain() {
+                    let (interval_tx, interval_rx) = tokio::sync::oneshot::channel();
+                    if worker_channel
+                        .send(WorkerMessage::HandOff {
+                            out_channel: interval_tx,
+                        })
+                        .await
+                        .is_err()
+                    {
+                        continue;
+                    }
+                    if let Ok(Some(interval)) = interval_rx.await {
+                        intervals.push(interval);
+                    }
+                }
+                slog::info!(log, "Handing off partial intervals"; "intervals" => intervals.len());
+                let _ = out_channel.send(intervals);
+                // Further reports could no longer be handed off, so stop
+                // accepting them.
+                break;
+            }
+            Message::Sync { out_channel } => {
+                // Workers handle messages in order, so have aggregated all
+                // earlier reports once they answer a query.
+                let mut queries = Vec::new();
+                for worker_channel in directory.values() {
+                    let (total_tx, total_rx) = tokio::sync::oneshot::channel();
+                    if worker_channel
+                        .send(WorkerMessage::GetTotal {
+                            out_channel: total_tx,
+                        })
+                        .await
+                        .is_ok()
+                    {
+                        queries.push(total_rx);
+                    }
+                }
+                for query in queries {
+                    let _ = query.await;
+                }
+                let _ = out_channel.send(());
+            }
+            Message::Flush { out_channel } => {
+                let mut flushed = Vec::new();
+                for (_, worker_channel) in directory.drain() {
+                    let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+                    if worker_channel
+                        .send(WorkerMessage::Flush { done: done_tx })
+                        .await
+                        .is_ok()
+                    {
+                        flushed.push(done_rx);
+                    }
+                }
+                slog::info!(log, "Flushing partial intervals"; "workers" => flushed.len());
+                // Workers report concurrently, and a worker which exited
+                // early drops its channel, so every wait ends.
+                for done in flushed {
+                    let _ = done.await;
+                }
+                let _ = out_channel.send(());
+                break;
+            }
+            Message::WatchUsage { out_channel } => {
+                let _ = out_channel.send(usage_updates.subscribe());
             }
         };
     }
 }
 
+fn spawn_worker<T>(
+    dest: std::net::IpAddr,
+    schedule: crate::clock::Schedule,
+    storage: &std::sync::Arc<dyn crate::storage::Storage>,
+    usage_writer: &tokio::sync::mpsc::Sender<crate::usage_writer::Message>,
+    clock: &std::sync::Arc<dyn crate::clock::Clock>,
+    stats: &std::sync::Arc<crate::stats::Stats>,
+    log: &slog::Logger,
+) -> tokio::sync::mpsc::Sender<WorkerMessage>
+where
+    T: NewReporter + Send + Sync + Clone + 'static,
+{
+    let (worker_chan_send, worker_chan_recv) = tokio::sync::mpsc::channel(32);
+    let worker_log = log.new(slog::o!("aggregation" => String::from(format!("{:?}", dest))));
+
+    let new_reporter = T::new(storage.clone(), usage_writer.clone(), dest);
+    let worker_clock = std::sync::Arc::clone(clock);
+    let worker_stats = stats.clone();
+    stats.aggregator_workers_started.increment();
+    tokio::task::spawn(async move {
+        aggregate_worker(
+            dest,
+            worker_chan_recv,
+            schedule,
+            new_reporter,
+            worker_clock,
+            worker_stats,
+            worker_log,
+        )
+        .await;
+    });
+    worker_chan_send
+}
+
 #[derive(Debug)]
 enum WorkerMessage {
-    Report { amount: crate::NetResourceBundle },
+    Report {
+        amount: crate::NetResourceBundle,
+    },
+    GetTotal {
+        out_channel: tokio::sync::oneshot::Sender<Option<crate::NetResourceBundle>>,
+    },
+    Readdress {
+        id: std::net::IpAddr,
+    },
+    ReloadInterval,
+    Resume {
+        start: chrono::DateTime<chrono::Utc>,
+        amount: crate::NetResourceBundle,
+    },
+    HandOff {
+        out_channel: tokio::sync::oneshot::Sender<Option<PartialInterval>>,
+    },
+    Flush {
+        done: tokio::sync::oneshot::Sender<()>,
+    },
 }
 
 async fn aggregate_worker<T>(
-    id: std::net::IpAddr,
+    mut id: std::net::IpAddr,
     mut chan: tokio::sync::mpsc::Receiver<WorkerMessage>,
-    period: std::time::Duration,
+    schedule: crate::clock::Schedule,
     mut reporter: T,
+    clock: std::sync::Arc<dyn crate::clock::Clock>,
+    stats: std::sync::Arc<crate::stats::Stats>,
     log: slog::Logger,
 ) -> ()
 where
@@ -101,42 +384,82 @@ where
     // relatively long time durations (minutes) targeted by the software though.
     let mut resources_aggregated = crate::NetResourceBundle::zeroed();
 
-    let interval_start = tokio::time::Instant::now();
-    let mut start_chrono = chrono::Utc::now();
-
-    let mut timer = tokio::time::interval_at(interval_start + period, period);
+    let mut start_chrono = clock.now();
+    // Signalled once the worker has reported everything, if asked to flush.
+    let mut flushed = None;
 
-    match reporter.initialize().await {
-        Ok(_) => {}
+    // Records which could not be reported yet, retried with backoff so that
+    // usage survives the database being briefly unavailable.
+    let mut retry_buffer = crate::reporter::RetryBuffer::default();
+    let mut initialized = match reporter.initialize().await {
+        Ok(_) => true,
+        // The reporter is initialized on a later retry, reporting the records
+        // aggregated in the meantime.
+        Err(crate::reporter::ReportError::DatabaseError(e)) => {
+            slog::warn!(log, "Failed to initialize reporter, retrying"; "id" => id.to_string(), "error" => e.to_string());
+            retry_buffer.back_off();
+            false
+        }
         Err(e) => {
             slog::error!(log, "Failed to initialize reporter"; "id" => id.to_string(), "error" => e.to_string());
             chan.close();
             return;
         }
-    }
+    };
+
+    let default_schedule = schedule;
+    let mut schedule = match initialized {
+        true => reporter_schedule(&reporter, default_schedule, &log).await,
+        false => default_schedule,
+    };
+    let mut timer = crate::clock::Ticker::new(
+        std::sync::Arc::clone(&clock),
+        start_chrono,
+        schedule.first_delay(start_chrono),
+        schedule.period,
+    );
     loop {
+        // Intervals due are reported before handling newer messages, so that
+        // replayed usage is never counted in the interval before its own.
         tokio::select! {
-            _ = timer.tick() => {
-                let tick_time = chrono::Utc::now();
+            biased;
+            due = timer.tick() => {
+                let tick_time = schedule.boundary(due);
                 let record_start = start_chrono;
                 let record_stop = tick_time;
                 let archived_resources = resources_aggregated;
+                let idle = archived_resources == crate::NetResourceBundle::zeroed();
 
                 // Reset the loop state variables for the next interval
                 resources_aggregated = crate::NetResourceBundle::zeroed();
                 start_chrono = tick_time;
 
-                let result = reporter.report(crate::reporter::UseRecord{
+                let dropped = retry_buffer.push(crate::reporter::UseRecord{
                     start: record_start,
                     end: record_stop,
                     usage: archived_resources,
-                }).await;
-                match result {
-                    Ok(_) => {},
-                    Err(e) => {
-                        slog::warn!(log, "Failed to write out report for {} with error {}", id, e);
+                });
+                if let Some(dropped) = dropped {
+                    stats.usage_record_errors.increment();
+                    slog::error!(log, "Dropping unreported usage record"; "id" => id.to_string(), "start" => dropped.start.to_rfc3339());
+                }
+                if retry_buffer.is_due() {
+                    match retry_reports(&mut reporter, &mut initialized, &mut retry_buffer, &stats, &log).await {
+                        Ok(true) => reschedule(&reporter, default_schedule, &mut schedule, &mut timer, &clock, &log).await,
+                        Ok(false) => {}
+                        Err(_) => break,
                     }
                 }
+                if T::RETIRES_WHEN_IDLE && idle && retry_buffer.is_empty() {
+                    break;
+                }
+            }
+            _ = tokio::time::sleep_until(retry_buffer.retry_at().unwrap_or_else(tokio::time::Instant::now)), if retry_buffer.retry_at().is_some() => {
+                match retry_reports(&mut reporter, &mut initialized, &mut retry_buffer, &stats, &log).await {
+                    Ok(true) => reschedule(&reporter, default_schedule, &mut schedule, &mut timer, &clock, &log).await,
+                    Ok(false) => {}
+                    Err(_) => break,
+                }
             }
             message = chan.recv() => {
                 if message.is_none() {
@@ -147,9 +470,587 @@ where
                         resources_aggregated += amount;
                         slog::debug!(log, "Aggregated {:?} bytes", resources_aggregated);
                     }
+                    WorkerMessage::GetTotal{out_channel} => {
+                        if out_channel.send(Some(resources_aggregated.clone())).is_err() {
+                            slog::debug!(log, "Total query requester went away");
+                        }
+                    }
+                    WorkerMessage::Readdress{id: new_id} => {
+                        slog::debug!(log, "Worker readdressed"; "old" => id.to_string(), "new" => new_id.to_string());
+                        id = new_id;
+                    }
+                    WorkerMessage::Resume{start, amount} => {
+                        // The interval began in the previous process, so it
+                        // ends correspondingly sooner.
+                        resources_aggregated += amount;
+                        start_chrono = start.min(start_chrono);
+                        timer = crate::clock::Ticker::new(
+                            std::sync::Arc::clone(&clock),
+                            clock.now(),
+                            schedule.resumed_delay(start_chrono, clock.now()),
+                            schedule.period,
+                        );
+                    }
+                    WorkerMessage::HandOff{out_channel} => {
+                        // Records still awaiting retry are folded into the
+                        // partial interval, so that the next process reports
+                        // their usage.
+                        for record in retry_buffer.drain() {
+                            resources_aggregated += record.usage;
+                            start_chrono = record.start.min(start_chrono);
+                        }
+                        let interval = (resources_aggregated != crate::NetResourceBundle::zeroed()).then_some(PartialInterval {
+                            id,
+                            start: start_chrono,
+                            usage: resources_aggregated,
+                        });
+                        let _ = out_channel.send(interval);
+                        slog::debug!(log, "Handed off worker {}", id);
+                        return;
+                    }
+                    WorkerMessage::Flush{done} => {
+                        flushed = Some(done);
+                        break;
+                    }
+                    WorkerMessage::ReloadInterval => {
+                        if initialized {
+                            reschedule(&reporter, default_schedule, &mut schedule, &mut timer, &clock, &log).await;
+                        }
+                    }
                 }
             }
         };
     }
+
+    // Reports queued before an idle worker stopped are still counted.
+    chan.close();
+    while let Some(message) = chan.recv().await {
+        if let WorkerMessage::Report { amount } = message {
+            resources_aggregated += amount;
+        }
+    }
+
+    // Write out the partial interval when the worker is retired, rather than
+    // losing the usage aggregated so far.
+    if resources_aggregated != crate::NetResourceBundle::zeroed() {
+        retry_buffer.push(crate::reporter::UseRecord {
+            start: start_chrono,
+            end: clock.now(),
+            usage: resources_aggregated,
+        });
+    }
+    if !retry_buffer.is_empty() {
+        let _ = retry_reports(
+            &mut reporter,
+            &mut initialized,
+            &mut retry_buffer,
+            &stats,
+            &log,
+        )
+        .await;
+    }
+    if !retry_buffer.is_empty() {
+        stats.usage_record_errors.add(retry_buffer.len() as u64);
+        slog::error!(log, "Dropping unreported usage records of retired worker"; "id" => id.to_string(), "records" => retry_buffer.len());
+    }
+    if let Some(done) = flushed {
+        let _ = done.send(());
+    }
     slog::debug!(log, "Shutting down worker {}", id);
 }
+
+// Initializes the reporter if a previous attempt failed, then reports the
+// buffered records. Returns whether the reporter was newly initialized, or an
+// error if it never can be, e.g. since the id no longer belongs to a
+// subscriber.
+async fn retry_reports<T>(
+    reporter: &mut T,
+    initialized: &mut bool,
+    retry_buffer: &mut crate::reporter::RetryBuffer,
+    stats: &crate::stats::Stats,
+    log: &slog::Logger,
+) -> Result<bool, crate::reporter::ReportError>
+where
+    T: Reporter + Send + Sync + Clone + 'static,
+{
+    let newly_initialized = !*initialized;
+    if newly_initialized {
+        match reporter.initialize().await {
+            Ok(_) => {
+                slog::info!(log, "Initialized reporter after retrying"; "buffered" => retry_buffer.len());
+                *initialized = true;
+            }
+            Err(e @ crate::reporter::ReportError::DatabaseError(_)) => {
+                slog::warn!(log, "Failed to initialize reporter, retrying"; "buffered" => retry_buffer.len(), "error" => e.to_string());
+                retry_buffer.back_off();
+                return Ok(false);
+            }
+            Err(e) => {
+                slog::error!(log, "Failed to initialize reporter"; "error" => e.to_string());
+                return Err(e);
+            }
+        }
+    }
+    match retry_buffer.flush(reporter).await {
+        Ok(reported) => stats.usage_records_queued.add(reported),
+        Err(e) => {
+            slog::warn!(log, "Failed to write out report, retrying"; "buffered" => retry_buffer.len(), "error" => e.to_string());
+        }
+    }
+    Ok(newly_initialized)
+}
+
+// Looks up the reporter's interval again, restarting the timer if it changed.
+// Usage aggregated so far rolls into the first record of the new schedule.
+async fn reschedule<T>(
+    reporter: &T,
+    default_schedule: crate::clock::Schedule,
+    schedule: &mut crate::clock::Schedule,
+    timer: &mut crate::clock::Ticker,
+    clock: &std::sync::Arc<dyn crate::clock::Clock>,
+    log: &slog::Logger,
+) where
+    T: Reporter + Send + Sync + Clone + 'static,
+{
+    let new_schedule = reporter_schedule(reporter, default_schedule, log).await;
+    if new_schedule != *schedule {
+        slog::info!(log, "Report interval changed"; "interval" => humantime::format_duration(new_schedule.period).to_string());
+        *schedule = new_schedule;
+        *timer = crate::clock::Ticker::new(
+            std::sync::Arc::clone(clock),
+            clock.now(),
+            schedule.first_delay(clock.now()),
+            schedule.period,
+        );
+    }
+}
+
+// Applies the reporter's interval override, if any, to the default schedule.
+// Lookup failures keep the default so that usage is still reported.
+async fn reporter_schedule<T>(
+    reporter: &T,
+    default_schedule: crate::clock::Schedule,
+    log: &slog::Logger,
+) -> crate::clock::Schedule
+where
+    T: Reporter + Send + Sync + Clone + 'static,
+{
+    match reporter.report_interval().await {
+        Ok(Some(period)) if !period.is_zero() => crate::clock::Schedule {
+            period,
+            ..default_schedule
+        },
+        Ok(_) => default_schedule,
+        Err(e) => {
+            slog::warn!(log, "Failed to look up report interval, using the default"; "error" => e.to_string());
+            default_schedule
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[derive(Debug, Clone, Default)]
+    struct RecordingReporter {
+        records: std::sync::Arc<std::sync::Mutex<Vec<crate::reporter::UseRecord>>>,
+        interval: Option<std::time::Duration>,
+    }
+
+    #[async_trait::async_trait]
+    impl Reporter for RecordingReporter {
+        async fn report(
+            &self,
+            use_record: crate::reporter::UseRecord,
+        ) -> Result<(), crate::reporter::ReportError> {
+            self.records.lock().unwrap().push(use_record);
+            Ok(())
+        }
+        async fn initialize(&mut self) -> Result<(), crate::reporter::ReportError> {
+            Ok(())
+        }
+        async fn report_interval(
+            &self,
+        ) -> Result<Option<std::time::Duration>, crate::reporter::ReportError> {
+            Ok(self.interval)
+        }
+    }
+
+    fn make_usage(bytes: i64) -> crate::NetResourceBundle {
+        crate::NetResourceBundle {
+            ran_bytes_up: 0,
+            ran_bytes_down: bytes,
+            wan_bytes_up: 0,
+            wan_bytes_down: bytes,
+            ..crate::NetResourceBundle::zeroed()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_interval_boundaries() {
+        let start = chrono::Utc.ymd(2022, 5, 13).and_hms(23, 0, 0);
+        let clock = std::sync::Arc::new(crate::clock::SimulatedClock::new(start));
+        let reporter = RecordingReporter::default();
+        let (sender, receiver) = tokio::sync::mpsc::channel(32);
+        let worker = tokio::task::spawn(aggregate_worker(
+            "10.45.0.2".parse().unwrap(),
+            receiver,
+            crate::clock::Schedule {
+                period: std::time::Duration::from_secs(60),
+                aligned: false,
+                timezone: crate::clock::SiteTimezone::default(),
+            },
+            reporter.clone(),
+            clock,
+            std::sync::Arc::new(crate::stats::Stats::default()),
+            slog::Logger::root(slog::Discard, slog::o!()),
+        ));
+        let report = |bytes| WorkerMessage::Report {
+            amount: make_usage(bytes),
+        };
+
+        // Usage on either side of the first boundary lands in separate records.
+        sender.send(report(100)).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_secs(30)).await;
+        sender.send(report(50)).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_secs(31)).await;
+        sender.send(report(10)).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+
+        // The partial interval is written out when the worker is retired.
+        sender.send(report(5)).await.unwrap();
+        drop(sender);
+        worker.await.unwrap();
+
+        let records = reporter.records.lock().unwrap();
+        let summary: Vec<_> = records
+            .iter()
+            .map(|record| {
+                (
+                    (record.start - start).num_seconds(),
+                    (record.end - start).num_seconds(),
+                    record.usage.wan_bytes_down,
+                )
+            })
+            .collect();
+        assert_eq!(summary, vec![(0, 60, 150), (60, 120, 10), (120, 121, 5)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_aligned_interval_boundaries() {
+        let start = chrono::Utc.ymd(2022, 5, 13).and_hms(23, 0, 45);
+        let clock = std::sync::Arc::new(crate::clock::SimulatedClock::new(start));
+        let reporter = RecordingReporter::default();
+        let (sender, receiver) = tokio::sync::mpsc::channel(32);
+        let worker = tokio::task::spawn(aggregate_worker(
+            "10.45.0.2".parse().unwrap(),
+            receiver,
+            crate::clock::Schedule {
+                period: std::time::Duration::from_secs(60),
+                aligned: true,
+                timezone: crate::clock::SiteTimezone::default(),
+            },
+            reporter.clone(),
+            clock,
+            std::sync::Arc::new(crate::stats::Stats::default()),
+            slog::Logger::root(slog::Discard, slog::o!()),
+        ));
+
+        // The first record ends at the top of the next minute.
+        sender
+            .send(WorkerMessage::Report {
+                amount: make_usage(100),
+            })
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_secs(20)).await;
+        sender
+            .send(WorkerMessage::Report {
+                amount: make_usage(10),
+            })
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+        drop(sender);
+        worker.await.unwrap();
+
+        let records = reporter.records.lock().unwrap();
+        let summary: Vec<_> = records
+            .iter()
+            .map(|record| {
+                (
+                    record.start.format("%H:%M:%S").to_string(),
+                    record.end.format("%H:%M:%S").to_string(),
+                    record.usage.wan_bytes_down,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (String::from("23:00:45"), String::from("23:01:00"), 100),
+                (String::from("23:01:00"), String::from("23:02:00"), 10),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_report_interval_override() {
+        let start = chrono::Utc.ymd(2022, 5, 13).and_hms(23, 0, 0);
+        let clock = std::sync::Arc::new(crate::clock::SimulatedClock::new(start));
+        let reporter = RecordingReporter {
+            interval: Some(std::time::Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(32);
+        let worker = tokio::task::spawn(aggregate_worker(
+            "10.45.0.2".parse().unwrap(),
+            receiver,
+            crate::clock::Schedule {
+                period: std::time::Duration::from_secs(60),
+                aligned: false,
+                timezone: crate::clock::SiteTimezone::default(),
+            },
+            reporter.clone(),
+            clock,
+            std::sync::Arc::new(crate::stats::Stats::default()),
+            slog::Logger::root(slog::Discard, slog::o!()),
+        ));
+
+        // Records are cut at the overridden interval rather than the default.
+        sender
+            .send(WorkerMessage::Report {
+                amount: make_usage(100),
+            })
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_secs(15)).await;
+        sender
+            .send(WorkerMessage::Report {
+                amount: make_usage(10),
+            })
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+        drop(sender);
+        worker.await.unwrap();
+
+        let records = reporter.records.lock().unwrap();
+        let summary: Vec<_> = records
+            .iter()
+            .map(|record| {
+                (
+                    (record.start - start).num_seconds(),
+                    (record.end - start).num_seconds(),
+                    record.usage.wan_bytes_down,
+                )
+            })
+            .collect();
+        assert_eq!(summary, vec![(0, 10, 100), (10, 20, 10)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_resume_and_hand_off() {
+        let start = chrono::Utc.ymd(2022, 5, 13).and_hms(23, 0, 0);
+        let clock = std::sync::Arc::new(crate::clock::SimulatedClock::new(start));
+        let reporter = RecordingReporter::default();
+        let (sender, receiver) = tokio::sync::mpsc::channel(32);
+        let worker = tokio::task::spawn(aggregate_worker(
+            "10.45.0.2".parse().unwrap(),
+            receiver,
+            crate::clock::Schedule {
+                period: std::time::Duration::from_secs(60),
+                aligned: false,
+                timezone: crate::clock::SiteTimezone::default(),
+            },
+            reporter.clone(),
+            clock,
+            std::sync::Arc::new(crate::stats::Stats::default()),
+            slog::Logger::root(slog::Discard, slog::o!()),
+        ));
+
+        // An interval resumed 20s in ends 40s later, including the usage
+        // aggregated by the previous process.
+        sender
+            .send(WorkerMessage::Resume {
+                start: start - chrono::Duration::seconds(20),
+                amount: make_usage(100),
+            })
+            .await
+            .unwrap();
+        sender
+            .send(WorkerMessage::Report {
+                amount: make_usage(10),
+            })
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_secs(45)).await;
+
+        // Handing off returns the partial interval without reporting it.
+        sender
+            .send(WorkerMessage::Report {
+                amount: make_usage(5),
+            })
+            .await
+            .unwrap();
+        let (out_channel, handed_off) = tokio::sync::oneshot::channel();
+        sender
+            .send(WorkerMessage::HandOff { out_channel })
+            .await
+            .unwrap();
+        let handed_off = handed_off.await.unwrap().unwrap();
+        worker.await.unwrap();
+
+        assert_eq!((handed_off.start - start).num_seconds(), 40);
+        assert_eq!(handed_off.usage.wan_bytes_down, 5);
+        let records = reporter.records.lock().unwrap();
+        let summary: Vec<_> = records
+            .iter()
+            .map(|record| {
+                (
+                    (record.start - start).num_seconds(),
+                    (record.end - start).num_seconds(),
+                    record.usage.wan_bytes_down,
+                )
+            })
+            .collect();
+        assert_eq!(summary, vec![(-20, 40, 110)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flush() {
+        let start = chrono::Utc.ymd(2022, 5, 13).and_hms(23, 0, 0);
+        let clock = std::sync::Arc::new(crate::clock::SimulatedClock::new(start));
+        let reporter = RecordingReporter::default();
+        let (sender, receiver) = tokio::sync::mpsc::channel(32);
+        let worker = tokio::task::spawn(aggregate_worker(
+            "10.45.0.2".parse().unwrap(),
+            receiver,
+            crate::clock::Schedule {
+                period: std::time::Duration::from_secs(60),
+                aligned: false,
+                timezone: crate::clock::SiteTimezone::default(),
+            },
+            reporter.clone(),
+            clock,
+            std::sync::Arc::new(crate::stats::Stats::default()),
+            slog::Logger::root(slog::Discard, slog::o!()),
+        ));
+
+        // The partial interval is reported before the flush completes.
+        sender
+            .send(WorkerMessage::Report {
+                amount: make_usage(100),
+            })
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_secs(20)).await;
+        let (done, flushed) = tokio::sync::oneshot::channel();
+        sender.send(WorkerMessage::Flush { done }).await.unwrap();
+        flushed.await.unwrap();
+        worker.await.unwrap();
+
+        let records = reporter.records.lock().unwrap();
+        let summary: Vec<_> = records
+            .iter()
+            .map(|record| {
+                (
+                    (record.start - start).num_seconds(),
+                    (record.end - start).num_seconds(),
+                    record.usage.wan_bytes_down,
+                )
+            })
+            .collect();
+        assert_eq!(summary, vec![(0, 20, 100)]);
+    }
+
+    #[derive(Debug, Clone, Default)]
+    struct FlakyReporter {
+        recorder: RecordingReporter,
+        // How many more attempts to initialize fail as if the database were
+        // unavailable.
+        failures: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl Reporter for FlakyReporter {
+        async fn report(
+            &self,
+            use_record: crate::reporter::UseRecord,
+        ) -> Result<(), crate::reporter::ReportError> {
+            self.recorder.report(use_record).await
+        }
+        async fn initialize(&mut self) -> Result<(), crate::reporter::ReportError> {
+            let failures = &self.failures;
+            match failures.load(std::sync::atomic::Ordering::SeqCst) {
+                0 => Ok(()),
+                remaining => {
+                    failures.store(remaining - 1, std::sync::atomic::Ordering::SeqCst);
+                    Err(crate::storage::StorageError::from(sqlx::Error::PoolTimedOut).into())
+                }
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_while_database_unavailable() {
+        let start = chrono::Utc.ymd(2022, 5, 13).and_hms(23, 0, 0);
+        let clock = std::sync::Arc::new(crate::clock::SimulatedClock::new(start));
+        let reporter = FlakyReporter {
+            failures: std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(7)),
+            ..Default::default()
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(32);
+        let worker = tokio::task::spawn(aggregate_worker(
+            "10.45.0.2".parse().unwrap(),
+            receiver,
+            crate::clock::Schedule {
+                period: std::time::Duration::from_secs(60),
+                aligned: false,
+                timezone: crate::clock::SiteTimezone::default(),
+            },
+            reporter.clone(),
+            clock,
+            std::sync::Arc::new(crate::stats::Stats::default()),
+            slog::Logger::root(slog::Discard, slog::o!()),
+        ));
+
+        // Records are held while initialization fails, backing off 1s, 2s, 4s
+        // and so on between attempts.
+        sender
+            .send(WorkerMessage::Report {
+                amount: make_usage(100),
+            })
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_secs(61)).await;
+        sender
+            .send(WorkerMessage::Report {
+                amount: make_usage(10),
+            })
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+        assert!(reporter.recorder.records.lock().unwrap().is_empty());
+
+        // The attempt after 127s succeeds and reports both records in order.
+        tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+        drop(sender);
+        worker.await.unwrap();
+
+        let records = reporter.recorder.records.lock().unwrap();
+        let summary: Vec<_> = records
+            .iter()
+            .map(|record| {
+                (
+                    (record.start - start).num_seconds(),
+                    (record.end - start).num_seconds(),
+                    record.usage.wan_bytes_down,
+                )
+            })
+            .collect();
+        assert_eq!(summary, vec![(0, 60, 100), (60, 120, 10)]);
+    }
+}
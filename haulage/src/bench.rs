@@ -0,0 +1,144 @@
+use structopt::StructOpt;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum BenchError {
+    #[error("Database operation failed: {0}")]
+    DatabaseError(#[from] sqlx::error::Error),
+    #[error("At least one subscriber must exist to benchmark against")]
+    NoSubscribers,
+    #[error("Bulk ingestion speedup {measured:.1}x is below the required {required:.1}x")]
+    BelowThreshold { measured: f64, required: f64 },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum BenchCommand {
+    /// Compare writing interval usage records with individual inserts against
+    /// the bulk COPY path. All benchmark writes are rolled back.
+    UsageIngest {
+        /// The number of usage records to write with each method.
+        #[structopt(long = "records", default_value = "10000")]
+        records: usize,
+
+        /// Fail if the bulk path is not at least this many times faster than
+        /// individual inserts.
+        #[structopt(long = "min-speedup")]
+        min_speedup: Option<f64>,
+    },
+}
+
+pub async fn run(
+    command: BenchCommand,
+    db_pool: &sqlx::PgPool,
+    log: &slog::Logger,
+) -> Result<(), BenchError> {
+    match command {
+        BenchCommand::UsageIngest {
+            records,
+            min_speedup,
+        } => bench_usage_ingest(db_pool, records, min_speedup, log).await,
+    }
+}
+
+async fn bench_usage_ingest(
+    db_pool: &sqlx::PgPool,
+    count: usize,
+    min_speedup: Option<f64>,
+    log: &slog::Logger,
+) -> Result<(), BenchError> {
+    let subscriber: Option<(i32,)> =
+        sqlx::query_as(r#"SELECT "internal_uid" FROM subscribers LIMIT 1"#)
+            .fetch_optional(db_pool)
+            .await?;
+    let subscriber = subscriber.ok_or(BenchError::NoSubscribers)?.0;
+    let records = synthetic_records(subscriber, count);
+    slog::info!(log, "Benchmarking usage ingestion"; "subscriber" => subscriber, "records" => count);
+
+    let insert_start = std::time::Instant::now();
+    let mut transaction = db_pool.begin().await?;
+    for (subscriber, record) in &records {
+        sqlx::query(
+            r#"
+            INSERT INTO subscriber_usage("subscriber", "start_time", "end_time", "ran_bytes_up", "ran_bytes_down", "wan_bytes_up", "wan_bytes_down", "tcp_bytes", "udp_bytes", "icmp_bytes", "other_bytes")
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+        "#,
+        )
+        .bind(subscriber)
+        .bind(record.start)
+        .bind(record.end)
+        .bind(record.usage.ran_bytes_up)
+        .bind(record.usage.ran_bytes_down)
+        .bind(record.usage.wan_bytes_up)
+        .bind(record.usage.wan_bytes_down)
+        .bind(record.usage.tcp_bytes)
+        .bind(record.usage.udp_bytes)
+        .bind(record.usage.icmp_bytes)
+        .bind(record.usage.other_bytes)
+        .execute(&mut transaction)
+        .await?;
+    }
+    transaction.rollback().await?;
+    let insert_elapsed = insert_start.elapsed();
+
+    let copy_start = std::time::Instant::now();
+    let mut transaction = db_pool.begin().await?;
+    crate::storage::copy_usage_records(&mut transaction, &records).await?;
+    transaction.rollback().await?;
+    let copy_elapsed = copy_start.elapsed();
+
+    let speedup = insert_elapsed.as_secs_f64() / copy_elapsed.as_secs_f64();
+    println!(
+        "insert: {} records in {:?} ({:.0} records/s)",
+        count,
+        insert_elapsed,
+        count as f64 / insert_elapsed.as_secs_f64()
+    );
+    println!(
+        "copy:   {} records in {:?} ({:.0} records/s)",
+        count,
+        copy_elapsed,
+        count as f64 / copy_elapsed.as_secs_f64()
+    );
+    println!("speedup: {:.1}x", speedup);
+
+    match min_speedup {
+        Some(required) if speedup < required => Err(BenchError::BelowThreshold {
+            measured: speedup,
+            required,
+        }),
+        _ => Ok(()),
+    }
+}
+
+// Generates records with distinct start times so they do not conflict with
+// each other. The benchmark transactions are rolled back, so none of the
+// records are kept.
+fn synthetic_records(subscriber: i32, count: usize) -> Vec<(i32, crate::reporter::UseRecord)> {
+    let base = chrono::Utc::now();
+    (0..count)
+        .map(|i| {
+            let start = base + chrono::Duration::seconds(i as i64);
+            (
+                subscriber,
+                crate::reporter::UseRecord {
+                    start,
+                    end: start + chrono::Duration::seconds(1),
+                    usage: crate::NetResourceBundle {
+                        ran_bytes_up: 1000,
+                        ran_bytes_down: 10000,
+                        wan_bytes_up: 1000,
+                        wan_bytes_down: 10000,
+                        tcp_bytes: 11000,
+                        udp_bytes: 0,
+                        icmp_bytes: 0,
+                        other_bytes: 0,
+                        services: crate::ServiceBytes {
+                            https_bytes: 11000,
+                            ..Default::default()
+                        },
+                    },
+                },
+            )
+        })
+        .collect()
+}
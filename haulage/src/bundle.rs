@@ -0,0 +1,774 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+
+use ring::signature::KeyPair;
+use structopt::StructOpt;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum BundleError {
+    #[error("Database operation failed: {0}")]
+    DatabaseError(#[from] sqlx::error::Error),
+    #[error("Failed to read or write the bundle: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Failed to encode or decode the bundle contents: {0}")]
+    EncodingError(#[from] serde_json::Error),
+    #[error("The signing key at {0} is not a valid Ed25519 key")]
+    InvalidKey(std::path::PathBuf),
+    #[error("Failed to generate a signing key")]
+    KeyGenerationFailed,
+    #[error("The bundle has no {0} entry")]
+    MissingEntry(String),
+    #[error("The bundle from {0} is not signed by a trusted key")]
+    Untrusted(String),
+    #[error("The bundle entry {0} does not match the signed manifest")]
+    DigestMismatch(String),
+    #[error(
+        "Expected a bundle of {expected} for {site}, got a bundle of {kind} for {bundle_site}"
+    )]
+    UnexpectedBundle {
+        expected: BundleKind,
+        site: String,
+        kind: BundleKind,
+        bundle_site: String,
+    },
+    #[error("No access policy found named {0}")]
+    UnknownPolicy(String),
+    #[error("Failed to apply a credit: {0}")]
+    CreditFailed(#[from] crate::adjust::AdjustError),
+}
+
+/// Exchange signed bundles of accounting data with sites that cannot reach
+/// the central database, e.g. by carrying them on a USB stick, and exit.
+/// Sites export usage, balances, and subscriber events for the central office
+/// to merge, and receive subscriber, policy, and balance updates back.
+#[derive(Debug, StructOpt)]
+pub enum BundleCommand {
+    /// Generate a key for signing bundles, readable only by haulage, and print
+    /// its public key for the receiving side to trust.
+    Keygen {
+        /// Where to write the key. An existing key is never overwritten.
+        #[structopt(long = "key")]
+        key: std::path::PathBuf,
+    },
+    /// At a site, export the usage, balances, and subscriber events recorded
+    /// since the last export.
+    Export {
+        /// The name of this site, as known to the central office.
+        #[structopt(long = "site")]
+        site: String,
+        /// The key signing the bundle.
+        #[structopt(long = "key")]
+        key: std::path::PathBuf,
+        /// Where to write the bundle.
+        #[structopt(long = "output")]
+        output: std::path::PathBuf,
+        /// How far before the end of the last export to export again, to pick
+        /// up records written late.
+        #[structopt(long = "lookback", default_value = "1h", parse(try_from_str = humantime::parse_duration))]
+        lookback: std::time::Duration,
+        /// Export everything rather than only what is new since the last
+        /// export, e.g. to replace a lost bundle.
+        #[structopt(long = "full")]
+        full: bool,
+    },
+    /// At the central office, verify a site's bundle and merge it as `haulage
+    /// merge` would. Bundles can be imported again without double counting.
+    Import {
+        /// A site's public key, as name=key. Can be given multiple times.
+        #[structopt(long = "trust", required = true, number_of_values = 1)]
+        trust: Vec<TrustedKey>,
+        /// The bundle to import.
+        bundle: std::path::PathBuf,
+    },
+    /// At the central office, export subscribers, their policies and static
+    /// IPs, and the balance adjustments not yet delivered to another site, for
+    /// the given site.
+    ExportUpdates {
+        /// The name of the site receiving the bundle.
+        #[structopt(long = "site")]
+        site: String,
+        /// The key signing the bundle.
+        #[structopt(long = "key")]
+        key: std::path::PathBuf,
+        /// Where to write the bundle.
+        #[structopt(long = "output")]
+        output: std::path::PathBuf,
+    },
+    /// At a site, verify an updates bundle from the central office and apply
+    /// it. Each balance adjustment is applied once however often the bundle
+    /// is imported.
+    ImportUpdates {
+        /// The name of this site, as known to the central office.
+        #[structopt(long = "site")]
+        site: String,
+        /// The central office's public key.
+        #[structopt(long = "public-key")]
+        public_key: String,
+        /// The bundle to import.
+        bundle: std::path::PathBuf,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrustedKey {
+    site: String,
+    key: Vec<u8>,
+}
+impl std::str::FromStr for TrustedKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((site, key)) if !site.is_empty() => Ok(TrustedKey {
+                site: site.to_owned(),
+                key: parse_hex(key).ok_or_else(|| format!("invalid public key for {}", site))?,
+            }),
+            _ => Err(format!("expected name=key, got {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BundleKind {
+    // Accounting data from a site for the central office.
+    Usage,
+    // Subscriber changes from the central office for a site.
+    Updates,
+}
+impl std::fmt::Display for BundleKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BundleKind::Usage => f.write_str("usage"),
+            BundleKind::Updates => f.write_str("updates"),
+        }
+    }
+}
+
+const MANIFEST: &str = "manifest.json";
+const SIGNATURE: &str = "manifest.sig";
+
+// Describes a bundle's entries by their digest, so that signing the manifest
+// signs the whole bundle.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+struct Manifest {
+    kind: BundleKind,
+    // The site exporting a usage bundle, or receiving an updates bundle.
+    site: String,
+    created: chrono::DateTime<chrono::Utc>,
+    // The hex SHA-256 digest of each entry by name.
+    entries: BTreeMap<String, String>,
+}
+
+#[derive(Debug)]
+struct Bundle {
+    manifest: Manifest,
+    entries: BTreeMap<String, Vec<u8>>,
+}
+impl Bundle {
+    fn entry<T: serde::de::DeserializeOwned>(&self, name: &str) -> Result<T, BundleError> {
+        let entry = self
+            .entries
+            .get(name)
+            .ok_or_else(|| BundleError::MissingEntry(name.to_owned()))?;
+        Ok(serde_json::from_slice(entry)?)
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, sqlx::FromRow)]
+struct Event {
+    id: i64,
+    time: chrono::DateTime<chrono::Utc>,
+    imsi: String,
+    kind: String,
+    details: serde_json::Value,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, sqlx::FromRow)]
+struct SubscriberUpdate {
+    imsi: String,
+    // Only given to subscribers new to the site, whose balances otherwise
+    // change only through credits.
+    data_balance: Option<i64>,
+    positive_balance_policy: String,
+    zero_balance_policy: String,
+    suspended: bool,
+    ips: Vec<ipnetwork::IpNetwork>,
+}
+
+// A balance adjustment made at the central office, e.g. a top up sold there.
+#[derive(Debug, serde::Serialize, serde::Deserialize, sqlx::FromRow)]
+struct Credit {
+    id: i64,
+    imsi: String,
+    bytes: i64,
+    operator: String,
+    reason: String,
+}
+
+pub async fn run(
+    command: BundleCommand,
+    db_pool: &sqlx::PgPool,
+    log: &slog::Logger,
+) -> Result<(), BundleError> {
+    match command {
+        BundleCommand::Keygen { key } => {
+            let key_pair = create_key(&key)?;
+            println!("{}", hex(key_pair.public_key().as_ref()));
+        }
+        BundleCommand::Export {
+            site,
+            key,
+            output,
+            lookback,
+            full,
+        } => {
+            let key_pair = load_key(&key)?;
+            export_usage(db_pool, &site, &key_pair, &output, lookback, full, log).await?;
+        }
+        BundleCommand::Import { trust, bundle } => {
+            let bundle = open(&std::fs::read(&bundle)?, |site| {
+                trust
+                    .iter()
+                    .find(|trusted| trusted.site == site)
+                    .map(|trusted| trusted.key.clone())
+            })?;
+            import_usage(db_pool, &bundle, log).await?;
+        }
+        BundleCommand::ExportUpdates { site, key, output } => {
+            let key_pair = load_key(&key)?;
+            export_updates(db_pool, &site, &key_pair, &output, log).await?;
+        }
+        BundleCommand::ImportUpdates {
+            site,
+            public_key,
+            bundle,
+        } => {
+            let public_key = parse_hex(&public_key)
+                .ok_or_else(|| BundleError::Untrusted("the central office".to_owned()))?;
+            let bundle = open(&std::fs::read(&bundle)?, |_| Some(public_key))?;
+            expect(&bundle, BundleKind::Updates, &site)?;
+            import_updates(db_pool, &bundle, log).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn export_usage(
+    db_pool: &sqlx::PgPool,
+    site: &str,
+    key_pair: &ring::signature::Ed25519KeyPair,
+    output: &std::path::Path,
+    lookback: std::time::Duration,
+    full: bool,
+    log: &slog::Logger,
+) -> Result<(), BundleError> {
+    let previous: Option<(Option<chrono::DateTime<chrono::Utc>>, Option<i64>)> = sqlx::query_as(
+        r#"SELECT "usage_watermark", "event_watermark" FROM bundle_exports ORDER BY "id" DESC LIMIT 1"#,
+    )
+    .fetch_optional(db_pool)
+    .await?;
+    let (usage_watermark, event_watermark) = match full {
+        true => (None, None),
+        false => previous.unwrap_or((None, None)),
+    };
+    let lookback =
+        chrono::Duration::from_std(lookback).unwrap_or_else(|_| chrono::Duration::max_value());
+    let since = usage_watermark
+        .and_then(|watermark| watermark.checked_sub_signed(lookback))
+        .unwrap_or_else(|| chrono::DateTime::<chrono::Utc>::from(std::time::UNIX_EPOCH));
+
+    let usage = crate::merge::query_usage(db_pool, since).await?;
+    let balances = crate::merge::query_balances(db_pool, site).await?;
+    let events: Vec<Event> = sqlx::query_as(
+        r#"SELECT "id", "time", "imsi", "kind", "details" FROM subscriber_events WHERE "id" > $1 ORDER BY "id""#,
+    )
+    .bind(event_watermark.unwrap_or(0))
+    .fetch_all(db_pool)
+    .await?;
+
+    let mut entries = BTreeMap::new();
+    entries.insert("usage.json".to_owned(), serde_json::to_vec(&usage)?);
+    entries.insert("balances.json".to_owned(), serde_json::to_vec(&balances)?);
+    entries.insert("events.json".to_owned(), serde_json::to_vec(&events)?);
+    std::fs::write(output, seal(BundleKind::Usage, site, entries, key_pair)?)?;
+
+    // Recorded only once the bundle is written, and never moving backwards, so
+    // that a failed or full export leaves the next export complete.
+    sqlx::query(
+        r#"
+        INSERT INTO bundle_exports("usage_watermark", "event_watermark")
+        SELECT
+            GREATEST($1, (SELECT "usage_watermark" FROM bundle_exports ORDER BY "id" DESC LIMIT 1)),
+            GREATEST($2, (SELECT "event_watermark" FROM bundle_exports ORDER BY "id" DESC LIMIT 1))
+    "#,
+    )
+    .bind(usage.iter().map(|record| record.end_time).max())
+    .bind(events.last().map(|event| event.id))
+    .execute(db_pool)
+    .await?;
+
+    slog::info!(log, "Exported bundle"; "output" => output.display().to_string(), "usage_records" => usage.len(), "balances" => balances.len(), "events" => events.len());
+    println!(
+        "{}: {} usage records, {} balances, {} events",
+        output.display(),
+        usage.len(),
+        balances.len(),
+        events.len()
+    );
+    Ok(())
+}
+
+async fn import_usage(
+    db_pool: &sqlx::PgPool,
+    bundle: &Bundle,
+    log: &slog::Logger,
+) -> Result<(), BundleError> {
+    let site = bundle.manifest.site.clone();
+    expect(bundle, BundleKind::Usage, &site)?;
+    let usage: Vec<crate::merge::UsageRecord> = bundle.entry("usage.json")?;
+    let mut balances: Vec<crate::merge::SiteBalance> = bundle.entry("balances.json")?;
+    let events: Vec<Event> = bundle.entry("events.json")?;
+
+    // The signature vouches for the site, so balances are attributed to it
+    // whatever they claim.
+    for balance in &mut balances {
+        balance.site = site.clone();
+    }
+
+    let mut transaction = db_pool.begin().await?;
+    let balances_changed =
+        crate::merge::apply_site(&mut transaction, &site, &usage, &balances).await?;
+    let mut events_added = 0;
+    for event in &events {
+        let result = sqlx::query(
+            r#"
+            INSERT INTO merged_subscriber_events("site", "id", "time", "imsi", "kind", "details")
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT ("site", "id") DO NOTHING
+        "#,
+        )
+        .bind(&site)
+        .bind(event.id)
+        .bind(event.time)
+        .bind(&event.imsi)
+        .bind(&event.kind)
+        .bind(&event.details)
+        .execute(&mut transaction)
+        .await?;
+        events_added += result.rows_affected();
+    }
+    transaction.commit().await?;
+
+    slog::info!(log, "Imported bundle"; "site" => &site, "created" => bundle.manifest.created.to_rfc3339(), "usage_records" => usage.len(), "balances_changed" => balances_changed, "events" => events_added);
+    println!(
+        "{}: {} usage records, {} balances ({} changed), {} new events",
+        site,
+        usage.len(),
+        balances.len(),
+        balances_changed,
+        events_added
+    );
+    Ok(())
+}
+
+async fn export_updates(
+    db_pool: &sqlx::PgPool,
+    site: &str,
+    key_pair: &ring::signature::Ed25519KeyPair,
+    output: &std::path::Path,
+    log: &slog::Logger,
+) -> Result<(), BundleError> {
+    let subscriber_query = r#"
+        SELECT
+            subscribers."imsi",
+            subscribers."data_balance",
+            positive."name" AS "positive_balance_policy",
+            zero."name" AS "zero_balance_policy",
+            subscribers."suspended",
+            ARRAY(SELECT "ip" FROM static_ips WHERE static_ips."imsi" = subscribers."imsi" ORDER BY "ip") AS "ips"
+        FROM subscribers
+        INNER JOIN access_policies positive ON positive."id" = subscribers."positive_balance_policy"
+        INNER JOIN access_policies zero ON zero."id" = subscribers."zero_balance_policy"
+        ORDER BY subscribers."imsi"
+    "#;
+    let subscribers: Vec<SubscriberUpdate> =
+        sqlx::query_as(subscriber_query).fetch_all(db_pool).await?;
+
+    // Credits go to the site last reporting the subscriber's balance, and stay
+    // with the first site they are bundled for so that they are never applied
+    // twice.
+    let mut transaction = db_pool.begin().await?;
+    let credit_query = r#"
+        SELECT "id", "imsi", "bytes", "operator", "reason"
+        FROM balance_adjustments
+        LEFT JOIN bundled_credits ON bundled_credits."adjustment" = balance_adjustments."id"
+        LEFT JOIN merged_balances USING ("imsi")
+        WHERE CASE
+            WHEN bundled_credits."site" IS NOT NULL THEN bundled_credits."site" = $1
+            ELSE merged_balances."site" IS NULL OR merged_balances."site" = $1
+        END
+        ORDER BY "id"
+    "#;
+    let credits: Vec<Credit> = sqlx::query_as(credit_query)
+        .bind(site)
+        .fetch_all(&mut transaction)
+        .await?;
+    for credit in &credits {
+        sqlx::query(
+            r#"INSERT INTO bundled_credits("adjustment", "site") VALUES ($1, $2) ON CONFLICT DO NOTHING"#,
+        )
+        .bind(credit.id)
+        .bind(site)
+        .execute(&mut transaction)
+        .await?;
+    }
+
+    let mut entries = BTreeMap::new();
+    entries.insert(
+        "subscribers.json".to_owned(),
+        serde_json::to_vec(&subscribers)?,
+    );
+    entries.insert("credits.json".to_owned(), serde_json::to_vec(&credits)?);
+    std::fs::write(output, seal(BundleKind::Updates, site, entries, key_pair)?)?;
+    transaction.commit().await?;
+
+    slog::info!(log, "Exported updates"; "site" => site, "output" => output.display().to_string(), "subscribers" => subscribers.len(), "credits" => credits.len());
+    println!(
+        "{}: {} subscribers, {} credits",
+        output.display(),
+        subscribers.len(),
+        credits.len()
+    );
+    Ok(())
+}
+
+async fn import_updates(
+    db_pool: &sqlx::PgPool,
+    bundle: &Bundle,
+    log: &slog::Logger,
+) -> Result<(), BundleError> {
+    let subscribers: Vec<SubscriberUpdate> = bundle.entry("subscribers.json")?;
+    let credits: Vec<Credit> = bundle.entry("credits.json")?;
+
+    let mut transaction = db_pool.begin().await?;
+    let policies: Vec<(String, i32)> =
+        sqlx::query_as(r#"SELECT "name", "id" FROM access_policies"#)
+            .fetch_all(&mut transaction)
+            .await?;
+    let policies: std::collections::HashMap<String, i32> = policies.into_iter().collect();
+    let policy_id = |name: &str| {
+        policies
+            .get(name)
+            .copied()
+            .ok_or_else(|| BundleError::UnknownPolicy(name.to_owned()))
+    };
+
+    // The current policy of new subscribers is left for the enforcer to bring
+    // up to date.
+    let mut new_subscribers = std::collections::HashSet::new();
+    for subscriber in &subscribers {
+        let (inserted,): (bool,) = sqlx::query_as(
+            r#"
+            INSERT INTO subscribers("imsi", "data_balance", "positive_balance_policy", "zero_balance_policy", "current_policy", "suspended")
+            VALUES ($1, $2, $3, $4, $4, $5)
+            ON CONFLICT ("imsi") DO UPDATE SET
+                "positive_balance_policy" = EXCLUDED."positive_balance_policy",
+                "zero_balance_policy" = EXCLUDED."zero_balance_policy",
+                "suspended" = EXCLUDED."suspended"
+            RETURNING (xmax = 0)
+        "#,
+        )
+        .bind(&subscriber.imsi)
+        .bind(subscriber.data_balance)
+        .bind(policy_id(&subscriber.positive_balance_policy)?)
+        .bind(policy_id(&subscriber.zero_balance_policy)?)
+        .bind(subscriber.suspended)
+        .fetch_one(&mut transaction)
+        .await?;
+        if inserted {
+            new_subscribers.insert(subscriber.imsi.as_str());
+        }
+        for ip in &subscriber.ips {
+            sqlx::query(
+                r#"
+                INSERT INTO static_ips("ip", "imsi") VALUES ($1, $2)
+                ON CONFLICT ("ip") DO UPDATE SET "imsi" = EXCLUDED."imsi", "leased" = false
+            "#,
+            )
+            .bind(ip)
+            .bind(&subscriber.imsi)
+            .execute(&mut transaction)
+            .await?;
+        }
+    }
+
+    let mut credits_applied = 0;
+    for credit in &credits {
+        let imported: Option<(i64,)> =
+            sqlx::query_as(r#"SELECT "id" FROM imported_credits WHERE "id" = $1"#)
+                .bind(credit.id)
+                .fetch_optional(&mut transaction)
+                .await?;
+        if imported.is_some() {
+            continue;
+        }
+        // The balance of subscribers new to the site already includes their
+        // credits.
+        if new_subscribers.contains(credit.imsi.as_str()) {
+            sqlx::query(r#"INSERT INTO imported_credits("id") VALUES ($1)"#)
+                .bind(credit.id)
+                .execute(&mut transaction)
+                .await?;
+            continue;
+        }
+        let reason = format!("central adjustment {}: {}", credit.id, credit.reason);
+        let adjustment = crate::adjust::apply_balance_adjustment(
+            &mut transaction,
+            &credit.imsi,
+            credit.bytes,
+            &credit.operator,
+            &reason,
+        )
+        .await?;
+        sqlx::query(r#"INSERT INTO imported_credits("id", "adjustment") VALUES ($1, $2)"#)
+            .bind(credit.id)
+            .bind(adjustment.id())
+            .execute(&mut transaction)
+            .await?;
+        credits_applied += 1;
+    }
+
+    // Delivered once the changes commit.
+    for subscriber in &subscribers {
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(crate::enforcer::APPLY_POLICY_CHANNEL)
+            .bind(&subscriber.imsi)
+            .execute(&mut transaction)
+            .await?;
+    }
+    transaction.commit().await?;
+
+    slog::info!(log, "Imported updates"; "created" => bundle.manifest.created.to_rfc3339(), "subscribers" => subscribers.len(), "credits_applied" => credits_applied);
+    println!(
+        "{} subscribers, {} of {} credits applied",
+        subscribers.len(),
+        credits_applied,
+        credits.len()
+    );
+    Ok(())
+}
+
+fn expect(bundle: &Bundle, kind: BundleKind, site: &str) -> Result<(), BundleError> {
+    if bundle.manifest.kind != kind || bundle.manifest.site != site {
+        return Err(BundleError::UnexpectedBundle {
+            expected: kind,
+            site: site.to_owned(),
+            kind: bundle.manifest.kind,
+            bundle_site: bundle.manifest.site.clone(),
+        });
+    }
+    Ok(())
+}
+
+// Packs the entries into a gzipped tarball alongside a manifest of their
+// digests and the manifest's signature.
+fn seal(
+    kind: BundleKind,
+    site: &str,
+    entries: BTreeMap<String, Vec<u8>>,
+    key_pair: &ring::signature::Ed25519KeyPair,
+) -> Result<Vec<u8>, BundleError> {
+    let manifest = Manifest {
+        kind,
+        site: site.to_owned(),
+        created: chrono::Utc::now(),
+        entries: entries
+            .iter()
+            .map(|(name, contents)| (name.clone(), digest(contents)))
+            .collect(),
+    };
+    let manifest = serde_json::to_vec_pretty(&manifest)?;
+    let signature = hex(key_pair.sign(&manifest).as_ref());
+
+    let mut archive = tar::Builder::new(flate2::write::GzEncoder::new(
+        Vec::new(),
+        flate2::Compression::default(),
+    ));
+    let files = [(MANIFEST, &manifest), (SIGNATURE, &signature.into_bytes())];
+    for (name, contents) in files.into_iter().chain(
+        entries
+            .iter()
+            .map(|(name, contents)| (name.as_str(), contents)),
+    ) {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o600);
+        header.set_mtime(chrono::Utc::now().timestamp().max(0) as u64);
+        header.set_cksum();
+        archive.append_data(&mut header, name, contents.as_slice())?;
+    }
+    let mut encoder = archive.into_inner()?;
+    encoder.flush()?;
+    Ok(encoder.finish()?)
+}
+
+// Unpacks a bundle, checking that its manifest is signed by the key trusted for
+// the site it names and that every entry matches the manifest. Entries not in
+// the manifest are ignored.
+fn open(
+    data: &[u8],
+    trusted_key: impl FnOnce(&str) -> Option<Vec<u8>>,
+) -> Result<Bundle, BundleError> {
+    let mut files = BTreeMap::new();
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(data));
+    for file in archive.entries()? {
+        let mut file = file?;
+        let name = file.path()?.to_string_lossy().into_owned();
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        files.insert(name, contents);
+    }
+
+    let manifest_bytes = files
+        .remove(MANIFEST)
+        .ok_or_else(|| BundleError::MissingEntry(MANIFEST.to_owned()))?;
+    let signature = files
+        .remove(SIGNATURE)
+        .ok_or_else(|| BundleError::MissingEntry(SIGNATURE.to_owned()))?;
+    let manifest: Manifest = serde_json::from_slice(&manifest_bytes)?;
+
+    let verified = trusted_key(&manifest.site)
+        .zip(std::str::from_utf8(&signature).ok().and_then(parse_hex))
+        .map(|(key, signature)| {
+            ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, key)
+                .verify(&manifest_bytes, &signature)
+                .is_ok()
+        })
+        .unwrap_or(false);
+    if !verified {
+        return Err(BundleError::Untrusted(manifest.site));
+    }
+
+    let mut entries = BTreeMap::new();
+    for (name, expected) in &manifest.entries {
+        let contents = files
+            .remove(name)
+            .ok_or_else(|| BundleError::MissingEntry(name.clone()))?;
+        if &digest(&contents) != expected {
+            return Err(BundleError::DigestMismatch(name.clone()));
+        }
+        entries.insert(name.clone(), contents);
+    }
+    Ok(Bundle { manifest, entries })
+}
+
+fn create_key(path: &std::path::Path) -> Result<ring::signature::Ed25519KeyPair, BundleError> {
+    let pkcs8 = ring::signature::Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new())
+        .map_err(|_| BundleError::KeyGenerationFailed)?;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(pkcs8.as_ref())?;
+    load_key(path)
+}
+
+fn load_key(path: &std::path::Path) -> Result<ring::signature::Ed25519KeyPair, BundleError> {
+    ring::signature::Ed25519KeyPair::from_pkcs8(&std::fs::read(path)?)
+        .map_err(|_| BundleError::InvalidKey(path.to_owned()))
+}
+
+fn digest(contents: &[u8]) -> String {
+    hex(ring::digest::digest(&ring::digest::SHA256, contents).as_ref())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim();
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_key() -> ring::signature::Ed25519KeyPair {
+        let pkcs8 =
+            ring::signature::Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new())
+                .unwrap();
+        ring::signature::Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    #[test]
+    fn test_bundles_only_open_with_the_site_key() {
+        let key = test_key();
+        let public_key = key.public_key().as_ref().to_vec();
+        let mut entries = BTreeMap::new();
+        entries.insert("usage.json".to_owned(), b"[]".to_vec());
+        let sealed = seal(BundleKind::Usage, "village1", entries, &key).unwrap();
+
+        let bundle = open(&sealed, |site| {
+            assert_eq!(site, "village1");
+            Some(public_key.clone())
+        })
+        .unwrap();
+        assert_eq!(bundle.manifest.kind, BundleKind::Usage);
+        assert_eq!(bundle.entries["usage.json"], b"[]".to_vec());
+        assert!(expect(&bundle, BundleKind::Updates, "village1").is_err());
+
+        let other_key = test_key().public_key().as_ref().to_vec();
+        assert!(matches!(
+            open(&sealed, |_| Some(other_key)),
+            Err(BundleError::Untrusted(_))
+        ));
+        assert!(matches!(
+            open(&sealed, |_| None),
+            Err(BundleError::Untrusted(_))
+        ));
+
+        let trusted: TrustedKey = format!("village1={}", hex(&public_key)).parse().unwrap();
+        assert_eq!(trusted.key, public_key);
+        assert!("village1=xyz".parse::<TrustedKey>().is_err());
+
+        // Replacing an entry invalidates the bundle even with the manifest and
+        // signature intact.
+        let mut tampered = tar::Builder::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        ));
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(sealed.as_slice()));
+        for file in archive.entries().unwrap() {
+            let mut file = file.unwrap();
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents).unwrap();
+            let name = file.path().unwrap().to_string_lossy().into_owned();
+            if name == "usage.json" {
+                contents = b"[{}]".to_vec();
+            }
+            let mut header = file.header().clone();
+            header.set_size(contents.len() as u64);
+            header.set_cksum();
+            tampered
+                .append_data(&mut header, name, contents.as_slice())
+                .unwrap();
+        }
+        let tampered = tampered.into_inner().unwrap().finish().unwrap();
+        assert!(matches!(
+            open(&tampered, |_| Some(public_key.clone())),
+            Err(BundleError::DigestMismatch(_))
+        ));
+    }
+}
@@ -0,0 +1,982 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum CaptureError {
+    #[error("Unable to find interface {0}")]
+    InterfaceNotFound(String),
+    #[error("Unhandled channel type")]
+    UnhandledChannelType,
+    #[error("Error when creating channel: {0}")]
+    ChannelError(#[from] std::io::Error),
+    #[error("The capture filter for the subscriber subnets is too large")]
+    FilterTooLarge,
+}
+
+// The framing of packets delivered by a capture source.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkType {
+    Ethernet,
+    // Bare IPv4 or IPv6 packets, as delivered by tunnel interfaces.
+    RawIp,
+}
+impl LinkType {
+    // The link type from the tcpdump.org LINKTYPE registry.
+    pub fn pcap_link_type(&self) -> u16 {
+        match self {
+            LinkType::Ethernet => 1,
+            LinkType::RawIp => 101,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CaptureStats {
+    pub packets_received: u64,
+    pub receive_errors: u64,
+}
+
+// A source of captured packets. Implementations block in next_packet for at
+// most a short timeout, returning a TimedOut error if no packet arrived, so
+// that the capture loop can flush partial batches and notice configuration
+// changes.
+pub trait CaptureSource: Send {
+    fn next_packet(&mut self) -> Result<&[u8], std::io::Error>;
+    fn stats(&self) -> CaptureStats;
+    fn link_type(&self) -> LinkType;
+    fn name(&self) -> &str;
+
+    // The time the last packet was captured, for sources replaying recorded
+    // traffic. Live sources return None.
+    fn timestamp(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        None
+    }
+}
+
+// Opens a capture source for the named interface. Given the subscriber
+// subnets, the capture only receives packets from or to those subnets, with
+// all other traffic discarded in the kernel.
+pub fn open(
+    interface_name: &str,
+    read_timeout: std::time::Duration,
+    filter_subnets: Option<&[ipnetwork::IpNetwork]>,
+) -> Result<Box<dyn CaptureSource>, CaptureError> {
+    match filter_subnets {
+        Some(subnets) => Ok(Box::new(PacketSocketCapture::open(
+            interface_name,
+            read_timeout,
+            subnets,
+        )?)),
+        None => Ok(Box::new(PnetCapture::open(interface_name, read_timeout)?)),
+    }
+}
+
+// Captures packets from a network interface with a pnet datalink channel.
+pub struct PnetCapture {
+    interface: pnet_datalink::NetworkInterface,
+    rx: Box<dyn pnet_datalink::DataLinkReceiver>,
+    stats: CaptureStats,
+}
+impl PnetCapture {
+    pub fn open(
+        interface_name: &str,
+        read_timeout: std::time::Duration,
+    ) -> Result<PnetCapture, CaptureError> {
+        let interface = pnet_datalink::interfaces()
+            .into_iter()
+            .find(|iface| iface.name == interface_name)
+            .ok_or_else(|| CaptureError::InterfaceNotFound(interface_name.to_owned()))?;
+
+        let channel_config = pnet_datalink::Config {
+            read_timeout: Some(read_timeout),
+            ..Default::default()
+        };
+        match pnet_datalink::channel(&interface, channel_config)? {
+            pnet_datalink::Channel::Ethernet(_, rx) => Ok(PnetCapture {
+                interface,
+                rx,
+                stats: Default::default(),
+            }),
+            _ => Err(CaptureError::UnhandledChannelType),
+        }
+    }
+}
+impl CaptureSource for PnetCapture {
+    fn next_packet(&mut self) -> Result<&[u8], std::io::Error> {
+        match self.rx.next() {
+            Ok(packet) => {
+                self.stats.packets_received += 1;
+                Ok(packet)
+            }
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::TimedOut {
+                    self.stats.receive_errors += 1;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn stats(&self) -> CaptureStats {
+        self.stats.clone()
+    }
+
+    // Interfaces without a MAC address, such as the tun devices used by
+    // cellular cores, deliver bare IP packets.
+    fn link_type(&self) -> LinkType {
+        match self.interface.mac {
+            Some(_) => LinkType::Ethernet,
+            None => LinkType::RawIp,
+        }
+    }
+
+    fn name(&self) -> &str {
+        &self.interface.name
+    }
+}
+
+// The largest packet delivered by a packet socket capture, which covers
+// packets coalesced by offloads.
+const PACKET_BUFFER_SIZE: usize = 65536;
+
+// Captures packets from a network interface with an AF_PACKET socket, with a
+// BPF filter attached so that only subscriber traffic is copied to userspace.
+pub struct PacketSocketCapture {
+    socket: std::os::fd::OwnedFd,
+    name: String,
+    link_type: LinkType,
+    buffer: Vec<u8>,
+    stats: CaptureStats,
+}
+impl PacketSocketCapture {
+    pub fn open(
+        interface_name: &str,
+        read_timeout: std::time::Duration,
+        subnets: &[ipnetwork::IpNetwork],
+    ) -> Result<PacketSocketCapture, CaptureError> {
+        use std::os::fd::{AsRawFd, FromRawFd};
+
+        let interface = pnet_datalink::interfaces()
+            .into_iter()
+            .find(|iface| iface.name == interface_name)
+            .ok_or_else(|| CaptureError::InterfaceNotFound(interface_name.to_owned()))?;
+        let link_type = match interface.mac {
+            Some(_) => LinkType::Ethernet,
+            None => LinkType::RawIp,
+        };
+        let program: Vec<libc::sock_filter> = compile_filter(link_type, subnets)
+            .iter()
+            .map(|instruction| libc::sock_filter {
+                code: instruction.code,
+                jt: instruction.jt,
+                jf: instruction.jf,
+                k: instruction.k,
+            })
+            .collect();
+        // The kernel's limit on the length of classic BPF programs.
+        if program.len() > 4096 {
+            return Err(CaptureError::FilterTooLarge);
+        }
+
+        // The socket is created without a protocol, so that it receives no
+        // packets until the filter is attached and it is bound.
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, 0) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let socket = unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) };
+
+        let filter = libc::sock_fprog {
+            len: program.len() as u16,
+            filter: program.as_ptr() as *mut libc::sock_filter,
+        };
+        set_socket_option(&socket, libc::SO_ATTACH_FILTER, &filter)?;
+        let timeout = libc::timeval {
+            tv_sec: read_timeout.as_secs() as libc::time_t,
+            tv_usec: read_timeout.subsec_micros() as libc::suseconds_t,
+        };
+        set_socket_option(&socket, libc::SO_RCVTIMEO, &timeout)?;
+
+        let mut address: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+        address.sll_family = libc::AF_PACKET as u16;
+        address.sll_protocol = (libc::ETH_P_ALL as u16).to_be();
+        address.sll_ifindex = interface.index as i32;
+        let result = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                &address as *const libc::sockaddr_ll as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        Ok(PacketSocketCapture {
+            socket,
+            name: interface.name,
+            link_type,
+            buffer: vec![0; PACKET_BUFFER_SIZE],
+            stats: Default::default(),
+        })
+    }
+}
+impl CaptureSource for PacketSocketCapture {
+    fn next_packet(&mut self) -> Result<&[u8], std::io::Error> {
+        use std::os::fd::AsRawFd;
+
+        let length = unsafe {
+            libc::recv(
+                self.socket.as_raw_fd(),
+                self.buffer.as_mut_ptr() as *mut libc::c_void,
+                self.buffer.len(),
+                0,
+            )
+        };
+        if length < 0 {
+            let e = std::io::Error::last_os_error();
+            // The receive timeout expiring is reported as would block.
+            if e.kind() == std::io::ErrorKind::WouldBlock {
+                return Err(std::io::ErrorKind::TimedOut.into());
+            }
+            self.stats.receive_errors += 1;
+            return Err(e);
+        }
+        self.stats.packets_received += 1;
+        Ok(&self.buffer[..length as usize])
+    }
+
+    fn stats(&self) -> CaptureStats {
+        self.stats.clone()
+    }
+
+    fn link_type(&self) -> LinkType {
+        self.link_type
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+// Replays the packets recorded in a pcap or pcap-ng file, such as a debug
+// capture. The end of the file is reported as an UnexpectedEof error.
+pub struct PcapFileCapture {
+    input: std::io::BufReader<std::fs::File>,
+    name: String,
+    format: FileFormat,
+    // The link type and timestamp resolution of each pcap-ng interface, or of
+    // the whole file for pcap.
+    interfaces: Vec<(Option<LinkType>, TimestampUnit)>,
+    link_type: LinkType,
+    timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    buffer: Vec<u8>,
+    stats: CaptureStats,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FileFormat {
+    Pcap { big_endian: bool },
+    Pcapng { big_endian: bool },
+}
+
+// Timestamps count either a decimal or binary fraction of a second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TimestampUnit {
+    Decimal(u32),
+    Binary(u32),
+}
+impl TimestampUnit {
+    fn per_second(self) -> Option<u64> {
+        match self {
+            TimestampUnit::Decimal(digits) => 10u64.checked_pow(digits),
+            TimestampUnit::Binary(bits) => 1u64.checked_shl(bits),
+        }
+    }
+
+    fn to_time(self, ticks: u64) -> Option<chrono::DateTime<chrono::Utc>> {
+        let per_second = self.per_second()?;
+        let nanos = (ticks % per_second) as u128 * 1_000_000_000 / per_second as u128;
+        let time =
+            chrono::NaiveDateTime::from_timestamp_opt((ticks / per_second) as i64, nanos as u32)?;
+        Some(chrono::DateTime::from_utc(time, chrono::Utc))
+    }
+}
+
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b23c4d;
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d0d0a;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+const PCAPNG_OPT_IF_TSRESOL: u16 = 9;
+
+// Maps tcpdump.org link types to the framings haulage can parse.
+fn file_link_type(link_type: u32) -> Option<LinkType> {
+    match link_type {
+        1 => Some(LinkType::Ethernet),
+        // Raw IP, and raw IPv4 and IPv6.
+        101 | 228 | 229 => Some(LinkType::RawIp),
+        _ => None,
+    }
+}
+
+impl PcapFileCapture {
+    pub fn open(path: &std::path::Path) -> Result<PcapFileCapture, CaptureError> {
+        use std::io::Read;
+
+        let mut input = std::io::BufReader::new(std::fs::File::open(path)?);
+        let mut magic = [0; 4];
+        input.read_exact(&mut magic)?;
+        let mut capture = PcapFileCapture {
+            input,
+            name: path.display().to_string(),
+            format: FileFormat::Pcap { big_endian: false },
+            interfaces: Vec::new(),
+            link_type: LinkType::Ethernet,
+            timestamp: None,
+            buffer: Vec::new(),
+            stats: Default::default(),
+        };
+
+        if u32::from_le_bytes(magic) == PCAPNG_SECTION_HEADER {
+            capture.read_section_header()?;
+            return Ok(capture);
+        }
+        let (big_endian, unit) = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+            (PCAP_MAGIC_MICROS, _) => (false, TimestampUnit::Decimal(6)),
+            (PCAP_MAGIC_NANOS, _) => (false, TimestampUnit::Decimal(9)),
+            (_, PCAP_MAGIC_MICROS) => (true, TimestampUnit::Decimal(6)),
+            (_, PCAP_MAGIC_NANOS) => (true, TimestampUnit::Decimal(9)),
+            _ => return Err(invalid_file("not a pcap or pcap-ng file").into()),
+        };
+        capture.format = FileFormat::Pcap { big_endian };
+        let mut header = [0; 20];
+        capture.input.read_exact(&mut header)?;
+        let link_type = capture.read_u32(&header, 16);
+        capture.interfaces.push((file_link_type(link_type), unit));
+        Ok(capture)
+    }
+
+    fn big_endian(&self) -> bool {
+        match self.format {
+            FileFormat::Pcap { big_endian } | FileFormat::Pcapng { big_endian } => big_endian,
+        }
+    }
+
+    fn read_u16(&self, bytes: &[u8], offset: usize) -> u16 {
+        let bytes = [bytes[offset], bytes[offset + 1]];
+        match self.big_endian() {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        }
+    }
+
+    fn read_u32(&self, bytes: &[u8], offset: usize) -> u32 {
+        let bytes = [
+            bytes[offset],
+            bytes[offset + 1],
+            bytes[offset + 2],
+            bytes[offset + 3],
+        ];
+        match self.big_endian() {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        }
+    }
+
+    // Reads the rest of a section header block, whose byte order magic sets
+    // the byte order of the section. Interfaces are numbered per section.
+    fn read_section_header(&mut self) -> Result<(), std::io::Error> {
+        use std::io::Read;
+
+        let mut lengths = [0; 8];
+        self.input.read_exact(&mut lengths)?;
+        let big_endian = match u32::from_le_bytes(lengths[4..8].try_into().unwrap()) {
+            PCAPNG_BYTE_ORDER_MAGIC => false,
+            _ if u32::from_be_bytes(lengths[4..8].try_into().unwrap())
+                == PCAPNG_BYTE_ORDER_MAGIC =>
+            {
+                true
+            }
+            _ => return Err(invalid_file("unknown pcap-ng byte order")),
+        };
+        self.format = FileFormat::Pcapng { big_endian };
+        self.interfaces.clear();
+        let length = self.read_u32(&lengths, 0) as usize;
+        // The type, lengths, and byte order magic were already read.
+        let mut rest = vec![
+            0;
+            length
+                .checked_sub(12)
+                .ok_or_else(|| invalid_file("short block"))?
+        ];
+        self.input.read_exact(&mut rest)
+    }
+
+    // Reads the next packet into the buffer, returning None at the end of the
+    // file.
+    fn read_packet(&mut self) -> Result<Option<()>, std::io::Error> {
+        use std::io::Read;
+
+        match self.format {
+            FileFormat::Pcap { .. } => {
+                let mut header = [0; 16];
+                if !read_or_end(&mut self.input, &mut header)? {
+                    return Ok(None);
+                }
+                let seconds = self.read_u32(&header, 0) as u64;
+                let fraction = self.read_u32(&header, 4) as u64;
+                let length = self.read_u32(&header, 8) as usize;
+                let (link_type, unit) = self.interfaces[0];
+                self.buffer.resize(length, 0);
+                self.input.read_exact(&mut self.buffer)?;
+                let ticks = unit
+                    .per_second()
+                    .and_then(|per_second| seconds.checked_mul(per_second))
+                    .and_then(|ticks| ticks.checked_add(fraction));
+                self.select(link_type, ticks.and_then(|ticks| unit.to_time(ticks)))?;
+                Ok(Some(()))
+            }
+            FileFormat::Pcapng { .. } => loop {
+                let mut header = [0; 8];
+                if !read_or_end(&mut self.input, &mut header)? {
+                    return Ok(None);
+                }
+                let block_type = self.read_u32(&header, 0);
+                if block_type == PCAPNG_SECTION_HEADER {
+                    self.read_section_header()?;
+                    continue;
+                }
+                let length = self.read_u32(&header, 4) as usize;
+                let mut body = vec![
+                    0;
+                    length
+                        .checked_sub(8)
+                        .ok_or_else(|| invalid_file("short block"))?
+                ];
+                self.input.read_exact(&mut body)?;
+                // The trailing copy of the length is not needed.
+                body.truncate(body.len().saturating_sub(4));
+
+                match block_type {
+                    PCAPNG_INTERFACE_DESCRIPTION if body.len() >= 8 => {
+                        let link_type = self.read_u16(&body, 0) as u32;
+                        let unit = self.interface_timestamp_unit(&body[8..]);
+                        self.interfaces.push((file_link_type(link_type), unit));
+                    }
+                    PCAPNG_ENHANCED_PACKET if body.len() >= 20 => {
+                        let interface = self.read_u32(&body, 0) as usize;
+                        let ticks =
+                            (self.read_u32(&body, 4) as u64) << 32 | self.read_u32(&body, 8) as u64;
+                        let length = self.read_u32(&body, 12) as usize;
+                        let packet = body
+                            .get(20..20 + length)
+                            .ok_or_else(|| invalid_file("truncated packet"))?;
+                        let (link_type, unit) = *self
+                            .interfaces
+                            .get(interface)
+                            .ok_or_else(|| invalid_file("packet from an undescribed interface"))?;
+                        self.buffer.clear();
+                        self.buffer.extend_from_slice(packet);
+                        self.select(link_type, unit.to_time(ticks))?;
+                        return Ok(Some(()));
+                    }
+                    // Statistics and other blocks carry no packets to replay.
+                    _ => {}
+                }
+            },
+        }
+    }
+
+    fn interface_timestamp_unit(&self, mut options: &[u8]) -> TimestampUnit {
+        while options.len() >= 4 {
+            let code = self.read_u16(options, 0);
+            let length = self.read_u16(options, 2) as usize;
+            let padded = length.div_ceil(4) * 4;
+            if code == PCAPNG_OPT_IF_TSRESOL && length == 1 && options.len() > 4 {
+                let resolution = options[4];
+                return match resolution & 0x80 {
+                    0 => TimestampUnit::Decimal(resolution as u32),
+                    _ => TimestampUnit::Binary((resolution & 0x7f) as u32),
+                };
+            }
+            options = options.get(4 + padded..).unwrap_or_default();
+        }
+        TimestampUnit::Decimal(6)
+    }
+
+    fn select(
+        &mut self,
+        link_type: Option<LinkType>,
+        timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<(), std::io::Error> {
+        self.link_type = link_type.ok_or_else(|| invalid_file("unsupported link type"))?;
+        self.timestamp = Some(timestamp.ok_or_else(|| invalid_file("invalid timestamp"))?);
+        Ok(())
+    }
+}
+impl CaptureSource for PcapFileCapture {
+    fn next_packet(&mut self) -> Result<&[u8], std::io::Error> {
+        match self.read_packet() {
+            Ok(Some(())) => {
+                self.stats.packets_received += 1;
+                Ok(&self.buffer)
+            }
+            Ok(None) => Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Err(e) => {
+                self.stats.receive_errors += 1;
+                Err(e)
+            }
+        }
+    }
+
+    fn stats(&self) -> CaptureStats {
+        self.stats.clone()
+    }
+
+    fn link_type(&self) -> LinkType {
+        self.link_type
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn timestamp(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.timestamp
+    }
+}
+
+fn invalid_file(reason: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason.to_owned())
+}
+
+// Fills the buffer, returning false if the input ended cleanly before it.
+fn read_or_end<R: std::io::Read>(input: &mut R, buffer: &mut [u8]) -> Result<bool, std::io::Error> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match input.read(&mut buffer[filled..])? {
+            0 if filled == 0 => return Ok(false),
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            read => filled += read,
+        }
+    }
+    Ok(true)
+}
+
+fn set_socket_option<T>(
+    socket: &std::os::fd::OwnedFd,
+    option: libc::c_int,
+    value: &T,
+) -> Result<(), std::io::Error> {
+    use std::os::fd::AsRawFd;
+
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            value as *const T as *const libc::c_void,
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+// A classic BPF instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Instruction {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+// Accepted packets are delivered in full.
+const BPF_ACCEPT: u32 = 262144;
+const UDP_PROTOCOL: u32 = 17;
+// Frames with more stacked VLAN tags are discarded by the filter.
+const MAX_FILTERED_VLAN_TAGS: u32 = 2;
+
+fn load_word(offset: u32) -> Instruction {
+    Instruction {
+        code: 0x20,
+        jt: 0,
+        jf: 0,
+        k: offset,
+    }
+}
+fn load_half(offset: u32) -> Instruction {
+    Instruction {
+        code: 0x28,
+        jt: 0,
+        jf: 0,
+        k: offset,
+    }
+}
+fn load_byte(offset: u32) -> Instruction {
+    Instruction {
+        code: 0x30,
+        jt: 0,
+        jf: 0,
+        k: offset,
+    }
+}
+// Loads a half word at an offset past the IPv4 header, whose length was
+// loaded into the index register by load_ipv4_header_length.
+fn load_half_after_header(offset: u32) -> Instruction {
+    Instruction {
+        code: 0x48,
+        jt: 0,
+        jf: 0,
+        k: offset,
+    }
+}
+fn load_ipv4_header_length(offset: u32) -> Instruction {
+    Instruction {
+        code: 0xb1,
+        jt: 0,
+        jf: 0,
+        k: offset,
+    }
+}
+fn and(mask: u32) -> Instruction {
+    Instruction {
+        code: 0x54,
+        jt: 0,
+        jf: 0,
+        k: mask,
+    }
+}
+fn shift_right(bits: u32) -> Instruction {
+    Instruction {
+        code: 0x74,
+        jt: 0,
+        jf: 0,
+        k: bits,
+    }
+}
+fn jump(skip: u32) -> Instruction {
+    Instruction {
+        code: 0x05,
+        jt: 0,
+        jf: 0,
+        k: skip,
+    }
+}
+fn jump_if_equal(value: u32, jt: u8, jf: u8) -> Instruction {
+    Instruction {
+        code: 0x15,
+        jt,
+        jf,
+        k: value,
+    }
+}
+fn ret(length: u32) -> Instruction {
+    Instruction {
+        code: 0x06,
+        jt: 0,
+        jf: 0,
+        k: length,
+    }
+}
+
+// Compiles a filter accepting IPv4 and IPv6 packets with a source or
+// destination address within any of the subnets, or carrying GTP-U, whose
+// subscriber addresses are only visible within the tunnel. Each match returns
+// immediately, so conditional jumps stay within their 8 bit range however
+// many subnets there are. Ethernet frames may carry up to two VLAN tags, each
+// of which shifts the packet along, so the blocks are repeated for each.
+fn compile_filter(link_type: LinkType, subnets: &[ipnetwork::IpNetwork]) -> Vec<Instruction> {
+    match link_type {
+        LinkType::Ethernet => {
+            let mut program = Vec::new();
+            for tags in (0..=MAX_FILTERED_VLAN_TAGS).rev() {
+                let header_offset = 14 + 4 * tags;
+                let mut level = vec![load_half(header_offset - 2)];
+                let blocks = ip_blocks(header_offset, subnets, 0x0800, 0x86DD);
+                // Continues at the next level's ethertype if this one is a tag.
+                if tags < MAX_FILTERED_VLAN_TAGS {
+                    level.push(jump_if_equal(0x8100, 2, 0));
+                    level.push(jump_if_equal(0x88A8, 1, 0));
+                    level.push(jump_if_equal(0x9100, 0, 1));
+                    level.push(jump(blocks.len() as u32));
+                }
+                level.extend(blocks);
+                level.extend(program);
+                program = level;
+            }
+            program
+        }
+        // The version is the top nibble of the first byte of raw IP packets.
+        LinkType::RawIp => {
+            let mut program = vec![load_byte(0), shift_right(4)];
+            program.extend(ip_blocks(0, subnets, 4, 6));
+            program
+        }
+    }
+}
+
+// Matches the packet at the offset with the protocol loaded, discarding it if
+// the protocol is neither of IPv4 or IPv6.
+fn ip_blocks(
+    header_offset: u32,
+    subnets: &[ipnetwork::IpNetwork],
+    ipv4: u32,
+    ipv6: u32,
+) -> Vec<Instruction> {
+    let mut program = Vec::new();
+    // Skips past the block unless the protocol matches, leaving the protocol
+    // loaded for the next comparison.
+    let mut add_block = |protocol: u32, block: Vec<Instruction>| {
+        program.push(jump_if_equal(protocol, 1, 0));
+        program.push(jump(block.len() as u32));
+        program.extend(block);
+    };
+    add_block(ipv4, ipv4_block(header_offset, subnets));
+    add_block(ipv6, ipv6_block(header_offset, subnets));
+    program.push(ret(0));
+    program
+}
+
+fn ipv4_block(header_offset: u32, subnets: &[ipnetwork::IpNetwork]) -> Vec<Instruction> {
+    let mut block = vec![
+        load_byte(header_offset + 9),
+        jump_if_equal(UDP_PROTOCOL, 0, 4),
+        load_ipv4_header_length(header_offset),
+        load_half_after_header(header_offset + 2),
+        jump_if_equal(crate::packet_parser::GTPU_PORT as u32, 0, 1),
+        ret(BPF_ACCEPT),
+    ];
+    // The source and destination address offsets.
+    for field in [12, 16] {
+        for subnet in subnets {
+            if let ipnetwork::IpNetwork::V4(subnet) = subnet {
+                block.push(load_word(header_offset + field));
+                block.push(and(u32::from(subnet.mask())));
+                block.push(jump_if_equal(u32::from(subnet.network()), 0, 1));
+                block.push(ret(BPF_ACCEPT));
+            }
+        }
+    }
+    block.push(ret(0));
+    block
+}
+
+fn ipv6_block(header_offset: u32, subnets: &[ipnetwork::IpNetwork]) -> Vec<Instruction> {
+    let words = |address: std::net::Ipv6Addr| {
+        let octets = address.octets();
+        let mut words = [0; 4];
+        for (word, chunk) in words.iter_mut().zip(octets.chunks(4)) {
+            *word = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        words
+    };
+
+    // Extension headers are not followed, as GTP-U is sent without them.
+    let mut block = vec![
+        load_byte(header_offset + 6),
+        jump_if_equal(UDP_PROTOCOL, 0, 3),
+        load_half(header_offset + 40 + 2),
+        jump_if_equal(crate::packet_parser::GTPU_PORT as u32, 0, 1),
+        ret(BPF_ACCEPT),
+    ];
+    // The source and destination address offsets.
+    for field in [8, 24] {
+        for subnet in subnets {
+            if let ipnetwork::IpNetwork::V6(subnet) = subnet {
+                let network = words(subnet.network());
+                let mask = words(subnet.mask());
+                // Only the words covered by the prefix need comparing, and a
+                // mismatch in any of them skips to the next subnet.
+                let compared = (subnet.prefix() as usize).div_ceil(32);
+                for i in 0..compared {
+                    let remaining = (compared - 1 - i) * 3 + 1;
+                    block.push(load_word(header_offset + field + 4 * i as u32));
+                    block.push(and(mask[i]));
+                    block.push(jump_if_equal(network[i], 0, remaining as u8));
+                }
+                block.push(ret(BPF_ACCEPT));
+            }
+        }
+    }
+    block.push(ret(0));
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the subset of classic BPF emitted by compile_filter.
+    fn run(program: &[Instruction], packet: &[u8]) -> u32 {
+        let load = |offset: u32, size: usize| {
+            let bytes = &packet[offset as usize..offset as usize + size];
+            bytes
+                .iter()
+                .fold(0, |value, byte| value << 8 | *byte as u32)
+        };
+        let (mut pc, mut a, mut x) = (0, 0, 0);
+        loop {
+            let instruction = program[pc];
+            pc += 1;
+            match instruction.code {
+                0x20 => a = load(instruction.k, 4),
+                0x28 => a = load(instruction.k, 2),
+                0x30 => a = load(instruction.k, 1),
+                0x48 => a = load(x + instruction.k, 2),
+                0xb1 => x = (load(instruction.k, 1) & 0x0f) * 4,
+                0x54 => a &= instruction.k,
+                0x74 => a >>= instruction.k,
+                0x05 => pc += instruction.k as usize,
+                0x15 => match a == instruction.k {
+                    true => pc += instruction.jt as usize,
+                    false => pc += instruction.jf as usize,
+                },
+                0x06 => return instruction.k,
+                code => panic!("Unexpected instruction {:x}", code),
+            }
+        }
+    }
+
+    fn ipv4_packet(src: [u8; 4], dst: [u8; 4]) -> Vec<u8> {
+        let mut packet = vec![0x45; 20];
+        packet[12..16].copy_from_slice(&src);
+        packet[16..20].copy_from_slice(&dst);
+        packet
+    }
+
+    #[test]
+    fn test_filter_matches_subscriber_subnets() {
+        let subnets: Vec<ipnetwork::IpNetwork> = vec![
+            "10.45.0.0/16".parse().unwrap(),
+            "2001:db8:1::/48".parse().unwrap(),
+        ];
+        let raw = compile_filter(LinkType::RawIp, &subnets);
+        assert_eq!(
+            run(&raw, &ipv4_packet([10, 45, 3, 2], [8, 8, 8, 8])),
+            BPF_ACCEPT
+        );
+        assert_eq!(
+            run(&raw, &ipv4_packet([8, 8, 8, 8], [10, 45, 3, 2])),
+            BPF_ACCEPT
+        );
+        assert_eq!(run(&raw, &ipv4_packet([10, 46, 0, 1], [8, 8, 8, 8])), 0);
+
+        let mut ipv6 = vec![0x60; 40];
+        ipv6[8..24].copy_from_slice(
+            &"2001:db8:2::1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        ipv6[24..40].copy_from_slice(
+            &"2001:db8:1::7"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        assert_eq!(run(&raw, &ipv6), BPF_ACCEPT);
+        ipv6[24..40].copy_from_slice(
+            &"2001:db8:3::7"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        assert_eq!(run(&raw, &ipv6), 0);
+
+        // Ethernet frames are matched on the ethertype, and others such as
+        // ARP are discarded.
+        let ethernet = compile_filter(LinkType::Ethernet, &subnets);
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend(ipv4_packet([8, 8, 8, 8], [10, 45, 0, 9]));
+        assert_eq!(run(&ethernet, &frame), BPF_ACCEPT);
+        frame[12..14].copy_from_slice(&[0x08, 0x06]);
+        assert_eq!(run(&ethernet, &frame), 0);
+
+        // Up to two VLAN tags are skipped to reach the ethertype.
+        let mut tagged = frame[..12].to_vec();
+        tagged.extend_from_slice(&[0x88, 0xA8, 0, 10, 0x81, 0x00, 0, 20, 0x08, 0x00]);
+        tagged.extend(ipv4_packet([10, 45, 0, 9], [8, 8, 8, 8]));
+        assert_eq!(run(&ethernet, &tagged), BPF_ACCEPT);
+        tagged[34..38].copy_from_slice(&[10, 46, 0, 9]);
+        assert_eq!(run(&ethernet, &tagged), 0);
+        let mut single = frame[..12].to_vec();
+        single.extend_from_slice(&[0x81, 0x00, 0, 20, 0x86, 0xDD]);
+        single.extend(ipv6.clone());
+        assert_eq!(run(&ethernet, &single), 0);
+        single[26..42].copy_from_slice(
+            &"2001:db8:1::7"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        assert_eq!(run(&ethernet, &single), BPF_ACCEPT);
+
+        // GTP-U is accepted between any addresses, e.g. from an eNodeB to
+        // the SGW, after IPv4 options if present.
+        let mut tunnel = ipv4_packet([192, 168, 0, 2], [192, 168, 0, 1]);
+        tunnel[0] = 0x46;
+        tunnel[9] = 17;
+        tunnel.extend_from_slice(&[0; 4]);
+        tunnel.extend_from_slice(&[0x08, 0x68, 0x08, 0x68, 0, 8, 0, 0]);
+        assert_eq!(run(&raw, &tunnel), BPF_ACCEPT);
+        tunnel[26..28].copy_from_slice(&53u16.to_be_bytes());
+        assert_eq!(run(&raw, &tunnel), 0);
+        ipv6[6] = 17;
+        ipv6.extend_from_slice(&[0x08, 0x68, 0x08, 0x68, 0, 8, 0, 0]);
+        assert_eq!(run(&raw, &ipv6), BPF_ACCEPT);
+    }
+
+    #[test]
+    fn test_read_recorded_captures() {
+        let time = chrono::DateTime::parse_from_rfc3339("2026-10-16T08:30:00.250Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let packet = ipv4_packet([10, 45, 0, 2], [8, 8, 8, 8]);
+        let path = std::env::temp_dir().join(format!("haulage-replay-{}", std::process::id()));
+
+        // A classic big endian pcap with nanosecond timestamps.
+        let mut classic = Vec::new();
+        classic.extend_from_slice(&0xA1B2_3C4Du32.to_be_bytes());
+        classic.extend_from_slice(&[0, 2, 0, 4]);
+        classic.extend_from_slice(&[0; 8]);
+        classic.extend_from_slice(&65535u32.to_be_bytes());
+        classic.extend_from_slice(&101u32.to_be_bytes());
+        classic.extend_from_slice(&(time.timestamp() as u32).to_be_bytes());
+        classic.extend_from_slice(&250_000_000u32.to_be_bytes());
+        classic.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+        classic.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+        classic.extend_from_slice(&packet);
+        std::fs::write(&path, &classic).unwrap();
+        let mut capture = PcapFileCapture::open(&path).unwrap();
+        assert_eq!(capture.next_packet().unwrap(), &packet[..]);
+        assert_eq!(capture.link_type(), LinkType::RawIp);
+        assert_eq!(capture.timestamp(), Some(time));
+        assert_eq!(
+            capture.next_packet().unwrap_err().kind(),
+            std::io::ErrorKind::UnexpectedEof
+        );
+
+        // A pcap-ng as written by the debug capture.
+        let mut writer =
+            crate::debug_capture::PcapngWriter::new(std::fs::File::create(&path).unwrap());
+        writer.write_section_header("haulage").unwrap();
+        writer
+            .write_interface_description("ogstun", LinkType::Ethernet.pcap_link_type())
+            .unwrap();
+        writer
+            .write_enhanced_packet(0, time.into(), &packet)
+            .unwrap();
+        drop(writer);
+        let mut capture = PcapFileCapture::open(&path).unwrap();
+        assert_eq!(capture.next_packet().unwrap(), &packet[..]);
+        assert_eq!(capture.link_type(), LinkType::Ethernet);
+        assert_eq!(capture.timestamp(), Some(time));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+
+// How long an address learned from a DNS answer stays attributed to the
+// class of the queried domain.
+const RESOLUTION_LIFETIME: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+
+// A class of destinations charged against subscriber balances at a weight
+// relative to ordinary traffic, e.g. 0.1 for a local content cache.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChargingClass {
+    pub name: String,
+    pub weight: f64,
+    // Destination networks in the class, e.g. the local cache subnet or a
+    // GeoIP export of in-country prefixes.
+    pub networks: Vec<ipnetwork::IpNetwork>,
+    // A file listing domains in the class, in the content filter list format.
+    pub domain_list: Option<std::path::PathBuf>,
+}
+
+// Attributes remote addresses to charging classes, either statically by
+// network or dynamically from the DNS answers subscribers receive. Domain
+// attribution takes precedence, followed by the longest matching network.
+// Unclassified destinations are charged at full weight.
+#[derive(Debug)]
+pub struct Classifier {
+    classes: Vec<ChargingClass>,
+    domains: HashMap<String, usize>,
+    resolved: HashMap<std::net::IpAddr, (usize, std::time::Instant)>,
+    last_expiry: std::time::Instant,
+}
+impl Classifier {
+    pub fn load(classes: &[ChargingClass]) -> Result<Classifier, std::io::Error> {
+        let mut domains = HashMap::new();
+        for (index, class) in classes.iter().enumerate() {
+            if let Some(path) = &class.domain_list {
+                let contents = std::fs::read_to_string(path)?;
+                for domain in crate::content_filter::parse_category_list(&contents) {
+                    domains.entry(domain).or_insert(index);
+                }
+            }
+        }
+        Ok(Classifier {
+            classes: classes.to_vec(),
+            domains,
+            resolved: HashMap::new(),
+            last_expiry: std::time::Instant::now(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.classes.is_empty()
+    }
+
+    // Attributes the answered addresses to the class of the queried domain or
+    // its closest listed parent domain.
+    pub fn learn(&mut self, response: &crate::packet_parser::DnsResponse, now: std::time::Instant) {
+        if now.duration_since(self.last_expiry) > RESOLUTION_LIFETIME / 24 {
+            self.resolved
+                .retain(|_, (_, learned)| now.duration_since(*learned) < RESOLUTION_LIFETIME);
+            self.last_expiry = now;
+        }
+
+        let domain = crate::content_filter::normalize_domain(&response.fqdn.to_string());
+        let class = std::iter::once(domain.as_str())
+            .chain(domain.match_indices('.').map(|(i, _)| &domain[i + 1..]))
+            .find_map(|suffix| self.domains.get(suffix).copied());
+        if let Some(class) = class {
+            for address in &response.addresses {
+                self.resolved.insert(*address, (class, now));
+            }
+        }
+    }
+
+    pub fn weight(&self, remote: std::net::IpAddr, now: std::time::Instant) -> f64 {
+        self.classify(remote, now)
+            .map_or(1.0, |class| self.classes[class].weight)
+    }
+
+    fn classify(&self, remote: std::net::IpAddr, now: std::time::Instant) -> Option<usize> {
+        if let Some((class, learned)) = self.resolved.get(&remote) {
+            if now.duration_since(*learned) < RESOLUTION_LIFETIME {
+                return Some(*class);
+            }
+        }
+        self.classes
+            .iter()
+            .enumerate()
+            .flat_map(|(index, class)| {
+                class
+                    .networks
+                    .iter()
+                    .filter(|network| network.contains(remote))
+                    .map(move |network| (network.prefix(), index))
+            })
+            .max_by_key(|(prefix, _)| *prefix)
+            .map(|(_, index)| index)
+    }
+}
+
+// Scales a byte count by a charging weight, rounding to the nearest byte.
+pub fn weighted_bytes(bytes: u64, weight: f64) -> u64 {
+    (bytes as f64 * weight).round() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn make_classifier() -> Classifier {
+        Classifier::load(&[
+            ChargingClass {
+                name: String::from("national"),
+                weight: 0.5,
+                networks: vec![ipnetwork::IpNetwork::from_str("10.0.0.0/8").unwrap()],
+                domain_list: None,
+            },
+            ChargingClass {
+                name: String::from("cache"),
+                weight: 0.1,
+                networks: vec![ipnetwork::IpNetwork::from_str("10.10.0.0/16").unwrap()],
+                domain_list: None,
+            },
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn test_longest_network_match() {
+        let classifier = make_classifier();
+        let now = std::time::Instant::now();
+        let weight = |address: &str| classifier.weight(address.parse().unwrap(), now);
+        assert_eq!(weight("10.10.3.4"), 0.1);
+        assert_eq!(weight("10.20.3.4"), 0.5);
+        assert_eq!(weight("8.8.8.8"), 1.0);
+    }
+
+    #[test]
+    fn test_learned_domain_takes_precedence() {
+        let mut classifier = make_classifier();
+        classifier.domains.insert(String::from("cache.example"), 1);
+        let now = std::time::Instant::now();
+        let address: std::net::IpAddr = "10.20.3.4".parse().unwrap();
+
+        classifier.learn(
+            &crate::packet_parser::DnsResponse {
+                fqdn: domain::base::name::Dname::from_str("video.cache.example.").unwrap(),
+                addresses: vec![address],
+                qtype: 1,
+                rcode: 0,
+                ttl: 300,
+            },
+            now,
+        );
+        assert_eq!(classifier.weight(address, now), 0.1);
+        assert_eq!(classifier.weight(address, now + RESOLUTION_LIFETIME), 0.5);
+    }
+
+    #[test]
+    fn test_weighted_bytes() {
+        assert_eq!(weighted_bytes(1000, 0.1), 100);
+        assert_eq!(weighted_bytes(15, 0.1), 2);
+        assert_eq!(weighted_bytes(1000, 1.0), 1000);
+    }
+}
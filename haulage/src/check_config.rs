@@ -0,0 +1,185 @@
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct CheckConfigCommand {
+    /// Also check that the configured database can be reached.
+    #[structopt(long = "database")]
+    database: bool,
+
+    /// Print the problems found as json rather than one per line.
+    #[structopt(long = "json")]
+    json: bool,
+}
+
+// A problem with the configuration, by the option it was found in when known.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Problem {
+    pub option: Option<String>,
+    pub message: String,
+}
+impl Problem {
+    fn new(option: &str, message: String) -> Problem {
+        Problem {
+            option: Some(option.to_owned()),
+            message,
+        }
+    }
+}
+
+// Validates a configuration file without touching the data plane, so that
+// mistakes are found before a restart rather than by the daemon failing at
+// boot. Returns whether the configuration is valid.
+pub async fn run(command: &CheckConfigCommand, path: &std::path::Path, log: &slog::Logger) -> bool {
+    let problems = match crate::config::load(path, log) {
+        Ok(config) => {
+            let mut problems = check_values(&config);
+            problems.extend(check_interfaces(&config).await);
+            if command.database {
+                if let Err(reason) = crate::self_test::check_database(&config.database).await {
+                    problems.push(Problem {
+                        option: None,
+                        message: format!("Unable to reach the database: {}", reason),
+                    });
+                }
+            }
+            problems
+        }
+        // Loading stops at the first problem, which names the option itself.
+        Err(e) => vec![Problem {
+            option: None,
+            message: e.to_string(),
+        }],
+    };
+
+    // Report directly, since the async log is not flushed before exiting.
+    if command.json {
+        println!(
+            "{}",
+            serde_json::json!({ "valid": problems.is_empty(), "problems": problems })
+        );
+    } else if problems.is_empty() {
+        println!("{} is valid", path.display());
+    } else {
+        for problem in &problems {
+            match &problem.option {
+                Some(option) => println!("ERROR {}: {}", option, problem.message),
+                None => println!("ERROR {}", problem.message),
+            }
+        }
+    }
+    problems.is_empty()
+}
+
+// Checks the values which parse but cannot work, e.g. zero length intervals,
+// which would otherwise only fail once the subsystem using them starts.
+fn check_values(config: &crate::config::Internal) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    let mut intervals = vec![
+        ("flowLogInterval", config.flow_log_interval),
+        ("userLogInterval", config.user_log_interval),
+        ("reenablePollInterval", config.reenable_poll_interval),
+        ("statsLogInterval", config.stats_log_interval),
+        ("usageFlushInterval", config.usage_flush_interval),
+    ];
+    if let Some(netflow) = &config.netflow {
+        intervals.push(("netflow.activeTimeout", netflow.active_timeout));
+    }
+    if let Some(forecast) = &config.usage_forecast {
+        intervals.push(("custom.usageForecast.interval", forecast.interval));
+    }
+    if let Some(leases) = &config.dhcp_leases {
+        intervals.push(("dhcpLeases.pollInterval", leases.poll_interval));
+    }
+    for (option, interval) in intervals {
+        if interval.is_zero() {
+            problems.push(Problem::new(
+                option,
+                String::from("must be longer than zero"),
+            ));
+        }
+    }
+
+    for address in &config.ignored_user_addresses {
+        if !config
+            .user_subnets
+            .iter()
+            .any(|subnet| subnet.contains(*address))
+        {
+            problems.push(Problem::new(
+                "ignoredUserAddresses",
+                format!("{} is not within any userSubnet", address),
+            ));
+        }
+    }
+    problems
+}
+
+async fn check_interfaces(config: &crate::config::Internal) -> Vec<Problem> {
+    let (subscriber_interface, upstream_interfaces) = config.enforcement_interfaces();
+    let mut problems = Vec::new();
+    if let Err(reason) = check_interface(&subscriber_interface).await {
+        problems.push(Problem::new("subscriberInterface", reason));
+    }
+    for interface in &upstream_interfaces {
+        if let Err(reason) = check_interface(interface).await {
+            problems.push(Problem::new("upstreamInterface", reason));
+        }
+    }
+    problems
+}
+
+// Looks the interface up within its namespace, if any.
+async fn check_interface(interface: &crate::netns::Interface) -> Result<(), String> {
+    let output = interface
+        .command("ip")
+        .args(["link", "show", "dev", &interface.name])
+        .output()
+        .await
+        .map_err(|e| format!("Unable to look up interface {}: {}", interface, e))?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(format!("Interface {} does not exist", interface)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_values() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let path = std::env::temp_dir().join("haulage_check_config_test.yml");
+        std::fs::write(
+            &path,
+            r#"
+flowLogInterval: "20m"
+userLogInterval: "0s"
+subscriberInterface: "ogstun"
+upstreamInterface: "eth0"
+userSubnet: "10.45.0.0/24"
+ignoredUserAddresses: ["10.45.0.1", "10.46.0.1"]
+custom:
+  reenablePollInterval: "5s"
+  dbLocation: "haulage_db"
+  dbUser: "haulage_db"
+  dbPass: "haulage_db"
+"#,
+        )
+        .unwrap();
+        let config = crate::config::load(&path, &log).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            check_values(&config),
+            vec![
+                Problem::new("userLogInterval", String::from("must be longer than zero")),
+                Problem::new(
+                    "ignoredUserAddresses",
+                    String::from("10.46.0.1 is not within any userSubnet")
+                ),
+            ]
+        );
+    }
+}
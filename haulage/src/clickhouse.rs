@@ -0,0 +1,527 @@
+use std::collections::HashMap;
+use thiserror::Error;
+
+use crate::accounting::AccountingLevel;
+
+#[derive(Error, Debug)]
+pub enum ExportError {
+    #[error("ClickHouse request failed: {0}")]
+    HttpError(#[from] reqwest::Error),
+    #[error("ClickHouse rejected the request: {0}")]
+    Rejected(String),
+    #[error("Failed to serialize record: {0}")]
+    SerdeJsonError(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // The base url of the ClickHouse http interface, e.g. http://localhost:8123
+    pub url: String,
+    pub database: String,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    // Records are buffered and inserted once this many accumulate or the flush
+    // interval elapses, whichever comes first.
+    pub batch_size: usize,
+    pub flush_interval: std::time::Duration,
+    pub level: AccountingLevel,
+}
+
+#[derive(Debug)]
+pub struct ClickhouseExporter {
+    dispatch_channel: tokio::sync::mpsc::Sender<Message>,
+}
+impl ClickhouseExporter {
+    pub fn new(
+        settings: Settings,
+        flow_log_schedule: crate::clock::Schedule,
+        pseudonymizer: Option<std::sync::Arc<crate::privacy::Pseudonymizer>>,
+        stats: std::sync::Arc<crate::stats::Stats>,
+        log: slog::Logger,
+    ) -> ClickhouseExporter {
+        let (sender, receiver) = tokio::sync::mpsc::channel(64);
+        tokio::task::spawn(async move {
+            export_records(
+                receiver,
+                settings,
+                flow_log_schedule,
+                pseudonymizer,
+                stats,
+                log,
+            )
+            .await;
+        });
+        ClickhouseExporter {
+            dispatch_channel: sender,
+        }
+    }
+    pub fn clone_input_channel(&self) -> tokio::sync::mpsc::Sender<Message> {
+        self.dispatch_channel.clone()
+    }
+}
+
+pub enum Message {
+    // Usage of individual flows observed within a batch of packets.
+    Flows(HashMap<FlowKey, FlowUsage>),
+    // A DNS response observed on its way to a subscriber.
+    DnsAnswer {
+        subscriber: std::net::IpAddr,
+        response: crate::packet_parser::DnsResponse,
+    },
+    // The server name a subscriber asked a remote address for in a TLS
+    // ClientHello, recorded as the domain of that address.
+    ServerName {
+        subscriber: std::net::IpAddr,
+        remote_addr: std::net::IpAddr,
+        server_name: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub user_addr: std::net::IpAddr,
+    pub remote_addr: std::net::IpAddr,
+    pub user_port: u16,
+    pub remote_port: u16,
+    pub protocol: u8,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FlowUsage {
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub packets: u64,
+    // TCP connection teardown observed in each direction.
+    pub fin_up: bool,
+    pub fin_down: bool,
+    pub reset: bool,
+}
+impl FlowUsage {
+    // Connections are finished once reset, or once both sides have sent a
+    // FIN. The final ACK of a graceful close lands in the next record.
+    pub fn is_finished(&self) -> bool {
+        self.reset || (self.fin_up && self.fin_down)
+    }
+}
+impl std::ops::AddAssign for FlowUsage {
+    fn add_assign(&mut self, rhs: Self) {
+        self.bytes_up += rhs.bytes_up;
+        self.bytes_down += rhs.bytes_down;
+        self.packets += rhs.packets;
+        self.fin_up |= rhs.fin_up;
+        self.fin_down |= rhs.fin_down;
+        self.reset |= rhs.reset;
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct FlowRecord {
+    start: String,
+    end: String,
+    user_addr: std::net::Ipv6Addr,
+    remote_addr: std::net::Ipv6Addr,
+    user_port: u16,
+    remote_port: u16,
+    protocol: u8,
+    bytes_up: u64,
+    bytes_down: u64,
+    packets: u64,
+    // Whether the flow ended within the record rather than continuing into
+    // the next interval.
+    finished: u8,
+    // The domain the subscriber last resolved the remote address from, or
+    // empty if unknown.
+    domain: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct DomainRecord {
+    time: String,
+    user_addr: std::net::Ipv6Addr,
+    domain: String,
+    remote_addr: std::net::Ipv6Addr,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct DnsRecord {
+    time: String,
+    user_addr: std::net::Ipv6Addr,
+    query: String,
+    qtype: u16,
+    rcode: u8,
+    answers: Vec<std::net::Ipv6Addr>,
+}
+
+const FLOW_TABLE_SCHEMA: &str = r#"
+    CREATE TABLE IF NOT EXISTS {database}.flows (
+        start DateTime64(3),
+        end DateTime64(3),
+        user_addr IPv6,
+        remote_addr IPv6,
+        user_port UInt16,
+        remote_port UInt16,
+        protocol UInt8,
+        bytes_up UInt64,
+        bytes_down UInt64,
+        packets UInt64,
+        finished UInt8 DEFAULT 0,
+        domain LowCardinality(String) DEFAULT ''
+    ) ENGINE = MergeTree
+    PARTITION BY toYYYYMM(start)
+    ORDER BY (user_addr, start)
+"#;
+
+// Upgrades flow tables created before flow termination was recorded.
+const FLOW_TABLE_ADD_FINISHED: &str = r#"
+    ALTER TABLE {database}.flows ADD COLUMN IF NOT EXISTS finished UInt8 DEFAULT 0
+"#;
+
+// Upgrades flow tables created before flows were annotated with domains.
+const FLOW_TABLE_ADD_DOMAIN: &str = r#"
+    ALTER TABLE {database}.flows ADD COLUMN IF NOT EXISTS domain LowCardinality(String) DEFAULT ''
+"#;
+
+const DOMAIN_TABLE_SCHEMA: &str = r#"
+    CREATE TABLE IF NOT EXISTS {database}.domains (
+        time DateTime64(3),
+        user_addr IPv6,
+        domain String,
+        remote_addr IPv6
+    ) ENGINE = MergeTree
+    PARTITION BY toYYYYMM(time)
+    ORDER BY (user_addr, time)
+"#;
+
+const DNS_TABLE_SCHEMA: &str = r#"
+    CREATE TABLE IF NOT EXISTS {database}.dns_responses (
+        time DateTime64(3),
+        user_addr IPv6,
+        query String,
+        qtype UInt16,
+        rcode UInt8,
+        answers Array(IPv6)
+    ) ENGINE = MergeTree
+    PARTITION BY toYYYYMM(time)
+    ORDER BY (user_addr, time)
+"#;
+
+// Flows are aggregated in memory for one flow log interval before being
+// written out, except that TCP connections are written out as soon as they are
+// torn down so that their end time is accurate. Written records are buffered into large batches since
+// ClickHouse strongly prefers few large inserts over many small ones.
+async fn export_records(
+    mut chan: tokio::sync::mpsc::Receiver<Message>,
+    settings: Settings,
+    flow_log_schedule: crate::clock::Schedule,
+    pseudonymizer: Option<std::sync::Arc<crate::privacy::Pseudonymizer>>,
+    stats: std::sync::Arc<crate::stats::Stats>,
+    log: slog::Logger,
+) {
+    let pseudonymizer = pseudonymizer.as_deref();
+    let client = ClickhouseClient::new(settings.clone());
+    // Tables are only created for the records collected at the configured
+    // level.
+    let mut schemas = vec![
+        FLOW_TABLE_SCHEMA,
+        FLOW_TABLE_ADD_FINISHED,
+        FLOW_TABLE_ADD_DOMAIN,
+    ];
+    if settings.level >= AccountingLevel::Domains {
+        schemas.push(DOMAIN_TABLE_SCHEMA);
+    }
+    if settings.level >= AccountingLevel::Dns {
+        schemas.push(DNS_TABLE_SCHEMA);
+    }
+    for schema in schemas {
+        client
+            .execute(&schema.replace("{database}", &settings.database), "")
+            .await
+            .unwrap_or_else(
+                |e| slog::warn!(log, "Failed to create ClickHouse table"; "error" => e.to_string()),
+            );
+    }
+
+    // Flows are annotated with their domain when first seen in each interval,
+    // while the answer they were opened with is most likely still cached.
+    let mut flows: HashMap<FlowKey, (FlowUsage, Option<String>)> = HashMap::new();
+    let mut dns_cache = crate::dns_cache::DnsCache::new(std::time::Instant::now());
+    let mut interval_start = chrono::Utc::now();
+    let mut flow_records: Vec<FlowRecord> = Vec::new();
+    let mut domain_records: Vec<DomainRecord> = Vec::new();
+    let mut dns_records: Vec<DnsRecord> = Vec::new();
+
+    let mut flow_timer = tokio::time::interval_at(
+        tokio::time::Instant::now() + flow_log_schedule.first_delay(interval_start),
+        flow_log_schedule.period,
+    );
+    let mut flush_timer = tokio::time::interval_at(
+        tokio::time::Instant::now() + settings.flush_interval,
+        settings.flush_interval,
+    );
+    loop {
+        tokio::select! {
+            _ = flow_timer.tick() => {
+                let interval_end = flow_log_schedule.boundary(chrono::Utc::now());
+                flow_records.extend(
+                    flows
+                        .drain()
+                        .map(|(key, (usage, domain))| flow_record(&key, &usage, domain, &interval_start, &interval_end, pseudonymizer)),
+                );
+                interval_start = interval_end;
+            }
+            _ = flush_timer.tick() => {
+                flush(&client, "flows", &mut flow_records, &stats, &log);
+                flush(&client, "domains", &mut domain_records, &stats, &log);
+                flush(&client, "dns_responses", &mut dns_records, &stats, &log);
+            }
+            message = chan.recv() => {
+                match message {
+                    Some(Message::Flows(batch)) => {
+                        let now = chrono::Utc::now();
+                        let instant = std::time::Instant::now();
+                        for (key, usage) in batch {
+                            let (flow, _) = flows.entry(key).or_insert_with(|| {
+                                let domain = dns_cache.lookup(key.user_addr, key.remote_addr, instant);
+                                (FlowUsage::default(), domain.map(String::from))
+                            });
+                            *flow += usage;
+                            if flow.is_finished() {
+                                let (usage, domain) = flows.remove(&key).unwrap();
+                                stats.flows_finished_early.increment();
+                                flow_records.push(flow_record(&key, &usage, domain, &interval_start, &now, pseudonymizer));
+                            }
+                        }
+                    }
+                    Some(Message::DnsAnswer { subscriber, response }) => {
+                        dns_cache.learn(subscriber, &response, std::time::Instant::now());
+                        let time = format_timestamp(&chrono::Utc::now());
+                        let domain = response.fqdn.to_string();
+                        domain_records.extend(response.addresses.iter().map(|address| DomainRecord {
+                            time: time.clone(),
+                            user_addr: user_addr(subscriber, pseudonymizer),
+                            domain: domain.clone(),
+                            remote_addr: to_ipv6(*address),
+                        }));
+                        if settings.level >= AccountingLevel::Dns {
+                            dns_records.push(DnsRecord {
+                                time,
+                                user_addr: user_addr(subscriber, pseudonymizer),
+                                query: domain,
+                                qtype: response.qtype,
+                                rcode: response.rcode,
+                                answers: response.addresses.iter().copied().map(to_ipv6).collect(),
+                            });
+                        }
+                    }
+                    Some(Message::ServerName { subscriber, remote_addr, server_name }) => {
+                        dns_cache.learn_server_name(subscriber, remote_addr, &server_name, std::time::Instant::now());
+                        domain_records.push(DomainRecord {
+                            time: format_timestamp(&chrono::Utc::now()),
+                            user_addr: user_addr(subscriber, pseudonymizer),
+                            domain: server_name,
+                            remote_addr: to_ipv6(remote_addr),
+                        });
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        if flow_records.len() >= settings.batch_size {
+            flush(&client, "flows", &mut flow_records, &stats, &log);
+        }
+        if domain_records.len() >= settings.batch_size {
+            flush(&client, "domains", &mut domain_records, &stats, &log);
+        }
+        if dns_records.len() >= settings.batch_size {
+            flush(&client, "dns_responses", &mut dns_records, &stats, &log);
+        }
+    }
+}
+
+// Inserts the buffered records in the background so that a slow ClickHouse
+// server does not stall the collection of new records.
+fn flush<T>(
+    client: &ClickhouseClient,
+    table: &'static str,
+    records: &mut Vec<T>,
+    stats: &std::sync::Arc<crate::stats::Stats>,
+    log: &slog::Logger,
+) where
+    T: serde::Serialize + Send + Sync + 'static,
+{
+    if records.is_empty() {
+        return;
+    }
+    let records = std::mem::take(records);
+    let client = client.clone();
+    let stats = std::sync::Arc::clone(stats);
+    let log = log.clone();
+    tokio::task::spawn(async move {
+        let count = records.len() as u64;
+        match client.insert(table, &records).await {
+            Ok(_) => stats.clickhouse_records_exported.add(count),
+            Err(e) => {
+                stats.clickhouse_export_errors.increment();
+                slog::warn!(log, "Failed to export records to ClickHouse"; "table" => table, "records" => count, "error" => e.to_string());
+            }
+        }
+    });
+}
+
+#[derive(Debug, Clone)]
+struct ClickhouseClient {
+    http: reqwest::Client,
+    settings: Settings,
+}
+impl ClickhouseClient {
+    fn new(settings: Settings) -> ClickhouseClient {
+        ClickhouseClient {
+            http: reqwest::Client::new(),
+            settings,
+        }
+    }
+
+    async fn insert<T: serde::Serialize>(
+        &self,
+        table: &str,
+        records: &[T],
+    ) -> Result<(), ExportError> {
+        let mut body = String::new();
+        for record in records {
+            body.push_str(&serde_json::to_string(record)?);
+            body.push('\n');
+        }
+        let query = format!(
+            "INSERT INTO {}.{} FORMAT JSONEachRow",
+            self.settings.database, table
+        );
+        self.execute(&query, &body).await
+    }
+
+    async fn execute(&self, query: &str, body: &str) -> Result<(), ExportError> {
+        // Let the server buffer inserts from all sources into larger parts.
+        let mut request = self
+            .http
+            .post(&self.settings.url)
+            .query(&[
+                ("query", query),
+                ("async_insert", "1"),
+                ("wait_for_async_insert", "0"),
+            ])
+            .body(body.to_owned());
+        if let Some(user) = &self.settings.user {
+            request = request.header("X-ClickHouse-User", user);
+        }
+        if let Some(password) = &self.settings.password {
+            request = request.header("X-ClickHouse-Key", password);
+        }
+
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            return Err(ExportError::Rejected(response.text().await?));
+        }
+        Ok(())
+    }
+}
+
+fn flow_record(
+    key: &FlowKey,
+    usage: &FlowUsage,
+    domain: Option<String>,
+    start: &chrono::DateTime<chrono::Utc>,
+    end: &chrono::DateTime<chrono::Utc>,
+    pseudonymizer: Option<&crate::privacy::Pseudonymizer>,
+) -> FlowRecord {
+    FlowRecord {
+        start: format_timestamp(start),
+        end: format_timestamp(end),
+        user_addr: user_addr(key.user_addr, pseudonymizer),
+        remote_addr: to_ipv6(key.remote_addr),
+        user_port: key.user_port,
+        remote_port: key.remote_port,
+        protocol: key.protocol,
+        bytes_up: usage.bytes_up,
+        bytes_down: usage.bytes_down,
+        packets: usage.packets,
+        finished: usage.is_finished() as u8,
+        domain: domain.unwrap_or_default(),
+    }
+}
+
+fn format_timestamp(time: &chrono::DateTime<chrono::Utc>) -> String {
+    time.format("%Y-%m-%d %H:%M:%S%.3f").to_string()
+}
+
+// Subscriber addresses are replaced with pseudonyms in privacy mode.
+fn user_addr(
+    addr: std::net::IpAddr,
+    pseudonymizer: Option<&crate::privacy::Pseudonymizer>,
+) -> std::net::Ipv6Addr {
+    match pseudonymizer {
+        Some(pseudonymizer) => pseudonymizer.address(addr),
+        None => to_ipv6(addr),
+    }
+}
+
+// ClickHouse stores both address families in a single IPv6 column, with IPv4
+// addresses mapped into the IPv6 space.
+fn to_ipv6(address: std::net::IpAddr) -> std::net::Ipv6Addr {
+    match address {
+        std::net::IpAddr::V4(address) => address.to_ipv6_mapped(),
+        std::net::IpAddr::V6(address) => address,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flow_record_serialization() {
+        let record = FlowRecord {
+            start: format_timestamp(&chrono::DateTime::from_utc(
+                chrono::NaiveDate::from_ymd(2022, 5, 13).and_hms_milli(23, 16, 50, 125),
+                chrono::Utc,
+            )),
+            end: "2022-05-13 23:17:50.125".to_owned(),
+            user_addr: to_ipv6("10.45.0.2".parse().unwrap()),
+            remote_addr: to_ipv6("2001:db8::1".parse().unwrap()),
+            user_port: 43512,
+            remote_port: 443,
+            protocol: 6,
+            bytes_up: 100,
+            bytes_down: 1500,
+            packets: 3,
+            finished: 0,
+            domain: String::from("xkcd.com"),
+        };
+        let serialized = serde_json::to_value(&record).unwrap();
+        assert_eq!(serialized["start"], "2022-05-13 23:16:50.125");
+        assert_eq!(serialized["user_addr"], "::ffff:10.45.0.2");
+        assert_eq!(serialized["remote_addr"], "2001:db8::1");
+    }
+
+    #[test]
+    fn test_flow_finished_by_teardown() {
+        let mut usage = FlowUsage {
+            packets: 1,
+            fin_up: true,
+            ..Default::default()
+        };
+        assert!(!usage.is_finished());
+        usage += FlowUsage {
+            packets: 1,
+            fin_down: true,
+            ..Default::default()
+        };
+        assert!(usage.is_finished());
+        assert_eq!(usage.packets, 2);
+
+        let reset = FlowUsage {
+            reset: true,
+            ..Default::default()
+        };
+        assert!(reset.is_finished());
+    }
+}
@@ -0,0 +1,528 @@
+// A source of wall clock timestamps. Interval timers use tokio time, which can
+// be paused and advanced in tests, so components that also timestamp records
+// take a clock to keep their timestamps consistent with simulated time.
+pub trait Clock: std::fmt::Debug + Send + Sync {
+    fn now(&self) -> chrono::DateTime<chrono::Utc>;
+
+    // Waits until the clock reaches the deadline.
+    fn sleep_until(
+        &self,
+        deadline: chrono::DateTime<chrono::Utc>,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send + '_>> {
+        let remaining = (deadline - self.now()).to_std().unwrap_or_default();
+        Box::pin(tokio::time::sleep(remaining))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> chrono::DateTime<chrono::Utc> {
+        chrono::Utc::now()
+    }
+}
+
+// When periodic records are cut. Aligned schedules cut records at multiples
+// of the period since the Unix epoch, e.g. at the top of each minute or hour,
+// so that records from different gateways and across restarts line up. The
+// first record after startup then covers a partial interval. Alignment is in
+// the site's local time, so that daily records are cut at local midnight.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Schedule {
+    pub period: std::time::Duration,
+    pub aligned: bool,
+    pub timezone: SiteTimezone,
+}
+impl Schedule {
+    // Returns the time from now until the end of the first interval.
+    pub fn first_delay(&self, now: chrono::DateTime<chrono::Utc>) -> std::time::Duration {
+        if !self.aligned {
+            return self.period;
+        }
+        (self.next_boundary(now) - now).to_std().unwrap_or_default()
+    }
+
+    // Returns the time from now until the end of an interval that started
+    // earlier, e.g. in a previous process.
+    pub fn resumed_delay(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> std::time::Duration {
+        if self.aligned {
+            return self.first_delay(now);
+        }
+        let elapsed = (now - start).to_std().unwrap_or_default();
+        self.period.saturating_sub(elapsed)
+    }
+
+    // Snaps a record boundary to the nearest scheduled boundary, absorbing
+    // the small delays in waking up to cut the record.
+    pub fn boundary(&self, now: chrono::DateTime<chrono::Utc>) -> chrono::DateTime<chrono::Utc> {
+        if !self.aligned {
+            return now;
+        }
+        let half = self.period.as_millis().max(1) as i64 / 2;
+        self.next_boundary(now - chrono::Duration::milliseconds(half))
+    }
+
+    // The first local multiple of the period after the given time. Around a
+    // daylight saving change the site's offset differs before and after the
+    // boundary, so it is tried with both, keeping the earliest boundary whose
+    // local time is actually a multiple. Otherwise the next multiple of the
+    // current local time is converted directly.
+    fn next_boundary(&self, time: chrono::DateTime<chrono::Utc>) -> chrono::DateTime<chrono::Utc> {
+        let period = self.period.as_millis().max(1) as i64;
+        let next_local = |offset: i64| {
+            let local = time.timestamp_millis() + offset * 1000;
+            (local.div_euclid(period) + 1) * period
+        };
+        let later = time + chrono::Duration::milliseconds(period);
+        let offsets = [self.timezone.offset(time), self.timezone.offset(later)];
+        offsets
+            .iter()
+            .map(|&offset| {
+                let millis = next_local(offset) - offset * 1000;
+                (offset, utc_from_millis(millis))
+            })
+            .filter(|&(offset, boundary)| self.timezone.offset(boundary) == offset)
+            .map(|(_, boundary)| boundary)
+            .min()
+            .unwrap_or_else(|| {
+                let local = utc_from_millis(next_local(offsets[0])).naive_utc();
+                self.timezone.to_utc(local)
+            })
+    }
+}
+
+fn utc_from_millis(millis: i64) -> chrono::DateTime<chrono::Utc> {
+    use chrono::TimeZone;
+    chrono::Utc.timestamp_millis(millis)
+}
+
+// The site's local time, either a fixed offset from UTC such as "+13:00" or
+// an IANA time zone such as "Africa/Nairobi", which follows daylight saving
+// time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SiteTimezone {
+    Fixed(chrono::FixedOffset),
+    Named(chrono_tz::Tz),
+}
+
+impl Default for SiteTimezone {
+    fn default() -> Self {
+        SiteTimezone::Fixed(chrono::FixedOffset::east(0))
+    }
+}
+
+impl std::str::FromStr for SiteTimezone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('+') || s.starts_with('-') {
+            return parse_utc_offset(s).map(SiteTimezone::Fixed);
+        }
+        s.parse()
+            .map(SiteTimezone::Named)
+            .map_err(|_| format!("Unknown time zone '{}', expected e.g. 'Africa/Nairobi'", s))
+    }
+}
+
+impl SiteTimezone {
+    // The local time at the given instant.
+    pub fn local(self, time: chrono::DateTime<chrono::Utc>) -> chrono::NaiveDateTime {
+        match self {
+            SiteTimezone::Fixed(offset) => time.with_timezone(&offset).naive_local(),
+            SiteTimezone::Named(tz) => time.with_timezone(&tz).naive_local(),
+        }
+    }
+
+    // The instant of the given local time. Local times repeated when clocks
+    // go back are taken at their first occurrence, and those skipped when
+    // clocks go forward are taken with the offset from before the change.
+    pub fn to_utc(self, local: chrono::NaiveDateTime) -> chrono::DateTime<chrono::Utc> {
+        use chrono::TimeZone;
+        let utc = match self {
+            SiteTimezone::Fixed(offset) => offset
+                .from_local_datetime(&local)
+                .earliest()
+                .map(|time| time.with_timezone(&chrono::Utc)),
+            SiteTimezone::Named(tz) => tz
+                .from_local_datetime(&local)
+                .earliest()
+                .map(|time| time.with_timezone(&chrono::Utc)),
+        };
+        utc.unwrap_or_else(|| {
+            let before = chrono::Utc.from_utc_datetime(&(local - chrono::Duration::days(1)));
+            chrono::Utc.from_utc_datetime(&local) - chrono::Duration::seconds(self.offset(before))
+        })
+    }
+
+    // The site's offset from UTC in seconds at the given instant.
+    fn offset(self, time: chrono::DateTime<chrono::Utc>) -> i64 {
+        (self.local(time) - time.naive_utc()).num_seconds()
+    }
+}
+
+// The current local time at the site, or of the system if no site time zone
+// is configured.
+pub fn local_now(timezone: Option<SiteTimezone>) -> chrono::NaiveDateTime {
+    match timezone {
+        Some(timezone) => timezone.local(chrono::Utc::now()),
+        None => chrono::Local::now().naive_local(),
+    }
+}
+
+// Parses a fixed offset from UTC such as "+13:00" or "-09:30".
+pub fn parse_utc_offset(offset: &str) -> Result<chrono::FixedOffset, String> {
+    let invalid = || format!("Invalid UTC offset '{}', expected e.g. '+13:00'", offset);
+    let (sign, rest) = match offset.split_at(offset.len().min(1)) {
+        ("+", rest) => (1, rest),
+        ("-", rest) => (-1, rest),
+        _ => return Err(invalid()),
+    };
+    let (hours, minutes) = rest.split_once(':').ok_or_else(invalid)?;
+    let hours: i32 = hours.parse().map_err(|_| invalid())?;
+    let minutes: i32 = minutes.parse().map_err(|_| invalid())?;
+    if !(0..60).contains(&minutes) {
+        return Err(invalid());
+    }
+    chrono::FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).ok_or_else(invalid)
+}
+
+// A clock following the capture timestamps of replayed packets, so that
+// recorded traffic is aggregated into the intervals it was captured in,
+// however fast it is replayed.
+#[derive(Debug)]
+pub struct ReplayClock {
+    time: tokio::sync::watch::Sender<chrono::DateTime<chrono::Utc>>,
+}
+impl ReplayClock {
+    pub fn new(start: chrono::DateTime<chrono::Utc>) -> ReplayClock {
+        ReplayClock {
+            time: tokio::sync::watch::channel(start).0,
+        }
+    }
+
+    // Moves the clock forward to the given time. Captures can be slightly out
+    // of order, so the clock never moves back.
+    pub fn advance(&self, time: chrono::DateTime<chrono::Utc>) {
+        self.time.send_if_modified(|now| {
+            let later = time > *now;
+            if later {
+                *now = time;
+            }
+            later
+        });
+    }
+}
+impl Clock for ReplayClock {
+    fn now(&self) -> chrono::DateTime<chrono::Utc> {
+        *self.time.borrow()
+    }
+
+    fn sleep_until(
+        &self,
+        deadline: chrono::DateTime<chrono::Utc>,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send + '_>> {
+        let mut time = self.time.subscribe();
+        Box::pin(async move {
+            // The sender lives as long as the clock, so waiting cannot fail.
+            let _ = time.wait_for(|now| *now >= deadline).await;
+        })
+    }
+}
+
+// Ticks at the end of each interval of a fixed period as measured by a clock,
+// like a tokio interval but following simulated and replayed clocks.
+#[derive(Debug)]
+pub struct Ticker {
+    clock: std::sync::Arc<dyn Clock>,
+    next: chrono::DateTime<chrono::Utc>,
+    period: chrono::Duration,
+}
+impl Ticker {
+    // Starts ticking first_delay after start, then every period.
+    pub fn new(
+        clock: std::sync::Arc<dyn Clock>,
+        start: chrono::DateTime<chrono::Utc>,
+        first_delay: std::time::Duration,
+        period: std::time::Duration,
+    ) -> Ticker {
+        let duration =
+            |duration| chrono::Duration::from_std(duration).expect("Interval out of range");
+        Ticker {
+            clock,
+            next: start + duration(first_delay),
+            period: duration(period),
+        }
+    }
+
+    // Waits for the next tick, returning the time it was due. Cancelling the
+    // wait, e.g. in a select, does not skip the tick.
+    pub async fn tick(&mut self) -> chrono::DateTime<chrono::Utc> {
+        self.clock.sleep_until(self.next).await;
+        let due = self.next;
+        self.next = due + self.period;
+        due
+    }
+}
+
+// Hours recurring daily in local time, e.g. "18:00-23:00". Hours may span
+// midnight, in which case they belong to the day they start on.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct DailyHours {
+    start: chrono::NaiveTime,
+    end: chrono::NaiveTime,
+}
+
+impl std::str::FromStr for DailyHours {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("Invalid hours '{}', expected e.g. '18:00-23:00'", s))?;
+        let parse = |time: &str| {
+            chrono::NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                .map_err(|e| format!("Invalid hours '{}': {}", s, e))
+        };
+        let hours = DailyHours {
+            start: parse(start)?,
+            end: parse(end)?,
+        };
+        if hours.start == hours.end {
+            return Err(format!("Hours '{}' are empty", s));
+        }
+        Ok(hours)
+    }
+}
+
+impl TryFrom<String> for DailyHours {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<DailyHours> for String {
+    fn from(hours: DailyHours) -> Self {
+        hours.to_string()
+    }
+}
+
+impl std::fmt::Display for DailyHours {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+impl DailyHours {
+    // The local start and end of the hours starting on the given day.
+    pub fn window(&self, day: chrono::NaiveDate) -> (chrono::NaiveDateTime, chrono::NaiveDateTime) {
+        let end_day = match self.end > self.start {
+            true => day,
+            false => day.succ(),
+        };
+        (day.and_time(self.start), end_day.and_time(self.end))
+    }
+
+    // The most recent day whose hours have ended by the given local time.
+    pub fn last_completed_day(&self, now: chrono::NaiveDateTime) -> chrono::NaiveDate {
+        let mut day = now.date();
+        while self.window(day).1 > now {
+            day = day.pred();
+        }
+        day
+    }
+
+    // Whether the given local time of day falls within the hours.
+    pub fn contains(&self, time: chrono::NaiveTime) -> bool {
+        match self.end > self.start {
+            true => self.start <= time && time < self.end,
+            false => self.start <= time || time < self.end,
+        }
+    }
+}
+
+// A clock following tokio time from a fixed starting timestamp, so that
+// advancing paused tokio time in tests advances the timestamps by exactly the
+// same amount.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct SimulatedClock {
+    start: chrono::DateTime<chrono::Utc>,
+    start_instant: tokio::time::Instant,
+}
+#[cfg(test)]
+impl SimulatedClock {
+    pub fn new(start: chrono::DateTime<chrono::Utc>) -> SimulatedClock {
+        SimulatedClock {
+            start,
+            start_instant: tokio::time::Instant::now(),
+        }
+    }
+}
+#[cfg(test)]
+impl Clock for SimulatedClock {
+    fn now(&self) -> chrono::DateTime<chrono::Utc> {
+        let elapsed = tokio::time::Instant::now() - self.start_instant;
+        self.start + chrono::Duration::from_std(elapsed).expect("Simulated time out of range")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_simulated_clock_follows_tokio_time() {
+        let start = chrono::Utc.ymd(2022, 5, 13).and_hms(23, 16, 50);
+        let clock = SimulatedClock::new(start);
+        assert_eq!(clock.now(), start);
+
+        tokio::time::advance(std::time::Duration::from_secs(90)).await;
+        assert_eq!(clock.now(), start + chrono::Duration::seconds(90));
+    }
+
+    #[tokio::test]
+    async fn test_replay_ticker_follows_replayed_time() {
+        let start = chrono::Utc.ymd(2022, 5, 13).and_hms(23, 0, 0);
+        let clock = std::sync::Arc::new(ReplayClock::new(start));
+        let mut ticker = Ticker::new(
+            clock.clone(),
+            start,
+            std::time::Duration::from_secs(60),
+            std::time::Duration::from_secs(60),
+        );
+
+        // Replayed time jumping past several intervals ticks for each.
+        clock.advance(start + chrono::Duration::seconds(150));
+        clock.advance(start + chrono::Duration::seconds(10));
+        assert_eq!(ticker.tick().await, start + chrono::Duration::seconds(60));
+        assert_eq!(ticker.tick().await, start + chrono::Duration::seconds(120));
+        let third = tokio::task::spawn(async move { ticker.tick().await });
+        tokio::task::yield_now().await;
+        assert!(!third.is_finished());
+        clock.advance(start + chrono::Duration::seconds(180));
+        assert_eq!(third.await.unwrap(), start + chrono::Duration::seconds(180));
+    }
+
+    #[test]
+    fn test_aligned_schedule() {
+        let schedule = Schedule {
+            period: std::time::Duration::from_secs(3600),
+            aligned: true,
+            timezone: SiteTimezone::default(),
+        };
+        let now = chrono::Utc.ymd(2022, 5, 13).and_hms(23, 16, 50);
+        assert_eq!(
+            schedule.first_delay(now),
+            std::time::Duration::from_secs(43 * 60 + 10)
+        );
+        let top_of_hour = chrono::Utc.ymd(2022, 5, 14).and_hms(0, 0, 0);
+        assert_eq!(
+            schedule.boundary(top_of_hour + chrono::Duration::milliseconds(20)),
+            top_of_hour
+        );
+        assert_eq!(
+            schedule.boundary(top_of_hour - chrono::Duration::milliseconds(20)),
+            top_of_hour
+        );
+
+        let unaligned = Schedule {
+            aligned: false,
+            ..schedule
+        };
+        assert_eq!(unaligned.first_delay(now), unaligned.period);
+        assert_eq!(unaligned.boundary(now), now);
+
+        // Daily records at a site 13 hours ahead of UTC are cut at its local
+        // midnight, 12:16:50 after the last.
+        let daily = Schedule {
+            period: std::time::Duration::from_secs(24 * 3600),
+            aligned: true,
+            timezone: "+13:00".parse().unwrap(),
+        };
+        assert_eq!(
+            daily.first_delay(now),
+            std::time::Duration::from_secs(11 * 3600 + 43 * 60 + 10)
+        );
+        let local_midnight = chrono::Utc.ymd(2022, 5, 14).and_hms(11, 0, 0);
+        assert_eq!(daily.boundary(local_midnight), local_midnight);
+        assert_eq!(
+            parse_utc_offset("-09:30").unwrap().local_minus_utc(),
+            -(9 * 3600 + 30 * 60)
+        );
+        assert!(parse_utc_offset("13:00").is_err());
+        assert!(parse_utc_offset("+25:00").is_err());
+
+        // Named time zones follow daylight saving time. The day Auckland
+        // clocks go back is 25 hours long, and the hour after 02:30 NZDT is
+        // 02:00 NZST.
+        let auckland: SiteTimezone = "Pacific/Auckland".parse().unwrap();
+        let daily = Schedule {
+            timezone: auckland,
+            ..daily
+        };
+        let start_of_day = chrono::Utc.ymd(2022, 4, 2).and_hms(11, 0, 0);
+        assert_eq!(
+            daily.first_delay(start_of_day),
+            std::time::Duration::from_secs(25 * 3600)
+        );
+        let next_day = chrono::Utc.ymd(2022, 4, 3).and_hms(12, 0, 0);
+        assert_eq!(
+            daily.boundary(next_day + chrono::Duration::milliseconds(20)),
+            next_day
+        );
+        let hourly = Schedule {
+            timezone: auckland,
+            ..schedule
+        };
+        assert_eq!(
+            hourly.first_delay(chrono::Utc.ymd(2022, 4, 2).and_hms(13, 30, 0)),
+            std::time::Duration::from_secs(30 * 60)
+        );
+        assert!("Mars/Olympus_Mons".parse::<SiteTimezone>().is_err());
+    }
+
+    #[test]
+    fn test_daily_hours() {
+        let day = chrono::NaiveDate::from_ymd(2022, 5, 13);
+        let evening: DailyHours = "18:00-23:00".parse().unwrap();
+        assert_eq!(
+            evening.window(day),
+            (day.and_hms(18, 0, 0), day.and_hms(23, 0, 0))
+        );
+        assert_eq!(evening.last_completed_day(day.and_hms(23, 0, 0)), day);
+        assert_eq!(
+            evening.last_completed_day(day.and_hms(22, 59, 0)),
+            day.pred()
+        );
+        assert!(evening.contains(chrono::NaiveTime::from_hms(18, 0, 0)));
+        assert!(!evening.contains(chrono::NaiveTime::from_hms(23, 0, 0)));
+
+        // Hours spanning midnight belong to the day they start on.
+        let night: DailyHours = "20:00-01:00".parse().unwrap();
+        assert_eq!(night.window(day).1, day.succ().and_hms(1, 0, 0));
+        assert_eq!(
+            night.last_completed_day(day.and_hms(0, 30, 0)),
+            day.pred().pred()
+        );
+        assert_eq!(night.last_completed_day(day.and_hms(1, 30, 0)), day.pred());
+        assert!(night.contains(chrono::NaiveTime::from_hms(0, 30, 0)));
+        assert!(!night.contains(chrono::NaiveTime::from_hms(12, 0, 0)));
+        assert_eq!(night.to_string(), "20:00-01:00");
+
+        assert!("18:00".parse::<DailyHours>().is_err());
+        assert!("18:00-18:00".parse::<DailyHours>().is_err());
+    }
+}
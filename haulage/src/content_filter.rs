@@ -0,0 +1,332 @@
+use std::collections::{HashMap, HashSet};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum FilterError {
+    #[error("Database operation failed: {0}")]
+    DatabaseError(#[from] sqlx::error::Error),
+    #[error("Failed to update iptables: {0}")]
+    IptablesExecutionError(#[from] std::io::Error),
+    #[error("iptables rejected rule: {0}")]
+    RuleRejected(String),
+}
+
+// How long cached subscriber filter settings are trusted before being
+// refreshed from the database.
+const SUBSCRIBER_CATEGORY_REFRESH: std::time::Duration = std::time::Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct ContentFilter {
+    dispatch_channel: tokio::sync::mpsc::Sender<Message>,
+}
+impl ContentFilter {
+    pub fn new(
+        category_lists: &HashMap<String, std::path::PathBuf>,
+        rule_lifetime: std::time::Duration,
+        db_pool: std::sync::Arc<sqlx::PgPool>,
+        log: slog::Logger,
+    ) -> Result<ContentFilter, std::io::Error> {
+        let categories = load_categories(category_lists)?;
+        for (name, domains) in &categories {
+            slog::info!(log, "Loaded content filter category"; "category" => name, "domains" => domains.len());
+        }
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(64);
+        tokio::task::spawn(async move {
+            filter_dns_answers(receiver, categories, rule_lifetime, db_pool, log).await;
+        });
+        Ok(ContentFilter {
+            dispatch_channel: sender,
+        })
+    }
+    pub fn clone_input_channel(&self) -> tokio::sync::mpsc::Sender<Message> {
+        self.dispatch_channel.clone()
+    }
+}
+
+pub enum Message {
+    // A DNS response observed on its way to a subscriber.
+    DnsAnswer {
+        subscriber: std::net::IpAddr,
+        response: crate::packet_parser::DnsResponse,
+    },
+}
+
+type Categories = HashMap<String, HashSet<String>>;
+
+// Category lists are plain text files with one domain per line. A listed
+// domain also matches all of its subdomains. Blank lines and lines starting
+// with '#' are ignored.
+fn load_categories(
+    category_lists: &HashMap<String, std::path::PathBuf>,
+) -> Result<Categories, std::io::Error> {
+    let mut categories = Categories::new();
+    for (name, path) in category_lists {
+        let contents = std::fs::read_to_string(path)?;
+        categories.insert(name.clone(), parse_category_list(&contents));
+    }
+    Ok(categories)
+}
+
+pub fn parse_category_list(contents: &str) -> HashSet<String> {
+    contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(normalize_domain)
+        .collect()
+}
+
+pub fn normalize_domain(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+// Returns the first of the given categories containing the domain or any of
+// its parent domains.
+fn matching_category<'a>(
+    categories: &Categories,
+    enabled: &'a [String],
+    domain: &str,
+) -> Option<&'a String> {
+    let domain = normalize_domain(domain);
+    let mut suffixes = vec![domain.as_str()];
+    suffixes.extend(domain.match_indices('.').map(|(i, _)| &domain[i + 1..]));
+
+    enabled
+        .iter()
+        .find(|category| match categories.get(*category) {
+            Some(domains) => suffixes.iter().any(|suffix| domains.contains(*suffix)),
+            None => false,
+        })
+}
+
+struct CachedCategories {
+    enabled: Vec<String>,
+    fetched: std::time::Instant,
+}
+
+async fn filter_dns_answers(
+    mut chan: tokio::sync::mpsc::Receiver<Message>,
+    categories: Categories,
+    rule_lifetime: std::time::Duration,
+    db_pool: std::sync::Arc<sqlx::PgPool>,
+    log: slog::Logger,
+) {
+    let mut subscriber_categories = HashMap::<std::net::IpAddr, CachedCategories>::new();
+
+    // Track the installed block rules with their expiration, so that the
+    // forwarding chain does not grow without bound as addresses change.
+    let mut installed_rules =
+        HashMap::<(std::net::IpAddr, std::net::IpAddr), std::time::Instant>::new();
+
+    let mut expiration_timer = tokio::time::interval(std::cmp::min(
+        rule_lifetime,
+        std::time::Duration::from_secs(60),
+    ));
+    loop {
+        tokio::select! {
+            _ = expiration_timer.tick() => {
+                let now = std::time::Instant::now();
+                let expired: Vec<_> = installed_rules
+                    .iter()
+                    .filter(|(_, expiration)| **expiration <= now)
+                    .map(|(rule, _)| *rule)
+                    .collect();
+                for (subscriber, remote) in expired {
+                    match delete_block_rule(&subscriber, &remote).await {
+                        Ok(_) => {
+                            installed_rules.remove(&(subscriber, remote));
+                        }
+                        Err(e) => {
+                            slog::warn!(log, "Failed to remove expired content filter rule"; "subscriber" => subscriber.to_string(), "remote" => remote.to_string(), "error" => e.to_string());
+                        }
+                    }
+                }
+            }
+            message = chan.recv() => {
+                let (subscriber, response) = match message {
+                    Some(Message::DnsAnswer { subscriber, response }) => (subscriber, response),
+                    None => break,
+                };
+
+                let needs_refresh = match subscriber_categories.get(&subscriber) {
+                    Some(cached) => cached.fetched.elapsed() > SUBSCRIBER_CATEGORY_REFRESH,
+                    None => true,
+                };
+                if needs_refresh {
+                    match query_subscriber_categories(&db_pool, subscriber).await {
+                        Ok(enabled) => {
+                            subscriber_categories.insert(subscriber, CachedCategories { enabled, fetched: std::time::Instant::now() });
+                        }
+                        Err(e) => {
+                            slog::warn!(log, "Unable to query subscriber content filter"; "subscriber" => subscriber.to_string(), "error" => e.to_string());
+                            continue;
+                        }
+                    }
+                }
+                let enabled = &subscriber_categories.get(&subscriber).unwrap().enabled;
+
+                let fqdn = response.fqdn.to_string();
+                let category = match matching_category(&categories, enabled, &fqdn) {
+                    Some(category) => category,
+                    None => continue,
+                };
+
+                for remote in response.addresses {
+                    let expiration = std::time::Instant::now() + rule_lifetime;
+                    if let Some(existing) = installed_rules.get_mut(&(subscriber, remote)) {
+                        *existing = expiration;
+                        continue;
+                    }
+                    match insert_block_rule(&subscriber, &remote).await {
+                        Ok(_) => {
+                            slog::info!(log, "Blocked filtered destination"; "subscriber" => subscriber.to_string(), "domain" => &fqdn, "category" => category, "remote" => remote.to_string());
+                            installed_rules.insert((subscriber, remote), expiration);
+                        }
+                        Err(e) => {
+                            slog::error!(log, "Failed to block filtered destination"; "subscriber" => subscriber.to_string(), "remote" => remote.to_string(), "error" => e.to_string());
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn iptables_command(address: &std::net::IpAddr) -> &'static str {
+    match address {
+        std::net::IpAddr::V4(_) => "iptables",
+        std::net::IpAddr::V6(_) => "ip6tables",
+    }
+}
+
+async fn insert_block_rule(
+    subscriber: &std::net::IpAddr,
+    remote: &std::net::IpAddr,
+) -> Result<(), FilterError> {
+    if subscriber.is_ipv4() != remote.is_ipv4() {
+        // A subscriber can only reach the address family it is assigned.
+        return Ok(());
+    }
+    let command_output = tokio::process::Command::new(iptables_command(subscriber))
+        .args([
+            "-I",
+            "FORWARD",
+            "-s",
+            &subscriber.to_string(),
+            "-d",
+            &remote.to_string(),
+            "-j",
+            "REJECT",
+        ])
+        .output()
+        .await?;
+
+    if !command_output.status.success() {
+        return Err(FilterError::RuleRejected(
+            String::from_utf8_lossy(&command_output.stderr).into_owned(),
+        ));
+    }
+    Ok(())
+}
+
+async fn delete_block_rule(
+    subscriber: &std::net::IpAddr,
+    remote: &std::net::IpAddr,
+) -> Result<(), FilterError> {
+    if subscriber.is_ipv4() != remote.is_ipv4() {
+        return Ok(());
+    }
+    let command_output = tokio::process::Command::new(iptables_command(subscriber))
+        .args([
+            "-D",
+            "FORWARD",
+            "-s",
+            &subscriber.to_string(),
+            "-d",
+            &remote.to_string(),
+            "-j",
+            "REJECT",
+        ])
+        .output()
+        .await?;
+
+    if !command_output.status.success() {
+        return Err(FilterError::RuleRejected(
+            String::from_utf8_lossy(&command_output.stderr).into_owned(),
+        ));
+    }
+    Ok(())
+}
+
+async fn query_subscriber_categories(
+    db_pool: &sqlx::PgPool,
+    ip: std::net::IpAddr,
+) -> Result<Vec<String>, FilterError> {
+    let mut transaction = db_pool.begin().await?;
+
+    let category_query = r#"
+        SELECT "content_filter_categories"
+        FROM subscribers
+        INNER JOIN static_ips ON static_ips.imsi = subscribers.imsi
+        WHERE static_ips.ip >>= $1
+    "#;
+
+    let rows: Vec<(Vec<String>,)> = sqlx::query_as(category_query)
+        .bind(ipnetwork::IpNetwork::from(ip))
+        .fetch_all(&mut transaction)
+        .await?;
+
+    transaction.commit().await?;
+
+    // Unknown subscribers are not filtered.
+    Ok(rows
+        .into_iter()
+        .next()
+        .map(|(categories,)| categories)
+        .unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_categories() -> Categories {
+        let mut categories = Categories::new();
+        categories.insert(
+            "gambling".to_owned(),
+            parse_category_list("# Gambling sites\nbets.example\n\nCasino.Example.\n"),
+        );
+        categories.insert("social".to_owned(), parse_category_list("social.example\n"));
+        categories
+    }
+
+    #[test]
+    fn test_match_subdomain() {
+        let categories = make_categories();
+        let enabled = vec!["gambling".to_owned()];
+        assert_eq!(
+            matching_category(&categories, &enabled, "www.casino.example."),
+            Some(&"gambling".to_owned())
+        );
+        assert_eq!(
+            matching_category(&categories, &enabled, "BETS.example"),
+            Some(&"gambling".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_no_match_for_disabled_or_similar_domains() {
+        let categories = make_categories();
+        let enabled = vec!["gambling".to_owned()];
+        assert_eq!(
+            matching_category(&categories, &enabled, "social.example."),
+            None
+        );
+        assert_eq!(
+            matching_category(&categories, &enabled, "notbets.example."),
+            None
+        );
+        assert_eq!(matching_category(&categories, &[], "bets.example."), None);
+    }
+}
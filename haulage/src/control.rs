@@ -0,0 +1,353 @@
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+#[derive(Error, Debug)]
+pub enum ControlError {
+    #[error("Control socket io failed: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Failed to parse json: {0}")]
+    SerdeJsonError(#[from] serde_json::Error),
+    #[error("Lost communication with subsystem")]
+    SubsystemUnavailable,
+    #[error("{0}")]
+    AdminError(#[from] crate::admin::AdminError),
+    #[error("{0}")]
+    ApiKeyError(#[from] crate::api_keys::ApiKeyError),
+    #[error("{0}")]
+    EnforcementError(#[from] crate::enforcer::EnforcementError),
+    #[error("{0}")]
+    ForecastError(#[from] crate::forecast::ForecastError),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(&'static str),
+    #[error("Enforcement is disabled")]
+    EnforcementDisabled,
+    #[error("A valid API key is required")]
+    Unauthenticated,
+    #[error("The {0} role is required")]
+    Unauthorized(&'static str),
+}
+
+// Handles to the live subsystems that control requests are answered from.
+#[derive(Debug, Clone)]
+pub struct Context {
+    pub db_pool: std::sync::Arc<sqlx::PgPool>,
+    pub user_aggregator: tokio::sync::mpsc::Sender<crate::async_aggregator::Message>,
+    pub user_accounter: tokio::sync::mpsc::Sender<crate::accounter::Message>,
+    // None when haulage only meters usage.
+    pub enforcer: Option<std::sync::Arc<crate::enforcer::Iptables>>,
+    // Whether requests must carry an API key granting the role they require,
+    // e.g. when the socket is shared with kiosk software.
+    pub require_api_key: bool,
+    pub plan_change_rules: std::sync::Arc<Vec<crate::admin::PlanChangeRule>>,
+}
+
+// Requests are sent as one json object per line, and each receives a single
+// line json response.
+#[derive(Debug, serde::Deserialize)]
+struct Envelope {
+    key: Option<String>,
+    #[serde(flatten)]
+    request: Request,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Request {
+    SubscriberUsage {
+        ip: std::net::IpAddr,
+    },
+    // The latest forecast of how long the subscriber's balance lasts, or null
+    // if they have none.
+    Forecast {
+        imsi: String,
+    },
+    Suspend {
+        imsi: String,
+    },
+    Resume {
+        imsi: String,
+    },
+    Exempt {
+        imsi: String,
+        minutes: u64,
+    },
+    TopUp {
+        imsi: String,
+        bytes: u64,
+    },
+    // Temporarily replaces the rates or service class of the subscriber's
+    // current policy, e.g. for a promotional boost.
+    OverridePolicy {
+        imsi: String,
+        backhaul_uplink_kibps: Option<u32>,
+        backhaul_downlink_kibps: Option<u32>,
+        dscp: Option<u8>,
+        minutes: u64,
+    },
+    ClearOverride {
+        imsi: String,
+    },
+    // Moves the subscriber to the plan named by its positive balance policy,
+    // keeping their zero balance policy unless another is given.
+    ChangePlan {
+        imsi: String,
+        policy: String,
+        zero_balance_policy: Option<String>,
+    },
+}
+impl Request {
+    fn required_role(&self) -> crate::api_keys::Role {
+        match self {
+            Request::SubscriberUsage { .. } | Request::Forecast { .. } => {
+                crate::api_keys::Role::ReadOnly
+            }
+            Request::TopUp { .. } => crate::api_keys::Role::Cashier,
+            Request::Suspend { .. }
+            | Request::Resume { .. }
+            | Request::Exempt { .. }
+            | Request::OverridePolicy { .. }
+            | Request::ClearOverride { .. }
+            | Request::ChangePlan { .. } => crate::api_keys::Role::Admin,
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum Response {
+    Ok { result: serde_json::Value },
+    Error { message: String },
+}
+
+#[derive(Debug, serde::Serialize)]
+struct SubscriberUsage {
+    ip: std::net::IpAddr,
+    // Balance including bytes not yet synchronized to the database, or None if
+    // the subscriber has not been seen since startup.
+    live_balance: Option<i64>,
+    // Usage aggregated in the current, not yet reported, interval.
+    current_interval_usage: Option<crate::NetResourceBundle>,
+}
+
+pub async fn serve(
+    socket_path: std::path::PathBuf,
+    context: Context,
+    log: slog::Logger,
+) -> Result<(), ControlError> {
+    // Remove a stale socket left behind by a previous run.
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(&socket_path)?;
+    slog::info!(log, "Listening for control connections"; "path" => socket_path.display().to_string());
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let context = context.clone();
+        let connection_log = log.new(slog::o!());
+        tokio::task::spawn(async move {
+            handle_connection(stream, context, &connection_log)
+                .await
+                .unwrap_or_else(|e| slog::warn!(connection_log, "Control connection failed"; "error" => e.to_string()));
+        });
+    }
+}
+
+async fn handle_connection(
+    stream: tokio::net::UnixStream,
+    context: Context,
+    log: &slog::Logger,
+) -> Result<(), ControlError> {
+    let (read_half, mut write_half) = stream.into_split();
+    let mut lines = tokio::io::BufReader::new(read_half).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        slog::debug!(log, "Control request"; "request" => &line);
+        let response = match serde_json::from_str::<Envelope>(&line) {
+            Ok(envelope) => match authorize_and_handle(envelope, &context, log).await {
+                Ok(result) => Response::Ok { result },
+                Err(e) => Response::Error {
+                    message: e.to_string(),
+                },
+            },
+            Err(e) => Response::Error {
+                message: format!("Invalid request: {}", e),
+            },
+        };
+
+        let mut serialized = serde_json::to_vec(&response)?;
+        serialized.push(b'\n');
+        write_half.write_all(&serialized).await?;
+    }
+
+    Ok(())
+}
+
+async fn authorize_and_handle(
+    envelope: Envelope,
+    context: &Context,
+    log: &slog::Logger,
+) -> Result<serde_json::Value, ControlError> {
+    // Without required keys, access is governed by the socket's permissions.
+    let key_name = match context.require_api_key {
+        true => {
+            let key = envelope.key.ok_or(ControlError::Unauthenticated)?;
+            let api_key = crate::api_keys::authenticate(&context.db_pool, &key)
+                .await?
+                .ok_or(ControlError::Unauthenticated)?;
+            let required = envelope.request.required_role();
+            if !api_key.role.permits(required) {
+                slog::warn!(log, "Rejected unauthorized control request"; "key" => &api_key.name, "required_role" => required.as_str());
+                return Err(ControlError::Unauthorized(required.as_str()));
+            }
+            api_key.name
+        }
+        false => String::from("control_socket"),
+    };
+    handle_request(envelope.request, &key_name, context, log).await
+}
+
+async fn handle_request(
+    request: Request,
+    key_name: &str,
+    context: &Context,
+    log: &slog::Logger,
+) -> Result<serde_json::Value, ControlError> {
+    match request {
+        Request::SubscriberUsage { ip } => {
+            let usage = SubscriberUsage {
+                ip,
+                live_balance: query_live_balance(context, ip).await?,
+                current_interval_usage: query_interval_usage(context, ip).await?,
+            };
+            Ok(serde_json::to_value(usage)?)
+        }
+        Request::Forecast { imsi } => {
+            let forecast = crate::forecast::query_forecast(&context.db_pool, &imsi).await?;
+            Ok(serde_json::to_value(forecast)?)
+        }
+        Request::Suspend { imsi } => {
+            let state =
+                crate::admin::set_subscriber_suspended(&context.db_pool, &imsi, true, log).await?;
+            Ok(serde_json::to_value(state)?)
+        }
+        Request::Resume { imsi } => {
+            let state =
+                crate::admin::set_subscriber_suspended(&context.db_pool, &imsi, false, log).await?;
+            Ok(serde_json::to_value(state)?)
+        }
+        Request::Exempt { imsi, minutes } => {
+            let duration = std::time::Duration::from_secs(minutes * 60);
+            let state =
+                crate::admin::exempt_subscriber(&context.db_pool, &imsi, duration, log).await?;
+            Ok(serde_json::to_value(state)?)
+        }
+        Request::TopUp { imsi, bytes } => {
+            let bytes = i64::try_from(bytes).unwrap_or(i64::MAX);
+            let state =
+                crate::admin::top_up_subscriber(&context.db_pool, &imsi, bytes, key_name, log)
+                    .await?;
+            Ok(serde_json::to_value(state)?)
+        }
+        Request::OverridePolicy {
+            imsi,
+            backhaul_uplink_kibps,
+            backhaul_downlink_kibps,
+            dscp,
+            minutes,
+        } => {
+            if dscp.is_some_and(|dscp| dscp > 63) {
+                return Err(ControlError::InvalidRequest("dscp must be at most 63"));
+            }
+            // Overrides are meant to be short lived, so are capped at a year.
+            if minutes == 0 || minutes > 366 * 24 * 60 {
+                return Err(ControlError::InvalidRequest(
+                    "minutes must be between 1 and 527040",
+                ));
+            }
+            let parameters = crate::enforcer::OverrideParameters {
+                backhaul_ul_rate_kibps: backhaul_uplink_kibps,
+                backhaul_dl_rate_kibps: backhaul_downlink_kibps,
+                dscp,
+                expires: chrono::Utc::now()
+                    + chrono::Duration::minutes(
+                        i64::try_from(minutes).unwrap_or(i64::MAX / 60_000),
+                    ),
+            };
+            let policy_override = context
+                .enforcer
+                .as_ref()
+                .ok_or(ControlError::EnforcementDisabled)?
+                .override_policy(&imsi, Some(parameters))
+                .await?;
+            slog::info!(log, "Overrode subscriber policy"; "imsi" => &imsi, "key" => key_name, "minutes" => minutes);
+            Ok(serde_json::to_value(policy_override)?)
+        }
+        Request::ClearOverride { imsi } => {
+            context
+                .enforcer
+                .as_ref()
+                .ok_or(ControlError::EnforcementDisabled)?
+                .override_policy(&imsi, None)
+                .await?;
+            slog::info!(log, "Cleared subscriber policy override"; "imsi" => &imsi, "key" => key_name);
+            Ok(serde_json::Value::Null)
+        }
+        Request::ChangePlan {
+            imsi,
+            policy,
+            zero_balance_policy,
+        } => {
+            let plan = crate::admin::Plan {
+                policy,
+                zero_balance_policy,
+            };
+            let state = crate::admin::change_subscriber_plan(
+                &context.db_pool,
+                &imsi,
+                &plan,
+                &context.plan_change_rules,
+                key_name,
+                log,
+            )
+            .await?;
+            Ok(serde_json::to_value(state)?)
+        }
+    }
+}
+
+async fn query_live_balance(
+    context: &Context,
+    ip: std::net::IpAddr,
+) -> Result<Option<i64>, ControlError> {
+    let (out_channel, result_channel) = tokio::sync::oneshot::channel();
+    context
+        .user_accounter
+        .send(crate::accounter::Message::GetBalance { ip, out_channel })
+        .await
+        .or(Err(ControlError::SubsystemUnavailable))?;
+    result_channel
+        .await
+        .or(Err(ControlError::SubsystemUnavailable))
+}
+
+async fn query_interval_usage(
+    context: &Context,
+    ip: std::net::IpAddr,
+) -> Result<Option<crate::NetResourceBundle>, ControlError> {
+    let (out_channel, result_channel) = tokio::sync::oneshot::channel();
+    context
+        .user_aggregator
+        .send(crate::async_aggregator::Message::GetTotal {
+            id: ip,
+            out_channel,
+        })
+        .await
+        .or(Err(ControlError::SubsystemUnavailable))?;
+    result_channel
+        .await
+        .or(Err(ControlError::SubsystemUnavailable))
+}
@@ -0,0 +1,372 @@
+use std::io::Write;
+
+// Writes a copy of captured traffic to a pcap-ng file for postmortem analysis.
+// Besides the packets themselves, the file records the capture interfaces and
+// periodic interface statistics blocks with drop counters, so that analysis
+// can account for traffic the capture itself missed.
+
+// Packets are queued to a dedicated writer thread, and dropped (and counted)
+// rather than stalling capture if the writer falls behind.
+const QUEUE_DEPTH: usize = 4096;
+const STATISTICS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+const SNAPLEN: u32 = 65535;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_INTERFACE_STATISTICS: u32 = 0x0000_0005;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const OPT_END: u16 = 0;
+const OPT_SHB_USERAPPL: u16 = 4;
+const OPT_IF_NAME: u16 = 2;
+const OPT_IF_TSRESOL: u16 = 9;
+const OPT_ISB_IFRECV: u16 = 4;
+const OPT_ISB_IFDROP: u16 = 5;
+const OPT_ISB_OSDROP: u16 = 7;
+const OPT_ISB_USRDELIV: u16 = 8;
+
+pub struct DebugCapture {
+    queue: std::sync::mpsc::SyncSender<Message>,
+    stats: std::sync::Arc<crate::stats::Stats>,
+}
+impl DebugCapture {
+    pub fn new(
+        path: &std::path::Path,
+        stats: std::sync::Arc<crate::stats::Stats>,
+        log: slog::Logger,
+    ) -> Result<DebugCapture, std::io::Error> {
+        let mut writer = PcapngWriter::new(std::io::BufWriter::new(std::fs::File::create(path)?));
+        writer.write_section_header(concat!("haulage ", env!("CARGO_PKG_VERSION")))?;
+
+        let (sender, receiver) = std::sync::mpsc::sync_channel(QUEUE_DEPTH);
+        let writer_stats = std::sync::Arc::clone(&stats);
+        std::thread::spawn(move || write_packets(receiver, writer, writer_stats, log));
+        Ok(DebugCapture {
+            queue: sender,
+            stats,
+        })
+    }
+
+    // Starts a new interface in the capture, which all following packets are
+    // attributed to.
+    pub fn start_interface(&self, name: &str, link_type: crate::capture::LinkType) {
+        self.send(Message::Interface {
+            name: name.to_owned(),
+            link_type: link_type.pcap_link_type(),
+        });
+    }
+
+    pub fn record(&self, packet: &[u8]) {
+        self.send(Message::Packet {
+            timestamp: std::time::SystemTime::now(),
+            data: bytes::Bytes::copy_from_slice(packet),
+        });
+    }
+
+    fn send(&self, message: Message) {
+        if self.queue.try_send(message).is_err() {
+            self.stats.debug_capture_drops.increment();
+        }
+    }
+}
+
+enum Message {
+    Interface {
+        name: String,
+        link_type: u16,
+    },
+    Packet {
+        timestamp: std::time::SystemTime,
+        data: bytes::Bytes,
+    },
+}
+
+// The capture interface currently being written, with its counters at the time
+// it was added so that statistics cover only this capture.
+struct CurrentInterface {
+    id: u32,
+    name: String,
+    baseline: InterfaceCounters,
+    drops_baseline: u64,
+    delivered: u64,
+}
+
+fn write_packets(
+    receiver: std::sync::mpsc::Receiver<Message>,
+    mut writer: PcapngWriter<std::io::BufWriter<std::fs::File>>,
+    stats: std::sync::Arc<crate::stats::Stats>,
+    log: slog::Logger,
+) {
+    let mut next_interface_id = 0;
+    let mut current: Option<CurrentInterface> = None;
+    let mut last_statistics = std::time::Instant::now();
+
+    loop {
+        let result = match receiver.recv_timeout(STATISTICS_INTERVAL) {
+            Ok(Message::Interface { name, link_type }) => {
+                if let Some(previous) = &current {
+                    write_statistics(&mut writer, previous, &stats)
+                        .unwrap_or_else(|e| slog::warn!(log, "Failed to write capture statistics"; "error" => e.to_string()));
+                }
+                let result = writer.write_interface_description(&name, link_type);
+                current = Some(CurrentInterface {
+                    id: next_interface_id,
+                    baseline: InterfaceCounters::read(&name).unwrap_or_default(),
+                    name,
+                    drops_baseline: stats.debug_capture_drops.get(),
+                    delivered: 0,
+                });
+                next_interface_id += 1;
+                result
+            }
+            Ok(Message::Packet { timestamp, data }) => match current.as_mut() {
+                Some(interface) => {
+                    interface.delivered += 1;
+                    writer.write_enhanced_packet(interface.id, timestamp, &data)
+                }
+                None => Ok(()),
+            },
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => Ok(()),
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        if let Err(e) = result {
+            slog::error!(log, "Failed to write debug capture, stopping"; "error" => e.to_string());
+            return;
+        }
+
+        if last_statistics.elapsed() >= STATISTICS_INTERVAL {
+            if let Some(interface) = &current {
+                write_statistics(&mut writer, interface, &stats)
+                    .and_then(|_| writer.flush())
+                    .unwrap_or_else(|e| slog::warn!(log, "Failed to write capture statistics"; "error" => e.to_string()));
+            }
+            last_statistics = std::time::Instant::now();
+        }
+    }
+
+    if let Some(interface) = &current {
+        write_statistics(&mut writer, interface, &stats)
+            .and_then(|_| writer.flush())
+            .unwrap_or_else(|e| slog::warn!(log, "Failed to write capture statistics"; "error" => e.to_string()));
+    }
+}
+
+fn write_statistics<W: Write>(
+    writer: &mut PcapngWriter<W>,
+    interface: &CurrentInterface,
+    stats: &crate::stats::Stats,
+) -> Result<(), std::io::Error> {
+    let counters = InterfaceCounters::read(&interface.name).map(|c| c.since(&interface.baseline));
+    writer.write_interface_statistics(
+        interface.id,
+        std::time::SystemTime::now(),
+        &CaptureStatistics {
+            interface_received: counters.as_ref().map(|c| c.packets),
+            interface_dropped: counters.as_ref().map(|c| c.dropped),
+            capture_dropped: stats
+                .debug_capture_drops
+                .get()
+                .saturating_sub(interface.drops_baseline),
+            delivered: interface.delivered,
+        },
+    )
+}
+
+// Kernel counters for an interface, covering both directions since the
+// capture sees both.
+#[derive(Debug, Clone, Default)]
+struct InterfaceCounters {
+    packets: u64,
+    dropped: u64,
+}
+impl InterfaceCounters {
+    fn read(interface: &str) -> Option<InterfaceCounters> {
+        let read_counter = |counter: &str| -> Option<u64> {
+            let path = format!("/sys/class/net/{}/statistics/{}", interface, counter);
+            std::fs::read_to_string(path).ok()?.trim().parse().ok()
+        };
+        Some(InterfaceCounters {
+            packets: read_counter("rx_packets")? + read_counter("tx_packets")?,
+            dropped: read_counter("rx_dropped")? + read_counter("tx_dropped")?,
+        })
+    }
+
+    fn since(&self, baseline: &InterfaceCounters) -> InterfaceCounters {
+        InterfaceCounters {
+            packets: self.packets.saturating_sub(baseline.packets),
+            dropped: self.dropped.saturating_sub(baseline.dropped),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CaptureStatistics {
+    pub interface_received: Option<u64>,
+    pub interface_dropped: Option<u64>,
+    pub capture_dropped: u64,
+    pub delivered: u64,
+}
+
+// A minimal pcap-ng writer producing little endian blocks.
+pub struct PcapngWriter<W: Write> {
+    output: W,
+}
+impl<W: Write> PcapngWriter<W> {
+    pub fn new(output: W) -> PcapngWriter<W> {
+        PcapngWriter { output }
+    }
+
+    pub fn write_section_header(&mut self, application: &str) -> Result<(), std::io::Error> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // The section length is unknown while streaming.
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        push_option(&mut body, OPT_SHB_USERAPPL, application.as_bytes());
+        push_option(&mut body, OPT_END, &[]);
+        self.write_block(BLOCK_SECTION_HEADER, &body)
+    }
+
+    pub fn write_interface_description(
+        &mut self,
+        name: &str,
+        link_type: u16,
+    ) -> Result<(), std::io::Error> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&link_type.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&SNAPLEN.to_le_bytes());
+        push_option(&mut body, OPT_IF_NAME, name.as_bytes());
+        // Microsecond timestamp resolution.
+        push_option(&mut body, OPT_IF_TSRESOL, &[6]);
+        push_option(&mut body, OPT_END, &[]);
+        self.write_block(BLOCK_INTERFACE_DESCRIPTION, &body)
+    }
+
+    pub fn write_enhanced_packet(
+        &mut self,
+        interface_id: u32,
+        timestamp: std::time::SystemTime,
+        data: &[u8],
+    ) -> Result<(), std::io::Error> {
+        let captured = &data[..std::cmp::min(data.len(), SNAPLEN as usize)];
+        let mut body = Vec::with_capacity(20 + captured.len() + 3);
+        body.extend_from_slice(&interface_id.to_le_bytes());
+        push_timestamp(&mut body, timestamp);
+        body.extend_from_slice(&(captured.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        body.extend_from_slice(captured);
+        pad_to_word(&mut body);
+        self.write_block(BLOCK_ENHANCED_PACKET, &body)
+    }
+
+    pub fn write_interface_statistics(
+        &mut self,
+        interface_id: u32,
+        timestamp: std::time::SystemTime,
+        statistics: &CaptureStatistics,
+    ) -> Result<(), std::io::Error> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&interface_id.to_le_bytes());
+        push_timestamp(&mut body, timestamp);
+        if let Some(received) = statistics.interface_received {
+            push_option(&mut body, OPT_ISB_IFRECV, &received.to_le_bytes());
+        }
+        if let Some(dropped) = statistics.interface_dropped {
+            push_option(&mut body, OPT_ISB_IFDROP, &dropped.to_le_bytes());
+        }
+        push_option(
+            &mut body,
+            OPT_ISB_OSDROP,
+            &statistics.capture_dropped.to_le_bytes(),
+        );
+        push_option(
+            &mut body,
+            OPT_ISB_USRDELIV,
+            &statistics.delivered.to_le_bytes(),
+        );
+        push_option(&mut body, OPT_END, &[]);
+        self.write_block(BLOCK_INTERFACE_STATISTICS, &body)
+    }
+
+    pub fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.output.flush()
+    }
+
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> Result<(), std::io::Error> {
+        // The total length covers the type, both length fields, and the body.
+        let total_length = (body.len() + 12) as u32;
+        self.output.write_all(&block_type.to_le_bytes())?;
+        self.output.write_all(&total_length.to_le_bytes())?;
+        self.output.write_all(body)?;
+        self.output.write_all(&total_length.to_le_bytes())
+    }
+}
+
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad_to_word(body);
+}
+
+fn push_timestamp(body: &mut Vec<u8>, timestamp: std::time::SystemTime) {
+    let micros = timestamp
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(micros as u32).to_le_bytes());
+}
+
+fn pad_to_word(body: &mut Vec<u8>) {
+    while !body.len().is_multiple_of(4) {
+        body.push(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_block_framing() {
+        let mut writer = PcapngWriter::new(Vec::new());
+        writer.write_section_header("haulage").unwrap();
+        writer
+            .write_interface_description("ogstun", crate::capture::LinkType::RawIp.pcap_link_type())
+            .unwrap();
+        writer
+            .write_enhanced_packet(0, std::time::UNIX_EPOCH, &[0x45, 0, 0, 20, 1])
+            .unwrap();
+        let output = writer.output;
+
+        // Walk the blocks using their framing, checking that the leading and
+        // trailing lengths agree and that all blocks are word aligned.
+        let mut offset = 0;
+        let mut block_types = Vec::new();
+        while offset < output.len() {
+            let length = read_u32(&output, offset + 4) as usize;
+            assert_eq!(length % 4, 0);
+            assert_eq!(read_u32(&output, offset + length - 4) as usize, length);
+            block_types.push(read_u32(&output, offset));
+            offset += length;
+        }
+        assert_eq!(offset, output.len());
+        assert_eq!(
+            block_types,
+            vec![
+                BLOCK_SECTION_HEADER,
+                BLOCK_INTERFACE_DESCRIPTION,
+                BLOCK_ENHANCED_PACKET
+            ]
+        );
+        assert_eq!(read_u32(&output, 8), BYTE_ORDER_MAGIC);
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+// Answers are kept for at least this long regardless of their TTL, since
+// clients routinely keep using addresses well past the short TTLs of CDNs.
+const MIN_LIFETIME: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+// Remembers the domain each subscriber most recently resolved each remote
+// address from, until the answer's time to live expires, so that flows can be
+// attributed to the domain they were opened for. Entries are per subscriber,
+// since the same shared hosting or CDN address is commonly resolved from
+// different domains by different subscribers.
+#[derive(Debug)]
+pub struct DnsCache {
+    entries: HashMap<(std::net::IpAddr, std::net::IpAddr), Entry>,
+    last_expiry: std::time::Instant,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    domain: String,
+    expires: std::time::Instant,
+}
+
+impl DnsCache {
+    pub fn new(now: std::time::Instant) -> DnsCache {
+        DnsCache {
+            entries: HashMap::new(),
+            last_expiry: now,
+        }
+    }
+
+    // Records the answered addresses as resolved from the queried domain.
+    pub fn learn(
+        &mut self,
+        subscriber: std::net::IpAddr,
+        response: &crate::packet_parser::DnsResponse,
+        now: std::time::Instant,
+    ) {
+        let domain = crate::content_filter::normalize_domain(&response.fqdn.to_string());
+        let lifetime = std::time::Duration::from_secs(response.ttl.into()).max(MIN_LIFETIME);
+        for address in &response.addresses {
+            self.insert(subscriber, *address, domain.clone(), now, lifetime);
+        }
+    }
+
+    // Records the server name a subscriber sent to a remote address, which
+    // carries no TTL of its own.
+    pub fn learn_server_name(
+        &mut self,
+        subscriber: std::net::IpAddr,
+        remote: std::net::IpAddr,
+        server_name: &str,
+        now: std::time::Instant,
+    ) {
+        self.insert(
+            subscriber,
+            remote,
+            crate::content_filter::normalize_domain(server_name),
+            now,
+            MIN_LIFETIME,
+        );
+    }
+
+    pub fn lookup(
+        &self,
+        subscriber: std::net::IpAddr,
+        remote: std::net::IpAddr,
+        now: std::time::Instant,
+    ) -> Option<&str> {
+        self.entries
+            .get(&(subscriber, remote))
+            .filter(|entry| entry.expires > now)
+            .map(|entry| entry.domain.as_str())
+    }
+
+    fn insert(
+        &mut self,
+        subscriber: std::net::IpAddr,
+        remote: std::net::IpAddr,
+        domain: String,
+        now: std::time::Instant,
+        lifetime: std::time::Duration,
+    ) {
+        if now.duration_since(self.last_expiry) > MIN_LIFETIME {
+            self.entries.retain(|_, entry| entry.expires > now);
+            self.last_expiry = now;
+        }
+        let expires = now + lifetime;
+        self.entries
+            .insert((subscriber, remote), Entry { domain, expires });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_lookup() {
+        let start = std::time::Instant::now();
+        let mut cache = DnsCache::new(start);
+        let subscriber: std::net::IpAddr = "10.45.0.2".parse().unwrap();
+        let other: std::net::IpAddr = "10.45.0.3".parse().unwrap();
+        let address: std::net::IpAddr = "151.101.0.67".parse().unwrap();
+        let response = |name: &str, ttl: u32| crate::packet_parser::DnsResponse {
+            fqdn: domain::base::name::Dname::from_str(name).unwrap(),
+            addresses: vec![address],
+            qtype: 1,
+            rcode: 0,
+            ttl,
+        };
+
+        cache.learn(subscriber, &response("xkcd.com.", 3600), start);
+        cache.learn(other, &response("Imgs.XKCD.com.", 60), start);
+        assert_eq!(cache.lookup(subscriber, address, start), Some("xkcd.com"));
+        assert_eq!(cache.lookup(other, address, start), Some("imgs.xkcd.com"));
+
+        // Short TTLs are extended to the minimum lifetime.
+        let later = start + MIN_LIFETIME - std::time::Duration::from_secs(1);
+        assert_eq!(cache.lookup(other, address, later), Some("imgs.xkcd.com"));
+        let expired = start + MIN_LIFETIME;
+        assert_eq!(cache.lookup(other, address, expired), None);
+        assert_eq!(cache.lookup(subscriber, address, expired), Some("xkcd.com"));
+
+        // The most recent resolution wins.
+        cache.learn_server_name(subscriber, address, "what-if.xkcd.com", later);
+        assert_eq!(
+            cache.lookup(subscriber, address, expired),
+            Some("what-if.xkcd.com")
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum DropsError {
+    #[error("Database operation failed: {0}")]
+    DatabaseError(#[from] sqlx::error::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // Whether to credit subscribers for the uplink bytes dropped by shaping.
+    // Uplink traffic is captured before it reaches the upstream queues, so is
+    // charged whether or not it is dropped. Downlink traffic is captured after
+    // the subscriber queue, so dropped downlink bytes are never charged.
+    pub refund: bool,
+}
+
+// The drops of one queue within an interval.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct IntervalDrops {
+    packets: u64,
+    bytes: u64,
+}
+
+fn interval_drops(
+    current: &crate::enforcer::QueueCounters,
+    previous: Option<&crate::enforcer::QueueCounters>,
+) -> IntervalDrops {
+    // Counters restart from zero when the queues are rebuilt, e.g. when the
+    // interfaces change, in which case everything counted since is new.
+    let previous = previous
+        .filter(|previous| {
+            current.drops >= previous.drops
+                && current.packets >= previous.packets
+                && current.bytes >= previous.bytes
+        })
+        .copied()
+        .unwrap_or_default();
+    let packets = current.packets - previous.packets;
+    let bytes = current.bytes - previous.bytes;
+
+    // Estimate the size of the dropped packets from those sent in the same
+    // interval, or over the lifetime of the queue if none were.
+    let average_size = match (packets, current.packets) {
+        (0, 0) => 0,
+        (0, lifetime_packets) => current.bytes / lifetime_packets,
+        (packets, _) => bytes / packets,
+    };
+    let drops = current.drops - previous.drops;
+    IntervalDrops {
+        packets: drops,
+        bytes: drops * average_size,
+    }
+}
+
+// Records the traffic dropped by each subscriber's shaping queues on the same
+// schedule as subscriber usage, optionally refunding the dropped uplink bytes.
+pub async fn record(
+    settings: Settings,
+    schedule: crate::clock::Schedule,
+    enforcer: std::sync::Arc<crate::enforcer::Iptables>,
+    db_pool: std::sync::Arc<sqlx::PgPool>,
+    log: slog::Logger,
+) {
+    let mut start = chrono::Utc::now();
+    let mut previous = enforcer.read_queue_stats().await.unwrap_or_else(|e| {
+        slog::warn!(log, "Unable to read queue statistics"; "error" => e.to_string());
+        HashMap::new()
+    });
+
+    let mut timer = tokio::time::interval_at(
+        tokio::time::Instant::now() + schedule.first_delay(start),
+        schedule.period,
+    );
+    loop {
+        timer.tick().await;
+        let end = schedule.boundary(chrono::Utc::now());
+        let current = match enforcer.read_queue_stats().await {
+            Ok(current) => current,
+            Err(e) => {
+                slog::warn!(log, "Unable to read queue statistics"; "error" => e.to_string());
+                continue;
+            }
+        };
+
+        for (subscriber, stats) in &current {
+            let previous = previous.get(subscriber);
+            let uplink = interval_drops(&stats.uplink, previous.map(|stats| &stats.uplink));
+            let downlink = interval_drops(&stats.downlink, previous.map(|stats| &stats.downlink));
+            if uplink.packets == 0 && downlink.packets == 0 {
+                continue;
+            }
+            let refund = match settings.refund {
+                true => uplink.bytes,
+                false => 0,
+            };
+            record_drops(&db_pool, *subscriber, (start, end), uplink, downlink, refund)
+                .await
+                .unwrap_or_else(|e| slog::error!(log, "Failed to record dropped traffic"; "subscriber" => subscriber, "error" => e.to_string()));
+        }
+        previous = current;
+        start = end;
+    }
+}
+
+async fn record_drops(
+    db_pool: &sqlx::PgPool,
+    subscriber: i32,
+    (start, end): (chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>),
+    uplink: IntervalDrops,
+    downlink: IntervalDrops,
+    refund: u64,
+) -> Result<(), DropsError> {
+    let mut transaction = db_pool.begin().await?;
+
+    let insert_query = r#"
+        INSERT INTO subscriber_drops("subscriber", "start_time", "end_time", "dropped_packets_up", "dropped_bytes_up", "dropped_packets_down", "dropped_bytes_down", "refunded_bytes")
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+    "#;
+    sqlx::query(insert_query)
+        .bind(subscriber)
+        .bind(start)
+        .bind(end)
+        .bind(uplink.packets as i64)
+        .bind(uplink.bytes as i64)
+        .bind(downlink.packets as i64)
+        .bind(downlink.bytes as i64)
+        .bind(refund as i64)
+        .execute(&mut transaction)
+        .await?;
+
+    // Credit the balance the subscriber was charged from, which is their
+    // pool's if they belong to one.
+    if refund > 0 {
+        sqlx::query(
+            r#"
+            UPDATE balance_pools SET "data_balance" = balance_pools."data_balance" + $2
+            FROM subscribers
+            WHERE subscribers."internal_uid" = $1 AND balance_pools."id" = subscribers."balance_pool"
+        "#,
+        )
+        .bind(subscriber)
+        .bind(refund as i64)
+        .execute(&mut transaction)
+        .await?;
+        sqlx::query(
+            r#"
+            UPDATE subscribers SET "data_balance" = "data_balance" + $2
+            WHERE "internal_uid" = $1 AND "balance_pool" IS NULL
+        "#,
+        )
+        .bind(subscriber)
+        .bind(refund as i64)
+        .execute(&mut transaction)
+        .await?;
+    }
+
+    transaction.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enforcer::QueueCounters;
+
+    #[test]
+    fn test_interval_drops_estimate_bytes() {
+        let previous = QueueCounters {
+            bytes: 150_000,
+            packets: 100,
+            drops: 10,
+        };
+        let current = QueueCounters {
+            bytes: 250_000,
+            packets: 200,
+            drops: 30,
+        };
+        assert_eq!(
+            interval_drops(&current, Some(&previous)),
+            IntervalDrops {
+                packets: 20,
+                bytes: 20_000,
+            }
+        );
+
+        // Nothing sent in the interval falls back to the lifetime average.
+        let stalled = QueueCounters {
+            drops: 40,
+            ..current
+        };
+        assert_eq!(
+            interval_drops(&stalled, Some(&current)),
+            IntervalDrops {
+                packets: 10,
+                bytes: 12_500,
+            }
+        );
+
+        // Rebuilt queues count from zero.
+        assert_eq!(interval_drops(&previous, Some(&current)).packets, 10);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::storage::{AccessPolicyRow, LimitPolicyParameters};
 use serde::Deserialize;
 use std::collections::HashMap;
 use thiserror::Error;
@@ -9,6 +10,8 @@ use i32 as PolicyId;
 pub enum EnforcementError {
     #[error("Database operation failed: {0}")]
     DatabaseError(#[from] sqlx::error::Error),
+    #[error("Storage operation failed: {0}")]
+    StorageError(#[from] crate::storage::StorageError),
     #[error("User ID is not uniquely present")]
     UserIdError,
     #[error("Failed to update iptables: {0}")]
@@ -21,51 +24,247 @@ pub enum EnforcementError {
     RateLimitPolicyError(i32),
     #[error("Rate limit policy parameter error {0}")]
     RateLimitParameterError(String),
-    #[error("The tc queuing discipline management function returned an error")]
-    TcCommandError,
+    #[error("Traffic control update failed: {0}")]
+    TcError(#[from] crate::tc::TcError),
+    #[error("Subscriber handle {0} does not fit a traffic control handle")]
+    TcHandleError(String),
     #[error("Failed to parse json: {0}")]
     SerdeJsonError(#[from] serde_json::Error),
+    #[error("Invalid DSCP value {0}")]
+    DscpValueError(i16),
 }
 
+// External tools can request immediate application of a subscriber's policy
+// after changing their row, rather than waiting for the next poll, with e.g.
+// `SELECT pg_notify('haulage_apply_policy', '001010000000001')`. The payload
+// is the subscriber's IMSI, or empty to check all subscribers.
+pub const APPLY_POLICY_CHANNEL: &str = "haulage_apply_policy";
+
 const BASE_HTB_RATE_KIBITPS: u32 = 100;
-const BASE_HTB_RATE_STR: &str = "100kbit";
-const FULL_INTERFACE_HTB_RATE_STR: &str = "1gbps";
-const HTB_CBURST_AMOUNT_STR: &str = "1mbit";
+const BASE_HTB_RATE_BYTES: u64 = 12_500;
+const FULL_INTERFACE_HTB_RATE_BYTES: u64 = 1_000_000_000;
+const HTB_CBURST_AMOUNT_BYTES: u64 = 131_072;
+const ROOT_CLASS_MINOR: u16 = 0x1000;
+// Filters of different protocols cannot share a priority. The subscriber
+// interface has no catchall filter, so the IPv6 filters can follow directly.
+const IPV4_DST_FILTER_PRIORITY: u16 = 1;
+const IPV6_DST_FILTER_PRIORITY: u16 = 2;
+const FALLBACK_CLASS_MINOR: u16 = 0xFFFF;
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub poll_period: std::time::Duration,
+    pub verification: Option<VerificationSettings>,
+    // How long subscribers whose balance ran out while haulage was down keep
+    // their previous policy after startup, released gradually over the window.
+    pub startup_grace: Option<std::time::Duration>,
+    // The site's time zone scheduled policy rates follow, or the system's
+    // local time if not configured.
+    pub timezone: Option<crate::clock::SiteTimezone>,
+}
+
+// Where and when policies are installed: the interfaces subscriber traffic is
+// shaped on, and the local time scheduled policy rates follow.
+#[derive(Debug, Clone, Copy)]
+struct Site<'a> {
+    subscriber_interface: &'a crate::netns::Interface,
+    upstream_interfaces: &'a [crate::netns::Interface],
+    timezone: Option<crate::clock::SiteTimezone>,
+}
+impl<'a> Site<'a> {
+    fn new(
+        subscriber_interface: &'a crate::netns::Interface,
+        upstream_interfaces: &'a [crate::netns::Interface],
+        timezone: Option<crate::clock::SiteTimezone>,
+    ) -> Site<'a> {
+        Site {
+            subscriber_interface,
+            upstream_interfaces,
+            timezone,
+        }
+    }
+
+    fn local_time(&self) -> chrono::NaiveTime {
+        crate::clock::local_now(self.timezone).time()
+    }
+}
+
+// The previous policies of subscribers within the startup grace window, and
+// when each is released to the policy of their current condition.
+type HeldPolicies = HashMap<i32, (SubscriberAccessInfo, tokio::time::Instant)>;
+
+// Checks that newly applied token bucket policies hold subscribers to their
+// rate, by watching the subscriber's queues for a short window after the
+// policy is applied.
+#[derive(Debug, Clone)]
+pub struct VerificationSettings {
+    pub window: std::time::Duration,
+    // The fraction the observed rate may stray from the policy's rate.
+    pub tolerance: f64,
+}
+
+// Applies the policy for a subscriber's balance condition. Subsystems hold an
+// enforcer as a trait object, so that backends other than iptables and tc can
+// be substituted.
+#[async_trait::async_trait]
+pub trait PolicyEnforcer: std::fmt::Debug + Send + Sync {
+    async fn update_policy(
+        &self,
+        target: UserId,
+        new_policy: SubscriberCondition,
+    ) -> Result<(), EnforcementError>;
+}
 
 #[derive(Debug)]
 pub struct Iptables {
-    dispatch_channel: tokio::sync::mpsc::Sender<PolicyUpdateMessage>,
+    dispatch_channel: tokio::sync::mpsc::Sender<EnforcerMessage>,
+    stats: std::sync::Arc<crate::stats::Stats>,
     log: slog::Logger,
 }
 impl Iptables {
     pub fn new(
-        poll_period: std::time::Duration,
-        subscriber_interface: &str,
-        upstream_interface: &Option<String>,
-        db_pool: std::sync::Arc<sqlx::PgPool>,
+        settings: Settings,
+        subscriber_interface: &crate::netns::Interface,
+        upstream_interfaces: &[crate::netns::Interface],
+        storage: std::sync::Arc<dyn crate::storage::Storage>,
+        resumed: Option<Handoff>,
+        stats: std::sync::Arc<crate::stats::Stats>,
         log: slog::Logger,
     ) -> Iptables {
         let (sender, receiver) = tokio::sync::mpsc::channel(64);
         let local_logger = log.clone();
-        let subscriber_interface = subscriber_interface.to_owned();
-        let upstream_interface = upstream_interface.to_owned();
+        let subscriber_interface = subscriber_interface.clone();
+        let upstream_interfaces = upstream_interfaces.to_vec();
         tokio::task::spawn(async move {
             enforce_via_iptables(
                 receiver,
-                poll_period,
+                settings,
                 subscriber_interface,
-                upstream_interface,
-                db_pool,
+                upstream_interfaces,
+                storage,
+                resumed,
                 log,
             )
             .await;
         });
         Iptables {
             dispatch_channel: sender,
+            stats,
             log: local_logger,
         }
     }
-    pub async fn update_policy(
+    // Exports the depth of the queue of enforcement requests, which grows when
+    // rule changes fall behind the policy changes requested.
+    pub fn watch_queue(&self, metrics: &crate::metrics::Metrics) {
+        metrics.watch_queue("enforcer", &self.dispatch_channel);
+    }
+    pub async fn change_interfaces(
+        &self,
+        subscriber_interface: &crate::netns::Interface,
+        upstream_interfaces: &[crate::netns::Interface],
+    ) -> Result<(), EnforcementError> {
+        let (result_channel_tx, result_channel_rx) =
+            tokio::sync::oneshot::channel::<Result<(), EnforcementError>>();
+        self.dispatch_channel
+            .send(EnforcerMessage::ChangeInterfaces {
+                subscriber_interface: subscriber_interface.clone(),
+                upstream_interfaces: upstream_interfaces.to_vec(),
+                out_channel: result_channel_tx,
+            })
+            .await
+            .or(Err(EnforcementError::CommunicationError))?;
+        result_channel_rx
+            .await
+            .or(Err(EnforcementError::CommunicationError))?
+    }
+    pub async fn change_addresses(
+        &self,
+        changes: Vec<(UserId, ipnetwork::IpNetwork)>,
+    ) -> Result<(), EnforcementError> {
+        let (result_channel_tx, result_channel_rx) =
+            tokio::sync::oneshot::channel::<Result<(), EnforcementError>>();
+        self.dispatch_channel
+            .send(EnforcerMessage::ChangeAddresses {
+                changes,
+                out_channel: result_channel_tx,
+            })
+            .await
+            .or(Err(EnforcementError::CommunicationError))?;
+        result_channel_rx
+            .await
+            .or(Err(EnforcementError::CommunicationError))?
+    }
+    // Layers temporary parameters over a subscriber's policy until they
+    // expire, or removes the current override if none are given. Returns the
+    // override now in place.
+    pub async fn override_policy(
+        &self,
+        imsi: &str,
+        parameters: Option<OverrideParameters>,
+    ) -> Result<Option<PolicyOverride>, EnforcementError> {
+        let (result_channel_tx, result_channel_rx) = tokio::sync::oneshot::channel();
+        self.dispatch_channel
+            .send(EnforcerMessage::OverridePolicy {
+                imsi: imsi.to_owned(),
+                parameters,
+                out_channel: result_channel_tx,
+            })
+            .await
+            .or(Err(EnforcementError::CommunicationError))?;
+        result_channel_rx
+            .await
+            .or(Err(EnforcementError::CommunicationError))?
+    }
+    // Reads the cumulative counters of each enforced subscriber's queues.
+    pub async fn read_queue_stats(
+        &self,
+    ) -> Result<HashMap<UserId, SubscriberQueueStats>, EnforcementError> {
+        let (result_channel_tx, result_channel_rx) = tokio::sync::oneshot::channel();
+        self.dispatch_channel
+            .send(EnforcerMessage::ReadQueueStats {
+                out_channel: result_channel_tx,
+            })
+            .await
+            .or(Err(EnforcementError::CommunicationError))?;
+        result_channel_rx
+            .await
+            .or(Err(EnforcementError::CommunicationError))?
+    }
+    // Stops enforcing, leaving the kernel state in place, and returns the
+    // state needed by a new process to take it over.
+    pub async fn hand_off(&self) -> Result<Handoff, EnforcementError> {
+        let (result_channel_tx, result_channel_rx) = tokio::sync::oneshot::channel::<Handoff>();
+        self.dispatch_channel
+            .send(EnforcerMessage::HandOff {
+                out_channel: result_channel_tx,
+            })
+            .await
+            .or(Err(EnforcementError::CommunicationError))?;
+        result_channel_rx
+            .await
+            .or(Err(EnforcementError::CommunicationError))
+    }
+    // Stops enforcing and removes the rules and queuing disciplines of all
+    // subscribers from the kernel, e.g. when haulage exits without handing
+    // off. Traffic is unrestricted until enforcement starts again.
+    pub async fn shut_down(&self) -> Result<(), EnforcementError> {
+        let (result_channel_tx, result_channel_rx) =
+            tokio::sync::oneshot::channel::<Result<(), EnforcementError>>();
+        self.dispatch_channel
+            .send(EnforcerMessage::ShutDown {
+                out_channel: result_channel_tx,
+            })
+            .await
+            .or(Err(EnforcementError::CommunicationError))?;
+        result_channel_rx
+            .await
+            .or(Err(EnforcementError::CommunicationError))?
+    }
+}
+
+#[async_trait::async_trait]
+impl PolicyEnforcer for Iptables {
+    async fn update_policy(
         &self,
         target: UserId,
         new_policy: SubscriberCondition,
@@ -73,39 +272,186 @@ impl Iptables {
         let (result_channel_tx, result_channel_rx) =
             tokio::sync::oneshot::channel::<Result<(), EnforcementError>>();
         self.dispatch_channel
-            .send(PolicyUpdateMessage {
+            .send(EnforcerMessage::PolicyUpdate {
                 new_state: new_policy,
                 target: target,
                 out_channel: result_channel_tx,
             })
             .await
             .or(Err(EnforcementError::CommunicationError))?;
-        return result_channel_rx.await.unwrap_or_else(|e| {
+        let result = result_channel_rx.await.unwrap_or_else(|e| {
             slog::error!(self.log, "Failed to receive enforcement worker result"; "error" => e.to_string());
             Err(EnforcementError::CommunicationError)
         });
+
+        match &result {
+            Ok(_) => self.stats.policy_updates.increment(),
+            Err(_) => self.stats.policy_update_errors.increment(),
+        }
+        return result;
+    }
+}
+
+// Stands in for the enforcer in monitoring-only deployments, recording the
+// policy changes which would have been applied without touching the data
+// plane.
+#[derive(Debug)]
+pub struct LogOnly {
+    log: slog::Logger,
+}
+impl LogOnly {
+    pub fn new(log: slog::Logger) -> LogOnly {
+        LogOnly { log }
+    }
+}
+
+#[async_trait::async_trait]
+impl PolicyEnforcer for LogOnly {
+    async fn update_policy(
+        &self,
+        target: UserId,
+        new_policy: SubscriberCondition,
+    ) -> Result<(), EnforcementError> {
+        let condition = match new_policy {
+            SubscriberCondition::_PositiveBalance => "positive balance",
+            SubscriberCondition::NoBalance => "no balance",
+        };
+        slog::info!(self.log, "Enforcement disabled, not applying policy"; "subscriber" => target, "condition" => condition);
+        Ok(())
     }
 }
 
+// The enforcement state installed in the kernel by one process, from which
+// the next process can continue without clearing and rebuilding the qdiscs
+// and rules of every subscriber.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Handoff {
+    subscriber_interface: crate::netns::Interface,
+    upstream_interfaces: Vec<crate::netns::Interface>,
+    next_handle_id: i32,
+    subscribers: HashMap<i32, SubscriberControlState>,
+}
+
+// Parameters layered over a subscriber's policy, e.g. a temporary rate boost
+// for a promotion, without defining a new policy for it. Rates only replace
+// those of token bucket links, so unlimited and blocked links are unaffected.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, Deserialize)]
+pub struct OverrideParameters {
+    pub backhaul_ul_rate_kibps: Option<u32>,
+    pub backhaul_dl_rate_kibps: Option<u32>,
+    pub dscp: Option<u8>,
+    pub expires: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, Deserialize)]
+pub struct PolicyOverride {
+    imsi: String,
+    // The policy the override was made against. The override has no effect
+    // while any other policy applies, e.g. once the subscriber's balance runs
+    // out, but still lasts until it expires.
+    policy_id: PolicyId,
+    #[serde(flatten)]
+    parameters: OverrideParameters,
+}
+impl PolicyOverride {
+    fn layer(&self, policy: &SubscriberAccessInfo) -> SubscriberAccessInfo {
+        let mut layered = policy.clone();
+        if policy.policy_id != self.policy_id {
+            return layered;
+        }
+        let override_rate = |link: &mut AccessPolicy, rate: Option<u32>| {
+            if let (AccessPolicy::TokenBucket(params), Some(rate)) = (link, rate) {
+                params.rate_kibps = rate;
+            }
+        };
+        override_rate(
+            &mut layered.backhaul_ul_policy,
+            self.parameters.backhaul_ul_rate_kibps,
+        );
+        override_rate(
+            &mut layered.backhaul_dl_policy,
+            self.parameters.backhaul_dl_rate_kibps,
+        );
+        if self.parameters.dscp.is_some() {
+            layered.dscp = self.parameters.dscp;
+        }
+        layered
+    }
+}
+
+// The counters of a queue, which restart from zero whenever the queue is
+// rebuilt. The kernel counts drops in packets rather than bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QueueCounters {
+    pub bytes: u64,
+    pub packets: u64,
+    pub drops: u64,
+}
+
+// The queues of one subscriber, with the uplink summed over every upstream
+// interface.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SubscriberQueueStats {
+    pub uplink: QueueCounters,
+    pub downlink: QueueCounters,
+}
+
 pub enum SubscriberCondition {
     _PositiveBalance,
     NoBalance,
 }
 
-struct PolicyUpdateMessage {
-    new_state: SubscriberCondition,
-    target: UserId,
-    out_channel: tokio::sync::oneshot::Sender<Result<(), EnforcementError>>,
+enum EnforcerMessage {
+    PolicyUpdate {
+        new_state: SubscriberCondition,
+        target: UserId,
+        out_channel: tokio::sync::oneshot::Sender<Result<(), EnforcementError>>,
+    },
+    // Moves all enforcement state to a new set of interfaces, e.g. when the
+    // backhaul fails over to a different NIC.
+    ChangeInterfaces {
+        subscriber_interface: crate::netns::Interface,
+        upstream_interfaces: Vec<crate::netns::Interface>,
+        out_channel: tokio::sync::oneshot::Sender<Result<(), EnforcementError>>,
+    },
+    // Moves the enforcement state of subscribers to newly assigned addresses.
+    ChangeAddresses {
+        changes: Vec<(UserId, ipnetwork::IpNetwork)>,
+        out_channel: tokio::sync::oneshot::Sender<Result<(), EnforcementError>>,
+    },
+    OverridePolicy {
+        imsi: String,
+        parameters: Option<OverrideParameters>,
+        out_channel: tokio::sync::oneshot::Sender<Result<Option<PolicyOverride>, EnforcementError>>,
+    },
+    ReadQueueStats {
+        out_channel: tokio::sync::oneshot::Sender<
+            Result<HashMap<UserId, SubscriberQueueStats>, EnforcementError>,
+        >,
+    },
+    HandOff {
+        out_channel: tokio::sync::oneshot::Sender<Handoff>,
+    },
+    ShutDown {
+        out_channel: tokio::sync::oneshot::Sender<Result<(), EnforcementError>>,
+    },
 }
 
 async fn enforce_via_iptables(
-    mut chan: tokio::sync::mpsc::Receiver<PolicyUpdateMessage>,
-    period: std::time::Duration,
-    subscriber_interface: String,
-    upstream_interface: Option<String>,
-    db_pool: std::sync::Arc<sqlx::PgPool>,
+    mut chan: tokio::sync::mpsc::Receiver<EnforcerMessage>,
+    settings: Settings,
+    mut subscriber_interface: crate::netns::Interface,
+    mut upstream_interfaces: Vec<crate::netns::Interface>,
+    storage: std::sync::Arc<dyn crate::storage::Storage>,
+    resumed: Option<Handoff>,
     log: slog::Logger,
 ) -> () {
+    let Settings {
+        poll_period: period,
+        verification,
+        startup_grace,
+        timezone,
+    } = settings;
     // Track local ephemeral state per subscriber in an in-memory table
     //
     // Issue handle ids to subscribers on a first-come first-serve basis. In
@@ -113,210 +459,682 @@ async fn enforce_via_iptables(
     // operating.
     let mut next_handle_id = 1;
     let mut subscriber_limit_control_state = HashMap::<i32, SubscriberControlState>::new();
+    let mut held = HeldPolicies::new();
+
+    // State handed off for different interfaces does not describe the
+    // kernel state of the configured ones.
+    let resumed = resumed.filter(|handoff| {
+        let matches = handoff.subscriber_interface == subscriber_interface
+            && handoff.upstream_interfaces == upstream_interfaces;
+        if !matches {
+            slog::warn!(
+                log,
+                "Ignoring enforcement state handed off for other interfaces"
+            );
+        }
+        matches
+    });
+    match resumed {
+        Some(handoff) => {
+            slog::info!(log, "Resuming handed off enforcement state"; "subscribers" => handoff.subscribers.len());
+            next_handle_id = handoff.next_handle_id;
+            subscriber_limit_control_state = handoff.subscribers;
+            resume_interfaces(
+                Site::new(&subscriber_interface, &upstream_interfaces, timezone),
+                &mut subscriber_limit_control_state,
+                &mut next_handle_id,
+                storage.as_ref(),
+                &log,
+            )
+            .await
+        }
+        None => {
+            if let Some(grace) = startup_grace {
+                held = hold_exhausted_policies(grace, storage.as_ref(), &log).await;
+            }
+            setup_interfaces(
+                Site::new(&subscriber_interface, &upstream_interfaces, timezone),
+                &mut subscriber_limit_control_state,
+                &mut next_handle_id,
+                &held,
+                storage.as_ref(),
+                &log,
+            )
+            .await
+        }
+    }
+    .expect("Unable to set up initial enforcement state");
+
+    // Polling continues regardless, so requests are only delayed rather than
+    // lost if the listener is unavailable.
+    let mut listener = match storage.listen(APPLY_POLICY_CHANNEL).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            slog::warn!(log, "Unable to listen for policy requests, relying on polling"; "error" => e.to_string());
+            None
+        }
+    };
 
-    // Clear any existing queuing disciplines on startup.
-    clear_interface_limit(&subscriber_interface, &log)
-        .await
-        .unwrap();
+    let mut timer = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        tokio::select! {
+            _ = timer.tick() => {
+                let reenabled_subs = query_modified_subscriber_access_state(storage.as_ref(), &log)
+                    .await
+                    .unwrap_or_else(|e| {
+                        slog::error!(log, "Unable to query for reenabled subscribers"; "error" => e.to_string());
+                        Vec::<SubscriberAccessInfo>::new()
+                    });
+                let now = tokio::time::Instant::now();
+                held.retain(|_, (_, release)| *release > now);
+                for sub in reenabled_subs.into_iter().filter(|sub| !held.contains_key(&sub.subscriber_id)) {
+                    if apply_access_info(&sub, &mut subscriber_limit_control_state, &mut next_handle_id, Site::new(&subscriber_interface, &upstream_interfaces, timezone), storage.as_ref(), &log).await {
+                        verify_new_policy(verification.as_ref(), &subscriber_limit_control_state[&sub.subscriber_id], &upstream_interfaces, &subscriber_interface, &log);
+                    }
+                }
+
+                let now = chrono::Utc::now();
+                let expired: Vec<String> = subscriber_limit_control_state
+                    .values()
+                    .filter_map(|state| state.policy_override.as_ref())
+                    .filter(|policy_override| policy_override.parameters.expires <= now)
+                    .map(|policy_override| policy_override.imsi.clone())
+                    .collect();
+                for imsi in expired {
+                    slog::info!(log, "Policy override expired"; "imsi" => &imsi);
+                    if let Err(e) = set_policy_override(&imsi, None, &mut subscriber_limit_control_state, Site::new(&subscriber_interface, &upstream_interfaces, timezone), storage.as_ref(), &log).await {
+                        slog::error!(log, "Unable to remove expired policy override"; "imsi" => &imsi, "error" => e.to_string());
+                    }
+                }
+
+                // Switch subscribers on scheduled policies between their
+                // rates as the scheduled hours begin and end, independent of
+                // any balance change.
+                let time = crate::clock::local_now(timezone).time();
+                let rescheduled: Vec<(i32, SubscriberAccessInfo)> = subscriber_limit_control_state
+                    .iter()
+                    .filter_map(|(id, state)| {
+                        let policy = state.requested_policy.as_ref()?;
+                        let switched = policy.is_scheduled()
+                            && state.applied_policy.as_ref() != Some(&effective_policy(state, policy, time));
+                        switched.then(|| (*id, policy.clone()))
+                    })
+                    .collect();
+                for (id, policy) in rescheduled {
+                    slog::info!(log, "Switching scheduled policy rates"; "id" => id, "policy" => policy.policy_id);
+                    if let Some(state) = subscriber_limit_control_state.get_mut(&id) {
+                        set_policy(id, state, &policy, Site::new(&subscriber_interface, &upstream_interfaces, timezone), storage.as_ref(), &log)
+                            .await
+                            .unwrap_or_else(|e| slog::error!(log, "Unable to switch scheduled policy rates"; "id" => id, "error" => e.to_string()));
+                    }
+                }
+            }
+            notification = recv_policy_request(&mut listener) => {
+                // Subscribers requested by name are released from the grace
+                // window, while checks of all subscribers leave it in place.
+                let subs = match notification {
+                    Ok(notification) if notification.payload().is_empty() => {
+                        slog::info!(log, "Checking all subscribers on request");
+                        query_modified_subscriber_access_state(storage.as_ref(), &log).await.map(|subs| {
+                            subs.into_iter().filter(|sub| !held.contains_key(&sub.subscriber_id)).collect()
+                        })
+                    }
+                    Ok(notification) => {
+                        slog::info!(log, "Applying subscriber policy on request"; "imsi" => notification.payload());
+                        query_subscriber_access_state(notification.payload(), storage.as_ref()).await.inspect(|subs| {
+                            for sub in subs {
+                                held.remove(&sub.subscriber_id);
+                            }
+                        })
+                    }
+                    Err(e) => {
+                        // The listener reconnects on the next receive, but
+                        // requests sent in the meantime are lost.
+                        slog::warn!(log, "Policy request listener failed"; "error" => e.to_string());
+                        tokio::time::sleep(period).await;
+                        Ok(Vec::new())
+                    }
+                };
+                for sub in subs.unwrap_or_else(|e| {
+                    slog::error!(log, "Unable to query requested subscriber policy"; "error" => e.to_string());
+                    Vec::new()
+                }) {
+                    if apply_access_info(&sub, &mut subscriber_limit_control_state, &mut next_handle_id, Site::new(&subscriber_interface, &upstream_interfaces, timezone), storage.as_ref(), &log).await {
+                        verify_new_policy(verification.as_ref(), &subscriber_limit_control_state[&sub.subscriber_id], &upstream_interfaces, &subscriber_interface, &log);
+                    }
+                }
+            }
+            message = chan.recv() => {
+                if message.is_none() {
+                    break;
+                }
+                match message.unwrap() {
+                    EnforcerMessage::PolicyUpdate { new_state, target, out_channel } => {
+                        held.remove(&target);
+                        let sub_limit_state = match subscriber_limit_control_state.entry(target) {
+                            std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                            std::collections::hash_map::Entry::Vacant(entry) => {
+                                let sub_handle = format!("{:03X}", next_handle_id);
+                                next_handle_id += 1;
+                                entry.insert(SubscriberControlState {
+                                    qdisc_handle: sub_handle,
+                                    addresses: query_subscriber_addresses(target, storage.as_ref(), &log).await.unwrap(),
+                                    applied_policy: None,
+                                    requested_policy: None,
+                                    policy_override: None,
+                                })
+                            }
+                        };
+
+                        let previous = sub_limit_state.applied_policy.clone();
+                        let result = set_policy_for_condition(target, sub_limit_state, new_state, Site::new(&subscriber_interface, &upstream_interfaces, timezone), storage.as_ref(), &log).await;
+                        if sub_limit_state.applied_policy != previous {
+                            verify_new_policy(verification.as_ref(), sub_limit_state, &upstream_interfaces, &subscriber_interface, &log);
+                        }
+                        out_channel.send(result).unwrap();
+                    }
+                    EnforcerMessage::ChangeInterfaces { subscriber_interface: new_subscriber_interface, upstream_interfaces: new_upstream_interfaces, out_channel } => {
+                        slog::info!(log, "Changing enforcement interfaces"; "subscriber_interface" => new_subscriber_interface.to_string(), "upstream_interfaces" => new_upstream_interfaces.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(","));
+
+                        // Remove state from interfaces no longer in use. The
+                        // old interface may have already gone away entirely.
+                        let new_interfaces: Vec<_> = std::iter::once(&new_subscriber_interface).chain(&new_upstream_interfaces).collect();
+                        for old_interface in std::iter::once(&subscriber_interface).chain(&upstream_interfaces) {
+                            if !new_interfaces.contains(&old_interface) {
+                                clear_interface_limit(old_interface, &log)
+                                    .await
+                                    .unwrap_or_else(|e| slog::warn!(log, "Unable to clear old interface"; "interface" => old_interface.to_string(), "error" => e.to_string()));
+                            }
+                        }
+
+                        subscriber_interface = new_subscriber_interface;
+                        upstream_interfaces = new_upstream_interfaces;
+                        let result = setup_interfaces(Site::new(&subscriber_interface, &upstream_interfaces, timezone), &mut subscriber_limit_control_state, &mut next_handle_id, &held, storage.as_ref(), &log).await;
+                        // The requester may have given up waiting.
+                        let _ = out_channel.send(result);
+                    }
+                    EnforcerMessage::ChangeAddresses { changes, out_channel } => {
+                        let mut changed = false;
+                        for (subscriber, new_ip) in changes {
+                            if let Some(state) = subscriber_limit_control_state.get_mut(&subscriber) {
+                                // Only the address of the changed family moves.
+                                let mut addresses = state.addresses;
+                                addresses.set(new_ip);
+                                if state.addresses == addresses {
+                                    continue;
+                                }
+                                slog::info!(log, "Moving subscriber enforcement"; "id" => subscriber, "old" => state.addresses.to_string(), "new" => addresses.to_string());
+                                clear_address_rules(subscriber_interface.namespace(), state, &log)
+                                    .await
+                                    .unwrap_or_else(|e| slog::warn!(log, "Unable to clear rules for old address"; "ip" => state.addresses.to_string(), "error" => e.to_string()));
+                                state.addresses = addresses;
+                                changed = true;
+                            }
+                        }
+
+                        // The tc filters are not individually addressable, so
+                        // rebuild them for all subscribers. Address changes are
+                        // rare enough that the brief interruption is acceptable.
+                        let result = match changed {
+                            true => setup_interfaces(Site::new(&subscriber_interface, &upstream_interfaces, timezone), &mut subscriber_limit_control_state, &mut next_handle_id, &held, storage.as_ref(), &log).await,
+                            false => Ok(()),
+                        };
+                        let _ = out_channel.send(result);
+                    }
+                    EnforcerMessage::OverridePolicy { imsi, parameters, out_channel } => {
+                        let result = set_policy_override(&imsi, parameters, &mut subscriber_limit_control_state, Site::new(&subscriber_interface, &upstream_interfaces, timezone), storage.as_ref(), &log).await;
+                        let _ = out_channel.send(result);
+                    }
+                    EnforcerMessage::ReadQueueStats { out_channel } => {
+                        let result = read_queue_stats(&subscriber_interface, &upstream_interfaces, &subscriber_limit_control_state).await;
+                        let _ = out_channel.send(result);
+                    }
+                    EnforcerMessage::HandOff { out_channel } => {
+                        slog::info!(log, "Handing off enforcement state"; "subscribers" => subscriber_limit_control_state.len());
+                        let _ = out_channel.send(Handoff {
+                            subscriber_interface,
+                            upstream_interfaces,
+                            next_handle_id,
+                            subscribers: subscriber_limit_control_state,
+                        });
+                        // The new process owns the kernel state from here.
+                        break;
+                    }
+                    EnforcerMessage::ShutDown { out_channel } => {
+                        slog::info!(log, "Clearing enforcement state"; "subscribers" => subscriber_limit_control_state.len());
+                        // Clear as much as possible, reporting the first failure.
+                        let mut result = Ok(());
+                        for state in subscriber_limit_control_state.values() {
+                            let cleared = clear_address_rules(subscriber_interface.namespace(), state, &log).await;
+                            if let Err(e) = &cleared {
+                                slog::warn!(log, "Unable to clear subscriber rules"; "ip" => state.addresses.to_string(), "error" => e.to_string());
+                            }
+                            result = result.and(cleared);
+                        }
+                        for interface in std::iter::once(&subscriber_interface).chain(&upstream_interfaces) {
+                            let cleared = clear_interface_limit(interface, &log).await;
+                            if let Err(e) = &cleared {
+                                slog::warn!(log, "Unable to clear interface"; "interface" => interface.to_string(), "error" => e.to_string());
+                            }
+                            result = result.and(cleared);
+                        }
+                        let _ = out_channel.send(result);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn recv_policy_request(
+    listener: &mut Option<sqlx::postgres::PgListener>,
+) -> Result<sqlx::postgres::PgNotification, sqlx::Error> {
+    match listener {
+        Some(listener) => listener.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Installs the given policy for a subscriber, allocating their control state
+// if they have not been enforced yet. Returns whether a different policy was
+// put in place.
+async fn apply_access_info(
+    sub: &SubscriberAccessInfo,
+    subscriber_limit_control_state: &mut HashMap<i32, SubscriberControlState>,
+    next_handle_id: &mut i32,
+    site: Site<'_>,
+    storage: &dyn crate::storage::Storage,
+    log: &slog::Logger,
+) -> bool {
+    let sub_limit_state = subscriber_limit_control_state
+        .entry(sub.subscriber_id)
+        .or_insert_with(|| {
+            let sub_handle = format!("{:03X}", next_handle_id);
+            *next_handle_id += 1;
+            SubscriberControlState {
+                qdisc_handle: sub_handle,
+                addresses: sub.addresses,
+                applied_policy: None,
+                requested_policy: None,
+                policy_override: None,
+            }
+        });
+
+    let previous = sub_limit_state.applied_policy.clone();
+    set_policy(
+        sub.subscriber_id,
+        sub_limit_state,
+        sub,
+        site,
+        storage,
+        log,
+    )
+    .await
+    .unwrap_or_else(|e| {
+        slog::error!(log, "Unable to reenable subscriber"; "id" => sub.subscriber_id, "error" => e.to_string())
+    });
+    sub_limit_state.applied_policy != previous
+}
+
+// Starts verifying the token bucket rates of a subscriber's newly applied
+// policy, if verification is configured. The verification runs in the
+// background, so it neither delays enforcement nor notices policies replaced
+// during its window, which are reported against the policy they started with.
+fn verify_new_policy(
+    settings: Option<&VerificationSettings>,
+    state: &SubscriberControlState,
+    upstream_interfaces: &[crate::netns::Interface],
+    subscriber_interface: &crate::netns::Interface,
+    log: &slog::Logger,
+) {
+    let (settings, policy) = match (settings, &state.applied_policy) {
+        (Some(settings), Some(policy)) => (settings, policy),
+        _ => return,
+    };
+    // Uplink traffic is shaped on the upstream interfaces, and downlink on the
+    // subscriber interface, as in set_policy.
+    let links = [
+        (
+            "uplink",
+            &policy.backhaul_ul_policy,
+            upstream_interfaces.to_vec(),
+            8,
+        ),
+        (
+            "downlink",
+            &policy.backhaul_dl_policy,
+            vec![subscriber_interface.clone()],
+            0,
+        ),
+    ];
+    for (direction, link, interfaces, id_offset) in links {
+        if let AccessPolicy::TokenBucket(params) = link {
+            let settings = settings.clone();
+            let handle = match sfq_handle(id_offset, &state.qdisc_handle) {
+                Ok(handle) => handle,
+                Err(e) => {
+                    slog::warn!(log, "Unable to verify applied rate limit"; "error" => e.to_string());
+                    continue;
+                }
+            };
+            let rate_kibps = params.rate_kibps;
+            let log = log.new(slog::o!(
+                "id" => policy.subscriber_id,
+                "policy" => policy.policy_id,
+                "direction" => direction,
+            ));
+            tokio::task::spawn(async move {
+                if let Err(e) =
+                    verify_token_bucket(&settings, &interfaces, handle, rate_kibps, &log).await
+                {
+                    slog::warn!(log, "Unable to verify applied rate limit"; "error" => e.to_string());
+                }
+            });
+        }
+    }
+}
+
+// Compares the rate a subscriber's queue is served at over the verification
+// window with the rate of its token bucket. Only a saturated queue, which
+// drops packets, shows whether the rate is reached, so an idle subscriber
+// can only be checked for exceeding it.
+async fn verify_token_bucket(
+    settings: &VerificationSettings,
+    interfaces: &[crate::netns::Interface],
+    handle: u32,
+    rate_kibps: u32,
+    log: &slog::Logger,
+) -> Result<(), EnforcementError> {
+    let read_counters = || async {
+        let mut total = QueueCounters::default();
+        for interface in interfaces {
+            if let Some(counters) = read_qdisc_counters(interface).await?.get(&handle) {
+                total.bytes += counters.bytes;
+                total.drops += counters.drops;
+            }
+        }
+        Ok::<_, EnforcementError>(total)
+    };
+    let start = read_counters().await?;
+    tokio::time::sleep(settings.window).await;
+    let end = read_counters().await?;
+
+    let bytes = end.bytes.saturating_sub(start.bytes);
+    let saturated = end.drops > start.drops;
+    let observed_kibps = bytes * 8 / 1000 / std::cmp::max(settings.window.as_secs(), 1);
+    match check_rate(rate_kibps, bytes, saturated, settings) {
+        RateCheck::Exceeded => {
+            slog::warn!(log, "Subscriber traffic exceeded the applied rate limit"; "rate_kibps" => rate_kibps, "observed_kibps" => observed_kibps);
+        }
+        RateCheck::Short => {
+            slog::warn!(log, "Saturated subscriber queue fell short of the applied rate limit"; "rate_kibps" => rate_kibps, "observed_kibps" => observed_kibps);
+        }
+        RateCheck::Consistent => {
+            slog::debug!(log, "Verified applied rate limit"; "rate_kibps" => rate_kibps, "observed_kibps" => observed_kibps, "saturated" => saturated);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum RateCheck {
+    Exceeded,
+    Short,
+    Consistent,
+}
+
+// The queue may briefly exceed its rate by the ceiling burst.
+fn check_rate(
+    rate_kibps: u32,
+    bytes: u64,
+    saturated: bool,
+    settings: &VerificationSettings,
+) -> RateCheck {
+    let expected = rate_kibps as f64 * 1000.0 / 8.0 * settings.window.as_secs_f64();
+    if bytes as f64 > expected * (1.0 + settings.tolerance) + HTB_CBURST_AMOUNT_BYTES as f64 {
+        RateCheck::Exceeded
+    } else if saturated && (bytes as f64) < expected * (1.0 - settings.tolerance) {
+        RateCheck::Short
+    } else {
+        RateCheck::Consistent
+    }
+}
+
+// Clears any existing queuing disciplines on the interfaces and installs the
+// qdisc, filter, and policy state of all subscribers. Used on startup and when
+// the enforcement interfaces change at runtime. Held subscribers get their
+// previous policy instead.
+async fn setup_interfaces(
+    site: Site<'_>,
+    subscriber_limit_control_state: &mut HashMap<i32, SubscriberControlState>,
+    next_handle_id: &mut i32,
+    held: &HeldPolicies,
+    storage: &dyn crate::storage::Storage,
+    log: &slog::Logger,
+) -> Result<(), EnforcementError> {
+    let Site {
+        subscriber_interface,
+        upstream_interfaces,
+        ..
+    } = site;
+    // Clear any existing queuing disciplines.
+    clear_interface_limit(subscriber_interface, log).await?;
 
     // Setup the root qdisc
-    setup_root_qdisc(&subscriber_interface, 0, &log)
-        .await
-        .unwrap();
+    setup_root_qdisc(subscriber_interface, 0, log).await?;
 
-    if upstream_interface.is_some() {
-        // Clear any existing queuing disciplines on startup.
-        clear_interface_limit(upstream_interface.as_ref().unwrap(), &log)
-            .await
-            .unwrap();
-        setup_root_qdisc(upstream_interface.as_ref().unwrap(), 8, &log)
-            .await
-            .unwrap();
-        setup_fallback_class(upstream_interface.as_ref().unwrap(), 8, &log)
-            .await
-            .unwrap();
+    for upstream_interface in upstream_interfaces {
+        clear_interface_limit(upstream_interface, log).await?;
+        setup_root_qdisc(upstream_interface, 8, log).await?;
+        setup_fallback_class(upstream_interface, 8, log).await?;
     }
 
-    // On startup synchronize the state in the database with the local iptables
-    // rules and qdisc configuration. This is not very robust, and would be
-    // better integrated with actual netfilter tables for efficiency and better
-    // control of the actual state of the rules present when other firewalls may
-    // also be active.
-    let current_db_state = query_all_subscriber_access_state(&db_pool, &log)
-        .await
-        .expect("Unable to get initial access policy state");
+    // Synchronize the state in the database with the local iptables rules and
+    // qdisc configuration. This is not very robust, and would be better
+    // integrated with actual netfilter tables for efficiency and better control
+    // of the actual state of the rules present when other firewalls may also be
+    // active.
+    let current_db_state = query_all_subscriber_access_state(storage, log).await?;
 
     for sub in current_db_state {
         // Assign ephemeral state to each subscriber
-        let sub_limit_state = subscriber_limit_control_state.get(&sub.subscriber_id);
-        let sub_limit_state = match sub_limit_state {
-            Some(state) => state,
-            None => {
+        let sub_limit_state = subscriber_limit_control_state
+            .entry(sub.subscriber_id)
+            .or_insert_with(|| {
                 let sub_handle = format!("{:03X}", next_handle_id);
-                next_handle_id += 1;
-                subscriber_limit_control_state.insert(
-                    sub.subscriber_id,
-                    SubscriberControlState {
-                        qdisc_handle: sub_handle,
-                        ip: sub.ip,
-                    },
-                );
-                subscriber_limit_control_state
-                    .get(&sub.subscriber_id)
-                    .expect("Unable to retrieve key just inserted")
-            }
-        };
+                *next_handle_id += 1;
+                SubscriberControlState {
+                    qdisc_handle: sub_handle,
+                    addresses: sub.addresses,
+                    applied_policy: None,
+                    requested_policy: None,
+                    policy_override: None,
+                }
+            });
+        // The interfaces were just cleared, so the policy must be reapplied.
+        sub_limit_state.applied_policy = None;
 
         // Setup subscriber class
-        setup_subscriber_class(
-            &subscriber_interface,
-            0,
-            &sub_limit_state.qdisc_handle,
-            &log,
-        )
-        .await
-        .unwrap();
+        setup_subscriber_class(subscriber_interface, 0, &sub_limit_state.qdisc_handle, log).await?;
 
-        add_subscriber_dst_filter(&subscriber_interface, 0, &sub_limit_state, &log)
-            .await
-            .unwrap();
+        add_subscriber_dst_filter(subscriber_interface, 0, sub_limit_state, log).await?;
 
-        if upstream_interface.is_some() {
+        if !upstream_interfaces.is_empty() {
             let id_offset = 8;
-            // Setup subscriber class
-            setup_subscriber_class(
-                upstream_interface.as_ref().unwrap(),
-                id_offset,
-                &sub_limit_state.qdisc_handle,
-                &log,
-            )
-            .await
-            .unwrap();
-
-            add_subscriber_mark_filter(
-                upstream_interface.as_ref().unwrap(),
-                id_offset,
-                &sub_limit_state,
-                &log,
-            )
-            .await
-            .unwrap();
+            for upstream_interface in upstream_interfaces {
+                // Setup subscriber class
+                setup_subscriber_class(
+                    upstream_interface,
+                    id_offset,
+                    &sub_limit_state.qdisc_handle,
+                    log,
+                )
+                .await?;
+
+                add_subscriber_mark_filter(upstream_interface, id_offset, &sub_limit_state, log)
+                    .await?;
+            }
 
+            // The mark is set once as traffic leaves the subscriber, and
+            // selects the subscriber's class on whichever upstream interface
+            // the traffic is routed out of.
             let mark_string = format!("0x{:X}{}", id_offset + 2, &sub_limit_state.qdisc_handle);
-            if !mark_rule_present(&sub_limit_state.ip.ip(), &mark_string)
-                .await
-                .unwrap()
-            {
-                set_mark_rule(&sub_limit_state.ip.ip(), &mark_string, &log)
-                    .await
-                    .unwrap();
+            for ip in sub_limit_state.addresses.iter() {
+                if !mark_rule_present(subscriber_interface.namespace(), &ip, &mark_string).await? {
+                    set_mark_rule(subscriber_interface.namespace(), &ip, &mark_string, log).await?;
+                }
             }
         }
 
+        let policy = held
+            .get(&sub.subscriber_id)
+            .map_or(&sub, |(previous, _)| previous);
         set_policy(
             sub.subscriber_id,
             sub_limit_state,
-            &sub,
-            &upstream_interface,
-            &subscriber_interface,
-            &db_pool,
-            &log,
+            policy,
+            site,
+            storage,
+            log,
         )
+        .await?;
+    }
+
+    Ok(())
+}
+
+// Looks up the subscribers whose balance ran out while haulage was down, and
+// schedules their release from their previous policy evenly across the grace
+// window, so that they are not all cut off at once after maintenance.
+async fn hold_exhausted_policies(
+    grace: std::time::Duration,
+    storage: &dyn crate::storage::Storage,
+    log: &slog::Logger,
+) -> HeldPolicies {
+    let mut previous = query_exhausted_previous_access_state(storage)
         .await
-        .expect("Unable to set initial subscriber policy");
+        .unwrap_or_else(|e| {
+            slog::warn!(log, "Unable to query subscribers exhausted while down"; "error" => e.to_string());
+            Vec::new()
+        });
+    if !previous.is_empty() {
+        slog::info!(log, "Holding previous policies of exhausted subscribers"; "subscribers" => previous.len(), "grace_s" => grace.as_secs());
     }
+    previous.sort_by_key(|sub| sub.subscriber_id);
+    let start = tokio::time::Instant::now();
+    let releases = release_offsets(previous.len(), grace);
+    previous
+        .into_iter()
+        .zip(releases)
+        .map(|(sub, offset)| (sub.subscriber_id, (sub, start + offset)))
+        .collect()
+}
 
-    let mut timer = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
-    loop {
-        tokio::select! {
-            _ = timer.tick() => {
-                let reenabled_subs = query_modified_subscriber_access_state(&db_pool, &log)
-                    .await
-                    .unwrap_or_else(|e| {
-                        slog::error!(log, "Unable to query for reenabled subscribers"; "error" => e.to_string());
-                        Vec::<SubscriberAccessInfo>::new()
-                    });
-                for sub in reenabled_subs {
-                    let sub_limit_state = subscriber_limit_control_state.get(&sub.subscriber_id);
-                    let sub_limit_state = match sub_limit_state {
-                        Some(state) => state,
-                        None => {
-                            let sub_handle = format!("{:03X}", next_handle_id);
-                            next_handle_id += 1;
-                            subscriber_limit_control_state.insert(
-                                sub.subscriber_id,
-                                SubscriberControlState {
-                                    qdisc_handle: sub_handle,
-                                    ip: sub.ip,
-                                },
-                            );
-                            subscriber_limit_control_state
-                                .get(&sub.subscriber_id)
-                                .expect("Unable to retrieve key just inserted")
-                        }
-                    };
+// Spreads the given number of releases evenly over the window, the last at
+// its end.
+fn release_offsets(count: usize, window: std::time::Duration) -> Vec<std::time::Duration> {
+    (1..=count)
+        .map(|i| window.mul_f64(i as f64 / count as f64))
+        .collect()
+}
 
-                    set_policy(sub.subscriber_id, sub_limit_state, &sub, &upstream_interface, &subscriber_interface, &db_pool, &log)
+// Takes over the enforcement state installed by a previous process, only
+// touching the kernel for subscribers whose policy changed in the meantime.
+// Subscribers added or moved to new addresses since the handoff need new tc
+// filters, which are not individually addressable, so fall back to
+// rebuilding the state of all subscribers.
+async fn resume_interfaces(
+    site: Site<'_>,
+    subscriber_limit_control_state: &mut HashMap<i32, SubscriberControlState>,
+    next_handle_id: &mut i32,
+    storage: &dyn crate::storage::Storage,
+    log: &slog::Logger,
+) -> Result<(), EnforcementError> {
+    let subscriber_interface = site.subscriber_interface;
+    let current_db_state = query_all_subscriber_access_state(storage, log).await?;
+
+    let changed = current_db_state.iter().any(|sub| {
+        subscriber_limit_control_state
+            .get(&sub.subscriber_id)
+            .is_none_or(|state| state.addresses != sub.addresses)
+    });
+    if changed {
+        slog::info!(
+            log,
+            "Subscribers changed since the handoff, rebuilding enforcement state"
+        );
+        for sub in &current_db_state {
+            if let Some(state) = subscriber_limit_control_state.get_mut(&sub.subscriber_id) {
+                if state.addresses != sub.addresses {
+                    clear_address_rules(subscriber_interface.namespace(), state, log)
                         .await
-                        .unwrap_or_else(|e| {
-                            slog::error!(log, "Unable to reenable subscriber"; "id" => sub.subscriber_id, "error" => e.to_string())
-                        });
+                        .unwrap_or_else(|e| slog::warn!(log, "Unable to clear rules for old address"; "ip" => state.addresses.to_string(), "error" => e.to_string()));
+                    state.addresses = sub.addresses;
                 }
             }
-            message = chan.recv() => {
-                if message.is_none() {
-                    break;
-                }
-                let message = message.unwrap();
-
-                let sub_limit_state = subscriber_limit_control_state.get(&message.target);
-                let sub_limit_state = match sub_limit_state {
-                    Some(state) => state,
-                    None => {
-                        let sub_handle = format!("{:03X}", next_handle_id);
-                        next_handle_id += 1;
-                        subscriber_limit_control_state.insert(
-                            message.target,
-                            SubscriberControlState {
-                                qdisc_handle: sub_handle,
-                                ip: query_subscriber_ip(message.target, &db_pool, &log).await.unwrap(),
-                            },
-                        );
-                        subscriber_limit_control_state
-                            .get(&message.target)
-                            .expect("Unable to retrieve key just inserted")
-                    }
-                };
+        }
+        return setup_interfaces(
+            site,
+            subscriber_limit_control_state,
+            next_handle_id,
+            &HeldPolicies::new(),
+            storage,
+            log,
+        )
+        .await;
+    }
 
-                let result = set_policy_for_condition(message.target, &sub_limit_state, message.new_state, &upstream_interface, &subscriber_interface, &db_pool, &log).await;
-                message.out_channel.send(result).unwrap();
-            }
+    for sub in current_db_state {
+        if let Some(sub_limit_state) = subscriber_limit_control_state.get_mut(&sub.subscriber_id) {
+            set_policy(sub.subscriber_id, sub_limit_state, &sub, site, storage, log).await?;
         }
     }
+    Ok(())
 }
 
-async fn forwarding_reject_rule_present(addr: &std::net::IpAddr) -> Result<bool, std::io::Error> {
+// Removes the iptables rules matching the subscriber's current addresses.
+async fn clear_address_rules(
+    netns: Option<&str>,
+    subscriber_state: &SubscriberControlState,
+    log: &slog::Logger,
+) -> Result<(), EnforcementError> {
+    let mark_string = format!("0x{:X}{}", 8 + 2, &subscriber_state.qdisc_handle);
+    for ip in subscriber_state.addresses.iter() {
+        delete_forwarding_reject_rule(netns, &ip, log).await?;
+        delete_mark_rule(netns, &ip, &mark_string, log).await?;
+        clear_dscp_rules(netns, &ip, log).await?;
+        clear_dns_rules(netns, &ip, log).await?;
+    }
+    Ok(())
+}
+
+async fn forwarding_reject_rule_present(
+    netns: Option<&str>,
+    addr: &ipnetwork::IpNetwork,
+) -> Result<bool, std::io::Error> {
     // IPTables holds state outside the lifetime of this program. The `-C`
     // option will return success if the rule is present, and 1 if it is not.
-    let output = tokio::process::Command::new("iptables")
-        .args(&["-C", "FORWARD", "-s", &addr.to_string(), "-j", "REJECT"])
+    let output = iptables(netns, addr)
+        .args(&["-C", "FORWARD", "-s", &rule_address(addr), "-j", "REJECT"])
         .output()
         .await?;
 
     Ok(output.status.success())
 }
 async fn mark_rule_present(
-    addr: &std::net::IpAddr,
+    netns: Option<&str>,
+    addr: &ipnetwork::IpNetwork,
     mark_string: &str,
 ) -> Result<bool, std::io::Error> {
     // IPTables holds state outside the lifetime of this program. The `-C`
     // option will return success if the rule is present, and 1 if it is not.
-    let output = tokio::process::Command::new("iptables")
+    let output = iptables(netns, addr)
         .args(&[
             "-C",
             "FORWARD",
             "-s",
-            &addr.to_string(),
+            &rule_address(addr),
             "-j",
             "MARK",
             "--set-mark",
@@ -327,95 +1145,149 @@ async fn mark_rule_present(
 
     Ok(output.status.success())
 }
+// Sets or removes the override of a subscriber's policy and applies the
+// result. Only subscribers already under enforcement can be overridden.
+async fn set_policy_override(
+    imsi: &str,
+    parameters: Option<OverrideParameters>,
+    subscriber_limit_control_state: &mut HashMap<i32, SubscriberControlState>,
+    site: Site<'_>,
+    storage: &dyn crate::storage::Storage,
+    log: &slog::Logger,
+) -> Result<Option<PolicyOverride>, EnforcementError> {
+    let sub = query_subscriber_access_state(imsi, storage)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or(EnforcementError::UserIdError)?;
+    let subscriber_state = subscriber_limit_control_state
+        .get_mut(&sub.subscriber_id)
+        .ok_or(EnforcementError::UserIdError)?;
+
+    subscriber_state.policy_override = parameters.map(|parameters| PolicyOverride {
+        imsi: imsi.to_owned(),
+        policy_id: sub.policy_id,
+        parameters,
+    });
+    set_policy(
+        sub.subscriber_id,
+        subscriber_state,
+        &sub,
+        site,
+        storage,
+        log,
+    )
+    .await?;
+    Ok(subscriber_state.policy_override.clone())
+}
+
 async fn set_policy_for_condition(
     target: UserId,
-    subscriber_state: &SubscriberControlState,
+    subscriber_state: &mut SubscriberControlState,
     condition: SubscriberCondition,
-    upstream_interface: &Option<String>,
-    subscriber_interface: &str,
-    db_pool: &sqlx::PgPool,
+    site: Site<'_>,
+    storage: &dyn crate::storage::Storage,
     log: &slog::Logger,
 ) -> Result<(), EnforcementError> {
-    let policy_to_apply = query_subscriber_access_policy(target, condition, db_pool, log).await?;
+    let policy_to_apply = query_subscriber_access_policy(target, condition, storage, log).await?;
 
     set_policy(
         target,
         subscriber_state,
         &policy_to_apply,
-        upstream_interface,
-        subscriber_interface,
-        db_pool,
+        site,
+        storage,
         log,
     )
     .await
 }
 
+// The policy to install for a subscriber at the given local time of day, with
+// any override layered over it and its schedule resolved.
+fn effective_policy(
+    subscriber_state: &SubscriberControlState,
+    policy: &SubscriberAccessInfo,
+    time: chrono::NaiveTime,
+) -> SubscriberAccessInfo {
+    match &subscriber_state.policy_override {
+        Some(policy_override) => policy_override.layer(policy),
+        None => policy.clone(),
+    }
+    .at(time)
+}
+
 async fn set_policy(
     target: UserId,
-    subscriber_state: &SubscriberControlState,
+    subscriber_state: &mut SubscriberControlState,
     policy: &SubscriberAccessInfo,
-    upstream_interface: &Option<String>,
-    subscriber_interface: &str,
-    db_pool: &sqlx::PgPool,
+    site: Site<'_>,
+    storage: &dyn crate::storage::Storage,
     log: &slog::Logger,
 ) -> Result<(), EnforcementError> {
-    // Apply policy across interfaces
+    let upstream_interfaces = site.upstream_interfaces;
+    let subscriber_interface = site.subscriber_interface;
+    subscriber_state.requested_policy = Some(policy.clone());
+    let policy = &effective_policy(subscriber_state, policy, site.local_time());
+
+    // Skip the kernel calls if the identical policy is already in place, but
+    // still record it in the database in case the stored policy has drifted.
+    if subscriber_state.applied_policy.as_ref() == Some(policy) {
+        slog::debug!(log, "Policy already applied"; "id" => target, "policy" => policy.policy_id);
+        update_current_policy(storage, target, policy.policy_id, log).await?;
+        return Ok(());
+    }
+    // Forget the applied policy until the new one is fully in place, since a
+    // failure part way leaves the kernel state unknown.
+    subscriber_state.applied_policy = None;
+
+    // Apply policy across interfaces. Uplink traffic may leave by any of the
+    // upstream interfaces, so each carries the same subscriber classes.
     match &policy.backhaul_ul_policy {
         AccessPolicy::Unlimited => {
-            match &upstream_interface {
-                None => {
-                    slog::warn!(
-                        log,
-                        "No 'upstreamInterface' configured, not modifying queues for unlimited rate limit policy!"
-                    );
-                }
-                Some(upstream_if) => {
-                    clear_user_limit(upstream_if, 8, &subscriber_state.qdisc_handle, &log).await?;
-                }
-            };
+            if upstream_interfaces.is_empty() {
+                slog::warn!(
+                    log,
+                    "No 'upstreamInterface' configured, not modifying queues for unlimited rate limit policy!"
+                );
+            }
+            for upstream_if in upstream_interfaces {
+                clear_user_limit(upstream_if, 8, &subscriber_state.qdisc_handle, &log).await?;
+            }
         }
         AccessPolicy::Block => {
             // Partially implemented-- currently no difference between
             // uplink and downlink block/allow policies, so set/unset
             // forwarding as part of the downlink policy only.
-            match &upstream_interface {
-                None => {
-                    slog::warn!(
-                        log,
-                        "No 'upstreamInterface' configured, not modifying htb qdisc for block rate limit policy!"
-                    );
-                }
-                Some(upstream_if) => {
-                    clear_user_limit(upstream_if, 8, &subscriber_state.qdisc_handle, &log).await?;
-                }
-            };
+            if upstream_interfaces.is_empty() {
+                slog::warn!(
+                    log,
+                    "No 'upstreamInterface' configured, not modifying htb qdisc for block rate limit policy!"
+                );
+            }
+            for upstream_if in upstream_interfaces {
+                clear_user_limit(upstream_if, 8, &subscriber_state.qdisc_handle, &log).await?;
+            }
         }
         AccessPolicy::TokenBucket(params) => {
-            match &upstream_interface {
-                None => {
-                    slog::error!(
-                        log,
-                        "Cannot set uplink TokenBucket rate limit policy without 'upstreamInterface' config!"
-                    );
-                    return Err(EnforcementError::RateLimitPolicyError(policy.policy_id));
-                }
-                Some(upstream_if) => {
-                    set_user_token_bucket(
-                        upstream_if,
-                        8,
-                        &subscriber_state.qdisc_handle,
-                        params,
-                        &log,
-                    )
+            if upstream_interfaces.is_empty() {
+                slog::error!(
+                    log,
+                    "Cannot set uplink TokenBucket rate limit policy without 'upstreamInterface' config!"
+                );
+                return Err(EnforcementError::RateLimitPolicyError(policy.policy_id));
+            }
+            for upstream_if in upstream_interfaces {
+                set_user_token_bucket(upstream_if, 8, &subscriber_state.qdisc_handle, params, &log)
                     .await?;
-                }
-            };
+            }
         }
     }
 
     match &policy.backhaul_dl_policy {
         AccessPolicy::Unlimited => {
-            delete_forwarding_reject_rule(&subscriber_state.ip.ip(), &log).await?;
+            for ip in subscriber_state.addresses.iter() {
+                delete_forwarding_reject_rule(subscriber_interface.namespace(), &ip, &log).await?;
+            }
             clear_user_limit(
                 &subscriber_interface,
                 0,
@@ -425,7 +1297,9 @@ async fn set_policy(
             .await?;
         }
         AccessPolicy::Block => {
-            set_forwarding_reject_rule(&subscriber_state.ip.ip(), &log).await?;
+            for ip in subscriber_state.addresses.iter() {
+                set_forwarding_reject_rule(subscriber_interface.namespace(), &ip, &log).await?;
+            }
             clear_user_limit(
                 &subscriber_interface,
                 0,
@@ -435,7 +1309,9 @@ async fn set_policy(
             .await?;
         }
         AccessPolicy::TokenBucket(params) => {
-            delete_forwarding_reject_rule(&subscriber_state.ip.ip(), &log).await?;
+            for ip in subscriber_state.addresses.iter() {
+                delete_forwarding_reject_rule(subscriber_interface.namespace(), &ip, &log).await?;
+            }
             set_user_token_bucket(
                 &subscriber_interface,
                 0,
@@ -447,21 +1323,273 @@ async fn set_policy(
         }
     }
 
-    update_current_policy(db_pool, target, policy.policy_id, log).await?;
+    // Remark traffic in both directions if the policy assigns a service class,
+    // replacing any marking from a previously applied policy.
+    for ip in subscriber_state.addresses.iter() {
+        clear_dscp_rules(subscriber_interface.namespace(), &ip, log).await?;
+        if let Some(dscp) = policy.dscp {
+            set_dscp_rules(subscriber_interface.namespace(), &ip, dscp, log).await?;
+        }
+    }
+
+    // Redirect DNS to the policy's resolver, if any, so that the subsystems
+    // attributing traffic by the answers subscribers receive see every lookup.
+    for ip in subscriber_state.addresses.iter() {
+        clear_dns_rules(subscriber_interface.namespace(), &ip, log).await?;
+        if let Some(resolver) = policy.dns_redirect {
+            set_dns_rules(subscriber_interface.namespace(), &ip, &resolver, log).await?;
+        }
+    }
+
+    subscriber_state.applied_policy = Some(policy.clone());
+    update_current_policy(storage, target, policy.policy_id, log).await?;
+    Ok(())
+}
+
+async fn set_dscp_rules(
+    netns: Option<&str>,
+    ip: &ipnetwork::IpNetwork,
+    dscp: u8,
+    log: &slog::Logger,
+) -> Result<(), EnforcementError> {
+    for direction in ["-s", "-d"] {
+        let command_output = iptables(netns, ip)
+            .args([
+                "-t",
+                "mangle",
+                "-I",
+                "FORWARD",
+                direction,
+                &rule_address(ip),
+                "-j",
+                "DSCP",
+                "--set-dscp",
+                &dscp.to_string(),
+            ])
+            .output()
+            .await?;
+
+        if !command_output.status.success() {
+            slog::error!(log, "iptables insert dscp rule failed"; "ip" => ip.to_string(), "dscp" => dscp);
+            return Err(EnforcementError::IptablesLogicError(
+                String::from_utf8_lossy(&command_output.stderr).into_owned(),
+            ));
+        }
+    }
+
     Ok(())
 }
 
+async fn clear_dscp_rules(
+    netns: Option<&str>,
+    ip: &ipnetwork::IpNetwork,
+    log: &slog::Logger,
+) -> Result<(), EnforcementError> {
+    // The DSCP value of an existing rule may not be known (e.g. after a
+    // restart), so find the rules to delete by listing the chain rather than
+    // checking for a specific rule with `-C`.
+    let list_output = iptables(netns, ip)
+        .args(["-t", "mangle", "-S", "FORWARD"])
+        .output()
+        .await?;
+
+    if !list_output.status.success() {
+        return Err(EnforcementError::IptablesLogicError(
+            String::from_utf8_lossy(&list_output.stderr).into_owned(),
+        ));
+    }
+
+    let listing = String::from_utf8_lossy(&list_output.stdout);
+    for rule in find_dscp_rules(&listing, ip) {
+        slog::debug!(log, "deleting dscp rule"; "ip" => ip.to_string(), "rule" => rule.join(" "));
+        let command_output = iptables(netns, ip)
+            .args(["-t", "mangle", "-D"])
+            .args(&rule)
+            .output()
+            .await?;
+
+        if !command_output.status.success() {
+            slog::error!(log, "iptables delete dscp rule failed"; "ip" => ip.to_string());
+            return Err(EnforcementError::IptablesLogicError(
+                String::from_utf8_lossy(&command_output.stderr).into_owned(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Finds the DSCP rules matching the given address in `iptables -S` output,
+// returning each rule's specification without the leading `-A`.
+fn find_dscp_rules(listing: &str, ip: &ipnetwork::IpNetwork) -> Vec<Vec<String>> {
+    find_address_rules(listing, ip, |fields| {
+        fields.windows(2).any(|pair| pair == ["-j", "DSCP"])
+    })
+}
+
+// Builds an iptables command for the address family of the given address.
+fn iptables(netns: Option<&str>, ip: &ipnetwork::IpNetwork) -> tokio::process::Command {
+    let program = match ip {
+        ipnetwork::IpNetwork::V4(_) => "iptables",
+        ipnetwork::IpNetwork::V6(_) => "ip6tables",
+    };
+    crate::netns::command(netns, program)
+}
+
+// Formats an address as iptables lists it, so that rules cover the whole of a
+// subscriber's prefix, e.g. an IPv6 /64.
+fn rule_address(ip: &ipnetwork::IpNetwork) -> String {
+    format!("{}/{}", ip.network(), ip.prefix())
+}
+
+// Finds the rules in `iptables -S` output matching the given address which
+// also satisfy the predicate.
+fn find_address_rules(
+    listing: &str,
+    ip: &ipnetwork::IpNetwork,
+    predicate: impl Fn(&[&str]) -> bool,
+) -> Vec<Vec<String>> {
+    let address = rule_address(ip);
+
+    listing
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+        .filter(|fields| fields.first() == Some(&"-A"))
+        .filter(|fields| {
+            fields
+                .windows(2)
+                .any(|pair| (pair[0] == "-s" || pair[0] == "-d") && pair[1] == address)
+        })
+        .filter(|fields| predicate(fields))
+        .map(|fields| fields[1..].iter().map(|f| f.to_string()).collect())
+        .collect()
+}
+
+const DNS_PORT: &str = "53";
+// DNS over TLS and over QUIC, which would hide lookups from the redirect.
+const DNS_OVER_TLS_PORT: &str = "853";
+
+// Redirects the subscriber's plain DNS queries to the resolver in the nat
+// table, and rejects their encrypted DNS in the filter table, where it is
+// checked before the forwarding reject rules of blocked subscribers.
+async fn set_dns_rules(
+    netns: Option<&str>,
+    ip: &ipnetwork::IpNetwork,
+    resolver: &std::net::IpAddr,
+    log: &slog::Logger,
+) -> Result<(), EnforcementError> {
+    // Queries can only be translated to a resolver of the same family.
+    if ip.is_ipv4() != resolver.is_ipv4() {
+        slog::warn!(log, "Not redirecting DNS to a resolver of another address family"; "ip" => ip.to_string(), "resolver" => resolver.to_string());
+        return Ok(());
+    }
+    let (address, resolver) = (rule_address(ip), resolver.to_string());
+    for protocol in ["udp", "tcp"] {
+        let rules: [Vec<&str>; 2] = [
+            vec![
+                "-t",
+                "nat",
+                "-I",
+                "PREROUTING",
+                "-s",
+                &address,
+                "-p",
+                protocol,
+                "--dport",
+                DNS_PORT,
+                "-j",
+                "DNAT",
+                "--to-destination",
+                &resolver,
+            ],
+            vec![
+                "-I",
+                "FORWARD",
+                "-s",
+                &address,
+                "-p",
+                protocol,
+                "--dport",
+                DNS_OVER_TLS_PORT,
+                "-j",
+                "REJECT",
+            ],
+        ];
+        for rule in rules {
+            let command_output = iptables(netns, ip).args(&rule).output().await?;
+
+            if !command_output.status.success() {
+                slog::error!(log, "iptables insert dns rule failed"; "ip" => &address, "resolver" => &resolver);
+                return Err(EnforcementError::IptablesLogicError(
+                    String::from_utf8_lossy(&command_output.stderr).into_owned(),
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn clear_dns_rules(
+    netns: Option<&str>,
+    ip: &ipnetwork::IpNetwork,
+    log: &slog::Logger,
+) -> Result<(), EnforcementError> {
+    for (table, chain) in [("nat", "PREROUTING"), ("filter", "FORWARD")] {
+        let list_output = iptables(netns, ip)
+            .args(["-t", table, "-S", chain])
+            .output()
+            .await?;
+
+        if !list_output.status.success() {
+            return Err(EnforcementError::IptablesLogicError(
+                String::from_utf8_lossy(&list_output.stderr).into_owned(),
+            ));
+        }
+
+        let listing = String::from_utf8_lossy(&list_output.stdout);
+        for rule in find_dns_rules(&listing, ip) {
+            slog::debug!(log, "deleting dns rule"; "ip" => ip.to_string(), "rule" => rule.join(" "));
+            let command_output = iptables(netns, ip)
+                .args(["-t", table, "-D"])
+                .args(&rule)
+                .output()
+                .await?;
+
+            if !command_output.status.success() {
+                slog::error!(log, "iptables delete dns rule failed"; "ip" => ip.to_string());
+                return Err(EnforcementError::IptablesLogicError(
+                    String::from_utf8_lossy(&command_output.stderr).into_owned(),
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Finds the DNS redirect and encrypted DNS reject rules matching the given
+// address, leaving its other reject rules in place.
+fn find_dns_rules(listing: &str, ip: &ipnetwork::IpNetwork) -> Vec<Vec<String>> {
+    find_address_rules(listing, ip, |fields| {
+        let has = |option: &str, value: &str| fields.windows(2).any(|pair| pair == [option, value]);
+        (has("-j", "DNAT") && has("--dport", DNS_PORT))
+            || (has("-j", "REJECT") && has("--dport", DNS_OVER_TLS_PORT))
+    })
+}
+
 async fn delete_forwarding_reject_rule(
-    ip: &std::net::IpAddr,
+    netns: Option<&str>,
+    ip: &ipnetwork::IpNetwork,
     log: &slog::Logger,
 ) -> Result<(), EnforcementError> {
-    if !forwarding_reject_rule_present(ip).await? {
+    if !forwarding_reject_rule_present(netns, ip).await? {
         slog::debug!(log, "Forwarding filter delete requested but filter not present"; "ip" => ip.to_string());
         return Ok(());
     }
 
-    let command_output = tokio::process::Command::new("iptables")
-        .args(&["-D", "FORWARD", "-s", &ip.to_string(), "-j", "REJECT"])
+    let command_output = iptables(netns, ip)
+        .args(&["-D", "FORWARD", "-s", &rule_address(ip), "-j", "REJECT"])
         .output()
         .await?;
 
@@ -476,18 +1604,19 @@ async fn delete_forwarding_reject_rule(
 }
 
 async fn set_forwarding_reject_rule(
-    ip: &std::net::IpAddr,
+    netns: Option<&str>,
+    ip: &ipnetwork::IpNetwork,
     log: &slog::Logger,
 ) -> Result<(), EnforcementError> {
     // Do not double insert, as this will require delete to run multiple times
     // and break the delete implementation
-    if forwarding_reject_rule_present(ip).await? {
+    if forwarding_reject_rule_present(netns, ip).await? {
         slog::info!(log, "Forwarding filter already present"; "ip" => ip.to_string());
         return Ok(());
     }
 
-    let command_status = tokio::process::Command::new("iptables")
-        .args(&["-I", "FORWARD", "-s", &ip.to_string(), "-j", "REJECT"])
+    let command_status = iptables(netns, ip)
+        .args(&["-I", "FORWARD", "-s", &rule_address(ip), "-j", "REJECT"])
         .status()
         .await?;
 
@@ -498,24 +1627,57 @@ async fn set_forwarding_reject_rule(
     Ok(())
 }
 
-async fn set_mark_rule(
-    ip: &std::net::IpAddr,
+async fn set_mark_rule(
+    netns: Option<&str>,
+    ip: &ipnetwork::IpNetwork,
+    mark_string: &str,
+    log: &slog::Logger,
+) -> Result<(), EnforcementError> {
+    // Do not double insert, as this will require delete to run multiple times
+    // and break the delete implementation
+    if mark_rule_present(netns, ip, mark_string).await? {
+        slog::info!(log, "Forwarding filter already present"; "ip" => ip.to_string());
+        return Ok(());
+    }
+
+    let command_status = iptables(netns, ip)
+        .args(&[
+            "-I",
+            "FORWARD",
+            "-s",
+            &rule_address(ip),
+            "-j",
+            "MARK",
+            "--set-mark",
+            mark_string,
+        ])
+        .status()
+        .await?;
+
+    if !command_status.success() {
+        slog::warn!(log, "iptables mark insert failed"; "ip" => ip.to_string());
+    }
+
+    Ok(())
+}
+
+async fn delete_mark_rule(
+    netns: Option<&str>,
+    ip: &ipnetwork::IpNetwork,
     mark_string: &str,
     log: &slog::Logger,
 ) -> Result<(), EnforcementError> {
-    // Do not double insert, as this will require delete to run multiple times
-    // and break the delete implementation
-    if mark_rule_present(ip, mark_string).await? {
-        slog::info!(log, "Forwarding filter already present"; "ip" => ip.to_string());
+    if !mark_rule_present(netns, ip, mark_string).await? {
+        slog::debug!(log, "Mark rule delete requested but rule not present"; "ip" => ip.to_string());
         return Ok(());
     }
 
-    let command_status = tokio::process::Command::new("iptables")
+    let command_status = iptables(netns, ip)
         .args(&[
-            "-I",
+            "-D",
             "FORWARD",
             "-s",
-            &ip.to_string(),
+            &rule_address(ip),
             "-j",
             "MARK",
             "--set-mark",
@@ -525,76 +1687,22 @@ async fn set_mark_rule(
         .await?;
 
     if !command_status.success() {
-        slog::warn!(log, "iptables mark insert failed"; "ip" => ip.to_string());
+        slog::warn!(log, "iptables mark delete failed"; "ip" => ip.to_string());
     }
 
     Ok(())
 }
 
-// A hacky fixup to remove the malformed options element from the token bucket
-// filter json output. This implementation assumes the input is ASCII, and that
-// the options element is never the first key in a givem object.
-fn delete_malformed_options_element(input: &str) -> String {
-    let mut output = String::new();
-    let mut i = input.find(r#","options":"#).unwrap_or(input.len());
-    let mut copy_begin_index: usize = 0;
-    while i < input.len() {
-        output.push_str(&input[copy_begin_index..i]);
-
-        // Find the matching close bracket by scanning the index without copying
-        let mut curly_count = 0;
-        while i < input.len() {
-            if input.as_bytes()[i] as char == '{' {
-                curly_count += 1;
-            }
-            if input.as_bytes()[i] as char == '}' {
-                curly_count -= 1;
-                if curly_count == 0 {
-                    i += 1;
-                    break;
-                }
-            }
-            i += 1;
-        }
-
-        if i >= input.len() {
-            break;
-        }
-        copy_begin_index = i;
-        i = input[copy_begin_index..]
-            .find(r#","options":"#)
-            .unwrap_or(input[copy_begin_index..].len())
-            + copy_begin_index;
-    }
-    // Handle any leftovers if needed
-    output.push_str(&input[copy_begin_index..i]);
-
-    output
-}
-
-async fn clear_interface_limit(iface: &str, log: &slog::Logger) -> Result<(), EnforcementError> {
+async fn clear_interface_limit(
+    interface: &crate::netns::Interface,
+    log: &slog::Logger,
+) -> Result<(), EnforcementError> {
+    let iface = interface.name.as_str();
     slog::debug!(log, "clearing interface config"; "interface" => iface);
-    let current_iface_status = tokio::process::Command::new("tc")
-        .args(&["-j", "qdisc", "show", "dev", iface])
-        .output()
-        .await?;
-
-    // Delete the options "key", which in debian Buster and earlier is not valid
-    // JSON!
-    // https://lkml.kernel.org/netdev/278df9b9-e2f6-fe8a-e7d6-432b29a39697@gmail.com/T/
-    let current_iface_status = delete_malformed_options_element(
-        std::str::from_utf8(&current_iface_status.stdout).unwrap(),
-    );
-    let current_iface_qdiscs: Vec<QDiscInfo> = serde_json::from_str(&current_iface_status)?;
-
-    let mut found_child = false;
-    for qdisc in current_iface_qdiscs {
-        if qdisc.handle != "0:" {
-            found_child = true;
-            break;
-        }
-    }
+    let mut tc = crate::tc::Tc::open(interface).await?;
 
+    // The kernel's default qdiscs have no handle.
+    let found_child = tc.qdisc_handles().await?.iter().any(|handle| *handle != 0);
     if !found_child {
         slog::info!(log, "only default qdisc present, nothing to clear"; "interface" => iface);
         return Ok(());
@@ -602,629 +1710,595 @@ async fn clear_interface_limit(iface: &str, log: &slog::Logger) -> Result<(), En
 
     slog::warn!(log, "clearing non-trivial qdisc config");
 
-    let clear_output = tokio::process::Command::new("tc")
-        .args(&["qdisc", "del", "dev", iface, "parent", "root"])
-        .output()
-        .await?;
-
-    if !clear_output.status.success() {
-        slog::error!(log, "tc command to clear interface failed";
-            "stdout" => String::from_utf8(clear_output.stdout).unwrap_or("[Failed to parse output]".to_owned()),
-            "stderr" => String::from_utf8(clear_output.stderr).unwrap_or("[Failed to parse output]".to_owned())
-        );
-        return Err(EnforcementError::TcCommandError);
+    if let Err(e) = tc.delete_root_qdisc().await {
+        slog::error!(log, "netlink request to clear interface failed"; "error" => e.to_string());
+        return Err(e.into());
     }
 
     Ok(())
 }
 
+// The handle of the root htb qdisc, and the major number of its classes.
+fn root_major(id_offset: u8) -> u16 {
+    id_offset as u16 + 1
+}
+
+// Builds the minor number of a class, or the major number of a qdisc, from its
+// kind prefix and the subscriber's handle fragment, e.g. 0x2001 for prefix 2
+// and fragment "001".
+fn subscriber_id(prefix: u8, sub_handle: &str) -> Result<u16, EnforcementError> {
+    u16::from_str_radix(&format!("{:X}{}", prefix, sub_handle), 16)
+        .map_err(|_| EnforcementError::TcHandleError(sub_handle.to_owned()))
+}
+
+// The handle of the sfq qdisc within a subscriber's class.
+fn sfq_handle(id_offset: u8, sub_handle: &str) -> Result<u32, EnforcementError> {
+    Ok(crate::tc::handle(
+        subscriber_id(id_offset + 6, sub_handle)?,
+        0,
+    ))
+}
+
 async fn setup_root_qdisc(
-    iface: &str,
+    interface: &crate::netns::Interface,
     id_offset: u8,
     log: &slog::Logger,
 ) -> Result<(), EnforcementError> {
+    let iface = interface.name.as_str();
     slog::debug!(log, "Setting up root qdisc"; "interface" => iface);
-
-    let add_status = tokio::process::Command::new("tc")
-        .args(&[
-            "qdisc",
-            "add",
-            "dev",
-            iface,
-            "parent",
-            "root",
-            "handle",
-            &format!("{:X}:", id_offset + 1),
-            "htb",
-        ])
-        .status()
-        .await?;
-
-    if !add_status.success() {
-        slog::warn!(log, "qdisc add root with htb failed");
+    let mut tc = crate::tc::Tc::open(interface).await?;
+    let major = root_major(id_offset);
+
+    if let Err(e) = tc
+        .add_qdisc(
+            crate::tc::TC_H_ROOT,
+            crate::tc::handle(major, 0),
+            crate::tc::Qdisc::Htb,
+        )
+        .await
+    {
+        slog::warn!(log, "qdisc add root with htb failed"; "error" => e.to_string());
     }
 
-    let add_status = tokio::process::Command::new("tc")
-        .args(&[
-            "class",
-            "add",
-            "dev",
-            iface,
-            "parent",
-            &format!("{:X}:", id_offset + 1),
-            "classid",
-            &format!("{:X}:0x{}000", id_offset + 1, 1),
-            "htb",
-            "rate",
-            FULL_INTERFACE_HTB_RATE_STR,
-            "burst",
-            HTB_CBURST_AMOUNT_STR,
-            "ceil",
-            FULL_INTERFACE_HTB_RATE_STR,
-            "cburst",
-            HTB_CBURST_AMOUNT_STR,
-        ])
-        .status()
-        .await?;
-
-    if !add_status.success() {
-        slog::warn!(log, "htb add subscriber class failed");
+    if let Err(e) = tc
+        .add_htb_class(
+            crate::tc::handle(major, 0),
+            crate::tc::handle(major, ROOT_CLASS_MINOR),
+            crate::tc::HtbRate {
+                rate: FULL_INTERFACE_HTB_RATE_BYTES,
+                ceil: FULL_INTERFACE_HTB_RATE_BYTES,
+                burst: Some(HTB_CBURST_AMOUNT_BYTES),
+                cburst: Some(HTB_CBURST_AMOUNT_BYTES),
+                prio: 0,
+            },
+        )
+        .await
+    {
+        slog::warn!(log, "htb add subscriber class failed"; "error" => e.to_string());
     }
 
     Ok(())
 }
 
 async fn setup_subscriber_class(
-    iface: &str,
+    interface: &crate::netns::Interface,
     id_offset: u8,
     sub_handle_fragment: &str,
     log: &slog::Logger,
 ) -> Result<(), EnforcementError> {
+    let iface = interface.name.as_str();
     slog::debug!(log, "adding subscriber class to base qdisc"; "interface" => iface, "sub" => sub_handle_fragment);
-
-    let add_status = tokio::process::Command::new("tc")
-        .args(&[
-            "class",
-            "add",
-            "dev",
-            iface,
-            "parent",
-            &format!("{:X}:0x{}000", id_offset + 1, 1),
-            "classid",
-            &format!("{:X}:0x{}{}", id_offset + 1, 2, sub_handle_fragment),
-            "htb",
-            "rate",
-            BASE_HTB_RATE_STR,
-        ])
-        .status()
-        .await?;
-
-    if !add_status.success() {
-        slog::warn!(log, "htb add subscriber class failed");
+    let mut tc = crate::tc::Tc::open(interface).await?;
+    let major = root_major(id_offset);
+    let class = crate::tc::handle(major, subscriber_id(2, sub_handle_fragment)?);
+
+    if let Err(e) = tc
+        .add_htb_class(
+            crate::tc::handle(major, ROOT_CLASS_MINOR),
+            class,
+            crate::tc::HtbRate {
+                rate: BASE_HTB_RATE_BYTES,
+                ceil: BASE_HTB_RATE_BYTES,
+                burst: None,
+                cburst: None,
+                prio: 0,
+            },
+        )
+        .await
+    {
+        slog::warn!(log, "htb add subscriber class failed"; "error" => e.to_string());
     }
 
-    let add_status = tokio::process::Command::new("tc")
-        .args(&[
-            "qdisc",
-            "add",
-            "dev",
-            iface,
-            "parent",
-            &format!("{:X}:0x{}{}", id_offset + 1, 2, sub_handle_fragment),
-            "handle",
-            &format!("{:X}{}:", id_offset + 6, sub_handle_fragment),
-            "sfq",
-            "perturb",
-            "30",
-            "headdrop",
-            "probability",
-            "0.5",
-            "redflowlimit",
-            "20000",
-            "ecn",
-            "harddrop",
-        ])
-        .status()
-        .await?;
-
-    if !add_status.success() {
-        slog::warn!(log, "qdisc add sub sfq failed");
+    if let Err(e) = tc
+        .add_qdisc(
+            class,
+            sfq_handle(id_offset, sub_handle_fragment)?,
+            crate::tc::Qdisc::SfqRed,
+        )
+        .await
+    {
+        slog::warn!(log, "qdisc add sub sfq failed"; "error" => e.to_string());
     }
 
     Ok(())
 }
 
 async fn setup_fallback_class(
-    iface: &str,
+    interface: &crate::netns::Interface,
     id_offset: u8,
     log: &slog::Logger,
 ) -> Result<(), EnforcementError> {
+    let iface = interface.name.as_str();
     slog::debug!(log, "adding fallback class to base qdisc"; "interface" => iface);
-
-    let add_status = tokio::process::Command::new("tc")
-        .args(&[
-            "class",
-            "add",
-            "dev",
-            iface,
-            "parent",
-            &format!("{:X}:0x{}000", id_offset + 1, 1),
-            "classid",
-            &format!("{:X}:0xFFFF", id_offset + 1),
-            "htb",
-            "rate",
-            BASE_HTB_RATE_STR,
-            "ceil",
-            FULL_INTERFACE_HTB_RATE_STR,
-            "cburst",
-            HTB_CBURST_AMOUNT_STR,
-        ])
-        .status()
-        .await?;
-
-    if !add_status.success() {
-        slog::warn!(log, "htb add default class failed");
+    let mut tc = crate::tc::Tc::open(interface).await?;
+    let major = root_major(id_offset);
+    let class = crate::tc::handle(major, FALLBACK_CLASS_MINOR);
+
+    if let Err(e) = tc
+        .add_htb_class(
+            crate::tc::handle(major, ROOT_CLASS_MINOR),
+            class,
+            crate::tc::HtbRate {
+                rate: BASE_HTB_RATE_BYTES,
+                ceil: FULL_INTERFACE_HTB_RATE_BYTES,
+                burst: None,
+                cburst: Some(HTB_CBURST_AMOUNT_BYTES),
+                prio: 0,
+            },
+        )
+        .await
+    {
+        slog::warn!(log, "htb add default class failed"; "error" => e.to_string());
     }
 
     slog::debug!(log, "adding catchall_filter"; "interface" => iface);
 
-    let add_status = tokio::process::Command::new("tc")
-        .args(&[
-            "filter",
-            "add",
-            "dev",
-            iface,
-            "parent",
-            &format!("{:X}:", id_offset + 1),
-            "prio",
-            "2",
-            "matchall",
-            "flowid",
-            &format!("{:X}:0xFFFF", id_offset + 1),
-        ])
-        .status()
-        .await?;
-
-    if !add_status.success() {
-        slog::warn!(log, "add catchall filter failed");
+    if let Err(e) = tc
+        .add_matchall_filter(crate::tc::handle(major, 0), 2, class)
+        .await
+    {
+        slog::warn!(log, "add catchall filter failed"; "error" => e.to_string());
     }
 
     slog::debug!(log, "adding catchall_qdisc"; "interface" => iface);
-    let add_status = tokio::process::Command::new("tc")
-        .args(&[
-            "qdisc",
-            "add",
-            "dev",
-            iface,
-            "parent",
-            &format!("{:X}:0xFFFF", id_offset + 1),
-            "handle",
-            &format!("0x{:X}FFF:", id_offset + 1),
-            "fq_codel",
-        ])
-        .status()
-        .await?;
-
-    if !add_status.success() {
-        slog::warn!(log, "add catchall qdisc failed");
+    if let Err(e) = tc
+        .add_qdisc(
+            class,
+            crate::tc::handle(major << 12 | 0xFFF, 0),
+            crate::tc::Qdisc::FqCodel,
+        )
+        .await
+    {
+        slog::warn!(log, "add catchall qdisc failed"; "error" => e.to_string());
     }
 
     Ok(())
 }
 
 async fn clear_user_limit(
-    iface: &str,
+    interface: &crate::netns::Interface,
     id_offset: u8,
     sub_handle: &str,
     log: &slog::Logger,
 ) -> Result<(), EnforcementError> {
+    let iface = interface.name.as_str();
     slog::debug!(log, "clearing limit"; "interface" => iface, "sub_handle" => sub_handle);
-
-    let change_status = tokio::process::Command::new("tc")
-        .args(&[
-            "class",
-            "change",
-            "dev",
-            iface,
-            "parent",
-            &format!("{:X}:0x{}000", id_offset + 1, 1),
-            "classid",
-            &format!("{:X}:0x{}{}", id_offset + 1, 2, sub_handle),
-            "htb",
-            "rate",
-            BASE_HTB_RATE_STR,
-            "ceil",
-            FULL_INTERFACE_HTB_RATE_STR,
-            "cburst",
-            HTB_CBURST_AMOUNT_STR,
-        ])
-        .status()
-        .await?;
-    if !change_status.success() {
-        slog::warn!(log, "htb class change rate limit to 1gbps failed");
+    let mut tc = crate::tc::Tc::open(interface).await?;
+    let major = root_major(id_offset);
+
+    if let Err(e) = tc
+        .change_htb_class(
+            crate::tc::handle(major, ROOT_CLASS_MINOR),
+            crate::tc::handle(major, subscriber_id(2, sub_handle)?),
+            crate::tc::HtbRate {
+                rate: BASE_HTB_RATE_BYTES,
+                ceil: FULL_INTERFACE_HTB_RATE_BYTES,
+                burst: None,
+                cburst: Some(HTB_CBURST_AMOUNT_BYTES),
+                prio: 0,
+            },
+        )
+        .await
+    {
+        slog::warn!(log, "htb class change rate limit to 1gbps failed"; "error" => e.to_string());
     }
 
     Ok(())
 }
 
 async fn set_user_token_bucket(
-    iface: &str,
+    interface: &crate::netns::Interface,
     id_offset: u8,
     sub_handle: &str,
     params: &TokenBucketParameters,
     log: &slog::Logger,
 ) -> Result<(), EnforcementError> {
+    let iface = interface.name.as_str();
     slog::debug!(log, "setting token bucket limit"; "interface" => iface, "sub_handle" => sub_handle);
-
-    let change_status = tokio::process::Command::new("tc")
-        .args(&[
-            "class",
-            "change",
-            "dev",
-            iface,
-            "parent",
-            &format!("{:X}:0x{}000", id_offset + 1, 1),
-            "classid",
-            &format!("{:X}:0x{}{}", id_offset + 1, 2, sub_handle),
-            "htb",
-            "rate",
-            &format!(
-                "{}kbit",
-                std::cmp::min(params.rate_kibps, BASE_HTB_RATE_KIBITPS)
-            ),
-            "ceil",
-            &format!("{}kbit", params.rate_kibps),
-            "cburst",
-            HTB_CBURST_AMOUNT_STR,
-        ])
-        .status()
-        .await?;
-    if !change_status.success() {
-        slog::warn!(log, "htb class change rate limit failed");
+    let mut tc = crate::tc::Tc::open(interface).await?;
+    let major = root_major(id_offset);
+
+    // Policy rates are in kilobits per second, of 1000 bits. The guaranteed
+    // rate never exceeds the ceiling, e.g. a lower scheduled rate.
+    let rate_bytes = |kibps: u32| kibps as u64 * 1000 / 8;
+    let min_rate_kibps = params.min_rate_kibps.unwrap_or(BASE_HTB_RATE_KIBITPS);
+    if let Err(e) = tc
+        .change_htb_class(
+            crate::tc::handle(major, ROOT_CLASS_MINOR),
+            crate::tc::handle(major, subscriber_id(2, sub_handle)?),
+            crate::tc::HtbRate {
+                rate: rate_bytes(std::cmp::min(min_rate_kibps, params.rate_kibps)),
+                ceil: rate_bytes(params.rate_kibps),
+                burst: params.burst_bytes.map(u64::from),
+                cburst: Some(HTB_CBURST_AMOUNT_BYTES),
+                prio: params.priority.unwrap_or(0).into(),
+            },
+        )
+        .await
+    {
+        slog::warn!(log, "htb class change rate limit failed"; "error" => e.to_string());
     }
 
     Ok(())
 }
 
 async fn add_subscriber_dst_filter(
-    iface: &str,
+    interface: &crate::netns::Interface,
     id_offset: u8,
     sub: &SubscriberControlState,
     log: &slog::Logger,
 ) -> Result<(), EnforcementError> {
-    // TODO(matt9j) Only supports IPv4, should support v4 and v6!
+    let iface = interface.name.as_str();
     slog::debug!(log, "adding sub dst_filter"; "interface" => iface, "sub_handle" => &sub.qdisc_handle);
+    let mut tc = crate::tc::Tc::open(interface).await?;
+    let major = root_major(id_offset);
 
-    let add_status = tokio::process::Command::new("tc")
-        .args(&[
-            "filter",
-            "add",
-            "dev",
-            iface,
-            "parent",
-            &format!("{:X}:", id_offset + 1),
-            "protocol",
-            "ip",
-            "prio",
-            "1",
-            "u32",
-            "match",
-            "ip",
-            "dst",
-            &sub.ip.to_string(),
-            "flowid",
-            &format!("{:X}:0x{}{}", id_offset + 1, 2, &sub.qdisc_handle),
-        ])
-        .status()
-        .await?;
-
-    if !add_status.success() {
-        slog::warn!(log, "add subscriber dst filter failed");
+    for destination in sub.addresses.iter() {
+        let priority = match destination {
+            ipnetwork::IpNetwork::V4(_) => IPV4_DST_FILTER_PRIORITY,
+            ipnetwork::IpNetwork::V6(_) => IPV6_DST_FILTER_PRIORITY,
+        };
+        if let Err(e) = tc
+            .add_u32_dst_filter(
+                crate::tc::handle(major, 0),
+                priority,
+                destination,
+                crate::tc::handle(major, subscriber_id(2, &sub.qdisc_handle)?),
+            )
+            .await
+        {
+            slog::warn!(log, "add subscriber dst filter failed"; "ip" => destination.to_string(), "error" => e.to_string());
+        }
     }
 
     Ok(())
 }
 
-// TODO(matt9j) heavily duplicated with add_subscriber_dst_filter
 async fn add_subscriber_mark_filter(
-    iface: &str,
+    interface: &crate::netns::Interface,
     id_offset: u8,
     sub: &SubscriberControlState,
     log: &slog::Logger,
 ) -> Result<(), EnforcementError> {
-    // TODO(matt9j) Only supports IPv4, should support v4 and v6!
+    let iface = interface.name.as_str();
     slog::debug!(log, "adding sub src filter"; "interface" => iface, "sub_handle" => &sub.qdisc_handle);
+    let mut tc = crate::tc::Tc::open(interface).await?;
+    let major = root_major(id_offset);
+
+    // The mark matches the one set by set_mark_rule.
+    if let Err(e) = tc
+        .add_fw_filter(
+            crate::tc::handle(major, 0),
+            1,
+            subscriber_id(id_offset + 2, &sub.qdisc_handle)? as u32,
+            crate::tc::handle(major, subscriber_id(2, &sub.qdisc_handle)?),
+        )
+        .await
+    {
+        slog::warn!(log, "add subscriber src filter failed"; "error" => e.to_string());
+    }
 
-    let add_status = tokio::process::Command::new("tc")
-        .args(&[
-            "filter",
-            "add",
-            "dev",
-            iface,
-            "parent",
-            &format!("{:X}:", id_offset + 1),
-            "prio",
-            "1",
-            "handle",
-            &format!("0x{:X}{}", id_offset + 2, &sub.qdisc_handle),
-            "fw",
-            "classid",
-            &format!("0x{:X}:0x{}{}", id_offset + 1, 2, &sub.qdisc_handle),
-        ])
-        .status()
-        .await?;
+    Ok(())
+}
 
-    if !add_status.success() {
-        slog::warn!(log, "add subscriber src filter failed");
+async fn read_queue_stats(
+    subscriber_interface: &crate::netns::Interface,
+    upstream_interfaces: &[crate::netns::Interface],
+    subscriber_limit_control_state: &HashMap<i32, SubscriberControlState>,
+) -> Result<HashMap<UserId, SubscriberQueueStats>, EnforcementError> {
+    let downlink = read_qdisc_counters(subscriber_interface).await?;
+    let mut uplinks = Vec::new();
+    for upstream_interface in upstream_interfaces {
+        uplinks.push(read_qdisc_counters(upstream_interface).await?);
     }
 
-    Ok(())
+    subscriber_limit_control_state
+        .iter()
+        .map(|(id, state)| {
+            // Each subscriber class holds an sfq qdisc, as set up by
+            // setup_subscriber_class.
+            let uplink_handle = sfq_handle(8, &state.qdisc_handle)?;
+            let uplink = uplinks
+                .iter()
+                .filter_map(|counters| counters.get(&uplink_handle))
+                .fold(QueueCounters::default(), |total, counters| QueueCounters {
+                    bytes: total.bytes + counters.bytes,
+                    packets: total.packets + counters.packets,
+                    drops: total.drops + counters.drops,
+                });
+            let stats = SubscriberQueueStats {
+                uplink,
+                downlink: downlink
+                    .get(&sfq_handle(0, &state.qdisc_handle)?)
+                    .copied()
+                    .unwrap_or_default(),
+            };
+            Ok((*id, stats))
+        })
+        .collect()
+}
+
+// Maps the handle of each qdisc on the interface to its counters.
+async fn read_qdisc_counters(
+    interface: &crate::netns::Interface,
+) -> Result<HashMap<u32, QueueCounters>, EnforcementError> {
+    let mut tc = crate::tc::Tc::open(interface).await?;
+    Ok(tc
+        .qdisc_stats()
+        .await?
+        .into_iter()
+        .map(|(handle, stats)| {
+            let counters = QueueCounters {
+                bytes: stats.bytes,
+                packets: stats.packets,
+                drops: stats.drops,
+            };
+            (handle, counters)
+        })
+        .collect())
 }
 
 async fn update_current_policy(
-    db_pool: &sqlx::PgPool,
+    storage: &dyn crate::storage::Storage,
     id: UserId,
     new_policy: PolicyId,
     log: &slog::Logger,
 ) -> Result<SubscriberAccessInfo, EnforcementError> {
-    let mut transaction = db_pool.begin().await?;
     slog::debug!(log, "noting the currently applied policy in the DB"; "id" => id);
-
-    let subscriber_update_query = r#"
-        UPDATE subscribers
-        SET "current_policy" = $1
-        FROM access_policies, static_ips
-        WHERE ("internal_uid" = $2) AND (subscribers.current_policy = access_policies.id) AND (subscribers.imsi = static_ips.imsi)
-        RETURNING "internal_uid" AS "subscriber_id", access_policies."id" AS "policy_id", "ip", "local_ul_policy_kind", "local_ul_policy_parameters", "local_dl_policy_kind", "local_dl_policy_parameters", "backhaul_ul_policy_kind", "backhaul_ul_policy_parameters", "backhaul_dl_policy_kind", "backhaul_dl_policy_parameters"
-    "#;
-
-    let policy_row: SubscriberAccessPolicyRow = sqlx::query_as(subscriber_update_query)
-        .bind(new_policy)
-        .bind(id)
-        .fetch_one(&mut transaction)
-        .await?;
-
-    transaction.commit().await?;
-
+    let policy_row = storage.update_current_policy(id, new_policy).await?;
     let parsed_access_info: SubscriberAccessInfo = (&policy_row).try_into()?;
     Ok(parsed_access_info)
 }
 
-async fn query_subscriber_ip(
+async fn query_subscriber_addresses(
     subscriber_id: UserId,
-    db_pool: &sqlx::PgPool,
+    storage: &dyn crate::storage::Storage,
     log: &slog::Logger,
-) -> Result<ipnetwork::IpNetwork, EnforcementError> {
-    slog::debug!(log, "querying subscriber ip");
-    let mut transaction = db_pool.begin().await?;
-
-    let ip_query = r#"
-        SELECT "ip"
-        FROM subscribers
-        INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
-        WHERE (subscribers.internal_uid = $1)
-    "#;
-
-    let ip_rows: Vec<SubscriberIpRow> = sqlx::query_as(ip_query)
-        .bind(subscriber_id)
-        .fetch_all(&mut transaction)
-        .await?;
-
-    transaction.commit().await?;
+) -> Result<SubscriberAddresses, EnforcementError> {
+    slog::debug!(log, "querying subscriber addresses");
+    let ips = storage.subscriber_addresses(subscriber_id).await?;
 
-    if ip_rows.len() != 1 {
+    if ips.is_empty() {
         return Err(EnforcementError::UserIdError);
     }
 
-    Ok(ip_rows.first().unwrap().ip)
+    let mut addresses = SubscriberAddresses::default();
+    for ip in ips {
+        addresses.set(ip);
+    }
+    Ok(addresses)
 }
 
 async fn query_subscriber_access_policy(
     subscriber_id: UserId,
     condition: SubscriberCondition,
-    db_pool: &sqlx::PgPool,
+    storage: &dyn crate::storage::Storage,
     log: &slog::Logger,
 ) -> Result<SubscriberAccessInfo, EnforcementError> {
     slog::debug!(log, "querying subscriber access policy");
-    let ratelimit_state_query = match condition {
-        SubscriberCondition::_PositiveBalance => {
-            r#"
-                SELECT "internal_uid" AS "subscriber_id", "access_policies"."id" AS "policy_id", "local_ul_policy_kind", "local_ul_policy_parameters", "local_dl_policy_kind", "local_dl_policy_parameters", "backhaul_ul_policy_kind", "backhaul_ul_policy_parameters", "backhaul_dl_policy_kind", "backhaul_dl_policy_parameters"
-                FROM subscribers
-                INNER JOIN access_policies ON subscribers.positive_balance_policy = access_policies.id
-                WHERE (internal_uid = $1)
-            "#
-        }
-        SubscriberCondition::NoBalance => {
-            r#"
-                SELECT "internal_uid" AS "subscriber_id", "access_policies"."id" AS "policy_id", "local_ul_policy_kind", "local_ul_policy_parameters", "local_dl_policy_kind", "local_dl_policy_parameters", "backhaul_ul_policy_kind", "backhaul_ul_policy_parameters", "backhaul_dl_policy_kind", "backhaul_dl_policy_parameters"
-                FROM subscribers
-                INNER JOIN access_policies ON subscribers.zero_balance_policy = access_policies.id
-                WHERE (internal_uid = $1)
-            "#
-        }
-    };
-
-    let mut transaction = db_pool.begin().await?;
-    let policy_rows: Vec<SubscriberAccessPolicyRow> = sqlx::query_as(ratelimit_state_query)
-        .bind(subscriber_id)
-        .fetch_all(&mut transaction)
+    let policy_rows = storage
+        .subscriber_access_policy(subscriber_id, condition)
         .await?;
 
-    transaction.commit().await?;
-
-    if policy_rows.len() != 1 {
+    let mut parsed = parse_access_rows(&policy_rows)?;
+    if parsed.len() != 1 {
         return Err(EnforcementError::UserIdError);
     }
-
-    let parsed_access_info: SubscriberAccessInfo = policy_rows.first().unwrap().try_into()?;
-    Ok(parsed_access_info)
+    Ok(parsed.remove(0))
 }
 
+// Queries the policy to apply for the condition of every subscriber, based on
+// their account balance, or the balance of their pool if they draw from one,
+// unless the subscriber is administratively suspended or temporarily exempt,
+// which take precedence over any balance condition.
 async fn query_all_subscriber_access_state(
-    db_pool: &sqlx::PgPool,
+    storage: &dyn crate::storage::Storage,
     log: &slog::Logger,
 ) -> Result<Vec<SubscriberAccessInfo>, EnforcementError> {
     slog::debug!(log, "querying global ratelimit db state");
-    let mut transaction = db_pool.begin().await?;
-
-    // Get the ratelimit state to apply for each condition of subscribers. Need
-    // to return different columns based on the subscriber's account balance (or
-    // possibly other conditions in the future).
-
-    // Zero balance subscribers
-    let ratelimit_state_query = r#"
-        SELECT "internal_uid" AS "subscriber_id", access_policies."id" AS "policy_id", "ip", "local_ul_policy_kind", "local_ul_policy_parameters", "local_dl_policy_kind", "local_dl_policy_parameters", "backhaul_ul_policy_kind", "backhaul_ul_policy_parameters", "backhaul_dl_policy_kind", "backhaul_dl_policy_parameters"
-        FROM subscribers
-        INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
-        INNER JOIN access_policies ON subscribers.zero_balance_policy = access_policies.id
-        WHERE (subscribers.data_balance = 0)
-    "#;
-
-    let zero_balance_rows: Vec<SubscriberAccessPolicyRow> = sqlx::query_as(ratelimit_state_query)
-        .fetch_all(&mut transaction)
-        .await?;
-
-    // Positive balance subscribers
-    let ratelimit_state_query = r#"
-        SELECT "internal_uid" AS "subscriber_id", access_policies."id" AS "policy_id", "ip", "local_ul_policy_kind", "local_ul_policy_parameters", "local_dl_policy_kind", "local_dl_policy_parameters", "backhaul_ul_policy_kind", "backhaul_ul_policy_parameters", "backhaul_dl_policy_kind", "backhaul_dl_policy_parameters"
-        FROM subscribers
-        INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
-        INNER JOIN access_policies ON subscribers.positive_balance_policy = access_policies.id
-        WHERE (subscribers.data_balance > 0)
-    "#;
-
-    let positive_balance_rows: Vec<SubscriberAccessPolicyRow> =
-        sqlx::query_as(ratelimit_state_query)
-            .fetch_all(&mut transaction)
-            .await?;
-
-    transaction.commit().await?;
+    let rows = storage.all_access_state().await?;
 
     // Once rows are retreived, parse them into our internal representation.
-    let mut parsed_ratelimits: Vec<SubscriberAccessInfo> = Vec::new();
-    parsed_ratelimits.reserve_exact(zero_balance_rows.len() + positive_balance_rows.len());
-    for row in zero_balance_rows.iter() {
-        parsed_ratelimits.push(row.try_into()?)
-    }
-    for row in positive_balance_rows.iter() {
-        parsed_ratelimits.push(row.try_into()?)
-    }
-
-    Ok(parsed_ratelimits)
+    parse_access_rows(&rows)
 }
 
 async fn query_modified_subscriber_access_state(
-    db_pool: &sqlx::PgPool,
+    storage: &dyn crate::storage::Storage,
     log: &slog::Logger,
 ) -> Result<Vec<SubscriberAccessInfo>, EnforcementError> {
-    let mut transaction = db_pool.begin().await?;
     slog::debug!(log, "querying subscribers with modified access state");
+    let rows = storage.modified_access_state().await?;
+    parse_access_rows(&rows)
+}
 
-    // Get the ratelimit state to apply for each condition of subscribers. Need
-    // to return different columns based on the subscriber's account balance (or
-    // possibly other conditions in the future).
-
-    // Zero balance subscribers
-    let ratelimit_state_updated_query = r#"
-        SELECT "internal_uid" AS "subscriber_id", access_policies."id" AS "policy_id", "ip", "local_ul_policy_kind", "local_ul_policy_parameters", "local_dl_policy_kind", "local_dl_policy_parameters", "backhaul_ul_policy_kind", "backhaul_ul_policy_parameters", "backhaul_dl_policy_kind", "backhaul_dl_policy_parameters"
-        FROM subscribers
-        INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
-        INNER JOIN access_policies ON subscribers.zero_balance_policy = access_policies.id
-        WHERE (subscribers.data_balance = 0) AND (subscribers.zero_balance_policy != subscribers.current_policy)
-    "#;
-
-    let zero_balance_rows: Vec<SubscriberAccessPolicyRow> =
-        sqlx::query_as(ratelimit_state_updated_query)
-            .fetch_all(&mut transaction)
-            .await?;
-
-    // Positive balance subscribers
-    let ratelimit_state_updated_query = r#"
-        SELECT "internal_uid" AS "subscriber_id", access_policies."id" AS "policy_id", "ip", "local_ul_policy_kind", "local_ul_policy_parameters", "local_dl_policy_kind", "local_dl_policy_parameters", "backhaul_ul_policy_kind", "backhaul_ul_policy_parameters", "backhaul_dl_policy_kind", "backhaul_dl_policy_parameters"
-        FROM subscribers
-        INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
-        INNER JOIN access_policies ON subscribers.positive_balance_policy = access_policies.id
-        WHERE (subscribers.data_balance > 0) AND (subscribers.positive_balance_policy != subscribers.current_policy)
-    "#;
-
-    let positive_balance_rows: Vec<SubscriberAccessPolicyRow> =
-        sqlx::query_as(ratelimit_state_updated_query)
-            .fetch_all(&mut transaction)
-            .await?;
-
-    transaction.commit().await?;
-
-    // Once rows are retreived, parse them into our internal representation.
-    let mut parsed_ratelimits: Vec<SubscriberAccessInfo> = Vec::new();
-    parsed_ratelimits.reserve_exact(zero_balance_rows.len() + positive_balance_rows.len());
-    for row in zero_balance_rows.iter() {
-        parsed_ratelimits.push(row.try_into()?)
-    }
-    for row in positive_balance_rows.iter() {
-        parsed_ratelimits.push(row.try_into()?)
-    }
+// Queries the policy last applied to each zero balance subscriber whose zero
+// balance policy was not yet in place, i.e. who ran out of balance while
+// haulage was not running. Suspended and exempt subscribers are left out,
+// since their policy does not depend on their balance.
+async fn query_exhausted_previous_access_state(
+    storage: &dyn crate::storage::Storage,
+) -> Result<Vec<SubscriberAccessInfo>, EnforcementError> {
+    let rows = storage.exhausted_previous_access_state().await?;
+    rows.iter().map(|row| row.try_into()).collect()
+}
 
-    Ok(parsed_ratelimits)
+// Queries the policy a subscriber should currently have, whether or not it is
+// already their current policy, e.g. when the parameters of their current
+// policy were changed in place.
+async fn query_subscriber_access_state(
+    imsi: &str,
+    storage: &dyn crate::storage::Storage,
+) -> Result<Vec<SubscriberAccessInfo>, EnforcementError> {
+    let rows = storage.subscriber_access_state(imsi).await?;
+    parse_access_rows(&rows)
 }
 
-#[derive(Debug)]
+#[derive(Debug, serde::Serialize, Deserialize)]
 struct SubscriberControlState {
     qdisc_handle: String,
-    ip: ipnetwork::IpNetwork,
+    addresses: SubscriberAddresses,
+    // The policy last installed in the kernel, if known to still be in place.
+    applied_policy: Option<SubscriberAccessInfo>,
+    // The policy last set for the subscriber, before any override is layered
+    // and any schedule resolved, from which scheduled rates are switched.
+    #[serde(default)]
+    requested_policy: Option<SubscriberAccessInfo>,
+    #[serde(default)]
+    policy_override: Option<PolicyOverride>,
 }
 
-#[derive(Debug, Deserialize)]
-struct QDiscInfo {
-    handle: String,
+// The addresses enforced for a subscriber, at most one of each family, e.g.
+// an IPv4 address and an IPv6 prefix for a dual-stack subscriber.
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, Deserialize)]
+struct SubscriberAddresses {
+    v4: Option<ipnetwork::Ipv4Network>,
+    v6: Option<ipnetwork::Ipv6Network>,
 }
+impl SubscriberAddresses {
+    // Replaces the subscriber's address of the same family.
+    fn set(&mut self, ip: ipnetwork::IpNetwork) {
+        match ip {
+            ipnetwork::IpNetwork::V4(network) => self.v4 = Some(network),
+            ipnetwork::IpNetwork::V6(network) => self.v6 = Some(network),
+        }
+    }
 
-#[derive(Debug, Clone, Deserialize)]
-struct LimitPolicyParameters {
-    rate_kibps: Option<u32>,
+    fn iter(&self) -> impl Iterator<Item = ipnetwork::IpNetwork> {
+        let v4 = self.v4.map(ipnetwork::IpNetwork::V4);
+        let v6 = self.v6.map(ipnetwork::IpNetwork::V6);
+        v4.into_iter().chain(v6)
+    }
 }
-
-#[derive(Debug, Clone, sqlx::FromRow)]
-struct SubscriberAccessPolicyRow {
-    ip: ipnetwork::IpNetwork,
-    subscriber_id: i32,
-    policy_id: i32,
-    local_ul_policy_kind: i32,
-    local_ul_policy_parameters: sqlx::types::Json<LimitPolicyParameters>,
-    local_dl_policy_kind: i32,
-    local_dl_policy_parameters: sqlx::types::Json<LimitPolicyParameters>,
-    backhaul_ul_policy_kind: i32,
-    backhaul_ul_policy_parameters: sqlx::types::Json<LimitPolicyParameters>,
-    backhaul_dl_policy_kind: i32,
-    backhaul_dl_policy_parameters: sqlx::types::Json<LimitPolicyParameters>,
+impl From<ipnetwork::IpNetwork> for SubscriberAddresses {
+    fn from(ip: ipnetwork::IpNetwork) -> Self {
+        let mut addresses = SubscriberAddresses::default();
+        addresses.set(ip);
+        addresses
+    }
 }
-
-#[derive(Debug, Clone, sqlx::FromRow)]
-struct SubscriberIpRow {
-    ip: ipnetwork::IpNetwork,
+impl std::fmt::Display for SubscriberAddresses {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let addresses: Vec<String> = self.iter().map(|ip| ip.to_string()).collect();
+        write!(f, "{}", addresses.join(","))
+    }
 }
 
-#[derive(Debug, Clone)]
+// A token bucket limiting a link to its rate, which it may borrow up to from
+// unused backhaul. Under contention the link is guaranteed its minimum rate,
+// and links of lower priority values borrow first.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, Deserialize)]
 struct TokenBucketParameters {
     rate_kibps: u32,
+    #[serde(default)]
+    min_rate_kibps: Option<u32>,
+    #[serde(default)]
+    burst_bytes: Option<u32>,
+    #[serde(default)]
+    priority: Option<u8>,
+    #[serde(default)]
+    schedule: Option<RateSchedule>,
+}
+impl TokenBucketParameters {
+    // The link policy in effect at the given local time of day.
+    fn at(&self, time: chrono::NaiveTime) -> AccessPolicy {
+        let rate_kibps = match &self.schedule {
+            Some(schedule) if schedule.hours.contains(time) => schedule.rate_kibps,
+            _ => Some(self.rate_kibps),
+        };
+        match rate_kibps {
+            Some(rate_kibps) => AccessPolicy::TokenBucket(TokenBucketParameters {
+                rate_kibps,
+                schedule: None,
+                ..self.clone()
+            }),
+            None => AccessPolicy::Unlimited,
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+// A rate replacing the token bucket rate during daily hours, e.g. for an
+// unlimited nights plan. Without a rate the link is unlimited during the hours.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, Deserialize)]
+struct RateSchedule {
+    hours: crate::clock::DailyHours,
+    rate_kibps: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, Deserialize)]
 enum AccessPolicy {
     Unlimited,
     Block,
     TokenBucket(TokenBucketParameters),
 }
+impl AccessPolicy {
+    fn at(&self, time: chrono::NaiveTime) -> AccessPolicy {
+        match self {
+            AccessPolicy::TokenBucket(params) => params.at(time),
+            policy => policy.clone(),
+        }
+    }
 
-#[derive(Debug, Clone)]
+    fn is_scheduled(&self) -> bool {
+        matches!(
+            self,
+            AccessPolicy::TokenBucket(TokenBucketParameters {
+                schedule: Some(_),
+                ..
+            })
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, Deserialize)]
 struct SubscriberAccessInfo {
-    ip: ipnetwork::IpNetwork,
+    addresses: SubscriberAddresses,
     subscriber_id: i32,
     policy_id: i32,
     _local_ul_policy: AccessPolicy,
     _local_dl_policy: AccessPolicy,
     backhaul_ul_policy: AccessPolicy,
     backhaul_dl_policy: AccessPolicy,
+    dscp: Option<u8>,
+    // The resolver subscribers' DNS queries are redirected to, if any.
+    #[serde(default)]
+    dns_redirect: Option<std::net::IpAddr>,
+}
+impl SubscriberAccessInfo {
+    // The policy with any scheduled rates resolved for the given local time of
+    // day, as installed in the kernel.
+    fn at(&self, time: chrono::NaiveTime) -> SubscriberAccessInfo {
+        SubscriberAccessInfo {
+            _local_ul_policy: self._local_ul_policy.at(time),
+            _local_dl_policy: self._local_dl_policy.at(time),
+            backhaul_ul_policy: self.backhaul_ul_policy.at(time),
+            backhaul_dl_policy: self.backhaul_dl_policy.at(time),
+            ..self.clone()
+        }
+    }
+
+    fn is_scheduled(&self) -> bool {
+        self.backhaul_ul_policy.is_scheduled() || self.backhaul_dl_policy.is_scheduled()
+    }
 }
 
 fn create_policy_from_parameters(
@@ -1235,23 +2309,76 @@ fn create_policy_from_parameters(
         1 => Ok(AccessPolicy::Unlimited),
         2 => Ok(AccessPolicy::Block),
         3 => {
+            let schedule = match &parameters.schedule {
+                Some(schedule) => Some(RateSchedule {
+                    hours: schedule
+                        .hours
+                        .parse()
+                        .map_err(EnforcementError::RateLimitParameterError)?,
+                    rate_kibps: schedule.rate_kibps,
+                }),
+                None => None,
+            };
             let parsed_parameters = TokenBucketParameters {
                 rate_kibps: parameters.rate_kibps.ok_or(
                     EnforcementError::RateLimitParameterError("Missing rate_kibps".to_owned()),
                 )?,
+                min_rate_kibps: parameters.min_rate_kibps,
+                burst_bytes: parameters.burst_bytes,
+                priority: parameters.priority,
+                schedule,
             };
+            if parsed_parameters
+                .min_rate_kibps
+                .is_some_and(|min_rate| min_rate > parsed_parameters.rate_kibps)
+            {
+                return Err(EnforcementError::RateLimitParameterError(
+                    "min_rate_kibps exceeds rate_kibps".to_owned(),
+                ));
+            }
+            // The kernel has eight htb priorities.
+            if parsed_parameters
+                .priority
+                .is_some_and(|priority| priority > 7)
+            {
+                return Err(EnforcementError::RateLimitParameterError(
+                    "priority must be at most 7".to_owned(),
+                ));
+            }
             Ok(AccessPolicy::TokenBucket(parsed_parameters))
         }
         _ => Err(EnforcementError::RateLimitPolicyError(policy_kind_id)),
     }
 }
 
-impl TryFrom<&SubscriberAccessPolicyRow> for SubscriberAccessInfo {
+// Parses the policy rows of subscribers, which have a row for each of their
+// addresses, into the access info of each subscriber.
+fn parse_access_rows<'a>(
+    rows: impl IntoIterator<Item = &'a AccessPolicyRow>,
+) -> Result<Vec<SubscriberAccessInfo>, EnforcementError> {
+    let mut parsed: Vec<SubscriberAccessInfo> = Vec::new();
+    let mut positions = HashMap::new();
+    for row in rows {
+        match positions.get(&row.subscriber_id) {
+            Some(position) => {
+                let info: &mut SubscriberAccessInfo = &mut parsed[*position];
+                info.addresses.set(row.ip)
+            }
+            None => {
+                positions.insert(row.subscriber_id, parsed.len());
+                parsed.push(row.try_into()?);
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+impl TryFrom<&AccessPolicyRow> for SubscriberAccessInfo {
     type Error = EnforcementError;
 
-    fn try_from(row: &SubscriberAccessPolicyRow) -> Result<Self, Self::Error> {
+    fn try_from(row: &AccessPolicyRow) -> Result<Self, Self::Error> {
         Ok(SubscriberAccessInfo {
-            ip: row.ip,
+            addresses: row.ip.into(),
             subscriber_id: row.subscriber_id,
             policy_id: row.policy_id,
             _local_ul_policy: create_policy_from_parameters(
@@ -1270,6 +2397,14 @@ impl TryFrom<&SubscriberAccessPolicyRow> for SubscriberAccessInfo {
                 row.backhaul_dl_policy_kind,
                 &row.backhaul_dl_policy_parameters,
             )?,
+            dscp: row
+                .dscp
+                .map(|dscp| match dscp {
+                    0..=63 => Ok(dscp as u8),
+                    _ => Err(EnforcementError::DscpValueError(dscp)),
+                })
+                .transpose()?,
+            dns_redirect: row.dns_redirect.map(|resolver| resolver.ip()),
         })
     }
 }
@@ -1279,9 +2414,256 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_options_parse() {
-        let input = r#" [{"kind":"tbf","handle":"1:","root":true,"refcnt":2,"options":{rate 1Mbit burst 3840b lat 10.0ms }},{"kind":"qfq","handle":"2:","parent":"1:1","options":{}}]"#;
-        let desired_output = r#" [{"kind":"tbf","handle":"1:","root":true,"refcnt":2},{"kind":"qfq","handle":"2:","parent":"1:1"}]"#;
-        assert_eq!(delete_malformed_options_element(input), desired_output)
+    fn test_find_dscp_rules() {
+        let listing = "-P FORWARD ACCEPT\n\
+            -A FORWARD -s 10.45.0.2/32 -j DSCP --set-dscp 0x0a\n\
+            -A FORWARD -d 10.45.0.2/32 -j DSCP --set-dscp 0x0a\n\
+            -A FORWARD -s 10.45.0.20/32 -j DSCP --set-dscp 0x0a\n\
+            -A FORWARD -s 10.45.0.2/32 -j MARK --set-xmark 0xa001/0xffffffff\n";
+        let rules = find_dscp_rules(listing, &"10.45.0.2".parse().unwrap());
+        assert_eq!(
+            rules,
+            vec![
+                vec![
+                    "FORWARD",
+                    "-s",
+                    "10.45.0.2/32",
+                    "-j",
+                    "DSCP",
+                    "--set-dscp",
+                    "0x0a"
+                ],
+                vec![
+                    "FORWARD",
+                    "-d",
+                    "10.45.0.2/32",
+                    "-j",
+                    "DSCP",
+                    "--set-dscp",
+                    "0x0a"
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_dns_rules() {
+        let listing = "-P FORWARD ACCEPT\n\
+            -A FORWARD -s 10.45.0.2/32 -p tcp -m tcp --dport 853 -j REJECT --reject-with icmp-port-unreachable\n\
+            -A FORWARD -s 10.45.0.2/32 -j REJECT --reject-with icmp-port-unreachable\n\
+            -A PREROUTING -s 10.45.0.2/32 -p udp -m udp --dport 53 -j DNAT --to-destination 10.45.0.1\n\
+            -A PREROUTING -s 10.45.0.20/32 -p udp -m udp --dport 53 -j DNAT --to-destination 10.45.0.1\n";
+        let rules = find_dns_rules(listing, &"10.45.0.2".parse().unwrap());
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0][8], "853");
+        assert_eq!(rules[1][0], "PREROUTING");
+    }
+
+    #[test]
+    fn test_dual_stack_subscriber() {
+        let row = |subscriber_id: i32, ip: &str| AccessPolicyRow {
+            ip: ip.parse().unwrap(),
+            subscriber_id,
+            policy_id: 1,
+            local_ul_policy_kind: 1,
+            local_ul_policy_parameters: sqlx::types::Json(LimitPolicyParameters::default()),
+            local_dl_policy_kind: 1,
+            local_dl_policy_parameters: sqlx::types::Json(LimitPolicyParameters::default()),
+            backhaul_ul_policy_kind: 1,
+            backhaul_ul_policy_parameters: sqlx::types::Json(LimitPolicyParameters::default()),
+            backhaul_dl_policy_kind: 2,
+            backhaul_dl_policy_parameters: sqlx::types::Json(LimitPolicyParameters::default()),
+            dscp: None,
+            dns_redirect: None,
+        };
+        let rows = vec![
+            row(1, "10.45.0.2/32"),
+            row(2, "2001:db8:0:2::/64"),
+            row(1, "2001:db8:0:1::/64"),
+        ];
+        let parsed = parse_access_rows(&rows).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(
+            parsed[0].addresses.iter().collect::<Vec<_>>(),
+            vec![
+                "10.45.0.2/32".parse::<ipnetwork::IpNetwork>().unwrap(),
+                "2001:db8:0:1::/64".parse().unwrap(),
+            ]
+        );
+        assert_eq!(parsed[1].addresses.v4, None);
+
+        // Rules cover the subscriber's whole prefix, as iptables lists it.
+        let listing = "-P FORWARD ACCEPT\n\
+            -A FORWARD -s 2001:db8:0:1::/64 -j REJECT --reject-with icmp6-port-unreachable\n\
+            -A FORWARD -s 2001:db8:0:1::/64 -j DSCP --set-dscp 0x0a\n";
+        let rules = find_dscp_rules(listing, &"2001:db8:0:1::5/64".parse().unwrap());
+        assert_eq!(rules.len(), 1);
+        assert_eq!(
+            rule_address(&"2001:db8:0:1::5/64".parse().unwrap()),
+            "2001:db8:0:1::/64"
+        );
+    }
+
+    #[test]
+    fn test_scheduled_token_bucket() {
+        let parameters: LimitPolicyParameters = serde_json::from_value(serde_json::json!({
+            "rate_kibps": 512,
+            "schedule": { "hours": "22:00-06:00", "rate_kibps": null },
+        }))
+        .unwrap();
+        let policy = create_policy_from_parameters(3, &parameters).unwrap();
+        assert!(policy.is_scheduled());
+
+        let day = policy.at(chrono::NaiveTime::from_hms(12, 0, 0));
+        assert_eq!(
+            day,
+            AccessPolicy::TokenBucket(TokenBucketParameters {
+                rate_kibps: 512,
+                ..Default::default()
+            })
+        );
+        assert!(!day.is_scheduled());
+        assert_eq!(
+            policy.at(chrono::NaiveTime::from_hms(23, 0, 0)),
+            AccessPolicy::Unlimited
+        );
+        assert_eq!(
+            policy.at(chrono::NaiveTime::from_hms(5, 59, 0)),
+            AccessPolicy::Unlimited
+        );
+
+        let invalid: LimitPolicyParameters = serde_json::from_value(serde_json::json!({
+            "rate_kibps": 512,
+            "schedule": { "hours": "22:00" },
+        }))
+        .unwrap();
+        assert!(create_policy_from_parameters(3, &invalid).is_err());
+    }
+
+    #[test]
+    fn test_token_bucket_contention_parameters() {
+        let parameters: LimitPolicyParameters = serde_json::from_value(serde_json::json!({
+            "rate_kibps": 4096,
+            "min_rate_kibps": 512,
+            "burst_bytes": 65536,
+            "priority": 2,
+            "schedule": { "hours": "00:00-06:00", "rate_kibps": 8192 },
+        }))
+        .unwrap();
+        let policy = create_policy_from_parameters(3, &parameters).unwrap();
+
+        // Scheduled rates only replace the ceiling.
+        assert_eq!(
+            policy.at(chrono::NaiveTime::from_hms(1, 0, 0)),
+            AccessPolicy::TokenBucket(TokenBucketParameters {
+                rate_kibps: 8192,
+                min_rate_kibps: Some(512),
+                burst_bytes: Some(65536),
+                priority: Some(2),
+                schedule: None,
+            })
+        );
+
+        let invalid = |parameters: serde_json::Value| {
+            let parameters: LimitPolicyParameters = serde_json::from_value(parameters).unwrap();
+            create_policy_from_parameters(3, &parameters).is_err()
+        };
+        assert!(invalid(
+            serde_json::json!({ "rate_kibps": 512, "min_rate_kibps": 1024 })
+        ));
+        assert!(invalid(
+            serde_json::json!({ "rate_kibps": 512, "priority": 8 })
+        ));
+    }
+
+    #[test]
+    fn test_policy_override_layers_over_its_policy() {
+        let policy = SubscriberAccessInfo {
+            addresses: "10.45.0.2/32"
+                .parse::<ipnetwork::IpNetwork>()
+                .unwrap()
+                .into(),
+            subscriber_id: 1,
+            policy_id: 5,
+            _local_ul_policy: AccessPolicy::Unlimited,
+            _local_dl_policy: AccessPolicy::Unlimited,
+            backhaul_ul_policy: AccessPolicy::Unlimited,
+            backhaul_dl_policy: AccessPolicy::TokenBucket(TokenBucketParameters {
+                rate_kibps: 2048,
+                ..Default::default()
+            }),
+            dscp: None,
+            dns_redirect: None,
+        };
+        let policy_override = PolicyOverride {
+            imsi: String::from("001010000000001"),
+            policy_id: 5,
+            parameters: OverrideParameters {
+                backhaul_ul_rate_kibps: Some(1024),
+                backhaul_dl_rate_kibps: Some(8192),
+                dscp: Some(34),
+                expires: chrono::Utc::now(),
+            },
+        };
+
+        // Unlimited links stay unlimited, and rated links take the override.
+        let layered = policy_override.layer(&policy);
+        assert_eq!(layered.backhaul_ul_policy, AccessPolicy::Unlimited);
+        assert_eq!(
+            layered.backhaul_dl_policy,
+            AccessPolicy::TokenBucket(TokenBucketParameters {
+                rate_kibps: 8192,
+                ..Default::default()
+            })
+        );
+        assert_eq!(layered.dscp, Some(34));
+
+        // Other policies, e.g. once the balance runs out, are left as is.
+        let zero_balance = SubscriberAccessInfo {
+            policy_id: 3,
+            ..policy
+        };
+        assert_eq!(policy_override.layer(&zero_balance), zero_balance);
+    }
+
+    #[test]
+    fn test_release_offsets() {
+        let window = std::time::Duration::from_secs(60);
+        assert_eq!(
+            release_offsets(3, window),
+            vec![
+                std::time::Duration::from_secs(20),
+                std::time::Duration::from_secs(40),
+                std::time::Duration::from_secs(60),
+            ]
+        );
+        assert!(release_offsets(0, window).is_empty());
+    }
+
+    #[test]
+    fn test_check_rate() {
+        let settings = VerificationSettings {
+            window: std::time::Duration::from_secs(10),
+            tolerance: 0.2,
+        };
+        // 1000 kbit/s over 10s is 1.25MB.
+        assert_eq!(
+            check_rate(1000, 1_250_000, true, &settings),
+            RateCheck::Consistent
+        );
+        assert_eq!(
+            check_rate(1000, 100_000, false, &settings),
+            RateCheck::Consistent
+        );
+        assert_eq!(check_rate(1000, 100_000, true, &settings), RateCheck::Short);
+        // The ceiling burst is allowed on top of the tolerance.
+        assert_eq!(
+            check_rate(1000, 1_600_000, false, &settings),
+            RateCheck::Consistent
+        );
+        assert_eq!(
+            check_rate(1000, 2_000_000, false, &settings),
+            RateCheck::Exceeded
+        );
     }
 }
@@ -0,0 +1,144 @@
+// Subscriber lifecycle events recorded for downstream billing and CRM systems.
+// Creation and deletion events are recorded by database triggers, since
+// subscribers are provisioned by external tools rather than by haulage.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    FirstSeen,
+    PolicyChanged,
+    Suspended,
+    Resumed,
+    Exempted,
+    AddressCollision,
+    ToppedUp,
+    Deprioritized,
+    Reprioritized,
+    Underdelivered,
+    BalanceThreshold,
+    BalanceAdjusted,
+    PlanChanged,
+    TrialGranted,
+    TrialExpired,
+}
+impl EventKind {
+    const ALL: [EventKind; 15] = [
+        EventKind::FirstSeen,
+        EventKind::PolicyChanged,
+        EventKind::Suspended,
+        EventKind::Resumed,
+        EventKind::Exempted,
+        EventKind::AddressCollision,
+        EventKind::ToppedUp,
+        EventKind::Deprioritized,
+        EventKind::Reprioritized,
+        EventKind::Underdelivered,
+        EventKind::BalanceThreshold,
+        EventKind::BalanceAdjusted,
+        EventKind::PlanChanged,
+        EventKind::TrialGranted,
+        EventKind::TrialExpired,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::FirstSeen => "first_seen",
+            EventKind::PolicyChanged => "policy_changed",
+            EventKind::Suspended => "suspended",
+            EventKind::Resumed => "resumed",
+            EventKind::Exempted => "exempted",
+            EventKind::AddressCollision => "address_collision",
+            EventKind::ToppedUp => "topped_up",
+            EventKind::Deprioritized => "deprioritized",
+            EventKind::Reprioritized => "reprioritized",
+            EventKind::Underdelivered => "underdelivered",
+            EventKind::BalanceThreshold => "balance_threshold",
+            EventKind::BalanceAdjusted => "balance_adjusted",
+            EventKind::PlanChanged => "plan_changed",
+            EventKind::TrialGranted => "trial_granted",
+            EventKind::TrialExpired => "trial_expired",
+        }
+    }
+}
+
+// Whether events of the named kind are ever recorded, including the creation
+// and deletion events recorded by the database triggers.
+pub fn is_known_kind(kind: &str) -> bool {
+    ["created", "deleted"].contains(&kind) || EventKind::ALL.iter().any(|k| k.as_str() == kind)
+}
+
+// The thresholds a balance fell to or below in a single debit, so that each
+// is only recorded once per crossing however large the debit was.
+pub fn crossed_thresholds(previous: i64, current: i64, thresholds: &[i64]) -> Vec<i64> {
+    thresholds
+        .iter()
+        .copied()
+        .filter(|threshold| previous > *threshold && current <= *threshold)
+        .collect()
+}
+
+// Records a balance falling to or below each configured threshold, e.g. so
+// that a hook can warn the subscriber before they run out.
+pub async fn record_balance_thresholds(
+    storage: &dyn crate::storage::Storage,
+    subscriber: i32,
+    previous: i64,
+    current: i64,
+    thresholds: &[i64],
+) -> Result<(), crate::storage::StorageError> {
+    for threshold in crossed_thresholds(previous, current, thresholds) {
+        storage
+            .record_event(
+                subscriber,
+                EventKind::BalanceThreshold,
+                serde_json::json!({ "threshold": threshold, "balance": current }),
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+// Records an event on the given connection, so that callers can record the
+// event in the same transaction as the change it describes.
+pub async fn record_event(
+    connection: &mut sqlx::PgConnection,
+    subscriber: i32,
+    kind: EventKind,
+    details: serde_json::Value,
+) -> Result<(), sqlx::Error> {
+    let insert_query = r#"
+        INSERT INTO subscriber_events("subscriber", "imsi", "kind", "details")
+        SELECT "internal_uid", "imsi", $2, $3
+        FROM subscribers
+        WHERE "internal_uid" = $1
+    "#;
+
+    sqlx::query(insert_query)
+        .bind(subscriber)
+        .bind(kind.as_str())
+        .bind(details)
+        .execute(connection)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crossed_thresholds() {
+        let thresholds = [100_000_000, 10_000_000];
+        assert_eq!(
+            crossed_thresholds(150_000_000, 5_000_000, &thresholds),
+            vec![100_000_000, 10_000_000]
+        );
+        assert_eq!(
+            crossed_thresholds(100_000_000, 50_000_000, &thresholds),
+            Vec::<i64>::new()
+        );
+        // Top ups raise the balance without crossing anything.
+        assert!(crossed_thresholds(5_000_000, 150_000_000, &thresholds).is_empty());
+        assert!(is_known_kind("balance_threshold"));
+        assert!(is_known_kind("deleted"));
+        assert!(!is_known_kind("suspend"));
+    }
+}
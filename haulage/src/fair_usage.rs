@@ -0,0 +1,325 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum FairUsageError {
+    #[error("Database operation failed: {0}")]
+    DatabaseError(#[from] sqlx::error::Error),
+    #[error("No access policy named {0}")]
+    UnknownPolicy(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // The access policy applied to deprioritized subscribers.
+    pub policy: String,
+    // The total capacity of the backhaul links, against which utilization is
+    // measured.
+    pub capacity_kbps: u64,
+    // The fraction of capacity above which the backhaul is congested.
+    pub congestion_threshold: f64,
+    // The fraction of recently active subscribers deprioritized during
+    // congestion, heaviest first.
+    pub heaviest_share: f64,
+    // How far back subscriber usage is ranked when congestion begins.
+    pub usage_window: std::time::Duration,
+    // How long utilization must stay below the threshold before congestion
+    // ends, so that the relief from deprioritizing does not itself end it.
+    pub restore_after: std::time::Duration,
+    pub interval: std::time::Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transition {
+    Began,
+    Ended,
+}
+
+// Tracks whether the backhaul is congested from periodic utilization samples.
+#[derive(Debug)]
+struct CongestionDetector {
+    threshold: f64,
+    restore_after: std::time::Duration,
+    congested: bool,
+    calm_since: Option<std::time::Instant>,
+}
+
+impl CongestionDetector {
+    fn new(threshold: f64, restore_after: std::time::Duration) -> CongestionDetector {
+        CongestionDetector {
+            threshold,
+            restore_after,
+            congested: false,
+            calm_since: None,
+        }
+    }
+
+    fn update(&mut self, utilization: f64, now: std::time::Instant) -> Option<Transition> {
+        if utilization >= self.threshold {
+            self.calm_since = None;
+            if !self.congested {
+                self.congested = true;
+                return Some(Transition::Began);
+            }
+            return None;
+        }
+
+        if self.congested {
+            let calm_since = *self.calm_since.get_or_insert(now);
+            if now.duration_since(calm_since) >= self.restore_after {
+                self.congested = false;
+                self.calm_since = None;
+                return Some(Transition::Ended);
+            }
+        }
+        None
+    }
+}
+
+// The number of subscribers to deprioritize out of those recently active.
+// Any share above zero deprioritizes at least the single heaviest subscriber.
+fn deprioritized_count(active: usize, share: f64) -> usize {
+    ((active as f64 * share).ceil() as usize).min(active)
+}
+
+// Samples backhaul utilization from the interface counters, and while it is
+// congested moves the heaviest recent users to the configured policy,
+// restoring them once congestion ends. Transitions are recorded as subscriber
+// events and in the subscriber journal, and applied promptly by notifying the
+// enforcer.
+pub async fn run(
+    settings: Settings,
+    interfaces: Vec<String>,
+    db_pool: std::sync::Arc<sqlx::PgPool>,
+    log: slog::Logger,
+) {
+    let policy_id = match query_policy_id(&db_pool, &settings.policy).await {
+        Ok(policy_id) => policy_id,
+        Err(e) => {
+            slog::error!(log, "Fair usage deprioritization disabled"; "error" => e.to_string());
+            return;
+        }
+    };
+
+    // Subscribers left deprioritized by a previous run are restored, since
+    // congestion is not known to be ongoing.
+    match restore_all(&db_pool).await {
+        Ok(0) => {}
+        Ok(restored) => {
+            slog::info!(log, "Restored subscribers deprioritized before startup"; "subscribers" => restored)
+        }
+        Err(e) => {
+            slog::error!(log, "Failed to restore deprioritized subscribers"; "error" => e.to_string())
+        }
+    }
+
+    let capacity_bytes_per_sec = settings.capacity_kbps as f64 * 1000.0 / 8.0;
+    let mut detector =
+        CongestionDetector::new(settings.congestion_threshold, settings.restore_after);
+    let mut previous_sample = read_backhaul_bytes(&interfaces)
+        .await
+        .map(|bytes| (bytes, std::time::Instant::now()));
+
+    let mut timer = tokio::time::interval_at(
+        tokio::time::Instant::now() + settings.interval,
+        settings.interval,
+    );
+    loop {
+        timer.tick().await;
+        let now = std::time::Instant::now();
+        let current_sample = match read_backhaul_bytes(&interfaces).await {
+            Some(bytes) => (bytes, now),
+            None => {
+                slog::warn!(log, "Unable to read backhaul interface counters"; "interfaces" => interfaces.join(","));
+                previous_sample = None;
+                continue;
+            }
+        };
+        let utilization = match previous_sample.replace(current_sample) {
+            Some((previous_bytes, previous_time)) => {
+                let elapsed = now.duration_since(previous_time).as_secs_f64();
+                current_sample.0.saturating_sub(previous_bytes) as f64
+                    / elapsed
+                    / capacity_bytes_per_sec
+            }
+            None => continue,
+        };
+        slog::debug!(log, "Sampled backhaul utilization"; "utilization" => utilization);
+
+        match detector.update(utilization, now) {
+            Some(Transition::Began) => {
+                match deprioritize_heaviest(&db_pool, policy_id, &settings, utilization).await {
+                    Ok(deprioritized) => {
+                        slog::info!(log, "Backhaul congested, deprioritized heaviest subscribers"; "utilization" => utilization, "subscribers" => deprioritized)
+                    }
+                    Err(e) => {
+                        slog::error!(log, "Failed to deprioritize subscribers"; "error" => e.to_string())
+                    }
+                }
+            }
+            Some(Transition::Ended) => match restore_all(&db_pool).await {
+                Ok(restored) => {
+                    slog::info!(log, "Backhaul congestion ended, restored subscribers"; "utilization" => utilization, "subscribers" => restored)
+                }
+                Err(e) => {
+                    slog::error!(log, "Failed to restore deprioritized subscribers"; "error" => e.to_string())
+                }
+            },
+            None => {}
+        }
+    }
+}
+
+// The bytes carried by all backhaul links, which only add up to a
+// consistent total if every link can be read.
+async fn read_backhaul_bytes(interfaces: &[String]) -> Option<u64> {
+    let mut total = 0;
+    for interface in interfaces {
+        total += crate::reconciler::read_interface_bytes(interface).await?;
+    }
+    Some(total)
+}
+
+async fn query_policy_id(db_pool: &sqlx::PgPool, name: &str) -> Result<i32, FairUsageError> {
+    let policy: Option<(i32,)> =
+        sqlx::query_as(r#"SELECT "id" FROM access_policies WHERE "name" = $1"#)
+            .bind(name)
+            .fetch_optional(db_pool)
+            .await?;
+    policy
+        .map(|(id,)| id)
+        .ok_or_else(|| FairUsageError::UnknownPolicy(name.to_owned()))
+}
+
+async fn deprioritize_heaviest(
+    db_pool: &sqlx::PgPool,
+    policy_id: i32,
+    settings: &Settings,
+    utilization: f64,
+) -> Result<usize, FairUsageError> {
+    let window_start = chrono::Utc::now()
+        - chrono::Duration::from_std(settings.usage_window)
+            .unwrap_or_else(|_| chrono::Duration::zero());
+
+    let mut transaction = db_pool.begin().await?;
+
+    // Suspended and exempt subscribers are ranked along with everyone else
+    // but left as they are, since their policy takes precedence anyway.
+    let usage_query = r#"
+        SELECT subscribers."internal_uid", SUM("ran_bytes_up" + "ran_bytes_down")::BIGINT AS "bytes", subscribers."suspended" OR COALESCE(subscribers."exempt_until" > now(), false) AS "overridden"
+        FROM subscriber_usage
+        INNER JOIN subscribers ON subscriber_usage."subscriber" = subscribers."internal_uid"
+        WHERE "end_time" > $1
+        GROUP BY subscribers."internal_uid"
+        ORDER BY "bytes" DESC
+    "#;
+    let usage: Vec<(i32, i64, bool)> = sqlx::query_as(usage_query)
+        .bind(window_start)
+        .fetch_all(&mut transaction)
+        .await?;
+
+    let count = deprioritized_count(usage.len(), settings.heaviest_share);
+    let mut deprioritized = 0;
+    for (subscriber, bytes, overridden) in usage.into_iter().take(count) {
+        if overridden {
+            continue;
+        }
+        sqlx::query(
+            r#"UPDATE subscribers SET "deprioritized_policy" = $2 WHERE "internal_uid" = $1"#,
+        )
+        .bind(subscriber)
+        .bind(policy_id)
+        .execute(&mut transaction)
+        .await?;
+        crate::events::record_event(
+            &mut transaction,
+            subscriber,
+            crate::events::EventKind::Deprioritized,
+            serde_json::json!({
+                "policy": settings.policy,
+                "window_bytes": bytes,
+                "utilization": utilization,
+            }),
+        )
+        .await?;
+        deprioritized += 1;
+    }
+
+    notify_enforcer(&mut transaction).await?;
+    transaction.commit().await?;
+    Ok(deprioritized)
+}
+
+async fn restore_all(db_pool: &sqlx::PgPool) -> Result<usize, FairUsageError> {
+    let mut transaction = db_pool.begin().await?;
+    let restored: Vec<(i32,)> = sqlx::query_as(
+        r#"
+        UPDATE subscribers SET "deprioritized_policy" = NULL
+        WHERE "deprioritized_policy" IS NOT NULL
+        RETURNING "internal_uid"
+    "#,
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+
+    for (subscriber,) in &restored {
+        crate::events::record_event(
+            &mut transaction,
+            *subscriber,
+            crate::events::EventKind::Reprioritized,
+            serde_json::json!({}),
+        )
+        .await?;
+    }
+
+    if !restored.is_empty() {
+        notify_enforcer(&mut transaction).await?;
+    }
+    transaction.commit().await?;
+    Ok(restored.len())
+}
+
+// Asks the enforcer to apply changed policies now rather than at its next
+// poll. The notification is delivered when the transaction commits.
+async fn notify_enforcer(connection: &mut sqlx::PgConnection) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT pg_notify($1, '')")
+        .bind(crate::enforcer::APPLY_POLICY_CHANNEL)
+        .execute(connection)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_congestion_ends_after_calm_period() {
+        let start = std::time::Instant::now();
+        let minute = std::time::Duration::from_secs(60);
+        let mut detector = CongestionDetector::new(0.8, 5 * minute);
+
+        assert_eq!(detector.update(0.5, start), None);
+        assert_eq!(
+            detector.update(0.9, start + minute),
+            Some(Transition::Began)
+        );
+        assert_eq!(detector.update(0.95, start + 2 * minute), None);
+
+        // A brief lull does not end congestion, and congestion resuming
+        // restarts the calm period.
+        assert_eq!(detector.update(0.3, start + 3 * minute), None);
+        assert_eq!(detector.update(0.85, start + 4 * minute), None);
+        assert_eq!(detector.update(0.3, start + 5 * minute), None);
+        assert_eq!(detector.update(0.3, start + 9 * minute), None);
+        assert_eq!(
+            detector.update(0.3, start + 10 * minute),
+            Some(Transition::Ended)
+        );
+        assert_eq!(detector.update(0.3, start + 11 * minute), None);
+
+        assert_eq!(deprioritized_count(0, 0.1), 0);
+        assert_eq!(deprioritized_count(5, 0.1), 1);
+        assert_eq!(deprioritized_count(40, 0.1), 4);
+        assert_eq!(deprioritized_count(3, 1.5), 3);
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::clickhouse::{FlowKey, FlowUsage};
+
+const COPY_STATEMENT: &str = r#"
+    COPY flow_logs("start_time", "end_time", "user_addr", "remote_addr", "user_port", "remote_port", "protocol", "bytes_up", "bytes_down", "packets")
+    FROM STDIN
+"#;
+
+// Records the usage of individual flows in the database when flows are
+// accounted without a ClickHouse export, e.g. on small deployments where the
+// flow volume is low enough to keep alongside the usage records. Each flow is
+// aggregated in memory for one flow log interval, and written with the times
+// its first and last packets were seen within the interval.
+#[derive(Debug)]
+pub struct FlowLogger {
+    dispatch_channel: tokio::sync::mpsc::Sender<Message>,
+}
+impl FlowLogger {
+    pub fn new(
+        flow_log_schedule: crate::clock::Schedule,
+        db_pool: std::sync::Arc<sqlx::PgPool>,
+        pseudonymizer: Option<std::sync::Arc<crate::privacy::Pseudonymizer>>,
+        stats: std::sync::Arc<crate::stats::Stats>,
+        log: slog::Logger,
+    ) -> FlowLogger {
+        let (sender, receiver) = tokio::sync::mpsc::channel(64);
+        tokio::task::spawn(async move {
+            log_flows(
+                receiver,
+                flow_log_schedule,
+                db_pool,
+                pseudonymizer,
+                stats,
+                log,
+            )
+            .await;
+        });
+        FlowLogger {
+            dispatch_channel: sender,
+        }
+    }
+    pub fn clone_input_channel(&self) -> tokio::sync::mpsc::Sender<Message> {
+        self.dispatch_channel.clone()
+    }
+}
+
+#[derive(Debug)]
+pub enum Message {
+    // Usage of individual flows observed within a batch of packets.
+    Flows(HashMap<FlowKey, FlowUsage>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct FlowLog {
+    first_seen: chrono::DateTime<chrono::Utc>,
+    last_seen: chrono::DateTime<chrono::Utc>,
+    usage: FlowUsage,
+}
+
+async fn log_flows(
+    mut chan: tokio::sync::mpsc::Receiver<Message>,
+    flow_log_schedule: crate::clock::Schedule,
+    db_pool: std::sync::Arc<sqlx::PgPool>,
+    pseudonymizer: Option<std::sync::Arc<crate::privacy::Pseudonymizer>>,
+    stats: std::sync::Arc<crate::stats::Stats>,
+    log: slog::Logger,
+) {
+    let mut flows: HashMap<FlowKey, FlowLog> = HashMap::new();
+    let mut timer = tokio::time::interval_at(
+        tokio::time::Instant::now() + flow_log_schedule.first_delay(chrono::Utc::now()),
+        flow_log_schedule.period,
+    );
+    loop {
+        tokio::select! {
+            _ = timer.tick() => {
+                write_flows(&db_pool, &mut flows, pseudonymizer.as_deref(), &stats, &log).await;
+            }
+            message = chan.recv() => {
+                match message {
+                    Some(Message::Flows(batch)) => {
+                        add_flows(&mut flows, batch, chrono::Utc::now());
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+
+    // Write out the partial interval before exiting.
+    write_flows(&db_pool, &mut flows, pseudonymizer.as_deref(), &stats, &log).await;
+}
+
+fn add_flows(
+    flows: &mut HashMap<FlowKey, FlowLog>,
+    batch: HashMap<FlowKey, FlowUsage>,
+    now: chrono::DateTime<chrono::Utc>,
+) {
+    for (key, usage) in batch {
+        let flow = flows.entry(key).or_insert_with(|| FlowLog {
+            first_seen: now,
+            last_seen: now,
+            usage: FlowUsage::default(),
+        });
+        flow.last_seen = now;
+        flow.usage += usage;
+    }
+}
+
+// Writes the aggregated flows with a single COPY. Flows are dropped if the
+// write fails rather than held for the next interval, since they are only
+// informational and would otherwise accumulate without bound.
+async fn write_flows(
+    db_pool: &sqlx::PgPool,
+    flows: &mut HashMap<FlowKey, FlowLog>,
+    pseudonymizer: Option<&crate::privacy::Pseudonymizer>,
+    stats: &crate::stats::Stats,
+    log: &slog::Logger,
+) {
+    if flows.is_empty() {
+        return;
+    }
+    let encoded = encode_copy_rows(flows, pseudonymizer);
+    let count = flows.len() as u64;
+    flows.clear();
+
+    let result = async {
+        let mut connection = db_pool.acquire().await?;
+        let mut copy = connection.copy_in_raw(COPY_STATEMENT).await?;
+        if let Err(e) = copy.send(encoded.into_bytes()).await {
+            copy.abort(e.to_string()).await?;
+            return Err(e);
+        }
+        copy.finish().await
+    }
+    .await;
+    match result {
+        Ok(rows) => stats.flow_records_written.add(rows),
+        Err(e) => {
+            stats.flow_record_errors.add(count);
+            slog::warn!(log, "Failed to write flow records"; "records" => count, "error" => e.to_string());
+        }
+    }
+}
+
+// Encodes the flows in the postgres COPY text format, with one tab separated
+// row per line. Subscriber addresses are replaced with pseudonyms in privacy
+// mode.
+fn encode_copy_rows(
+    flows: &HashMap<FlowKey, FlowLog>,
+    pseudonymizer: Option<&crate::privacy::Pseudonymizer>,
+) -> String {
+    let mut encoded = String::new();
+    for (key, flow) in flows {
+        let user_addr = match pseudonymizer {
+            Some(pseudonymizer) => std::net::IpAddr::V6(pseudonymizer.address(key.user_addr)),
+            None => key.user_addr,
+        };
+        writeln!(
+            encoded,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            flow.first_seen.to_rfc3339(),
+            flow.last_seen.to_rfc3339(),
+            user_addr,
+            key.remote_addr,
+            key.user_port,
+            key.remote_port,
+            key.protocol,
+            flow.usage.bytes_up,
+            flow.usage.bytes_down,
+            flow.usage.packets,
+        )
+        .expect("Writing to a string cannot fail");
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_flows() {
+        let key = FlowKey {
+            user_addr: "10.45.0.2".parse().unwrap(),
+            remote_addr: "8.8.8.8".parse().unwrap(),
+            user_port: 50000,
+            remote_port: 443,
+            protocol: 6,
+        };
+        let usage = FlowUsage {
+            bytes_up: 100,
+            bytes_down: 1000,
+            packets: 2,
+            ..FlowUsage::default()
+        };
+        let start = chrono::DateTime::parse_from_rfc3339("2026-10-17T08:00:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let later = start + chrono::Duration::seconds(90);
+
+        let mut flows = HashMap::new();
+        add_flows(&mut flows, HashMap::from([(key, usage.clone())]), start);
+        add_flows(&mut flows, HashMap::from([(key, usage)]), later);
+        let flow = &flows[&key];
+        assert_eq!(flow.first_seen, start);
+        assert_eq!(flow.last_seen, later);
+        assert_eq!(flow.usage.bytes_up, 200);
+        assert_eq!(flow.usage.bytes_down, 2000);
+        assert_eq!(flow.usage.packets, 4);
+
+        assert_eq!(
+            encode_copy_rows(&flows, None),
+            "2026-10-17T08:00:00+00:00\t2026-10-17T08:01:30+00:00\t10.45.0.2\t8.8.8.8\t50000\t443\t6\t200\t2000\t4\n"
+        );
+    }
+}
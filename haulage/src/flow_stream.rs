@@ -0,0 +1,233 @@
+use std::hash::{Hash, Hasher};
+
+use futures_util::StreamExt;
+use thiserror::Error;
+
+use crate::clickhouse::{FlowKey, FlowUsage};
+use crate::grpc::ResponseStream;
+
+// The messages and server generated from proto/flows.proto.
+mod proto {
+    tonic::include_proto!("haulage.flows.v1");
+}
+
+#[derive(Error, Debug)]
+pub enum FlowStreamError {
+    #[error("gRPC transport failed: {0}")]
+    TransportError(#[from] tonic::transport::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub listen: std::net::SocketAddr,
+    // Whether subscribers must present an API key, of any role, as a bearer
+    // token in their call's authorization metadata.
+    pub require_api_key: bool,
+}
+
+// Batches held for each subscriber before the oldest are skipped, so slow
+// subscribers miss flows rather than holding up the capture.
+const SUBSCRIBER_BACKLOG: usize = 1024;
+
+// The flows of one batch of packets, aggregated as for the flow exporter.
+#[derive(Debug)]
+pub struct FlowBatch {
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub flows: Vec<(FlowKey, FlowUsage)>,
+}
+
+// Streams flow events to external consumers over gRPC as packets are handled,
+// so tools such as intrusion detection can share haulage's capture rather
+// than sniffing the same interface again. Nothing is stored, so consumers
+// only see flows while subscribed.
+#[derive(Debug)]
+pub struct FlowStream {
+    dispatch_channel: tokio::sync::broadcast::Sender<std::sync::Arc<FlowBatch>>,
+}
+impl FlowStream {
+    pub fn new(
+        settings: Settings,
+        db_pool: std::sync::Arc<sqlx::PgPool>,
+        pseudonymizer: Option<std::sync::Arc<crate::privacy::Pseudonymizer>>,
+        log: slog::Logger,
+    ) -> FlowStream {
+        let (sender, _) = tokio::sync::broadcast::channel(SUBSCRIBER_BACKLOG);
+        let context = Context {
+            batches: sender.clone(),
+            require_api_key: settings.require_api_key,
+            db_pool,
+            pseudonymizer,
+        };
+        tokio::task::spawn(async move {
+            serve(settings.listen, context, log.clone())
+                .await
+                .unwrap_or_else(
+                    |e| slog::error!(log, "Flow stream failed"; "error" => e.to_string()),
+                );
+        });
+        FlowStream {
+            dispatch_channel: sender,
+        }
+    }
+    pub fn clone_input_channel(&self) -> tokio::sync::broadcast::Sender<std::sync::Arc<FlowBatch>> {
+        self.dispatch_channel.clone()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Context {
+    batches: tokio::sync::broadcast::Sender<std::sync::Arc<FlowBatch>>,
+    require_api_key: bool,
+    db_pool: std::sync::Arc<sqlx::PgPool>,
+    pseudonymizer: Option<std::sync::Arc<crate::privacy::Pseudonymizer>>,
+}
+
+// What a consumer asked to receive.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Subscription {
+    sample_rate: u64,
+    user_addrs: Vec<String>,
+}
+impl From<proto::SubscribeRequest> for Subscription {
+    fn from(request: proto::SubscribeRequest) -> Self {
+        Subscription {
+            sample_rate: request.sample_rate as u64,
+            user_addrs: request.user_addr,
+        }
+    }
+}
+impl Subscription {
+    fn samples(&self, key: &FlowKey) -> bool {
+        if self.sample_rate <= 1 {
+            return true;
+        }
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish().is_multiple_of(self.sample_rate)
+    }
+
+    fn includes(&self, user_addr: &str) -> bool {
+        self.user_addrs.is_empty() || self.user_addrs.iter().any(|addr| addr == user_addr)
+    }
+}
+
+fn flow_event(
+    time: chrono::DateTime<chrono::Utc>,
+    user_addr: String,
+    key: &FlowKey,
+    usage: &FlowUsage,
+) -> proto::FlowEvent {
+    proto::FlowEvent {
+        time_ms: time.timestamp_millis(),
+        user_addr,
+        remote_addr: key.remote_addr.to_string(),
+        user_port: key.user_port as u32,
+        remote_port: key.remote_port as u32,
+        protocol: key.protocol as u32,
+        bytes_up: usage.bytes_up,
+        bytes_down: usage.bytes_down,
+        packets: usage.packets,
+        fin_up: usage.fin_up,
+        fin_down: usage.fin_down,
+        reset: usage.reset,
+    }
+}
+
+// Serves gRPC over cleartext HTTP/2, which clients reach with an insecure
+// channel, e.g. `grpcurl -plaintext`.
+async fn serve(
+    listen: std::net::SocketAddr,
+    context: Context,
+    log: slog::Logger,
+) -> Result<(), FlowStreamError> {
+    slog::info!(log, "Listening for flow stream subscribers"; "address" => listen.to_string());
+    tonic::transport::Server::builder()
+        .add_service(proto::flow_stream_server::FlowStreamServer::new(
+            FlowStreamService { context, log },
+        ))
+        .serve(listen)
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug)]
+struct FlowStreamService {
+    context: Context,
+    log: slog::Logger,
+}
+
+#[tonic::async_trait]
+impl proto::flow_stream_server::FlowStream for FlowStreamService {
+    type SubscribeStream = ResponseStream<proto::FlowEvent>;
+
+    async fn subscribe(
+        &self,
+        request: tonic::Request<proto::SubscribeRequest>,
+    ) -> Result<tonic::Response<Self::SubscribeStream>, tonic::Status> {
+        let context = &self.context;
+        if context.require_api_key {
+            match crate::grpc::authenticate(request.metadata(), &context.db_pool, &self.log).await {
+                Some(api_key) => {
+                    slog::info!(self.log, "Flow stream subscribed"; "key" => api_key.name)
+                }
+                None => {
+                    return Err(tonic::Status::unauthenticated(
+                        "A valid API key is required",
+                    ))
+                }
+            }
+        }
+        let subscription = Subscription::from(request.into_inner());
+
+        let pseudonymizer = context.pseudonymizer.clone();
+        let events = crate::grpc::broadcast_stream(context.batches.subscribe(), self.log.clone())
+            .flat_map(move |batch| {
+                let events: Vec<_> = batch
+                    .flows
+                    .iter()
+                    .filter(|(key, _)| subscription.samples(key))
+                    .filter_map(|(key, usage)| {
+                        let user_addr = match &pseudonymizer {
+                            Some(pseudonymizer) => pseudonymizer.address(key.user_addr).to_string(),
+                            None => key.user_addr.to_string(),
+                        };
+                        subscription
+                            .includes(&user_addr)
+                            .then(|| flow_event(batch.time, user_addr, key, usage))
+                    })
+                    .collect();
+                futures_util::stream::iter(events)
+            })
+            .map(Ok);
+        Ok(tonic::Response::new(Box::pin(events)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscription_sampling() {
+        let subscription = Subscription::from(proto::SubscribeRequest {
+            sample_rate: 4,
+            user_addr: vec![String::from("10.45.0.2")],
+        });
+        assert!(subscription.includes("10.45.0.2"));
+        assert!(!subscription.includes("10.45.0.3"));
+        // Roughly one in four flows are sampled, and always the same flows.
+        let key = |port| FlowKey {
+            user_addr: "10.45.0.2".parse().unwrap(),
+            remote_addr: "192.0.2.1".parse().unwrap(),
+            user_port: port,
+            remote_port: 443,
+            protocol: 6,
+        };
+        let sampled = (0..1000)
+            .filter(|port| subscription.samples(&key(*port)))
+            .count();
+        assert!((150..350).contains(&sampled), "sampled {}", sampled);
+        assert_eq!(subscription.samples(&key(7)), subscription.samples(&key(7)));
+        assert!(Subscription::default().samples(&key(7)));
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ForecastError {
+    #[error("Database operation failed: {0}")]
+    DatabaseError(#[from] sqlx::error::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub interval: std::time::Duration,
+    // How many days of usage history each forecast is based on.
+    pub days: u32,
+    // The weight of each day's usage against the average of the days before
+    // it, between 0 and 1, with higher values following recent usage closer.
+    pub smoothing: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Forecast {
+    pub imsi: String,
+    pub computed_at: chrono::DateTime<chrono::Utc>,
+    // The smoothed usage per day the forecast expects to continue.
+    pub daily_bytes: i64,
+    // How many days the balance lasts at the expected usage, or None without
+    // any usage in the history.
+    pub days_remaining: Option<f64>,
+}
+
+// Periodically forecasts how long each subscriber's balance will last, from an
+// exponentially weighted moving average of their daily usage, and stores the
+// forecasts in the subscriber_forecasts table for portals to show.
+pub async fn run(settings: Settings, db_pool: std::sync::Arc<sqlx::PgPool>, log: slog::Logger) {
+    let mut timer = tokio::time::interval(settings.interval);
+    loop {
+        timer.tick().await;
+        match update_forecasts(&settings, &db_pool).await {
+            Ok(count) => slog::debug!(log, "Updated usage forecasts"; "subscribers" => count),
+            Err(e) => {
+                slog::warn!(log, "Failed to update usage forecasts"; "error" => e.to_string())
+            }
+        }
+    }
+}
+
+async fn update_forecasts(
+    settings: &Settings,
+    db_pool: &sqlx::PgPool,
+) -> Result<usize, ForecastError> {
+    // Days are counted back from now rather than by calendar date, so that
+    // the most recent day is complete.
+    let history_query = r#"
+        SELECT "subscriber", FLOOR(EXTRACT(EPOCH FROM (now() - "start_time")) / 86400)::INT AS "days_ago", SUM("ran_bytes_up" + "ran_bytes_down")::BIGINT
+        FROM subscriber_usage
+        WHERE "start_time" > now() - make_interval(days => $1)
+        GROUP BY "subscriber", "days_ago"
+    "#;
+    let rows: Vec<(i32, i32, i64)> = sqlx::query_as(history_query)
+        .bind(settings.days as i32)
+        .fetch_all(db_pool)
+        .await?;
+
+    let mut daily_usage: HashMap<i32, Vec<i64>> = HashMap::new();
+    for (subscriber, days_ago, bytes) in rows {
+        let days = daily_usage
+            .entry(subscriber)
+            .or_insert_with(|| vec![0; settings.days as usize]);
+        // Oldest first, for smoothing in order.
+        let index = usize::try_from(days_ago)
+            .ok()
+            .and_then(|days_ago| days.len().checked_sub(days_ago + 1));
+        if let Some(day) = index.and_then(|index| days.get_mut(index)) {
+            *day += bytes;
+        }
+    }
+    let (subscribers, daily_bytes): (Vec<i32>, Vec<i64>) = daily_usage
+        .iter()
+        .map(|(subscriber, days)| (*subscriber, ewma(days, settings.smoothing).round() as i64))
+        .unzip();
+
+    // Subscribers drawing from a pool are forecast against the pool balance.
+    let upsert_query = r#"
+        INSERT INTO subscriber_forecasts("subscriber", "computed_at", "daily_bytes", "days_remaining")
+        SELECT forecasts.subscriber, now(), forecasts.daily_bytes,
+            CASE WHEN forecasts.daily_bytes > 0 THEN COALESCE(balance_pools."data_balance", subscribers."data_balance")::DOUBLE PRECISION / forecasts.daily_bytes END
+        FROM UNNEST($1::INT[], $2::BIGINT[]) AS forecasts(subscriber, daily_bytes)
+        INNER JOIN subscribers ON subscribers."internal_uid" = forecasts.subscriber
+        LEFT JOIN balance_pools ON balance_pools."id" = subscribers."balance_pool"
+        ON CONFLICT ("subscriber") DO UPDATE
+        SET "computed_at" = EXCLUDED."computed_at", "daily_bytes" = EXCLUDED."daily_bytes", "days_remaining" = EXCLUDED."days_remaining"
+    "#;
+    let count = subscribers.len();
+    let mut transaction = db_pool.begin().await?;
+    sqlx::query(upsert_query)
+        .bind(subscribers)
+        .bind(daily_bytes)
+        .execute(&mut transaction)
+        .await?;
+    // Subscribers without usage in the history no longer have a forecast.
+    // The time is fixed within the transaction, so only the forecasts just
+    // written are kept.
+    sqlx::query(r#"DELETE FROM subscriber_forecasts WHERE "computed_at" < now()"#)
+        .execute(&mut transaction)
+        .await?;
+    transaction.commit().await?;
+    Ok(count)
+}
+
+pub async fn query_forecast(
+    db_pool: &sqlx::PgPool,
+    imsi: &str,
+) -> Result<Option<Forecast>, ForecastError> {
+    let forecast_query = r#"
+        SELECT subscribers."imsi", subscriber_forecasts."computed_at", subscriber_forecasts."daily_bytes", subscriber_forecasts."days_remaining"
+        FROM subscriber_forecasts
+        INNER JOIN subscribers ON subscribers."internal_uid" = subscriber_forecasts."subscriber"
+        WHERE subscribers."imsi" = $1
+    "#;
+    let row: Option<(String, chrono::DateTime<chrono::Utc>, i64, Option<f64>)> =
+        sqlx::query_as(forecast_query)
+            .bind(imsi)
+            .fetch_optional(db_pool)
+            .await?;
+    Ok(row.map(
+        |(imsi, computed_at, daily_bytes, days_remaining)| Forecast {
+            imsi,
+            computed_at,
+            daily_bytes,
+            days_remaining,
+        },
+    ))
+}
+
+// Smooths the daily usage, oldest first, starting from the first day.
+fn ewma(daily: &[i64], smoothing: f64) -> f64 {
+    let mut days = daily.iter().map(|bytes| *bytes as f64);
+    let first = days.next().unwrap_or(0.0);
+    days.fold(first, |average, bytes| {
+        smoothing * bytes + (1.0 - smoothing) * average
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ewma() {
+        assert_eq!(ewma(&[], 0.5), 0.0);
+        assert_eq!(ewma(&[100, 100, 100], 0.3), 100.0);
+        assert_eq!(ewma(&[0, 100, 200], 0.5), 125.0);
+        // Full smoothing only follows the most recent day.
+        assert_eq!(ewma(&[500, 0, 40], 1.0), 40.0);
+    }
+}
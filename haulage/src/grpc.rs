@@ -0,0 +1,47 @@
+// The pieces of gRPC shared by haulage's services.
+
+// Looks up the API key presented as a bearer token in a call's authorization
+// metadata, or returns None if there is no valid key.
+pub async fn authenticate(
+    metadata: &tonic::metadata::MetadataMap,
+    db_pool: &sqlx::PgPool,
+    log: &slog::Logger,
+) -> Option<crate::api_keys::ApiKey> {
+    let key = metadata
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))?;
+    crate::api_keys::authenticate(db_pool, key)
+        .await
+        .unwrap_or_else(|e| {
+            slog::warn!(log, "Failed to authenticate gRPC caller"; "error" => e.to_string());
+            None
+        })
+}
+
+// A stream of responses to a server streaming call.
+pub type ResponseStream<T> =
+    std::pin::Pin<Box<dyn futures_util::Stream<Item = Result<T, tonic::Status>> + Send>>;
+
+// Receives from a broadcast channel until it closes, skipping past messages
+// missed by falling behind. The caller cancelling drops the stream, and with
+// it the receiver.
+pub fn broadcast_stream<T: Clone + Send + 'static>(
+    receiver: tokio::sync::broadcast::Receiver<T>,
+    log: slog::Logger,
+) -> impl futures_util::Stream<Item = T> + Send {
+    futures_util::stream::unfold(receiver, move |mut receiver| {
+        let log = log.clone();
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(message) => return Some((message, receiver)),
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                        slog::debug!(log, "gRPC stream fell behind"; "skipped" => skipped);
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }
+    })
+}
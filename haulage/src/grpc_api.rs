@@ -0,0 +1,344 @@
+use futures_util::StreamExt;
+use thiserror::Error;
+
+use crate::admin::{AdminError, SubscriberState};
+use crate::grpc::ResponseStream;
+
+// The messages and server generated from proto/control.proto.
+mod proto {
+    tonic::include_proto!("haulage.control.v1");
+}
+
+#[derive(Error, Debug)]
+pub enum GrpcApiError {
+    #[error("gRPC transport failed: {0}")]
+    TransportError(#[from] tonic::transport::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub listen: std::net::SocketAddr,
+    // Whether callers must present an API key with the role each method
+    // requires, as a bearer token in their call's authorization metadata.
+    pub require_api_key: bool,
+}
+
+// Handles to the live subsystems that calls are answered from.
+#[derive(Debug, Clone)]
+pub struct Context {
+    pub db_pool: std::sync::Arc<sqlx::PgPool>,
+    pub user_aggregator: tokio::sync::mpsc::Sender<crate::async_aggregator::Message>,
+    pub require_api_key: bool,
+}
+
+#[derive(Debug)]
+struct Control {
+    context: Context,
+    log: slog::Logger,
+}
+
+impl From<&SubscriberState> for proto::Subscriber {
+    fn from(state: &SubscriberState) -> Self {
+        proto::Subscriber {
+            subscriber_id: state.subscriber_id,
+            imsi: state.imsi.clone(),
+            data_balance: state.data_balance,
+            balance_pool: state.balance_pool.unwrap_or(0),
+            policy: state.policy.clone(),
+            zero_balance_policy: state.zero_balance_policy.clone(),
+            current_policy: state.current_policy.clone(),
+            suspended: state.suspended,
+            exempt_until_ms: state
+                .exempt_until
+                .map_or(0, |until| until.timestamp_millis()),
+            addresses: state
+                .addresses
+                .iter()
+                .map(|address| address.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl From<&crate::async_aggregator::UsageUpdate> for proto::UsageEvent {
+    fn from(update: &crate::async_aggregator::UsageUpdate) -> Self {
+        proto::UsageEvent {
+            time_ms: update.time.timestamp_millis(),
+            user_addr: update.id.to_string(),
+            ran_bytes_up: update.amount.ran_bytes_up,
+            ran_bytes_down: update.amount.ran_bytes_down,
+            wan_bytes_up: update.amount.wan_bytes_up,
+            wan_bytes_down: update.amount.wan_bytes_down,
+        }
+    }
+}
+
+// Serves the control API as gRPC over cleartext HTTP/2, which clients reach
+// with an insecure channel, e.g. `grpcurl -plaintext`.
+pub async fn serve(
+    listen: std::net::SocketAddr,
+    context: Context,
+    log: slog::Logger,
+) -> Result<(), GrpcApiError> {
+    slog::info!(log, "Listening for gRPC API calls"; "address" => listen.to_string());
+    tonic::transport::Server::builder()
+        .add_service(proto::control_server::ControlServer::new(Control {
+            context,
+            log,
+        }))
+        .serve(listen)
+        .await?;
+    Ok(())
+}
+
+impl Control {
+    // Checks the caller's API key permits the role, returning the name changes
+    // are attributed to.
+    async fn authorize<T>(
+        &self,
+        request: &tonic::Request<T>,
+        required: crate::api_keys::Role,
+    ) -> Result<String, tonic::Status> {
+        // Without required keys, access is governed by who can reach the
+        // listen address.
+        if !self.context.require_api_key {
+            return Ok(String::from("grpc_api"));
+        }
+        match crate::grpc::authenticate(request.metadata(), &self.context.db_pool, &self.log).await
+        {
+            Some(api_key) if api_key.role.permits(required) => Ok(api_key.name),
+            Some(api_key) => {
+                slog::warn!(self.log, "Rejected unauthorized gRPC API call"; "key" => &api_key.name, "required_role" => required.as_str());
+                Err(tonic::Status::permission_denied(format!(
+                    "The {} role is required",
+                    required.as_str()
+                )))
+            }
+            None => Err(tonic::Status::unauthenticated(
+                "A valid API key is required",
+            )),
+        }
+    }
+
+    fn status(&self, method: &str, e: AdminError) -> tonic::Status {
+        match e {
+            AdminError::UnknownSubscriberId(_) | AdminError::UnknownSubscriber(_) => {
+                tonic::Status::not_found(e.to_string())
+            }
+            AdminError::UnknownPolicy(_) => tonic::Status::invalid_argument(e.to_string()),
+            _ => {
+                slog::warn!(self.log, "gRPC API call failed"; "method" => method, "error" => e.to_string());
+                tonic::Status::internal("Internal error")
+            }
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl proto::control_server::Control for Control {
+    async fn get_subscriber(
+        &self,
+        request: tonic::Request<proto::GetSubscriberRequest>,
+    ) -> Result<tonic::Response<proto::Subscriber>, tonic::Status> {
+        self.authorize(&request, crate::api_keys::Role::ReadOnly)
+            .await?;
+        let state =
+            crate::admin::query_subscriber(&self.context.db_pool, request.get_ref().subscriber_id)
+                .await
+                .map_err(|e| self.status("GetSubscriber", e))?;
+        Ok(tonic::Response::new((&state).into()))
+    }
+
+    async fn list_subscribers(
+        &self,
+        request: tonic::Request<proto::ListSubscribersRequest>,
+    ) -> Result<tonic::Response<proto::ListSubscribersResponse>, tonic::Status> {
+        self.authorize(&request, crate::api_keys::Role::ReadOnly)
+            .await?;
+        let states = crate::admin::query_subscribers(&self.context.db_pool, None)
+            .await
+            .map_err(|e| self.status("ListSubscribers", e))?;
+        Ok(tonic::Response::new(proto::ListSubscribersResponse {
+            subscribers: states.iter().map(proto::Subscriber::from).collect(),
+        }))
+    }
+
+    async fn top_up(
+        &self,
+        request: tonic::Request<proto::TopUpRequest>,
+    ) -> Result<tonic::Response<proto::Subscriber>, tonic::Status> {
+        let caller = self
+            .authorize(&request, crate::api_keys::Role::Cashier)
+            .await?;
+        let request = request.into_inner();
+        let state = top_up(
+            &self.context.db_pool,
+            request.subscriber_id,
+            request.bytes,
+            &caller,
+            &self.log,
+        )
+        .await
+        .map_err(|e| self.status("TopUp", e))?;
+        Ok(tonic::Response::new((&state).into()))
+    }
+
+    async fn set_policy(
+        &self,
+        request: tonic::Request<proto::SetPolicyRequest>,
+    ) -> Result<tonic::Response<proto::Subscriber>, tonic::Status> {
+        let caller = self
+            .authorize(&request, crate::api_keys::Role::Admin)
+            .await?;
+        let request = request.into_inner();
+        let plan = plan(&request)
+            .ok_or_else(|| tonic::Status::invalid_argument("A policy is required"))?;
+        let state = set_policy(
+            &self.context.db_pool,
+            request.subscriber_id,
+            &plan,
+            &caller,
+            &self.log,
+        )
+        .await
+        .map_err(|e| self.status("SetPolicy", e))?;
+        Ok(tonic::Response::new((&state).into()))
+    }
+
+    type LiveUsageStream = ResponseStream<proto::UsageEvent>;
+
+    // Streams each usage report of the requested subscribers until the caller
+    // cancels. Nothing is stored, so callers only see usage while watching.
+    async fn live_usage(
+        &self,
+        request: tonic::Request<proto::LiveUsageRequest>,
+    ) -> Result<tonic::Response<Self::LiveUsageStream>, tonic::Status> {
+        self.authorize(&request, crate::api_keys::Role::ReadOnly)
+            .await?;
+        let user_addrs = user_addrs(request.get_ref()).map_err(tonic::Status::invalid_argument)?;
+
+        let (out_channel, receiver) = tokio::sync::oneshot::channel();
+        let watched = self
+            .context
+            .user_aggregator
+            .send(crate::async_aggregator::Message::WatchUsage { out_channel })
+            .await;
+        let updates = match (watched, receiver.await) {
+            (Ok(()), Ok(updates)) => updates,
+            _ => return Err(tonic::Status::unavailable("Aggregator unavailable")),
+        };
+
+        let events = crate::grpc::broadcast_stream(updates, self.log.clone())
+            .filter(move |update| {
+                let watched = user_addrs.is_empty() || user_addrs.contains(&update.id);
+                std::future::ready(watched)
+            })
+            .map(|update| proto::UsageEvent::from(&update))
+            .map(Ok);
+        Ok(tonic::Response::new(Box::pin(events)))
+    }
+}
+
+// A policy is required, and an empty zero balance policy is kept.
+fn plan(request: &proto::SetPolicyRequest) -> Option<crate::admin::Plan> {
+    if request.policy.is_empty() {
+        return None;
+    }
+    Some(crate::admin::Plan {
+        policy: request.policy.clone(),
+        zero_balance_policy: Some(request.zero_balance_policy.clone())
+            .filter(|name| !name.is_empty()),
+    })
+}
+
+fn user_addrs(request: &proto::LiveUsageRequest) -> Result<Vec<std::net::IpAddr>, String> {
+    request
+        .user_addr
+        .iter()
+        .map(|addr| {
+            addr.parse()
+                .map_err(|_| format!("Invalid address {}", addr))
+        })
+        .collect()
+}
+
+async fn top_up(
+    db_pool: &sqlx::PgPool,
+    subscriber_id: i32,
+    bytes: i64,
+    caller: &str,
+    log: &slog::Logger,
+) -> Result<SubscriberState, AdminError> {
+    let imsi = crate::admin::query_imsi(db_pool, subscriber_id).await?;
+    crate::admin::top_up_subscriber(db_pool, &imsi, bytes, caller, log).await?;
+    crate::admin::query_subscriber(db_pool, subscriber_id).await
+}
+
+async fn set_policy(
+    db_pool: &sqlx::PgPool,
+    subscriber_id: i32,
+    plan: &crate::admin::Plan,
+    caller: &str,
+    log: &slog::Logger,
+) -> Result<SubscriberState, AdminError> {
+    let imsi = crate::admin::query_imsi(db_pool, subscriber_id).await?;
+    // Without rules the balance is kept, as for `haulage admin set-policy`.
+    crate::admin::change_subscriber_plan(db_pool, &imsi, plan, &[], caller, log).await?;
+    crate::admin::query_subscriber(db_pool, subscriber_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_requests_and_encode_subscriber() {
+        let request = proto::SetPolicyRequest {
+            subscriber_id: 7,
+            policy: String::from("Unlimited"),
+            zero_balance_policy: String::new(),
+        };
+        let requested = plan(&request).unwrap();
+        assert_eq!(requested.policy, "Unlimited");
+        assert_eq!(requested.zero_balance_policy, None);
+        let request = proto::SetPolicyRequest {
+            policy: String::new(),
+            ..request
+        };
+        assert!(plan(&request).is_none());
+
+        let mut request = proto::LiveUsageRequest {
+            user_addr: vec![String::from("10.45.0.2")],
+        };
+        assert_eq!(
+            user_addrs(&request).unwrap(),
+            vec!["10.45.0.2".parse::<std::net::IpAddr>().unwrap()]
+        );
+        request.user_addr.push(String::from("not an address"));
+        assert!(user_addrs(&request).is_err());
+
+        // A zero balance is still encoded, unlike an absent balance.
+        let mut state = SubscriberState {
+            subscriber_id: 1,
+            imsi: String::from("001010000000001"),
+            data_balance: Some(0),
+            balance_pool: None,
+            policy: String::from("Unlimited"),
+            zero_balance_policy: String::from("Local Only"),
+            current_policy: String::from("Local Only"),
+            suspended: false,
+            exempt_until: None,
+            addresses: vec!["10.45.0.2/32".parse().unwrap()],
+        };
+        let encoded = |state: &SubscriberState| {
+            prost::Message::encode_to_vec(&proto::Subscriber::from(state))
+        };
+        assert!(encoded(&state).windows(2).any(|field| field == [3 << 3, 0]));
+        assert_eq!(
+            proto::Subscriber::from(&state).addresses,
+            vec![String::from("10.45.0.2/32")]
+        );
+        state.data_balance = None;
+        assert!(!encoded(&state).windows(2).any(|field| field == [3 << 3, 0]));
+    }
+}
@@ -0,0 +1,170 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum GuaranteeError {
+    #[error("Database operation failed: {0}")]
+    DatabaseError(#[from] sqlx::error::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // The daily busy hours in local time, e.g. "18:00-23:00". Busy hours
+    // spanning midnight belong to the day they start on.
+    pub busy_hours: crate::clock::DailyHours,
+    // The site's time zone the busy hours are in, or the system's local time
+    // if not configured.
+    pub timezone: Option<crate::clock::SiteTimezone>,
+    // The number of recent days considered when flagging underdelivery.
+    pub days: u32,
+    // The number of those days the guarantee must be missed on to flag it.
+    pub chronic_days: u32,
+    // The downlink traffic a subscriber must use during the busy hours for
+    // the day to be checked, since idle subscribers never reach their rate.
+    pub min_active_bytes: u64,
+    // How long after the busy hours end to wait for usage to be recorded.
+    pub settle: std::time::Duration,
+    pub interval: std::time::Duration,
+}
+
+fn to_utc(
+    local: chrono::NaiveDateTime,
+    timezone: Option<crate::clock::SiteTimezone>,
+) -> chrono::DateTime<chrono::Utc> {
+    use chrono::TimeZone;
+    if let Some(timezone) = timezone {
+        return timezone.to_utc(local);
+    }
+    // Times skipped by a daylight saving transition are taken as UTC rather
+    // than failing the whole day.
+    chrono::Local
+        .from_local_datetime(&local)
+        .earliest()
+        .map(|time| time.with_timezone(&chrono::Utc))
+        .unwrap_or_else(|| chrono::Utc.from_utc_datetime(&local))
+}
+
+// Checks once per completed day whether each active subscriber on a plan with
+// a guaranteed rate achieved it at some point during the busy hours, and flags
+// subscribers who missed it on too many recent days. Achieved throughput is
+// averaged over each usage record, so short bursts above the guarantee within
+// a record are not credited.
+pub async fn verify(settings: Settings, db_pool: std::sync::Arc<sqlx::PgPool>, log: slog::Logger) {
+    let settle =
+        chrono::Duration::from_std(settings.settle).unwrap_or_else(|_| chrono::Duration::zero());
+    let mut last_checked = None;
+    let mut timer = tokio::time::interval(settings.interval);
+    loop {
+        timer.tick().await;
+        let day = settings
+            .busy_hours
+            .last_completed_day(crate::clock::local_now(settings.timezone) - settle);
+        if last_checked == Some(day) {
+            continue;
+        }
+
+        match check_day(&db_pool, &settings, day).await {
+            Ok(underdelivered) => {
+                for (subscriber, unmet_days) in underdelivered {
+                    slog::warn!(log, "Subscriber chronically below guaranteed rate"; "subscriber" => subscriber, "unmet_days" => unmet_days, "days" => settings.days);
+                }
+                slog::debug!(log, "Checked bandwidth guarantees"; "day" => day.to_string());
+                last_checked = Some(day);
+            }
+            Err(e) => {
+                slog::error!(log, "Failed to check bandwidth guarantees"; "day" => day.to_string(), "error" => e.to_string())
+            }
+        }
+    }
+}
+
+// Whether the guarantee was missed on enough of the recent days to flag it.
+fn is_chronic(unmet_days: i64, chronic_days: u32) -> bool {
+    unmet_days >= chronic_days as i64
+}
+
+// Records the checks for the busy hours of one day, returning the subscribers
+// newly found chronically underdelivered along with their unmet days. Days
+// already checked, e.g. before a restart, are left as they are.
+async fn check_day(
+    db_pool: &sqlx::PgPool,
+    settings: &Settings,
+    day: chrono::NaiveDate,
+) -> Result<Vec<(i32, i64)>, GuaranteeError> {
+    let (start, end) = settings.busy_hours.window(day);
+    let mut transaction = db_pool.begin().await?;
+
+    // Subscribers are held to the guarantee of the policy currently applied
+    // to them, so that suspended, zero balance and deprioritized subscribers
+    // are not held to the guarantee of their plan.
+    let check_query = r#"
+        INSERT INTO guarantee_checks("subscriber", "day", "window_start", "window_end", "guaranteed_kibps", "peak_kibps", "busy_bytes", "met")
+        SELECT "subscriber", $3, $1, $2, "guaranteed_kibps", "peak_kibps", "busy_bytes", "peak_kibps" >= "guaranteed_kibps"
+        FROM (
+            SELECT subscriber_usage."subscriber", access_policies."guaranteed_kibps",
+                COALESCE(MAX("wan_bytes_down" * 8 / 1024 / NULLIF(EXTRACT(EPOCH FROM "end_time" - "start_time"), 0)), 0)::INT AS "peak_kibps",
+                SUM("wan_bytes_down")::BIGINT AS "busy_bytes"
+            FROM subscriber_usage
+            INNER JOIN subscribers ON subscriber_usage."subscriber" = subscribers."internal_uid"
+            INNER JOIN access_policies ON subscribers."current_policy" = access_policies."id"
+            WHERE access_policies."guaranteed_kibps" IS NOT NULL AND "start_time" >= $1 AND "end_time" <= $2
+            GROUP BY subscriber_usage."subscriber", access_policies."guaranteed_kibps"
+            HAVING SUM("wan_bytes_down") >= $4
+        ) AS busy_usage
+        ON CONFLICT DO NOTHING
+        RETURNING "subscriber", "met"
+    "#;
+    let checked: Vec<(i32, bool)> = sqlx::query_as(check_query)
+        .bind(to_utc(start, settings.timezone))
+        .bind(to_utc(end, settings.timezone))
+        .bind(day)
+        .bind(settings.min_active_bytes as i64)
+        .fetch_all(&mut transaction)
+        .await?;
+
+    let unmet_query = r#"
+        SELECT COUNT(*) FROM guarantee_checks
+        WHERE "subscriber" = $1 AND NOT "met" AND "day" > $2 AND "day" <= $3
+    "#;
+    let first_day = day - chrono::Duration::days(settings.days as i64);
+    let mut underdelivered = Vec::new();
+    for (subscriber, met) in checked {
+        if met {
+            continue;
+        }
+        let (unmet_days,): (i64,) = sqlx::query_as(unmet_query)
+            .bind(subscriber)
+            .bind(first_day)
+            .bind(day)
+            .fetch_one(&mut transaction)
+            .await?;
+        if !is_chronic(unmet_days, settings.chronic_days) {
+            continue;
+        }
+        crate::events::record_event(
+            &mut transaction,
+            subscriber,
+            crate::events::EventKind::Underdelivered,
+            serde_json::json!({
+                "day": day,
+                "unmet_days": unmet_days,
+                "days": settings.days,
+            }),
+        )
+        .await?;
+        underdelivered.push((subscriber, unmet_days));
+    }
+
+    transaction.commit().await?;
+    Ok(underdelivered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_chronic() {
+        assert!(!is_chronic(3, 4));
+        assert!(is_chronic(4, 4));
+    }
+}
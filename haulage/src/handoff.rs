@@ -0,0 +1,190 @@
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+
+use thiserror::Error;
+
+// Bumped whenever the handed off state changes incompatibly, so that a new
+// version starts cold rather than misreading the state of an old one.
+const FORMAT_VERSION: u32 = 3;
+
+#[derive(Error, Debug)]
+pub enum HandoffError {
+    #[error("Failed to access the handoff state: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Failed to parse the handoff state: {0}")]
+    SerdeJsonError(#[from] serde_json::Error),
+    #[error("Failed to collect state from a subsystem: {0}")]
+    EnforcementError(#[from] crate::enforcer::EnforcementError),
+    #[error("The aggregator exited before handing off its state")]
+    CommunicationError,
+    #[error("Unsupported handoff state version {0}")]
+    UnsupportedVersion(u32),
+}
+
+// The state handed from a stopping process to its replacement, e.g. across a
+// package upgrade, so that partial usage intervals are continued rather than
+// lost and enforcement is taken over rather than rebuilt.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct State {
+    version: u32,
+    written: chrono::DateTime<chrono::Utc>,
+    // Identifies the boot, after which the kernel enforcement state is gone.
+    boot_id: Option<String>,
+    pub intervals: Vec<crate::async_aggregator::PartialInterval>,
+    pub enforcement: Option<crate::enforcer::Handoff>,
+}
+
+fn read_boot_id() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/random/boot_id")
+        .ok()
+        .map(|id| id.trim().to_owned())
+}
+
+// Reads and removes the state handed off by a previous process, if any, so
+// that it is only ever resumed once. State that cannot be used is discarded
+// with a warning, since starting cold is always safe.
+pub fn take(path: &std::path::Path, log: &slog::Logger) -> Option<State> {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            slog::warn!(log, "Unable to read handoff state"; "error" => e.to_string());
+            return None;
+        }
+    };
+    if let Err(e) = std::fs::remove_file(path) {
+        slog::warn!(log, "Unable to remove handoff state, not resuming it"; "error" => e.to_string());
+        return None;
+    }
+    let mut state = match parse(&contents) {
+        Ok(state) => state,
+        Err(e) => {
+            slog::warn!(log, "Discarding unusable handoff state"; "error" => e.to_string());
+            return None;
+        }
+    };
+    if state.boot_id.is_none() || state.boot_id != read_boot_id() {
+        slog::info!(
+            log,
+            "Handoff state is from a previous boot, rebuilding enforcement"
+        );
+        state.enforcement = None;
+    }
+    slog::info!(log, "Resuming handoff state"; "written" => state.written.to_rfc3339(), "intervals" => state.intervals.len());
+    Some(state)
+}
+
+fn parse(contents: &[u8]) -> Result<State, HandoffError> {
+    // Check the version first, since other fields may not parse at all.
+    #[derive(serde::Deserialize)]
+    struct Version {
+        version: u32,
+    }
+    let version: Version = serde_json::from_slice(contents)?;
+    if version.version != FORMAT_VERSION {
+        return Err(HandoffError::UnsupportedVersion(version.version));
+    }
+    Ok(serde_json::from_slice(contents)?)
+}
+
+// Hands off the state to the next process once capture has stopped, e.g.
+// when systemd stops haulage during a package upgrade. The partial intervals
+// of subscribers are handed off, while everything else awaiting a write is
+// written out first, so the state is only written once nothing more can be
+// lost by exiting.
+pub async fn hand_off(
+    path: &std::path::Path,
+    subsystems: crate::shutdown::Subsystems,
+    log: &slog::Logger,
+) {
+    match collect(&subsystems, log).await {
+        Ok(state) => match write(path, &state) {
+            Ok(()) => {
+                slog::info!(log, "Handed off state"; "intervals" => state.intervals.len(), "path" => path.display().to_string())
+            }
+            Err(e) => slog::error!(log, "Failed to write handoff state"; "error" => e.to_string()),
+        },
+        Err(e) => slog::error!(log, "Failed to collect handoff state"; "error" => e.to_string()),
+    }
+}
+
+// Stops aggregation and enforcement and collects their state. The user
+// aggregator stops before the accounter and usage writer are flushed, so no
+// usage it reports can be written out as well as handed off.
+async fn collect(
+    subsystems: &crate::shutdown::Subsystems,
+    log: &slog::Logger,
+) -> Result<State, HandoffError> {
+    let (intervals_tx, intervals_rx) = tokio::sync::oneshot::channel();
+    subsystems
+        .user_aggregator
+        .send(crate::async_aggregator::Message::HandOff {
+            out_channel: intervals_tx,
+        })
+        .await
+        .map_err(|_| HandoffError::CommunicationError)?;
+    let intervals = intervals_rx
+        .await
+        .map_err(|_| HandoffError::CommunicationError)?;
+    crate::shutdown::write_out_usage(subsystems, log).await;
+    let enforcement = match &subsystems.enforcer {
+        Some(enforcer) => Some(enforcer.hand_off().await?),
+        None => None,
+    };
+
+    Ok(State {
+        version: FORMAT_VERSION,
+        written: chrono::Utc::now(),
+        boot_id: read_boot_id(),
+        intervals,
+        enforcement,
+    })
+}
+
+// Writes the state readable only by haulage. The state is written to a
+// temporary file first, so that a crash while writing leaves no partial state
+// to be resumed.
+fn write(path: &std::path::Path, state: &State) -> Result<(), HandoffError> {
+    let temporary_path = path.with_extension("tmp");
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temporary_path)?;
+    file.write_all(&serde_json::to_vec(state)?)?;
+    file.sync_all()?;
+    std::fs::rename(&temporary_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_round_trips_and_checks_version() {
+        let state = State {
+            version: FORMAT_VERSION,
+            written: chrono::Utc::now(),
+            boot_id: read_boot_id(),
+            intervals: vec![crate::async_aggregator::PartialInterval {
+                id: "10.45.0.2".parse().unwrap(),
+                start: chrono::Utc::now(),
+                usage: crate::NetResourceBundle {
+                    ran_bytes_down: 1500,
+                    ..crate::NetResourceBundle::zeroed()
+                },
+            }],
+            enforcement: None,
+        };
+        let serialized = serde_json::to_vec(&state).unwrap();
+        let parsed = parse(&serialized).unwrap();
+        assert_eq!(parsed.intervals, state.intervals);
+        assert_eq!(parsed.boot_id, state.boot_id);
+
+        let mut future = serde_json::to_value(&state).unwrap();
+        future["version"] = serde_json::json!(FORMAT_VERSION + 1);
+        assert!(parse(&serde_json::to_vec(&future).unwrap()).is_err());
+    }
+}
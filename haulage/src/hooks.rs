@@ -0,0 +1,195 @@
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+
+#[derive(Error, Debug)]
+pub enum HookError {
+    #[error("Failed to run hook: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Hook did not finish within {0:?}")]
+    TimedOut(std::time::Duration),
+    #[error("Hook exited unsuccessfully ({status}): {stderr}")]
+    Failed { status: String, stderr: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub hooks: Vec<Hook>,
+    // How often the subscriber event feed is checked for new events.
+    pub poll_interval: std::time::Duration,
+}
+
+// An executable run for each subscriber event of the given kinds, or for
+// every event if no kinds are given.
+#[derive(Debug, Clone)]
+pub struct Hook {
+    pub events: Vec<String>,
+    pub command: std::path::PathBuf,
+    pub args: Vec<String>,
+    pub timeout: std::time::Duration,
+}
+
+impl Hook {
+    fn handles(&self, kind: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|event| event == kind)
+    }
+}
+
+#[derive(Debug, Clone, sqlx::FromRow, serde::Serialize)]
+struct EventRow {
+    id: i64,
+    time: chrono::DateTime<chrono::Utc>,
+    subscriber: i32,
+    imsi: String,
+    kind: String,
+    details: serde_json::Value,
+}
+
+// Runs the configured hooks for each event recorded in the subscriber event
+// feed, e.g. enforcement transitions and balance thresholds, so that operators
+// can extend haulage with their own scripts. Each hook receives the event as
+// a json object on stdin, along with its main fields in the environment:
+//   HAULAGE_EVENT_ID, HAULAGE_EVENT, HAULAGE_EVENT_TIME, HAULAGE_SUBSCRIBER,
+//   HAULAGE_IMSI
+// Hooks run one at a time in the order the events were recorded. Events are
+// only delivered once, so hooks that fail or time out are not retried, and
+// events recorded while haulage is not running are not delivered at all.
+pub async fn run(
+    settings: Settings,
+    db_pool: std::sync::Arc<sqlx::PgPool>,
+    stats: std::sync::Arc<crate::stats::Stats>,
+    log: slog::Logger,
+) {
+    let mut last_event = None;
+    let mut timer = tokio::time::interval(settings.poll_interval);
+    loop {
+        timer.tick().await;
+        let after = match last_event {
+            Some(id) => id,
+            None => match latest_event_id(&db_pool).await {
+                Ok(id) => *last_event.insert(id),
+                Err(e) => {
+                    slog::warn!(log, "Failed to read the subscriber event feed"; "error" => e.to_string());
+                    continue;
+                }
+            },
+        };
+        let events = match query_events(&db_pool, after).await {
+            Ok(events) => events,
+            Err(e) => {
+                slog::warn!(log, "Failed to query subscriber events"; "error" => e.to_string());
+                continue;
+            }
+        };
+
+        for event in events {
+            last_event = Some(event.id);
+            for hook in settings
+                .hooks
+                .iter()
+                .filter(|hook| hook.handles(&event.kind))
+            {
+                stats.hook_runs.increment();
+                match run_hook(hook, &event).await {
+                    Ok(()) => {
+                        slog::debug!(log, "Ran hook"; "command" => hook.command.display().to_string(), "event" => event.id)
+                    }
+                    Err(e) => {
+                        stats.hook_failures.increment();
+                        slog::warn!(log, "Hook failed"; "command" => hook.command.display().to_string(), "event" => event.id, "kind" => &event.kind, "error" => e.to_string());
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn latest_event_id(db_pool: &sqlx::PgPool) -> Result<i64, sqlx::Error> {
+    let (id,): (Option<i64>,) = sqlx::query_as(r#"SELECT MAX("id") FROM subscriber_events"#)
+        .fetch_one(db_pool)
+        .await?;
+    Ok(id.unwrap_or(0))
+}
+
+async fn query_events(db_pool: &sqlx::PgPool, after: i64) -> Result<Vec<EventRow>, sqlx::Error> {
+    let query = r#"
+        SELECT "id", "time", "subscriber", "imsi", "kind", "details"
+        FROM subscriber_events
+        WHERE "id" > $1
+        ORDER BY "id"
+    "#;
+    sqlx::query_as(query).bind(after).fetch_all(db_pool).await
+}
+
+fn environment(event: &EventRow) -> Vec<(&'static str, String)> {
+    vec![
+        ("HAULAGE_EVENT_ID", event.id.to_string()),
+        ("HAULAGE_EVENT", event.kind.clone()),
+        ("HAULAGE_EVENT_TIME", event.time.to_rfc3339()),
+        ("HAULAGE_SUBSCRIBER", event.subscriber.to_string()),
+        ("HAULAGE_IMSI", event.imsi.clone()),
+    ]
+}
+
+async fn run_hook(hook: &Hook, event: &EventRow) -> Result<(), HookError> {
+    let input = serde_json::to_vec(event).expect("Events serialize to json");
+    let mut child = tokio::process::Command::new(&hook.command)
+        .args(&hook.args)
+        .envs(environment(event))
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::piped())
+        // Hooks that time out are killed when their future is dropped.
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let output = tokio::time::timeout(hook.timeout, async move {
+        // Hooks are free to ignore their input and exit without reading it.
+        let _ = stdin.write_all(&input).await;
+        drop(stdin);
+        child.wait_with_output().await
+    })
+    .await
+    .map_err(|_| HookError::TimedOut(hook.timeout))??;
+
+    if !output.status.success() {
+        return Err(HookError::Failed {
+            status: output.status.to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hook_contract() {
+        let event = EventRow {
+            id: 12,
+            time: chrono::Utc::now(),
+            subscriber: 7,
+            imsi: String::from("001010000000001"),
+            kind: String::from("suspended"),
+            details: serde_json::json!({"reason": "unpaid"}),
+        };
+        let hook = |script: &str| Hook {
+            events: vec![String::from("suspended")],
+            command: std::path::PathBuf::from("/bin/sh"),
+            args: vec![String::from("-c"), String::from(script)],
+            timeout: std::time::Duration::from_secs(5),
+        };
+        assert!(hook("").handles("suspended"));
+        assert!(!hook("").handles("resumed"));
+
+        // The event is given both in the environment and as json on stdin.
+        let check = r#"test "$HAULAGE_EVENT $HAULAGE_IMSI" = "suspended 001010000000001" && grep -q '"reason":"unpaid"'"#;
+        run_hook(&hook(check), &event).await.unwrap();
+        assert!(matches!(
+            run_hook(&hook("echo broken >&2; exit 3"), &event).await,
+            Err(HookError::Failed { stderr, .. }) if stderr == "broken"
+        ));
+    }
+}
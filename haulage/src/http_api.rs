@@ -0,0 +1,330 @@
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use thiserror::Error;
+
+use crate::admin::AdminError;
+
+#[derive(Error, Debug)]
+pub enum HttpApiError {
+    #[error("HTTP API server failed: {0}")]
+    ServerError(#[from] hyper::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub listen: std::net::SocketAddr,
+    // Whether callers must present an API key with the role each endpoint
+    // requires, as a bearer token in the authorization header.
+    pub require_api_key: bool,
+}
+
+// Bounds the json body of a request.
+const MAX_REQUEST_BYTES: usize = 64 * 1024;
+// How long a caller has to send the request headers, and how long a request
+// may take to be answered, so slow callers cannot hold connections open.
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+// Bounds the requests answered at once, the rest waiting their turn.
+const MAX_CONCURRENT_REQUESTS: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct Context {
+    pub db_pool: std::sync::Arc<sqlx::PgPool>,
+    pub require_api_key: bool,
+}
+
+// Credits the subscriber's balance, or their pool's, as a cashier would:
+//   POST /subscribers/{id}/balance {"bytes": 1000000}
+#[derive(Debug, serde::Deserialize)]
+struct TopUpRequest {
+    bytes: i64,
+}
+
+// Limits the usage to that recorded since a time:
+//   GET /subscribers/{id}/usage?since=2026-10-01T00:00:00Z
+#[derive(Debug, serde::Deserialize)]
+struct UsageQuery {
+    since: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Balance {
+    subscriber_id: i32,
+    imsi: String,
+    // The balance of the subscriber's pool if they draw from one.
+    data_balance: Option<i64>,
+    balance_pool: Option<i32>,
+}
+
+// The usage a subscriber's recorded intervals add up to, since a time if
+// given. Intervals still being aggregated are not yet included.
+#[derive(Debug, sqlx::FromRow, serde::Serialize)]
+struct Usage {
+    subscriber_id: i32,
+    ran_bytes_up: i64,
+    ran_bytes_down: i64,
+    wan_bytes_up: i64,
+    wan_bytes_down: i64,
+    first_interval_start: Option<chrono::DateTime<chrono::Utc>>,
+    last_interval_end: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+// The state shared by the handlers.
+#[derive(Debug, Clone)]
+struct Api {
+    context: Context,
+    log: slog::Logger,
+}
+
+// An HTTP status with the message returned in its json body.
+#[derive(Debug)]
+struct ApiError(StatusCode, String);
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = serde_json::json!({ "error": self.1 });
+        (self.0, axum::Json(body)).into_response()
+    }
+}
+
+type ApiResult = Result<axum::Json<serde_json::Value>, ApiError>;
+
+pub async fn serve(
+    listen: std::net::SocketAddr,
+    context: Context,
+    log: slog::Logger,
+) -> Result<(), HttpApiError> {
+    let server = axum::Server::try_bind(&listen)?.http1_header_read_timeout(REQUEST_TIMEOUT);
+    slog::info!(log, "Serving HTTP API"; "listen" => listen.to_string());
+    server
+        .serve(router(Api { context, log }).into_make_service())
+        .await?;
+    Ok(())
+}
+
+// The endpoints of the Go haulage, so that dashboards built against it work
+// unchanged.
+fn router(api: Api) -> axum::Router {
+    use axum::routing::get;
+    axum::Router::new()
+        .route("/subscribers", get(subscribers))
+        .route("/subscribers/", get(subscribers))
+        .route("/subscribers/:id/usage", get(usage))
+        .route("/subscribers/:id/balance", get(balance).post(top_up))
+        .fallback(|| async { ApiError(StatusCode::NOT_FOUND, String::from("Not found")) })
+        .layer(axum::extract::DefaultBodyLimit::max(MAX_REQUEST_BYTES))
+        .layer(tower::limit::GlobalConcurrencyLimitLayer::new(
+            MAX_CONCURRENT_REQUESTS,
+        ))
+        .layer(tower_http::timeout::TimeoutLayer::new(REQUEST_TIMEOUT))
+        .with_state(api)
+}
+
+async fn subscribers(State(api): State<Api>, headers: HeaderMap) -> ApiResult {
+    authorize(&api, &headers, crate::api_keys::Role::ReadOnly).await?;
+    let states = crate::admin::query_subscribers(&api.context.db_pool, None)
+        .await
+        .map_err(|e| admin_error(&api, e))?;
+    Ok(axum::Json(serde_json::json!(states)))
+}
+
+async fn usage(
+    State(api): State<Api>,
+    Path(subscriber): Path<i32>,
+    Query(query): Query<UsageQuery>,
+    headers: HeaderMap,
+) -> ApiResult {
+    let since = query
+        .since
+        .map(|since| chrono::DateTime::parse_from_rfc3339(&since))
+        .transpose()
+        .map_err(|_| {
+            ApiError(
+                StatusCode::BAD_REQUEST,
+                String::from("since must be an RFC 3339 time"),
+            )
+        })?
+        .map(|since| since.with_timezone(&chrono::Utc));
+    authorize(&api, &headers, crate::api_keys::Role::ReadOnly).await?;
+    let usage = query_usage(&api.context.db_pool, subscriber, since)
+        .await
+        .map_err(|e| admin_error(&api, e))?;
+    Ok(axum::Json(serde_json::json!(usage)))
+}
+
+async fn balance(
+    State(api): State<Api>,
+    Path(subscriber): Path<i32>,
+    headers: HeaderMap,
+) -> ApiResult {
+    authorize(&api, &headers, crate::api_keys::Role::ReadOnly).await?;
+    let state = crate::admin::query_subscriber(&api.context.db_pool, subscriber)
+        .await
+        .map_err(|e| admin_error(&api, e))?;
+    Ok(axum::Json(serde_json::json!(Balance {
+        subscriber_id: state.subscriber_id,
+        imsi: state.imsi,
+        data_balance: state.data_balance,
+        balance_pool: state.balance_pool,
+    })))
+}
+
+async fn top_up(
+    State(api): State<Api>,
+    Path(subscriber): Path<i32>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> ApiResult {
+    let caller = authorize(&api, &headers, crate::api_keys::Role::Cashier).await?;
+    let top_up: TopUpRequest = serde_json::from_slice(&body)
+        .map_err(|e| ApiError(StatusCode::BAD_REQUEST, format!("Invalid request: {}", e)))?;
+    let state = top_up_subscriber(
+        &api.context.db_pool,
+        subscriber,
+        top_up.bytes,
+        &caller,
+        &api.log,
+    )
+    .await
+    .map_err(|e| admin_error(&api, e))?;
+    Ok(axum::Json(serde_json::json!(state)))
+}
+
+// Checks the caller's API key permits the role, returning the name changes
+// are attributed to.
+async fn authorize(
+    api: &Api,
+    headers: &HeaderMap,
+    required: crate::api_keys::Role,
+) -> Result<String, ApiError> {
+    // Without required keys, access is governed by who can reach the listen
+    // address.
+    if !api.context.require_api_key {
+        return Ok(String::from("http_api"));
+    }
+    let unauthorized = || {
+        ApiError(
+            StatusCode::UNAUTHORIZED,
+            String::from("A valid API key is required"),
+        )
+    };
+    let key = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(unauthorized)?;
+    let api_key = crate::api_keys::authenticate(&api.context.db_pool, key)
+        .await
+        .unwrap_or_else(|e| {
+            slog::warn!(api.log, "Failed to authenticate HTTP API caller"; "error" => e.to_string());
+            None
+        })
+        .ok_or_else(unauthorized)?;
+    if !api_key.role.permits(required) {
+        slog::warn!(api.log, "Rejected unauthorized HTTP API request"; "key" => &api_key.name, "required_role" => required.as_str());
+        return Err(ApiError(
+            StatusCode::FORBIDDEN,
+            format!("The {} role is required", required.as_str()),
+        ));
+    }
+    Ok(api_key.name)
+}
+
+fn admin_error(api: &Api, e: AdminError) -> ApiError {
+    match e {
+        AdminError::UnknownSubscriberId(_) => ApiError(StatusCode::NOT_FOUND, e.to_string()),
+        _ => {
+            slog::warn!(api.log, "HTTP API request failed"; "error" => e.to_string());
+            ApiError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                String::from("Internal error"),
+            )
+        }
+    }
+}
+
+async fn top_up_subscriber(
+    db_pool: &sqlx::PgPool,
+    subscriber: i32,
+    bytes: i64,
+    caller: &str,
+    log: &slog::Logger,
+) -> Result<crate::admin::TopUpState, AdminError> {
+    let imsi = crate::admin::query_imsi(db_pool, subscriber).await?;
+    crate::admin::top_up_subscriber(db_pool, &imsi, bytes, caller, log).await
+}
+
+async fn query_usage(
+    db_pool: &sqlx::PgPool,
+    subscriber: i32,
+    since: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<Usage, AdminError> {
+    // Distinguishes unknown subscribers from those without usage.
+    crate::admin::query_imsi(db_pool, subscriber).await?;
+    let usage_query = r#"
+        SELECT
+            $1 AS "subscriber_id",
+            COALESCE(SUM("ran_bytes_up"), 0)::bigint AS "ran_bytes_up",
+            COALESCE(SUM("ran_bytes_down"), 0)::bigint AS "ran_bytes_down",
+            COALESCE(SUM("wan_bytes_up"), 0)::bigint AS "wan_bytes_up",
+            COALESCE(SUM("wan_bytes_down"), 0)::bigint AS "wan_bytes_down",
+            MIN("start_time") AS "first_interval_start",
+            MAX("end_time") AS "last_interval_end"
+        FROM subscriber_usage
+        WHERE "subscriber" = $1 AND ($2::timestamptz IS NULL OR "start_time" >= $2)
+    "#;
+    Ok(sqlx::query_as(usage_query)
+        .bind(subscriber)
+        .bind(since)
+        .fetch_one(db_pool)
+        .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_route_requests() {
+        let api = Api {
+            context: Context {
+                db_pool: std::sync::Arc::new(
+                    sqlx::PgPool::connect_lazy("postgres://haulage@localhost/haulage").unwrap(),
+                ),
+                require_api_key: true,
+            },
+            log: slog::Logger::root(slog::Discard, slog::o!()),
+        };
+        let status = |method: &str, uri: &str| {
+            let request = axum::http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(axum::body::Body::empty())
+                .unwrap();
+            let router = router(api.clone());
+            async move { router.oneshot(request).await.unwrap().status() }
+        };
+
+        // Callers without a key are turned away before the database is used.
+        assert_eq!(
+            status("POST", "/subscribers/7/balance").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status("GET", "/subscribers/").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status("GET", "/subscribers/7/usage?since=yesterday").await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status("GET", "/subscribers/7/plan").await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status("DELETE", "/subscribers/7/usage").await,
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+    }
+}
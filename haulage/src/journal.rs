@@ -0,0 +1,240 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum JournalError {
+    #[error("Database operation failed: {0}")]
+    DatabaseError(#[from] sqlx::error::Error),
+    #[error("Malformed journal entry {0}: {1}")]
+    MalformedEntry(i64, serde_json::Error),
+}
+
+// The balance and policy state of a subscriber, reconstructed by replaying the
+// subscriber journal maintained by database triggers.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct SubscriberState {
+    pub imsi: String,
+    pub subscriber_id: Option<i32>,
+    pub data_balance: Option<i64>,
+    pub current_policy: Option<i32>,
+    pub suspended: Option<bool>,
+    pub exempt_until: Option<chrono::DateTime<chrono::Utc>>,
+    pub balance_pool: Option<i32>,
+    pub deprioritized_policy: Option<i32>,
+    pub pool_balance: Option<i64>,
+    pub last_change: Option<chrono::DateTime<chrono::Utc>>,
+}
+impl std::fmt::Display for SubscriberState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            serde_json::to_string_pretty(self).map_err(|_| std::fmt::Error)?
+        )
+    }
+}
+
+// Only the columns that changed are recorded in each entry, so absent fields
+// leave the replayed state untouched while explicit nulls clear it.
+#[derive(Debug, serde::Deserialize)]
+struct SubscriberChanges {
+    #[serde(default, deserialize_with = "present")]
+    data_balance: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "present")]
+    current_policy: Option<Option<i32>>,
+    #[serde(default, deserialize_with = "present")]
+    suspended: Option<Option<bool>>,
+    #[serde(default, deserialize_with = "present")]
+    exempt_until: Option<Option<chrono::DateTime<chrono::Utc>>>,
+    #[serde(default, deserialize_with = "present")]
+    balance_pool: Option<Option<i32>>,
+    #[serde(default, deserialize_with = "present")]
+    deprioritized_policy: Option<Option<i32>>,
+}
+
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: serde::Deserialize<'de>,
+{
+    serde::Deserialize::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct JournalEntry {
+    id: i64,
+    time: chrono::DateTime<chrono::Utc>,
+    subscriber: Option<i32>,
+    changes: serde_json::Value,
+}
+
+impl SubscriberState {
+    fn apply(&mut self, entry: &JournalEntry) -> Result<(), JournalError> {
+        let changes: SubscriberChanges = serde_json::from_value(entry.changes.clone())
+            .map_err(|e| JournalError::MalformedEntry(entry.id, e))?;
+        if let Some(data_balance) = changes.data_balance {
+            self.data_balance = data_balance;
+        }
+        if let Some(current_policy) = changes.current_policy {
+            self.current_policy = current_policy;
+        }
+        if let Some(suspended) = changes.suspended {
+            self.suspended = suspended;
+        }
+        if let Some(exempt_until) = changes.exempt_until {
+            self.exempt_until = exempt_until;
+        }
+        if let Some(balance_pool) = changes.balance_pool {
+            self.balance_pool = balance_pool;
+        }
+        if let Some(deprioritized_policy) = changes.deprioritized_policy {
+            self.deprioritized_policy = deprioritized_policy;
+        }
+        self.subscriber_id = entry.subscriber.or(self.subscriber_id);
+        self.last_change = Some(entry.time);
+        Ok(())
+    }
+}
+
+// Reconstructs the state of a subscriber as of the given time by replaying
+// their journal entries in order, including the balance of the pool they
+// belonged to at the time. Returns None if the subscriber had no journal
+// entries by then.
+pub async fn replay_subscriber(
+    db_pool: &sqlx::PgPool,
+    imsi: &str,
+    at: chrono::DateTime<chrono::Utc>,
+) -> Result<Option<SubscriberState>, JournalError> {
+    let subscriber_query = r#"
+        SELECT "id", "time", "subscriber", "changes"
+        FROM subscriber_journal
+        WHERE "imsi" = $1 AND "time" <= $2
+        ORDER BY "id"
+    "#;
+    let entries: Vec<JournalEntry> = sqlx::query_as(subscriber_query)
+        .bind(imsi)
+        .bind(at)
+        .fetch_all(db_pool)
+        .await?;
+    if entries.is_empty() {
+        return Ok(None);
+    }
+
+    let mut state = SubscriberState {
+        imsi: imsi.to_owned(),
+        ..Default::default()
+    };
+    for entry in &entries {
+        state.apply(entry)?;
+    }
+
+    if let Some(balance_pool) = state.balance_pool {
+        let pool_query = r#"
+            SELECT "id", "time", "subscriber", "changes"
+            FROM subscriber_journal
+            WHERE "balance_pool" = $1 AND "subscriber" IS NULL AND "time" <= $2
+            ORDER BY "id"
+        "#;
+        let pool_entries: Vec<JournalEntry> = sqlx::query_as(pool_query)
+            .bind(balance_pool)
+            .bind(at)
+            .fetch_all(db_pool)
+            .await?;
+        let mut pool = SubscriberState::default();
+        for entry in &pool_entries {
+            pool.apply(entry)?;
+        }
+        state.pool_balance = pool.data_balance;
+    }
+
+    Ok(Some(state))
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Verification {
+    pub entries: i64,
+    // The first entry whose contents or position in the chain do not match
+    // its hash, if any.
+    pub first_invalid_entry: Option<i64>,
+}
+impl std::fmt::Display for Verification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.first_invalid_entry {
+            None => write!(f, "journal intact: {} entries verified", self.entries),
+            Some(id) => write!(f, "journal modified: entry {} does not match its hash", id),
+        }
+    }
+}
+
+// Recomputes the hash chain of the whole journal, detecting entries that were
+// modified, removed, or reordered after being written.
+pub async fn verify(db_pool: &sqlx::PgPool) -> Result<Verification, JournalError> {
+    let verify_query = r#"
+        SELECT
+            (SELECT count(*) FROM subscriber_journal),
+            (
+                SELECT min("id") FROM (
+                    SELECT
+                        "id",
+                        "hash",
+                        "previous_hash",
+                        subscriber_journal_hash("previous_hash", "id", "time", "subscriber", "imsi", "balance_pool", "changes") AS "expected_hash",
+                        lag("hash") OVER (ORDER BY "id") AS "chained_hash"
+                    FROM subscriber_journal
+                ) AS chain
+                WHERE "hash" <> "expected_hash" OR "previous_hash" IS DISTINCT FROM "chained_hash"
+            )
+    "#;
+    let (entries, first_invalid_entry): (i64, Option<i64>) =
+        sqlx::query_as(verify_query).fetch_one(db_pool).await?;
+    Ok(Verification {
+        entries,
+        first_invalid_entry,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: i64, changes: serde_json::Value) -> JournalEntry {
+        JournalEntry {
+            id,
+            time: chrono::Utc::now(),
+            subscriber: Some(7),
+            changes,
+        }
+    }
+
+    #[test]
+    fn test_replay_applies_only_recorded_changes() {
+        let mut state = SubscriberState::default();
+        state
+            .apply(&entry(
+                1,
+                serde_json::json!({
+                    "data_balance": 1000,
+                    "current_policy": 2,
+                    "suspended": false,
+                    "exempt_until": null,
+                    "balance_pool": 3,
+                }),
+            ))
+            .unwrap();
+        state
+            .apply(&entry(2, serde_json::json!({ "data_balance": 400 })))
+            .unwrap();
+        state
+            .apply(&entry(3, serde_json::json!({ "balance_pool": null })))
+            .unwrap();
+
+        assert_eq!(state.subscriber_id, Some(7));
+        assert_eq!(state.data_balance, Some(400));
+        assert_eq!(state.current_policy, Some(2));
+        assert_eq!(state.suspended, Some(false));
+        assert_eq!(state.balance_pool, None);
+
+        assert!(state
+            .apply(&entry(4, serde_json::json!({ "data_balance": "lots" })))
+            .is_err());
+    }
+}
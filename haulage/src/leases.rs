@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum LeaseError {
+    #[error("Database operation failed: {0}")]
+    DatabaseError(#[from] sqlx::error::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LeaseFormat {
+    Dnsmasq,
+    Kea,
+}
+
+// The lease field holding the subscriber's IMSI.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Identity {
+    ClientId,
+    Hostname,
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub path: std::path::PathBuf,
+    pub format: LeaseFormat,
+    pub identify_by: Identity,
+    pub poll_interval: std::time::Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Lease {
+    ip: IpAddr,
+    client_id: Option<String>,
+    hostname: Option<String>,
+    // None for leases that never expire.
+    expires: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+// Polls a DHCP server's lease file and keeps the leased addresses in
+// static_ips, marked as leased so that operator assigned addresses are never
+// replaced or removed. The address watcher then moves each subscriber's live
+// state to follow its lease, as it does for any other address change.
+pub async fn track_leases(
+    settings: Settings,
+    db_pool: std::sync::Arc<sqlx::PgPool>,
+    log: slog::Logger,
+) {
+    let mut timer = tokio::time::interval(settings.poll_interval);
+    let mut synced = None;
+    loop {
+        timer.tick().await;
+        let contents = match tokio::fs::read_to_string(&settings.path).await {
+            Ok(contents) => contents,
+            Err(e) => {
+                slog::warn!(log, "Unable to read lease file"; "path" => settings.path.display().to_string(), "error" => e.to_string());
+                continue;
+            }
+        };
+
+        let leases = match settings.format {
+            LeaseFormat::Dnsmasq => parse_dnsmasq(&contents),
+            LeaseFormat::Kea => parse_kea(&contents),
+        };
+        let current = current_assignments(&leases, settings.identify_by, chrono::Utc::now());
+        // Leases are re-synced even if the file is unchanged while any remain
+        // unassigned, since their subscribers may be provisioned later.
+        if synced.as_ref() == Some(&current) {
+            continue;
+        }
+        match sync_assignments(&db_pool, &current, &log).await {
+            Ok(true) => synced = Some(current),
+            Ok(false) => synced = None,
+            Err(e) => {
+                slog::error!(log, "Failed to update leased addresses"; "error" => e.to_string());
+                synced = None;
+            }
+        }
+    }
+}
+
+// Parses a dnsmasq lease file, with one "expiry mac ip hostname client-id"
+// line per lease.
+fn parse_dnsmasq(contents: &str) -> Vec<Lease> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 5 || fields[0] == "duid" {
+                return None;
+            }
+            let expires = match fields[0].parse::<i64>().ok()? {
+                0 => None,
+                expiry => Some(chrono::DateTime::from_utc(
+                    chrono::NaiveDateTime::from_timestamp_opt(expiry, 0)?,
+                    chrono::Utc,
+                )),
+            };
+            Some(Lease {
+                ip: fields[2].parse().ok()?,
+                client_id: known_field(fields[4]).and_then(decode_client_id),
+                hostname: known_field(fields[3]).map(str::to_owned),
+                expires,
+            })
+        })
+        .collect()
+}
+
+// Parses a Kea memfile lease CSV, which is appended to as leases change, so
+// later lines replace earlier ones for the same address.
+fn parse_kea(contents: &str) -> Vec<Lease> {
+    let mut lines = contents.lines();
+    let header: Vec<&str> = match lines.next() {
+        Some(header) => header.split(',').collect(),
+        None => return Vec::new(),
+    };
+    let column = |name: &str| header.iter().position(|column| *column == name);
+    let (address, client_id, lifetime, expire, hostname, state) = match (
+        column("address"),
+        column("client_id"),
+        column("valid_lifetime"),
+        column("expire"),
+        column("hostname"),
+        column("state"),
+    ) {
+        (Some(a), Some(c), Some(l), Some(e), Some(h), Some(s)) => (a, c, l, e, h, s),
+        _ => return Vec::new(),
+    };
+
+    let mut leases: HashMap<IpAddr, Option<Lease>> = HashMap::new();
+    for line in lines {
+        let fields: Vec<&str> = line.split(',').collect();
+        let ip = match fields.get(address).and_then(|ip| ip.parse().ok()) {
+            Some(ip) => ip,
+            None => continue,
+        };
+        // Released and declined leases are written with a zero lifetime or
+        // non-default state, and end any earlier lease for the address.
+        let active = fields.get(state) == Some(&"0") && fields.get(lifetime) != Some(&"0");
+        let expires = fields
+            .get(expire)
+            .and_then(|expire| expire.parse::<i64>().ok())
+            .and_then(|expire| chrono::NaiveDateTime::from_timestamp_opt(expire, 0))
+            .map(|expire| chrono::DateTime::from_utc(expire, chrono::Utc));
+        let lease = match (active, expires) {
+            (true, Some(expires)) => Some(Lease {
+                ip,
+                client_id: fields
+                    .get(client_id)
+                    .and_then(|id| known_field(id))
+                    .and_then(decode_client_id),
+                hostname: fields
+                    .get(hostname)
+                    .and_then(|name| known_field(name))
+                    .map(str::to_owned),
+                expires: Some(expires),
+            }),
+            _ => None,
+        };
+        leases.insert(ip, lease);
+    }
+    leases.into_values().flatten().collect()
+}
+
+fn known_field(field: &str) -> Option<&str> {
+    match field {
+        "" | "*" => None,
+        field => Some(field),
+    }
+}
+
+// Decodes a client identifier written as colon separated hex bytes. Text
+// identifiers are sent with a leading zero type byte, which is dropped.
+fn decode_client_id(hex: &str) -> Option<String> {
+    let bytes = hex
+        .split(':')
+        .map(|byte| u8::from_str_radix(byte, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    let text = match bytes.split_first() {
+        Some((0, text)) => text,
+        _ => &bytes,
+    };
+    String::from_utf8(text.to_vec())
+        .ok()
+        .filter(|text| !text.is_empty() && text.chars().all(|c| c.is_ascii_graphic()))
+}
+
+// Maps the unexpired leased addresses to the IMSIs they identify. A
+// subscriber holding several leases of an address family keeps only the one
+// expiring last, since each subscriber is followed at a single address.
+fn current_assignments(
+    leases: &[Lease],
+    identify_by: Identity,
+    now: chrono::DateTime<chrono::Utc>,
+) -> HashMap<IpAddr, String> {
+    let mut latest: HashMap<(&str, bool), &Lease> = HashMap::new();
+    for lease in leases {
+        if matches!(lease.expires, Some(expires) if expires <= now) {
+            continue;
+        }
+        let imsi = match identify_by {
+            Identity::ClientId => lease.client_id.as_deref(),
+            Identity::Hostname => lease.hostname.as_deref(),
+        };
+        let imsi = match imsi {
+            Some(imsi) => imsi,
+            None => continue,
+        };
+        let entry = latest.entry((imsi, lease.ip.is_ipv4())).or_insert(lease);
+        // Leases without an expiry outlast all others.
+        let outlasts = match (lease.expires, entry.expires) {
+            (None, Some(_)) => true,
+            (Some(expires), Some(entry_expires)) => expires > entry_expires,
+            _ => false,
+        };
+        if outlasts {
+            *entry = lease;
+        }
+    }
+    latest
+        .into_iter()
+        .map(|((imsi, _), lease)| (lease.ip, imsi.to_owned()))
+        .collect()
+}
+
+// Brings the leased rows of static_ips in line with the current leases,
+// returning whether every lease was assigned to a subscriber.
+async fn sync_assignments(
+    db_pool: &sqlx::PgPool,
+    current: &HashMap<IpAddr, String>,
+    log: &slog::Logger,
+) -> Result<bool, LeaseError> {
+    let mut transaction = db_pool.begin().await?;
+
+    let rows: Vec<(ipnetwork::IpNetwork, String)> =
+        sqlx::query_as(r#"SELECT "ip", "imsi" FROM static_ips WHERE "leased""#)
+            .fetch_all(&mut transaction)
+            .await?;
+    let assigned: HashMap<IpAddr, String> = rows
+        .into_iter()
+        .map(|(network, imsi)| (network.ip(), imsi))
+        .collect();
+
+    for (ip, imsi) in &assigned {
+        if current.get(ip) == Some(imsi) {
+            continue;
+        }
+        sqlx::query(r#"DELETE FROM static_ips WHERE "ip" = $1 AND "leased""#)
+            .bind(ipnetwork::IpNetwork::from(*ip))
+            .execute(&mut transaction)
+            .await?;
+        slog::info!(log, "Lease ended"; "ip" => ip.to_string(), "imsi" => imsi);
+    }
+
+    let mut all_assigned = true;
+    for (ip, imsi) in current {
+        if assigned.get(ip) == Some(imsi) {
+            continue;
+        }
+        // Leases only take over addresses that are not assigned statically,
+        // and only for subscribers that exist.
+        let result = sqlx::query(
+            r#"
+            INSERT INTO static_ips("ip", "imsi", "leased")
+            SELECT $1, $2, true WHERE EXISTS (SELECT 1 FROM subscribers WHERE "imsi" = $2)
+            ON CONFLICT ("ip") DO UPDATE SET "imsi" = EXCLUDED."imsi" WHERE static_ips."leased"
+        "#,
+        )
+        .bind(ipnetwork::IpNetwork::from(*ip))
+        .bind(imsi)
+        .execute(&mut transaction)
+        .await?;
+        if result.rows_affected() == 0 {
+            slog::debug!(log, "Lease not assigned to a subscriber"; "ip" => ip.to_string(), "imsi" => imsi);
+            all_assigned = false;
+            continue;
+        }
+        slog::info!(log, "Lease assigned"; "ip" => ip.to_string(), "imsi" => imsi);
+    }
+
+    transaction.commit().await?;
+    Ok(all_assigned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_leases() {
+        let now = chrono::DateTime::from_utc(
+            chrono::NaiveDateTime::from_timestamp_opt(1_700_000_000, 0).unwrap(),
+            chrono::Utc,
+        );
+
+        let dnsmasq = "\
+1700000600 aa:bb:cc:dd:ee:01 10.45.0.2 001010000000001 *
+0 aa:bb:cc:dd:ee:02 10.45.0.3 * 00:30:30:31:30:31:30:30:30:30:30:30:30:30:30:32
+1699999999 aa:bb:cc:dd:ee:03 10.45.0.4 001010000000003 *
+1700000300 aa:bb:cc:dd:ee:01 10.45.0.5 001010000000001 *
+duid 00:01:00:01:2c:4d:5e:6f:aa:bb:cc:dd:ee:ff
+";
+        let leases = parse_dnsmasq(dnsmasq);
+        assert_eq!(leases.len(), 4);
+        assert_eq!(leases[1].client_id.as_deref(), Some("001010000000002"));
+        assert_eq!(leases[1].expires, None);
+        assert_eq!(
+            current_assignments(&leases, Identity::Hostname, now),
+            HashMap::from([("10.45.0.2".parse().unwrap(), "001010000000001".to_owned())])
+        );
+        assert_eq!(
+            current_assignments(&leases, Identity::ClientId, now),
+            HashMap::from([("10.45.0.3".parse().unwrap(), "001010000000002".to_owned())])
+        );
+
+        let kea = "\
+address,hwaddr,client_id,valid_lifetime,expire,subnet_id,fqdn_fwd,fqdn_rev,hostname,state,user_context
+10.45.0.2,aa:bb:cc:dd:ee:01,,3600,1700000600,1,0,0,001010000000001,0,
+10.45.0.3,aa:bb:cc:dd:ee:02,,3600,1700000600,1,0,0,001010000000002,0,
+10.45.0.3,aa:bb:cc:dd:ee:02,,0,1700000100,1,0,0,001010000000002,0,
+10.45.0.4,aa:bb:cc:dd:ee:03,,3600,1700000600,1,0,0,001010000000003,1,
+";
+        assert_eq!(
+            current_assignments(&parse_kea(kea), Identity::Hostname, now),
+            HashMap::from([("10.45.0.2".parse().unwrap(), "001010000000001".to_owned())])
+        );
+        assert_eq!(decode_client_id("01:aa:bb:cc:dd:ee:01"), None);
+    }
+}
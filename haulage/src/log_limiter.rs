@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+
+// Each log callsite may emit a short burst of messages, and is then limited to
+// a steady rate, so that a single misbehaving device cannot flood the log.
+const BURST: f64 = 20.0;
+const MESSAGES_PER_SECOND: f64 = 1.0;
+
+type Callsite = (&'static str, u32);
+
+// Token bucket rate limiting of info level messages, keyed by the location of
+// the log statement. Messages dropped by the limiter are counted so that their
+// volume can be reported periodically in summarized form.
+#[derive(Debug, Default)]
+pub struct LogLimiter {
+    callsites: std::sync::Mutex<HashMap<Callsite, CallsiteState>>,
+}
+impl LogLimiter {
+    pub fn new() -> LogLimiter {
+        Default::default()
+    }
+
+    fn allow(&self, record: &slog::Record) -> bool {
+        let mut callsites = self.callsites.lock().unwrap();
+        let state = callsites
+            .entry((record.file(), record.line()))
+            .or_insert_with(|| CallsiteState::new(std::time::Instant::now()));
+        if state.bucket.take(std::time::Instant::now()) {
+            return true;
+        }
+        if state.suppressed == 0 {
+            state.example = Some(record.msg().to_string());
+        }
+        state.suppressed += 1;
+        false
+    }
+
+    // Returns the messages suppressed since the last call, resetting the
+    // counts.
+    pub fn take_suppressed(&self) -> Vec<Suppressed> {
+        let mut callsites = self.callsites.lock().unwrap();
+        callsites
+            .iter_mut()
+            .filter(|(_, state)| state.suppressed > 0)
+            .map(|((file, line), state)| Suppressed {
+                location: format!("{}:{}", file, line),
+                example: state.example.take().unwrap_or_default(),
+                count: std::mem::take(&mut state.suppressed),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct Suppressed {
+    pub location: String,
+    pub example: String,
+    pub count: u64,
+}
+
+#[derive(Debug)]
+struct CallsiteState {
+    bucket: TokenBucket,
+    suppressed: u64,
+    example: Option<String>,
+}
+impl CallsiteState {
+    fn new(now: std::time::Instant) -> CallsiteState {
+        CallsiteState {
+            bucket: TokenBucket::new(BURST, MESSAGES_PER_SECOND, now),
+            suppressed: 0,
+            example: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    last_refill: std::time::Instant,
+}
+impl TokenBucket {
+    fn new(capacity: f64, rate: f64, now: std::time::Instant) -> TokenBucket {
+        TokenBucket {
+            capacity,
+            rate,
+            tokens: capacity,
+            last_refill: now,
+        }
+    }
+
+    fn take(&mut self, now: std::time::Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.capacity);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// A drain applying the limiter to info level records before passing them on.
+// Warnings and errors are always passed through, and debug records are only
+// enabled when explicitly debugging.
+pub struct LimitedDrain<D> {
+    inner: D,
+    limiter: std::sync::Arc<LogLimiter>,
+}
+impl<D> LimitedDrain<D> {
+    pub fn new(inner: D, limiter: std::sync::Arc<LogLimiter>) -> LimitedDrain<D> {
+        LimitedDrain { inner, limiter }
+    }
+}
+impl<D: slog::Drain<Ok = ()>> slog::Drain for LimitedDrain<D> {
+    type Ok = ();
+    type Err = D::Err;
+
+    fn log(
+        &self,
+        record: &slog::Record,
+        values: &slog::OwnedKVList,
+    ) -> Result<Self::Ok, Self::Err> {
+        if record.level() == slog::Level::Info && !self.limiter.allow(record) {
+            return Ok(());
+        }
+        self.inner.log(record, values)
+    }
+}
+
+// Periodically logs a summary of the messages suppressed by the limiter.
+pub async fn report_suppressed(
+    limiter: std::sync::Arc<LogLimiter>,
+    interval: std::time::Duration,
+    log: slog::Logger,
+) {
+    let mut timer = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        timer.tick().await;
+        for suppressed in limiter.take_suppressed() {
+            slog::info!(log, "Suppressed repetitive log messages"; "count" => suppressed.count, "location" => suppressed.location, "example" => suppressed.example);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = std::time::Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 0.5, start);
+        assert!(bucket.take(start));
+        assert!(bucket.take(start));
+        assert!(!bucket.take(start));
+
+        // Half a token has refilled after one second.
+        let later = start + std::time::Duration::from_secs(1);
+        assert!(!bucket.take(later));
+        let later = start + std::time::Duration::from_secs(2);
+        assert!(bucket.take(later));
+
+        // Refilling stops at the bucket capacity.
+        let much_later = start + std::time::Duration::from_secs(3600);
+        assert!(bucket.take(much_later));
+        assert!(bucket.take(much_later));
+        assert!(!bucket.take(much_later));
+    }
+}
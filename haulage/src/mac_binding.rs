@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum BindingError {
+    #[error("Database operation failed: {0}")]
+    DatabaseError(#[from] sqlx::error::Error),
+}
+
+// How often the bindings already seen are forgotten, so that devices
+// registered after they were first seen, and bindings changed by hand, are
+// bound again.
+const REBIND_PERIOD: std::time::Duration = std::time::Duration::from_secs(60);
+
+// Identifies subscribers by the link layer addresses of their devices, for
+// hotspots where addresses rotate too often to assign statically. Each address
+// a registered device sends from is kept in static_ips, marked as leased, so
+// that the per-address reporters and accounters resolve the device's
+// subscriber as they would for a static address, and the address watcher
+// moves the subscriber's live state when the device's address changes.
+#[derive(Debug)]
+pub struct MacBinder {
+    dispatch_channel: tokio::sync::mpsc::Sender<Message>,
+}
+impl MacBinder {
+    pub fn new(db_pool: std::sync::Arc<sqlx::PgPool>, log: slog::Logger) -> MacBinder {
+        let (sender, receiver) = tokio::sync::mpsc::channel(64);
+        tokio::task::spawn(async move {
+            bind_macs(receiver, db_pool, log).await;
+        });
+        MacBinder {
+            dispatch_channel: sender,
+        }
+    }
+    pub fn clone_input_channel(&self) -> tokio::sync::mpsc::Sender<Message> {
+        self.dispatch_channel.clone()
+    }
+}
+
+pub enum Message {
+    // The device seen sending from each address. The reply is sent once the
+    // addresses are bound, so that usage from a new address is only reported
+    // after its subscriber can be resolved.
+    Bindings {
+        bindings: HashMap<std::net::IpAddr, pnet_datalink::MacAddr>,
+        done: tokio::sync::oneshot::Sender<()>,
+    },
+}
+
+async fn bind_macs(
+    mut chan: tokio::sync::mpsc::Receiver<Message>,
+    db_pool: std::sync::Arc<sqlx::PgPool>,
+    log: slog::Logger,
+) {
+    let mut bound: HashMap<std::net::IpAddr, pnet_datalink::MacAddr> = HashMap::new();
+    let mut timer = tokio::time::interval(REBIND_PERIOD);
+    loop {
+        tokio::select! {
+            _ = timer.tick() => bound.clear(),
+            message = chan.recv() => {
+                match message {
+                    Some(Message::Bindings { bindings, done }) => {
+                        for (ip, mac) in bindings {
+                            if bound.get(&ip) == Some(&mac) {
+                                continue;
+                            }
+                            match bind_address(&db_pool, ip, mac).await {
+                                Ok(Some(imsi)) => {
+                                    slog::info!(log, "Bound device address"; "ip" => ip.to_string(), "mac" => mac.to_string(), "imsi" => imsi);
+                                }
+                                Ok(None) => {}
+                                Err(e) => {
+                                    slog::warn!(log, "Failed to bind device address"; "ip" => ip.to_string(), "mac" => mac.to_string(), "error" => e.to_string());
+                                    continue;
+                                }
+                            }
+                            bound.insert(ip, mac);
+                        }
+                        // The batch may have stopped waiting at shutdown.
+                        let _ = done.send(());
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+}
+
+// Assigns an address to the subscriber owning the device sending from it,
+// replacing the subscriber's previously bound address of the same family.
+// Returns the subscriber's IMSI if the binding changed.
+async fn bind_address(
+    db_pool: &sqlx::PgPool,
+    ip: std::net::IpAddr,
+    mac: pnet_datalink::MacAddr,
+) -> Result<Option<String>, BindingError> {
+    let mut transaction = db_pool.begin().await?;
+
+    let owner: Option<(String,)> =
+        sqlx::query_as(r#"SELECT "imsi" FROM subscriber_macs WHERE "mac" = $1::MACADDR"#)
+            .bind(mac.to_string())
+            .fetch_optional(&mut transaction)
+            .await?;
+    let imsi = match owner {
+        Some((imsi,)) => imsi,
+        None => return Ok(None),
+    };
+
+    let moved = sqlx::query(
+        r#"
+        DELETE FROM static_ips
+        WHERE "leased" AND "imsi" = $1 AND family("ip") = family($2) AND "ip" <> $2
+    "#,
+    )
+    .bind(&imsi)
+    .bind(ipnetwork::IpNetwork::from(ip))
+    .execute(&mut transaction)
+    .await?;
+
+    // Addresses assigned statically are never taken over.
+    let bound = sqlx::query(
+        r#"
+        INSERT INTO static_ips("ip", "imsi", "leased") VALUES ($1, $2, true)
+        ON CONFLICT ("ip") DO UPDATE SET "imsi" = EXCLUDED."imsi"
+        WHERE static_ips."leased" AND static_ips."imsi" <> EXCLUDED."imsi"
+    "#,
+    )
+    .bind(ipnetwork::IpNetwork::from(ip))
+    .bind(&imsi)
+    .execute(&mut transaction)
+    .await?;
+
+    transaction.commit().await?;
+    match moved.rows_affected() + bound.rows_affected() {
+        0 => Ok(None),
+        _ => Ok(Some(imsi)),
+    }
+}
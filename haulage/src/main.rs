@@ -1,19 +1,77 @@
-use std::collections::HashSet;
-use std::iter::FromIterator;
-use std::str::FromStr;
+use std::collections::{HashMap, HashSet};
 
 use git_version::git_version;
-use reporter::UserReporter;
+use reporter::{DestinationReporter, UserReporter};
 use slog::*;
 use sqlx::migrate::Migrate;
 use sqlx::prelude::*;
 use structopt::StructOpt;
 
 mod accounter;
+mod accounting;
+mod address_collision;
+mod address_watcher;
+mod adjust;
+mod admin;
+mod airtime;
+mod api_keys;
+mod archive;
 mod async_aggregator;
+mod bench;
+mod bundle;
+mod capture;
+mod charging;
+mod check_config;
+mod clickhouse;
+mod clock;
+mod content_filter;
+mod control;
+mod debug_capture;
+mod dns_cache;
+mod drops;
 mod enforcer;
+mod events;
+mod fair_usage;
+mod flow_logger;
+mod flow_stream;
+mod forecast;
+mod grpc;
+mod grpc_api;
+mod guarantee;
+mod handoff;
+mod hooks;
+mod http_api;
+mod journal;
+mod leases;
+mod log_limiter;
+mod mac_binding;
+mod merge;
+mod metrics;
+mod nat_cpe;
+mod netflow;
+mod netns;
+mod nft_quota;
 mod packet_parser;
+mod pinning;
+mod policies;
+mod presence;
+mod privacy;
+mod protobuf;
+mod quota_dns;
+mod reconciler;
+mod remote_write;
+mod replay;
 mod reporter;
+mod self_test;
+mod shedding;
+mod shutdown;
+mod stats;
+mod storage;
+mod syslog;
+mod tc;
+mod trial;
+mod usage_writer;
+mod wan_usage;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "haulage", about = "A small-scale traffic monitor.")]
@@ -40,9 +98,43 @@ struct Opt {
     /// Show debug log information
     #[structopt(short = "v", long = "verbose")]
     verbose: bool,
+
+    /// Replay the packets recorded in a pcap or pcap-ng file through parsing,
+    /// aggregation, and reporting, timed by their capture timestamps, then
+    /// exit. Usage records are written to the configured database, but
+    /// balances and enforcement are left untouched.
+    #[structopt(long = "replay")]
+    replay: Option<std::path::PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Perform administrative operations against the configured database and
+    /// exit.
+    Admin(admin::AdminCommand),
+    /// Record corrections to subscriber usage and balances and exit.
+    Adjust(adjust::AdjustCommand),
+    /// Run performance benchmarks against the configured database and exit.
+    Bench(bench::BenchCommand),
+    /// Export or import signed bundles for offline sites and exit.
+    Bundle(bundle::BundleCommand),
+    /// Validate the configuration file without starting, reporting each
+    /// problem found, and exit non-zero if there are any.
+    CheckConfig(check_config::CheckConfigCommand),
+    /// Merge site usage and balances into the central database and exit.
+    Merge(merge::MergeCommand),
+    /// Check capture, packet parsing, the database, and the traffic control
+    /// tools without changing any state, reporting pass or fail for each, and
+    /// exit.
+    SelfTest,
 }
 
 mod config {
+    use std::str::FromStr;
+
     #[derive(Debug, serde::Deserialize)]
     #[serde(rename_all = "camelCase")]
     pub struct Version {
@@ -56,150 +148,2953 @@ mod config {
         pub flow_log_interval: std::time::Duration,
         #[serde(with = "humantime_serde")]
         pub user_log_interval: std::time::Duration,
+        pub align_log_intervals: Option<bool>,
+        // The site's offset from UTC, e.g. "+13:00", for local day boundaries.
+        pub site_utc_offset: Option<String>,
+        // The site's IANA time zone, e.g. "Africa/Nairobi", following daylight
+        // saving time.
+        pub site_timezone: Option<String>,
+        pub accounting_level: Option<AccountingLevel>,
         pub interface: Option<String>,
         pub subscriber_interface: Option<String>,
-        pub upstream_interface: Option<String>,
-        pub user_subnet: String,
+        // Several upstream interfaces can be given when the backhaul is load
+        // balanced between multiple WAN links.
+        pub upstream_interface: Option<OneOrMany<String>>,
+        // The `ip netns` namespaces containing the interfaces, if not the
+        // namespace haulage runs in.
+        pub subscriber_namespace: Option<String>,
+        pub upstream_namespace: Option<String>,
+        pub user_subnet: OneOrMany<String>,
         pub ignored_user_addresses: Vec<String>,
+        // Accounts all addresses within an IPv6 /64 as a single user.
+        #[serde(default)]
+        pub group_ipv6_prefixes: bool,
         pub custom: V1Custom,
     }
 
+    // Allows a config key to hold either a single value or a list of values,
+    // e.g. a single subscriber subnet or a dual-stack IPv4 and IPv6 pair.
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(untagged)]
+    pub enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+    impl<T> OneOrMany<T> {
+        pub fn into_vec(self) -> Vec<T> {
+            match self {
+                OneOrMany::One(value) => vec![value],
+                OneOrMany::Many(values) => values,
+            }
+        }
+    }
+
     #[derive(Debug, serde::Deserialize)]
     #[serde(rename_all = "camelCase")]
     pub struct V1Custom {
         #[serde(with = "humantime_serde")]
         pub reenable_poll_interval: std::time::Duration,
-        pub db_location: String,
-        pub db_user: String,
-        pub db_pass: String,
+        pub db_backend: Option<DbBackend>,
+        pub db_path: Option<std::path::PathBuf>,
+        pub db_location: Option<String>,
+        pub db_user: Option<String>,
+        pub db_pass: Option<String>,
+        pub db_host: Option<String>,
+        pub db_port: Option<u16>,
+        pub db_ssl_mode: Option<SslMode>,
+        pub db_ssl_root_cert: Option<std::path::PathBuf>,
+        pub db_url: Option<String>,
         pub db_auto_upgrade: Option<bool>,
+        #[serde(default, with = "humantime_serde")]
+        pub stats_log_interval: Option<std::time::Duration>,
+        pub stats_export_path: Option<std::path::PathBuf>,
+        pub control_socket_path: Option<std::path::PathBuf>,
+        pub control_require_api_key: Option<bool>,
+        #[serde(default, with = "humantime_serde")]
+        pub usage_flush_interval: Option<std::time::Duration>,
+        pub usage_spill_path: Option<std::path::PathBuf>,
+        pub filter_capture: Option<bool>,
+        pub cpu_pinning: Option<CpuPinning>,
+        pub content_filter: Option<ContentFilter>,
+        pub clickhouse: Option<Clickhouse>,
+        pub flow_stream: Option<FlowStream>,
+        pub grpc_api: Option<GrpcApi>,
+        pub http_api: Option<HttpApi>,
+        pub syslog: Option<Syslog>,
+        pub netflow: Option<Netflow>,
+        pub remote_write: Option<RemoteWrite>,
+        pub metrics: Option<Metrics>,
+        pub archive: Option<Archive>,
+        pub quota_dns: Option<QuotaDns>,
+        pub address_collision: Option<AddressCollision>,
+        pub dhcp_leases: Option<DhcpLeases>,
+        pub identify_by_mac: Option<bool>,
+        pub nat_cpe: Option<NatCpe>,
+        pub presence: Option<Presence>,
+        pub top_destinations: Option<TopDestinations>,
+        pub trial: Option<Trial>,
+        pub reconciliation: Option<Reconciliation>,
+        pub usage_forecast: Option<UsageForecast>,
+        pub fair_usage: Option<FairUsage>,
+        pub drop_accounting: Option<DropAccounting>,
+        pub wifi_airtime: Option<WifiAirtime>,
+        pub guarantee_verification: Option<GuaranteeVerification>,
+        pub enforcement_verification: Option<EnforcementVerification>,
+        #[serde(default, with = "humantime_serde")]
+        pub enforcement_startup_grace: Option<std::time::Duration>,
+        #[serde(default)]
+        pub balance_thresholds: Vec<u64>,
+        pub hooks: Option<Hooks>,
+        pub debug_capture_path: Option<std::path::PathBuf>,
+        pub privacy_key_path: Option<std::path::PathBuf>,
+        pub handoff_path: Option<std::path::PathBuf>,
+        #[serde(default)]
+        pub policies: std::collections::BTreeMap<String, Policy>,
+        pub nft_quota: Option<bool>,
+        pub enforcement: Option<Enforcement>,
+        #[serde(default)]
+        pub charging_classes: std::collections::BTreeMap<String, ChargingClass>,
+        #[serde(default)]
+        pub plan_changes: Vec<PlanChange>,
+    }
+
+    // How balances carry over between plans, by the names of their positive
+    // balance policies. Changes matching no rule keep the balance.
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct PlanChange {
+        pub from: Option<String>,
+        pub to: Option<String>,
+        pub balance: BalanceRule,
+        pub factor: Option<f64>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum BalanceRule {
+        Keep,
+        Forfeit,
+        Scale,
+    }
+
+    // A class of destinations charged at a weight relative to other traffic.
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ChargingClass {
+        pub weight: f64,
+        #[serde(default)]
+        pub networks: Vec<String>,
+        pub domain_list: Option<std::path::PathBuf>,
+    }
+
+    // A named access policy. Links without a configured policy are unlimited.
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Policy {
+        pub local_uplink: Option<LinkPolicy>,
+        pub local_downlink: Option<LinkPolicy>,
+        pub backhaul_uplink: Option<LinkPolicy>,
+        pub backhaul_downlink: Option<LinkPolicy>,
+        pub dscp: Option<u8>,
+        // The downlink rate subscribers on the plan are promised during the
+        // busy hours, checked when guarantee verification is configured.
+        pub guaranteed_rate_kibps: Option<u32>,
+        // The resolver subscribers on the plan must use for DNS.
+        pub dns_redirect: Option<std::net::IpAddr>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(tag = "kind", rename_all = "snake_case")]
+    pub enum LinkPolicy {
+        Unlimited,
+        Block,
+        TokenBucket {
+            #[serde(rename = "rateKibps")]
+            rate_kibps: u32,
+            #[serde(rename = "minRateKibps")]
+            min_rate_kibps: Option<u32>,
+            #[serde(rename = "burstBytes")]
+            burst_bytes: Option<u32>,
+            priority: Option<u8>,
+            schedule: Option<RateSchedule>,
+        },
+    }
+
+    // A rate applied during daily local hours instead, e.g. "00:00-06:00".
+    // Without a rate the link is unlimited during the hours.
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct RateSchedule {
+        pub hours: crate::clock::DailyHours,
+        pub rate_kibps: Option<u32>,
+    }
+    impl TryFrom<LinkPolicy> for crate::policies::LinkPolicy {
+        type Error = String;
+
+        fn try_from(policy: LinkPolicy) -> Result<Self, Self::Error> {
+            match policy {
+                LinkPolicy::Unlimited => Ok(crate::policies::LinkPolicy::Unlimited),
+                LinkPolicy::Block => Ok(crate::policies::LinkPolicy::Block),
+                LinkPolicy::TokenBucket {
+                    rate_kibps,
+                    min_rate_kibps,
+                    burst_bytes,
+                    priority,
+                    schedule,
+                } => {
+                    if min_rate_kibps.is_some_and(|min_rate| min_rate > rate_kibps) {
+                        return Err(String::from("'minRateKibps' exceeds 'rateKibps'"));
+                    }
+                    if priority.is_some_and(|priority| priority > 7) {
+                        return Err(String::from("'priority' must be at most 7"));
+                    }
+                    Ok(crate::policies::LinkPolicy::TokenBucket(
+                        crate::policies::TokenBucket {
+                            rate_kibps,
+                            min_rate_kibps,
+                            burst_bytes,
+                            priority,
+                            schedule: schedule.map(|schedule| crate::policies::ScheduledRate {
+                                hours: schedule.hours,
+                                rate_kibps: schedule.rate_kibps,
+                            }),
+                        },
+                    ))
+                }
+            }
+        }
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Reconciliation {
+        #[serde(with = "humantime_serde")]
+        pub interval: std::time::Duration,
+        pub tolerance: Option<f64>,
+        pub interface_tolerance: Option<f64>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct UsageForecast {
+        #[serde(default, with = "humantime_serde")]
+        pub interval: Option<std::time::Duration>,
+        pub days: Option<u32>,
+        pub smoothing: Option<f64>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct FairUsage {
+        pub policy: String,
+        pub capacity_kbps: u64,
+        pub congestion_threshold: Option<f64>,
+        pub heaviest_share: Option<f64>,
+        #[serde(default, with = "humantime_serde")]
+        pub usage_window: Option<std::time::Duration>,
+        #[serde(default, with = "humantime_serde")]
+        pub restore_after: Option<std::time::Duration>,
+        #[serde(default, with = "humantime_serde")]
+        pub interval: Option<std::time::Duration>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct CpuPinning {
+        #[serde(default)]
+        pub capture_cores: Vec<usize>,
+        #[serde(default)]
+        pub parser_cores: Vec<usize>,
+        pub parser_threads: Option<usize>,
+        pub realtime_priority: Option<i32>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct DropAccounting {
+        pub refund_dropped_bytes: Option<bool>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct WifiAirtime {
+        pub hostapd_sockets: Vec<std::path::PathBuf>,
+        pub apply_weights: Option<bool>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Hooks {
+        #[serde(default, with = "humantime_serde")]
+        pub poll_interval: Option<std::time::Duration>,
+        pub commands: Vec<Hook>,
+    }
+
+    // An executable run for subscriber events, given the event as json on
+    // stdin and in HAULAGE_* environment variables.
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Hook {
+        #[serde(default)]
+        pub events: Vec<String>,
+        pub command: std::path::PathBuf,
+        #[serde(default)]
+        pub args: Vec<String>,
+        #[serde(default, with = "humantime_serde")]
+        pub timeout: Option<std::time::Duration>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct GuaranteeVerification {
+        pub busy_hours: String,
+        pub days: Option<u32>,
+        pub chronic_days: Option<u32>,
+        pub min_active_bytes: Option<u64>,
+        #[serde(default, with = "humantime_serde")]
+        pub interval: Option<std::time::Duration>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct EnforcementVerification {
+        #[serde(default, with = "humantime_serde")]
+        pub window: Option<std::time::Duration>,
+        pub tolerance: Option<f64>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ContentFilter {
+        // Maps category names to files listing the domains in the category.
+        pub categories: std::collections::HashMap<String, std::path::PathBuf>,
+        #[serde(default, with = "humantime_serde")]
+        pub rule_lifetime: Option<std::time::Duration>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Clickhouse {
+        pub url: String,
+        pub database: Option<String>,
+        pub user: Option<String>,
+        pub password: Option<String>,
+        pub batch_size: Option<usize>,
+        #[serde(default, with = "humantime_serde")]
+        pub flush_interval: Option<std::time::Duration>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Syslog {
+        pub address: String,
+        pub transport: Option<SyslogTransport>,
+        pub facility: Option<String>,
+        pub ca_path: Option<std::path::PathBuf>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Netflow {
+        pub collector: String,
+        pub format: Option<NetflowFormat>,
+        #[serde(default, with = "humantime_serde")]
+        pub active_timeout: Option<std::time::Duration>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum NetflowFormat {
+        NetflowV9,
+        Ipfix,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct RemoteWrite {
+        pub url: String,
+        pub user: Option<String>,
+        pub password: Option<String>,
+        pub instance: Option<String>,
+        #[serde(default, with = "humantime_serde")]
+        pub interval: Option<std::time::Duration>,
+        pub subscriber_metrics: Option<bool>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Metrics {
+        pub listen: std::net::SocketAddr,
+        pub subscriber_metrics: Option<bool>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct FlowStream {
+        pub listen: std::net::SocketAddr,
+        pub require_api_key: Option<bool>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct GrpcApi {
+        pub listen: std::net::SocketAddr,
+        pub require_api_key: Option<bool>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct HttpApi {
+        pub listen: std::net::SocketAddr,
+        pub require_api_key: Option<bool>,
+    }
+
+    // Where aged usage records are moved to, either a local directory or an S3
+    // compatible bucket.
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Archive {
+        #[serde(with = "humantime_serde")]
+        pub max_age: std::time::Duration,
+        #[serde(default, with = "humantime_serde")]
+        pub interval: Option<std::time::Duration>,
+        pub directory: Option<std::path::PathBuf>,
+        pub s3: Option<ArchiveS3>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ArchiveS3 {
+        pub endpoint: String,
+        pub bucket: String,
+        pub region: Option<String>,
+        pub access_key: String,
+        pub secret_key: String,
+        pub prefix: Option<String>,
+    }
+
+    #[derive(Debug, PartialEq, serde::Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum Enforcement {
+        Enabled,
+        Disabled,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum AccountingLevel {
+        Usage,
+        Flows,
+        Domains,
+        Dns,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum SyslogTransport {
+        Udp,
+        Tcp,
+        Tls,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct QuotaDns {
+        pub listen_address: std::net::SocketAddr,
+        pub hostname: Option<String>,
+        pub redirect_address: Option<std::net::Ipv4Addr>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct NatCpe {
+        pub port_block_size: Option<u16>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Presence {
+        pub from_traffic: Option<bool>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct TopDestinations {
+        pub count: Option<usize>,
+        #[serde(default, with = "humantime_serde")]
+        pub interval: Option<std::time::Duration>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Trial {
+        pub policy: String,
+        pub exhausted_policy: String,
+        pub allowance: u64,
+        #[serde(default, with = "humantime_serde")]
+        pub lifetime: Option<std::time::Duration>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct AddressCollision {
+        #[serde(default, with = "humantime_serde")]
+        pub window: Option<std::time::Duration>,
+        pub block: Option<bool>,
+    }
+
+    // SQLite and MySQL run metering, accounting, and enforcement, without
+    // the subsystems which require Postgres.
+    #[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum DbBackend {
+        #[default]
+        Postgres,
+        Sqlite,
+        Mysql,
+    }
+
+    // The libpq sslmode names.
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum SslMode {
+        Disable,
+        Allow,
+        Prefer,
+        Require,
+        VerifyCa,
+        VerifyFull,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct DhcpLeases {
+        pub path: std::path::PathBuf,
+        pub format: LeaseFormat,
+        pub identify_by: Option<LeaseIdentity>,
+        #[serde(default, with = "humantime_serde")]
+        pub poll_interval: Option<std::time::Duration>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum LeaseFormat {
+        Dnsmasq,
+        Kea,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum LeaseIdentity {
+        ClientId,
+        Hostname,
+    }
+
+    // The sectioned configuration schema, selected with `version: 2`. Options
+    // keep their V1 names and meanings, grouped by the part of haulage they
+    // configure, with the database options losing their db prefix. Unlike V1,
+    // unknown options are rejected rather than ignored.
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase", deny_unknown_fields)]
+    pub struct V2 {
+        // Already read to select this schema.
+        #[serde(rename = "version")]
+        _version: i16,
+        pub database: V2Database,
+        pub capture: V2Capture,
+        pub enforcement: V2Enforcement,
+        pub reporting: V2Reporting,
+        #[serde(default)]
+        pub control: V2Control,
+        pub privacy_key_path: Option<std::path::PathBuf>,
+        pub handoff_path: Option<std::path::PathBuf>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase", deny_unknown_fields)]
+    pub struct V2Database {
+        pub backend: Option<DbBackend>,
+        pub path: Option<std::path::PathBuf>,
+        pub name: Option<String>,
+        pub user: Option<String>,
+        pub pass: Option<String>,
+        pub host: Option<String>,
+        pub port: Option<u16>,
+        pub ssl_mode: Option<SslMode>,
+        pub ssl_root_cert: Option<std::path::PathBuf>,
+        pub url: Option<String>,
+        pub auto_upgrade: Option<bool>,
+    }
+
+    // Where traffic is captured and how it is attributed to subscribers.
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase", deny_unknown_fields)]
+    pub struct V2Capture {
+        pub subscriber_interface: String,
+        pub upstream_interface: OneOrMany<String>,
+        pub subscriber_namespace: Option<String>,
+        pub upstream_namespace: Option<String>,
+        pub user_subnet: OneOrMany<String>,
+        #[serde(default)]
+        pub ignored_user_addresses: Vec<String>,
+        pub filter: Option<bool>,
+        pub cpu_pinning: Option<CpuPinning>,
+        pub debug_capture_path: Option<std::path::PathBuf>,
+        pub dhcp_leases: Option<DhcpLeases>,
+        pub identify_by_mac: Option<bool>,
+        #[serde(default)]
+        pub group_ipv6_prefixes: bool,
+    }
+
+    // How balances and policies are applied to subscribers.
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase", deny_unknown_fields)]
+    pub struct V2Enforcement {
+        pub mode: Option<Enforcement>,
+        #[serde(with = "humantime_serde")]
+        pub reenable_poll_interval: std::time::Duration,
+        #[serde(default)]
+        pub policies: std::collections::BTreeMap<String, Policy>,
+        pub nft_quota: Option<bool>,
+        #[serde(default)]
+        pub charging_classes: std::collections::BTreeMap<String, ChargingClass>,
+        #[serde(default)]
+        pub plan_changes: Vec<PlanChange>,
+        #[serde(default)]
+        pub balance_thresholds: Vec<u64>,
+        pub content_filter: Option<ContentFilter>,
+        pub quota_dns: Option<QuotaDns>,
+        pub address_collision: Option<AddressCollision>,
+        pub trial: Option<Trial>,
+        pub fair_usage: Option<FairUsage>,
+        pub drop_accounting: Option<DropAccounting>,
+        pub enforcement_verification: Option<EnforcementVerification>,
+        #[serde(default, with = "humantime_serde")]
+        pub enforcement_startup_grace: Option<std::time::Duration>,
+    }
+
+    // What is recorded about subscriber traffic, and where it is exported.
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "camelCase", deny_unknown_fields)]
+    pub struct V2Reporting {
+        #[serde(with = "humantime_serde")]
+        pub flow_log_interval: std::time::Duration,
+        #[serde(with = "humantime_serde")]
+        pub user_log_interval: std::time::Duration,
+        pub align_log_intervals: Option<bool>,
+        pub site_utc_offset: Option<String>,
+        pub site_timezone: Option<String>,
+        pub accounting_level: Option<AccountingLevel>,
+        #[serde(default, with = "humantime_serde")]
+        pub stats_log_interval: Option<std::time::Duration>,
+        pub stats_export_path: Option<std::path::PathBuf>,
+        #[serde(default, with = "humantime_serde")]
+        pub usage_flush_interval: Option<std::time::Duration>,
+        pub usage_spill_path: Option<std::path::PathBuf>,
+        pub clickhouse: Option<Clickhouse>,
+        pub flow_stream: Option<FlowStream>,
+        pub syslog: Option<Syslog>,
+        pub netflow: Option<Netflow>,
+        pub remote_write: Option<RemoteWrite>,
+        pub metrics: Option<Metrics>,
+        pub archive: Option<Archive>,
+        pub nat_cpe: Option<NatCpe>,
+        pub presence: Option<Presence>,
+        pub top_destinations: Option<TopDestinations>,
+        pub reconciliation: Option<Reconciliation>,
+        pub usage_forecast: Option<UsageForecast>,
+        pub guarantee_verification: Option<GuaranteeVerification>,
+        pub wifi_airtime: Option<WifiAirtime>,
+    }
+
+    // How operators and other systems control a running instance.
+    #[derive(Debug, Default, serde::Deserialize)]
+    #[serde(rename_all = "camelCase", deny_unknown_fields)]
+    pub struct V2Control {
+        pub socket_path: Option<std::path::PathBuf>,
+        pub require_api_key: Option<bool>,
+        pub grpc_api: Option<GrpcApi>,
+        pub http_api: Option<HttpApi>,
+        pub hooks: Option<Hooks>,
     }
 
     // An internal configuration structure used by the rest of the program that can
     // be updated without breaking compatibility with existing configuration files.
     #[derive(Debug)]
     pub struct Internal {
-        pub db_name: String,
-        pub db_user: String,
-        pub db_pass: String,
+        pub database: Database,
         pub db_auto_upgrade: bool,
         pub flow_log_interval: std::time::Duration,
         pub user_log_interval: std::time::Duration,
+        pub align_log_intervals: bool,
+        pub site_timezone: Option<crate::clock::SiteTimezone>,
+        pub accounting_level: crate::accounting::AccountingLevel,
         pub reenable_poll_interval: std::time::Duration,
         pub subscriber_interface: String,
-        pub upstream_interface: Option<String>,
-        pub user_subnet: ipnetwork::IpNetwork,
+        pub upstream_interfaces: Vec<String>,
+        pub subscriber_namespace: Option<String>,
+        pub upstream_namespace: Option<String>,
+        pub user_subnets: Vec<ipnetwork::IpNetwork>,
         pub ignored_user_addresses: std::collections::HashSet<std::net::IpAddr>,
+        pub group_ipv6_prefixes: bool,
+        pub stats_log_interval: std::time::Duration,
+        pub stats_export_path: Option<std::path::PathBuf>,
+        pub control_socket_path: Option<std::path::PathBuf>,
+        pub control_require_api_key: bool,
+        pub usage_flush_interval: std::time::Duration,
+        pub usage_spill_path: Option<std::path::PathBuf>,
+        pub filter_capture: bool,
+        pub cpu_pinning: Option<crate::pinning::Settings>,
+        pub content_filter_categories: std::collections::HashMap<String, std::path::PathBuf>,
+        pub content_filter_rule_lifetime: std::time::Duration,
+        pub clickhouse: Option<crate::clickhouse::Settings>,
+        pub flow_stream: Option<crate::flow_stream::Settings>,
+        pub grpc_api: Option<crate::grpc_api::Settings>,
+        pub http_api: Option<crate::http_api::Settings>,
+        pub syslog: Option<crate::syslog::Settings>,
+        pub netflow: Option<crate::netflow::Settings>,
+        pub remote_write: Option<crate::remote_write::Settings>,
+        pub metrics: Option<crate::metrics::Settings>,
+        pub archive: Option<crate::archive::Settings>,
+        pub quota_dns: Option<crate::quota_dns::Settings>,
+        pub address_collision: Option<crate::address_collision::Settings>,
+        pub dhcp_leases: Option<crate::leases::Settings>,
+        // Whether subscribers are identified by the link layer addresses of
+        // their devices, in addition to their static addresses.
+        pub identify_by_mac: bool,
+        pub nat_cpe: Option<crate::nat_cpe::Settings>,
+        pub presence: Option<crate::presence::Settings>,
+        pub top_destinations: Option<crate::reporter::DestinationSettings>,
+        pub trial: Option<crate::trial::Settings>,
+        pub reconciliation: Option<crate::reconciler::Settings>,
+        pub usage_forecast: Option<crate::forecast::Settings>,
+        pub fair_usage: Option<crate::fair_usage::Settings>,
+        pub drop_accounting: Option<crate::drops::Settings>,
+        pub wifi_airtime: Option<crate::airtime::Settings>,
+        pub guarantee_verification: Option<crate::guarantee::Settings>,
+        pub enforcement_verification: Option<crate::enforcer::VerificationSettings>,
+        pub enforcement_startup_grace: Option<std::time::Duration>,
+        pub balance_thresholds: Vec<i64>,
+        pub hooks: Option<crate::hooks::Settings>,
+        pub debug_capture_path: Option<std::path::PathBuf>,
+        pub privacy_key_path: Option<std::path::PathBuf>,
+        pub handoff_path: Option<std::path::PathBuf>,
+        pub policies: Vec<crate::policies::PolicyTemplate>,
+        pub nft_quota: bool,
+        // Whether policies are applied to the data plane, otherwise haulage
+        // only meters usage and logs the policy changes it would make.
+        pub enforcement: bool,
+        pub charging_classes: Vec<crate::charging::ChargingClass>,
+        pub plan_change_rules: Vec<crate::admin::PlanChangeRule>,
     }
-}
-
-#[tokio::main]
-async fn main() {
-    // Find and store build version information
-    const GIT_VERSION: &str = git_version!(
-        args = ["--long", "--all", "--always", "--dirty=-modified"],
-        fallback = "unknown"
-    );
 
-    // Parse input arguments
-    let opt = Opt::from_args();
-
-    // Setup slog terminal logging
-    let log_decorator = slog_term::PlainDecorator::new(std::io::stdout());
-    let drain = slog_term::CompactFormat::new(log_decorator).build().fuse();
+    impl Internal {
+        // The subscriber and upstream interfaces along with their namespaces.
+        pub fn enforcement_interfaces(
+            &self,
+        ) -> (crate::netns::Interface, Vec<crate::netns::Interface>) {
+            (
+                crate::netns::Interface::new(
+                    &self.subscriber_interface,
+                    self.subscriber_namespace.as_deref(),
+                ),
+                self.upstream_interfaces
+                    .iter()
+                    .map(|upstream| {
+                        crate::netns::Interface::new(upstream, self.upstream_namespace.as_deref())
+                    })
+                    .collect(),
+            )
+        }
+    }
 
-    let mut log_level = Level::Info;
-    if opt.verbose {
-        log_level = Level::Debug;
+    #[derive(Debug)]
+    pub enum Database {
+        // Boxed, since the options are far larger than a path.
+        Postgres(Box<sqlx::postgres::PgConnectOptions>),
+        Sqlite(std::path::PathBuf),
+        Mysql(Box<sqlx::mysql::MySqlConnectOptions>),
     }
 
-    let drain = slog::LevelFilter::new(drain, log_level).fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
+    #[derive(thiserror::Error, Debug)]
+    pub enum ConfigError {
+        #[error("Failed to read config file: {0}")]
+        Io(#[from] std::io::Error),
+        #[error("Failed to parse config: {0}")]
+        Parse(#[from] serde_yaml::Error),
+        #[error("{0}")]
+        Invalid(String),
+        #[error("Unsupported configuration version '{0}' specified")]
+        UnsupportedVersion(i16),
+    }
 
-    let root_log = slog::Logger::root(
-        drain.fuse(),
-        slog::o!("build" => GIT_VERSION,
-        "pkg-version" => env!("CARGO_PKG_VERSION"),
-        ),
-    );
+    // Defaults shared by both schemas.
+    const DEFAULT_STATS_LOG_INTERVAL: std::time::Duration = std::time::Duration::from_secs(300);
+    const DEFAULT_USAGE_FLUSH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+    const DEFAULT_RULE_LIFETIME: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
 
-    slog::info!(root_log, "Arguments {:?}", opt);
+    // The connection options of the database servers, which are built the
+    // same way other than their TLS settings.
+    trait ServerOptions: FromStr<Err = sqlx::Error> {
+        fn localhost() -> Self;
+        fn database(self, name: &str) -> Self;
+        fn username(self, user: &str) -> Self;
+        fn password(self, pass: &str) -> Self;
+        fn host(self, host: &str) -> Self;
+        fn port(self, port: u16) -> Self;
+    }
 
-    // Read the configuration file
-    let config_string = std::fs::read_to_string(opt.config).expect("Failed to read config file");
-    let parsed_config_version: config::Version =
-        serde_yaml::from_str(&config_string).expect("Failed to extract version from config file");
-    slog::debug!(
-        root_log,
-        "Parsed the config version {:?}",
-        parsed_config_version
-    );
-    let config_version = parsed_config_version.version.unwrap_or(1);
+    impl ServerOptions for sqlx::postgres::PgConnectOptions {
+        fn localhost() -> Self {
+            Self::new().host("localhost")
+        }
+        fn database(self, name: &str) -> Self {
+            Self::database(self, name)
+        }
+        fn username(self, user: &str) -> Self {
+            Self::username(self, user)
+        }
+        fn password(self, pass: &str) -> Self {
+            Self::password(self, pass)
+        }
+        fn host(self, host: &str) -> Self {
+            Self::host(self, host)
+        }
+        fn port(self, port: u16) -> Self {
+            Self::port(self, port)
+        }
+    }
 
-    let config = match config_version {
-        1 => {
-            let parsed_config: config::V1 =
-                serde_yaml::from_str(&config_string).expect("Failed to parse config");
-            slog::debug!(root_log, "Parsed config {:?}", parsed_config);
+    impl ServerOptions for sqlx::mysql::MySqlConnectOptions {
+        fn localhost() -> Self {
+            Self::new().host("localhost")
+        }
+        fn database(self, name: &str) -> Self {
+            Self::database(self, name)
+        }
+        fn username(self, user: &str) -> Self {
+            Self::username(self, user)
+        }
+        fn password(self, pass: &str) -> Self {
+            Self::password(self, pass)
+        }
+        fn host(self, host: &str) -> Self {
+            Self::host(self, host)
+        }
+        fn port(self, port: u16) -> Self {
+            Self::port(self, port)
+        }
+    }
 
-            // Handle interface backwards compatibility.
-            let subscriber_interface = match parsed_config.interface {
-                Some(interface) => {
-                    slog::warn!(root_log, "The 'interface' config parameter is deprecated");
-                    if parsed_config.subscriber_interface.is_some() {
-                        slog::error!(root_log, "Cannot configure 'interface' and 'subscriberInterface' at the same time");
-                        panic!("Invalid configuration!");
+    // Builds the database connection from a full URL, if any, with the
+    // individual options overriding its parts. Without a URL the database
+    // name and credentials, given with the names of their options in each
+    // schema, are required, and the host defaults to localhost.
+    fn server_options<O: ServerOptions>(
+        url: (&str, Option<&str>),
+        credentials: [(&str, Option<&str>); 3],
+        host: Option<&str>,
+        port: Option<u16>,
+    ) -> Result<O, ConfigError> {
+        let mut options = match url {
+            (option, Some(url)) => O::from_str(url)
+                .map_err(|e| ConfigError::Invalid(format!("Invalid '{}': {}", option, e)))?,
+            (_, None) => {
+                for (option, value) in credentials {
+                    if value.is_none() {
+                        return Err(ConfigError::Invalid(format!("No '{}' supplied", option)));
                     }
-                    interface
                 }
-                None => {
-                    if parsed_config.subscriber_interface.is_none() {
-                        slog::error!(root_log, "No 'subscriberInterface' supplied");
-                        panic!("Invalid configuration!");
+                O::localhost()
+            }
+        };
+        let [(_, name), (_, user), (_, pass)] = credentials;
+        if let Some(name) = name {
+            options = options.database(name);
+        }
+        if let Some(user) = user {
+            options = options.username(user);
+        }
+        if let Some(pass) = pass {
+            options = options.password(pass);
+        }
+        if let Some(host) = host {
+            options = options.host(host);
+        }
+        if let Some(port) = port {
+            options = options.port(port);
+        }
+        Ok(options)
+    }
+
+    fn db_options(
+        url: (&str, Option<&str>),
+        credentials: [(&str, Option<&str>); 3],
+        host: Option<&str>,
+        port: Option<u16>,
+        ssl_mode: Option<&SslMode>,
+        ssl_root_cert: Option<&std::path::Path>,
+    ) -> Result<sqlx::postgres::PgConnectOptions, ConfigError> {
+        let mut options: sqlx::postgres::PgConnectOptions =
+            server_options(url, credentials, host, port)?;
+        if let Some(mode) = ssl_mode {
+            options = options.ssl_mode(match mode {
+                SslMode::Disable => sqlx::postgres::PgSslMode::Disable,
+                SslMode::Allow => sqlx::postgres::PgSslMode::Allow,
+                SslMode::Prefer => sqlx::postgres::PgSslMode::Prefer,
+                SslMode::Require => sqlx::postgres::PgSslMode::Require,
+                SslMode::VerifyCa => sqlx::postgres::PgSslMode::VerifyCa,
+                SslMode::VerifyFull => sqlx::postgres::PgSslMode::VerifyFull,
+            });
+        }
+        if let Some(cert) = ssl_root_cert {
+            options = options.ssl_root_cert(cert);
+        }
+        Ok(options)
+    }
+
+    // As db_options, for MySQL. The libpq sslmode names are mapped to their
+    // nearest MySQL equivalents.
+    fn mysql_options(
+        url: (&str, Option<&str>),
+        credentials: [(&str, Option<&str>); 3],
+        host: Option<&str>,
+        port: Option<u16>,
+        ssl_mode: Option<&SslMode>,
+        ssl_root_cert: Option<&std::path::Path>,
+    ) -> Result<sqlx::mysql::MySqlConnectOptions, ConfigError> {
+        let mut options: sqlx::mysql::MySqlConnectOptions =
+            server_options(url, credentials, host, port)?;
+        if let Some(mode) = ssl_mode {
+            options = options.ssl_mode(match mode {
+                SslMode::Disable => sqlx::mysql::MySqlSslMode::Disabled,
+                SslMode::Allow | SslMode::Prefer => sqlx::mysql::MySqlSslMode::Preferred,
+                SslMode::Require => sqlx::mysql::MySqlSslMode::Required,
+                SslMode::VerifyCa => sqlx::mysql::MySqlSslMode::VerifyCa,
+                SslMode::VerifyFull => sqlx::mysql::MySqlSslMode::VerifyIdentity,
+            });
+        }
+        if let Some(cert) = ssl_root_cert {
+            options = options.ssl_ca(cert);
+        }
+        Ok(options)
+    }
+
+    fn user_subnets(subnets: OneOrMany<String>) -> Result<Vec<ipnetwork::IpNetwork>, ConfigError> {
+        subnets
+            .into_vec()
+            .iter()
+            .map(|subnet| {
+                ipnetwork::IpNetwork::from_str(subnet).map_err(|e| {
+                    ConfigError::Invalid(format!("Invalid user subnet '{}': {}", subnet, e))
+                })
+            })
+            .collect()
+    }
+
+    fn ignored_user_addresses(
+        addresses: &[String],
+    ) -> Result<std::collections::HashSet<std::net::IpAddr>, ConfigError> {
+        addresses
+            .iter()
+            .map(|a| {
+                std::net::IpAddr::from_str(a).map_err(|e| {
+                    ConfigError::Invalid(format!("Invalid ignored address '{}': {}", a, e))
+                })
+            })
+            .collect()
+    }
+
+    fn policies(
+        policies: std::collections::BTreeMap<String, Policy>,
+    ) -> Result<Vec<crate::policies::PolicyTemplate>, ConfigError> {
+        policies
+            .into_iter()
+            .map(|(name, policy)| {
+                if policy.dscp.is_some_and(|dscp| dscp > 63) {
+                    return Err(ConfigError::Invalid(format!(
+                        "Invalid DSCP value for policy '{}'",
+                        name
+                    )));
+                }
+                if policy
+                    .guaranteed_rate_kibps
+                    .is_some_and(|rate| rate == 0 || rate > i32::MAX as u32)
+                {
+                    return Err(ConfigError::Invalid(format!(
+                        "Invalid guaranteed rate for policy '{}'",
+                        name
+                    )));
+                }
+                let link = |link: Option<LinkPolicy>| match link {
+                    Some(link) => link.try_into().map_err(|e| {
+                        ConfigError::Invalid(format!(
+                            "Invalid link policy for policy '{}': {}",
+                            name, e
+                        ))
+                    }),
+                    None => Ok(crate::policies::LinkPolicy::Unlimited),
+                };
+                let local_ul = link(policy.local_uplink)?;
+                let local_dl = link(policy.local_downlink)?;
+                let backhaul_ul = link(policy.backhaul_uplink)?;
+                let backhaul_dl = link(policy.backhaul_downlink)?;
+                Ok(crate::policies::PolicyTemplate {
+                    name,
+                    local_ul,
+                    local_dl,
+                    backhaul_ul,
+                    backhaul_dl,
+                    dscp: policy.dscp,
+                    guaranteed_kibps: policy.guaranteed_rate_kibps,
+                    dns_redirect: policy.dns_redirect,
+                })
+            })
+            .collect()
+    }
+
+    fn charging_classes(
+        classes: std::collections::BTreeMap<String, ChargingClass>,
+    ) -> Result<Vec<crate::charging::ChargingClass>, ConfigError> {
+        classes
+            .into_iter()
+            .map(|(name, class)| {
+                if !class.weight.is_finite() || class.weight < 0.0 {
+                    return Err(ConfigError::Invalid(format!(
+                        "Invalid weight for charging class '{}'",
+                        name
+                    )));
+                }
+                let networks = class
+                    .networks
+                    .iter()
+                    .map(|network| {
+                        ipnetwork::IpNetwork::from_str(network).map_err(|e| {
+                            ConfigError::Invalid(format!(
+                                "Invalid network '{}' in charging class '{}': {}",
+                                network, name, e
+                            ))
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(crate::charging::ChargingClass {
+                    name,
+                    weight: class.weight,
+                    networks,
+                    domain_list: class.domain_list,
+                })
+            })
+            .collect()
+    }
+
+    fn plan_change_rules(
+        changes: Vec<PlanChange>,
+    ) -> Result<Vec<crate::admin::PlanChangeRule>, ConfigError> {
+        let mut rules = Vec::new();
+        for change in changes {
+            let balance = match (change.balance, change.factor) {
+                (BalanceRule::Keep, None) => crate::admin::BalanceRule::Keep,
+                (BalanceRule::Forfeit, None) => crate::admin::BalanceRule::Forfeit,
+                (BalanceRule::Scale, Some(factor)) if factor.is_finite() && factor >= 0.0 => {
+                    crate::admin::BalanceRule::Scale(factor)
+                }
+                _ => {
+                    return Err(ConfigError::Invalid(String::from(
+                        "'planChanges' need a nonnegative 'factor' with, and only with, the scale balance rule",
+                    )))
+                }
+            };
+            rules.push(crate::admin::PlanChangeRule {
+                from: change.from,
+                to: change.to,
+                balance,
+            });
+        }
+        Ok(rules)
+    }
+
+    fn balance_thresholds(thresholds: &[u64]) -> Result<Vec<i64>, ConfigError> {
+        thresholds
+            .iter()
+            .map(|threshold| i64::try_from(*threshold))
+            .collect::<Result<Vec<i64>, _>>()
+            .map_err(|_| ConfigError::Invalid(String::from("'balanceThresholds' are too large")))
+    }
+
+    // Without an explicit level, everything the configured exports can hold
+    // is recorded.
+    fn accounting_level(
+        level: Option<AccountingLevel>,
+        clickhouse: bool,
+    ) -> Result<crate::accounting::AccountingLevel, ConfigError> {
+        match (level, clickhouse) {
+            (None, false) | (Some(AccountingLevel::Usage), false) => {
+                Ok(crate::accounting::AccountingLevel::Usage)
+            }
+            (None, true) => Ok(crate::accounting::AccountingLevel::Domains),
+            (Some(AccountingLevel::Usage), true) => Err(ConfigError::Invalid(String::from(
+                "Cannot configure 'clickhouse' with the usage 'accountingLevel'",
+            ))),
+            // Without ClickHouse, flows are logged to the database.
+            (Some(AccountingLevel::Flows), _) => Ok(crate::accounting::AccountingLevel::Flows),
+            (Some(_), false) => Err(ConfigError::Invalid(String::from(
+                "The domains and dns 'accountingLevel' require 'clickhouse'",
+            ))),
+            (Some(AccountingLevel::Domains), true) => {
+                Ok(crate::accounting::AccountingLevel::Domains)
+            }
+            (Some(AccountingLevel::Dns), true) => Ok(crate::accounting::AccountingLevel::Dns),
+        }
+    }
+
+    // The site's local time, given either as a fixed offset or a named time
+    // zone.
+    fn site_timezone(
+        offset: Option<&str>,
+        timezone: Option<&str>,
+    ) -> Result<Option<crate::clock::SiteTimezone>, ConfigError> {
+        let site = match (offset, timezone) {
+            (Some(_), Some(_)) => {
+                return Err(ConfigError::Invalid(String::from(
+                    "Only one of 'siteUtcOffset' and 'siteTimezone' can be set",
+                )))
+            }
+            (Some(offset), None) => {
+                Some(crate::clock::parse_utc_offset(offset).map(crate::clock::SiteTimezone::Fixed))
+            }
+            (None, Some(timezone)) => Some(timezone.parse()),
+            (None, None) => None,
+        };
+        site.transpose().map_err(ConfigError::Invalid)
+    }
+
+    // The categories and rule lifetime of the content filter, which has no
+    // categories when unconfigured.
+    fn content_filter(
+        filter: Option<ContentFilter>,
+    ) -> (
+        std::collections::HashMap<String, std::path::PathBuf>,
+        std::time::Duration,
+    ) {
+        match filter {
+            Some(filter) => (
+                filter.categories,
+                filter.rule_lifetime.unwrap_or(DEFAULT_RULE_LIFETIME),
+            ),
+            None => (Default::default(), DEFAULT_RULE_LIFETIME),
+        }
+    }
+
+    impl Syslog {
+        fn settings(self) -> Result<crate::syslog::Settings, ConfigError> {
+            let facility = self.facility.as_deref().unwrap_or("local0");
+            let facility = crate::syslog::facility_code(facility).ok_or_else(|| {
+                ConfigError::Invalid(format!("Invalid syslog facility '{}'", facility))
+            })?;
+            Ok(crate::syslog::Settings {
+                address: self.address,
+                transport: match self.transport {
+                    None | Some(SyslogTransport::Udp) => crate::syslog::Transport::Udp,
+                    Some(SyslogTransport::Tcp) => crate::syslog::Transport::Tcp,
+                    Some(SyslogTransport::Tls) => crate::syslog::Transport::Tls,
+                },
+                facility,
+                ca_path: self.ca_path,
+            })
+        }
+    }
+
+    impl RemoteWrite {
+        // Intervals are exported once they have settled, i.e. been written.
+        fn settings(self, settle: std::time::Duration) -> crate::remote_write::Settings {
+            crate::remote_write::Settings {
+                url: self.url,
+                user: self.user,
+                password: self.password,
+                instance: self.instance.unwrap_or_else(|| {
+                    std::fs::read_to_string("/proc/sys/kernel/hostname")
+                        .map(|hostname| hostname.trim().to_owned())
+                        .unwrap_or_else(|_| String::from("haulage"))
+                }),
+                interval: self.interval.unwrap_or(std::time::Duration::from_secs(60)),
+                subscriber_metrics: self.subscriber_metrics.unwrap_or(true),
+                settle,
+            }
+        }
+    }
+
+    impl Archive {
+        fn settings(self) -> Result<crate::archive::Settings, ConfigError> {
+            let destination = match (self.directory, self.s3) {
+                (Some(directory), None) => crate::archive::Destination::Directory(directory),
+                (None, Some(s3)) => {
+                    if reqwest::Url::parse(&s3.endpoint).is_err() {
+                        return Err(ConfigError::Invalid(format!(
+                            "Invalid archive endpoint '{}'",
+                            s3.endpoint
+                        )));
+                    }
+                    crate::archive::Destination::S3(crate::archive::S3Settings {
+                        endpoint: s3.endpoint,
+                        bucket: s3.bucket,
+                        region: s3.region.unwrap_or_else(|| String::from("us-east-1")),
+                        access_key: s3.access_key,
+                        secret_key: s3.secret_key,
+                        prefix: s3.prefix.unwrap_or_default(),
+                    })
+                }
+                _ => {
+                    return Err(ConfigError::Invalid(String::from(
+                        "'archive' requires exactly one of 'directory' or 's3'",
+                    )))
+                }
+            };
+            Ok(crate::archive::Settings {
+                max_age: self.max_age,
+                interval: self
+                    .interval
+                    .unwrap_or(std::time::Duration::from_secs(24 * 60 * 60)),
+                destination,
+            })
+        }
+    }
+
+    impl FairUsage {
+        fn settings(self) -> Result<crate::fair_usage::Settings, ConfigError> {
+            let congestion_threshold = self.congestion_threshold.unwrap_or(0.8);
+            let heaviest_share = self.heaviest_share.unwrap_or(0.1);
+            let is_fraction = |value: f64| value > 0.0 && value <= 1.0;
+            if self.capacity_kbps == 0
+                || !is_fraction(congestion_threshold)
+                || !is_fraction(heaviest_share)
+            {
+                return Err(ConfigError::Invalid(String::from(
+                    "'fairUsage' requires a nonzero 'capacityKbps' and a 'congestionThreshold' and 'heaviestShare' between 0 and 1",
+                )));
+            }
+            Ok(crate::fair_usage::Settings {
+                policy: self.policy,
+                capacity_kbps: self.capacity_kbps,
+                congestion_threshold,
+                heaviest_share,
+                usage_window: self
+                    .usage_window
+                    .unwrap_or(std::time::Duration::from_secs(15 * 60)),
+                restore_after: self
+                    .restore_after
+                    .unwrap_or(std::time::Duration::from_secs(5 * 60)),
+                interval: self.interval.unwrap_or(std::time::Duration::from_secs(30)),
+            })
+        }
+    }
+
+    impl UsageForecast {
+        fn settings(self) -> Result<crate::forecast::Settings, ConfigError> {
+            let smoothing = self.smoothing.unwrap_or(0.3);
+            if !(smoothing > 0.0 && smoothing <= 1.0) {
+                return Err(ConfigError::Invalid(String::from(
+                    "'usageForecast' 'smoothing' must be greater than 0 and at most 1",
+                )));
+            }
+            let days = self.days.unwrap_or(28);
+            if days == 0 {
+                return Err(ConfigError::Invalid(String::from(
+                    "'usageForecast' 'days' must be at least 1",
+                )));
+            }
+            Ok(crate::forecast::Settings {
+                interval: self
+                    .interval
+                    .unwrap_or(std::time::Duration::from_secs(60 * 60)),
+                days,
+                smoothing,
+            })
+        }
+    }
+
+    impl EnforcementVerification {
+        fn settings(self) -> Result<crate::enforcer::VerificationSettings, ConfigError> {
+            let tolerance = self.tolerance.unwrap_or(0.2);
+            if !(0.0..1.0).contains(&tolerance) {
+                return Err(ConfigError::Invalid(String::from(
+                    "'enforcementVerification' 'tolerance' must be at least 0 and less than 1",
+                )));
+            }
+            Ok(crate::enforcer::VerificationSettings {
+                window: self.window.unwrap_or(std::time::Duration::from_secs(30)),
+                tolerance,
+            })
+        }
+    }
+
+    impl GuaranteeVerification {
+        fn settings(
+            self,
+            timezone: Option<crate::clock::SiteTimezone>,
+            settle: std::time::Duration,
+        ) -> Result<crate::guarantee::Settings, ConfigError> {
+            let busy_hours = self.busy_hours.parse().map_err(ConfigError::Invalid)?;
+            let days = self.days.unwrap_or(7);
+            let chronic_days = self.chronic_days.unwrap_or(4);
+            if chronic_days == 0 || chronic_days > days {
+                return Err(ConfigError::Invalid(String::from(
+                    "'guaranteeVerification' requires a nonzero 'chronicDays' no greater than 'days'",
+                )));
+            }
+            Ok(crate::guarantee::Settings {
+                busy_hours,
+                timezone,
+                days,
+                chronic_days,
+                min_active_bytes: self.min_active_bytes.unwrap_or(10_000_000),
+                settle,
+                interval: self
+                    .interval
+                    .unwrap_or(std::time::Duration::from_secs(60 * 60)),
+            })
+        }
+    }
+
+    impl WifiAirtime {
+        fn settings(self) -> Result<crate::airtime::Settings, ConfigError> {
+            if self.hostapd_sockets.is_empty() {
+                return Err(ConfigError::Invalid(String::from(
+                    "'wifiAirtime' requires at least one of 'hostapdSockets'",
+                )));
+            }
+            Ok(crate::airtime::Settings {
+                hostapd_sockets: self.hostapd_sockets,
+                apply_weights: self.apply_weights.unwrap_or(false),
+            })
+        }
+    }
+
+    impl TopDestinations {
+        fn settings(
+            self,
+            user_log_interval: std::time::Duration,
+        ) -> Result<crate::reporter::DestinationSettings, ConfigError> {
+            if self.count == Some(0) || self.interval.is_some_and(|i| i.is_zero()) {
+                return Err(ConfigError::Invalid(String::from(
+                    "'topDestinations' count and interval must be positive",
+                )));
+            }
+            Ok(crate::reporter::DestinationSettings {
+                count: self.count.unwrap_or(20),
+                interval: self.interval.unwrap_or(user_log_interval),
+            })
+        }
+    }
+
+    impl Trial {
+        fn settings(self) -> Result<crate::trial::Settings, ConfigError> {
+            Ok(crate::trial::Settings {
+                policy: self.policy,
+                exhausted_policy: self.exhausted_policy,
+                allowance: i64::try_from(self.allowance).map_err(|_| {
+                    ConfigError::Invalid(String::from("'trial' allowance is too large"))
+                })?,
+                lifetime: self
+                    .lifetime
+                    .unwrap_or(std::time::Duration::from_secs(24 * 60 * 60)),
+            })
+        }
+    }
+
+    impl CpuPinning {
+        fn settings(self) -> Result<crate::pinning::Settings, ConfigError> {
+            if self
+                .capture_cores
+                .iter()
+                .chain(self.parser_cores.iter())
+                .any(|core| *core > crate::pinning::MAX_CORE)
+            {
+                return Err(ConfigError::Invalid(String::from(
+                    "'cpuPinning' cores are out of range",
+                )));
+            }
+            if let Some(priority) = self.realtime_priority {
+                if !(1..=99).contains(&priority) {
+                    return Err(ConfigError::Invalid(String::from(
+                        "'realtimePriority' must be from 1 to 99",
+                    )));
+                }
+            }
+            Ok(crate::pinning::Settings {
+                parser_threads: self
+                    .parser_threads
+                    .unwrap_or(self.parser_cores.len())
+                    .max(1),
+                capture_cores: self.capture_cores,
+                parser_cores: self.parser_cores,
+                realtime_priority: self.realtime_priority,
+            })
+        }
+    }
+
+    impl Hooks {
+        fn settings(self) -> Result<crate::hooks::Settings, ConfigError> {
+            let mut commands = Vec::new();
+            for hook in self.commands {
+                if let Some(event) = hook
+                    .events
+                    .iter()
+                    .find(|event| !crate::events::is_known_kind(event))
+                {
+                    return Err(ConfigError::Invalid(format!(
+                        "Unknown hook event '{}'",
+                        event
+                    )));
+                }
+                commands.push(crate::hooks::Hook {
+                    events: hook.events,
+                    command: hook.command,
+                    args: hook.args,
+                    timeout: hook.timeout.unwrap_or(std::time::Duration::from_secs(30)),
+                });
+            }
+            Ok(crate::hooks::Settings {
+                hooks: commands,
+                poll_interval: self
+                    .poll_interval
+                    .unwrap_or(std::time::Duration::from_secs(2)),
+            })
+        }
+    }
+
+    impl Clickhouse {
+        fn settings(
+            self,
+            level: crate::accounting::AccountingLevel,
+        ) -> crate::clickhouse::Settings {
+            crate::clickhouse::Settings {
+                url: self.url,
+                database: self.database.unwrap_or_else(|| String::from("haulage")),
+                user: self.user,
+                password: self.password,
+                batch_size: self.batch_size.unwrap_or(10000),
+                flush_interval: self
+                    .flush_interval
+                    .unwrap_or(std::time::Duration::from_secs(10)),
+                level,
+            }
+        }
+    }
+
+    impl Netflow {
+        fn settings(self) -> crate::netflow::Settings {
+            crate::netflow::Settings {
+                collector: self.collector,
+                format: match self.format {
+                    Some(NetflowFormat::NetflowV9) => crate::netflow::Format::NetflowV9,
+                    Some(NetflowFormat::Ipfix) | None => crate::netflow::Format::Ipfix,
+                },
+                active_timeout: self
+                    .active_timeout
+                    .unwrap_or(std::time::Duration::from_secs(60)),
+            }
+        }
+    }
+
+    impl QuotaDns {
+        fn settings(self) -> crate::quota_dns::Settings {
+            crate::quota_dns::Settings {
+                listen_address: self.listen_address,
+                hostname: crate::content_filter::normalize_domain(
+                    self.hostname.as_deref().unwrap_or("quota.haulage.local"),
+                ),
+                redirect_address: self.redirect_address,
+            }
+        }
+    }
+
+    impl DhcpLeases {
+        fn settings(self, default_poll_interval: std::time::Duration) -> crate::leases::Settings {
+            crate::leases::Settings {
+                path: self.path,
+                format: match self.format {
+                    LeaseFormat::Dnsmasq => crate::leases::LeaseFormat::Dnsmasq,
+                    LeaseFormat::Kea => crate::leases::LeaseFormat::Kea,
+                },
+                identify_by: match self.identify_by {
+                    Some(LeaseIdentity::ClientId) => crate::leases::Identity::ClientId,
+                    Some(LeaseIdentity::Hostname) | None => crate::leases::Identity::Hostname,
+                },
+                poll_interval: self.poll_interval.unwrap_or(default_poll_interval),
+            }
+        }
+    }
+
+    impl NatCpe {
+        fn settings(self) -> Result<crate::nat_cpe::Settings, ConfigError> {
+            if self.port_block_size == Some(0) {
+                return Err(ConfigError::Invalid(String::from(
+                    "'natCpe' requires a nonzero 'portBlockSize'",
+                )));
+            }
+            Ok(crate::nat_cpe::Settings {
+                port_block_size: self.port_block_size,
+            })
+        }
+    }
+
+    impl FlowStream {
+        fn settings(self) -> crate::flow_stream::Settings {
+            crate::flow_stream::Settings {
+                listen: self.listen,
+                require_api_key: self.require_api_key.unwrap_or(true),
+            }
+        }
+    }
+
+    impl GrpcApi {
+        fn settings(self) -> crate::grpc_api::Settings {
+            crate::grpc_api::Settings {
+                listen: self.listen,
+                require_api_key: self.require_api_key.unwrap_or(true),
+            }
+        }
+    }
+
+    impl HttpApi {
+        fn settings(self) -> crate::http_api::Settings {
+            crate::http_api::Settings {
+                listen: self.listen,
+                require_api_key: self.require_api_key.unwrap_or(true),
+            }
+        }
+    }
+
+    impl Metrics {
+        fn settings(self) -> crate::metrics::Settings {
+            crate::metrics::Settings {
+                listen: self.listen,
+                subscriber_metrics: self.subscriber_metrics.unwrap_or(true),
+            }
+        }
+    }
+
+    impl AddressCollision {
+        fn settings(self) -> crate::address_collision::Settings {
+            crate::address_collision::Settings {
+                window: self
+                    .window
+                    .unwrap_or(std::time::Duration::from_secs(10 * 60)),
+                block: self.block.unwrap_or(false),
+            }
+        }
+    }
+
+    impl Presence {
+        fn settings(self) -> crate::presence::Settings {
+            crate::presence::Settings {
+                from_traffic: self.from_traffic.unwrap_or(true),
+            }
+        }
+    }
+
+    impl Reconciliation {
+        fn settings(self) -> crate::reconciler::Settings {
+            crate::reconciler::Settings {
+                interval: self.interval,
+                tolerance: self.tolerance.unwrap_or(0.05),
+                interface_tolerance: self.interface_tolerance.unwrap_or(0.2),
+            }
+        }
+    }
+
+    impl DropAccounting {
+        fn settings(self) -> crate::drops::Settings {
+            crate::drops::Settings {
+                refund: self.refund_dropped_bytes.unwrap_or(false),
+            }
+        }
+    }
+
+    impl V1 {
+        fn into_internal(self, log: &slog::Logger) -> Result<Internal, ConfigError> {
+            let custom = self.custom;
+            let database =
+                match custom.db_backend.unwrap_or_default() {
+                    DbBackend::Postgres => Database::Postgres(Box::new(db_options(
+                        ("dbUrl", custom.db_url.as_deref()),
+                        [
+                            ("dbLocation", custom.db_location.as_deref()),
+                            ("dbUser", custom.db_user.as_deref()),
+                            ("dbPass", custom.db_pass.as_deref()),
+                        ],
+                        custom.db_host.as_deref(),
+                        custom.db_port,
+                        custom.db_ssl_mode.as_ref(),
+                        custom.db_ssl_root_cert.as_deref(),
+                    )?)),
+                    DbBackend::Sqlite => Database::Sqlite(custom.db_path.ok_or_else(|| {
+                        ConfigError::Invalid(String::from("No 'dbPath' supplied"))
+                    })?),
+                    DbBackend::Mysql => Database::Mysql(Box::new(mysql_options(
+                        ("dbUrl", custom.db_url.as_deref()),
+                        [
+                            ("dbLocation", custom.db_location.as_deref()),
+                            ("dbUser", custom.db_user.as_deref()),
+                            ("dbPass", custom.db_pass.as_deref()),
+                        ],
+                        custom.db_host.as_deref(),
+                        custom.db_port,
+                        custom.db_ssl_mode.as_ref(),
+                        custom.db_ssl_root_cert.as_deref(),
+                    )?)),
+                };
+
+            // Handle interface backwards compatibility.
+            let subscriber_interface = match self.interface {
+                Some(interface) => {
+                    slog::warn!(log, "The 'interface' config parameter is deprecated");
+                    if self.subscriber_interface.is_some() {
+                        return Err(ConfigError::Invalid(String::from(
+                            "Cannot configure 'interface' and 'subscriberInterface' at the same time",
+                        )));
+                    }
+                    interface
+                }
+                None => self.subscriber_interface.ok_or_else(|| {
+                    ConfigError::Invalid(String::from("No 'subscriberInterface' supplied"))
+                })?,
+            };
+            let upstream_interfaces = self
+                .upstream_interface
+                .map(OneOrMany::into_vec)
+                .unwrap_or_default();
+            if upstream_interfaces.is_empty() {
+                slog::warn!(log, "No 'upstreamInterface' configured, but will be required in a future version of haulage");
+            }
+
+            let usage_flush_interval = custom
+                .usage_flush_interval
+                .unwrap_or(DEFAULT_USAGE_FLUSH_INTERVAL);
+            let settle = self.user_log_interval + usage_flush_interval;
+            let accounting_level =
+                accounting_level(self.accounting_level, custom.clickhouse.is_some())?;
+            let site_timezone = site_timezone(
+                self.site_utc_offset.as_deref(),
+                self.site_timezone.as_deref(),
+            )?;
+            let (content_filter_categories, content_filter_rule_lifetime) =
+                content_filter(custom.content_filter);
+
+            Ok(Internal {
+                database,
+                db_auto_upgrade: custom.db_auto_upgrade.unwrap_or(true),
+                flow_log_interval: self.flow_log_interval,
+                user_log_interval: self.user_log_interval,
+                align_log_intervals: self.align_log_intervals.unwrap_or(false),
+                site_timezone,
+                accounting_level,
+                reenable_poll_interval: custom.reenable_poll_interval,
+                subscriber_interface,
+                upstream_interfaces,
+                subscriber_namespace: self.subscriber_namespace,
+                upstream_namespace: self.upstream_namespace,
+                user_subnets: user_subnets(self.user_subnet)?,
+                ignored_user_addresses: ignored_user_addresses(&self.ignored_user_addresses)?,
+                group_ipv6_prefixes: self.group_ipv6_prefixes,
+                stats_log_interval: custom
+                    .stats_log_interval
+                    .unwrap_or(DEFAULT_STATS_LOG_INTERVAL),
+                stats_export_path: custom.stats_export_path,
+                control_socket_path: custom.control_socket_path,
+                control_require_api_key: custom.control_require_api_key.unwrap_or(false),
+                usage_flush_interval,
+                usage_spill_path: custom.usage_spill_path,
+                filter_capture: custom.filter_capture.unwrap_or(false),
+                cpu_pinning: custom.cpu_pinning.map(CpuPinning::settings).transpose()?,
+                content_filter_categories,
+                content_filter_rule_lifetime,
+                clickhouse: custom
+                    .clickhouse
+                    .map(|clickhouse| clickhouse.settings(accounting_level)),
+                flow_stream: custom.flow_stream.map(FlowStream::settings),
+                grpc_api: custom.grpc_api.map(GrpcApi::settings),
+                http_api: custom.http_api.map(HttpApi::settings),
+                syslog: custom.syslog.map(Syslog::settings).transpose()?,
+                netflow: custom.netflow.map(Netflow::settings),
+                remote_write: custom
+                    .remote_write
+                    .map(|remote_write| remote_write.settings(settle)),
+                metrics: custom.metrics.map(Metrics::settings),
+                archive: custom.archive.map(Archive::settings).transpose()?,
+                quota_dns: custom.quota_dns.map(QuotaDns::settings),
+                address_collision: custom.address_collision.map(AddressCollision::settings),
+                dhcp_leases: custom
+                    .dhcp_leases
+                    .map(|leases| leases.settings(custom.reenable_poll_interval)),
+                identify_by_mac: custom.identify_by_mac.unwrap_or(false),
+                nat_cpe: custom.nat_cpe.map(NatCpe::settings).transpose()?,
+                presence: custom.presence.map(Presence::settings),
+                top_destinations: custom
+                    .top_destinations
+                    .map(|top| top.settings(self.user_log_interval))
+                    .transpose()?,
+                trial: custom.trial.map(Trial::settings).transpose()?,
+                reconciliation: custom.reconciliation.map(Reconciliation::settings),
+                usage_forecast: custom
+                    .usage_forecast
+                    .map(UsageForecast::settings)
+                    .transpose()?,
+                fair_usage: custom.fair_usage.map(FairUsage::settings).transpose()?,
+                drop_accounting: custom.drop_accounting.map(DropAccounting::settings),
+                wifi_airtime: custom.wifi_airtime.map(WifiAirtime::settings).transpose()?,
+                guarantee_verification: custom
+                    .guarantee_verification
+                    .map(|verification| verification.settings(site_timezone, settle))
+                    .transpose()?,
+                enforcement_verification: custom
+                    .enforcement_verification
+                    .map(EnforcementVerification::settings)
+                    .transpose()?,
+                enforcement_startup_grace: custom.enforcement_startup_grace,
+                balance_thresholds: balance_thresholds(&custom.balance_thresholds)?,
+                hooks: custom.hooks.map(Hooks::settings).transpose()?,
+                debug_capture_path: custom.debug_capture_path,
+                privacy_key_path: custom.privacy_key_path,
+                handoff_path: custom.handoff_path,
+                policies: policies(custom.policies)?,
+                nft_quota: custom.nft_quota.unwrap_or(false),
+                enforcement: custom.enforcement != Some(Enforcement::Disabled),
+                charging_classes: charging_classes(custom.charging_classes)?,
+                plan_change_rules: plan_change_rules(custom.plan_changes)?,
+            })
+        }
+    }
+
+    impl V2 {
+        fn into_internal(self) -> Result<Internal, ConfigError> {
+            let V2 {
+                _version: _,
+                database,
+                capture,
+                enforcement,
+                reporting,
+                control,
+                privacy_key_path,
+                handoff_path,
+            } = self;
+            let db_auto_upgrade = database.auto_upgrade.unwrap_or(true);
+            let database = match database.backend.unwrap_or_default() {
+                DbBackend::Postgres => Database::Postgres(Box::new(db_options(
+                    ("database.url", database.url.as_deref()),
+                    [
+                        ("database.name", database.name.as_deref()),
+                        ("database.user", database.user.as_deref()),
+                        ("database.pass", database.pass.as_deref()),
+                    ],
+                    database.host.as_deref(),
+                    database.port,
+                    database.ssl_mode.as_ref(),
+                    database.ssl_root_cert.as_deref(),
+                )?)),
+                DbBackend::Sqlite => Database::Sqlite(database.path.ok_or_else(|| {
+                    ConfigError::Invalid(String::from("No 'database.path' supplied"))
+                })?),
+                DbBackend::Mysql => Database::Mysql(Box::new(mysql_options(
+                    ("database.url", database.url.as_deref()),
+                    [
+                        ("database.name", database.name.as_deref()),
+                        ("database.user", database.user.as_deref()),
+                        ("database.pass", database.pass.as_deref()),
+                    ],
+                    database.host.as_deref(),
+                    database.port,
+                    database.ssl_mode.as_ref(),
+                    database.ssl_root_cert.as_deref(),
+                )?)),
+            };
+
+            let usage_flush_interval = reporting
+                .usage_flush_interval
+                .unwrap_or(DEFAULT_USAGE_FLUSH_INTERVAL);
+            let settle = reporting.user_log_interval + usage_flush_interval;
+            let accounting_level =
+                accounting_level(reporting.accounting_level, reporting.clickhouse.is_some())?;
+            let site_timezone = site_timezone(
+                reporting.site_utc_offset.as_deref(),
+                reporting.site_timezone.as_deref(),
+            )?;
+            let (content_filter_categories, content_filter_rule_lifetime) =
+                content_filter(enforcement.content_filter);
+
+            Ok(Internal {
+                database,
+                db_auto_upgrade,
+                flow_log_interval: reporting.flow_log_interval,
+                user_log_interval: reporting.user_log_interval,
+                align_log_intervals: reporting.align_log_intervals.unwrap_or(false),
+                site_timezone,
+                accounting_level,
+                reenable_poll_interval: enforcement.reenable_poll_interval,
+                subscriber_interface: capture.subscriber_interface,
+                upstream_interfaces: capture.upstream_interface.into_vec(),
+                subscriber_namespace: capture.subscriber_namespace,
+                upstream_namespace: capture.upstream_namespace,
+                user_subnets: user_subnets(capture.user_subnet)?,
+                ignored_user_addresses: ignored_user_addresses(&capture.ignored_user_addresses)?,
+                group_ipv6_prefixes: capture.group_ipv6_prefixes,
+                stats_log_interval: reporting
+                    .stats_log_interval
+                    .unwrap_or(DEFAULT_STATS_LOG_INTERVAL),
+                stats_export_path: reporting.stats_export_path,
+                control_socket_path: control.socket_path,
+                control_require_api_key: control.require_api_key.unwrap_or(false),
+                usage_flush_interval,
+                usage_spill_path: reporting.usage_spill_path,
+                filter_capture: capture.filter.unwrap_or(false),
+                cpu_pinning: capture.cpu_pinning.map(CpuPinning::settings).transpose()?,
+                content_filter_categories,
+                content_filter_rule_lifetime,
+                clickhouse: reporting
+                    .clickhouse
+                    .map(|clickhouse| clickhouse.settings(accounting_level)),
+                flow_stream: reporting.flow_stream.map(FlowStream::settings),
+                grpc_api: control.grpc_api.map(GrpcApi::settings),
+                http_api: control.http_api.map(HttpApi::settings),
+                syslog: reporting.syslog.map(Syslog::settings).transpose()?,
+                netflow: reporting.netflow.map(Netflow::settings),
+                remote_write: reporting
+                    .remote_write
+                    .map(|remote_write| remote_write.settings(settle)),
+                metrics: reporting.metrics.map(Metrics::settings),
+                archive: reporting.archive.map(Archive::settings).transpose()?,
+                quota_dns: enforcement.quota_dns.map(QuotaDns::settings),
+                address_collision: enforcement
+                    .address_collision
+                    .map(AddressCollision::settings),
+                dhcp_leases: capture
+                    .dhcp_leases
+                    .map(|leases| leases.settings(enforcement.reenable_poll_interval)),
+                identify_by_mac: capture.identify_by_mac.unwrap_or(false),
+                nat_cpe: reporting.nat_cpe.map(NatCpe::settings).transpose()?,
+                presence: reporting.presence.map(Presence::settings),
+                top_destinations: reporting
+                    .top_destinations
+                    .map(|top| top.settings(reporting.user_log_interval))
+                    .transpose()?,
+                trial: enforcement.trial.map(Trial::settings).transpose()?,
+                reconciliation: reporting.reconciliation.map(Reconciliation::settings),
+                usage_forecast: reporting
+                    .usage_forecast
+                    .map(UsageForecast::settings)
+                    .transpose()?,
+                fair_usage: enforcement
+                    .fair_usage
+                    .map(FairUsage::settings)
+                    .transpose()?,
+                drop_accounting: enforcement.drop_accounting.map(DropAccounting::settings),
+                wifi_airtime: reporting
+                    .wifi_airtime
+                    .map(WifiAirtime::settings)
+                    .transpose()?,
+                guarantee_verification: reporting
+                    .guarantee_verification
+                    .map(|verification| verification.settings(site_timezone, settle))
+                    .transpose()?,
+                enforcement_verification: enforcement
+                    .enforcement_verification
+                    .map(EnforcementVerification::settings)
+                    .transpose()?,
+                enforcement_startup_grace: enforcement.enforcement_startup_grace,
+                balance_thresholds: balance_thresholds(&enforcement.balance_thresholds)?,
+                hooks: control.hooks.map(Hooks::settings).transpose()?,
+                debug_capture_path: capture.debug_capture_path,
+                privacy_key_path,
+                handoff_path,
+                policies: policies(enforcement.policies)?,
+                nft_quota: enforcement.nft_quota.unwrap_or(false),
+                enforcement: enforcement.mode != Some(Enforcement::Disabled),
+                charging_classes: charging_classes(enforcement.charging_classes)?,
+                plan_change_rules: plan_change_rules(enforcement.plan_changes)?,
+            })
+        }
+    }
+
+    // Rejects options which cannot be combined, however they were written.
+    fn check_combinations(config: &Internal) -> Result<(), ConfigError> {
+        // Kernel quotas count raw bytes, so would cut off subscribers before
+        // their weighted balance is exhausted.
+        if config.nft_quota && !config.charging_classes.is_empty() {
+            return Err(ConfigError::Invalid(String::from(
+                "Cannot configure 'nftQuota' and 'chargingClasses' at the same time",
+            )));
+        }
+        // Both keep the leased addresses in static_ips, so would remove each
+        // other's.
+        if config.identify_by_mac && config.dhcp_leases.is_some() {
+            return Err(ConfigError::Invalid(String::from(
+                "Cannot configure 'identifyByMac' and 'dhcpLeases' at the same time",
+            )));
+        }
+        // These features act on or read from the enforcement state.
+        if !config.enforcement {
+            for (option, configured) in [
+                ("nftQuota", config.nft_quota),
+                (
+                    "contentFilter",
+                    !config.content_filter_categories.is_empty(),
+                ),
+                ("dropAccounting", config.drop_accounting.is_some()),
+            ] {
+                if configured {
+                    return Err(ConfigError::Invalid(format!(
+                        "Cannot configure '{}' with 'enforcement: disabled'",
+                        option
+                    )));
+                }
+            }
+        }
+        // Only metering, accounting, and enforcement run on SQLite and MySQL,
+        // the remaining subsystems use Postgres directly.
+        let backend = match config.database {
+            Database::Postgres(_) => None,
+            Database::Sqlite(_) => Some("sqlite"),
+            Database::Mysql(_) => Some("mysql"),
+        };
+        if let Some(backend) = backend {
+            for (option, configured) in [
+                ("controlSocketPath", config.control_socket_path.is_some()),
+                ("grpcApi", config.grpc_api.is_some()),
+                ("httpApi", config.http_api.is_some()),
+                ("flowStream", config.flow_stream.is_some()),
+                ("syslog", config.syslog.is_some()),
+                ("remoteWrite", config.remote_write.is_some()),
+                ("archive", config.archive.is_some()),
+                ("quotaDns", config.quota_dns.is_some()),
+                ("addressCollision", config.address_collision.is_some()),
+                ("dhcpLeases", config.dhcp_leases.is_some()),
+                ("identifyByMac", config.identify_by_mac),
+                ("natCpe", config.nat_cpe.is_some()),
+                ("presence", config.presence.is_some()),
+                ("trial", config.trial.is_some()),
+                ("reconciliation", config.reconciliation.is_some()),
+                ("usageForecast", config.usage_forecast.is_some()),
+                ("fairUsage", config.fair_usage.is_some()),
+                ("dropAccounting", config.drop_accounting.is_some()),
+                ("wifiAirtime", config.wifi_airtime.is_some()),
+                (
+                    "guaranteeVerification",
+                    config.guarantee_verification.is_some(),
+                ),
+                ("hooks", config.hooks.is_some()),
+                (
+                    "contentFilter",
+                    !config.content_filter_categories.is_empty(),
+                ),
+                (
+                    "accountingLevel: flows",
+                    config.clickhouse.is_none()
+                        && config.accounting_level == crate::accounting::AccountingLevel::Flows,
+                ),
+            ] {
+                if configured {
+                    return Err(ConfigError::Invalid(format!(
+                        "Cannot configure '{}' with 'dbBackend: {}'",
+                        option, backend
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Reads and validates the configuration file. Used both at startup and
+    // when reloading the configuration of a running instance.
+    pub fn load(path: &std::path::Path, log: &slog::Logger) -> Result<Internal, ConfigError> {
+        let config_string = std::fs::read_to_string(path)?;
+        let parsed_config_version: Version = serde_yaml::from_str(&config_string)?;
+        slog::debug!(log, "Parsed the config version {:?}", parsed_config_version);
+        let config_version = parsed_config_version.version.unwrap_or(1);
+
+        let config = match config_version {
+            1 => {
+                let parsed_config: V1 = serde_yaml::from_str(&config_string)?;
+                slog::debug!(log, "Parsed config {:?}", parsed_config);
+                parsed_config.into_internal(log)?
+            }
+            2 => {
+                let parsed_config: V2 = serde_yaml::from_str(&config_string)?;
+                slog::debug!(log, "Parsed config {:?}", parsed_config);
+                parsed_config.into_internal()?
+            }
+            _ => return Err(ConfigError::UnsupportedVersion(config_version)),
+        };
+        check_combinations(&config)?;
+        Ok(config)
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    // Find and store build version information
+    const GIT_VERSION: &str = git_version!(
+        args = ["--long", "--all", "--always", "--dirty=-modified"],
+        fallback = "unknown"
+    );
+
+    // Parse input arguments
+    let opt = Opt::from_args();
+
+    // Setup slog terminal logging
+    let log_decorator = slog_term::PlainDecorator::new(std::io::stdout());
+    let drain = slog_term::CompactFormat::new(log_decorator).build().fuse();
+
+    let mut log_level = Level::Info;
+    if opt.verbose {
+        log_level = Level::Debug;
+    }
+
+    let drain = slog::LevelFilter::new(drain, log_level).fuse();
+    let drain = slog_async::Async::new(drain).build().fuse();
+
+    let root_log = slog::Logger::root(
+        drain.fuse(),
+        slog::o!("build" => GIT_VERSION,
+        "pkg-version" => env!("CARGO_PKG_VERSION"),
+        ),
+    );
+
+    slog::info!(root_log, "Arguments {:?}", opt);
+
+    // The configuration is checked before loading it, since loading an invalid
+    // configuration aborts.
+    if let Some(Command::CheckConfig(check_command)) = &opt.command {
+        let check_log = root_log.new(o!("subsystem" => "check_config"));
+        if !check_config::run(check_command, &opt.config, &check_log).await {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Read the configuration file
+    let config = config::load(&opt.config, &root_log).unwrap_or_else(|e| {
+        slog::error!(root_log, "Failed to load configuration"; "error" => e.to_string());
+        panic!("Invalid configuration!");
+    });
+
+    let config = std::sync::Arc::new(config);
+
+    // The self test runs before connecting, so that an unreachable database is
+    // reported as a failure rather than aborting.
+    if let Some(Command::SelfTest) = opt.command {
+        let self_test_log = root_log.new(o!("subsystem" => "self_test"));
+        if !self_test::run(&config, &self_test_log).await {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Connect to backing storage database
+    let (storage, db_pool): (
+        std::sync::Arc<dyn storage::Storage>,
+        Option<std::sync::Arc<sqlx::PgPool>>,
+    ) = match &config.database {
+        config::Database::Postgres(options) => {
+            let db_pool =
+                match connect_postgres(options, &opt, config.db_auto_upgrade, &root_log).await {
+                    Some(db_pool) => std::sync::Arc::new(db_pool),
+                    None => return,
+                };
+            (
+                std::sync::Arc::new(storage::Postgres::new(std::sync::Arc::clone(&db_pool))),
+                Some(db_pool),
+            )
+        }
+        config::Database::Sqlite(path) => {
+            let sqlite = storage::Sqlite::open(path, &opt.migration_directory.join("sqlite"))
+                .await
+                .unwrap_or_else(|e| {
+                    slog::error!(root_log, "Failed to open database"; "path" => path.display().to_string(), "error" => e.to_string());
+                    panic!("Cannot continue without a database");
+                });
+            slog::info!(root_log, "Opened database"; "path" => path.display().to_string());
+            if opt.migrate {
+                slog::info!(root_log, "Migrations complete, exiting haulage.");
+                return;
+            }
+            (std::sync::Arc::new(sqlite), None)
+        }
+        config::Database::Mysql(options) => {
+            let auto_upgrade = config.db_auto_upgrade || opt.migrate;
+            let mysql = storage::Mysql::connect(
+                options,
+                &opt.migration_directory.join("mysql"),
+                auto_upgrade,
+            )
+            .await
+            .unwrap_or_else(|e| {
+                slog::error!(root_log, "Failed to connect to database"; "error" => e.to_string());
+                panic!("Cannot continue without a database");
+            });
+            slog::info!(root_log, "Connected to database");
+            if opt.migrate {
+                slog::info!(root_log, "Migrations complete, exiting haulage.");
+                return;
+            }
+            (std::sync::Arc::new(mysql), None)
+        }
+    };
+    // The subsystems and commands beyond metering, accounting, and
+    // enforcement use Postgres directly. The configuration rejects them with
+    // other backends, leaving only the commands to refuse here.
+    let postgres = || {
+        db_pool.clone().unwrap_or_else(|| {
+            eprintln!("This requires the Postgres database backend");
+            std::process::exit(1);
+        })
+    };
+
+    // Named policies are synchronized before running any command, so that
+    // they can be referenced by name.
+    policies::sync_policies(storage.as_ref(), &config.policies, &root_log)
+        .await
+        .unwrap_or_else(|e| {
+            slog::error!(root_log, "Failed to synchronize access policies"; "error" => e.to_string());
+            panic!("Cannot continue without configured access policies");
+        });
+
+    match opt.command {
+        Some(Command::Admin(admin_command)) => {
+            let admin_log = root_log.new(o!("subsystem" => "admin"));
+            if let Err(e) = admin::run(
+                admin_command,
+                &postgres(),
+                &config.plan_change_rules,
+                &admin_log,
+            )
+            .await
+            {
+                // Report directly, since exiting does not flush the async log.
+                eprintln!("Admin command failed: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Bench(bench_command)) => {
+            let bench_log = root_log.new(o!("subsystem" => "bench"));
+            if let Err(e) = bench::run(bench_command, &postgres(), &bench_log).await {
+                eprintln!("Benchmark failed: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Adjust(adjust_command)) => {
+            let adjust_log = root_log.new(o!("subsystem" => "adjust"));
+            if let Err(e) = adjust::run(adjust_command, &postgres(), &adjust_log).await {
+                eprintln!("Adjustment failed: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Bundle(bundle_command)) => {
+            let bundle_log = root_log.new(o!("subsystem" => "bundle"));
+            if let Err(e) = bundle::run(bundle_command, &postgres(), &bundle_log).await {
+                eprintln!("Bundle failed: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Merge(merge_command)) => {
+            let merge_log = root_log.new(o!("subsystem" => "merge"));
+            if let Err(e) = merge::run(merge_command, &postgres(), &merge_log).await {
+                eprintln!("Merge failed: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::CheckConfig(_)) | Some(Command::SelfTest) | None => {}
+    }
+
+    if let Some(path) = &opt.replay {
+        let replay_log = root_log.new(o!("subsystem" => "replay"));
+        if let Err(e) = replay::run(path, config, storage, &replay_log).await {
+            eprintln!("Replay failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Create the shared statistics registry updated by all subsystems.
+    let stats = std::sync::Arc::new(stats::Stats::default());
+    {
+        let stats = std::sync::Arc::clone(&stats);
+        let period = config.stats_log_interval;
+        let export_path = config.stats_export_path.clone();
+        let stats_log = root_log.new(o!("subsystem" => "stats"));
+        tokio::task::spawn(async move {
+            stats::summarize(stats, period, export_path, stats_log).await;
+        });
+    }
+
+    // Continue from the state handed off by the previous process if there is
+    // one, e.g. across a package upgrade.
+    let (resumed_intervals, resumed_enforcement) = match config
+        .handoff_path
+        .as_ref()
+        .and_then(|path| handoff::take(path, &root_log))
+    {
+        Some(state) => (state.intervals, state.enforcement),
+        None => (Vec::new(), None),
+    };
+
+    // Create the main user aggregation, accounting, and enforcement subsystems.
+    let (subscriber_interface, upstream_interfaces) = config.enforcement_interfaces();
+    // In monitoring-only mode no enforcer is constructed, so the data plane
+    // is left untouched.
+    let user_enforcer = match config.enforcement {
+        true => Some(std::sync::Arc::new(enforcer::Iptables::new(
+            enforcer::Settings {
+                poll_period: config.reenable_poll_interval,
+                verification: config.enforcement_verification.clone(),
+                startup_grace: config.enforcement_startup_grace,
+                timezone: config.site_timezone,
+            },
+            &subscriber_interface,
+            &upstream_interfaces,
+            std::sync::Arc::clone(&storage),
+            resumed_enforcement,
+            std::sync::Arc::clone(&stats),
+            root_log.new(o!("subsystem" => "user_enforcer")),
+        ))),
+        false => {
+            slog::info!(root_log, "Enforcement disabled, only metering usage");
+            None
+        }
+    };
+
+    // In privacy mode subscriber identifiers are pseudonymized in all exports.
+    let pseudonymizer = config.privacy_key_path.as_ref().map(|path| {
+        std::sync::Arc::new(
+            privacy::Pseudonymizer::load_or_create(path).expect("Failed to load the privacy key"),
+        )
+    });
+
+    let syslog_exporter = config.syslog.clone().map(|settings| {
+        syslog::SyslogExporter::new(
+            settings,
+            postgres(),
+            pseudonymizer.clone(),
+            std::sync::Arc::clone(&stats),
+            root_log.new(o!("subsystem" => "syslog")),
+        )
+    });
+
+    // Push metrics to a remote Prometheus compatible endpoint if configured.
+    if let Some(settings) = config.remote_write.clone() {
+        let db_pool = postgres();
+        let pseudonymizer = pseudonymizer.clone();
+        let stats = std::sync::Arc::clone(&stats);
+        let remote_write_log = root_log.new(o!("subsystem" => "remote_write"));
+        tokio::task::spawn(async move {
+            remote_write::push(settings, db_pool, pseudonymizer, stats, remote_write_log).await;
+        });
+    }
+
+    // Serve metrics for Prometheus to scrape if configured.
+    let metrics = config.metrics.clone().map(|settings| {
+        let metrics = std::sync::Arc::new(metrics::Metrics::new(
+            &settings,
+            std::sync::Arc::clone(&stats),
+            pseudonymizer.clone(),
+        ));
+        let served = std::sync::Arc::clone(&metrics);
+        let metrics_log = root_log.new(o!("subsystem" => "metrics"));
+        tokio::task::spawn(async move {
+            metrics::serve(settings.listen, served, metrics_log.clone())
+                .await
+                .unwrap_or_else(|e| slog::error!(metrics_log, "Metrics endpoint failed"; "error" => e.to_string()));
+        });
+        metrics
+    });
+
+    // Move aged usage records out of the database if configured.
+    if let Some(settings) = config.archive.clone() {
+        let db_pool = postgres();
+        let archive_log = root_log.new(o!("subsystem" => "archive"));
+        tokio::task::spawn(async move {
+            archive::run(settings, db_pool, archive_log).await;
+        });
+    }
+
+    let usage_writer = usage_writer::UsageWriter::new(
+        usage_writer::Settings {
+            flush_interval: config.usage_flush_interval,
+            destination_count: config.top_destinations.as_ref().map_or(0, |top| top.count),
+            spill_path: config.usage_spill_path.clone(),
+        },
+        storage.clone(),
+        syslog_exporter
+            .as_ref()
+            .map(syslog::SyslogExporter::clone_input_channel),
+        std::sync::Arc::clone(&stats),
+        root_log.new(o!("subsystem" => "usage_writer")),
+    );
+
+    let user_aggregator = async_aggregator::AsyncAggregator::new::<UserReporter>(
+        clock::Schedule {
+            period: config.user_log_interval,
+            aligned: config.align_log_intervals,
+            timezone: config.site_timezone.unwrap_or_default(),
+        },
+        storage.clone(),
+        usage_writer.clone_input_channel(),
+        std::sync::Arc::new(clock::SystemClock),
+        std::sync::Arc::clone(&stats),
+        root_log.new(o!("aggregator" => "user")),
+    );
+    // Destinations are aggregated on aligned intervals, so that their records
+    // share boundaries to be ranked within.
+    let destination_aggregator = config.top_destinations.as_ref().map(|top| {
+        async_aggregator::AsyncAggregator::new::<DestinationReporter>(
+            clock::Schedule {
+                period: top.interval,
+                aligned: true,
+                timezone: config.site_timezone.unwrap_or_default(),
+            },
+            storage.clone(),
+            usage_writer.clone_input_channel(),
+            std::sync::Arc::new(clock::SystemClock),
+            std::sync::Arc::clone(&stats),
+            root_log.new(o!("aggregator" => "destination")),
+        )
+    });
+    if !resumed_intervals.is_empty() {
+        user_aggregator
+            .clone_input_channel()
+            .send(async_aggregator::Message::Resume {
+                intervals: resumed_intervals,
+            })
+            .await
+            .unwrap_or_else(|e| slog::error!(root_log, "Failed to resume partial intervals"; "error" => e.to_string()));
+    }
+
+    // Optionally cut off subscribers in the kernel at the exact byte their
+    // balance runs out.
+    let quota = match config.nft_quota {
+        true => Some(std::sync::Arc::new(
+            nft_quota::NftQuota::new(root_log.new(o!("subsystem" => "nft_quota")))
+                .await
+                .expect("Unable to set up nft quota enforcement"),
+        )),
+        false => None,
+    };
+
+    let charging_classifier = charging::Classifier::load(&config.charging_classes)
+        .expect("Failed to load charging class domain lists");
+    // The accounter only applies policies, so takes any enforcement backend.
+    let policy_enforcer: std::sync::Arc<dyn enforcer::PolicyEnforcer> = match &user_enforcer {
+        Some(user_enforcer) => user_enforcer.clone(),
+        None => std::sync::Arc::new(enforcer::LogOnly::new(
+            root_log.new(o!("subsystem" => "user_enforcer")),
+        )),
+    };
+    let user_accounter = accounter::UserAccounter::new(
+        config.user_log_interval,
+        accounter::AccounterContext {
+            storage: storage.clone(),
+            usage_writer: usage_writer.clone_input_channel(),
+            enforcer: policy_enforcer,
+            quota,
+            stats: std::sync::Arc::clone(&stats),
+            metrics: metrics.clone(),
+            balance_thresholds: std::sync::Arc::new(config.balance_thresholds.clone()),
+        },
+        charging_classifier,
+        root_log.new(o!("accounter" => "user")),
+    );
+    if let Some(metrics) = &metrics {
+        metrics.watch_queue("usage_writer", &usage_writer.clone_input_channel());
+        metrics.watch_queue("user_aggregator", &user_aggregator.clone_input_channel());
+        if let Some(aggregator) = &destination_aggregator {
+            metrics.watch_queue("destination_aggregator", &aggregator.clone_input_channel());
+        }
+        metrics.watch_queue("user_accounter", &user_accounter.clone_input_channel());
+        if let Some(user_enforcer) = &user_enforcer {
+            user_enforcer.watch_queue(metrics);
+        }
+    }
+
+    // Content filtering is only enabled if categories are configured.
+    let content_filter = match config.content_filter_categories.is_empty() {
+        true => None,
+        false => Some(
+            content_filter::ContentFilter::new(
+                &config.content_filter_categories,
+                config.content_filter_rule_lifetime,
+                postgres(),
+                root_log.new(o!("subsystem" => "content_filter")),
+            )
+            .expect("Failed to load content filter category lists"),
+        ),
+    };
+
+    // Flow and domain level records are only exported if configured, since
+    // their volume is far too high to store alongside the usage records.
+    slog::info!(root_log, "Recording subscriber traffic"; "accounting_level" => format!("{:?}", config.accounting_level));
+    let flow_exporter = config.clickhouse.clone().map(|settings| {
+        clickhouse::ClickhouseExporter::new(
+            settings,
+            clock::Schedule {
+                period: config.flow_log_interval,
+                aligned: config.align_log_intervals,
+                timezone: config.site_timezone.unwrap_or_default(),
+            },
+            pseudonymizer.clone(),
+            std::sync::Arc::clone(&stats),
+            root_log.new(o!("subsystem" => "clickhouse")),
+        )
+    });
+    let netflow_exporter = config.netflow.clone().map(|settings| {
+        netflow::NetflowExporter::new(
+            settings,
+            config.user_subnets.clone(),
+            pseudonymizer.clone(),
+            std::sync::Arc::clone(&stats),
+            root_log.new(o!("subsystem" => "netflow")),
+        )
+    });
+    let flow_stream = config.flow_stream.clone().map(|settings| {
+        flow_stream::FlowStream::new(
+            settings,
+            postgres(),
+            pseudonymizer.clone(),
+            root_log.new(o!("subsystem" => "flow_stream")),
+        )
+    });
+
+    // Without ClickHouse, flow level records are kept in the database.
+    let flow_logger = match (&flow_exporter, config.accounting_level) {
+        (None, accounting::AccountingLevel::Flows) => Some(flow_logger::FlowLogger::new(
+            clock::Schedule {
+                period: config.flow_log_interval,
+                aligned: config.align_log_intervals,
+                timezone: config.site_timezone.unwrap_or_default(),
+            },
+            postgres(),
+            pseudonymizer.clone(),
+            std::sync::Arc::clone(&stats),
+            root_log.new(o!("subsystem" => "flow_logger")),
+        )),
+        _ => None,
+    };
+
+    // Follow subscriber address changes without requiring a restart.
+    {
+        let storage = std::sync::Arc::clone(&storage);
+        let aggregator = user_aggregator.clone_input_channel();
+        let accounter = user_accounter.clone_input_channel();
+        let enforcer = user_enforcer.clone();
+        let watcher_log = root_log.new(o!("subsystem" => "address_watcher"));
+        let period = config.reenable_poll_interval;
+        tokio::task::spawn(async move {
+            address_watcher::watch_addresses(
+                period,
+                storage,
+                aggregator,
+                accounter,
+                enforcer,
+                watcher_log,
+            )
+            .await;
+        });
+    }
+
+    // Assign subscribers the addresses leased to them by a DHCP server.
+    if let Some(settings) = config.dhcp_leases.clone() {
+        let db_pool = postgres();
+        let leases_log = root_log.new(o!("subsystem" => "leases"));
+        tokio::task::spawn(async move {
+            leases::track_leases(settings, db_pool, leases_log).await;
+        });
+    }
+
+    // Periodically cross-check the accounting sources if configured.
+    if let Some(settings) = config.reconciliation.clone() {
+        let interface = config.subscriber_interface.clone();
+        let db_pool = postgres();
+        let stats = std::sync::Arc::clone(&stats);
+        let reconciler_log = root_log.new(o!("subsystem" => "reconciler"));
+        tokio::task::spawn(async move {
+            reconciler::reconcile(settings, interface, db_pool, stats, reconciler_log).await;
+        });
+    }
+
+    // Periodically forecast when subscribers run out of balance if configured.
+    if let Some(settings) = config.usage_forecast.clone() {
+        let db_pool = postgres();
+        let forecast_log = root_log.new(o!("subsystem" => "forecast"));
+        tokio::task::spawn(async move {
+            forecast::run(settings, db_pool, forecast_log).await;
+        });
+    }
+
+    // Deprioritize the heaviest subscribers during backhaul congestion if
+    // configured. Upstream traffic is all backhaul, so its interfaces are
+    // measured when available.
+    if let Some(settings) = config.fair_usage.clone() {
+        let interfaces = match config.upstream_interfaces.is_empty() {
+            true => vec![config.subscriber_interface.clone()],
+            false => config.upstream_interfaces.clone(),
+        };
+        let db_pool = postgres();
+        let fair_usage_log = root_log.new(o!("subsystem" => "fair_usage"));
+        tokio::task::spawn(async move {
+            fair_usage::run(settings, interfaces, db_pool, fair_usage_log).await;
+        });
+    }
+
+    // Verify the bandwidth guarantees of subscriber plans if configured.
+    if let Some(settings) = config.guarantee_verification.clone() {
+        let db_pool = postgres();
+        let guarantee_log = root_log.new(o!("subsystem" => "guarantee"));
+        tokio::task::spawn(async move {
+            guarantee::verify(settings, db_pool, guarantee_log).await;
+        });
+    }
+
+    // Run the operator's hooks for subscriber events if configured.
+    if let Some(settings) = config.hooks.clone() {
+        let db_pool = postgres();
+        let stats = std::sync::Arc::clone(&stats);
+        let hooks_log = root_log.new(o!("subsystem" => "hooks"));
+        tokio::task::spawn(async move {
+            hooks::run(settings, db_pool, stats, hooks_log).await;
+        });
+    }
+
+    // Record the traffic dropped by subscriber shaping if configured. The
+    // configuration requires enforcement for drop accounting.
+    if let (Some(settings), Some(enforcer)) =
+        (config.drop_accounting.clone(), user_enforcer.clone())
+    {
+        let schedule = clock::Schedule {
+            period: config.user_log_interval,
+            aligned: config.align_log_intervals,
+            timezone: config.site_timezone.unwrap_or_default(),
+        };
+        let db_pool = postgres();
+        let drops_log = root_log.new(o!("subsystem" => "drops"));
+        tokio::task::spawn(async move {
+            drops::record(settings, schedule, enforcer, db_pool, drops_log).await;
+        });
+    }
+
+    // Record the airtime of WiFi stations if configured.
+    if let Some(settings) = config.wifi_airtime.clone() {
+        let schedule = clock::Schedule {
+            period: config.user_log_interval,
+            aligned: config.align_log_intervals,
+            timezone: config.site_timezone.unwrap_or_default(),
+        };
+        let interface = subscriber_interface.clone();
+        let db_pool = postgres();
+        let airtime_log = root_log.new(o!("subsystem" => "airtime"));
+        tokio::task::spawn(async move {
+            airtime::record(settings, interface, schedule, db_pool, airtime_log).await;
+        });
+    }
+
+    // Record the traffic carried by each upstream interface, which is only
+    // kept in Postgres.
+    if let (false, Some(db_pool)) = (upstream_interfaces.is_empty(), db_pool.clone()) {
+        let interfaces = upstream_interfaces.clone();
+        let schedule = clock::Schedule {
+            period: config.user_log_interval,
+            aligned: config.align_log_intervals,
+            timezone: config.site_timezone.unwrap_or_default(),
+        };
+        let wan_usage_log = root_log.new(o!("subsystem" => "wan_usage"));
+        tokio::task::spawn(async move {
+            wan_usage::record(interfaces, schedule, db_pool, wan_usage_log).await;
+        });
+    }
+
+    // Serve live queries against the running subsystems if configured.
+    if let Some(socket_path) = config.control_socket_path.clone() {
+        let control_context = control::Context {
+            db_pool: postgres(),
+            user_aggregator: user_aggregator.clone_input_channel(),
+            user_accounter: user_accounter.clone_input_channel(),
+            enforcer: user_enforcer.clone(),
+            require_api_key: config.control_require_api_key,
+            plan_change_rules: std::sync::Arc::new(config.plan_change_rules.clone()),
+        };
+        let control_log = root_log.new(o!("subsystem" => "control"));
+        tokio::task::spawn(async move {
+            control::serve(socket_path, control_context, control_log.clone())
+                .await
+                .unwrap_or_else(|e| slog::error!(control_log, "Control socket failed"; "error" => e.to_string()));
+        });
+    }
+
+    // Serve the control API over gRPC if configured.
+    if let Some(settings) = config.grpc_api.clone() {
+        let grpc_api_context = grpc_api::Context {
+            db_pool: postgres(),
+            user_aggregator: user_aggregator.clone_input_channel(),
+            require_api_key: settings.require_api_key,
+        };
+        let grpc_api_log = root_log.new(o!("subsystem" => "grpc_api"));
+        tokio::task::spawn(async move {
+            grpc_api::serve(settings.listen, grpc_api_context, grpc_api_log.clone())
+                .await
+                .unwrap_or_else(
+                    |e| slog::error!(grpc_api_log, "gRPC API failed"; "error" => e.to_string()),
+                );
+        });
+    }
+
+    // Serve the JSON endpoints of the Go haulage if configured.
+    if let Some(settings) = config.http_api.clone() {
+        let http_api_context = http_api::Context {
+            db_pool: postgres(),
+            require_api_key: settings.require_api_key,
+        };
+        let http_api_log = root_log.new(o!("subsystem" => "http_api"));
+        tokio::task::spawn(async move {
+            http_api::serve(settings.listen, http_api_context, http_api_log.clone())
+                .await
+                .unwrap_or_else(
+                    |e| slog::error!(http_api_log, "HTTP API failed"; "error" => e.to_string()),
+                );
+        });
+    }
+
+    // Answer subscriber quota lookups over DNS if configured.
+    if let Some(settings) = config.quota_dns.clone() {
+        let db_pool = postgres();
+        let accounter = user_accounter.clone_input_channel();
+        let quota_dns_log = root_log.new(o!("subsystem" => "quota_dns"));
+        tokio::task::spawn(async move {
+            quota_dns::serve(settings, db_pool, accounter, quota_dns_log.clone())
+                .await
+                .unwrap_or_else(|e| slog::error!(quota_dns_log, "Quota DNS responder failed"; "error" => e.to_string()));
+        });
+    }
+
+    // Apply interface changes from the configuration file on SIGHUP, e.g. when
+    // the backhaul fails over to a different NIC.
+    let (capture_interface_sender, mut capture_interface) =
+        tokio::sync::watch::channel(config.subscriber_interface.clone());
+    {
+        let config_path = opt.config.clone();
+        let config = std::sync::Arc::clone(&config);
+        let enforcer = user_enforcer.clone();
+        let aggregator = user_aggregator.clone_input_channel();
+        let reload_log = root_log.new(o!("subsystem" => "reload"));
+        tokio::task::spawn(async move {
+            reload_on_hangup(
+                config_path,
+                &config,
+                enforcer,
+                aggregator,
+                capture_interface_sender,
+                reload_log,
+            )
+            .await;
+        });
+    }
+
+    // Stop capturing and write out the usage aggregated so far when asked to
+    // exit, rather than losing it.
+    let (stop_sender, stop) = tokio::sync::watch::channel(None);
+    {
+        let shutdown_log = root_log.new(o!("subsystem" => "shutdown"));
+        tokio::task::spawn(async move {
+            let signal = shutdown::wait_for_signal(&shutdown_log).await;
+            let _ = stop_sender.send(Some(signal));
+        });
+    }
+
+    let capture_filter = config
+        .filter_capture
+        .then_some(config.user_subnets.as_slice());
+    let mut capture_source = capture::open(
+        &config.subscriber_interface,
+        PACKET_BATCH_TIMEOUT,
+        capture_filter,
+    )
+    .unwrap_or_else(|e| {
+        slog::error!(root_log, "Unable to open capture"; "interface" => &config.subscriber_interface, "error" => e.to_string());
+        panic!("No listenable interface found");
+    });
+
+    let mut interface_log = root_log.new(o!("interface" => String::from(capture_source.name())));
+
+    // Informational messages about individual packets are rate limited, since
+    // a single misbehaving device can otherwise flood the log with them.
+    let log_limiter = std::sync::Arc::new(log_limiter::LogLimiter::new());
+    {
+        let log_limiter = std::sync::Arc::clone(&log_limiter);
+        let limiter_log = root_log.new(o!("subsystem" => "log_limiter"));
+        tokio::task::spawn(async move {
+            log_limiter::report_suppressed(log_limiter, LOG_SUMMARY_INTERVAL, limiter_log).await;
+        });
+    }
+    let limited_log = |log: &Logger| {
+        Logger::root(
+            log_limiter::LimitedDrain::new(log.clone(), std::sync::Arc::clone(&log_limiter)),
+            o!(),
+        )
+    };
+    let mut packet_log = limited_log(&interface_log);
+
+    // Keep a copy of all captured traffic for postmortem analysis if configured.
+    let debug_capture = config.debug_capture_path.as_ref().map(|path| {
+        let debug_capture = debug_capture::DebugCapture::new(
+            path,
+            std::sync::Arc::clone(&stats),
+            root_log.new(o!("subsystem" => "debug_capture")),
+        )
+        .expect("Failed to create debug capture file");
+        debug_capture.start_interface(capture_source.name(), capture_source.link_type());
+        debug_capture
+    });
+
+    let collision_detector = config.address_collision.clone().map(|settings| {
+        address_collision::CollisionDetector::new(
+            settings,
+            postgres(),
+            std::sync::Arc::clone(&stats),
+            root_log.new(o!("subsystem" => "address_collision")),
+        )
+    });
+
+    let nat_observer = config.nat_cpe.clone().map(|settings| {
+        nat_cpe::NatObserver::new(
+            settings,
+            clock::Schedule {
+                period: config.user_log_interval,
+                aligned: config.align_log_intervals,
+                timezone: config.site_timezone.unwrap_or_default(),
+            },
+            postgres(),
+            root_log.new(o!("subsystem" => "nat_cpe")),
+        )
+    });
+
+    let presence_tracker = config.presence.as_ref().map(|_| {
+        presence::PresenceTracker::new(
+            clock::Schedule {
+                period: config.user_log_interval,
+                aligned: config.align_log_intervals,
+                timezone: config.site_timezone.unwrap_or_default(),
+            },
+            postgres(),
+            root_log.new(o!("subsystem" => "presence")),
+        )
+    });
+
+    let trial_provisioner = config.trial.clone().map(|settings| {
+        trial::TrialProvisioner::new(
+            settings,
+            postgres(),
+            user_aggregator.clone_input_channel(),
+            user_accounter.clone_input_channel(),
+            root_log.new(o!("subsystem" => "trial")),
+        )
+    });
+
+    let mac_binder = config.identify_by_mac.then(|| {
+        mac_binding::MacBinder::new(postgres(), root_log.new(o!("subsystem" => "mac_binding")))
+    });
+
+    // Remember the owners of recent flows, so that packets of known flows can
+    // be shed cheaply if accounting falls behind.
+    let flow_cache = std::sync::Arc::new(shedding::FlowCache::new());
+    {
+        let flow_cache = std::sync::Arc::clone(&flow_cache);
+        let stats = std::sync::Arc::clone(&stats);
+        tokio::task::spawn(async move {
+            shedding::expire_flows(flow_cache, SHED_FLOW_TIMEOUT, stats).await;
+        });
+    }
+
+    // Hold IPv4 fragments until their datagrams are complete.
+    let fragment_cache = std::sync::Arc::new(packet_parser::FragmentCache::new());
+    {
+        let fragment_cache = std::sync::Arc::clone(&fragment_cache);
+        let stats = std::sync::Arc::clone(&stats);
+        tokio::task::spawn(async move {
+            packet_parser::expire_fragments(fragment_cache, FRAGMENT_TIMEOUT, stats).await;
+        });
+    }
+
+    let sinks = PacketSinks {
+        user_aggregator: user_aggregator.clone_input_channel(),
+        destination_aggregator: destination_aggregator
+            .as_ref()
+            .map(|a| a.clone_input_channel()),
+        user_accounter: user_accounter.clone_input_channel(),
+        accounter_classifies: !config.charging_classes.is_empty(),
+        exports_domains: config.accounting_level >= accounting::AccountingLevel::Domains,
+        content_filter: content_filter.as_ref().map(|f| f.clone_input_channel()),
+        flow_exporter: flow_exporter.as_ref().map(|e| e.clone_input_channel()),
+        flow_logger: flow_logger.as_ref().map(|l| l.clone_input_channel()),
+        exports_flows: config.accounting_level >= accounting::AccountingLevel::Flows,
+        flow_stream: flow_stream.as_ref().map(|s| s.clone_input_channel()),
+        netflow_exporter: netflow_exporter.as_ref().map(|e| e.clone_input_channel()),
+        collision_detector: collision_detector.as_ref().map(|d| d.clone_input_channel()),
+        nat_observer: nat_observer.as_ref().map(|o| o.clone_input_channel()),
+        presence_tracker: presence_tracker.as_ref().map(|t| t.clone_input_channel()),
+        trial_provisioner: trial_provisioner.as_ref().map(|p| p.clone_input_channel()),
+        mac_binder: mac_binder.as_ref().map(|b| b.clone_input_channel()),
+        flow_cache,
+        fragment_cache,
+    };
+
+    // Keep the capture path apart from the database and enforcement work if
+    // configured. The capture loop runs on this thread.
+    let parser_runtime = match &config.cpu_pinning {
+        Some(settings) => {
+            let pinning_log = root_log.new(o!("subsystem" => "pinning"));
+            pinning::isolate_shared_runtime(settings, &pinning_log);
+            pinning::pin_capture_thread(settings, &pinning_log);
+            pinning::parser_runtime(settings, &pinning_log).unwrap_or_else(|e| {
+                slog::error!(pinning_log, "Parsing on the shared runtime"; "error" => e.to_string());
+                None
+            })
+        }
+        None => None,
+    };
+    let parser_handle = parser_runtime
+        .as_ref()
+        .map(|runtime| runtime.handle().clone())
+        .unwrap_or_else(tokio::runtime::Handle::current);
+
+    let mut batch: Vec<PacketKind> = Vec::with_capacity(PACKET_BATCH_SIZE);
+    let mut batch_start = std::time::Instant::now();
+    // Batches still being parsed, awaited before flushing at shutdown.
+    let mut parsing: Vec<tokio::task::JoinHandle<()>> = Vec::new();
+    loop {
+        // Capture times out regularly, so the stop is seen promptly.
+        let stopping = stop.borrow().is_some();
+        if capture_interface.has_changed().unwrap_or(false) {
+            let interface_name = capture_interface.borrow_and_update().clone();
+            match capture::open(&interface_name, PACKET_BATCH_TIMEOUT, capture_filter) {
+                Ok(new_source) => {
+                    let previous_stats = capture_source.stats();
+                    slog::info!(interface_log, "Closing capture"; "packets" => previous_stats.packets_received, "errors" => previous_stats.receive_errors);
+                    capture_source = new_source;
+                    interface_log =
+                        root_log.new(o!("interface" => String::from(capture_source.name())));
+                    packet_log = limited_log(&interface_log);
+                    if let Some(debug_capture) = &debug_capture {
+                        debug_capture
+                            .start_interface(capture_source.name(), capture_source.link_type());
+                    }
+                    slog::info!(interface_log, "Switched capture interface");
+                }
+                Err(e) => {
+                    slog::error!(interface_log, "Unable to switch capture interface"; "new_interface" => &interface_name, "error" => e.to_string());
+                }
+            }
+        }
+
+        let link_type = capture_source.link_type();
+        match capture_source.next_packet() {
+            Ok(packet) => {
+                stats.packets_captured.increment();
+                if let Some(debug_capture) = &debug_capture {
+                    debug_capture.record(packet);
+                }
+                let packet_data_copy = bytes::Bytes::copy_from_slice(packet);
+
+                let packet_kind = match link_type {
+                    capture::LinkType::Ethernet => PacketKind::Ethernet(packet_data_copy),
+                    capture::LinkType::RawIp => {
+                        // Distinguish between IPv4 and IPv6 by checking the IP
+                        // version nybl. Could be brittle to non-ip payloads.
+                        match (packet[0] & 0xF0) >> 4 {
+                            0x4 => PacketKind::IPv4(packet_data_copy),
+                            0x6 => PacketKind::IPv6(packet_data_copy),
+                            value => {
+                                slog::error!(interface_log, "Invalid IP version parsed"; "version"=> value);
+                                stats.parse_errors.increment();
+                                continue;
+                            }
+                        }
                     }
-                    parsed_config.subscriber_interface.unwrap()
+                };
+
+                if batch.is_empty() {
+                    batch_start = std::time::Instant::now();
                 }
-            };
-            if parsed_config.upstream_interface.is_none() {
-                slog::warn!(root_log, "No 'upstreamInterface' configured, but will be required in a future version of haulage");
-            }
-
-            config::Internal {
-                db_name: parsed_config.custom.db_location,
-                db_user: parsed_config.custom.db_user,
-                db_pass: parsed_config.custom.db_pass,
-                db_auto_upgrade: parsed_config.custom.db_auto_upgrade.unwrap_or(true),
-                flow_log_interval: parsed_config.flow_log_interval,
-                user_log_interval: parsed_config.user_log_interval,
-                reenable_poll_interval: parsed_config.custom.reenable_poll_interval,
-                subscriber_interface: subscriber_interface,
-                upstream_interface: parsed_config.upstream_interface,
-                user_subnet: ipnetwork::IpNetwork::from_str(&parsed_config.user_subnet).unwrap(),
-                ignored_user_addresses: HashSet::from_iter(
-                    parsed_config.ignored_user_addresses.iter().map(|a| {
-                        std::net::IpAddr::from_str(a).expect("Failed to parse configued IP address")
-                    }),
-                ),
+                batch.push(packet_kind);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {}
+            Err(e) => {
+                stats.capture_errors.increment();
+                slog::error!(interface_log, "packetdump unable to receive packet: {}", e);
             }
         }
-        _ => {
-            slog::error!(
-                root_log,
-                "Unsupported configuration version '{}' specified",
-                config_version
-            );
-            panic!("Unsupported configuration version specified");
-        }
-    };
 
-    let config = std::sync::Arc::new(config);
+        if batch.len() >= PACKET_BATCH_SIZE
+            || (!batch.is_empty() && batch_start.elapsed() >= PACKET_BATCH_TIMEOUT)
+            || (!batch.is_empty() && stopping)
+        {
+            let packets = std::mem::replace(&mut batch, Vec::with_capacity(PACKET_BATCH_SIZE));
+            let batch_log = packet_log.new(o!());
+            let sinks = sinks.clone();
+            let config = config.clone();
+            let stats = std::sync::Arc::clone(&stats);
 
-    // Connect to backing storage database
-    let db_string = format!(
-        "postgres://{}:{}@localhost/{}",
-        config.db_user, config.db_pass, config.db_name
-    );
+            parsing.retain(|handle| !handle.is_finished());
+            parsing.push(parser_handle.spawn(async move {
+                handle_packet_batch(packets, sinks, config, stats, batch_log).await;
+            }));
+        }
+        if stopping {
+            break;
+        }
+    }
+
+    let capture_stats = capture_source.stats();
+    slog::info!(interface_log, "Stopped capture"; "packets" => capture_stats.packets_received, "errors" => capture_stats.receive_errors);
+    for handle in parsing {
+        let _ = handle.await;
+    }
+    if let Some(runtime) = parser_runtime {
+        runtime.shutdown_background();
+    }
+    let subsystems = shutdown::Subsystems {
+        user_aggregator: user_aggregator.clone_input_channel(),
+        destination_aggregator: destination_aggregator.map(|a| a.clone_input_channel()),
+        accounter: user_accounter.clone_input_channel(),
+        usage_writer: usage_writer.clone_input_channel(),
+        enforcer: user_enforcer,
+    };
+    // Hand off to the next process when terminated if configured, rather than
+    // reporting the partial intervals early and rebuilding enforcement.
+    let signal = *stop.borrow();
+    match (signal, &config.handoff_path) {
+        (Some(shutdown::Signal::Terminate), Some(path)) => {
+            handoff::hand_off(
+                path,
+                subsystems,
+                &root_log.new(o!("subsystem" => "handoff")),
+            )
+            .await
+        }
+        _ => shutdown::flush(subsystems, &root_log.new(o!("subsystem" => "shutdown"))).await,
+    }
+    slog::info!(root_log, "Stopped");
+}
 
+// Connects to Postgres and checks its schema migrations, running them if
+// allowed. Returns None if haulage should exit instead, e.g. after an explicit
+// db-upgrade or with migrations that cannot be applied.
+async fn connect_postgres(
+    options: &sqlx::postgres::PgConnectOptions,
+    opt: &Opt,
+    auto_upgrade: bool,
+    log: &Logger,
+) -> Option<sqlx::PgPool> {
     // TODO(matt9j) Temporary workaround to set all transactions to serializable
     // until sqlx supports per-transaction isolation settings.
     let db_pool = sqlx::postgres::PgPoolOptions::new()
@@ -210,21 +3105,15 @@ async fn main() {
                 Ok(())
             })
         })
-        .connect(&db_string);
+        .connect_with(options.clone());
 
     let db_pool = tokio::time::timeout(std::time::Duration::from_secs(5), db_pool)
         .await
         .expect("DB connection timed out")
         .unwrap();
-    slog::info!(
-        root_log,
-        "Connected to database db={} user={}",
-        config.db_name,
-        config.db_user
-    );
-    let db_pool = std::sync::Arc::new(db_pool);
+    slog::info!(log, "Connected to database");
 
-    let mut migrator = sqlx::migrate::Migrator::new(opt.migration_directory)
+    let mut migrator = sqlx::migrate::Migrator::new(opt.migration_directory.as_path())
         .await
         .expect("Unable to read available database schema migrations");
 
@@ -232,27 +3121,26 @@ async fn main() {
     // while ignoring missing migrations.
     if opt.migrate {
         slog::warn!(
-            root_log,
+            log,
             "Running database migrations as part of explicit db-upgrade, this process can not be easily undone!"
         );
         migrator.set_ignore_missing(true);
-        migrator.run(db_pool.as_ref()).await.unwrap();
-        slog::info!(root_log, "Migrations complete, exiting haulage.");
+        migrator.run(&db_pool).await.unwrap();
+        slog::info!(log, "Migrations complete, exiting haulage.");
 
-        return;
+        return None;
     }
 
     // Get a set of available migrations and a set of applied migrations
     let available_migrations: HashSet<_> = migrator.iter().map(|x| x.version).collect();
     let applied_migrations: HashSet<_> = db_pool
-        .as_ref()
         .acquire()
         .await
         .expect("Unable to acquire DB connection")
         .list_applied_migrations()
         .await
         .unwrap_or_else(|e| {
-            slog::warn!(root_log, "Unable to query for applied migrations: {}", e);
+            slog::warn!(log, "Unable to query for applied migrations: {}", e);
             vec![]
         })
         .iter()
@@ -261,7 +3149,7 @@ async fn main() {
 
     if available_migrations != applied_migrations {
         slog::warn!(
-            root_log,
+            log,
             "There is a difference between the expected set of DB schema migrations for this version of haulage \
             and the migrations applied to the configured database."
         );
@@ -275,7 +3163,7 @@ async fn main() {
         // Print the list of unapplied migrations if any exist before checking for extra migrations.
         if unapplied_migrations.len() != 0 {
             slog::warn!(
-                root_log,
+                log,
                 "The following migrations are expected by this version of haulage, but not applied to the local database";
                 "unapplied_migrations" => format!("{:?}", unapplied_migrations)
             );
@@ -284,223 +3172,777 @@ async fn main() {
         // Extra migrations are possibly dangerous, and should require manual intervention & backup before upgrading.
         if extra_migrations.len() != 0 {
             slog::error!(
-                root_log,
+                log,
                 "The following migrations are present in your database but unknown to this version of haulage!";
                 "extra_migrations" => format!("{:?}", extra_migrations)
             );
             slog::error!(
-                root_log,
+                log,
                 "You can attempt to upgrade your database schema to be compatible with this version of haulage by manually running `haulage --db-upgrade`"
             );
             slog::error!(
-                root_log,
+                log,
                 "***BE SURE TO BACK UP YOUR DATABASE BEFORE UPGRADING*** The upgrade operation cannot be easily undone."
             );
             slog::error!(
-                root_log,
+                log,
                 "Cannot proceed without correcting the database schema."
             );
-            return;
+            return None;
         }
 
         if unapplied_migrations.len() != 0 {
-            if !config.db_auto_upgrade {
+            if !auto_upgrade {
                 slog::error!(
-                    root_log,
+                    log,
                     "Unapplied migrations exist, but dbAutoUpgrade is disabled, exiting..."
                 );
                 slog::error!(
-                    root_log,
+                    log,
                     "You can upgrade your database schema to be compatible with this version of haulage by manually running `haulage --db-upgrade`"
                 );
                 slog::error!(
-                    root_log,
+                    log,
                     "***BE SURE TO BACK UP YOUR DATABASE BEFORE UPGRADING*** The upgrade operation cannot be easily undone."
                 );
-                return;
+                return None;
             }
 
             slog::warn!(
-                root_log,
+                log,
                 "Running database migrations as part of dbAutoUpgrade, this process can not be easily undone!"
             );
-            migrator.run(db_pool.as_ref()).await.unwrap_or_else(|e| {
-                slog::error!(root_log, "Failed to migrate with error {}", e);
+            migrator.run(&db_pool).await.unwrap_or_else(|e| {
+                slog::error!(log, "Failed to migrate with error {}", e);
                 panic!("Cannot continue with failed migrations");
             });
-            slog::info!(root_log, "Migrations complete");
+            slog::info!(log, "Migrations complete");
         }
     }
 
-    // Create the main user aggregation, accounting, and enforcement subsystems.
-    let user_enforcer = enforcer::Iptables::new(
-        config.reenable_poll_interval,
-        &config.subscriber_interface,
-        &config.upstream_interface,
-        std::sync::Arc::clone(&db_pool),
-        root_log.new(o!("subsystem" => "user_enforcer")),
-    );
-    let user_enforcer = std::sync::Arc::new(user_enforcer);
+    Some(db_pool)
+}
 
-    let user_aggregator = async_aggregator::AsyncAggregator::new::<UserReporter>(
-        config.user_log_interval,
-        db_pool.clone(),
-        root_log.new(o!("aggregator" => "user")),
-    );
+// Reloads the configuration file on each SIGHUP. Only the subscriber and
+// upstream interfaces are currently applied at runtime, other changes require
+// a restart. Report interval overrides are also reloaded from the database.
+async fn reload_on_hangup(
+    config_path: std::path::PathBuf,
+    initial_config: &config::Internal,
+    enforcer: Option<std::sync::Arc<enforcer::Iptables>>,
+    aggregator: tokio::sync::mpsc::Sender<async_aggregator::Message>,
+    capture_interface: tokio::sync::watch::Sender<String>,
+    log: Logger,
+) {
+    let mut hangups = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            slog::error!(log, "Unable to listen for reload signals"; "error" => e.to_string());
+            return;
+        }
+    };
 
-    let user_accounter = accounter::UserAccounter::new(
-        config.user_log_interval,
-        db_pool.clone(),
-        std::sync::Arc::clone(&user_enforcer),
-        root_log.new(o!("accounter" => "user")),
-    );
+    let mut interfaces = initial_config.enforcement_interfaces();
+    while hangups.recv().await.is_some() {
+        slog::info!(log, "Reloading configuration"; "path" => config_path.display().to_string());
+        aggregator
+            .send(async_aggregator::Message::ReloadIntervals)
+            .await
+            .unwrap_or_else(|e| slog::error!(log, "Failed to reload report intervals"; "error" => e.to_string()));
+        let new_config = match config::load(&config_path, &log) {
+            Ok(new_config) => new_config,
+            Err(e) => {
+                slog::error!(log, "Failed to reload configuration, keeping the current configuration"; "error" => e.to_string());
+                continue;
+            }
+        };
 
-    // This is a lambda closure to do a match in the filter function! Cool...
-    let interface_name_match =
-        |iface: &pnet_datalink::NetworkInterface| iface.name == config.subscriber_interface;
+        let new_interfaces = new_config.enforcement_interfaces();
+        if new_interfaces == interfaces {
+            slog::info!(log, "No interface changes to apply");
+            continue;
+        }
 
-    let interface = pnet_datalink::interfaces()
-        .into_iter()
-        .filter(interface_name_match)
-        .next()
-        .unwrap_or_else(|| {
-            slog::error!(
-                root_log,
-                "Unable to find configured interface {}",
-                config.subscriber_interface
-            );
-            panic!("No listenable interface found");
-        });
+        if let Some(enforcer) = &enforcer {
+            enforcer
+                .change_interfaces(&new_interfaces.0, &new_interfaces.1)
+                .await
+                .unwrap_or_else(|e| slog::error!(log, "Failed to move enforcement to new interfaces"; "error" => e.to_string()));
+        }
+
+        // Follow the new subscriber interface for capture even if enforcement
+        // could not be fully moved, so that usage is still accounted.
+        if new_interfaces.0.name != interfaces.0.name {
+            capture_interface
+                .send(new_config.subscriber_interface.clone())
+                .unwrap_or_else(|e| slog::error!(log, "Failed to notify capture of interface change"; "error" => e.to_string()));
+        }
+
+        interfaces = new_interfaces;
+    }
+}
+
+// Packets are handled in small batches so that the per-subscriber reports from
+// each batch can be combined before being sent to the aggregation and
+// accounting subsystems, reducing channel traffic and task wakeups on busy
+// links.
+const PACKET_BATCH_SIZE: usize = 64;
+const PACKET_BATCH_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(10);
+
+// Packets of known flows are shed once less than this fraction of the
+// aggregator or accounter input channel is free, before sends start blocking.
+const SHED_THRESHOLD: f64 = 0.25;
+// How long idle flows are remembered for shedding.
+const SHED_FLOW_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+// How long the fragments of an incomplete IPv4 datagram are held, matching
+// the Linux default.
+const FRAGMENT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+// How often counts of rate limited log messages are reported.
+const LOG_SUMMARY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+// The input channels of the subsystems consuming the results of packet
+// handling.
+#[derive(Debug, Clone)]
+struct PacketSinks {
+    user_aggregator: tokio::sync::mpsc::Sender<async_aggregator::Message>,
+    destination_aggregator: Option<tokio::sync::mpsc::Sender<async_aggregator::Message>>,
+    user_accounter: tokio::sync::mpsc::Sender<accounter::Message>,
+    // Whether the accounter attributes DNS answers to charging classes.
+    accounter_classifies: bool,
+    content_filter: Option<tokio::sync::mpsc::Sender<content_filter::Message>>,
+    flow_exporter: Option<tokio::sync::mpsc::Sender<clickhouse::Message>>,
+    flow_logger: Option<tokio::sync::mpsc::Sender<flow_logger::Message>>,
+    // Whether the flow exporter records flows, which are otherwise only
+    // collected for the flow stream.
+    exports_flows: bool,
+    flow_stream: Option<tokio::sync::broadcast::Sender<std::sync::Arc<flow_stream::FlowBatch>>>,
+    netflow_exporter: Option<tokio::sync::mpsc::Sender<netflow::Message>>,
+    // Whether the flow exporter records the domains subscribers resolve.
+    exports_domains: bool,
+    collision_detector: Option<tokio::sync::mpsc::Sender<address_collision::Message>>,
+    nat_observer: Option<tokio::sync::mpsc::Sender<nat_cpe::Message>>,
+    presence_tracker: Option<tokio::sync::mpsc::Sender<presence::Message>>,
+    trial_provisioner: Option<tokio::sync::mpsc::Sender<trial::Message>>,
+    mac_binder: Option<tokio::sync::mpsc::Sender<mac_binding::Message>>,
+    flow_cache: std::sync::Arc<shedding::FlowCache>,
+    fragment_cache: std::sync::Arc<packet_parser::FragmentCache>,
+}
+impl PacketSinks {
+    // Whether the subsystems receiving per-subscriber reports are falling
+    // behind the capture.
+    fn overloaded(&self) -> bool {
+        let nearly_full = |capacity: usize, max_capacity: usize| {
+            (capacity as f64) < (max_capacity as f64) * SHED_THRESHOLD
+        };
+        nearly_full(
+            self.user_aggregator.capacity(),
+            self.user_aggregator.max_capacity(),
+        ) || nearly_full(
+            self.user_accounter.capacity(),
+            self.user_accounter.max_capacity(),
+        )
+    }
+}
+
+async fn handle_packet_batch(
+    packets: Vec<PacketKind>,
+    sinks: PacketSinks,
+    config: std::sync::Arc<config::Internal>,
+    stats: std::sync::Arc<stats::Stats>,
+    log: Logger,
+) {
+    let mut reports = ReportBatch::default();
+    let now = std::time::Instant::now();
+    let overloaded = sinks.overloaded();
+    for packet in packets {
+        if overloaded && shed_packet(&packet, &sinks.flow_cache, now) {
+            stats.packets_shed.increment();
+            continue;
+        }
+        handle_packet(
+            packet,
+            &mut reports,
+            &sinks.fragment_cache,
+            now,
+            &config,
+            &stats,
+            &log,
+        );
+    }
 
-    // Create the receive channel
-    let (_, mut rx) = match pnet_datalink::channel(&interface, Default::default()) {
-        Ok(pnet_datalink::Channel::Ethernet(tx, rx)) => (tx, rx),
-        Ok(_) => {
-            slog::error!(root_log, "Unable to match a valid channel type");
-            panic!("Unhandled channel type!");
+    // Report the bytes of previously shed packets once the backlog clears.
+    if !overloaded {
+        for shed in sinks.flow_cache.take_shed() {
+            reports.add_shed(&shed, sinks.destination_aggregator.is_some());
+            stats.shed_bytes_reported.add(shed.bytes);
         }
-        Err(e) => panic!("Error when creating channel: {}", e),
+    }
+    sinks
+        .flow_cache
+        .learn(std::mem::take(&mut reports.known_flows), now);
+    reports.send(&sinks, &log).await;
+}
+
+// Attributes a packet to its known flow without parsing or reporting it,
+// returning false if the packet must be handled normally.
+fn shed_packet(
+    packet: &PacketKind,
+    flow_cache: &shedding::FlowCache,
+    now: std::time::Instant,
+) -> bool {
+    let peeked = match packet {
+        PacketKind::Ethernet(packet_bytes) => packet_parser::peek_ethernet(packet_bytes),
+        PacketKind::IPv4(packet_bytes) => packet_parser::peek_ipv4(packet_bytes),
+        PacketKind::IPv6(packet_bytes) => packet_parser::peek_ipv6(packet_bytes),
     };
+    match peeked {
+        Some((fivetuple, length)) => flow_cache.shed(&fivetuple, length as u64, now),
+        None => false,
+    }
+}
+
+// Reports accumulated across a batch of packets, such that each subscriber
+// receives at most one message per subsystem per batch.
+#[derive(Debug, Default)]
+struct ReportBatch {
+    user_usage: HashMap<std::net::IpAddr, NetResourceBundle>,
+    destination_usage: HashMap<std::net::IpAddr, NetResourceBundle>,
+    // The bytes of each subscriber by remote address, for weighting charges.
+    user_charges: HashMap<std::net::IpAddr, HashMap<std::net::IpAddr, u64>>,
+    dns_answers: Vec<(std::net::IpAddr, packet_parser::DnsResponse)>,
+    // The subscriber, remote address, and name of each TLS server contacted.
+    server_names: Vec<(std::net::IpAddr, std::net::IpAddr, String)>,
+    flows: HashMap<clickhouse::FlowKey, clickhouse::FlowUsage>,
+    netflow_flows: HashMap<netflow::FlowKey, netflow::FlowCounters>,
+    address_claims: Vec<packet_parser::AddressClaim>,
+    nat_observations: Vec<nat_cpe::Observation>,
+    sightings: HashSet<presence::Sighting>,
+    // The device last seen at each subscriber address.
+    mac_bindings: HashMap<std::net::IpAddr, pnet_datalink::MacAddr>,
+    // Subscriber addresses sending traffic beyond the subscriber subnets.
+    senders: HashSet<std::net::IpAddr>,
+    known_flows: HashMap<packet_parser::FiveTuple, shedding::KnownFlow>,
+}
+impl ReportBatch {
+    fn add_shed(&mut self, shed: &shedding::ShedUsage, tracks_destinations: bool) {
+        let (bytes_up, bytes_down) = match shed.flow.upload {
+            true => (shed.bytes, 0),
+            false => (0, shed.bytes),
+        };
+        if tracks_destinations {
+            self.add_destination(shed.flow.remote_addr, bytes_up, bytes_down);
+        }
+        self.add_usage(
+            shed.flow.user_addr,
+            NetResourceBundle {
+                ran_bytes_down: bytes_down as i64,
+                ran_bytes_up: bytes_up as i64,
+                wan_bytes_down: bytes_down as i64,
+                wan_bytes_up: bytes_up as i64,
+                ..NetResourceBundle::zeroed()
+            }
+            .with_protocol(shed.flow.protocol)
+            .with_service(shed.flow.protocol, shed.flow.remote_port),
+        );
+        self.add_charge(shed.flow.user_addr, shed.flow.remote_addr, shed.bytes);
+    }
 
-    let interface_log = root_log.new(o!("interface" => String::from(&interface.name[..])));
+    fn add_usage(&mut self, id: std::net::IpAddr, amount: NetResourceBundle) {
+        *self
+            .user_usage
+            .entry(id)
+            .or_insert_with(NetResourceBundle::zeroed) += amount;
+    }
 
-    loop {
-        match rx.next() {
-            Ok(packet) => {
-                let packet_data_copy = bytes::Bytes::copy_from_slice(packet);
-                let packet_log = interface_log.new(o!());
-                let channel = user_aggregator.clone_input_channel();
-                let enforcer_channel = user_accounter.clone_input_channel();
-                let config = config.clone();
-
-                let packet_kind = match interface.mac {
-                    Some(_) => PacketKind::Ethernet(packet_data_copy),
-                    None => {
-                        // Distinguish between IPv4 and IPv6 by checking the IP
-                        // version nybl. Could be brittle to non-ip payloads.
-                        match (packet[0] & 0xF0) >> 4 {
-                            0x4 => PacketKind::IPv4(packet_data_copy),
-                            0x6 => PacketKind::IPv6(packet_data_copy),
-                            value => {
-                                slog::error!(packet_log, "Invalid IP version parsed"; "version"=> value);
-                                continue;
-                            }
-                        }
+    fn add_destination(&mut self, remote: std::net::IpAddr, bytes_up: u64, bytes_down: u64) {
+        *self
+            .destination_usage
+            .entry(remote)
+            .or_insert_with(NetResourceBundle::zeroed) += NetResourceBundle {
+            wan_bytes_up: bytes_up as i64,
+            wan_bytes_down: bytes_down as i64,
+            ..NetResourceBundle::zeroed()
+        };
+    }
+
+    fn add_charge(&mut self, id: std::net::IpAddr, remote: std::net::IpAddr, amount: u64) {
+        *self
+            .user_charges
+            .entry(id)
+            .or_default()
+            .entry(remote)
+            .or_insert(0) += amount;
+    }
+
+    fn add_flow(&mut self, flow: &UserRemote, tcp_flags: u16) {
+        let key = clickhouse::FlowKey {
+            user_addr: flow.user_addr,
+            remote_addr: flow.remote_addr,
+            user_port: flow.user_port,
+            remote_port: flow.remote_port,
+            protocol: flow.protocol,
+        };
+        *self.flows.entry(key).or_default() += clickhouse::FlowUsage {
+            bytes_up: flow.bytes_up,
+            bytes_down: flow.bytes_down,
+            packets: 1,
+            fin_up: tcp_flags & pnet_packet::tcp::TcpFlags::FIN != 0 && flow.bytes_up > 0,
+            fin_down: tcp_flags & pnet_packet::tcp::TcpFlags::FIN != 0 && flow.bytes_down > 0,
+            reset: tcp_flags & pnet_packet::tcp::TcpFlags::RST != 0,
+        };
+    }
+
+    fn add_netflow(&mut self, key: netflow::FlowKey, bytes: u64, tcp_flags: u16) {
+        *self.netflow_flows.entry(key).or_default() += netflow::FlowCounters {
+            bytes,
+            packets: 1,
+            tcp_flags: tcp_flags as u8,
+        };
+    }
+
+    async fn send(self, sinks: &PacketSinks, log: &Logger) {
+        // Devices are bound to their addresses before reporting usage, so that
+        // the workers started for a new address resolve its subscriber.
+        if let Some(mac_binder) = &sinks.mac_binder {
+            if !self.mac_bindings.is_empty() {
+                let (done, bound) = tokio::sync::oneshot::channel();
+                match mac_binder
+                    .send(mac_binding::Message::Bindings {
+                        bindings: self.mac_bindings,
+                        done,
+                    })
+                    .await
+                {
+                    Ok(()) => {
+                        let _ = bound.await;
                     }
+                    Err(e) => {
+                        slog::error!(log, "Failed to send to MAC binder"; "error" => e.to_string())
+                    }
+                }
+            }
+        }
+        for (id, amount) in self.user_usage {
+            sinks
+                .user_aggregator
+                .send(async_aggregator::Message::Report { id, amount })
+                .await
+                .unwrap_or_else(
+                    |e| slog::error!(log, "Failed to send to dispatcher"; "error" => e.to_string()),
+                );
+        }
+        if let Some(destination_aggregator) = &sinks.destination_aggregator {
+            for (id, amount) in self.destination_usage {
+                destination_aggregator
+                    .send(async_aggregator::Message::Report { id, amount })
+                    .await
+                    .unwrap_or_else(
+                        |e| slog::error!(log, "Failed to send to dispatcher"; "error" => e.to_string()),
+                    );
+            }
+        }
+        for (ip, amounts) in self.user_charges {
+            sinks
+                .user_accounter
+                .send(accounter::Message::Report { ip, amounts })
+                .await
+                .unwrap_or_else(
+                    |e| slog::error!(log, "Failed to send to dispatcher"; "error" => e.to_string()),
+                );
+        }
+        // Batches are only copied for the flow stream while it has subscribers.
+        if let Some(flow_stream) = &sinks.flow_stream {
+            if !self.flows.is_empty() && flow_stream.receiver_count() > 0 {
+                let batch = flow_stream::FlowBatch {
+                    time: chrono::Utc::now(),
+                    flows: self
+                        .flows
+                        .iter()
+                        .map(|(key, usage)| (*key, usage.clone()))
+                        .collect(),
                 };
-
-                tokio::task::spawn(async move {
-                    handle_packet(packet_kind, channel, enforcer_channel, config, packet_log).await;
-                });
+                // Sending only fails if every subscriber left in the meantime.
+                let _ = flow_stream.send(std::sync::Arc::new(batch));
             }
-            Err(e) => {
-                slog::error!(interface_log, "packetdump unable to receive packet: {}", e);
+        }
+        if let Some(netflow_exporter) = &sinks.netflow_exporter {
+            if !self.netflow_flows.is_empty() {
+                netflow_exporter
+                    .send(netflow::Message::Flows(self.netflow_flows))
+                    .await
+                    .unwrap_or_else(
+                        |e| slog::error!(log, "Failed to send to netflow exporter"; "error" => e.to_string()),
+                    );
+            }
+        }
+        if let (Some(flow_exporter), true) = (&sinks.flow_exporter, sinks.exports_flows) {
+            if !self.flows.is_empty() {
+                flow_exporter
+                    .send(clickhouse::Message::Flows(self.flows))
+                    .await
+                    .unwrap_or_else(
+                        |e| slog::error!(log, "Failed to send to flow exporter"; "error" => e.to_string()),
+                    );
+            }
+        } else if let Some(flow_logger) = &sinks.flow_logger {
+            if !self.flows.is_empty() {
+                flow_logger
+                    .send(flow_logger::Message::Flows(self.flows))
+                    .await
+                    .unwrap_or_else(
+                        |e| slog::error!(log, "Failed to send to flow logger"; "error" => e.to_string()),
+                    );
+            }
+        }
+        if let Some(trial_provisioner) = &sinks.trial_provisioner {
+            if !self.address_claims.is_empty() {
+                trial_provisioner
+                    .send(trial::Message::Claims(self.address_claims.clone()))
+                    .await
+                    .unwrap_or_else(
+                        |e| slog::error!(log, "Failed to send to trial provisioner"; "error" => e.to_string()),
+                    );
+            }
+            if !self.senders.is_empty() {
+                trial_provisioner
+                    .send(trial::Message::Senders(self.senders.into_iter().collect()))
+                    .await
+                    .unwrap_or_else(
+                        |e| slog::error!(log, "Failed to send to trial provisioner"; "error" => e.to_string()),
+                    );
+            }
+        }
+        if let Some(collision_detector) = &sinks.collision_detector {
+            if !self.address_claims.is_empty() {
+                collision_detector
+                    .send(address_collision::Message::Claims(self.address_claims))
+                    .await
+                    .unwrap_or_else(
+                        |e| slog::error!(log, "Failed to send to collision detector"; "error" => e.to_string()),
+                    );
+            }
+        }
+        if let Some(nat_observer) = &sinks.nat_observer {
+            if !self.nat_observations.is_empty() {
+                nat_observer
+                    .send(nat_cpe::Message::Observations(self.nat_observations))
+                    .await
+                    .unwrap_or_else(
+                        |e| slog::error!(log, "Failed to send to NAT observer"; "error" => e.to_string()),
+                    );
+            }
+        }
+        if let (Some(flow_exporter), true) = (&sinks.flow_exporter, sinks.exports_domains) {
+            for (subscriber, remote_addr, server_name) in self.server_names {
+                flow_exporter
+                    .send(clickhouse::Message::ServerName {
+                        subscriber,
+                        remote_addr,
+                        server_name,
+                    })
+                    .await
+                    .unwrap_or_else(
+                        |e| slog::error!(log, "Failed to send to flow exporter"; "error" => e.to_string()),
+                    );
+            }
+        }
+        if let Some(presence_tracker) = &sinks.presence_tracker {
+            if !self.sightings.is_empty() {
+                presence_tracker
+                    .send(presence::Message::Sightings(
+                        self.sightings.into_iter().collect(),
+                    ))
+                    .await
+                    .unwrap_or_else(
+                        |e| slog::error!(log, "Failed to send to presence tracker"; "error" => e.to_string()),
+                    );
+            }
+        }
+        for (subscriber, response) in self.dns_answers {
+            if let (Some(flow_exporter), true) = (&sinks.flow_exporter, sinks.exports_domains) {
+                flow_exporter
+                    .send(clickhouse::Message::DnsAnswer {
+                        subscriber,
+                        response: response.clone(),
+                    })
+                    .await
+                    .unwrap_or_else(
+                        |e| slog::error!(log, "Failed to send to flow exporter"; "error" => e.to_string()),
+                    );
+            }
+            if sinks.accounter_classifies {
+                sinks
+                    .user_accounter
+                    .send(accounter::Message::DnsAnswer {
+                        response: response.clone(),
+                    })
+                    .await
+                    .unwrap_or_else(
+                        |e| slog::error!(log, "Failed to send to dispatcher"; "error" => e.to_string()),
+                    );
+            }
+            if let Some(content_filter) = &sinks.content_filter {
+                content_filter
+                    .send(content_filter::Message::DnsAnswer {
+                        subscriber,
+                        response,
+                    })
+                    .await
+                    .unwrap_or_else(
+                        |e| slog::error!(log, "Failed to send to content filter"; "error" => e.to_string()),
+                    );
             }
         }
     }
 }
 
-async fn handle_packet<'a>(
-    packet: PacketKind,
-    user_agg_channel: tokio::sync::mpsc::Sender<async_aggregator::Message>,
-    user_enforcer_channel: tokio::sync::mpsc::Sender<accounter::Message>,
-    config: std::sync::Arc<config::Internal>,
-    log: Logger,
-) -> () {
+fn handle_packet(
+    mut packet: PacketKind,
+    reports: &mut ReportBatch,
+    fragment_cache: &packet_parser::FragmentCache,
+    now: std::time::Instant,
+    config: &config::Internal,
+    stats: &stats::Stats,
+    log: &Logger,
+) {
+    // IPv4 fragments are held until their datagram is complete, which is then
+    // handled in place of the final fragment.
+    let reassembly = match &packet {
+        PacketKind::Ethernet(packet_bytes) => fragment_cache.reassemble_ethernet(packet_bytes, now),
+        PacketKind::IPv4(packet_bytes) => fragment_cache.reassemble_ipv4(packet_bytes, now),
+        PacketKind::IPv6(_) => packet_parser::Reassembly::Unfragmented,
+    };
+    match reassembly {
+        packet_parser::Reassembly::Unfragmented => {}
+        packet_parser::Reassembly::Complete(datagram) => {
+            stats.fragments_reassembled.increment();
+            let datagram = bytes::Bytes::from(datagram);
+            packet = match packet {
+                PacketKind::Ethernet(_) => PacketKind::Ethernet(datagram),
+                _ => PacketKind::IPv4(datagram),
+            };
+        }
+        packet_parser::Reassembly::Held => return,
+        packet_parser::Reassembly::Dropped => {
+            stats.fragments_dropped.increment();
+            return;
+        }
+    }
+
     let parsed_packet = match packet {
-        PacketKind::Ethernet(packet_bytes) => packet_parser::parse_ethernet(&packet_bytes, &log),
-        PacketKind::IPv4(packet_bytes) => packet_parser::parse_ipv4(&packet_bytes, &log),
-        PacketKind::IPv6(packet_bytes) => packet_parser::parse_ipv6(&packet_bytes, &log),
+        PacketKind::Ethernet(packet_bytes) => {
+            if config.address_collision.is_some()
+                || config.presence.is_some()
+                || config.trial.is_some()
+            {
+                if let Some(claim) = packet_parser::parse_address_claim(&packet_bytes) {
+                    if config
+                        .user_subnets
+                        .iter()
+                        .any(|subnet| subnet.contains(claim.ip))
+                    {
+                        if config.presence.is_some() {
+                            reports.sightings.insert(presence::Sighting {
+                                ip: claim.ip,
+                                source: presence::Source::Neighbor,
+                            });
+                        }
+                        if config.address_collision.is_some() || config.trial.is_some() {
+                            reports.address_claims.push(claim);
+                        }
+                    }
+                }
+            }
+            packet_parser::parse_ethernet(&packet_bytes, log)
+        }
+        PacketKind::IPv4(packet_bytes) => packet_parser::parse_ipv4(&packet_bytes, log),
+        PacketKind::IPv6(packet_bytes) => packet_parser::parse_ipv6(&packet_bytes, log),
     };
 
     match parsed_packet {
         Ok(packet_info) => {
+            stats.packets_parsed.increment();
             slog::debug!(log, "Received packet info {:?}", packet_info);
             let normalized_flow = normalize_address(
                 &packet_info.fivetuple,
+                packet_info.macs,
                 packet_info.ip_payload_length as u64,
-                &config.user_subnet,
+                &config.user_subnets,
                 &config.ignored_user_addresses,
             );
+            let normalized_flow = match config.group_ipv6_prefixes {
+                true => group_ipv6_prefix(normalized_flow),
+                false => normalized_flow,
+            };
             slog::debug!(log, "Normalized to {:?}", normalized_flow);
 
             match normalized_flow {
                 NormalizedFlow::UserRemote(flow) => {
-                    user_agg_channel
-                        .send(async_aggregator::Message::Report {
-                            id: flow.user_addr,
-                            amount: NetResourceBundle {
-                                ran_bytes_down: flow.bytes_down as i64,
-                                ran_bytes_up: flow.bytes_up as i64,
-                                wan_bytes_down: flow.bytes_down as i64,
-                                wan_bytes_up: flow.bytes_up as i64,
-                            }
-                        })
-                        .await
-                        .unwrap_or_else(
-                            |e| slog::error!(log, "Failed to send to dispatcher"; "error" => e.to_string()),
+                    // Flows needing more than their fivetuple to account, such
+                    // as DNS answers and tunneled traffic, are never shed.
+                    if packet_info.encapsulation.is_empty() && packet_info.dns_response.is_none() {
+                        reports.known_flows.insert(
+                            packet_info.fivetuple,
+                            shedding::KnownFlow {
+                                user_addr: flow.user_addr,
+                                remote_addr: flow.remote_addr,
+                                protocol: flow.protocol,
+                                remote_port: flow.remote_port,
+                                upload: flow.bytes_up > 0,
+                            },
                         );
-                    user_enforcer_channel
-                        .send(accounter::Message::Report {
-                            ip: flow.user_addr,
-                            amount: flow.bytes_down + flow.bytes_up,
-                        })
-                        .await
-                        .unwrap_or_else(
-                            |e| slog::error!(log, "Failed to send to dispatcher"; "error" => e.to_string()),
+                    }
+                    reports.add_usage(
+                        flow.user_addr,
+                        NetResourceBundle {
+                            ran_bytes_down: flow.bytes_down as i64,
+                            ran_bytes_up: flow.bytes_up as i64,
+                            wan_bytes_down: flow.bytes_down as i64,
+                            wan_bytes_up: flow.bytes_up as i64,
+                            ..NetResourceBundle::zeroed()
+                        }
+                        .with_protocol(flow.protocol)
+                        .with_service(flow.protocol, flow.remote_port),
+                    );
+                    reports.add_charge(
+                        flow.user_addr,
+                        flow.remote_addr,
+                        flow.bytes_down + flow.bytes_up,
+                    );
+                    stats
+                        .user_bytes_charged
+                        .add(flow.bytes_down + flow.bytes_up);
+
+                    if flow.bytes_up > 0 && config.trial.is_some() {
+                        reports.senders.insert(flow.user_addr);
+                    }
+                    if let (true, Some(mac)) = (config.identify_by_mac, flow.user_mac) {
+                        reports.mac_bindings.insert(flow.user_addr, mac);
+                    }
+                    if config.top_destinations.is_some() {
+                        reports.add_destination(flow.remote_addr, flow.bytes_up, flow.bytes_down);
+                    }
+                    if config.accounting_level >= accounting::AccountingLevel::Flows
+                        || config.flow_stream.is_some()
+                    {
+                        reports.add_flow(&flow, packet_info.tcp_flags);
+                    }
+                    if config.netflow.is_some() {
+                        reports.add_netflow(
+                            netflow::FlowKey::directed(
+                                (flow.user_addr, flow.user_port),
+                                (flow.remote_addr, flow.remote_port),
+                                flow.protocol,
+                                flow.bytes_up == 0,
+                            ),
+                            flow.bytes_up + flow.bytes_down,
+                            packet_info.tcp_flags,
                         );
+                    }
+                    if flow.bytes_up > 0
+                        && config
+                            .presence
+                            .as_ref()
+                            .is_some_and(|presence| presence.from_traffic)
+                    {
+                        reports.sightings.insert(presence::Sighting {
+                            ip: flow.user_addr,
+                            source: presence::Source::Traffic,
+                        });
+                    }
+                    if config.nat_cpe.is_some() {
+                        reports.nat_observations.push(nat_cpe::Observation {
+                            user_addr: flow.user_addr,
+                            remote_addr: flow.remote_addr,
+                            user_port: flow.user_port,
+                            remote_port: flow.remote_port,
+                            bytes_up: flow.bytes_up,
+                            bytes_down: flow.bytes_down,
+                            ttl: packet_info.ttl,
+                            ip_id: packet_info.ip_id,
+                        });
+                    }
+                    // DNS answers are only held in memory by the content
+                    // filter and charging classes.
+                    if let Some(response) = packet_info.dns_response {
+                        if !config.content_filter_categories.is_empty()
+                            || !config.charging_classes.is_empty()
+                            || config.accounting_level >= accounting::AccountingLevel::Domains
+                        {
+                            reports.dns_answers.push((flow.user_addr, response));
+                        }
+                    }
+                    // Server names attribute encrypted traffic to domains even
+                    // when the lookup was cached or encrypted.
+                    if let Some(server_name) = packet_info.tls_server_name {
+                        if config.accounting_level >= accounting::AccountingLevel::Domains {
+                            reports.server_names.push((
+                                flow.user_addr,
+                                flow.remote_addr,
+                                server_name,
+                            ));
+                        }
+                    }
                 }
                 NormalizedFlow::UserUser(flow) => {
-                    user_agg_channel
-                        .send(async_aggregator::Message::Report {
-                            id: flow.a_addr,
-                            amount: NetResourceBundle {
-                                ran_bytes_down: flow.bytes_b_to_a as i64,
-                                ran_bytes_up: flow.bytes_a_to_b as i64,
-                                wan_bytes_down: 0,
-                                wan_bytes_up: 0,
-                            }
-                        })
-                        .await
-                        .unwrap_or_else(
-                            |e| slog::error!(log, "Failed to send to dispatcher"; "error" => e.to_string()),
-                        );
-                    user_agg_channel
-                        .send(async_aggregator::Message::Report {
-                            id: flow.b_addr,
-                            amount: NetResourceBundle {
-                                ran_bytes_down: flow.bytes_a_to_b as i64,
-                                ran_bytes_up: flow.bytes_b_to_a as i64,
-                                wan_bytes_down: 0,
-                                wan_bytes_up: 0,
-                            }
-                        })
-                        .await
-                        .unwrap_or_else(
-                            |e| slog::error!(log, "Failed to send to dispatcher"; "error" => e.to_string()),
+                    if config.netflow.is_some() {
+                        reports.add_netflow(
+                            netflow::FlowKey::directed(
+                                (flow.a_addr, flow.a_port),
+                                (flow.b_addr, flow.b_port),
+                                flow.protocol,
+                                flow.bytes_a_to_b == 0,
+                            ),
+                            flow.bytes_a_to_b + flow.bytes_b_to_a,
+                            packet_info.tcp_flags,
                         );
+                    }
+                    if config
+                        .presence
+                        .as_ref()
+                        .is_some_and(|presence| presence.from_traffic)
+                    {
+                        let sender = match flow.bytes_a_to_b > 0 {
+                            true => flow.a_addr,
+                            false => flow.b_addr,
+                        };
+                        reports.sightings.insert(presence::Sighting {
+                            ip: sender,
+                            source: presence::Source::Traffic,
+                        });
+                    }
+                    reports.add_usage(
+                        flow.a_addr,
+                        NetResourceBundle {
+                            ran_bytes_down: flow.bytes_b_to_a as i64,
+                            ran_bytes_up: flow.bytes_a_to_b as i64,
+                            wan_bytes_down: 0,
+                            wan_bytes_up: 0,
+                            ..NetResourceBundle::zeroed()
+                        }
+                        .with_protocol(flow.protocol)
+                        .with_service(flow.protocol, flow.b_port),
+                    );
+                    reports.add_usage(
+                        flow.b_addr,
+                        NetResourceBundle {
+                            ran_bytes_down: flow.bytes_a_to_b as i64,
+                            ran_bytes_up: flow.bytes_b_to_a as i64,
+                            wan_bytes_down: 0,
+                            wan_bytes_up: 0,
+                            ..NetResourceBundle::zeroed()
+                        }
+                        .with_protocol(flow.protocol)
+                        .with_service(flow.protocol, flow.a_port),
+                    );
+                }
+                NormalizedFlow::LinkLocal(fivetuple, bytes) => {
+                    stats.packets_link_local.increment();
+                    slog::debug!(log, "Ignored link local flow"; "flow" => std::format!("{:?}", fivetuple), "size" => bytes);
                 }
                 NormalizedFlow::Other(fivetuple, bytes) => {
+                    stats.packets_unnormalized.increment();
                     slog::info!(log, "Recevied unnormalizable flow"; "flow" => std::format!("{:?}", fivetuple), "size" => bytes);
                 }
             }
@@ -510,6 +3952,7 @@ async fn handle_packet<'a>(
                 slog::debug!(log, "Got an arp top level!");
             }
             _ => {
+                stats.parse_errors.increment();
                 slog::debug! {log, "Some other error {}", e};
             }
         },
@@ -520,6 +3963,9 @@ async fn handle_packet<'a>(
 pub enum NormalizedFlow {
     UserRemote(UserRemote),
     UserUser(UserUser),
+    // Traffic confined to the subscriber link, such as IPv6 neighbor
+    // discovery, which never crosses the network and is not billed.
+    LinkLocal(packet_parser::FiveTuple, u64),
     Other(packet_parser::FiveTuple, u64),
 }
 
@@ -532,6 +3978,8 @@ pub struct UserRemote {
     pub protocol: u8,
     pub bytes_up: u64,
     pub bytes_down: u64,
+    // The link layer address of the subscriber's device, if captured.
+    pub user_mac: Option<pnet_datalink::MacAddr>,
 }
 
 #[derive(Debug)]
@@ -545,12 +3993,20 @@ pub struct UserUser {
     pub bytes_b_to_a: u64,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct NetResourceBundle {
     pub ran_bytes_up: i64,
     pub ran_bytes_down: i64,
     pub wan_bytes_up: i64,
     pub wan_bytes_down: i64,
+    // The RAN bytes in both directions broken down by transport protocol.
+    pub tcp_bytes: i64,
+    pub udp_bytes: i64,
+    pub icmp_bytes: i64,
+    pub other_bytes: i64,
+    // The RAN bytes in both directions broken down by the service implied by
+    // the remote port.
+    pub services: ServiceBytes,
 }
 impl std::ops::Add for NetResourceBundle {
     type Output = Self;
@@ -561,6 +4017,11 @@ impl std::ops::Add for NetResourceBundle {
             ran_bytes_down: self.ran_bytes_down + other.ran_bytes_down,
             wan_bytes_up: self.wan_bytes_up + other.wan_bytes_up,
             wan_bytes_down: self.wan_bytes_down + other.wan_bytes_down,
+            tcp_bytes: self.tcp_bytes + other.tcp_bytes,
+            udp_bytes: self.udp_bytes + other.udp_bytes,
+            icmp_bytes: self.icmp_bytes + other.icmp_bytes,
+            other_bytes: self.other_bytes + other.other_bytes,
+            services: self.services + other.services,
         }
     }
 }
@@ -570,6 +4031,11 @@ impl std::ops::AddAssign for NetResourceBundle {
         self.ran_bytes_down = self.ran_bytes_down + rhs.ran_bytes_down;
         self.wan_bytes_up = self.wan_bytes_up + rhs.wan_bytes_up;
         self.wan_bytes_down = self.wan_bytes_down + rhs.wan_bytes_down;
+        self.tcp_bytes = self.tcp_bytes + rhs.tcp_bytes;
+        self.udp_bytes = self.udp_bytes + rhs.udp_bytes;
+        self.icmp_bytes = self.icmp_bytes + rhs.icmp_bytes;
+        self.other_bytes = self.other_bytes + rhs.other_bytes;
+        self.services = self.services + rhs.services;
     }
 }
 impl NetResourceBundle {
@@ -579,26 +4045,127 @@ impl NetResourceBundle {
             ran_bytes_down: 0,
             wan_bytes_up: 0,
             wan_bytes_down: 0,
+            tcp_bytes: 0,
+            udp_bytes: 0,
+            icmp_bytes: 0,
+            other_bytes: 0,
+            services: ServiceBytes::default(),
+        }
+    }
+
+    // Attributes all of the RAN bytes in the bundle to the given IP protocol
+    // number.
+    fn with_protocol(mut self, protocol: u8) -> Self {
+        let bytes = self.ran_bytes_up + self.ran_bytes_down;
+        match protocol {
+            6 => self.tcp_bytes = bytes,
+            17 => self.udp_bytes = bytes,
+            // ICMP and ICMPv6
+            1 | 58 => self.icmp_bytes = bytes,
+            _ => self.other_bytes = bytes,
+        }
+        self
+    }
+
+    // Attributes all of the RAN bytes in the bundle to the service bucket of
+    // the given IP protocol number and remote port.
+    fn with_service(mut self, protocol: u8, remote_port: u16) -> Self {
+        let bytes = self.ran_bytes_up + self.ran_bytes_down;
+        match (protocol, remote_port) {
+            (6, 443) => self.services.https_bytes = bytes,
+            (6, 80) => self.services.http_bytes = bytes,
+            (6 | 17, 53) => self.services.dns_bytes = bytes,
+            (17, 443) => self.services.quic_bytes = bytes,
+            (6 | 17, port) if is_voip_port(port) => self.services.voip_bytes = bytes,
+            _ => self.services.other_bytes = bytes,
+        }
+        self
+    }
+}
+
+// Usage by well-known remote port, giving a coarse view of the service mix
+// without attributing traffic to domains.
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ServiceBytes {
+    pub https_bytes: i64,
+    pub http_bytes: i64,
+    pub dns_bytes: i64,
+    pub quic_bytes: i64,
+    // Handed off intervals from versions without the field have none.
+    #[serde(default)]
+    pub voip_bytes: i64,
+    pub other_bytes: i64,
+}
+impl std::ops::Add for ServiceBytes {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        ServiceBytes {
+            https_bytes: self.https_bytes + other.https_bytes,
+            http_bytes: self.http_bytes + other.http_bytes,
+            dns_bytes: self.dns_bytes + other.dns_bytes,
+            quic_bytes: self.quic_bytes + other.quic_bytes,
+            voip_bytes: self.voip_bytes + other.voip_bytes,
+            other_bytes: self.other_bytes + other.other_bytes,
+        }
+    }
+}
+
+// Hosts using privacy extensions rotate through temporary addresses within
+// their /64, so each user address is replaced by its /64 network to account
+// and enforce the host as a single subscriber.
+fn group_ipv6_prefix(flow: NormalizedFlow) -> NormalizedFlow {
+    fn prefix(addr: std::net::IpAddr) -> std::net::IpAddr {
+        match addr {
+            std::net::IpAddr::V4(_) => addr,
+            std::net::IpAddr::V6(addr) => std::net::IpAddr::V6(std::net::Ipv6Addr::from(
+                u128::from(addr) & !(u64::MAX as u128),
+            )),
         }
     }
+    match flow {
+        NormalizedFlow::UserRemote(mut flow) => {
+            flow.user_addr = prefix(flow.user_addr);
+            NormalizedFlow::UserRemote(flow)
+        }
+        NormalizedFlow::UserUser(mut flow) => {
+            flow.a_addr = prefix(flow.a_addr);
+            flow.b_addr = prefix(flow.b_addr);
+            NormalizedFlow::UserUser(flow)
+        }
+        other => other,
+    }
+}
+
+// Signaling and media relay ports of common voice and video calling services:
+// SIP, IAX2, and STUN/TURN, which carries the calls of most messaging apps.
+// Media sent directly between dynamic ports cannot be told apart by port.
+fn is_voip_port(port: u16) -> bool {
+    matches!(port, 5060 | 5061 | 4569 | 3478..=3481)
 }
 
 fn normalize_address(
     flow_fivetuple: &packet_parser::FiveTuple,
+    macs: Option<(pnet_datalink::MacAddr, pnet_datalink::MacAddr)>,
     bytes: u64,
-    user_subnet: &ipnetwork::IpNetwork,
+    user_subnets: &[ipnetwork::IpNetwork],
     non_user_addrs: &HashSet<std::net::IpAddr>,
 ) -> NormalizedFlow {
-    let mut src_is_user = false;
-    let mut dst_is_user = false;
-
-    if user_subnet.contains(flow_fivetuple.src) && !non_user_addrs.contains(&flow_fivetuple.src) {
-        src_is_user = true;
-    }
-    if user_subnet.contains(flow_fivetuple.dst) && !non_user_addrs.contains(&flow_fivetuple.dst) {
-        dst_is_user = true;
+    // Link local addresses are reused on every link and neighbor discovery
+    // targets solicited-node multicast groups, so neither can be attributed
+    // to a subscriber even if a subscriber subnet happens to contain them.
+    if is_link_scoped(&flow_fivetuple.src) || is_link_scoped(&flow_fivetuple.dst) {
+        return NormalizedFlow::LinkLocal(*flow_fivetuple, bytes);
     }
 
+    // Subnets of one address family never contain addresses of the other, so
+    // IPv4 and IPv6 subscriber subnets can be checked uniformly.
+    let is_user = |addr: &std::net::IpAddr| {
+        user_subnets.iter().any(|subnet| subnet.contains(*addr)) && !non_user_addrs.contains(addr)
+    };
+    let src_is_user = is_user(&flow_fivetuple.src);
+    let dst_is_user = is_user(&flow_fivetuple.dst);
+
     if src_is_user && !dst_is_user {
         return NormalizedFlow::UserRemote(UserRemote {
             user_addr: flow_fivetuple.src,
@@ -608,6 +4175,7 @@ fn normalize_address(
             protocol: flow_fivetuple.protocol,
             bytes_up: bytes,
             bytes_down: 0,
+            user_mac: macs.map(|(src, _)| src),
         });
     } else if !src_is_user && dst_is_user {
         return NormalizedFlow::UserRemote(UserRemote {
@@ -618,6 +4186,7 @@ fn normalize_address(
             protocol: flow_fivetuple.protocol,
             bytes_up: 0,
             bytes_down: bytes,
+            user_mac: macs.map(|(_, dst)| dst),
         });
     } else if src_is_user && dst_is_user {
         // Normalize all user-user flows to assign endpoint a to the lower IP address.
@@ -647,8 +4216,232 @@ fn normalize_address(
     }
 }
 
+// Matches IPv6 link local unicast (fe80::/10) and interface or link local
+// scoped multicast, which includes the solicited-node groups (ff02::1:ff00:0/104).
+fn is_link_scoped(addr: &std::net::IpAddr) -> bool {
+    match addr {
+        std::net::IpAddr::V4(_) => false,
+        std::net::IpAddr::V6(addr) => {
+            let first = addr.segments()[0];
+            (first & 0xffc0) == 0xfe80 || first == 0xff01 || first == 0xff02
+        }
+    }
+}
+
 enum PacketKind {
     Ethernet(bytes::Bytes),
     IPv4(bytes::Bytes),
     IPv6(bytes::Bytes),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_fivetuple(src: &str, dst: &str) -> packet_parser::FiveTuple {
+        packet_parser::FiveTuple {
+            src: src.parse().unwrap(),
+            dst: dst.parse().unwrap(),
+            src_port: 50000,
+            dst_port: 443,
+            protocol: 6,
+        }
+    }
+
+    fn make_dual_stack_subnets() -> Vec<ipnetwork::IpNetwork> {
+        vec![
+            "10.45.0.0/24".parse().unwrap(),
+            "2001:db8:45::/48".parse().unwrap(),
+        ]
+    }
+
+    #[test]
+    fn test_normalize_ipv6_user_remote() {
+        let flow = make_fivetuple("2001:db8:45::10", "2a04:4e42:400::67");
+        match normalize_address(
+            &flow,
+            None,
+            100,
+            &make_dual_stack_subnets(),
+            &HashSet::new(),
+        ) {
+            NormalizedFlow::UserRemote(flow) => {
+                assert_eq!(
+                    flow.user_addr,
+                    "2001:db8:45::10".parse::<std::net::IpAddr>().unwrap()
+                );
+                assert_eq!(flow.bytes_up, 100);
+                assert_eq!(flow.bytes_down, 0);
+            }
+            other => panic!("Unexpected normalization {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_group_ipv6_prefix() {
+        let flow = make_fivetuple("2001:db8:45:0:a1b2:c3d4:e5f6:1", "2a04:4e42:400::67");
+        match group_ipv6_prefix(normalize_address(
+            &flow,
+            None,
+            100,
+            &make_dual_stack_subnets(),
+            &HashSet::new(),
+        )) {
+            NormalizedFlow::UserRemote(flow) => {
+                assert_eq!(
+                    flow.user_addr,
+                    "2001:db8:45::".parse::<std::net::IpAddr>().unwrap()
+                );
+                assert_eq!(
+                    flow.remote_addr,
+                    "2a04:4e42:400::67".parse::<std::net::IpAddr>().unwrap()
+                );
+            }
+            other => panic!("Unexpected normalization {:?}", other),
+        }
+        // IPv4 users are left as is.
+        let flow = make_fivetuple("10.45.0.2", "8.8.8.8");
+        match group_ipv6_prefix(normalize_address(
+            &flow,
+            None,
+            100,
+            &make_dual_stack_subnets(),
+            &HashSet::new(),
+        )) {
+            NormalizedFlow::UserRemote(flow) => {
+                assert_eq!(
+                    flow.user_addr,
+                    "10.45.0.2".parse::<std::net::IpAddr>().unwrap()
+                );
+            }
+            other => panic!("Unexpected normalization {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_normalize_dual_stack_ipv4_remote_user() {
+        let flow = make_fivetuple("8.8.8.8", "10.45.0.2");
+        let gateway = pnet_datalink::MacAddr(0x02, 0, 0, 0, 0, 0x01);
+        let device = pnet_datalink::MacAddr(0x02, 0, 0, 0, 0, 0x02);
+        match normalize_address(
+            &flow,
+            Some((gateway, device)),
+            100,
+            &make_dual_stack_subnets(),
+            &HashSet::new(),
+        ) {
+            NormalizedFlow::UserRemote(flow) => {
+                assert_eq!(
+                    flow.user_addr,
+                    "10.45.0.2".parse::<std::net::IpAddr>().unwrap()
+                );
+                assert_eq!(flow.bytes_down, 100);
+                assert_eq!(flow.user_mac, Some(device));
+            }
+            other => panic!("Unexpected normalization {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_normalize_ignored_ipv6_address() {
+        let flow = make_fivetuple("2001:db8:45::1", "2a04:4e42:400::67");
+        let ignored = HashSet::from_iter(vec!["2001:db8:45::1".parse().unwrap()]);
+        assert!(matches!(
+            normalize_address(&flow, None, 100, &make_dual_stack_subnets(), &ignored),
+            NormalizedFlow::Other(_, 100)
+        ));
+    }
+
+    #[test]
+    fn test_normalize_neighbor_discovery() {
+        // A subscriber subnet containing link local addresses must still not
+        // bill neighbor discovery.
+        let mut subnets = make_dual_stack_subnets();
+        subnets.push("fe80::/10".parse().unwrap());
+        for (src, dst) in [
+            ("2001:db8:45::10", "ff02::1:ff00:1"),
+            ("fe80::1", "fe80::2"),
+            ("fe80::1", "2001:db8:45::10"),
+            ("2001:db8:45::10", "ff02::2"),
+        ] {
+            let flow = make_fivetuple(src, dst);
+            assert!(matches!(
+                normalize_address(&flow, None, 72, &subnets, &HashSet::new()),
+                NormalizedFlow::LinkLocal(_, 72)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_bundle_protocol_breakdown() {
+        let bundle = |up, down| NetResourceBundle {
+            ran_bytes_up: up,
+            ran_bytes_down: down,
+            ..NetResourceBundle::zeroed()
+        };
+        let mut total = bundle(10, 90).with_protocol(6);
+        total += bundle(5, 15).with_protocol(17);
+        total += bundle(1, 1).with_protocol(58);
+        total += bundle(4, 0).with_protocol(47);
+        assert_eq!(
+            (
+                total.tcp_bytes,
+                total.udp_bytes,
+                total.icmp_bytes,
+                total.other_bytes
+            ),
+            (100, 20, 2, 4)
+        );
+        assert_eq!(total.ran_bytes_up + total.ran_bytes_down, 126);
+    }
+
+    #[test]
+    fn test_bundle_service_breakdown() {
+        let bundle = |up, down| NetResourceBundle {
+            ran_bytes_up: up,
+            ran_bytes_down: down,
+            ..NetResourceBundle::zeroed()
+        };
+        let mut total = bundle(10, 90).with_service(6, 443);
+        total += bundle(5, 15).with_service(17, 443);
+        total += bundle(1, 1).with_service(17, 53);
+        total += bundle(2, 0).with_service(6, 80);
+        total += bundle(4, 0).with_service(6, 8080);
+        total += bundle(3, 3).with_service(17, 3478);
+        total += bundle(1, 0).with_service(6, 5061);
+        assert_eq!(
+            total.services,
+            ServiceBytes {
+                https_bytes: 100,
+                http_bytes: 2,
+                dns_bytes: 2,
+                quic_bytes: 20,
+                voip_bytes: 7,
+                other_bytes: 4,
+            }
+        );
+    }
+
+    #[test]
+    fn test_v2_config_matches_v1() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let load = |name: &str| {
+            let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("..")
+                .join(name);
+            format!("{:?}", config::load(&path, &log).unwrap())
+        };
+        assert_eq!(load("config.v2.yml"), load("config.yml"));
+    }
+
+    #[test]
+    fn test_v2_config_rejects_unknown_options() {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../config.v2.yml");
+        let example = std::fs::read_to_string(path).unwrap();
+        assert!(serde_yaml::from_str::<config::V2>(&example).is_ok());
+
+        // A V1 option name in a section is reported rather than ignored.
+        let misplaced = example.replace("capture:\n", "capture:\n  filterCapture: true\n");
+        assert!(serde_yaml::from_str::<config::V2>(&misplaced).is_err());
+    }
+}
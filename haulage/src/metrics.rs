@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+#[derive(Error, Debug)]
+pub enum MetricsError {
+    #[error("Metrics endpoint io failed: {0}")]
+    IoError(#[from] std::io::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub listen: std::net::SocketAddr,
+    // Whether to export usage series for each subscriber address, which grow
+    // with the number of subscribers.
+    pub subscriber_metrics: bool,
+}
+
+// Bounds the request read before it is answered, since only the request line
+// is used.
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+// A latency counted in non-cumulative buckets by the statistics counters,
+// exported as a Prometheus histogram.
+struct Histogram {
+    prefix: &'static str,
+    metric: &'static str,
+    // Each bucket counter by suffix, with its upper bound in seconds.
+    buckets: &'static [(&'static str, f64)],
+}
+
+const HISTOGRAMS: [Histogram; 2] = [
+    Histogram {
+        prefix: "enforcement_latency_",
+        metric: "haulage_enforcement_latency_seconds",
+        buckets: &[
+            ("under_100ms", 0.1),
+            ("under_500ms", 0.5),
+            ("under_1s", 1.0),
+            ("under_5s", 5.0),
+            ("over_5s", f64::INFINITY),
+        ],
+    },
+    Histogram {
+        prefix: "db_write_latency_",
+        metric: "haulage_db_write_latency_seconds",
+        buckets: &[
+            ("under_10ms", 0.01),
+            ("under_50ms", 0.05),
+            ("under_250ms", 0.25),
+            ("under_1s", 1.0),
+            ("over_1s", f64::INFINITY),
+        ],
+    },
+];
+
+// Serves the statistics counters in the Prometheus text format for scraping,
+// along with values only meaningful live: per-subscriber usage since start and
+// the depth of the queues between subsystems, which grows when a subsystem
+// falls behind the capture.
+pub struct Metrics {
+    stats: std::sync::Arc<crate::stats::Stats>,
+    subscriber_metrics: bool,
+    pseudonymizer: Option<std::sync::Arc<crate::privacy::Pseudonymizer>>,
+    subscriber_bytes: std::sync::Mutex<HashMap<std::net::IpAddr, u64>>,
+    queues: std::sync::Mutex<Vec<Queue>>,
+}
+impl std::fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Metrics")
+    }
+}
+
+struct Queue {
+    name: &'static str,
+    // None once the queue is closed.
+    depth: Box<dyn Fn() -> Option<usize> + Send + Sync>,
+}
+
+impl Metrics {
+    pub fn new(
+        settings: &Settings,
+        stats: std::sync::Arc<crate::stats::Stats>,
+        pseudonymizer: Option<std::sync::Arc<crate::privacy::Pseudonymizer>>,
+    ) -> Metrics {
+        Metrics {
+            stats,
+            subscriber_metrics: settings.subscriber_metrics,
+            pseudonymizer,
+            subscriber_bytes: std::sync::Mutex::new(HashMap::new()),
+            queues: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn add_subscriber_bytes(&self, ip: std::net::IpAddr, bytes: u64) {
+        if !self.subscriber_metrics {
+            return;
+        }
+        let mut subscriber_bytes = self.subscriber_bytes.lock().unwrap();
+        *subscriber_bytes.entry(ip).or_default() += bytes;
+    }
+
+    // Exports the number of messages waiting in a subsystem's input channel.
+    // Only a weak handle is kept, so that watching a channel does not keep its
+    // subsystem running.
+    pub fn watch_queue<T: Send + 'static>(
+        &self,
+        name: &'static str,
+        sender: &tokio::sync::mpsc::Sender<T>,
+    ) {
+        let sender = sender.downgrade();
+        self.queues.lock().unwrap().push(Queue {
+            name,
+            depth: Box::new(move || {
+                let sender = sender.upgrade()?;
+                Some(sender.max_capacity() - sender.capacity())
+            }),
+        });
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = match serde_json::to_value(self.stats.snapshot()) {
+            Ok(serde_json::Value::Object(counters)) => counters,
+            _ => serde_json::Map::new(),
+        };
+        let counter = |name: &str| counters.get(name).and_then(|value| value.as_u64());
+
+        for (name, value) in &counters {
+            let histogram = HISTOGRAMS
+                .iter()
+                .any(|histogram| name.starts_with(histogram.prefix));
+            if let (false, Some(value)) = (histogram, value.as_u64()) {
+                let _ = writeln!(out, "# TYPE haulage_{}_total counter", name);
+                let _ = writeln!(out, "haulage_{}_total {}", name, value);
+            }
+        }
+
+        for Histogram {
+            prefix,
+            metric,
+            buckets,
+        } in HISTOGRAMS
+        {
+            let _ = writeln!(out, "# TYPE {} histogram", metric);
+            let mut cumulative = 0;
+            for (suffix, bound) in buckets {
+                cumulative += counter(&format!("{}{}", prefix, suffix)).unwrap_or(0);
+                let le = match bound.is_finite() {
+                    true => bound.to_string(),
+                    false => String::from("+Inf"),
+                };
+                let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", metric, le, cumulative);
+            }
+            let total_ms = counter(&format!("{}ms_total", prefix)).unwrap_or(0);
+            let _ = writeln!(out, "{}_sum {}", metric, total_ms as f64 / 1000.0);
+            let _ = writeln!(
+                out,
+                "{}_count {}",
+                metric,
+                counter(&format!("{}count", prefix)).unwrap_or(0)
+            );
+        }
+
+        let _ = writeln!(out, "# TYPE haulage_queue_depth gauge");
+        for queue in self.queues.lock().unwrap().iter() {
+            if let Some(depth) = (queue.depth)() {
+                let _ = writeln!(
+                    out,
+                    "haulage_queue_depth{{queue=\"{}\"}} {}",
+                    queue.name, depth
+                );
+            }
+        }
+
+        if self.subscriber_metrics {
+            let _ = writeln!(out, "# TYPE haulage_subscriber_bytes_total counter");
+            for (ip, bytes) in self.subscriber_bytes.lock().unwrap().iter() {
+                let ip = match &self.pseudonymizer {
+                    Some(pseudonymizer) => std::net::IpAddr::V6(pseudonymizer.address(*ip)),
+                    None => *ip,
+                };
+                let _ = writeln!(
+                    out,
+                    "haulage_subscriber_bytes_total{{ip=\"{}\"}} {}",
+                    ip, bytes
+                );
+            }
+        }
+        out
+    }
+}
+
+pub async fn serve(
+    listen: std::net::SocketAddr,
+    metrics: std::sync::Arc<Metrics>,
+    log: slog::Logger,
+) -> Result<(), MetricsError> {
+    let listener = tokio::net::TcpListener::bind(listen).await?;
+    slog::info!(log, "Serving metrics"; "listen" => listen.to_string());
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let metrics = std::sync::Arc::clone(&metrics);
+        let log = log.clone();
+        tokio::task::spawn(async move {
+            respond(stream, &metrics).await.unwrap_or_else(|e| {
+                slog::debug!(log, "Metrics request failed"; "peer" => peer.to_string(), "error" => e.to_string())
+            });
+        });
+    }
+}
+
+// Answers a single HTTP/1.1 request and closes the connection, which is all
+// a scraper needs.
+async fn respond(mut stream: tokio::net::TcpStream, metrics: &Metrics) -> Result<(), MetricsError> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 || request.len() + read > MAX_REQUEST_BYTES {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let (status, body) = match route(request.lines().next().unwrap_or_default()) {
+        Route::Metrics => ("200 OK", metrics.render()),
+        Route::NotFound => ("404 Not Found", String::from("Not found\n")),
+        Route::MethodNotAllowed => (
+            "405 Method Not Allowed",
+            String::from("Method not allowed\n"),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum Route {
+    Metrics,
+    NotFound,
+    MethodNotAllowed,
+}
+
+fn route(request_line: &str) -> Route {
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next(), parts.next().unwrap_or_default());
+    // Query parameters, e.g. from scrape configs, are ignored.
+    let path = path.split('?').next().unwrap_or_default();
+    match (method, path) {
+        (Some("GET"), "/metrics") => Route::Metrics,
+        (Some("GET"), _) => Route::NotFound,
+        _ => Route::MethodNotAllowed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_metrics() {
+        let stats = std::sync::Arc::new(crate::stats::Stats::default());
+        stats.packets_parsed.add(7);
+        stats.record_db_write_latency(std::time::Duration::from_millis(5));
+        stats.record_db_write_latency(std::time::Duration::from_millis(300));
+        let settings = Settings {
+            listen: "127.0.0.1:9464".parse().unwrap(),
+            subscriber_metrics: true,
+        };
+        let metrics = Metrics::new(&settings, stats, None);
+        metrics.add_subscriber_bytes("10.45.0.2".parse().unwrap(), 100);
+        metrics.add_subscriber_bytes("10.45.0.2".parse().unwrap(), 50);
+        let (sender, _receiver) = tokio::sync::mpsc::channel::<u8>(8);
+        sender.try_send(1).unwrap();
+        metrics.watch_queue("user_aggregator", &sender);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("haulage_packets_parsed_total 7\n"));
+        assert!(!rendered.contains("haulage_db_write_latency_count_total"));
+        assert!(rendered.contains("haulage_db_write_latency_seconds_bucket{le=\"0.01\"} 1\n"));
+        assert!(rendered.contains("haulage_db_write_latency_seconds_bucket{le=\"1\"} 2\n"));
+        assert!(rendered.contains("haulage_db_write_latency_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(rendered.contains("haulage_db_write_latency_seconds_sum 0.305\n"));
+        assert!(rendered.contains("haulage_queue_depth{queue=\"user_aggregator\"} 1\n"));
+        assert!(rendered.contains("haulage_subscriber_bytes_total{ip=\"10.45.0.2\"} 150\n"));
+
+        assert_eq!(route("GET /metrics HTTP/1.1"), Route::Metrics);
+        assert_eq!(route("GET /metrics?x=1 HTTP/1.1"), Route::Metrics);
+        assert_eq!(route("GET / HTTP/1.1"), Route::NotFound);
+        assert_eq!(route("POST /metrics HTTP/1.1"), Route::MethodNotAllowed);
+    }
+}
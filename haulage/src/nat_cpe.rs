@@ -0,0 +1,332 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum NatCpeError {
+    #[error("Database operation failed: {0}")]
+    DatabaseError(#[from] sqlx::error::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // The size of the blocks of CPE ports to break usage down by, e.g. to
+    // match CPEs which allocate each LAN device its own block of ports.
+    pub port_block_size: Option<u16>,
+}
+
+// The largest difference between consecutive identification fields sent by a
+// single device, allowing for the packets it sends within the household.
+const SEQUENCE_GAP: u16 = 256;
+
+// The number of times a sequence of identification fields must move between
+// connections to be taken as one device's counter rather than a connection's.
+const MIN_FLOW_SWITCHES: u32 = 4;
+
+// Bounds the sequences tracked per subscriber, e.g. under a port scan.
+const MAX_SEQUENCES: usize = 256;
+
+// The part of a packet relevant to the devices behind a subscriber's CPE.
+#[derive(Debug, Clone)]
+pub struct Observation {
+    pub user_addr: std::net::IpAddr,
+    pub remote_addr: std::net::IpAddr,
+    pub user_port: u16,
+    pub remote_port: u16,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub ttl: u8,
+    pub ip_id: Option<u16>,
+}
+
+// Estimates the devices behind NAT CPE and optionally breaks their usage down
+// by the CPE's ports, on the same intervals as subscriber usage. Traffic is
+// still attributed to the CPE's address, so balances and policies apply to
+// the whole household.
+#[derive(Debug)]
+pub struct NatObserver {
+    dispatch_channel: tokio::sync::mpsc::Sender<Message>,
+}
+impl NatObserver {
+    pub fn new(
+        settings: Settings,
+        schedule: crate::clock::Schedule,
+        db_pool: std::sync::Arc<sqlx::PgPool>,
+        log: slog::Logger,
+    ) -> NatObserver {
+        let (sender, receiver) = tokio::sync::mpsc::channel(64);
+        tokio::task::spawn(async move {
+            observe_households(receiver, settings, schedule, db_pool, log).await;
+        });
+        NatObserver {
+            dispatch_channel: sender,
+        }
+    }
+    pub fn clone_input_channel(&self) -> tokio::sync::mpsc::Sender<Message> {
+        self.dispatch_channel.clone()
+    }
+}
+
+pub enum Message {
+    Observations(Vec<Observation>),
+}
+
+// A run of IPv4 identification fields which appear to come from one counter.
+#[derive(Debug, Clone)]
+struct Sequence {
+    ttl: u8,
+    last_id: u16,
+    last_flow: (std::net::IpAddr, u16, u16),
+    flow_switches: u32,
+}
+
+// Counts the devices behind a NAT from the uplink packets passing through it,
+// after Bellovin's technique for counting NATted hosts. Devices which number
+// all their packets from a single counter, e.g. Windows hosts, show up as a
+// sequence of identification fields shared across connections. Devices using
+// a counter per connection, e.g. Linux and Android hosts, can only be told
+// apart by their default TTLs, so the estimate is a lower bound.
+#[derive(Debug, Default)]
+struct DeviceEstimator {
+    sequences: Vec<Sequence>,
+    ttls: BTreeSet<u8>,
+}
+impl DeviceEstimator {
+    fn observe(&mut self, ttl: u8, ip_id: Option<u16>, flow: (std::net::IpAddr, u16, u16)) {
+        self.ttls.insert(ttl);
+        // Packets not fragmented are often sent with a zero identification.
+        let id = match ip_id {
+            Some(0) | None => return,
+            Some(id) => id,
+        };
+
+        let next = self
+            .sequences
+            .iter_mut()
+            .filter(|sequence| sequence.ttl == ttl)
+            .filter_map(|sequence| {
+                let gap = id.wrapping_sub(sequence.last_id);
+                (gap > 0 && gap <= SEQUENCE_GAP).then_some((gap, sequence))
+            })
+            .min_by_key(|(gap, _)| *gap);
+        match next {
+            Some((_, sequence)) => {
+                sequence.last_id = id;
+                if sequence.last_flow != flow {
+                    sequence.last_flow = flow;
+                    sequence.flow_switches += 1;
+                }
+            }
+            None => {
+                if self.sequences.len() >= MAX_SEQUENCES {
+                    self.sequences.remove(0);
+                }
+                self.sequences.push(Sequence {
+                    ttl,
+                    last_id: id,
+                    last_flow: flow,
+                    flow_switches: 0,
+                });
+            }
+        }
+    }
+
+    fn estimate(&self) -> usize {
+        let shared_counters = self
+            .sequences
+            .iter()
+            .filter(|sequence| sequence.flow_switches >= MIN_FLOW_SWITCHES)
+            .count();
+        shared_counters.max(self.ttls.len())
+    }
+}
+
+// What has been observed of one subscriber within the current interval.
+#[derive(Debug, Default)]
+struct Household {
+    devices: DeviceEstimator,
+    // The bytes up and down by the first port of each block.
+    port_blocks: BTreeMap<u16, (u64, u64)>,
+}
+impl Household {
+    fn observe(&mut self, observation: &Observation, port_block_size: Option<u16>) {
+        // Only packets from the household carry its devices' header fields.
+        if observation.bytes_up > 0 {
+            self.devices.observe(
+                observation.ttl,
+                observation.ip_id,
+                (
+                    observation.remote_addr,
+                    observation.remote_port,
+                    observation.user_port,
+                ),
+            );
+        }
+        if let Some(size) = port_block_size {
+            let block = observation.user_port - observation.user_port % size;
+            let bytes = self.port_blocks.entry(block).or_default();
+            bytes.0 += observation.bytes_up;
+            bytes.1 += observation.bytes_down;
+        }
+    }
+}
+
+async fn observe_households(
+    mut chan: tokio::sync::mpsc::Receiver<Message>,
+    settings: Settings,
+    schedule: crate::clock::Schedule,
+    db_pool: std::sync::Arc<sqlx::PgPool>,
+    log: slog::Logger,
+) {
+    let mut households: HashMap<std::net::IpAddr, Household> = HashMap::new();
+    let mut start = chrono::Utc::now();
+    let mut timer = tokio::time::interval_at(
+        tokio::time::Instant::now() + schedule.first_delay(start),
+        schedule.period,
+    );
+    loop {
+        tokio::select! {
+            _ = timer.tick() => {
+                let end = schedule.boundary(chrono::Utc::now());
+                for (ip, household) in households.drain() {
+                    // Addresses are looked up each interval, since they may be
+                    // reassigned between intervals.
+                    let subscriber = match query_subscriber(&db_pool, ip).await {
+                        Ok(Some(subscriber)) => subscriber,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            slog::warn!(log, "Failed to look up subscriber"; "ip" => ip.to_string(), "error" => e.to_string());
+                            continue;
+                        }
+                    };
+                    record_household(&db_pool, subscriber, (start, end), &household)
+                        .await
+                        .unwrap_or_else(|e| slog::error!(log, "Failed to record household devices"; "subscriber" => subscriber, "error" => e.to_string()));
+                }
+                start = end;
+            }
+            message = chan.recv() => {
+                match message {
+                    Some(Message::Observations(batch)) => {
+                        for observation in batch {
+                            households
+                                .entry(observation.user_addr)
+                                .or_default()
+                                .observe(&observation, settings.port_block_size);
+                        }
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+}
+
+async fn query_subscriber(
+    db_pool: &sqlx::PgPool,
+    ip: std::net::IpAddr,
+) -> Result<Option<i32>, NatCpeError> {
+    let subscriber_query = r#"
+        SELECT "internal_uid"
+        FROM subscribers
+        INNER JOIN static_ips ON static_ips.imsi = subscribers.imsi
+        WHERE static_ips.ip >>= $1
+    "#;
+    let subscriber: Option<(i32,)> = sqlx::query_as(subscriber_query)
+        .bind(ipnetwork::IpNetwork::from(ip))
+        .fetch_optional(db_pool)
+        .await?;
+    Ok(subscriber.map(|(subscriber,)| subscriber))
+}
+
+// Subscribers with several addresses, e.g. an IPv4 address and an IPv6 prefix,
+// have the households observed at each address added together.
+async fn record_household(
+    db_pool: &sqlx::PgPool,
+    subscriber: i32,
+    (start, end): (chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>),
+    household: &Household,
+) -> Result<(), NatCpeError> {
+    let mut transaction = db_pool.begin().await?;
+
+    let devices_query = r#"
+        INSERT INTO subscriber_devices("subscriber", "start_time", "end_time", "estimated_devices")
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT ("subscriber", "start_time") DO UPDATE
+        SET "estimated_devices" = subscriber_devices."estimated_devices" + EXCLUDED."estimated_devices"
+    "#;
+    sqlx::query(devices_query)
+        .bind(subscriber)
+        .bind(start)
+        .bind(end)
+        .bind(household.devices.estimate() as i32)
+        .execute(&mut transaction)
+        .await?;
+
+    let ports_query = r#"
+        INSERT INTO subscriber_port_usage("subscriber", "start_time", "port_block", "bytes_up", "bytes_down")
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT ("subscriber", "start_time", "port_block") DO UPDATE
+        SET "bytes_up" = subscriber_port_usage."bytes_up" + EXCLUDED."bytes_up",
+            "bytes_down" = subscriber_port_usage."bytes_down" + EXCLUDED."bytes_down"
+    "#;
+    for (block, (bytes_up, bytes_down)) in &household.port_blocks {
+        sqlx::query(ports_query)
+            .bind(subscriber)
+            .bind(start)
+            .bind(*block as i32)
+            .bind(*bytes_up as i64)
+            .bind(*bytes_down as i64)
+            .execute(&mut transaction)
+            .await?;
+    }
+
+    transaction.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_devices() {
+        let remote: std::net::IpAddr = "192.0.2.1".parse().unwrap();
+        let flows = [(remote, 443, 40000), (remote, 443, 40001)];
+        let mut estimator = DeviceEstimator::default();
+
+        // Two hosts numbering packets from their own counters, interleaving
+        // their connections.
+        for i in 0..10 {
+            estimator.observe(127, Some(1000 + i), flows[i as usize % 2]);
+            estimator.observe(127, Some(30000 + i), flows[(i as usize + 1) % 2]);
+        }
+        assert_eq!(estimator.estimate(), 2);
+
+        // Per connection counters are not counted as devices of their own,
+        // but another default TTL is.
+        for i in 0..10 {
+            estimator.observe(63, Some(50000 + i), (remote, 80, 40002));
+            estimator.observe(63, Some(0), (remote, 80, 40003));
+        }
+        assert_eq!(estimator.estimate(), 2);
+        estimator.observe(255, None, (remote, 53, 40004));
+        assert_eq!(estimator.estimate(), 3);
+
+        let mut household = Household::default();
+        let observation = Observation {
+            user_addr: "10.45.0.2".parse().unwrap(),
+            remote_addr: remote,
+            user_port: 2100,
+            remote_port: 443,
+            bytes_up: 0,
+            bytes_down: 1500,
+            ttl: 57,
+            ip_id: None,
+        };
+        household.observe(&observation, Some(1024));
+        assert_eq!(household.port_blocks.get(&2048), Some(&(0, 1500)));
+        // Downlink packets carry the remote host's header fields.
+        assert_eq!(household.devices.estimate(), 0);
+    }
+}
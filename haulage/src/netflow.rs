@@ -0,0 +1,476 @@
+use std::collections::HashMap;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum NetflowError {
+    #[error("Flow export io failed: {0}")]
+    IoError(#[from] std::io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    NetflowV9,
+    Ipfix,
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // The host and port of the collector, e.g. nfcapd's default of port 2055.
+    pub collector: String,
+    pub format: Format,
+    // Flows are exported at least this often while active, and once idle
+    // for this long.
+    pub active_timeout: std::time::Duration,
+}
+
+// Export packets are kept below a typical path MTU, since flow records are
+// sent over UDP without fragmentation handling.
+const MAX_PACKET_BYTES: usize = 1400;
+
+// The ids of the templates describing IPv4 and IPv6 records. Ids below 256
+// are reserved for sets.
+const IPV4_TEMPLATE_ID: u16 = 256;
+const IPV6_TEMPLATE_ID: u16 = 257;
+
+// The fields of each record by element id and length, shared by both formats
+// except for the flow times.
+const IPV4_ADDRESS_FIELDS: [(u16, u16); 2] = [(8, 4), (12, 4)];
+const IPV6_ADDRESS_FIELDS: [(u16, u16); 2] = [(27, 16), (28, 16)];
+const COUNTER_FIELDS: [(u16, u16); 6] = [(7, 2), (11, 2), (4, 1), (6, 1), (1, 8), (2, 8)];
+// FIRST_SWITCHED and LAST_SWITCHED, relative to the exporter's uptime.
+const NETFLOW_V9_TIME_FIELDS: [(u16, u16); 2] = [(22, 4), (21, 4)];
+// flowStartMilliseconds and flowEndMilliseconds, since the Unix epoch.
+const IPFIX_TIME_FIELDS: [(u16, u16); 2] = [(152, 8), (153, 8)];
+
+// One direction of a flow, since NetFlow and IPFIX records are
+// unidirectional.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub src_addr: std::net::IpAddr,
+    pub dst_addr: std::net::IpAddr,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub protocol: u8,
+}
+impl FlowKey {
+    // The direction of a packet between two endpoints, sent by the first
+    // unless reversed.
+    pub fn directed(
+        a: (std::net::IpAddr, u16),
+        b: (std::net::IpAddr, u16),
+        protocol: u8,
+        reversed: bool,
+    ) -> FlowKey {
+        let (src, dst) = match reversed {
+            false => (a, b),
+            true => (b, a),
+        };
+        FlowKey {
+            src_addr: src.0,
+            dst_addr: dst.0,
+            src_port: src.1,
+            dst_port: dst.1,
+            protocol,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FlowCounters {
+    pub bytes: u64,
+    pub packets: u64,
+    // The TCP flags seen on any packet, ORed together.
+    pub tcp_flags: u8,
+}
+impl std::ops::AddAssign for FlowCounters {
+    fn add_assign(&mut self, other: Self) {
+        self.bytes += other.bytes;
+        self.packets += other.packets;
+        self.tcp_flags |= other.tcp_flags;
+    }
+}
+
+pub enum Message {
+    // The flows observed within a batch of packets.
+    Flows(HashMap<FlowKey, FlowCounters>),
+}
+
+// Exports the normalized flows between subscribers and remote hosts, and
+// between subscribers, to a NetFlow v9 or IPFIX collector, so that existing
+// flow analysis tools such as nfdump work from haulage's capture.
+#[derive(Debug)]
+pub struct NetflowExporter {
+    dispatch_channel: tokio::sync::mpsc::Sender<Message>,
+}
+impl NetflowExporter {
+    pub fn new(
+        settings: Settings,
+        user_subnets: Vec<ipnetwork::IpNetwork>,
+        pseudonymizer: Option<std::sync::Arc<crate::privacy::Pseudonymizer>>,
+        stats: std::sync::Arc<crate::stats::Stats>,
+        log: slog::Logger,
+    ) -> NetflowExporter {
+        let (sender, receiver) = tokio::sync::mpsc::channel(64);
+        let privacy = pseudonymizer.map(|pseudonymizer| Privacy {
+            user_subnets,
+            pseudonymizer,
+        });
+        tokio::task::spawn(async move {
+            export_flows(receiver, settings, privacy, stats, log).await;
+        });
+        NetflowExporter {
+            dispatch_channel: sender,
+        }
+    }
+    pub fn clone_input_channel(&self) -> tokio::sync::mpsc::Sender<Message> {
+        self.dispatch_channel.clone()
+    }
+}
+
+// In privacy mode, the subscriber addresses of records are pseudonymized,
+// leaving remote addresses as they are.
+struct Privacy {
+    user_subnets: Vec<ipnetwork::IpNetwork>,
+    pseudonymizer: std::sync::Arc<crate::privacy::Pseudonymizer>,
+}
+impl Privacy {
+    fn pseudonymize(&self, key: FlowKey) -> FlowKey {
+        let pseudonymize = |addr: std::net::IpAddr| match self
+            .user_subnets
+            .iter()
+            .any(|subnet| subnet.contains(addr))
+        {
+            true => std::net::IpAddr::V6(self.pseudonymizer.address(addr)),
+            false => addr,
+        };
+        FlowKey {
+            src_addr: pseudonymize(key.src_addr),
+            dst_addr: pseudonymize(key.dst_addr),
+            ..key
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ActiveFlow {
+    counters: FlowCounters,
+    first: chrono::DateTime<chrono::Utc>,
+    last: chrono::DateTime<chrono::Utc>,
+}
+
+async fn export_flows(
+    mut chan: tokio::sync::mpsc::Receiver<Message>,
+    settings: Settings,
+    privacy: Option<Privacy>,
+    stats: std::sync::Arc<crate::stats::Stats>,
+    log: slog::Logger,
+) {
+    let mut encoder = Encoder::new(settings.format, chrono::Utc::now());
+    let mut socket = None;
+    let mut flows: HashMap<FlowKey, ActiveFlow> = HashMap::new();
+    let mut timer = tokio::time::interval_at(
+        tokio::time::Instant::now() + settings.active_timeout,
+        settings.active_timeout,
+    );
+    loop {
+        let exported: Vec<(FlowKey, ActiveFlow)> = tokio::select! {
+            _ = timer.tick() => flows.drain().collect(),
+            message = chan.recv() => match message {
+                Some(Message::Flows(batch)) => {
+                    let now = chrono::Utc::now();
+                    for (key, counters) in batch {
+                        let key = match &privacy {
+                            Some(privacy) => privacy.pseudonymize(key),
+                            None => key,
+                        };
+                        let flow = flows.entry(key).or_insert(ActiveFlow {
+                            counters: FlowCounters::default(),
+                            first: now,
+                            last: now,
+                        });
+                        flow.counters += counters;
+                        flow.last = now;
+                    }
+                    continue;
+                }
+                None => break,
+            },
+        };
+        if exported.is_empty() {
+            continue;
+        }
+        let packets = encoder.encode(&exported, chrono::Utc::now());
+        match send(&mut socket, &settings.collector, &packets).await {
+            Ok(()) => stats.netflow_records_exported.add(exported.len() as u64),
+            Err(e) => {
+                // Reconnect with the next export, e.g. once the collector's
+                // name resolves again.
+                socket = None;
+                stats.netflow_export_errors.increment();
+                slog::warn!(log, "Failed to export flows"; "collector" => &settings.collector, "records" => exported.len(), "error" => e.to_string());
+            }
+        }
+    }
+}
+
+async fn send(
+    socket: &mut Option<tokio::net::UdpSocket>,
+    collector: &str,
+    packets: &[Vec<u8>],
+) -> Result<(), NetflowError> {
+    let socket = match socket {
+        Some(socket) => socket,
+        None => {
+            let connected = tokio::net::UdpSocket::bind("[::]:0").await?;
+            connected.connect(collector).await?;
+            socket.insert(connected)
+        }
+    };
+    for packet in packets {
+        socket.send(packet).await?;
+    }
+    Ok(())
+}
+
+// Encodes flows into export packets, each carrying the template of its
+// records so that collectors can decode them without waiting for a template
+// refresh, e.g. after a collector restart.
+struct Encoder {
+    format: Format,
+    // The exporter's start, which NetFlow v9 times are relative to.
+    start: chrono::DateTime<chrono::Utc>,
+    // Counts packets for NetFlow v9, and data records for IPFIX.
+    sequence: u32,
+}
+impl Encoder {
+    fn new(format: Format, start: chrono::DateTime<chrono::Utc>) -> Encoder {
+        Encoder {
+            format,
+            start,
+            sequence: 0,
+        }
+    }
+
+    fn encode(
+        &mut self,
+        flows: &[(FlowKey, ActiveFlow)],
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Vec<Vec<u8>> {
+        let (ipv4, ipv6): (Vec<_>, Vec<_>) = flows
+            .iter()
+            .partition(|(key, _)| key.src_addr.is_ipv4() && key.dst_addr.is_ipv4());
+        let mut packets = Vec::new();
+        for (template_id, flows) in [(IPV4_TEMPLATE_ID, ipv4), (IPV6_TEMPLATE_ID, ipv6)] {
+            let fields = self.fields(template_id);
+            let template = self.template_set(template_id, &fields);
+            let record_length: usize = fields.iter().map(|(_, length)| *length as usize).sum();
+            let per_packet =
+                (MAX_PACKET_BYTES - self.header_bytes() - template.len() - 4) / record_length;
+            for chunk in flows.chunks(per_packet) {
+                let mut data = Vec::new();
+                for (key, flow) in chunk {
+                    self.encode_record(&mut data, key, flow);
+                }
+                let mut set = Vec::new();
+                encode_set(&mut set, template_id, &data);
+                packets.push(self.packet(now, chunk.len(), &[&template, &set]));
+            }
+        }
+        packets
+    }
+
+    fn fields(&self, template_id: u16) -> Vec<(u16, u16)> {
+        let addresses = match template_id {
+            IPV4_TEMPLATE_ID => IPV4_ADDRESS_FIELDS,
+            _ => IPV6_ADDRESS_FIELDS,
+        };
+        let times = match self.format {
+            Format::NetflowV9 => NETFLOW_V9_TIME_FIELDS,
+            Format::Ipfix => IPFIX_TIME_FIELDS,
+        };
+        [&addresses[..], &COUNTER_FIELDS, &times].concat()
+    }
+
+    fn template_set(&self, template_id: u16, fields: &[(u16, u16)]) -> Vec<u8> {
+        let mut template = Vec::new();
+        template.extend_from_slice(&template_id.to_be_bytes());
+        template.extend_from_slice(&(fields.len() as u16).to_be_bytes());
+        for (id, length) in fields {
+            template.extend_from_slice(&id.to_be_bytes());
+            template.extend_from_slice(&length.to_be_bytes());
+        }
+        let set_id = match self.format {
+            Format::NetflowV9 => 0,
+            Format::Ipfix => 2,
+        };
+        let mut set = Vec::new();
+        encode_set(&mut set, set_id, &template);
+        set
+    }
+
+    fn encode_record(&self, data: &mut Vec<u8>, key: &FlowKey, flow: &ActiveFlow) {
+        for addr in [key.src_addr, key.dst_addr] {
+            match (addr, key.src_addr.is_ipv4() && key.dst_addr.is_ipv4()) {
+                (std::net::IpAddr::V4(addr), true) => data.extend_from_slice(&addr.octets()),
+                (std::net::IpAddr::V4(addr), false) => {
+                    data.extend_from_slice(&addr.to_ipv6_mapped().octets())
+                }
+                (std::net::IpAddr::V6(addr), _) => data.extend_from_slice(&addr.octets()),
+            }
+        }
+        data.extend_from_slice(&key.src_port.to_be_bytes());
+        data.extend_from_slice(&key.dst_port.to_be_bytes());
+        data.push(key.protocol);
+        data.push(flow.counters.tcp_flags);
+        data.extend_from_slice(&flow.counters.bytes.to_be_bytes());
+        data.extend_from_slice(&flow.counters.packets.to_be_bytes());
+        match self.format {
+            Format::NetflowV9 => {
+                data.extend_from_slice(&self.uptime_ms(flow.first).to_be_bytes());
+                data.extend_from_slice(&self.uptime_ms(flow.last).to_be_bytes());
+            }
+            Format::Ipfix => {
+                data.extend_from_slice(&(flow.first.timestamp_millis() as u64).to_be_bytes());
+                data.extend_from_slice(&(flow.last.timestamp_millis() as u64).to_be_bytes());
+            }
+        }
+    }
+
+    fn packet(
+        &mut self,
+        now: chrono::DateTime<chrono::Utc>,
+        records: usize,
+        sets: &[&[u8]],
+    ) -> Vec<u8> {
+        let length = self.header_bytes() + sets.iter().map(|set| set.len()).sum::<usize>();
+        let mut packet = Vec::with_capacity(length);
+        match self.format {
+            Format::NetflowV9 => {
+                packet.extend_from_slice(&9u16.to_be_bytes());
+                // The count includes the template record.
+                packet.extend_from_slice(&(records as u16 + 1).to_be_bytes());
+                packet.extend_from_slice(&self.uptime_ms(now).to_be_bytes());
+                packet.extend_from_slice(&(now.timestamp() as u32).to_be_bytes());
+                packet.extend_from_slice(&self.sequence.to_be_bytes());
+                self.sequence = self.sequence.wrapping_add(1);
+            }
+            Format::Ipfix => {
+                packet.extend_from_slice(&10u16.to_be_bytes());
+                packet.extend_from_slice(&(length as u16).to_be_bytes());
+                packet.extend_from_slice(&(now.timestamp() as u32).to_be_bytes());
+                packet.extend_from_slice(&self.sequence.to_be_bytes());
+                self.sequence = self.sequence.wrapping_add(records as u32);
+            }
+        }
+        // The source id or observation domain.
+        packet.extend_from_slice(&0u32.to_be_bytes());
+        for set in sets {
+            packet.extend_from_slice(set);
+        }
+        packet
+    }
+
+    fn header_bytes(&self) -> usize {
+        match self.format {
+            Format::NetflowV9 => 20,
+            Format::Ipfix => 16,
+        }
+    }
+
+    fn uptime_ms(&self, time: chrono::DateTime<chrono::Utc>) -> u32 {
+        (time - self.start).num_milliseconds().max(0) as u32
+    }
+}
+
+// Appends a set with its id and length, padded to a four byte boundary.
+fn encode_set(buffer: &mut Vec<u8>, set_id: u16, contents: &[u8]) {
+    let padding = (4 - contents.len() % 4) % 4;
+    buffer.extend_from_slice(&set_id.to_be_bytes());
+    buffer.extend_from_slice(&((4 + contents.len() + padding) as u16).to_be_bytes());
+    buffer.extend_from_slice(contents);
+    buffer.resize(buffer.len() + padding, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_export_packets() {
+        let start: chrono::DateTime<chrono::Utc> = "2026-10-16T00:00:00Z".parse().unwrap();
+        let flow = ActiveFlow {
+            counters: FlowCounters {
+                bytes: 1500,
+                packets: 2,
+                tcp_flags: 0x12,
+            },
+            first: start + chrono::Duration::seconds(1),
+            last: start + chrono::Duration::seconds(2),
+        };
+        let ipv4 = FlowKey::directed(
+            ("10.45.0.2".parse().unwrap(), 40000),
+            ("192.0.2.1".parse().unwrap(), 443),
+            6,
+            true,
+        );
+        let ipv6 = FlowKey::directed(
+            ("2001:db8:45::2".parse().unwrap(), 40000),
+            ("2001:db8::1".parse().unwrap(), 443),
+            17,
+            false,
+        );
+        assert_eq!(ipv4.src_port, 443);
+        let now = start + chrono::Duration::seconds(3);
+
+        let mut encoder = Encoder::new(Format::Ipfix, start);
+        let packets = encoder.encode(&[(ipv4, flow.clone()), (ipv6, flow.clone())], now);
+        assert_eq!(packets.len(), 2);
+        let packet = &packets[0];
+        assert_eq!(&packet[0..2], &10u16.to_be_bytes());
+        assert_eq!(&packet[2..4], &(packet.len() as u16).to_be_bytes());
+        // A template set of 10 fields, then a data set of one 46 byte record
+        // padded to 48 bytes.
+        let template_length = 4 + 4 + 10 * 4;
+        assert_eq!(&packet[16..18], &2u16.to_be_bytes());
+        let data = &packet[16 + template_length..];
+        assert_eq!(&data[0..2], &IPV4_TEMPLATE_ID.to_be_bytes());
+        assert_eq!(&data[2..4], &52u16.to_be_bytes());
+        assert_eq!(&data[4..8], &[192, 0, 2, 1]);
+        assert_eq!(&data[8..12], &[10, 45, 0, 2]);
+        assert_eq!(&data[12..14], &443u16.to_be_bytes());
+        assert_eq!(&data[18..26], &1500u64.to_be_bytes());
+        assert_eq!(
+            &data[34..42],
+            &(flow.first.timestamp_millis() as u64).to_be_bytes()
+        );
+        // IPFIX sequence numbers count the data records sent before.
+        assert_eq!(&packets[1][8..12], &1u32.to_be_bytes());
+
+        let mut encoder = Encoder::new(Format::NetflowV9, start);
+        let packets = encoder.encode(&[(ipv6, flow.clone())], now);
+        let packet = &packets[0];
+        assert_eq!(&packet[0..2], &9u16.to_be_bytes());
+        assert_eq!(&packet[2..4], &2u16.to_be_bytes());
+        assert_eq!(&packet[4..8], &3000u32.to_be_bytes());
+        let data = &packet[20 + template_length..];
+        assert_eq!(&data[0..2], &IPV6_TEMPLATE_ID.to_be_bytes());
+        assert_eq!(&data[4 + 32..4 + 34], &40000u16.to_be_bytes());
+        assert_eq!(&data[4 + 54..4 + 58], &1000u32.to_be_bytes());
+
+        // Records beyond a packet's worth are split across packets.
+        let many: Vec<_> = (0..100)
+            .map(|port| {
+                let key = FlowKey {
+                    src_port: port,
+                    ..ipv4
+                };
+                (key, flow.clone())
+            })
+            .collect();
+        let packets = Encoder::new(Format::Ipfix, start).encode(&many, now);
+        assert!(packets.len() > 1);
+        assert!(packets
+            .iter()
+            .all(|packet| packet.len() <= MAX_PACKET_BYTES));
+    }
+}
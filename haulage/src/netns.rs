@@ -0,0 +1,69 @@
+// A network interface and the network namespace containing it. In
+// containerized core deployments the subscriber and upstream interfaces may
+// live inside a container's namespace, reached from the host over veth pairs,
+// so traffic control commands must run inside that namespace.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Interface {
+    pub name: String,
+    // The name of a namespace managed by `ip netns`, or None for the namespace
+    // haulage runs in.
+    pub namespace: Option<String>,
+}
+impl Interface {
+    pub fn new(name: &str, namespace: Option<&str>) -> Interface {
+        Interface {
+            name: name.to_owned(),
+            namespace: namespace.map(str::to_owned),
+        }
+    }
+
+    pub fn namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    // Builds a command running in the interface's namespace.
+    pub fn command(&self, program: &str) -> tokio::process::Command {
+        command(self.namespace(), program)
+    }
+}
+impl std::fmt::Display for Interface {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.namespace {
+            Some(namespace) => write!(f, "{}@{}", self.name, namespace),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+// Builds a command running in the given namespace via `ip netns exec`.
+pub fn command(namespace: Option<&str>, program: &str) -> tokio::process::Command {
+    match namespace {
+        Some(namespace) => {
+            let mut command = tokio::process::Command::new("ip");
+            command.args(["netns", "exec", namespace, program]);
+            command
+        }
+        None => tokio::process::Command::new(program),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_namespaced_command() {
+        let interface = Interface::new("ogstun", Some("open5gs"));
+        let command = interface.command("tc");
+        let command = command.as_std();
+        assert_eq!(command.get_program(), "ip");
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            vec!["netns", "exec", "open5gs", "tc"]
+        );
+        assert_eq!(interface.to_string(), "ogstun@open5gs");
+
+        let host = Interface::new("eth0", None);
+        assert_eq!(host.command("tc").as_std().get_program(), "tc");
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum QuotaError {
+    #[error("Failed to run nft: {0}")]
+    NftExecutionError(#[from] std::io::Error),
+    #[error("nft rejected the ruleset: {0}")]
+    NftRejected(String),
+    #[error("Failed to parse nft output: {0}")]
+    SerdeJsonError(#[from] serde_json::Error),
+    #[error("Quota not found in nft output")]
+    QuotaMissing,
+    #[error("Lost communication with quota enforcer")]
+    CommunicationError,
+}
+
+const TABLE: &str = "haulage";
+
+// Enforces subscriber balances in the kernel with an nft quota object per
+// subscriber, so traffic is cut off at the exact byte the balance runs out
+// rather than up to one accounting interval late. Each quota is reset to the
+// database balance whenever the accounter synchronizes the balance, and any
+// bytes the kernel counted beyond those debited from captured traffic are
+// reported back so they can also be charged.
+#[derive(Debug)]
+pub struct NftQuota {
+    dispatch_channel: tokio::sync::mpsc::Sender<Message>,
+}
+impl NftQuota {
+    pub async fn new(log: slog::Logger) -> Result<NftQuota, QuotaError> {
+        run_nft_script(&table_script()).await?;
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(64);
+        tokio::task::spawn(async move {
+            manage_quotas(receiver, log).await;
+        });
+        Ok(NftQuota {
+            dispatch_channel: sender,
+        })
+    }
+
+    // Resets the subscriber's quota to their current balance, returning the
+    // number of bytes the kernel counted since the last reset beyond the given
+    // number of bytes already debited.
+    pub async fn sync(
+        &self,
+        subscriber: i32,
+        ip: std::net::IpAddr,
+        balance: i64,
+        debited: i64,
+    ) -> Result<u64, QuotaError> {
+        let (result_channel_tx, result_channel_rx) = tokio::sync::oneshot::channel();
+        self.dispatch_channel
+            .send(Message::Sync {
+                subscriber,
+                ip,
+                balance,
+                debited,
+                out_channel: result_channel_tx,
+            })
+            .await
+            .or(Err(QuotaError::CommunicationError))?;
+        result_channel_rx
+            .await
+            .or(Err(QuotaError::CommunicationError))?
+    }
+}
+
+enum Message {
+    Sync {
+        subscriber: i32,
+        ip: std::net::IpAddr,
+        balance: i64,
+        debited: i64,
+        out_channel: tokio::sync::oneshot::Sender<Result<u64, QuotaError>>,
+    },
+}
+
+async fn manage_quotas(mut chan: tokio::sync::mpsc::Receiver<Message>, log: slog::Logger) {
+    // The address mapped to each installed subscriber chain.
+    let mut installed: HashMap<i32, std::net::IpAddr> = HashMap::new();
+
+    while let Some(message) = chan.recv().await {
+        match message {
+            Message::Sync {
+                subscriber,
+                ip,
+                balance,
+                debited,
+                out_channel,
+            } => {
+                let previous_ip = installed.get(&subscriber).copied();
+                let result: Result<u64, QuotaError> = async {
+                    let unaccounted = match previous_ip {
+                        Some(_) => {
+                            let used = query_quota_used(subscriber).await?;
+                            used.saturating_sub(debited.max(0) as u64)
+                        }
+                        None => 0,
+                    };
+                    run_nft_script(&subscriber_script(subscriber, previous_ip, ip, balance))
+                        .await?;
+                    Ok(unaccounted)
+                }
+                .await;
+
+                match &result {
+                    Ok(unaccounted) => {
+                        installed.insert(subscriber, ip);
+                        slog::debug!(log, "Reset subscriber quota"; "subscriber" => subscriber, "balance" => balance, "unaccounted" => unaccounted);
+                    }
+                    Err(e) => {
+                        slog::warn!(log, "Failed to reset subscriber quota"; "subscriber" => subscriber, "error" => e.to_string());
+                    }
+                }
+                // The requester may have given up waiting.
+                let _ = out_channel.send(result);
+            }
+        }
+    }
+}
+
+// Recreates the haulage table, discarding any quotas from a previous run.
+// Subscriber traffic is dispatched to per-subscriber chains through address
+// maps, so that address changes only require replacing map elements.
+fn table_script() -> String {
+    format!(
+        "table inet {table}\n\
+         delete table inet {table}\n\
+         table inet {table} {{\n\
+         \tmap subscribers_v4 {{ type ipv4_addr : verdict; }}\n\
+         \tmap subscribers_v6 {{ type ipv6_addr : verdict; }}\n\
+         \tchain forward {{\n\
+         \t\ttype filter hook forward priority 0; policy accept;\n\
+         \t\tip saddr vmap @subscribers_v4\n\
+         \t\tip daddr vmap @subscribers_v4\n\
+         \t\tip6 saddr vmap @subscribers_v6\n\
+         \t\tip6 daddr vmap @subscribers_v6\n\
+         \t}}\n\
+         }}\n",
+        table = TABLE
+    )
+}
+
+// Replaces the subscriber's quota with a fresh quota of the given balance.
+// Named quotas cannot be resized while referenced, so the subscriber's chain
+// is flushed and rebuilt around the new quota in a single nft transaction.
+fn subscriber_script(
+    subscriber: i32,
+    previous_ip: Option<std::net::IpAddr>,
+    ip: std::net::IpAddr,
+    balance: i64,
+) -> String {
+    let chain = format!("sub_{}", subscriber);
+    let mut script = String::new();
+    match previous_ip {
+        Some(previous_ip) => {
+            script += &format!("flush chain inet {} {}\n", TABLE, chain);
+            script += &format!("delete quota inet {} {}\n", TABLE, chain);
+            if previous_ip != ip {
+                script += &format!(
+                    "delete element inet {} {} {{ {} }}\n",
+                    TABLE,
+                    address_map(&previous_ip),
+                    previous_ip
+                );
+            }
+        }
+        None => {
+            script += &format!("add chain inet {} {}\n", TABLE, chain);
+        }
+    }
+    if previous_ip != Some(ip) {
+        script += &format!(
+            "add element inet {} {} {{ {} : jump {} }}\n",
+            TABLE,
+            address_map(&ip),
+            ip,
+            chain
+        );
+    }
+    script += &format!(
+        "add quota inet {table} {chain} {{ over {balance} bytes }}\n\
+         add rule inet {table} {chain} quota name \"{chain}\" drop\n",
+        table = TABLE,
+        chain = chain,
+        balance = balance.max(0)
+    );
+    script
+}
+
+fn address_map(ip: &std::net::IpAddr) -> &'static str {
+    match ip {
+        std::net::IpAddr::V4(_) => "subscribers_v4",
+        std::net::IpAddr::V6(_) => "subscribers_v6",
+    }
+}
+
+async fn query_quota_used(subscriber: i32) -> Result<u64, QuotaError> {
+    let output = tokio::process::Command::new("nft")
+        .args([
+            "-j",
+            "list",
+            "quota",
+            "inet",
+            TABLE,
+            &format!("sub_{}", subscriber),
+        ])
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(QuotaError::NftRejected(
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ));
+    }
+    parse_quota_used(&output.stdout)
+}
+
+fn parse_quota_used(listing: &[u8]) -> Result<u64, QuotaError> {
+    let listing: serde_json::Value = serde_json::from_slice(listing)?;
+    listing["nftables"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find_map(|object| object["quota"]["used"].as_u64())
+        .ok_or(QuotaError::QuotaMissing)
+}
+
+async fn run_nft_script(script: &str) -> Result<(), QuotaError> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut child = tokio::process::Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("nft stdin is piped");
+    stdin.write_all(script.as_bytes()).await?;
+    drop(stdin);
+
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(QuotaError::NftRejected(
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_quota_used() {
+        let listing = br#"{"nftables": [{"metainfo": {"version": "1.0.2", "release_name": "Lester Gooch", "json_schema_version": 1}}, {"quota": {"family": "inet", "name": "sub_7", "table": "haulage", "handle": 3, "bytes": 1000000, "used": 1234, "inv": true}}]}"#;
+        assert_eq!(parse_quota_used(listing).unwrap(), 1234);
+        assert!(matches!(
+            parse_quota_used(br#"{"nftables": []}"#),
+            Err(QuotaError::QuotaMissing)
+        ));
+    }
+
+    #[test]
+    fn test_subscriber_script() {
+        let ip: std::net::IpAddr = "10.45.0.2".parse().unwrap();
+        assert_eq!(
+            subscriber_script(7, Some(ip), ip, 500),
+            "flush chain inet haulage sub_7\n\
+             delete quota inet haulage sub_7\n\
+             add quota inet haulage sub_7 { over 500 bytes }\n\
+             add rule inet haulage sub_7 quota name \"sub_7\" drop\n"
+        );
+
+        let script = subscriber_script(7, None, ip, -5);
+        assert!(script.starts_with("add chain inet haulage sub_7\n"));
+        assert!(
+            script.contains("add element inet haulage subscribers_v4 { 10.45.0.2 : jump sub_7 }\n")
+        );
+        assert!(script.contains("{ over 0 bytes }"));
+
+        let moved = subscriber_script(7, Some("10.45.0.3".parse().unwrap()), ip, 500);
+        assert!(moved.contains("delete element inet haulage subscribers_v4 { 10.45.0.3 }\n"));
+        assert!(
+            moved.contains("add element inet haulage subscribers_v4 { 10.45.0.2 : jump sub_7 }\n")
+        );
+    }
+}
@@ -1,15 +1,70 @@
 use thiserror::Error;
 
 mod parse_dns;
+mod parse_neighbor;
+mod parse_tls;
+mod reassembly;
 
+pub use parse_dns::DnsResponse;
+pub use parse_neighbor::{parse_address_claim, AddressClaim};
+pub use reassembly::{expire_fragments, FragmentCache, Reassembly};
+
+// The fivetuple, length, DNS response, and TLS server name describe the
+// innermost packet, after any tunnel encapsulations have been removed.
 #[derive(Debug)]
 pub struct PacketInfo {
     pub fivetuple: FiveTuple,
     pub ip_payload_length: u16,
     pub dns_response: Option<parse_dns::DnsResponse>,
+    // The server name a TLS ClientHello to an HTTPS port asked for.
+    pub tls_server_name: Option<String>,
+    // The TCP control flags, or zero for other transports.
+    pub tcp_flags: u16,
+    // The encapsulations removed to reach the inner packet, outermost first.
+    pub encapsulation: Vec<Encapsulation>,
+    // The TTL or hop limit, and the IPv4 identification field, which hint at
+    // the device that sent the packet.
+    pub ttl: u8,
+    pub ip_id: Option<u16>,
+    // The VLAN the innermost packet was tagged with, if any. Of stacked QinQ
+    // tags, the inner customer tag is kept.
+    pub vlan_id: Option<u16>,
+    // The source and destination link layer addresses of the innermost
+    // ethernet frame, if the packet was captured with its ethernet header.
+    pub macs: Option<(pnet_datalink::MacAddr, pnet_datalink::MacAddr)>,
+}
+
+// Tunnels which may carry subscriber traffic, e.g. on the S1-U or N3
+// interfaces of a cellular core, or in an overlay network.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Encapsulation {
+    GtpU { teid: u32 },
+    Vxlan { vni: u32 },
+}
+
+pub const GTPU_PORT: u16 = 2152;
+const HTTPS_PORT: u16 = 443;
+const VXLAN_PORT: u16 = 4789;
+
+// Limits nested encapsulation, so crafted packets cannot recurse without
+// bound.
+const MAX_LAYER_DEPTH: usize = 4;
+
+// Limits the IPv6 extension headers walked before the transport header. Valid
+// packets carry at most one of each kind, with destination options twice.
+const MAX_IPV6_EXTENSIONS: usize = 8;
+
+// A network layer header and everything it encapsulates. Packets are parsed
+// one layer at a time, and tunnel payloads are parsed as a new stack of layers,
+// so that the transport hooks such as DNS parsing always see the innermost
+// packet.
+enum Layer<'p> {
+    Ethernet(&'p [u8]),
+    Ipv4(&'p [u8]),
+    Ipv6(&'p [u8]),
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct FiveTuple {
     pub src: std::net::IpAddr,
     pub dst: std::net::IpAddr,
@@ -31,12 +86,61 @@ pub enum PacketParseError {
 pub fn parse_ethernet(
     packet: &[u8],
     logger: &slog::Logger,
+) -> Result<PacketInfo, PacketParseError> {
+    parse_layer(Layer::Ethernet(packet), 0, logger)
+}
+
+pub fn parse_ipv4(packet: &[u8], logger: &slog::Logger) -> Result<PacketInfo, PacketParseError> {
+    parse_layer(Layer::Ipv4(packet), 0, logger)
+}
+
+pub fn parse_ipv6(packet: &[u8], logger: &slog::Logger) -> Result<PacketInfo, PacketParseError> {
+    parse_layer(Layer::Ipv6(packet), 0, logger)
+}
+
+// Reads only the fivetuple and IP payload length of plain TCP and UDP packets,
+// for cheaply attributing packets of already known flows when overloaded.
+// Returns None for anything needing a full parse, such as DNS responses and
+// tunnels.
+pub fn peek_ethernet(packet: &[u8]) -> Option<(FiveTuple, u16)> {
+    peek_layer(Layer::Ethernet(packet))
+}
+
+pub fn peek_ipv4(packet: &[u8]) -> Option<(FiveTuple, u16)> {
+    peek_layer(Layer::Ipv4(packet))
+}
+
+pub fn peek_ipv6(packet: &[u8]) -> Option<(FiveTuple, u16)> {
+    peek_layer(Layer::Ipv6(packet))
+}
+
+fn parse_layer(
+    layer: Layer<'_>,
+    depth: usize,
+    logger: &slog::Logger,
+) -> Result<PacketInfo, PacketParseError> {
+    if depth > MAX_LAYER_DEPTH {
+        return Err(PacketParseError::BadPacket);
+    }
+    match layer {
+        Layer::Ethernet(packet) => parse_ethernet_layer(packet, depth, logger),
+        Layer::Ipv4(packet) => parse_ipv4_layer(packet, depth, logger),
+        Layer::Ipv6(packet) => parse_ipv6_layer(packet, depth, logger),
+    }
+}
+
+fn parse_ethernet_layer(
+    packet: &[u8],
+    depth: usize,
+    logger: &slog::Logger,
 ) -> Result<PacketInfo, PacketParseError> {
     let ethernet =
         pnet_packet::ethernet::EthernetPacket::new(packet).ok_or(PacketParseError::BadPacket)?;
-    match ethernet.get_ethertype() {
-        EtherTypes::Ipv4 => parse_ipv4(ethernet.payload(), logger),
-        EtherTypes::Ipv6 => parse_ipv6(ethernet.payload(), logger),
+    let (ethertype, payload, vlan_id) =
+        strip_vlan_tags(&ethernet).ok_or(PacketParseError::BadPacket)?;
+    let info = match ethertype {
+        EtherTypes::Ipv4 => parse_layer(Layer::Ipv4(payload), depth, logger),
+        EtherTypes::Ipv6 => parse_layer(Layer::Ipv6(payload), depth, logger),
         EtherTypes::Arp => Err(PacketParseError::IsArp),
         _ => {
             slog::info!(
@@ -44,15 +148,50 @@ pub fn parse_ethernet(
                 "Unknown packet: {} > {}; ethertype: {:?} length: {}",
                 ethernet.get_source(),
                 ethernet.get_destination(),
-                ethernet.get_ethertype(),
+                ethertype,
                 ethernet.packet_size(),
             );
             Err(PacketParseError::BadPacket)
         }
+    };
+    info.map(|mut info| {
+        // The tags of a tunnel's own frames do not describe the inner packet.
+        if info.encapsulation.is_empty() {
+            info.vlan_id = vlan_id;
+        }
+        // Frames carried in a tunnel have already filled in their own
+        // addresses.
+        info.macs
+            .get_or_insert((ethernet.get_source(), ethernet.get_destination()));
+        info
+    })
+}
+
+// Removes any 802.1Q or QinQ VLAN tags following the Ethernet header,
+// returning the ethertype and payload they carry, and the innermost VLAN ID.
+fn strip_vlan_tags<'p>(
+    ethernet: &'p pnet_packet::ethernet::EthernetPacket<'p>,
+) -> Option<(EtherType, &'p [u8], Option<u16>)> {
+    let mut ethertype = ethernet.get_ethertype();
+    let mut payload = ethernet.payload();
+    let mut vlan_id = None;
+    while matches!(
+        ethertype,
+        EtherTypes::Vlan | EtherTypes::PBridge | EtherTypes::QinQ
+    ) {
+        let tag = payload.get(..4)?;
+        vlan_id = Some(u16::from_be_bytes([tag[0], tag[1]]) & 0x0fff);
+        ethertype = EtherType::new(u16::from_be_bytes([tag[2], tag[3]]));
+        payload = &payload[4..];
     }
+    Some((ethertype, payload, vlan_id))
 }
 
-pub fn parse_ipv4(packet: &[u8], logger: &slog::Logger) -> Result<PacketInfo, PacketParseError> {
+fn parse_ipv4_layer(
+    packet: &[u8],
+    depth: usize,
+    logger: &slog::Logger,
+) -> Result<PacketInfo, PacketParseError> {
     match Ipv4Packet::new(packet) {
         Some(header) => parse_transport(
             std::net::IpAddr::V4(header.get_source()),
@@ -61,8 +200,10 @@ pub fn parse_ipv4(packet: &[u8], logger: &slog::Logger) -> Result<PacketInfo, Pa
             header.get_total_length() - ((header.get_header_length() as u16) * 4),
             header.get_next_level_protocol(),
             header.payload(),
+            depth,
             logger,
-        ),
+        )
+        .map(|info| with_ip_header(info, header.get_ttl(), Some(header.get_identification()))),
         None => {
             slog::info!(logger, "Malformed IPv4 Packet");
             Err(PacketParseError::BadPacket)
@@ -70,39 +211,128 @@ pub fn parse_ipv4(packet: &[u8], logger: &slog::Logger) -> Result<PacketInfo, Pa
     }
 }
 
-pub fn parse_ipv6(packet: &[u8], logger: &slog::Logger) -> Result<PacketInfo, PacketParseError> {
-    match Ipv6Packet::new(packet) {
-        Some(header) => parse_transport(
+fn parse_ipv6_layer(
+    packet: &[u8],
+    depth: usize,
+    logger: &slog::Logger,
+) -> Result<PacketInfo, PacketParseError> {
+    let header = match Ipv6Packet::new(packet) {
+        Some(header) => header,
+        None => {
+            slog::info!(logger, "Malformed IPv6 Packet");
+            return Err(PacketParseError::BadPacket);
+        }
+    };
+    let transport = match skip_ipv6_extensions(header.get_next_header(), header.payload()) {
+        Some(transport) => transport,
+        None => {
+            slog::info!(logger, "Malformed IPv6 extension headers");
+            return Err(PacketParseError::BadPacket);
+        }
+    };
+    // Like IPv4 options, extension headers are not counted in the payload.
+    let extensions_length = header.payload().len() - transport.payload.len();
+    let ip_payload_length = header
+        .get_payload_length()
+        .checked_sub(extensions_length as u16)
+        .ok_or(PacketParseError::BadPacket)?;
+
+    let parsed = match transport.has_transport_header {
+        true => parse_transport(
             std::net::IpAddr::V6(header.get_source()),
             std::net::IpAddr::V6(header.get_destination()),
-            header.get_payload_length(),
-            header.get_next_header(),
-            header.payload(),
+            ip_payload_length,
+            transport.protocol,
+            transport.payload,
+            depth,
             logger,
-        )
+        ),
+        false => Err(PacketParseError::UnhandledTransport),
+    };
+    parsed
+        .map(|info| with_ip_header(info, header.get_hop_limit(), None))
         .or_else(|e| match e {
             PacketParseError::UnhandledTransport => Ok(PacketInfo {
                 fivetuple: create_unknown_transport_fivetuple(
                     std::net::IpAddr::V6(header.get_source()),
                     std::net::IpAddr::V6(header.get_destination()),
-                    header.get_next_header(),
+                    transport.protocol,
                     logger,
                 ),
-                ip_payload_length: header.get_payload_length(),
+                ip_payload_length,
                 dns_response: None,
+                tls_server_name: None,
+                tcp_flags: 0,
+                encapsulation: Vec::new(),
+                ttl: header.get_hop_limit(),
+                ip_id: None,
+                vlan_id: None,
+                macs: None,
             }),
             _ => Err(e),
-        }),
-        None => {
-            slog::info!(logger, "Malformed IPv6 Packet");
-            Err(PacketParseError::BadPacket)
-        }
+        })
+}
+
+// The transport protocol of an IPv6 packet and its payload, found after any
+// extension headers.
+struct Ipv6Transport<'p> {
+    protocol: IpNextHeaderProtocol,
+    payload: &'p [u8],
+    // Whether the payload begins with the transport header, which is only
+    // the case for the first fragment of a fragmented packet.
+    has_transport_header: bool,
+}
+
+// Walks the chain of standard extension headers following the fixed IPv6
+// header. ESP is treated as the transport, since everything after it is
+// encrypted.
+fn skip_ipv6_extensions(
+    mut next_header: IpNextHeaderProtocol,
+    mut payload: &[u8],
+) -> Option<Ipv6Transport<'_>> {
+    let mut has_transport_header = true;
+    for _ in 0..MAX_IPV6_EXTENSIONS {
+        let length = match next_header {
+            // Lengths are in units of eight bytes, excluding the first eight.
+            IpNextHeaderProtocols::Hopopt
+            | IpNextHeaderProtocols::Ipv6Route
+            | IpNextHeaderProtocols::Ipv6Opts => (*payload.get(1)? as usize + 1) * 8,
+            IpNextHeaderProtocols::Ipv6Frag => {
+                let offset = u16::from_be_bytes([*payload.get(2)?, *payload.get(3)?]) >> 3;
+                has_transport_header &= offset == 0;
+                8
+            }
+            // Lengths are in units of four bytes, excluding the first eight.
+            IpNextHeaderProtocols::Ah => (*payload.get(1)? as usize + 2) * 4,
+            protocol => {
+                return Some(Ipv6Transport {
+                    protocol,
+                    payload,
+                    has_transport_header,
+                })
+            }
+        };
+        next_header = IpNextHeaderProtocol::new(*payload.first()?);
+        payload = payload.get(length..)?;
     }
+    None
 }
 
-use pnet_packet::ethernet::EtherTypes;
+// Fills in the IP header fields of the innermost packet, leaving those already
+// filled in by the inner packet of a tunnel.
+fn with_ip_header(mut info: PacketInfo, ttl: u8, ip_id: Option<u16>) -> PacketInfo {
+    if info.encapsulation.is_empty() {
+        info.ttl = ttl;
+        info.ip_id = ip_id;
+    }
+    info
+}
+
+use pnet_packet::ethernet::{EtherType, EtherTypes};
+use pnet_packet::icmp::IcmpPacket;
+use pnet_packet::icmpv6::Icmpv6Packet;
 use pnet_packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
-use pnet_packet::ipv4::Ipv4Packet;
+use pnet_packet::ipv4::{Ipv4Flags, Ipv4Packet};
 use pnet_packet::ipv6::Ipv6Packet;
 use pnet_packet::tcp::TcpPacket;
 use pnet_packet::udp::UdpPacket;
@@ -138,15 +368,29 @@ fn parse_transport(
     ip_payload_length: u16,
     protocol: IpNextHeaderProtocol,
     packet: &[u8],
+    depth: usize,
     logger: &slog::Logger,
 ) -> Result<PacketInfo, PacketParseError> {
     match protocol {
-        IpNextHeaderProtocols::Udp => {
-            parse_transport_udp(source, destination, ip_payload_length, packet, logger)
-        }
+        IpNextHeaderProtocols::Udp => parse_transport_udp(
+            source,
+            destination,
+            ip_payload_length,
+            packet,
+            depth,
+            logger,
+        ),
         IpNextHeaderProtocols::Tcp => {
             parse_transport_tcp(source, destination, ip_payload_length, packet, logger)
         }
+        IpNextHeaderProtocols::Icmp | IpNextHeaderProtocols::Icmpv6 => parse_transport_icmp(
+            source,
+            destination,
+            ip_payload_length,
+            protocol,
+            packet,
+            logger,
+        ),
         _ => Err(PacketParseError::UnhandledTransport),
     }
 }
@@ -156,6 +400,7 @@ fn parse_transport_udp(
     destination: std::net::IpAddr,
     ip_payload_length: u16,
     packet: &[u8],
+    depth: usize,
     logger: &slog::Logger,
 ) -> Result<PacketInfo, PacketParseError> {
     match UdpPacket::new(packet) {
@@ -176,6 +421,20 @@ fn parse_transport_udp(
                 return Err(PacketParseError::BadPacket);
             }
 
+            // Parse the tunneled packet in place of the tunnel itself. Traffic
+            // which only looks like a tunnel is handled as plain UDP.
+            if let Some((encapsulation, inner)) = decapsulate(dst_port, udp.payload()) {
+                match parse_layer(inner, depth + 1, logger) {
+                    Ok(mut info) => {
+                        info.encapsulation.insert(0, encapsulation);
+                        return Ok(info);
+                    }
+                    Err(e) => {
+                        slog::debug!(logger, "Failed to parse tunneled packet"; "error" => e.to_string());
+                    }
+                }
+            }
+
             // Attempt to parse DNS if on the known DNS port
             let mut dns_response = None;
             if src_port == 53 {
@@ -199,6 +458,13 @@ fn parse_transport_udp(
                 },
                 ip_payload_length: ip_payload_length,
                 dns_response: dns_response,
+                tls_server_name: None,
+                tcp_flags: 0,
+                encapsulation: Vec::new(),
+                ttl: 0,
+                ip_id: None,
+                vlan_id: None,
+                macs: None,
             })
         }
         None => {
@@ -233,6 +499,11 @@ fn parse_transport_tcp(
                 return Err(PacketParseError::BadPacket);
             }
 
+            let tls_server_name = match dst_port {
+                HTTPS_PORT => parse_tls::parse_client_hello_server_name(tcp.payload()),
+                _ => None,
+            };
+
             Ok(PacketInfo {
                 fivetuple: FiveTuple {
                     src: source,
@@ -243,6 +514,13 @@ fn parse_transport_tcp(
                 },
                 ip_payload_length: ip_payload_length,
                 dns_response: None,
+                tls_server_name,
+                tcp_flags: tcp.get_flags(),
+                encapsulation: Vec::new(),
+                ttl: 0,
+                ip_id: None,
+                vlan_id: None,
+                macs: None,
             })
         }
         None => {
@@ -252,9 +530,195 @@ fn parse_transport_tcp(
     }
 }
 
+// ICMP has no ports, so the message type and code are folded into the
+// destination port as type * 256 + code, as in NetFlow, leaving the source port
+// zero. Echo requests and their replies are therefore separate flows, as are
+// errors returned for other flows.
+fn parse_transport_icmp(
+    source: std::net::IpAddr,
+    destination: std::net::IpAddr,
+    ip_payload_length: u16,
+    protocol: IpNextHeaderProtocol,
+    packet: &[u8],
+    logger: &slog::Logger,
+) -> Result<PacketInfo, PacketParseError> {
+    let message = match protocol {
+        IpNextHeaderProtocols::Icmp => {
+            IcmpPacket::new(packet).map(|icmp| (icmp.get_icmp_type().0, icmp.get_icmp_code().0))
+        }
+        _ => Icmpv6Packet::new(packet)
+            .map(|icmp| (icmp.get_icmpv6_type().0, icmp.get_icmpv6_code().0)),
+    };
+    match message {
+        Some((icmp_type, icmp_code)) => {
+            slog::debug!(
+                logger,
+                "ICMP Packet: {} > {}; type: {} code: {} length: {}",
+                source,
+                destination,
+                icmp_type,
+                icmp_code,
+                packet.len()
+            );
+
+            if (ip_payload_length as usize) != packet.len() {
+                return Err(PacketParseError::BadPacket);
+            }
+
+            Ok(PacketInfo {
+                fivetuple: FiveTuple {
+                    src: source,
+                    dst: destination,
+                    src_port: 0,
+                    dst_port: u16::from_be_bytes([icmp_type, icmp_code]),
+                    protocol: protocol.to_primitive_values().0,
+                },
+                ip_payload_length,
+                dns_response: None,
+                tls_server_name: None,
+                tcp_flags: 0,
+                encapsulation: Vec::new(),
+                ttl: 0,
+                ip_id: None,
+                vlan_id: None,
+                macs: None,
+            })
+        }
+        None => {
+            slog::info!(logger, "Malformed ICMP Packet");
+            Err(PacketParseError::BadPacket)
+        }
+    }
+}
+
+// Returns the inner layer of a GTP-U or VXLAN tunnel payload, identified by
+// its well known destination port.
+fn decapsulate(dst_port: u16, payload: &[u8]) -> Option<(Encapsulation, Layer<'_>)> {
+    match dst_port {
+        GTPU_PORT => decapsulate_gtpu(payload),
+        VXLAN_PORT => decapsulate_vxlan(payload),
+        _ => None,
+    }
+}
+
+fn decapsulate_gtpu(payload: &[u8]) -> Option<(Encapsulation, Layer<'_>)> {
+    const GPDU_MESSAGE_TYPE: u8 = 0xff;
+
+    // Only version 1 G-PDUs carry user traffic.
+    let flags = *payload.first()?;
+    if flags >> 5 != 1 || flags & 0x10 == 0 || *payload.get(1)? != GPDU_MESSAGE_TYPE {
+        return None;
+    }
+    let length = u16::from_be_bytes([*payload.get(2)?, *payload.get(3)?]) as usize;
+    let teid = u32::from_be_bytes(payload.get(4..8)?.try_into().ok()?);
+    let payload = payload.get(8..8 + length)?;
+
+    // The sequence number, N-PDU number, and next extension type fields are
+    // present if any of the optional field flags are set.
+    let mut offset = 0;
+    if flags & 0x07 != 0 {
+        let mut next_extension = *payload.get(3)?;
+        offset = 4;
+        while next_extension != 0 {
+            // Extension lengths are in units of four bytes, and the next
+            // extension type is the final byte of each extension.
+            let extension_length = *payload.get(offset)? as usize * 4;
+            if extension_length == 0 {
+                return None;
+            }
+            offset += extension_length;
+            next_extension = *payload.get(offset - 1)?;
+        }
+    }
+
+    let inner = payload.get(offset..)?;
+    let layer = match *inner.first()? >> 4 {
+        4 => Layer::Ipv4(inner),
+        6 => Layer::Ipv6(inner),
+        _ => return None,
+    };
+    Some((Encapsulation::GtpU { teid }, layer))
+}
+
+fn decapsulate_vxlan(payload: &[u8]) -> Option<(Encapsulation, Layer<'_>)> {
+    const VNI_VALID_FLAG: u8 = 0x08;
+
+    if *payload.first()? & VNI_VALID_FLAG == 0 {
+        return None;
+    }
+    let vni = u32::from_be_bytes([0, *payload.get(4)?, *payload.get(5)?, *payload.get(6)?]);
+    Some((
+        Encapsulation::Vxlan { vni },
+        Layer::Ethernet(payload.get(8..)?),
+    ))
+}
+
+fn peek_layer(layer: Layer<'_>) -> Option<(FiveTuple, u16)> {
+    let (src, dst, length, protocol, payload) = match layer {
+        Layer::Ethernet(packet) => {
+            let ethernet = pnet_packet::ethernet::EthernetPacket::new(packet)?;
+            let (ethertype, payload, _) = strip_vlan_tags(&ethernet)?;
+            return match ethertype {
+                EtherTypes::Ipv4 => peek_layer(Layer::Ipv4(payload)),
+                EtherTypes::Ipv6 => peek_layer(Layer::Ipv6(payload)),
+                _ => None,
+            };
+        }
+        Layer::Ipv4(packet) => {
+            let header = Ipv4Packet::new(packet)?;
+            // Fragments are reassembled before they are parsed.
+            if header.get_flags() & Ipv4Flags::MoreFragments != 0
+                || header.get_fragment_offset() != 0
+            {
+                return None;
+            }
+            let header_length = (header.get_header_length() as u16) * 4;
+            (
+                std::net::IpAddr::V4(header.get_source()),
+                std::net::IpAddr::V4(header.get_destination()),
+                header.get_total_length().checked_sub(header_length)?,
+                header.get_next_level_protocol(),
+                packet.get(header_length as usize..)?,
+            )
+        }
+        Layer::Ipv6(packet) => {
+            let header = Ipv6Packet::new(packet)?;
+            (
+                std::net::IpAddr::V6(header.get_source()),
+                std::net::IpAddr::V6(header.get_destination()),
+                header.get_payload_length(),
+                header.get_next_header(),
+                packet.get(40..)?,
+            )
+        }
+    };
+
+    let src_port = u16::from_be_bytes([*payload.first()?, *payload.get(1)?]);
+    let dst_port = u16::from_be_bytes([*payload.get(2)?, *payload.get(3)?]);
+    match protocol {
+        IpNextHeaderProtocols::Tcp => {}
+        IpNextHeaderProtocols::Udp => {
+            if src_port == 53 || matches!(dst_port, GTPU_PORT | VXLAN_PORT) {
+                return None;
+            }
+        }
+        _ => return None,
+    }
+    Some((
+        FiveTuple {
+            src,
+            dst,
+            src_port,
+            dst_port,
+            protocol: protocol.to_primitive_values().0,
+        },
+        length,
+    ))
+}
+
 #[cfg(test)]
 mod tests {
-    use super::parse_ethernet;
+    use super::{parse_ethernet, parse_ipv4, parse_ipv6, peek_ethernet, Encapsulation};
 
     const TEST_IPV4_PACKET: &str = "14c03e83666fe4a47133c971080045000235e844400040061e9e0a000080b9c76d99b63001bbaf5d3bd0d3c31b4b801801f6948700000101080a3b098b4aec67f47616030101fc010001f80303a9a47cf7f55f7386da68128b9da84d8565dc071f965ce761d2230796a9bc620a2003a7231a0f6ee16741a9bb46e38bd85dc29ea5c45ab69dfed0f3fa9039f557610024130113031302c02bc02fcca9cca8c02cc030c00ac009c013c014009c009d002f0035000a0100018b0000000f000d00000a6d617474396a2e6e657400170000ff01000100000a000e000c001d00170018001901000101000b00020100002300000010000e000c02683208687474702f312e310005000501000000000033006b0069001d0020866a8ea435a8ea303dddba9875cec5723f88415b1b0ba8129976e1dac7f9a46500170041047355eede7258e545dd2dc5cce6b7b635d3df79f4061ecbbbedff9eb2eaf2927fbdc89914f349c7f27638e29a7984f5075634aab7cb0c08790f861d64ad316e3d002b00050403040303000d0018001604030503060308040805080604010501060102030201002d00020101001c000240010015009400000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000";
     const TEST_IPV6_PACKET: &str = "145bd1af5dc0e4a47133c97186dd60004fe702250640260017020f8097b000000000000000242a044e42040000000000000000000067c5a401bb5c07ea85f13e4b9c801801fbc63e00000101080a8d33f62c849849241603010200010001fc030331638499a07df01440c31689c1aa4701e3478405716c48ce3125e77bc2e406a2208bee720bab28182c6c2f45ce8f39808164ab2f34a5115927587d64dfa1858b2d0024130113031302c02bc02fcca9cca8c02cc030c00ac009c013c014009c009d002f0035000a0100018f0000000d000b000008786b63642e636f6d00170000ff01000100000a000e000c001d00170018001901000101000b00020100002300000010000e000c02683208687474702f312e310005000501000000000033006b0069001d0020a2880dc8967058e95ab9dd1b084987f6554f3a9cc23c67db918b67f770cdac3c0017004104b02f928f211882dbb0503634a3459b81e9c4c9e094a1e4ad868faf9a505a33d0b60e3933aba6682c6308ee344c805a6e45cd7ca19be97f3efd7204727681c031002b00050403040303000d0018001604030503060308040805080604010501060102030201002d00020101001c000240010015009a00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000";
@@ -287,6 +751,26 @@ mod tests {
         assert_eq!(result.fivetuple.src_port, 50596);
         assert_eq!(result.fivetuple.src, expected_src);
         assert_eq!(result.fivetuple.dst, expected_dst);
+        assert_eq!(result.tls_server_name.as_deref(), Some("xkcd.com"));
+        assert_eq!(
+            result.tcp_flags,
+            pnet_packet::tcp::TcpFlags::PSH | pnet_packet::tcp::TcpFlags::ACK
+        );
+    }
+
+    #[test]
+    fn test_peek_matches_parse() {
+        let log = make_logger();
+        for packet in [TEST_IPV4_PACKET, TEST_IPV6_PACKET] {
+            let packet_bytes = decode_hex(packet).unwrap();
+            let parsed = parse_ethernet(&packet_bytes, &log).unwrap();
+            let (fivetuple, length) = peek_ethernet(&packet_bytes).unwrap();
+            assert_eq!(fivetuple, parsed.fivetuple);
+            assert_eq!(length, parsed.ip_payload_length);
+        }
+
+        // DNS responses always need a full parse.
+        assert!(peek_ethernet(&decode_hex(TEST_DNS_PACKET).unwrap()).is_none());
     }
 
     #[test]
@@ -295,6 +779,14 @@ mod tests {
         let packet_bytes = decode_hex(TEST_IPV4_PACKET).unwrap();
         let result = parse_ethernet(&packet_bytes, &log).unwrap();
         assert_eq!(result.fivetuple.dst_port, 443);
+        assert_eq!(result.tls_server_name.as_deref(), Some("matt9j.net"));
+        assert_eq!(
+            result.macs,
+            Some((
+                pnet_datalink::MacAddr(0xe4, 0xa4, 0x71, 0x33, 0xc9, 0x71),
+                pnet_datalink::MacAddr(0x14, 0xc0, 0x3e, 0x83, 0x66, 0x6f),
+            ))
+        );
     }
 
     #[test]
@@ -322,7 +814,197 @@ mod tests {
                 "2a04:4e42:400::67".parse().unwrap(),
                 "2a04:4e42:600::67".parse().unwrap(),
             ],
+            qtype: 28,
+            rcode: 0,
+            ttl: 2815,
         };
         assert_eq!(dns_response, expected_response);
     }
+
+    #[test]
+    fn test_parse_vlan_tagged() {
+        let log = make_logger();
+        let untagged = decode_hex(TEST_IPV4_PACKET).unwrap();
+        assert_eq!(parse_ethernet(&untagged, &log).unwrap().vlan_id, None);
+
+        // An 802.1ad service tag of 100 outside a customer tag of 42.
+        let mut tagged = untagged[..12].to_vec();
+        tagged.extend_from_slice(&[0x88, 0xa8, 0x00, 0x64, 0x81, 0x00, 0x20, 0x2a]);
+        tagged.extend_from_slice(&untagged[12..]);
+        let parsed = parse_ethernet(&tagged, &log).unwrap();
+        let expected = parse_ethernet(&untagged, &log).unwrap();
+        assert_eq!(parsed.fivetuple, expected.fivetuple);
+        assert_eq!(parsed.ip_payload_length, expected.ip_payload_length);
+        assert_eq!(parsed.vlan_id, Some(42));
+        assert_eq!(peek_ethernet(&tagged).unwrap().0, expected.fivetuple);
+
+        // A truncated tag is malformed.
+        assert!(parse_ethernet(&tagged[..16], &log).is_err());
+    }
+
+    // Wraps a payload in a minimal outer IPv4 and UDP header addressed to the
+    // given port. Checksums are not verified by the parser.
+    fn make_udp_tunnel(dst_port: u16, payload: &[u8]) -> Vec<u8> {
+        let udp_length = (8 + payload.len()) as u16;
+        let total_length = 20 + udp_length;
+        let mut packet = vec![0x45, 0x00];
+        packet.extend_from_slice(&total_length.to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2]);
+        packet.extend_from_slice(&dst_port.to_be_bytes());
+        packet.extend_from_slice(&dst_port.to_be_bytes());
+        packet.extend_from_slice(&udp_length.to_be_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn test_parse_dns_in_gtpu() {
+        let log = make_logger();
+        let inner = decode_hex(TEST_DNS_PACKET).unwrap().slice(14..);
+
+        // A G-PDU with a sequence number and one PDU session container
+        // extension header.
+        let mut gtpu = vec![0x32, 0xff];
+        gtpu.extend_from_slice(&((inner.len() + 4 + 8) as u16).to_be_bytes());
+        gtpu.extend_from_slice(&0x0000_0457_u32.to_be_bytes());
+        gtpu.extend_from_slice(&[0x00, 0x01, 0x00, 0x85]);
+        gtpu.extend_from_slice(&[0x02, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        gtpu.extend_from_slice(&inner);
+
+        let result = parse_ipv4(&make_udp_tunnel(2152, &gtpu), &log).unwrap();
+        assert_eq!(
+            result.encapsulation,
+            vec![Encapsulation::GtpU { teid: 0x457 }]
+        );
+        assert_eq!(
+            result.fivetuple.src,
+            "8.8.8.8".parse::<std::net::IpAddr>().unwrap()
+        );
+        assert_eq!(result.ip_payload_length, 146);
+        assert_eq!(result.dns_response.unwrap().addresses.len(), 4);
+        // The header fields are those of the inner packet, not the tunnel.
+        assert_eq!(result.ttl, inner[8]);
+        assert_eq!(result.ip_id, Some(u16::from_be_bytes([inner[4], inner[5]])));
+    }
+
+    #[test]
+    fn test_parse_dns_in_vxlan() {
+        let log = make_logger();
+        let mut vxlan = vec![0x08, 0, 0, 0, 0x00, 0x10, 0x01, 0];
+        vxlan.extend_from_slice(&decode_hex(TEST_DNS_PACKET).unwrap());
+
+        let result = parse_ipv4(&make_udp_tunnel(4789, &vxlan), &log).unwrap();
+        assert_eq!(
+            result.encapsulation,
+            vec![Encapsulation::Vxlan { vni: 0x1001 }]
+        );
+        assert_eq!(
+            result.fivetuple.dst,
+            "192.168.1.241".parse::<std::net::IpAddr>().unwrap()
+        );
+        assert!(result.dns_response.is_some());
+        // The tunneled frame's addresses, not those of the tunnel.
+        assert_eq!(
+            result.macs,
+            Some((
+                pnet_datalink::MacAddr(0x70, 0x8b, 0xcd, 0xad, 0x14, 0x80),
+                pnet_datalink::MacAddr(0xe4, 0xa4, 0x71, 0x33, 0xc9, 0x71),
+            ))
+        );
+    }
+
+    // An ICMP echo request from 10.45.0.2 to 8.8.8.8 with 56 bytes of data, as
+    // sent by ping, and the reply to it.
+    const TEST_ICMP_ECHO_REQUEST: &str = "45000054a1b2400040010000 0a2d0002 08080808 0800f7ff002a0001 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f3031323334353637";
+    const TEST_ICMP_ECHO_REPLY: &str = "450000540000000075010000 08080808 0a2d0002 0000ffff002a0001 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f3031323334353637";
+    // A neighbor solicitation from 2001:db8::2 for 2001:db8::1, with a source
+    // link-layer address option.
+    const TEST_NEIGHBOR_SOLICITATION: &str = "6000000000203aff 20010db8000000000000000000000002 ff0200000000000000000001ff000001 870000000000000000000000 20010db8000000000000000000000001 0101020000000002";
+
+    #[test]
+    fn test_parse_icmp() {
+        let log = make_logger();
+        let decode = |packet: &str| decode_hex(&packet.replace(' ', "")).unwrap();
+
+        let request = parse_ipv4(&decode(TEST_ICMP_ECHO_REQUEST), &log).unwrap();
+        assert_eq!(request.fivetuple.protocol, 1);
+        assert_eq!(request.fivetuple.src_port, 0);
+        assert_eq!(request.fivetuple.dst_port, 8 << 8);
+        assert_eq!(request.ip_payload_length, 64);
+        assert_eq!(request.ttl, 64);
+
+        let reply = parse_ipv4(&decode(TEST_ICMP_ECHO_REPLY), &log).unwrap();
+        assert_eq!(
+            reply.fivetuple.src,
+            "8.8.8.8".parse::<std::net::IpAddr>().unwrap()
+        );
+        assert_eq!(reply.fivetuple.dst_port, 0);
+        assert_eq!(reply.ip_payload_length, 64);
+
+        let solicitation = parse_ipv6(&decode(TEST_NEIGHBOR_SOLICITATION), &log).unwrap();
+        assert_eq!(solicitation.fivetuple.protocol, 58);
+        assert_eq!(solicitation.fivetuple.dst_port, 135 << 8);
+        assert_eq!(solicitation.ip_payload_length, 32);
+        assert_eq!(solicitation.ttl, 255);
+
+        // Truncated messages are rejected rather than miscounted.
+        let mut truncated = decode(TEST_ICMP_ECHO_REQUEST).to_vec();
+        truncated.truncate(22);
+        assert!(parse_ipv4(&truncated, &log).is_err());
+    }
+
+    // Wraps a payload in a minimal IPv6 header from 2001:db8::2 to 2001:db8::1.
+    fn make_ipv6(next_header: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0];
+        packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[next_header, 64]);
+        packet.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        packet.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn test_parse_ipv6_extension_headers() {
+        let log = make_logger();
+        let udp = [
+            0xc0, 0x00, 0x01, 0xbb, 0x00, 0x0c, 0x00, 0x00, 0xde, 0xad, 0xbe, 0xef,
+        ];
+
+        // Hop-by-hop options padded to eight bytes, then destination options
+        // padded to sixteen, then UDP.
+        let mut chain = vec![60, 0, 0x01, 0x04, 0, 0, 0, 0];
+        chain.extend_from_slice(&[17, 1, 0x01, 0x0c, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        chain.extend_from_slice(&udp);
+        let result = parse_ipv6(&make_ipv6(0, &chain), &log).unwrap();
+        assert_eq!(result.fivetuple.protocol, 17);
+        assert_eq!(result.fivetuple.src_port, 0xc000);
+        assert_eq!(result.fivetuple.dst_port, 443);
+        assert_eq!(result.ip_payload_length, 12);
+
+        // The first fragment carries the transport header, and later
+        // fragments are counted without ports.
+        let mut first = vec![17, 0, 0x00, 0x01, 0, 0, 0, 1];
+        first.extend_from_slice(&udp);
+        let result = parse_ipv6(&make_ipv6(44, &first), &log).unwrap();
+        assert_eq!(result.fivetuple.dst_port, 443);
+        let mut later = vec![17, 0, 0x05, 0xa8, 0, 0, 0, 1];
+        later.extend_from_slice(&[0; 32]);
+        let result = parse_ipv6(&make_ipv6(44, &later), &log).unwrap();
+        assert_eq!(result.fivetuple.protocol, 17);
+        assert_eq!(result.fivetuple.dst_port, 0);
+        assert_eq!(result.ip_payload_length, 32);
+
+        // A routing header running past the end of the packet is malformed.
+        assert!(parse_ipv6(&make_ipv6(43, &[17, 4, 0, 0, 0, 0, 0, 0]), &log).is_err());
+    }
+
+    #[test]
+    fn test_non_tunnel_on_tunnel_port() {
+        let log = make_logger();
+        let result = parse_ipv4(&make_udp_tunnel(2152, &[0xde, 0xad, 0xbe, 0xef]), &log).unwrap();
+        assert!(result.encapsulation.is_empty());
+        assert_eq!(result.fivetuple.dst_port, 2152);
+    }
 }
@@ -15,10 +15,17 @@ pub enum DnsParseError {
     NotDnsResponse,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct DnsResponse {
     pub fqdn: domain::base::name::Dname<Bytes>,
     pub addresses: Vec<IpAddr>,
+    // The record type queried for, e.g. 1 for A, and the response code, e.g.
+    // 3 for NXDOMAIN.
+    pub qtype: u16,
+    pub rcode: u8,
+    // The shortest time to live of the records leading to the addresses, in
+    // seconds, or zero without any addresses.
+    pub ttl: u32,
 }
 
 pub fn parse_dns_payload(
@@ -44,12 +51,14 @@ pub fn parse_dns_payload(
     // Parse all available answers and add them to the answer list.
     let answer_section = parsed_message.answer()?;
     let mut answer_addresses: Vec<IpAddr> = Vec::with_capacity(10);
+    let mut ttl = u32::MAX;
     for a in answer_section.limit_to_in::<domain::rdata::AllRecordData<_, _>>() {
         let answer = a?;
         slog::debug! {logger, "parsed DNS answer {:?}", answer};
         if answer.owner().ne(&current_canonical_name) {
             continue;
         }
+        ttl = ttl.min(answer.ttl());
 
         match answer.data() {
             domain::rdata::AllRecordData::A(parsed_answer) => {
@@ -67,9 +76,15 @@ pub fn parse_dns_payload(
         }
     }
 
+    if answer_addresses.is_empty() {
+        ttl = 0;
+    }
     return Ok(DnsResponse {
         fqdn: query.to_bytes(),
         addresses: answer_addresses,
+        qtype: question.qtype().to_int(),
+        rcode: parsed_message.header().rcode().to_int(),
+        ttl,
     });
 }
 
@@ -110,6 +125,9 @@ mod tests {
                 "151.101.128.67".parse().unwrap(),
                 "151.101.192.67".parse().unwrap(),
             ],
+            qtype: 1,
+            rcode: 0,
+            ttl: 3223,
         };
         assert_eq!(parse_dns_payload(&data, &log).unwrap(), expected_result);
     }
@@ -126,6 +144,9 @@ mod tests {
                 "2a04:4e42:400::67".parse().unwrap(),
                 "2a04:4e42:600::67".parse().unwrap(),
             ],
+            qtype: 28,
+            rcode: 0,
+            ttl: 1624,
         };
         assert_eq!(parse_dns_payload(&data, &log).unwrap(), expected_result);
     }
@@ -140,6 +161,9 @@ mod tests {
                 "104.18.21.226".parse().unwrap(),
                 "104.18.20.226".parse().unwrap(),
             ],
+            qtype: 1,
+            rcode: 0,
+            ttl: 299,
         };
         assert_eq!(parse_dns_payload(&data, &log).unwrap(), expected_result);
     }
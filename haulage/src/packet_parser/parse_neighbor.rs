@@ -0,0 +1,137 @@
+use pnet_packet::arp::{ArpOperations, ArpPacket};
+use pnet_packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet_packet::icmpv6::ndp::{
+    NdpOptionType, NdpOptionTypes, NeighborAdvertPacket, NeighborSolicitPacket,
+};
+use pnet_packet::icmpv6::{Icmpv6Packet, Icmpv6Types};
+use pnet_packet::ip::IpNextHeaderProtocols;
+use pnet_packet::ipv6::Ipv6Packet;
+use pnet_packet::Packet;
+
+// A link layer address announced as the owner of an IP address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AddressClaim {
+    pub ip: std::net::IpAddr,
+    pub mac: pnet_datalink::MacAddr,
+}
+
+// Extracts the address ownership announced by an ARP packet or an IPv6
+// neighbor discovery message in an ethernet frame, if any. Probes from hosts
+// without an address yet are not claims.
+pub fn parse_address_claim(frame: &[u8]) -> Option<AddressClaim> {
+    let ethernet = EthernetPacket::new(frame)?;
+    match ethernet.get_ethertype() {
+        EtherTypes::Arp => {
+            let arp = ArpPacket::new(ethernet.payload())?;
+            let operation = arp.get_operation();
+            let ip = arp.get_sender_proto_addr();
+            if (operation != ArpOperations::Request && operation != ArpOperations::Reply)
+                || ip.is_unspecified()
+            {
+                return None;
+            }
+            Some(AddressClaim {
+                ip: std::net::IpAddr::V4(ip),
+                mac: arp.get_sender_hw_addr(),
+            })
+        }
+        EtherTypes::Ipv6 => {
+            let ipv6 = Ipv6Packet::new(ethernet.payload())?;
+            if ipv6.get_next_header() != IpNextHeaderProtocols::Icmpv6 {
+                return None;
+            }
+            let payload = ipv6.payload();
+            match Icmpv6Packet::new(payload)?.get_icmpv6_type() {
+                Icmpv6Types::NeighborAdvert => {
+                    let advert = NeighborAdvertPacket::new(payload)?;
+                    let mac =
+                        link_layer_option(&advert.get_options(), NdpOptionTypes::TargetLLAddr)?;
+                    Some(AddressClaim {
+                        ip: std::net::IpAddr::V6(advert.get_target_addr()),
+                        mac,
+                    })
+                }
+                Icmpv6Types::NeighborSolicit => {
+                    let source = ipv6.get_source();
+                    if source.is_unspecified() {
+                        return None;
+                    }
+                    let solicit = NeighborSolicitPacket::new(payload)?;
+                    let mac =
+                        link_layer_option(&solicit.get_options(), NdpOptionTypes::SourceLLAddr)?;
+                    Some(AddressClaim {
+                        ip: std::net::IpAddr::V6(source),
+                        mac,
+                    })
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn link_layer_option(
+    options: &[pnet_packet::icmpv6::ndp::NdpOption],
+    option_type: NdpOptionType,
+) -> Option<pnet_datalink::MacAddr> {
+    options
+        .iter()
+        .find(|option| option.option_type == option_type && option.data.len() >= 6)
+        .map(|option| {
+            let d = &option.data;
+            pnet_datalink::MacAddr::new(d[0], d[1], d[2], d[3], d[4], d[5])
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ethernet_frame(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0xff; 6];
+        frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01]);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn test_arp_claim() {
+        let mut arp = vec![0, 1, 0x08, 0, 6, 4, 0, 2];
+        arp.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01, 10, 45, 0, 2]);
+        arp.extend_from_slice(&[0; 10]);
+        let claim = parse_address_claim(&ethernet_frame(0x0806, &arp)).unwrap();
+        assert_eq!(claim.ip, "10.45.0.2".parse::<std::net::IpAddr>().unwrap());
+        assert_eq!(
+            claim.mac,
+            pnet_datalink::MacAddr::new(0x02, 0, 0, 0, 0, 0x01)
+        );
+
+        // Duplicate address detection probes do not claim an address.
+        arp[14..18].copy_from_slice(&[0, 0, 0, 0]);
+        assert_eq!(parse_address_claim(&ethernet_frame(0x0806, &arp)), None);
+    }
+
+    #[test]
+    fn test_neighbor_advertisement_claim() {
+        let target: std::net::Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let mut advert = vec![136, 0, 0, 0, 0x60, 0, 0, 0];
+        advert.extend_from_slice(&target.octets());
+        advert.extend_from_slice(&[2, 1, 0x02, 0, 0, 0, 0, 0x02]);
+
+        let mut ipv6 = vec![0x60, 0, 0, 0];
+        ipv6.extend_from_slice(&(advert.len() as u16).to_be_bytes());
+        ipv6.extend_from_slice(&[58, 255]);
+        ipv6.extend_from_slice(&target.octets());
+        ipv6.extend_from_slice(&"ff02::1".parse::<std::net::Ipv6Addr>().unwrap().octets());
+        ipv6.extend_from_slice(&advert);
+
+        let claim = parse_address_claim(&ethernet_frame(0x86dd, &ipv6)).unwrap();
+        assert_eq!(claim.ip, std::net::IpAddr::V6(target));
+        assert_eq!(
+            claim.mac,
+            pnet_datalink::MacAddr::new(0x02, 0, 0, 0, 0, 0x02)
+        );
+    }
+}
@@ -0,0 +1,136 @@
+// Extracts the server name indication from a TLS ClientHello, naming the
+// service encrypted traffic is for even when its DNS lookup was not seen.
+// Only the part of the ClientHello within the given segment is parsed, which
+// holds the extension for nearly all clients, and anything malformed or
+// truncated is ignored.
+pub fn parse_client_hello_server_name(payload: &[u8]) -> Option<String> {
+    const HANDSHAKE_RECORD: u8 = 0x16;
+    const CLIENT_HELLO: u8 = 0x01;
+    const SERVER_NAME_EXTENSION: u16 = 0x0000;
+    const HOST_NAME: u8 = 0x00;
+
+    let mut record = Reader(payload);
+    if record.u8()? != HANDSHAKE_RECORD {
+        return None;
+    }
+    record.skip(4)?;
+    if record.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    // The handshake length, version, and random.
+    record.skip(3 + 2 + 32)?;
+    let session_id_length = record.u8()? as usize;
+    record.skip(session_id_length)?;
+    let cipher_suites_length = record.u16()? as usize;
+    record.skip(cipher_suites_length)?;
+    let compression_methods_length = record.u8()? as usize;
+    record.skip(compression_methods_length)?;
+
+    let extensions_length = record.u16()? as usize;
+    let mut extensions = Reader(record.take(extensions_length.min(record.0.len()))?);
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let length = extensions.u16()? as usize;
+        let mut extension = Reader(extensions.take(length)?);
+        if extension_type != SERVER_NAME_EXTENSION {
+            continue;
+        }
+        let list_length = extension.u16()? as usize;
+        let mut names = Reader(extension.take(list_length)?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let length = names.u16()? as usize;
+            let name = names.take(length)?;
+            if name_type == HOST_NAME {
+                return host_name(name);
+            }
+        }
+        return None;
+    }
+    None
+}
+
+// Host names are ASCII letters, digits, hyphens, and dots, and are compared
+// case insensitively.
+fn host_name(name: &[u8]) -> Option<String> {
+    let valid = !name.is_empty()
+        && name
+            .iter()
+            .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_'));
+    match valid {
+        true => Some(String::from_utf8_lossy(name).to_ascii_lowercase()),
+        false => None,
+    }
+}
+
+struct Reader<'p>(&'p [u8]);
+impl<'p> Reader<'p> {
+    fn take(&mut self, length: usize) -> Option<&'p [u8]> {
+        let taken = self.0.get(..length)?;
+        self.0 = &self.0[length..];
+        Some(taken)
+    }
+
+    fn skip(&mut self, length: usize) -> Option<()> {
+        self.take(length).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds a ClientHello with a session ID, two cipher suites, and an
+    // unrelated extension before the server name.
+    fn client_hello(name: &[u8]) -> Vec<u8> {
+        let mut server_name = vec![0x00];
+        server_name.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        server_name.extend_from_slice(name);
+        let mut extensions = vec![0x00, 0x17, 0x00, 0x00, 0x00, 0x00];
+        extensions.extend_from_slice(&(server_name.len() as u16 + 2).to_be_bytes());
+        extensions.extend_from_slice(&(server_name.len() as u16).to_be_bytes());
+        extensions.extend(server_name);
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0; 32]);
+        hello.extend_from_slice(&[4, 1, 2, 3, 4]);
+        hello.extend_from_slice(&[0, 4, 0x13, 0x01, 0x13, 0x02]);
+        hello.extend_from_slice(&[1, 0]);
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend(extensions);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(hello.len() as u16 + 4).to_be_bytes());
+        record.extend_from_slice(&[0x01, 0, 0, hello.len() as u8]);
+        record.extend(hello);
+        record
+    }
+
+    #[test]
+    fn test_client_hello_server_name() {
+        let record = client_hello(b"Static.WhatsApp.net");
+        assert_eq!(
+            parse_client_hello_server_name(&record).as_deref(),
+            Some("static.whatsapp.net")
+        );
+        // Truncated before the name, not a handshake, or not a host name.
+        assert_eq!(
+            parse_client_hello_server_name(&record[..record.len() - 4]),
+            None
+        );
+        assert_eq!(
+            parse_client_hello_server_name(&[0x17, 0x03, 0x03, 0x00, 0x10]),
+            None
+        );
+        assert_eq!(parse_client_hello_server_name(&client_hello(b"a b")), None);
+    }
+}
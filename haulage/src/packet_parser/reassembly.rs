@@ -0,0 +1,375 @@
+use std::collections::HashMap;
+
+use pnet_packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet_packet::ipv4::{Ipv4Flags, Ipv4Packet};
+
+// Bounds the datagrams awaiting fragments, and the bytes held for them, so
+// fragments which are never completed, e.g. from a flood of first fragments,
+// cannot exhaust memory. Fragments beyond the limits are dropped.
+const MAX_PENDING_DATAGRAMS: usize = 1024;
+const MAX_PENDING_BYTES: usize = 8 * 1024 * 1024;
+
+// Fragments of the same datagram share their addresses, identification, and
+// protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FragmentKey {
+    src: std::net::Ipv4Addr,
+    dst: std::net::Ipv4Addr,
+    id: u16,
+    protocol: u8,
+}
+
+#[derive(Debug)]
+struct PartialDatagram {
+    // The IP header of the first fragment, which carries the options copied
+    // into the reassembled datagram.
+    header: Option<Vec<u8>>,
+    payload: Vec<u8>,
+    // The byte ranges of the payload received so far, sorted and merged.
+    received: Vec<(usize, usize)>,
+    // The payload length, known once the last fragment arrives.
+    length: Option<usize>,
+    first_seen: std::time::Instant,
+}
+impl PartialDatagram {
+    fn new(now: std::time::Instant) -> PartialDatagram {
+        PartialDatagram {
+            header: None,
+            payload: Vec::new(),
+            received: Vec::new(),
+            length: None,
+            first_seen: now,
+        }
+    }
+
+    fn add_range(&mut self, start: usize, end: usize) {
+        self.received.push((start, end));
+        self.received.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(self.received.len());
+        for (start, end) in self.received.drain(..) {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        self.received = merged;
+    }
+
+    fn complete(&self) -> bool {
+        match (&self.header, self.length) {
+            (Some(_), Some(length)) => self.received == [(0, length)],
+            _ => false,
+        }
+    }
+
+    // Builds the reassembled datagram, with its length updated and its
+    // fragmentation fields cleared.
+    fn assemble(self) -> Vec<u8> {
+        let mut datagram = self.header.unwrap_or_default();
+        let length = self.length.unwrap_or_default();
+        let total_length = (datagram.len() + length) as u16;
+        datagram[2..4].copy_from_slice(&total_length.to_be_bytes());
+        datagram[6..8].copy_from_slice(&[0, 0]);
+        datagram.extend_from_slice(&self.payload[..length]);
+        datagram
+    }
+}
+
+// What became of a packet offered for reassembly.
+#[derive(Debug, PartialEq)]
+pub enum Reassembly {
+    // The packet is not an IPv4 fragment, and is parsed as is.
+    Unfragmented,
+    // The packet completed a datagram, which is parsed in its place. Frames
+    // keep their ethernet header and VLAN tags ahead of the datagram.
+    Complete(Vec<u8>),
+    // The packet is held until the rest of its datagram arrives.
+    Held,
+    // The packet was dropped as malformed or beyond the cache's limits.
+    Dropped,
+}
+
+// Holds the fragments of IPv4 datagrams until they are complete, so that the
+// transport headers and payloads of fragmented datagrams, e.g. large DNS
+// answers and VPN traffic, are parsed and accounted like any other.
+#[derive(Debug, Default)]
+pub struct FragmentCache {
+    pending: std::sync::Mutex<Pending>,
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    datagrams: HashMap<FragmentKey, PartialDatagram>,
+    // The payload bytes allocated across all datagrams.
+    bytes: usize,
+}
+impl FragmentCache {
+    pub fn new() -> FragmentCache {
+        FragmentCache::default()
+    }
+
+    // Offers an ethernet frame for reassembly if it carries IPv4.
+    pub fn reassemble_ethernet(&self, frame: &[u8], now: std::time::Instant) -> Reassembly {
+        let ethernet = match EthernetPacket::new(frame) {
+            Some(ethernet) => ethernet,
+            None => return Reassembly::Unfragmented,
+        };
+        match super::strip_vlan_tags(&ethernet) {
+            Some((EtherTypes::Ipv4, payload, _)) => match self.reassemble_ipv4(payload, now) {
+                Reassembly::Complete(datagram) => {
+                    let mut reassembled = frame[..frame.len() - payload.len()].to_vec();
+                    reassembled.extend_from_slice(&datagram);
+                    Reassembly::Complete(reassembled)
+                }
+                reassembly => reassembly,
+            },
+            _ => Reassembly::Unfragmented,
+        }
+    }
+
+    pub fn reassemble_ipv4(&self, packet: &[u8], now: std::time::Instant) -> Reassembly {
+        let header = match Ipv4Packet::new(packet) {
+            Some(header) => header,
+            None => return Reassembly::Unfragmented,
+        };
+        let more_fragments = header.get_flags() & Ipv4Flags::MoreFragments != 0;
+        let offset = header.get_fragment_offset() as usize * 8;
+        if !more_fragments && offset == 0 {
+            return Reassembly::Unfragmented;
+        }
+
+        // Short headers would leave the reassembled datagram without the fields
+        // updated when assembling it.
+        let header_length = header.get_header_length() as usize * 4;
+        if header_length < 20 || header_length > packet.len() {
+            return Reassembly::Dropped;
+        }
+        let fragment = (header.get_total_length() as usize)
+            .checked_sub(header_length)
+            .and_then(|length| packet.get(header_length..header_length + length));
+        let fragment = match fragment {
+            // Every fragment but the last carries a multiple of eight bytes.
+            Some(fragment) if !more_fragments || fragment.len() % 8 == 0 => fragment,
+            _ => return Reassembly::Dropped,
+        };
+        let end = offset + fragment.len();
+        if end > u16::MAX as usize - header_length {
+            return Reassembly::Dropped;
+        }
+
+        let key = FragmentKey {
+            src: header.get_source(),
+            dst: header.get_destination(),
+            id: header.get_identification(),
+            protocol: header.get_next_level_protocol().0,
+        };
+        let mut pending = self.pending.lock().unwrap();
+        let allocated = match pending.datagrams.get(&key) {
+            Some(datagram) => datagram.payload.len(),
+            None if pending.datagrams.len() >= MAX_PENDING_DATAGRAMS => return Reassembly::Dropped,
+            None => 0,
+        };
+        // Any fragment extending a datagram counts against the byte limit,
+        // not only the first fragment seen of it.
+        let growth = end.saturating_sub(allocated);
+        if pending.bytes + growth > MAX_PENDING_BYTES {
+            return Reassembly::Dropped;
+        }
+        pending.bytes += growth;
+        let datagram = pending
+            .datagrams
+            .entry(key)
+            .or_insert_with(|| PartialDatagram::new(now));
+
+        if !more_fragments {
+            // Conflicting last fragments leave the datagram unable to complete
+            // until it expires.
+            if datagram.length.is_some_and(|length| length != end) {
+                return Reassembly::Dropped;
+            }
+            datagram.length = Some(end);
+        }
+        if offset == 0 {
+            datagram.header = Some(packet[..header_length].to_vec());
+        }
+        if datagram.payload.len() < end {
+            datagram.payload.resize(end, 0);
+        }
+        datagram.payload[offset..end].copy_from_slice(fragment);
+        datagram.add_range(offset, end);
+
+        if datagram.complete() {
+            let datagram = pending.datagrams.remove(&key).unwrap();
+            pending.bytes -= datagram.payload.len();
+            return Reassembly::Complete(datagram.assemble());
+        }
+        Reassembly::Held
+    }
+
+    // Forgets datagrams whose fragments did not all arrive within the
+    // timeout, returning the number of payload bytes received for them.
+    pub fn expire(&self, now: std::time::Instant, timeout: std::time::Duration) -> u64 {
+        let mut pending = self.pending.lock().unwrap();
+        let Pending { datagrams, bytes } = &mut *pending;
+        let mut lost = 0;
+        datagrams.retain(|_, datagram| {
+            let keep = now.duration_since(datagram.first_seen) < timeout;
+            if !keep {
+                *bytes -= datagram.payload.len();
+                lost += datagram
+                    .received
+                    .iter()
+                    .map(|(start, end)| (end - start) as u64)
+                    .sum::<u64>();
+            }
+            keep
+        });
+        lost
+    }
+}
+
+// Periodically forgets incomplete datagrams.
+pub async fn expire_fragments(
+    cache: std::sync::Arc<FragmentCache>,
+    timeout: std::time::Duration,
+    stats: std::sync::Arc<crate::stats::Stats>,
+) {
+    let mut timer = tokio::time::interval(timeout);
+    loop {
+        timer.tick().await;
+        let lost = cache.expire(std::time::Instant::now(), timeout);
+        stats.fragment_bytes_lost.add(lost);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv4_fragment(id: u16, offset: usize, more_fragments: bool, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0];
+        packet.extend_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&id.to_be_bytes());
+        let flags = ((more_fragments as u16) << 13) | (offset / 8) as u16;
+        packet.extend_from_slice(&flags.to_be_bytes());
+        packet.extend_from_slice(&[64, 17, 0, 0, 10, 45, 0, 2, 192, 0, 2, 1]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn test_reassemble_out_of_order() {
+        let cache = FragmentCache::new();
+        let now = std::time::Instant::now();
+        let payload: Vec<u8> = (0..40).collect();
+
+        assert_eq!(
+            cache.reassemble_ipv4(&ipv4_fragment(7, 0, false, &payload), now),
+            Reassembly::Unfragmented
+        );
+        assert_eq!(
+            cache.reassemble_ipv4(&ipv4_fragment(7, 24, false, &payload[24..]), now),
+            Reassembly::Held
+        );
+        assert_eq!(
+            cache.reassemble_ipv4(&ipv4_fragment(7, 0, true, &payload[..16]), now),
+            Reassembly::Held
+        );
+        // Fragments of other datagrams are kept apart.
+        assert_eq!(
+            cache.reassemble_ipv4(&ipv4_fragment(8, 16, true, &payload[16..24]), now),
+            Reassembly::Held
+        );
+        assert_eq!(
+            cache.reassemble_ipv4(&ipv4_fragment(7, 16, true, &payload[16..24]), now),
+            Reassembly::Complete(ipv4_fragment(7, 0, false, &payload))
+        );
+
+        let later = now + std::time::Duration::from_secs(31);
+        assert_eq!(cache.expire(later, std::time::Duration::from_secs(30)), 8);
+        let pending = cache.pending.lock().unwrap();
+        assert!(pending.datagrams.is_empty());
+        assert_eq!(pending.bytes, 0);
+    }
+
+    #[test]
+    fn test_drop_short_header() {
+        let cache = FragmentCache::new();
+        let now = std::time::Instant::now();
+        let payload: Vec<u8> = (0..16).collect();
+
+        for header_length in [0, 1, 4] {
+            for (offset, more_fragments) in [(0, true), (8, false)] {
+                let mut fragment =
+                    ipv4_fragment(9, offset, more_fragments, &payload[offset..offset + 8]);
+                fragment[0] = 0x40 | header_length;
+                assert_eq!(cache.reassemble_ipv4(&fragment, now), Reassembly::Dropped);
+            }
+        }
+
+        // The cache is left usable for well formed fragments.
+        assert_eq!(
+            cache.reassemble_ipv4(&ipv4_fragment(9, 8, false, &payload[8..]), now),
+            Reassembly::Held
+        );
+        assert_eq!(
+            cache.reassemble_ipv4(&ipv4_fragment(9, 0, true, &payload[..8]), now),
+            Reassembly::Complete(ipv4_fragment(9, 0, false, &payload))
+        );
+    }
+
+    #[test]
+    fn test_pending_bytes_limit() {
+        let cache = FragmentCache::new();
+        let now = std::time::Instant::now();
+        let payload = vec![0; 8];
+
+        // Later fragments extending a held datagram are dropped once the
+        // pending bytes would exceed the limit.
+        let mut id = 0;
+        let mut offset = 0;
+        loop {
+            let fragment = ipv4_fragment(id, offset, true, &payload);
+            match cache.reassemble_ipv4(&fragment, now) {
+                Reassembly::Held => {}
+                Reassembly::Dropped => break,
+                other => panic!("unexpected {:?}", other),
+            }
+            offset += 65000;
+            if offset > 65000 {
+                offset = 0;
+                id += 1;
+            }
+        }
+        let pending = cache.pending.lock().unwrap();
+        assert!(pending.bytes <= MAX_PENDING_BYTES);
+        assert!(pending.bytes > MAX_PENDING_BYTES - 65008);
+        assert_eq!(
+            pending.bytes,
+            pending
+                .datagrams
+                .values()
+                .map(|d| d.payload.len())
+                .sum::<usize>()
+        );
+    }
+
+    #[test]
+    fn test_reassemble_keeps_link_layer() {
+        let cache = FragmentCache::new();
+        let now = std::time::Instant::now();
+        let payload: Vec<u8> = (0..16).collect();
+        let mut link_layer = vec![2, 0, 0, 0, 0, 1, 2, 0, 0, 0, 0, 2, 0x81, 0x00, 0, 42];
+        link_layer.extend_from_slice(&[0x08, 0x00]);
+        let frame = |fragment: Vec<u8>| [link_layer.clone(), fragment].concat();
+
+        assert_eq!(
+            cache.reassemble_ethernet(&frame(ipv4_fragment(9, 0, true, &payload[..8])), now),
+            Reassembly::Held
+        );
+        assert_eq!(
+            cache.reassemble_ethernet(&frame(ipv4_fragment(9, 8, false, &payload[8..])), now),
+            Reassembly::Complete(frame(ipv4_fragment(9, 0, false, &payload)))
+        );
+    }
+}
@@ -0,0 +1,181 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum PinningError {
+    #[error("Failed to set CPU affinity: {0}")]
+    Affinity(std::io::Error),
+    #[error("Failed to set real-time priority: {0}")]
+    Priority(std::io::Error),
+    #[error("Failed to start parser threads: {0}")]
+    Runtime(std::io::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // The cores the capture loop runs on, or any core if empty.
+    pub capture_cores: Vec<usize>,
+    // The cores packet batches are parsed on. If empty, batches are parsed on
+    // the shared runtime alongside the database and enforcement work.
+    pub parser_cores: Vec<usize>,
+    pub parser_threads: usize,
+    // The SCHED_FIFO priority of the capture and parser threads, from 1 to
+    // 99, or None to leave them at normal priority.
+    pub realtime_priority: Option<i32>,
+}
+impl Settings {
+    // The cores reserved for the capture path, which the shared runtime's
+    // workers are moved off of.
+    fn reserved_cores(&self) -> Vec<usize> {
+        let mut cores = [self.capture_cores.as_slice(), self.parser_cores.as_slice()].concat();
+        cores.sort_unstable();
+        cores.dedup();
+        cores
+    }
+}
+
+// The largest core index a cpu_set_t can hold.
+pub const MAX_CORE: usize = libc::CPU_SETSIZE as usize - 1;
+
+// The tokio runtime built by main names its worker and blocking threads with
+// this prefix, truncated by the kernel to 15 characters.
+const SHARED_RUNTIME_THREAD_PREFIX: &str = "tokio-runtime-w";
+const PARSER_THREAD_NAME: &str = "haulage-parser";
+
+// Pins the calling thread, or another thread of the process by its id, to the
+// given cores.
+fn set_affinity(thread: libc::pid_t, cores: &[usize]) -> Result<(), PinningError> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for core in cores {
+        unsafe { libc::CPU_SET(*core, &mut set) };
+    }
+    let result =
+        unsafe { libc::sched_setaffinity(thread, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+    match result {
+        0 => Ok(()),
+        _ => Err(PinningError::Affinity(std::io::Error::last_os_error())),
+    }
+}
+
+fn set_realtime_priority(priority: i32) -> Result<(), PinningError> {
+    let param = libc::sched_param {
+        sched_priority: priority,
+    };
+    let result = unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) };
+    match result {
+        0 => Ok(()),
+        _ => Err(PinningError::Priority(std::io::Error::last_os_error())),
+    }
+}
+
+// Applies the capture path's affinity and priority to the calling thread.
+fn pin_current_thread(cores: &[usize], priority: Option<i32>) -> Result<(), PinningError> {
+    if !cores.is_empty() {
+        set_affinity(0, cores)?;
+    }
+    if let Some(priority) = priority {
+        set_realtime_priority(priority)?;
+    }
+    Ok(())
+}
+
+// Pins the thread running the capture loop, which must be called from that
+// thread.
+pub fn pin_capture_thread(settings: &Settings, log: &slog::Logger) {
+    match pin_current_thread(&settings.capture_cores, settings.realtime_priority) {
+        Ok(()) => {
+            slog::info!(log, "Pinned capture thread"; "cores" => format!("{:?}", settings.capture_cores), "priority" => settings.realtime_priority)
+        }
+        Err(e) => slog::warn!(log, "Unable to pin capture thread"; "error" => e.to_string()),
+    }
+}
+
+// Builds a runtime dedicated to parsing packet batches, with its threads
+// pinned to the parser cores, or returns None to parse on the shared runtime.
+pub fn parser_runtime(
+    settings: &Settings,
+    log: &slog::Logger,
+) -> Result<Option<tokio::runtime::Runtime>, PinningError> {
+    if settings.parser_cores.is_empty() {
+        return Ok(None);
+    }
+    let cores = settings.parser_cores.clone();
+    let priority = settings.realtime_priority;
+    let thread_log = log.clone();
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(settings.parser_threads)
+        .thread_name(PARSER_THREAD_NAME)
+        .on_thread_start(move || {
+            pin_current_thread(&cores, priority).unwrap_or_else(|e| {
+                slog::warn!(thread_log, "Unable to pin parser thread"; "error" => e.to_string())
+            })
+        })
+        .enable_all()
+        .build()
+        .map(Some)
+        .map_err(PinningError::Runtime)
+}
+
+// Moves the worker threads of the shared runtime, which run the database,
+// enforcement, and export work, off of the cores reserved for the capture
+// path. Threads the workers start later inherit their affinity.
+pub fn isolate_shared_runtime(settings: &Settings, log: &slog::Logger) {
+    let reserved = settings.reserved_cores();
+    let online = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) }.max(1) as usize;
+    let shared: Vec<usize> = (0..online)
+        .filter(|core| !reserved.contains(core))
+        .collect();
+    // Gateways with no cores to spare share them with the capture path, which
+    // then relies on its priority alone.
+    if shared.is_empty() {
+        slog::info!(log, "No unreserved cores for the shared runtime");
+        return;
+    }
+
+    let threads = match std::fs::read_dir("/proc/self/task") {
+        Ok(threads) => threads,
+        Err(e) => {
+            slog::warn!(log, "Unable to list threads"; "error" => e.to_string());
+            return;
+        }
+    };
+    for thread in threads.flatten() {
+        let name = std::fs::read_to_string(thread.path().join("comm")).unwrap_or_default();
+        if !name.starts_with(SHARED_RUNTIME_THREAD_PREFIX) {
+            continue;
+        }
+        if let Some(id) = thread.file_name().to_str().and_then(|id| id.parse().ok()) {
+            set_affinity(id, &shared).unwrap_or_else(|e| {
+                slog::warn!(log, "Unable to move runtime thread"; "thread" => id, "error" => e.to_string())
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pin_current_thread() {
+        let pinned = std::thread::spawn(|| {
+            pin_current_thread(&[0], None).unwrap();
+            let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+            let result = unsafe {
+                libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set)
+            };
+            assert_eq!(result, 0);
+            (0..=MAX_CORE)
+                .filter(|core| unsafe { libc::CPU_ISSET(*core, &set) })
+                .collect::<Vec<usize>>()
+        });
+        assert_eq!(pinned.join().unwrap(), vec![0]);
+
+        let settings = Settings {
+            capture_cores: vec![1],
+            parser_cores: vec![1, 0],
+            parser_threads: 2,
+            realtime_priority: None,
+        };
+        assert_eq!(settings.reserved_cores(), vec![0, 1]);
+    }
+}
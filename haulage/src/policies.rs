@@ -0,0 +1,130 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum PolicyError {
+    #[error("Database operation failed: {0}")]
+    DatabaseError(#[from] crate::storage::StorageError),
+}
+
+// The policy applied to one direction of one link, matching the entries of the
+// link_policy_kinds table.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkPolicy {
+    Unlimited,
+    Block,
+    TokenBucket(TokenBucket),
+}
+
+// The parameters of a token bucket link as stored in the database. The rate
+// is the ceiling the link may borrow up to, while the minimum rate is
+// guaranteed under contention and links of lower priority values borrow
+// first.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct TokenBucket {
+    pub rate_kibps: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_rate_kibps: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub burst_bytes: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<ScheduledRate>,
+}
+
+// A rate replacing a token bucket's rate during daily hours, e.g. for an
+// unlimited nights plan. Without a rate the link is unlimited during the hours.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct ScheduledRate {
+    pub hours: crate::clock::DailyHours,
+    pub rate_kibps: Option<u32>,
+}
+
+impl LinkPolicy {
+    pub fn kind_id(&self) -> i32 {
+        match self {
+            LinkPolicy::Unlimited => 1,
+            LinkPolicy::Block => 2,
+            LinkPolicy::TokenBucket(_) => 3,
+        }
+    }
+
+    pub fn parameters(&self) -> serde_json::Value {
+        match self {
+            LinkPolicy::Unlimited | LinkPolicy::Block => serde_json::json!({}),
+            LinkPolicy::TokenBucket(bucket) => {
+                serde_json::to_value(bucket).unwrap_or_else(|_| serde_json::json!({}))
+            }
+        }
+    }
+}
+
+// A named access policy defined in the configuration file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolicyTemplate {
+    pub name: String,
+    pub local_ul: LinkPolicy,
+    pub local_dl: LinkPolicy,
+    pub backhaul_ul: LinkPolicy,
+    pub backhaul_dl: LinkPolicy,
+    pub dscp: Option<u8>,
+    pub guaranteed_kibps: Option<u32>,
+    pub dns_redirect: Option<std::net::IpAddr>,
+}
+
+// Creates or updates the access policies defined in the configuration, keyed by
+// name, so that plans can be managed under version control. Policies only
+// present in the database are left untouched. Changed definitions are applied
+// to subscribers when the enforcer synchronizes all subscribers at startup.
+pub async fn sync_policies(
+    storage: &dyn crate::storage::Storage,
+    templates: &[PolicyTemplate],
+    log: &slog::Logger,
+) -> Result<(), PolicyError> {
+    let ids = storage.sync_policies(templates).await?;
+    for (template, id) in templates.iter().zip(ids) {
+        slog::info!(log, "Synchronized access policy from config"; "name" => &template.name, "id" => id);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link_policy_parameters() {
+        assert_eq!(LinkPolicy::Unlimited.kind_id(), 1);
+        assert_eq!(LinkPolicy::Block.parameters(), serde_json::json!({}));
+
+        let bucket = LinkPolicy::TokenBucket(TokenBucket {
+            rate_kibps: 512,
+            ..Default::default()
+        });
+        assert_eq!(bucket.kind_id(), 3);
+        assert_eq!(
+            bucket.parameters(),
+            serde_json::json!({ "rate_kibps": 512 })
+        );
+
+        let unlimited_nights = LinkPolicy::TokenBucket(TokenBucket {
+            rate_kibps: 512,
+            min_rate_kibps: Some(128),
+            priority: Some(1),
+            schedule: Some(ScheduledRate {
+                hours: "00:00-06:00".parse().unwrap(),
+                rate_kibps: None,
+            }),
+            ..Default::default()
+        });
+        assert_eq!(
+            unlimited_nights.parameters(),
+            serde_json::json!({
+                "rate_kibps": 512,
+                "min_rate_kibps": 128,
+                "priority": 1,
+                "schedule": { "hours": "00:00-06:00", "rate_kibps": null },
+            })
+        );
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum PresenceError {
+    #[error("Database operation failed: {0}")]
+    DatabaseError(#[from] sqlx::error::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // Whether traffic sent by subscribers counts as presence, or only their
+    // ARP and neighbor discovery messages.
+    pub from_traffic: bool,
+}
+
+// How a subscriber's device was seen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Source {
+    // Claiming its address with ARP or IPv6 neighbor discovery.
+    Neighbor,
+    // Sending traffic. Traffic towards a subscriber says nothing of whether
+    // their device is online to receive it.
+    Traffic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Sighting {
+    pub ip: std::net::IpAddr,
+    pub source: Source,
+}
+
+// Records when each subscriber was first and last seen within each interval,
+// on the same intervals as subscriber usage, without logging their flows.
+#[derive(Debug)]
+pub struct PresenceTracker {
+    dispatch_channel: tokio::sync::mpsc::Sender<Message>,
+}
+impl PresenceTracker {
+    pub fn new(
+        schedule: crate::clock::Schedule,
+        db_pool: std::sync::Arc<sqlx::PgPool>,
+        log: slog::Logger,
+    ) -> PresenceTracker {
+        let (sender, receiver) = tokio::sync::mpsc::channel(64);
+        tokio::task::spawn(async move {
+            track_presence(receiver, schedule, db_pool, log).await;
+        });
+        PresenceTracker {
+            dispatch_channel: sender,
+        }
+    }
+    pub fn clone_input_channel(&self) -> tokio::sync::mpsc::Sender<Message> {
+        self.dispatch_channel.clone()
+    }
+}
+
+pub enum Message {
+    Sightings(Vec<Sighting>),
+}
+
+// What has been seen of one address within the current interval.
+#[derive(Debug, Clone, PartialEq)]
+struct Presence {
+    first_seen: chrono::DateTime<chrono::Utc>,
+    last_seen: chrono::DateTime<chrono::Utc>,
+    by_neighbor: bool,
+    by_traffic: bool,
+}
+impl Presence {
+    fn new(source: Source, now: chrono::DateTime<chrono::Utc>) -> Presence {
+        Presence {
+            first_seen: now,
+            last_seen: now,
+            by_neighbor: source == Source::Neighbor,
+            by_traffic: source == Source::Traffic,
+        }
+    }
+
+    fn observe(&mut self, source: Source, now: chrono::DateTime<chrono::Utc>) {
+        self.first_seen = self.first_seen.min(now);
+        self.last_seen = self.last_seen.max(now);
+        match source {
+            Source::Neighbor => self.by_neighbor = true,
+            Source::Traffic => self.by_traffic = true,
+        }
+    }
+}
+
+fn observe_sightings(
+    addresses: &mut HashMap<std::net::IpAddr, Presence>,
+    sightings: &[Sighting],
+    now: chrono::DateTime<chrono::Utc>,
+) {
+    for sighting in sightings {
+        addresses
+            .entry(sighting.ip)
+            .and_modify(|presence| presence.observe(sighting.source, now))
+            .or_insert_with(|| Presence::new(sighting.source, now));
+    }
+}
+
+async fn track_presence(
+    mut chan: tokio::sync::mpsc::Receiver<Message>,
+    schedule: crate::clock::Schedule,
+    db_pool: std::sync::Arc<sqlx::PgPool>,
+    log: slog::Logger,
+) {
+    let mut addresses: HashMap<std::net::IpAddr, Presence> = HashMap::new();
+    let mut start = chrono::Utc::now();
+    let mut timer = tokio::time::interval_at(
+        tokio::time::Instant::now() + schedule.first_delay(start),
+        schedule.period,
+    );
+    loop {
+        tokio::select! {
+            _ = timer.tick() => {
+                let end = schedule.boundary(chrono::Utc::now());
+                for (ip, presence) in addresses.drain() {
+                    // Addresses are looked up each interval, since they may be
+                    // reassigned between intervals.
+                    let subscriber = match query_subscriber(&db_pool, ip).await {
+                        Ok(Some(subscriber)) => subscriber,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            slog::warn!(log, "Failed to look up subscriber"; "ip" => ip.to_string(), "error" => e.to_string());
+                            continue;
+                        }
+                    };
+                    record_presence(&db_pool, subscriber, (start, end), &presence)
+                        .await
+                        .unwrap_or_else(|e| slog::error!(log, "Failed to record presence"; "subscriber" => subscriber, "error" => e.to_string()));
+                }
+                start = end;
+            }
+            message = chan.recv() => {
+                match message {
+                    Some(Message::Sightings(sightings)) => {
+                        observe_sightings(&mut addresses, &sightings, chrono::Utc::now());
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+}
+
+async fn query_subscriber(
+    db_pool: &sqlx::PgPool,
+    ip: std::net::IpAddr,
+) -> Result<Option<i32>, PresenceError> {
+    let subscriber_query = r#"
+        SELECT "internal_uid"
+        FROM subscribers
+        INNER JOIN static_ips ON static_ips.imsi = subscribers.imsi
+        WHERE static_ips.ip >>= $1
+    "#;
+    let subscriber: Option<(i32,)> = sqlx::query_as(subscriber_query)
+        .bind(ipnetwork::IpNetwork::from(ip))
+        .fetch_optional(db_pool)
+        .await?;
+    Ok(subscriber.map(|(subscriber,)| subscriber))
+}
+
+// Subscribers with several addresses, e.g. an IPv4 address and an IPv6 prefix,
+// have the presence seen at each address merged.
+async fn record_presence(
+    db_pool: &sqlx::PgPool,
+    subscriber: i32,
+    (start, end): (chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>),
+    presence: &Presence,
+) -> Result<(), PresenceError> {
+    let presence_query = r#"
+        INSERT INTO subscriber_presence("subscriber", "start_time", "end_time", "first_seen", "last_seen", "by_neighbor", "by_traffic")
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        ON CONFLICT ("subscriber", "start_time") DO UPDATE
+        SET "first_seen" = LEAST(subscriber_presence."first_seen", EXCLUDED."first_seen"),
+            "last_seen" = GREATEST(subscriber_presence."last_seen", EXCLUDED."last_seen"),
+            "by_neighbor" = subscriber_presence."by_neighbor" OR EXCLUDED."by_neighbor",
+            "by_traffic" = subscriber_presence."by_traffic" OR EXCLUDED."by_traffic"
+    "#;
+    sqlx::query(presence_query)
+        .bind(subscriber)
+        .bind(start)
+        .bind(end)
+        .bind(presence.first_seen)
+        .bind(presence.last_seen)
+        .bind(presence.by_neighbor)
+        .bind(presence.by_traffic)
+        .execute(db_pool)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_observe_sightings() {
+        let ip: std::net::IpAddr = "10.45.0.2".parse().unwrap();
+        let first = chrono::Utc.ymd(2026, 10, 16).and_hms(9, 0, 0);
+        let later = first + chrono::Duration::seconds(30);
+        let mut addresses = HashMap::new();
+
+        observe_sightings(
+            &mut addresses,
+            &[Sighting {
+                ip,
+                source: Source::Neighbor,
+            }],
+            first,
+        );
+        observe_sightings(
+            &mut addresses,
+            &[Sighting {
+                ip,
+                source: Source::Traffic,
+            }],
+            later,
+        );
+        assert_eq!(
+            addresses.get(&ip),
+            Some(&Presence {
+                first_seen: first,
+                last_seen: later,
+                by_neighbor: true,
+                by_traffic: true,
+            })
+        );
+    }
+}
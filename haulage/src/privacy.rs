@@ -0,0 +1,109 @@
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+
+use thiserror::Error;
+
+const KEY_LENGTH: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum PrivacyError {
+    #[error("Failed to access the privacy key: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("The privacy key at {0} is not {KEY_LENGTH} bytes long")]
+    InvalidKey(std::path::PathBuf),
+    #[error("Failed to generate a privacy key")]
+    KeyGenerationFailed,
+}
+
+// Replaces subscriber identifiers in exported data with keyed hashes, so that
+// records of the same subscriber can still be linked to each other but not to
+// the subscriber without the key, which never leaves the gateway.
+pub struct Pseudonymizer {
+    key: ring::hmac::Key,
+}
+impl std::fmt::Debug for Pseudonymizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Pseudonymizer")
+    }
+}
+
+impl Pseudonymizer {
+    // Reads the key from the given path, generating a new random key readable
+    // only by haulage if none exists yet. Replacing the key unlinks all
+    // pseudonyms exported before from those exported after.
+    pub fn load_or_create(path: &std::path::Path) -> Result<Pseudonymizer, PrivacyError> {
+        let key = match std::fs::read(path) {
+            Ok(key) => key,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let mut key = [0u8; KEY_LENGTH];
+                ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut key)
+                    .map_err(|_| PrivacyError::KeyGenerationFailed)?;
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(path)?
+                    .write_all(&key)?;
+                key.to_vec()
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if key.len() != KEY_LENGTH {
+            return Err(PrivacyError::InvalidKey(path.to_owned()));
+        }
+        Ok(Pseudonymizer::new(&key))
+    }
+
+    pub fn new(key: &[u8]) -> Pseudonymizer {
+        Pseudonymizer {
+            key: ring::hmac::Key::new(ring::hmac::HMAC_SHA256, key),
+        }
+    }
+
+    // Returns a pseudonym for a subscriber identifier of the given kind, e.g.
+    // an imsi. The kind keeps identifiers of different kinds that happen to
+    // be equal from sharing a pseudonym.
+    pub fn pseudonym(&self, kind: &str, identifier: &str) -> String {
+        let tag = ring::hmac::sign(&self.key, format!("{}:{}", kind, identifier).as_bytes());
+        tag.as_ref()[..16]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    // Returns a pseudonym for a subscriber address, itself an address so that
+    // it fits existing address columns.
+    pub fn address(&self, addr: std::net::IpAddr) -> std::net::Ipv6Addr {
+        let tag = ring::hmac::sign(&self.key, format!("address:{}", addr).as_bytes());
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(&tag.as_ref()[..16]);
+        std::net::Ipv6Addr::from(octets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pseudonyms_are_stable_and_keyed() {
+        let pseudonymizer = Pseudonymizer::new(&[1; KEY_LENGTH]);
+        let other_key = Pseudonymizer::new(&[2; KEY_LENGTH]);
+
+        let pseudonym = pseudonymizer.pseudonym("imsi", "001010000000001");
+        assert_eq!(pseudonym.len(), 32);
+        assert_eq!(
+            pseudonym,
+            pseudonymizer.pseudonym("imsi", "001010000000001")
+        );
+        assert_ne!(
+            pseudonym,
+            pseudonymizer.pseudonym("subscriber", "001010000000001")
+        );
+        assert_ne!(pseudonym, other_key.pseudonym("imsi", "001010000000001"));
+
+        let addr = "10.45.0.2".parse().unwrap();
+        assert_eq!(pseudonymizer.address(addr), pseudonymizer.address(addr));
+        assert_ne!(pseudonymizer.address(addr), other_key.address(addr));
+    }
+}
@@ -0,0 +1,36 @@
+// The few pieces of the protobuf wire format needed to hand encode the remote
+// write messages, rather than generating code for them.
+
+pub fn encode_key(buffer: &mut Vec<u8>, field: u64, wire_type: u64) {
+    encode_varint(buffer, field << 3 | wire_type);
+}
+
+pub fn encode_bytes(buffer: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    encode_key(buffer, field, 2);
+    encode_varint(buffer, bytes.len() as u64);
+    buffer.extend_from_slice(bytes);
+}
+
+pub fn encode_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_fields() {
+        let mut message = Vec::new();
+        encode_key(&mut message, 1, 0);
+        encode_varint(&mut message, 300);
+        encode_bytes(&mut message, 3, b"10.45.0.2");
+        assert_eq!(message[..4], [0x08, 0xAC, 0x02, 0x1A]);
+        assert_eq!(message[4], 9);
+        assert_eq!(&message[5..], b"10.45.0.2");
+    }
+}
@@ -0,0 +1,228 @@
+use domain::base::iana::{Class, Rcode};
+use domain::base::{Message, MessageBuilder, Rtype};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum QuotaDnsError {
+    #[error("Quota DNS socket failed: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Failed to build DNS response")]
+    ShortBuf(#[from] domain::base::ShortBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // Where to listen for queries, e.g. the subscriber gateway address.
+    pub listen_address: std::net::SocketAddr,
+    // The normalized name answered, e.g. quota.haulage.local
+    pub hostname: String,
+    // An address returned for A queries of the name, e.g. a portal showing
+    // the balance in more detail.
+    pub redirect_address: Option<std::net::Ipv4Addr>,
+}
+
+// Answers queries for a single well-known name with the querying subscriber's
+// remaining balance as a TXT record, so that any device can check its quota
+// with a DNS lookup. The subscriber is identified by the source address of the
+// query, so queries must reach haulage directly rather than through a
+// forwarding resolver. All other names are refused.
+pub async fn serve(
+    settings: Settings,
+    db_pool: std::sync::Arc<sqlx::PgPool>,
+    accounter: tokio::sync::mpsc::Sender<crate::accounter::Message>,
+    log: slog::Logger,
+) -> Result<(), QuotaDnsError> {
+    let socket = std::sync::Arc::new(tokio::net::UdpSocket::bind(settings.listen_address).await?);
+    slog::info!(log, "Answering quota queries"; "address" => settings.listen_address.to_string(), "hostname" => &settings.hostname);
+
+    let mut buffer = vec![0; 512];
+    loop {
+        let (length, source) = socket.recv_from(&mut buffer).await?;
+        let query = match Message::from_octets(bytes::Bytes::copy_from_slice(&buffer[..length])) {
+            Ok(query) if !query.header().qr() => query,
+            _ => continue,
+        };
+
+        // Balance lookups may wait on the database, so each query is answered
+        // independently.
+        let socket = std::sync::Arc::clone(&socket);
+        let settings = settings.clone();
+        let db_pool = std::sync::Arc::clone(&db_pool);
+        let accounter = accounter.clone();
+        let log = log.clone();
+        tokio::task::spawn(async move {
+            let response = match is_quota_query(&query, &settings.hostname) {
+                true => {
+                    let balance = query_balance(&db_pool, &accounter, source.ip(), &log).await;
+                    quota_response(&query, &settings, balance)
+                }
+                false => refused_response(&query),
+            };
+            let result = match response {
+                Ok(response) => socket.send_to(&response, source).await.map(|_| ()),
+                Err(e) => {
+                    slog::warn!(log, "Failed to answer quota query"; "error" => e.to_string());
+                    Ok(())
+                }
+            };
+            result.unwrap_or_else(
+                |e| slog::warn!(log, "Failed to send quota response"; "error" => e.to_string()),
+            );
+        });
+    }
+}
+
+fn is_quota_query(query: &Message<bytes::Bytes>, hostname: &str) -> bool {
+    query.first_question().is_some_and(|question| {
+        crate::content_filter::normalize_domain(&question.qname().to_string()) == hostname
+    })
+}
+
+// Prefers the live balance from the accounter, which includes usage not yet
+// written to the database, falling back to the stored balance for
+// subscribers not seen since startup. Returns None for unknown addresses.
+async fn query_balance(
+    db_pool: &sqlx::PgPool,
+    accounter: &tokio::sync::mpsc::Sender<crate::accounter::Message>,
+    ip: std::net::IpAddr,
+    log: &slog::Logger,
+) -> Option<i64> {
+    let (out_channel, result_channel) = tokio::sync::oneshot::channel();
+    if accounter
+        .send(crate::accounter::Message::GetBalance { ip, out_channel })
+        .await
+        .is_ok()
+    {
+        if let Ok(Some(balance)) = result_channel.await {
+            return Some(balance);
+        }
+    }
+
+    let balance_query = r#"
+        SELECT COALESCE(balance_pools."data_balance", subscribers."data_balance")
+        FROM subscribers
+        INNER JOIN static_ips ON static_ips.imsi = subscribers.imsi
+        LEFT JOIN balance_pools ON balance_pools.id = subscribers.balance_pool
+        WHERE static_ips.ip >>= $1
+    "#;
+    let rows: Result<Vec<(i64,)>, sqlx::Error> = sqlx::query_as(balance_query)
+        .bind(ipnetwork::IpNetwork::from(ip))
+        .fetch_all(db_pool)
+        .await;
+    match rows {
+        Ok(rows) if rows.len() == 1 => Some(rows[0].0),
+        Ok(_) => None,
+        Err(e) => {
+            slog::warn!(log, "Failed to query balance"; "ip" => ip.to_string(), "error" => e.to_string());
+            None
+        }
+    }
+}
+
+fn refused_response(query: &Message<bytes::Bytes>) -> Result<Vec<u8>, QuotaDnsError> {
+    let builder = MessageBuilder::new_vec().start_answer(query, Rcode::Refused)?;
+    Ok(builder.finish())
+}
+
+fn quota_response(
+    query: &Message<bytes::Bytes>,
+    settings: &Settings,
+    balance: Option<i64>,
+) -> Result<Vec<u8>, QuotaDnsError> {
+    let question = query
+        .first_question()
+        .expect("Quota queries have a question");
+
+    let mut builder = MessageBuilder::new_vec().start_answer(query, Rcode::NoError)?;
+    builder.header_mut().set_aa(true);
+    // Balances change constantly, so answers are never cached.
+    let ttl = 0;
+    match question.qtype() {
+        Rtype::Txt | Rtype::Any => {
+            let text = describe_balance(balance);
+            builder.push((
+                question.qname(),
+                Class::In,
+                ttl,
+                domain::rdata::Txt::<Vec<u8>>::from_slice(text.as_bytes())?,
+            ))?;
+        }
+        Rtype::A => {
+            if let Some(address) = settings.redirect_address {
+                builder.push((
+                    question.qname(),
+                    Class::In,
+                    ttl,
+                    domain::rdata::A::new(address),
+                ))?;
+            }
+        }
+        _ => {}
+    }
+    Ok(builder.finish())
+}
+
+fn describe_balance(balance: Option<i64>) -> String {
+    match balance {
+        Some(bytes) => format!(
+            "{:.2} MB remaining ({} bytes)",
+            bytes as f64 / 1_000_000.0,
+            bytes
+        ),
+        None => String::from("No subscriber is assigned this address"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn make_query(name: &str, qtype: Rtype) -> Message<bytes::Bytes> {
+        let mut builder = MessageBuilder::new_vec().question();
+        builder
+            .push((
+                domain::base::Dname::<Vec<u8>>::from_str(name).unwrap(),
+                qtype,
+            ))
+            .unwrap();
+        Message::from_octets(bytes::Bytes::from(builder.finish())).unwrap()
+    }
+
+    fn make_settings() -> Settings {
+        Settings {
+            listen_address: "127.0.0.1:53".parse().unwrap(),
+            hostname: String::from("quota.haulage.local"),
+            redirect_address: Some("10.45.0.1".parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_quota_txt_response() {
+        let settings = make_settings();
+        let query = make_query("Quota.Haulage.Local.", Rtype::Txt);
+        assert!(is_quota_query(&query, &settings.hostname));
+
+        let response = quota_response(&query, &settings, Some(1_500_000)).unwrap();
+        let response = Message::from_octets(response).unwrap();
+        assert_eq!(response.header().rcode(), Rcode::NoError);
+        let answers: Vec<_> = response
+            .answer()
+            .unwrap()
+            .limit_to::<domain::rdata::Txt<_>>()
+            .map(|record| record.unwrap().data().as_flat_slice().unwrap().to_vec())
+            .collect();
+        assert_eq!(answers, vec![b"1.50 MB remaining (1500000 bytes)".to_vec()]);
+    }
+
+    #[test]
+    fn test_other_names_refused() {
+        let query = make_query("example.com.", Rtype::A);
+        assert!(!is_quota_query(&query, "quota.haulage.local"));
+
+        let response = refused_response(&query).unwrap();
+        let response = Message::from_octets(response).unwrap();
+        assert_eq!(response.header().rcode(), Rcode::Refused);
+        assert_eq!(response.header_counts().ancount(), 0);
+    }
+}
@@ -0,0 +1,272 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ReconcileError {
+    #[error("Database operation failed: {0}")]
+    DatabaseError(#[from] sqlx::error::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub interval: std::time::Duration,
+    // The relative difference allowed between the internal byte counts.
+    pub tolerance: f64,
+    // The relative difference allowed between the captured bytes and the
+    // interface counters, which also include link layer overhead and traffic
+    // not belonging to any subscriber.
+    pub interface_tolerance: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiscrepancyKind {
+    // Interval usage records written to the database vs. bytes captured.
+    UsageRecords,
+    // Balance decrements synchronized to the database vs. bytes captured.
+    BalanceDebits,
+    // Bytes captured vs. bytes counted by the subscriber interface.
+    Capture,
+}
+impl DiscrepancyKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DiscrepancyKind::UsageRecords => "usage_records",
+            DiscrepancyKind::BalanceDebits => "balance_debits",
+            DiscrepancyKind::Capture => "capture",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Discrepancy {
+    pub kind: DiscrepancyKind,
+    pub expected_bytes: u64,
+    pub measured_bytes: u64,
+}
+
+// The byte counts from each independent source over one window.
+#[derive(Debug, Clone, Default)]
+struct WindowTotals {
+    captured: u64,
+    // The captured bytes after applying charging class weights.
+    weighted: u64,
+    recorded: u64,
+    debited: u64,
+    interface: Option<u64>,
+}
+
+// Periodically compares the bytes captured by haulage against the usage
+// records, balance decrements, and kernel interface counters covering the same
+// window, recording any disagreement beyond the configured tolerance so that
+// silent undercounting is noticed without manual auditing.
+pub async fn reconcile(
+    settings: Settings,
+    interface: String,
+    db_pool: std::sync::Arc<sqlx::PgPool>,
+    stats: std::sync::Arc<crate::stats::Stats>,
+    log: slog::Logger,
+) {
+    let mut window_start = chrono::Utc::now();
+    let mut previous_stats = stats.snapshot();
+    let mut previous_interface = read_interface_bytes(&interface).await;
+
+    let mut timer = tokio::time::interval_at(
+        tokio::time::Instant::now() + settings.interval,
+        settings.interval,
+    );
+    loop {
+        timer.tick().await;
+        let window_end = chrono::Utc::now();
+        let current_stats = stats.snapshot();
+        let current_interface = read_interface_bytes(&interface).await;
+        let delta = current_stats.delta(&previous_stats);
+
+        let recorded = match query_recorded_usage(&db_pool, window_start, window_end).await {
+            Ok(recorded) => recorded,
+            Err(e) => {
+                slog::warn!(log, "Unable to query recorded usage"; "error" => e.to_string());
+                continue;
+            }
+        };
+        let totals = WindowTotals {
+            captured: delta.user_bytes_charged,
+            weighted: delta.user_bytes_weighted,
+            recorded,
+            debited: delta.user_bytes_debited,
+            interface: match (previous_interface, current_interface) {
+                (Some(previous), Some(current)) => Some(current.saturating_sub(previous)),
+                _ => None,
+            },
+        };
+
+        let discrepancies = find_discrepancies(&totals, &settings);
+        slog::info!(log, "Reconciled accounting window"; "captured" => totals.captured, "recorded" => totals.recorded, "debited" => totals.debited, "interface" => totals.interface, "discrepancies" => discrepancies.len());
+        for discrepancy in &discrepancies {
+            slog::warn!(log, "Accounting discrepancy detected"; "kind" => discrepancy.kind.as_str(), "expected" => discrepancy.expected_bytes, "measured" => discrepancy.measured_bytes);
+        }
+        if !discrepancies.is_empty() {
+            stats
+                .reconciliation_discrepancies
+                .add(discrepancies.len() as u64);
+            record_discrepancies(&db_pool, window_start, window_end, &discrepancies)
+                .await
+                .unwrap_or_else(|e| slog::error!(log, "Failed to record discrepancies"; "error" => e.to_string()));
+        }
+
+        window_start = window_end;
+        previous_stats = current_stats;
+        previous_interface = current_interface;
+    }
+}
+
+fn find_discrepancies(totals: &WindowTotals, settings: &Settings) -> Vec<Discrepancy> {
+    let mut discrepancies = Vec::new();
+    let mut check = |kind, expected: u64, measured: u64, tolerance: f64| {
+        let difference = (expected as f64 - measured as f64).abs();
+        if difference > tolerance * std::cmp::max(expected, measured) as f64 {
+            discrepancies.push(Discrepancy {
+                kind,
+                expected_bytes: expected,
+                measured_bytes: measured,
+            });
+        }
+    };
+
+    check(
+        DiscrepancyKind::UsageRecords,
+        totals.captured,
+        totals.recorded,
+        settings.tolerance,
+    );
+    check(
+        DiscrepancyKind::BalanceDebits,
+        totals.weighted,
+        totals.debited,
+        settings.tolerance,
+    );
+
+    // The interface also carries overhead and non-subscriber traffic, so only
+    // capturing less than the interface saw is suspicious.
+    if let Some(interface) = totals.interface {
+        if totals.captured < interface {
+            check(
+                DiscrepancyKind::Capture,
+                interface,
+                totals.captured,
+                settings.interface_tolerance,
+            );
+        }
+    }
+    discrepancies
+}
+
+pub async fn read_interface_bytes(interface: &str) -> Option<u64> {
+    let mut total = 0;
+    for counter in ["rx_bytes", "tx_bytes"] {
+        let path = format!("/sys/class/net/{}/statistics/{}", interface, counter);
+        let value = tokio::fs::read_to_string(path).await.ok()?;
+        total += value.trim().parse::<u64>().ok()?;
+    }
+    Some(total)
+}
+
+async fn query_recorded_usage(
+    db_pool: &sqlx::PgPool,
+    window_start: chrono::DateTime<chrono::Utc>,
+    window_end: chrono::DateTime<chrono::Utc>,
+) -> Result<u64, ReconcileError> {
+    let mut transaction = db_pool.begin().await?;
+
+    // Only the backhaul bytes are charged against subscriber balances.
+    let usage_query = r#"
+        SELECT COALESCE(SUM("wan_bytes_up" + "wan_bytes_down"), 0)::BIGINT
+        FROM subscriber_usage
+        WHERE "end_time" > $1 AND "end_time" <= $2
+    "#;
+
+    let (recorded,): (i64,) = sqlx::query_as(usage_query)
+        .bind(window_start)
+        .bind(window_end)
+        .fetch_one(&mut transaction)
+        .await?;
+
+    transaction.commit().await?;
+    Ok(recorded.max(0) as u64)
+}
+
+async fn record_discrepancies(
+    db_pool: &sqlx::PgPool,
+    window_start: chrono::DateTime<chrono::Utc>,
+    window_end: chrono::DateTime<chrono::Utc>,
+    discrepancies: &[Discrepancy],
+) -> Result<(), ReconcileError> {
+    let mut transaction = db_pool.begin().await?;
+
+    let insert_query = r#"
+        INSERT INTO accounting_discrepancies("window_start", "window_end", "kind", "expected_bytes", "measured_bytes")
+        VALUES ($1, $2, $3, $4, $5)
+    "#;
+    for discrepancy in discrepancies {
+        sqlx::query(insert_query)
+            .bind(window_start)
+            .bind(window_end)
+            .bind(discrepancy.kind.as_str())
+            .bind(discrepancy.expected_bytes as i64)
+            .bind(discrepancy.measured_bytes as i64)
+            .execute(&mut transaction)
+            .await?;
+    }
+
+    transaction.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_settings() -> Settings {
+        Settings {
+            interval: std::time::Duration::from_secs(3600),
+            tolerance: 0.05,
+            interface_tolerance: 0.2,
+        }
+    }
+
+    #[test]
+    fn test_consistent_window() {
+        let totals = WindowTotals {
+            captured: 1000,
+            weighted: 1000,
+            recorded: 980,
+            debited: 1000,
+            interface: Some(1100),
+        };
+        assert_eq!(find_discrepancies(&totals, &make_settings()), vec![]);
+    }
+
+    #[test]
+    fn test_undercounted_window() {
+        let totals = WindowTotals {
+            captured: 1000,
+            weighted: 1000,
+            recorded: 500,
+            debited: 1000,
+            interface: Some(5000),
+        };
+        assert_eq!(
+            find_discrepancies(&totals, &make_settings()),
+            vec![
+                Discrepancy {
+                    kind: DiscrepancyKind::UsageRecords,
+                    expected_bytes: 1000,
+                    measured_bytes: 500,
+                },
+                Discrepancy {
+                    kind: DiscrepancyKind::Capture,
+                    expected_bytes: 5000,
+                    measured_bytes: 1000,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+use crate::protobuf::{encode_bytes, encode_key, encode_varint};
+
+#[derive(Error, Debug)]
+pub enum RemoteWriteError {
+    #[error("Remote write request failed: {0}")]
+    HttpError(#[from] reqwest::Error),
+    #[error("Remote write endpoint rejected the request: {0}")]
+    Rejected(String),
+    #[error("Database operation failed: {0}")]
+    DatabaseError(#[from] sqlx::error::Error),
+    #[error("Failed to compress the request: {0}")]
+    CompressionError(#[from] snap::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // The remote write endpoint, e.g.
+    // https://prometheus-prod-01-eu-west-0.grafana.net/api/prom/push
+    pub url: String,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    // Added to every series to tell sites apart, defaulting to the hostname.
+    pub instance: String,
+    pub interval: std::time::Duration,
+    // Whether to push usage and balance series for each subscriber, which
+    // grow with the number of subscribers.
+    pub subscriber_metrics: bool,
+    // How long after an interval ends to wait for its usage to be recorded.
+    pub settle: std::time::Duration,
+}
+
+// One sample of one series, identified by its labels.
+#[derive(Debug, Clone, PartialEq)]
+struct Sample {
+    labels: BTreeMap<&'static str, String>,
+    value: f64,
+}
+
+impl Sample {
+    fn new(name: String, value: f64) -> Sample {
+        let mut labels = BTreeMap::new();
+        labels.insert("__name__", name);
+        Sample { labels, value }
+    }
+
+    fn label(mut self, name: &'static str, value: &str) -> Sample {
+        self.labels.insert(name, value.to_owned());
+        self
+    }
+}
+
+// Usage counted by the exporter since it started, since Prometheus counters
+// only need to be monotonic within the lifetime of the process.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct UsageCounters {
+    ran_bytes_up: u64,
+    ran_bytes_down: u64,
+    wan_bytes_up: u64,
+    wan_bytes_down: u64,
+}
+
+// Periodically pushes the health counters and, if enabled, subscriber usage
+// and balances to a Prometheus remote write endpoint, e.g. Grafana Cloud or
+// Mimir, for sites without a Prometheus server of their own. Counters missed
+// while the endpoint is unreachable are caught up by the next push.
+pub async fn push(
+    settings: Settings,
+    db_pool: std::sync::Arc<sqlx::PgPool>,
+    pseudonymizer: Option<std::sync::Arc<crate::privacy::Pseudonymizer>>,
+    stats: std::sync::Arc<crate::stats::Stats>,
+    log: slog::Logger,
+) {
+    let http = reqwest::Client::new();
+    let settle =
+        chrono::Duration::from_std(settings.settle).unwrap_or_else(|_| chrono::Duration::zero());
+    let mut usage = BTreeMap::<String, UsageCounters>::new();
+    let mut counted_until = chrono::Utc::now() - settle;
+
+    let mut timer = tokio::time::interval_at(
+        tokio::time::Instant::now() + settings.interval,
+        settings.interval,
+    );
+    loop {
+        timer.tick().await;
+        let mut samples = stats_samples(&stats.snapshot());
+
+        if settings.subscriber_metrics {
+            let count_until = chrono::Utc::now() - settle;
+            match count_usage(&db_pool, counted_until, count_until, &mut usage).await {
+                Ok(()) => counted_until = count_until,
+                Err(e) => {
+                    slog::warn!(log, "Failed to count subscriber usage"; "error" => e.to_string())
+                }
+            }
+            match query_balances(&db_pool).await {
+                Ok(balances) => samples.extend(subscriber_samples(
+                    &usage,
+                    &balances,
+                    pseudonymizer.as_deref(),
+                )),
+                Err(e) => {
+                    slog::warn!(log, "Failed to query subscriber balances"; "error" => e.to_string())
+                }
+            }
+        }
+
+        let samples: Vec<Sample> = samples
+            .into_iter()
+            .map(|sample| sample.label("instance", &settings.instance))
+            .collect();
+        let timestamp = chrono::Utc::now().timestamp_millis();
+        match send(&http, &settings, &encode_write_request(&samples, timestamp)).await {
+            Ok(()) => stats.remote_write_pushes.increment(),
+            Err(e) => {
+                stats.remote_write_errors.increment();
+                slog::warn!(log, "Failed to push metrics"; "series" => samples.len(), "error" => e.to_string());
+            }
+        }
+    }
+}
+
+fn stats_samples(snapshot: &crate::stats::Snapshot) -> Vec<Sample> {
+    let counters = match serde_json::to_value(snapshot) {
+        Ok(serde_json::Value::Object(counters)) => counters,
+        _ => return Vec::new(),
+    };
+    counters
+        .into_iter()
+        .filter_map(|(name, value)| {
+            Some(Sample::new(
+                format!("haulage_{}_total", name),
+                value.as_u64()? as f64,
+            ))
+        })
+        .collect()
+}
+
+fn subscriber_samples(
+    usage: &BTreeMap<String, UsageCounters>,
+    balances: &[(String, i64)],
+    pseudonymizer: Option<&crate::privacy::Pseudonymizer>,
+) -> Vec<Sample> {
+    let identify = |imsi: &str| match pseudonymizer {
+        Some(pseudonymizer) => pseudonymizer.pseudonym("imsi", imsi),
+        None => imsi.to_owned(),
+    };
+
+    let mut samples = Vec::new();
+    for (imsi, counters) in usage {
+        let imsi = identify(imsi);
+        for (link, direction, bytes) in [
+            ("ran", "up", counters.ran_bytes_up),
+            ("ran", "down", counters.ran_bytes_down),
+            ("wan", "up", counters.wan_bytes_up),
+            ("wan", "down", counters.wan_bytes_down),
+        ] {
+            samples.push(
+                Sample::new(String::from("haulage_subscriber_bytes_total"), bytes as f64)
+                    .label("imsi", &imsi)
+                    .label("link", link)
+                    .label("direction", direction),
+            );
+        }
+    }
+    for (imsi, balance) in balances {
+        samples.push(
+            Sample::new(
+                String::from("haulage_subscriber_balance_bytes"),
+                *balance as f64,
+            )
+            .label("imsi", &identify(imsi)),
+        );
+    }
+    samples
+}
+
+// Adds the usage recorded in the given period to the running counters.
+async fn count_usage(
+    db_pool: &sqlx::PgPool,
+    start: chrono::DateTime<chrono::Utc>,
+    end: chrono::DateTime<chrono::Utc>,
+    usage: &mut BTreeMap<String, UsageCounters>,
+) -> Result<(), RemoteWriteError> {
+    let usage_query = r#"
+        SELECT subscribers."imsi", SUM("ran_bytes_up")::BIGINT, SUM("ran_bytes_down")::BIGINT, SUM("wan_bytes_up")::BIGINT, SUM("wan_bytes_down")::BIGINT
+        FROM subscriber_usage
+        INNER JOIN subscribers ON subscriber_usage."subscriber" = subscribers."internal_uid"
+        WHERE "end_time" > $1 AND "end_time" <= $2
+        GROUP BY subscribers."imsi"
+    "#;
+    let rows: Vec<(String, i64, i64, i64, i64)> = sqlx::query_as(usage_query)
+        .bind(start)
+        .bind(end)
+        .fetch_all(db_pool)
+        .await?;
+    for (imsi, ran_up, ran_down, wan_up, wan_down) in rows {
+        let counters = usage.entry(imsi).or_default();
+        counters.ran_bytes_up += ran_up.max(0) as u64;
+        counters.ran_bytes_down += ran_down.max(0) as u64;
+        counters.wan_bytes_up += wan_up.max(0) as u64;
+        counters.wan_bytes_down += wan_down.max(0) as u64;
+    }
+    Ok(())
+}
+
+// The balance each subscriber draws from, which is their pool's if they
+// belong to one.
+async fn query_balances(db_pool: &sqlx::PgPool) -> Result<Vec<(String, i64)>, RemoteWriteError> {
+    let balance_query = r#"
+        SELECT subscribers."imsi", COALESCE(balance_pools."data_balance", subscribers."data_balance")
+        FROM subscribers
+        LEFT JOIN balance_pools ON balance_pools."id" = subscribers."balance_pool"
+        WHERE COALESCE(balance_pools."data_balance", subscribers."data_balance") IS NOT NULL
+    "#;
+    Ok(sqlx::query_as(balance_query).fetch_all(db_pool).await?)
+}
+
+async fn send(
+    http: &reqwest::Client,
+    settings: &Settings,
+    write_request: &[u8],
+) -> Result<(), RemoteWriteError> {
+    let body = snap::raw::Encoder::new().compress_vec(write_request)?;
+    let mut request = http
+        .post(&settings.url)
+        .header("Content-Encoding", "snappy")
+        .header("Content-Type", "application/x-protobuf")
+        .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+        .body(body);
+    if let Some(user) = &settings.user {
+        request = request.basic_auth(user, settings.password.as_ref());
+    }
+
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        return Err(RemoteWriteError::Rejected(response.text().await?));
+    }
+    Ok(())
+}
+
+// Encodes a remote write WriteRequest protobuf by hand, since it is only a few
+// nested messages:
+//   WriteRequest { repeated TimeSeries timeseries = 1; }
+//   TimeSeries { repeated Label labels = 1; repeated Sample samples = 2; }
+//   Label { string name = 1; string value = 2; }
+//   Sample { double value = 1; int64 timestamp = 2; }
+fn encode_write_request(samples: &[Sample], timestamp: i64) -> Vec<u8> {
+    let mut request = Vec::new();
+    for sample in samples {
+        let mut series = Vec::new();
+        // Labels are ordered by name, as the protocol requires.
+        for (name, value) in &sample.labels {
+            let mut label = Vec::new();
+            encode_bytes(&mut label, 1, name.as_bytes());
+            encode_bytes(&mut label, 2, value.as_bytes());
+            encode_bytes(&mut series, 1, &label);
+        }
+        let mut point = Vec::new();
+        encode_key(&mut point, 1, 1);
+        point.extend_from_slice(&sample.value.to_le_bytes());
+        encode_key(&mut point, 2, 0);
+        encode_varint(&mut point, timestamp as u64);
+        encode_bytes(&mut series, 2, &point);
+        encode_bytes(&mut request, 1, &series);
+    }
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_write_request() {
+        let sample = Sample::new(String::from("up"), 1.0).label("instance", "a");
+        let encoded = encode_write_request(&[sample], 300);
+
+        let mut expected = vec![0x0A, 45];
+        // Labels, with __name__ ordered first.
+        expected.extend_from_slice(&[0x0A, 14, 0x0A, 8]);
+        expected.extend_from_slice(b"__name__");
+        expected.extend_from_slice(&[0x12, 2]);
+        expected.extend_from_slice(b"up");
+        expected.extend_from_slice(&[0x0A, 13, 0x0A, 8]);
+        expected.extend_from_slice(b"instance");
+        expected.extend_from_slice(&[0x12, 1]);
+        expected.extend_from_slice(b"a");
+        // The sample, with a multi-byte varint timestamp.
+        expected.extend_from_slice(&[0x12, 12, 0x09]);
+        expected.extend_from_slice(&1.0f64.to_le_bytes());
+        expected.extend_from_slice(&[0x10, 0xAC, 0x02]);
+        assert_eq!(encoded, expected);
+    }
+}
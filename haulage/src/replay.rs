@@ -0,0 +1,184 @@
+use crate::capture::CaptureSource;
+use crate::clock::Clock;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ReplayError {
+    #[error("Unable to open the recorded capture: {0}")]
+    OpenError(#[from] crate::capture::CaptureError),
+    #[error("Unable to read the recorded capture: {0}")]
+    ReadError(#[from] std::io::Error),
+    #[error("The recorded capture contains no packets")]
+    Empty,
+}
+
+// Runs the packets recorded in a pcap or pcap-ng file through parsing,
+// normalization, aggregation, and reporting, writing the usage records to the
+// configured database, e.g. to debug or regression test the pipeline against
+// a trace. Aggregation intervals follow the recorded timestamps rather than
+// the wall clock, so a trace is reported in the same intervals however fast
+// it is replayed. Balances, enforcement, and the other subsystems are left
+// untouched.
+pub async fn run(
+    path: &std::path::Path,
+    config: std::sync::Arc<crate::config::Internal>,
+    storage: std::sync::Arc<dyn crate::storage::Storage>,
+    log: &slog::Logger,
+) -> Result<(), ReplayError> {
+    let mut capture = crate::capture::PcapFileCapture::open(path)?;
+    let mut packet = match next_packet(&mut capture)? {
+        Some(packet) => packet,
+        None => return Err(ReplayError::Empty),
+    };
+    let clock = std::sync::Arc::new(crate::clock::ReplayClock::new(packet.1));
+    slog::info!(log, "Replaying capture"; "path" => path.display().to_string(), "start" => packet.1.to_string());
+
+    let stats = std::sync::Arc::new(crate::stats::Stats::default());
+    let usage_writer = crate::usage_writer::UsageWriter::new(
+        crate::usage_writer::Settings {
+            flush_interval: config.usage_flush_interval,
+            destination_count: 0,
+            spill_path: None,
+        },
+        std::sync::Arc::clone(&storage),
+        None,
+        std::sync::Arc::clone(&stats),
+        log.new(slog::o!("subsystem" => "usage_writer")),
+    );
+    let user_aggregator =
+        crate::async_aggregator::AsyncAggregator::new::<crate::reporter::UserReporter>(
+            crate::clock::Schedule {
+                period: config.user_log_interval,
+                aligned: config.align_log_intervals,
+                timezone: config.site_timezone.unwrap_or_default(),
+            },
+            storage,
+            usage_writer.clone_input_channel(),
+            clock.clone(),
+            std::sync::Arc::clone(&stats),
+            log.new(slog::o!("aggregator" => "user")),
+        );
+
+    // Replayed usage is not charged, so accounting reports are discarded.
+    let (user_accounter, mut charges) = tokio::sync::mpsc::channel(64);
+    tokio::task::spawn(async move { while charges.recv().await.is_some() {} });
+
+    let sinks = crate::PacketSinks {
+        user_aggregator: user_aggregator.clone_input_channel(),
+        user_accounter,
+        destination_aggregator: None,
+        accounter_classifies: false,
+        content_filter: None,
+        flow_exporter: None,
+        flow_logger: None,
+        exports_flows: false,
+        flow_stream: None,
+        netflow_exporter: None,
+        exports_domains: false,
+        collision_detector: None,
+        nat_observer: None,
+        presence_tracker: None,
+        mac_binder: None,
+        trial_provisioner: None,
+        fragment_cache: std::sync::Arc::new(crate::packet_parser::FragmentCache::new()),
+        flow_cache: std::sync::Arc::new(crate::shedding::FlowCache::new()),
+    };
+
+    // Packets are handled in batches spanning at most the batch timeout of
+    // recorded time, as they would have been when captured live. Each batch
+    // is aggregated before the clock moves on to the next, so that its usage
+    // is reported in the interval it was captured in.
+    loop {
+        let batch_start = packet.1;
+        clock.advance(batch_start);
+        let mut batch = vec![packet.0];
+        let mut next = None;
+        while let Some(following) = next_packet(&mut capture)? {
+            if batch.len() >= crate::PACKET_BATCH_SIZE
+                || (following.1 - batch_start).to_std().unwrap_or_default()
+                    >= crate::PACKET_BATCH_TIMEOUT
+            {
+                next = Some(following);
+                break;
+            }
+            batch.push(following.0);
+        }
+
+        crate::handle_packet_batch(
+            batch,
+            sinks.clone(),
+            std::sync::Arc::clone(&config),
+            std::sync::Arc::clone(&stats),
+            log.clone(),
+        )
+        .await;
+        let (synced_tx, synced) = tokio::sync::oneshot::channel();
+        let _ = sinks
+            .user_aggregator
+            .send(crate::async_aggregator::Message::Sync {
+                out_channel: synced_tx,
+            })
+            .await;
+        let _ = synced.await;
+
+        packet = match next {
+            Some(next) => next,
+            None => break,
+        };
+    }
+
+    // The trace ends with a partial interval, reported up to its last packet.
+    let (flushed_tx, flushed) = tokio::sync::oneshot::channel();
+    let _ = sinks
+        .user_aggregator
+        .send(crate::async_aggregator::Message::Flush {
+            out_channel: flushed_tx,
+        })
+        .await;
+    let _ = flushed.await;
+    let (written_tx, written) = tokio::sync::oneshot::channel();
+    let _ = usage_writer
+        .clone_input_channel()
+        .send(crate::usage_writer::Message::Flush {
+            out_channel: written_tx,
+        })
+        .await;
+    let _ = written.await;
+
+    let capture_stats = capture.stats();
+    slog::info!(log, "Replay complete";
+        "packets" => capture_stats.packets_received,
+        "end" => clock.now().to_string(),
+        "parse_errors" => stats.parse_errors.get(),
+        "records" => stats.usage_records_written.get(),
+    );
+    Ok(())
+}
+
+// Reads the next recorded packet and its capture time, or None at the end of
+// the recording.
+fn next_packet(
+    capture: &mut crate::capture::PcapFileCapture,
+) -> Result<Option<(crate::PacketKind, chrono::DateTime<chrono::Utc>)>, std::io::Error> {
+    loop {
+        let data = match capture.next_packet() {
+            Ok(packet) => bytes::Bytes::copy_from_slice(packet),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let kind = match capture.link_type() {
+            crate::capture::LinkType::Ethernet => crate::PacketKind::Ethernet(data),
+            crate::capture::LinkType::RawIp => match data.first().map(|byte| byte >> 4) {
+                Some(4) => crate::PacketKind::IPv4(data),
+                Some(6) => crate::PacketKind::IPv6(data),
+                // Packets which are not IP are skipped, as they are live.
+                _ => continue,
+            },
+        };
+        // Packets always carry a timestamp when read from a file.
+        return Ok(Some((
+            kind,
+            capture.timestamp().unwrap_or_else(chrono::Utc::now),
+        )));
+    }
+}
@@ -7,25 +7,61 @@ use thiserror::Error;
 #[derive(Error, Debug)]
 pub enum ReportError {
     #[error("Database operation failed: {0}")]
-    DatabaseError(#[from] sqlx::error::Error),
+    DatabaseError(#[from] crate::storage::StorageError),
     #[error("Failed to lookup user")]
     UserLookupError,
+    #[error("Usage writer is not running")]
+    WriterUnavailable,
 }
 
 #[async_trait]
 pub trait Reporter {
+    // Whether aggregation workers stop after an interval without usage, for
+    // ids which are numerous and transient rather than a bounded set of
+    // subscribers. A new worker starts if the id is seen again.
+    const RETIRES_WHEN_IDLE: bool = false;
+
     async fn report(&self, use_record: UseRecord) -> Result<(), ReportError>;
-    fn new(pool: Arc<sqlx::PgPool>, id: std::net::IpAddr) -> Self;
     async fn initialize(&mut self) -> Result<(), ReportError>;
+    // An override of the configured report interval, looked up once the
+    // reporter is initialized and again on reload.
+    async fn report_interval(&self) -> Result<Option<std::time::Duration>, ReportError> {
+        Ok(None)
+    }
+}
+
+// Constructs the reporter of each aggregation worker the dispatcher starts.
+pub trait NewReporter: Reporter {
+    fn new(
+        storage: Arc<dyn crate::storage::Storage>,
+        usage_writer: tokio::sync::mpsc::Sender<crate::usage_writer::Message>,
+        id: std::net::IpAddr,
+    ) -> Self;
 }
 
 #[derive(Debug, Clone)]
 pub struct UserReporter {
-    db_pool: Arc<sqlx::PgPool>,
+    storage: Arc<dyn crate::storage::Storage>,
+    usage_writer: tokio::sync::mpsc::Sender<crate::usage_writer::Message>,
     ip_addr: std::net::IpAddr,
     id: i32,
 }
 
+impl NewReporter for UserReporter {
+    fn new(
+        storage: Arc<dyn crate::storage::Storage>,
+        usage_writer: tokio::sync::mpsc::Sender<crate::usage_writer::Message>,
+        ip: std::net::IpAddr,
+    ) -> Self {
+        Self {
+            storage,
+            usage_writer,
+            ip_addr: ip,
+            id: -1,
+        }
+    }
+}
+
 #[async_trait]
 impl Reporter for UserReporter {
     async fn report(&self, record: UseRecord) -> Result<(), ReportError> {
@@ -33,49 +69,20 @@ impl Reporter for UserReporter {
             // TODO Actually enforce at compile time rather than with a runtime panic.
             panic!("Invalid ID: reporter not initialized!");
         }
-        let mut transaction = self.db_pool.begin().await?;
-
-        let update_history_query = r#"
-            INSERT INTO subscriber_usage("subscriber", "start_time", "end_time", "ran_bytes_up", "ran_bytes_down", "wan_bytes_up", "wan_bytes_down")
-            VALUES ($1, $2, $3, $4, $5, $6, $7)
-        "#;
-        sqlx::query(update_history_query)
-            .bind(&self.id)
-            .bind(&record.start)
-            .bind(&record.end)
-            .bind(&record.usage.ran_bytes_up)
-            .bind(&record.usage.ran_bytes_down)
-            .bind(&record.usage.wan_bytes_up)
-            .bind(&record.usage.wan_bytes_down)
-            .execute(&mut transaction)
-            .await?;
-
-        transaction.commit().await?;
-        Ok(())
-    }
 
-    fn new(pool: Arc<sqlx::PgPool>, ip: std::net::IpAddr) -> Self {
-        Self {
-            db_pool: pool,
-            ip_addr: ip,
-            id: -1,
-        }
+        // Records are queued and written in bulk alongside other subscribers'
+        // records rather than individually.
+        self.usage_writer
+            .send(crate::usage_writer::Message::Record {
+                subscriber: self.id,
+                record,
+            })
+            .await
+            .or(Err(ReportError::WriterUnavailable))
     }
 
     async fn initialize(&mut self) -> Result<(), ReportError> {
-        let mut transaction = self.db_pool.begin().await?;
-
-        let id_query = r#"
-            SELECT "internal_uid" AS "subscriber_id", ip
-            FROM subscribers
-            INNER JOIN static_ips ON static_ips.imsi = subscribers.imsi
-            WHERE static_ips.ip = $1
-        "#;
-
-        let rows: Vec<IdRow> = sqlx::query_as(id_query)
-            .bind(ipnetwork::IpNetwork::from(self.ip_addr))
-            .fetch_all(&mut transaction)
-            .await?;
+        let rows = self.storage.subscribers_by_address(self.ip_addr).await?;
 
         // Ensure the user is unique
         if rows.len() != 1 {
@@ -86,9 +93,63 @@ impl Reporter for UserReporter {
         self.id = user_state.subscriber_id;
         Ok(())
     }
+
+    async fn report_interval(&self) -> Result<Option<std::time::Duration>, ReportError> {
+        Ok(self.storage.report_interval(self.id).await?)
+    }
 }
 
+// Reports the backhaul traffic exchanged with a remote host, for ranking the
+// top destinations of each interval.
 #[derive(Debug, Clone)]
+pub struct DestinationReporter {
+    usage_writer: tokio::sync::mpsc::Sender<crate::usage_writer::Message>,
+    destination: std::net::IpAddr,
+}
+
+impl NewReporter for DestinationReporter {
+    fn new(
+        _storage: Arc<dyn crate::storage::Storage>,
+        usage_writer: tokio::sync::mpsc::Sender<crate::usage_writer::Message>,
+        destination: std::net::IpAddr,
+    ) -> Self {
+        Self {
+            usage_writer,
+            destination,
+        }
+    }
+}
+
+#[async_trait]
+impl Reporter for DestinationReporter {
+    const RETIRES_WHEN_IDLE: bool = true;
+
+    async fn report(&self, record: UseRecord) -> Result<(), ReportError> {
+        if record.usage == crate::NetResourceBundle::zeroed() {
+            return Ok(());
+        }
+        self.usage_writer
+            .send(crate::usage_writer::Message::Destination {
+                destination: self.destination,
+                record,
+            })
+            .await
+            .or(Err(ReportError::WriterUnavailable))
+    }
+
+    async fn initialize(&mut self) -> Result<(), ReportError> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DestinationSettings {
+    // How many destinations are kept per interval.
+    pub count: usize,
+    pub interval: std::time::Duration,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct UseRecord {
     pub start: chrono::DateTime<Utc>,
     pub end: chrono::DateTime<Utc>,
@@ -96,6 +157,89 @@ pub struct UseRecord {
     pub usage: crate::NetResourceBundle,
 }
 
+// Bounds the records held for a reporter while it cannot report, a day of
+// records at the default interval. The oldest are dropped beyond this.
+const MAX_RETRY_RECORDS: usize = 1440;
+const MIN_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+const MAX_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+// Holds the records a reporter failed to report, e.g. while the database is
+// unavailable, and reports them in order once a retry succeeds, backing off
+// exponentially between failed attempts.
+#[derive(Debug)]
+pub struct RetryBuffer {
+    records: std::collections::VecDeque<UseRecord>,
+    delay: std::time::Duration,
+    retry_at: Option<tokio::time::Instant>,
+}
+impl Default for RetryBuffer {
+    fn default() -> Self {
+        RetryBuffer {
+            records: std::collections::VecDeque::new(),
+            delay: MIN_RETRY_DELAY,
+            retry_at: None,
+        }
+    }
+}
+impl RetryBuffer {
+    // Queues a record behind those awaiting retry, returning the oldest record
+    // if it was dropped to make room.
+    pub fn push(&mut self, record: UseRecord) -> Option<UseRecord> {
+        self.records.push_back(record);
+        match self.records.len() > MAX_RETRY_RECORDS {
+            true => self.records.pop_front(),
+            false => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    // When the next attempt is due after a failure, if any.
+    pub fn retry_at(&self) -> Option<tokio::time::Instant> {
+        self.retry_at
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.retry_at
+            .is_none_or(|retry_at| retry_at <= tokio::time::Instant::now())
+    }
+
+    // Delays the next attempt, doubling the delay after each consecutive
+    // failure.
+    pub fn back_off(&mut self) {
+        self.retry_at = Some(tokio::time::Instant::now() + self.delay);
+        self.delay = (self.delay * 2).min(MAX_RETRY_DELAY);
+    }
+
+    // Reports the buffered records in order, stopping and backing off at the
+    // first failure. Returns the number of records reported.
+    pub async fn flush<T: Reporter + Sync>(&mut self, reporter: &T) -> Result<u64, ReportError> {
+        let mut reported = 0;
+        while let Some(record) = self.records.front() {
+            if let Err(e) = reporter.report(record.clone()).await {
+                self.back_off();
+                return Err(e);
+            }
+            self.records.pop_front();
+            reported += 1;
+        }
+        self.delay = MIN_RETRY_DELAY;
+        self.retry_at = None;
+        Ok(reported)
+    }
+
+    // Removes all buffered records, e.g. to hand them off to a new process.
+    pub fn drain(&mut self) -> impl Iterator<Item = UseRecord> + '_ {
+        self.records.drain(..)
+    }
+}
+
 #[derive(Debug, Clone, sqlx::FromRow)]
 struct SubscriberReportRow {
     subscriber: i32,
@@ -114,9 +258,3 @@ struct SubscriberRow {
     balance: rust_decimal::Decimal,
     bridged: bool,
 }
-
-#[derive(Debug, Clone, sqlx::FromRow)]
-struct IdRow {
-    subscriber_id: i32,
-    ip: ipnetwork::IpNetwork,
-}
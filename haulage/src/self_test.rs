@@ -0,0 +1,186 @@
+// Verifies that a node can run haulage before subscribers are put on it, by
+// exercising each subsystem's external dependencies without changing any
+// state. Returns whether all checks passed.
+pub async fn run(config: &crate::config::Internal, log: &slog::Logger) -> bool {
+    let mut results = Vec::new();
+
+    let mut interfaces = vec![&config.subscriber_interface];
+    interfaces.extend(&config.upstream_interfaces);
+    for interface in interfaces {
+        results.push((
+            format!("capture {}", interface),
+            check_capture(interface, config).await,
+        ));
+    }
+    results.push((String::from("parser"), check_parser(log)));
+    results.push((
+        String::from("database"),
+        check_database(&config.database).await,
+    ));
+    // Monitoring-only nodes never change the data plane.
+    if config.enforcement {
+        let (subscriber_interface, _) = config.enforcement_interfaces();
+        results.push((
+            String::from("iptables"),
+            check_command(
+                subscriber_interface.command("iptables"),
+                &["-w", "-n", "-L", "FORWARD"],
+            )
+            .await,
+        ));
+        results.push((
+            String::from("tc"),
+            check_traffic_control(&subscriber_interface).await,
+        ));
+    }
+    if config.nft_quota {
+        results.push((
+            String::from("nft"),
+            check_command(tokio::process::Command::new("nft"), &["list", "tables"]).await,
+        ));
+    }
+
+    // Report directly, since the async log is not flushed before exiting.
+    for (subsystem, result) in &results {
+        match result {
+            Ok(()) => println!("PASS {}", subsystem),
+            Err(reason) => println!("FAIL {}: {}", subsystem, reason),
+        }
+    }
+    results.iter().all(|(_, result)| result.is_ok())
+}
+
+async fn check_capture(interface: &str, config: &crate::config::Internal) -> Result<(), String> {
+    let interface = interface.to_owned();
+    let filter = config.filter_capture.then(|| config.user_subnets.clone());
+    // Opening a capture blocks, so is kept off of the async executor.
+    tokio::task::spawn_blocking(move || {
+        crate::capture::open(
+            &interface,
+            std::time::Duration::from_millis(100),
+            filter.as_deref(),
+        )
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+// Reads the interface's qdiscs over rtnetlink, as the enforcer does.
+async fn check_traffic_control(interface: &crate::netns::Interface) -> Result<(), String> {
+    let mut tc = crate::tc::Tc::open(interface)
+        .await
+        .map_err(|e| e.to_string())?;
+    tc.qdisc_stats()
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+// Parses a synthetic DNS response, exercising the full parser pipeline
+// through to domain attribution.
+fn check_parser(log: &slog::Logger) -> Result<(), String> {
+    let info = crate::packet_parser::parse_ipv4(&synthetic_dns_packet(), log)
+        .map_err(|e| e.to_string())?;
+    let expected: std::net::IpAddr = "10.45.0.2".parse().unwrap();
+    let answer: std::net::IpAddr = "192.0.2.1".parse().unwrap();
+    if info.fivetuple.dst != expected || info.fivetuple.src_port != 53 {
+        return Err(format!("unexpected flow {:?}", info.fivetuple));
+    }
+    match info.dns_response {
+        Some(response) if response.addresses == vec![answer] => Ok(()),
+        other => Err(format!("unexpected DNS response {:?}", other)),
+    }
+}
+
+// An IPv4 UDP packet from 8.8.8.8 to 10.45.0.2 carrying a DNS response for
+// example.com with a single A record. Checksums are not verified.
+fn synthetic_dns_packet() -> Vec<u8> {
+    let mut dns = vec![
+        0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+    ];
+    dns.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+    dns.extend_from_slice(&[
+        0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10, 0x00, 0x04, 192, 0, 2, 1,
+    ]);
+
+    let udp_length = (8 + dns.len()) as u16;
+    let mut packet = vec![0x45, 0x00];
+    packet.extend_from_slice(&(20 + udp_length).to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0, 0, 64, 17, 0, 0, 8, 8, 8, 8, 10, 45, 0, 2]);
+    packet.extend_from_slice(&53_u16.to_be_bytes());
+    packet.extend_from_slice(&40000_u16.to_be_bytes());
+    packet.extend_from_slice(&udp_length.to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(&dns);
+    packet
+}
+
+pub async fn check_database(database: &crate::config::Database) -> Result<(), String> {
+    let options = match database {
+        crate::config::Database::Postgres(db_options) => db_options,
+        // The database is only read, so is not created if it does not exist.
+        crate::config::Database::Sqlite(path) => {
+            let options = sqlx::sqlite::SqliteConnectOptions::new()
+                .filename(path)
+                .read_only(true);
+            let db_pool = sqlx::SqlitePool::connect_with(options)
+                .await
+                .map_err(|e| e.to_string())?;
+            let _: (i64,) = sqlx::query_as(r#"SELECT COUNT(*) FROM subscribers"#)
+                .fetch_one(&db_pool)
+                .await
+                .map_err(|e| e.to_string())?;
+            return Ok(());
+        }
+        crate::config::Database::Mysql(db_options) => {
+            let connect = sqlx::MySqlPool::connect_with((**db_options).clone());
+            let db_pool = tokio::time::timeout(std::time::Duration::from_secs(5), connect)
+                .await
+                .map_err(|_| String::from("connection timed out"))?
+                .map_err(|e| e.to_string())?;
+            let _: (i64,) = sqlx::query_as(r#"SELECT COUNT(*) FROM subscribers"#)
+                .fetch_one(&db_pool)
+                .await
+                .map_err(|e| e.to_string())?;
+            return Ok(());
+        }
+    };
+    let connect = sqlx::PgPool::connect_with((**options).clone());
+    let db_pool = tokio::time::timeout(std::time::Duration::from_secs(5), connect)
+        .await
+        .map_err(|_| String::from("connection timed out"))?
+        .map_err(|e| e.to_string())?;
+
+    let mut transaction = db_pool.begin().await.map_err(|e| e.to_string())?;
+    let _: (i64,) = sqlx::query_as(r#"SELECT COUNT(*) FROM subscribers"#)
+        .fetch_one(&mut transaction)
+        .await
+        .map_err(|e| e.to_string())?;
+    transaction.rollback().await.map_err(|e| e.to_string())
+}
+
+// Runs a read-only command, passing if it exits successfully.
+async fn check_command(mut command: tokio::process::Command, args: &[&str]) -> Result<(), String> {
+    let output = command
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| e.to_string())?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(String::from_utf8_lossy(&output.stderr).trim().to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_synthetic_packet_parses() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        assert_eq!(check_parser(&log), Ok(()));
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+
+use crate::packet_parser::FiveTuple;
+
+// Bounds the memory used to remember flows. Flows beyond the limit are always
+// fully parsed.
+const MAX_KNOWN_FLOWS: usize = 65536;
+
+// Remembers which subscriber recently seen flows belong to, so that when the
+// accounting subsystems fall behind, packets of known flows can skip parsing
+// and reporting entirely. The bytes of skipped packets are tallied per flow
+// and reported once the backlog clears, so overload delays accounting rather
+// than losing it, except for flows forgotten before the backlog clears.
+#[derive(Debug, Default)]
+pub struct FlowCache {
+    flows: std::sync::Mutex<HashMap<FiveTuple, FlowState>>,
+    // Avoids scanning all flows for shed bytes when nothing was shed.
+    shed_pending: std::sync::atomic::AtomicBool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KnownFlow {
+    pub user_addr: std::net::IpAddr,
+    pub remote_addr: std::net::IpAddr,
+    pub protocol: u8,
+    pub remote_port: u16,
+    // Whether packets of the flow are sent by the subscriber.
+    pub upload: bool,
+}
+
+#[derive(Debug)]
+struct FlowState {
+    flow: KnownFlow,
+    shed_bytes: u64,
+    last_seen: std::time::Instant,
+}
+
+// Bytes shed from a flow, to be reported in place of the skipped packets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShedUsage {
+    pub flow: KnownFlow,
+    pub bytes: u64,
+}
+
+impl FlowCache {
+    pub fn new() -> FlowCache {
+        FlowCache::default()
+    }
+
+    // Records the owners of flows fully parsed in a batch of packets.
+    pub fn learn(&self, learned: HashMap<FiveTuple, KnownFlow>, now: std::time::Instant) {
+        let mut flows = self.flows.lock().unwrap();
+        for (fivetuple, flow) in learned {
+            if flows.len() >= MAX_KNOWN_FLOWS && !flows.contains_key(&fivetuple) {
+                continue;
+            }
+            let state = flows.entry(fivetuple).or_insert(FlowState {
+                flow,
+                shed_bytes: 0,
+                last_seen: now,
+            });
+            state.flow = flow;
+            state.last_seen = now;
+        }
+    }
+
+    // Tallies the bytes of a packet in place of parsing it, returning false if
+    // the flow is not known and the packet must be parsed.
+    pub fn shed(&self, fivetuple: &FiveTuple, bytes: u64, now: std::time::Instant) -> bool {
+        let mut flows = self.flows.lock().unwrap();
+        match flows.get_mut(fivetuple) {
+            Some(state) => {
+                state.shed_bytes += bytes;
+                state.last_seen = now;
+                self.shed_pending
+                    .store(true, std::sync::atomic::Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Takes the bytes shed from all flows since the last call.
+    pub fn take_shed(&self) -> Vec<ShedUsage> {
+        if !self
+            .shed_pending
+            .swap(false, std::sync::atomic::Ordering::Relaxed)
+        {
+            return Vec::new();
+        }
+        let mut flows = self.flows.lock().unwrap();
+        flows
+            .values_mut()
+            .filter(|state| state.shed_bytes > 0)
+            .map(|state| ShedUsage {
+                flow: state.flow,
+                bytes: std::mem::take(&mut state.shed_bytes),
+            })
+            .collect()
+    }
+
+    // Forgets flows idle for longer than the timeout, returning the number of
+    // shed bytes that will never be reported.
+    pub fn expire(&self, now: std::time::Instant, timeout: std::time::Duration) -> u64 {
+        let mut flows = self.flows.lock().unwrap();
+        let mut lost = 0;
+        flows.retain(|_, state| {
+            let keep = now.duration_since(state.last_seen) < timeout;
+            if !keep {
+                lost += state.shed_bytes;
+            }
+            keep
+        });
+        lost
+    }
+}
+
+// Periodically forgets idle flows.
+pub async fn expire_flows(
+    cache: std::sync::Arc<FlowCache>,
+    timeout: std::time::Duration,
+    stats: std::sync::Arc<crate::stats::Stats>,
+) {
+    let mut timer = tokio::time::interval(timeout);
+    loop {
+        timer.tick().await;
+        let lost = cache.expire(std::time::Instant::now(), timeout);
+        stats.shed_bytes_lost.add(lost);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shed_bytes_reported_once() {
+        let cache = FlowCache::new();
+        let now = std::time::Instant::now();
+        let fivetuple = FiveTuple {
+            src: "10.45.0.2".parse().unwrap(),
+            dst: "93.184.216.34".parse().unwrap(),
+            src_port: 50000,
+            dst_port: 443,
+            protocol: 6,
+        };
+        let flow = KnownFlow {
+            user_addr: fivetuple.src,
+            remote_addr: fivetuple.dst,
+            protocol: 6,
+            remote_port: 443,
+            upload: true,
+        };
+
+        assert!(!cache.shed(&fivetuple, 100, now));
+        cache.learn(HashMap::from([(fivetuple, flow)]), now);
+        assert!(cache.shed(&fivetuple, 100, now));
+        assert!(cache.shed(&fivetuple, 50, now));
+
+        assert_eq!(cache.take_shed(), vec![ShedUsage { flow, bytes: 150 }]);
+        assert_eq!(cache.take_shed(), vec![]);
+
+        // Bytes shed from flows that expire are lost.
+        assert!(cache.shed(&fivetuple, 10, now));
+        let timeout = std::time::Duration::from_secs(60);
+        assert_eq!(cache.expire(now + timeout, timeout), 10);
+        assert!(!cache.shed(&fivetuple, 10, now));
+    }
+}
@@ -0,0 +1,108 @@
+// Bounds how long workers may take to write out their usage, e.g. while the
+// database is unavailable, before the usage writer is stopped regardless.
+const FLUSH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+// The subsystems holding state which must be written out or removed before
+// haulage exits.
+#[derive(Debug)]
+pub struct Subsystems {
+    pub user_aggregator: tokio::sync::mpsc::Sender<crate::async_aggregator::Message>,
+    pub destination_aggregator: Option<tokio::sync::mpsc::Sender<crate::async_aggregator::Message>>,
+    pub accounter: tokio::sync::mpsc::Sender<crate::accounter::Message>,
+    pub usage_writer: tokio::sync::mpsc::Sender<crate::usage_writer::Message>,
+    pub enforcer: Option<std::sync::Arc<crate::enforcer::Iptables>>,
+}
+
+// The signal haulage was asked to exit with. Only SIGTERM, e.g. from systemd
+// during a package upgrade, hands off to a replacement process if configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Interrupt,
+    Terminate,
+}
+
+// Returns once haulage is asked to exit with SIGINT or SIGTERM.
+pub async fn wait_for_signal(log: &slog::Logger) -> Signal {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let (mut interrupts, mut terminations) = match (
+        signal(SignalKind::interrupt()),
+        signal(SignalKind::terminate()),
+    ) {
+        (Ok(interrupts), Ok(terminations)) => (interrupts, terminations),
+        (Err(e), _) | (_, Err(e)) => {
+            slog::error!(log, "Unable to listen for shutdown signals"; "error" => e.to_string());
+            return std::future::pending().await;
+        }
+    };
+    tokio::select! {
+        _ = interrupts.recv() => {
+            slog::info!(log, "Interrupted, shutting down");
+            Signal::Interrupt
+        }
+        _ = terminations.recv() => {
+            slog::info!(log, "Terminated, shutting down");
+            Signal::Terminate
+        }
+    }
+}
+
+// Writes out the usage aggregated since the last interval and removes the
+// enforcement state, once capture has stopped.
+pub async fn flush(subsystems: Subsystems, log: &slog::Logger) {
+    write_out_usage(&subsystems, log).await;
+
+    if let Some(enforcer) = subsystems.enforcer {
+        match enforcer.shut_down().await {
+            Ok(()) => slog::info!(log, "Cleared enforcement state"),
+            Err(e) => {
+                slog::error!(log, "Failed to clear enforcement state"; "error" => e.to_string())
+            }
+        }
+    }
+}
+
+// Writes out the partial intervals of the aggregators still running, the
+// charges not yet synchronized by the accounter, and the records buffered by
+// the usage writer. Each step waits for the previous one, since the
+// aggregators and accounter write through the usage writer.
+pub async fn write_out_usage(subsystems: &Subsystems, log: &slog::Logger) {
+    let workers = async {
+        let aggregators =
+            std::iter::once(&subsystems.user_aggregator).chain(&subsystems.destination_aggregator);
+        for aggregator in aggregators {
+            let (out_channel, done) = tokio::sync::oneshot::channel();
+            if aggregator
+                .send(crate::async_aggregator::Message::Flush { out_channel })
+                .await
+                .is_ok()
+            {
+                let _ = done.await;
+            }
+        }
+        let (out_channel, done) = tokio::sync::oneshot::channel();
+        if subsystems
+            .accounter
+            .send(crate::accounter::Message::Flush { out_channel })
+            .await
+            .is_ok()
+        {
+            let _ = done.await;
+        }
+    };
+    if tokio::time::timeout(FLUSH_TIMEOUT, workers).await.is_err() {
+        slog::warn!(log, "Timed out writing out partial intervals");
+    }
+
+    let (out_channel, done) = tokio::sync::oneshot::channel();
+    match subsystems
+        .usage_writer
+        .send(crate::usage_writer::Message::Flush { out_channel })
+        .await
+    {
+        Ok(()) => {
+            let _ = done.await;
+        }
+        Err(_) => slog::warn!(log, "Usage writer stopped before the final flush"),
+    }
+}
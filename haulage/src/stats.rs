@@ -0,0 +1,224 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Declares the set of process-wide counters along with a plain snapshot type,
+// so that adding a counter only requires touching this list.
+macro_rules! define_stats {
+    ($($name:ident),* $(,)?) => {
+        #[derive(Debug, Default)]
+        pub struct Stats {
+            $(pub $name: Counter,)*
+        }
+
+        #[derive(Debug, Clone, Default, serde::Serialize)]
+        pub struct Snapshot {
+            $(pub $name: u64,)*
+        }
+
+        impl Stats {
+            pub fn snapshot(&self) -> Snapshot {
+                Snapshot {
+                    $($name: self.$name.get(),)*
+                }
+            }
+        }
+
+        impl Snapshot {
+            // Counters are monotonic, so the difference between two snapshots
+            // is the activity within the interval between them.
+            pub fn delta(&self, earlier: &Snapshot) -> Snapshot {
+                Snapshot {
+                    $($name: self.$name.saturating_sub(earlier.$name),)*
+                }
+            }
+
+            pub fn summary(&self) -> String {
+                let fields: Vec<String> = vec![$(format!("{}={}", stringify!($name), self.$name),)*];
+                fields.join(" ")
+            }
+        }
+    };
+}
+
+define_stats!(
+    packets_captured,
+    capture_errors,
+    debug_capture_drops,
+    packets_parsed,
+    parse_errors,
+    packets_unnormalized,
+    packets_link_local,
+    fragments_reassembled,
+    fragments_dropped,
+    fragment_bytes_lost,
+    packets_shed,
+    shed_bytes_reported,
+    shed_bytes_lost,
+    aggregator_reports,
+    aggregator_workers_started,
+    aggregator_dispatch_errors,
+    usage_records_queued,
+    usage_records_written,
+    usage_flushes,
+    usage_record_errors,
+    db_write_latency_count,
+    db_write_latency_ms_total,
+    db_write_latency_under_10ms,
+    db_write_latency_under_50ms,
+    db_write_latency_under_250ms,
+    db_write_latency_under_1s,
+    db_write_latency_over_1s,
+    accounter_reports,
+    accounter_workers_started,
+    accounter_dispatch_errors,
+    user_bytes_charged,
+    user_bytes_weighted,
+    user_bytes_debited,
+    user_bytes_exempt,
+    balance_syncs,
+    balance_sync_errors,
+    quota_unaccounted_bytes,
+    policy_updates,
+    policy_update_errors,
+    enforcement_latency_count,
+    enforcement_latency_ms_total,
+    enforcement_latency_under_100ms,
+    enforcement_latency_under_500ms,
+    enforcement_latency_under_1s,
+    enforcement_latency_under_5s,
+    enforcement_latency_over_5s,
+    clickhouse_records_exported,
+    flows_finished_early,
+    clickhouse_export_errors,
+    flow_records_written,
+    flow_record_errors,
+    syslog_messages_sent,
+    syslog_send_errors,
+    netflow_records_exported,
+    netflow_export_errors,
+    remote_write_pushes,
+    remote_write_errors,
+    reconciliation_discrepancies,
+    address_collisions,
+    hook_runs,
+    hook_failures,
+);
+
+impl Stats {
+    // Records the time from detecting an exhausted balance to the enforcement
+    // rules being applied, as a histogram of non-cumulative buckets along
+    // with the total for computing the mean.
+    pub fn record_enforcement_latency(&self, latency: std::time::Duration) {
+        self.enforcement_latency_count.increment();
+        self.enforcement_latency_ms_total
+            .add(latency.as_millis() as u64);
+        let bucket = match latency.as_millis() {
+            0..=99 => &self.enforcement_latency_under_100ms,
+            100..=499 => &self.enforcement_latency_under_500ms,
+            500..=999 => &self.enforcement_latency_under_1s,
+            1000..=4999 => &self.enforcement_latency_under_5s,
+            _ => &self.enforcement_latency_over_5s,
+        };
+        bucket.increment();
+    }
+
+    // Records the time taken to write a batch of usage records and debits to
+    // the database, bucketed as for enforcement latency.
+    pub fn record_db_write_latency(&self, latency: std::time::Duration) {
+        self.db_write_latency_count.increment();
+        self.db_write_latency_ms_total
+            .add(latency.as_millis() as u64);
+        let bucket = match latency.as_millis() {
+            0..=9 => &self.db_write_latency_under_10ms,
+            10..=49 => &self.db_write_latency_under_50ms,
+            50..=249 => &self.db_write_latency_under_250ms,
+            250..=999 => &self.db_write_latency_under_1s,
+            _ => &self.db_write_latency_over_1s,
+        };
+        bucket.increment();
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+impl Counter {
+    pub fn increment(&self) {
+        self.add(1);
+    }
+    pub fn add(&self, amount: u64) {
+        self.0.fetch_add(amount, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Periodically log a single summary line of the activity since the last
+// summary, and optionally export the cumulative counters as json for external
+// collection (e.g. a node_exporter textfile collector or a site monitoring
+// script).
+pub async fn summarize(
+    stats: std::sync::Arc<Stats>,
+    period: std::time::Duration,
+    export_path: Option<std::path::PathBuf>,
+    log: slog::Logger,
+) {
+    let mut previous = stats.snapshot();
+    let mut timer = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        timer.tick().await;
+        let current = stats.snapshot();
+        slog::info!(log, "Stats summary"; "interval" => humantime::format_duration(period).to_string(), "activity" => current.delta(&previous).summary());
+
+        if let Some(path) = &export_path {
+            export_snapshot(path, &current)
+                .await
+                .unwrap_or_else(|e| slog::warn!(log, "Failed to export stats"; "path" => path.display().to_string(), "error" => e.to_string()));
+        }
+        previous = current;
+    }
+}
+
+async fn export_snapshot(path: &std::path::Path, snapshot: &Snapshot) -> std::io::Result<()> {
+    // Write to a temporary file and rename so readers never see a partial
+    // snapshot.
+    let serialized = serde_json::to_vec(snapshot)?;
+    let temp_path = path.with_extension("tmp");
+    tokio::fs::write(&temp_path, serialized).await?;
+    tokio::fs::rename(&temp_path, path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_delta() {
+        let stats = Stats::default();
+        stats.packets_captured.add(5);
+        let earlier = stats.snapshot();
+        stats.packets_captured.add(3);
+        stats.parse_errors.increment();
+
+        let delta = stats.snapshot().delta(&earlier);
+        assert_eq!(delta.packets_captured, 3);
+        assert_eq!(delta.parse_errors, 1);
+        assert_eq!(delta.packets_parsed, 0);
+    }
+
+    #[test]
+    fn test_enforcement_latency_buckets() {
+        let stats = Stats::default();
+        stats.record_enforcement_latency(std::time::Duration::from_millis(40));
+        stats.record_enforcement_latency(std::time::Duration::from_millis(500));
+        stats.record_enforcement_latency(std::time::Duration::from_secs(7));
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.enforcement_latency_count, 3);
+        assert_eq!(snapshot.enforcement_latency_ms_total, 7540);
+        assert_eq!(snapshot.enforcement_latency_under_100ms, 1);
+        assert_eq!(snapshot.enforcement_latency_under_500ms, 0);
+        assert_eq!(snapshot.enforcement_latency_under_1s, 1);
+        assert_eq!(snapshot.enforcement_latency_over_5s, 1);
+    }
+}
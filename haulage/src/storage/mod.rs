@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use thiserror::Error;
+
+use crate::reporter::UseRecord;
+use crate::usage_writer::DebitResult;
+
+mod mysql;
+mod postgres;
+mod sqlite;
+
+pub use mysql::Mysql;
+pub use postgres::{copy_usage_records, Postgres};
+pub use sqlite::Sqlite;
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("Database operation failed: {0}")]
+    DatabaseError(#[from] sqlx::error::Error),
+    #[error("Database migration failed: {0}")]
+    MigrateError(#[from] sqlx::migrate::MigrateError),
+    #[error("Unapplied migrations exist, but automatic upgrades are disabled")]
+    UnappliedMigrations,
+    #[error("Invalid address in the database: {0}")]
+    InvalidAddress(String),
+}
+
+// A subscriber matching an address, with the balance they draw from, which is
+// their pool's balance if they belong to one.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct SubscriberBalance {
+    pub subscriber_id: i32,
+    pub data_balance: i64,
+}
+
+// Everything the usage writer writes with one flush, which is written in a
+// single transaction so that a crash cannot record usage without charging it
+// or charge usage without recording it.
+#[derive(Debug)]
+pub struct UsageBatch<'a> {
+    pub records: &'a [(i32, UseRecord)],
+    // The bytes to debit from each subscriber's balance.
+    pub debits: &'a HashMap<i32, i64>,
+    // The top destinations of each interval written, and how many are kept
+    // per interval, since the records of an interval may be split across
+    // flushes.
+    pub destinations: &'a [&'a (std::net::IpAddr, UseRecord)],
+    pub destination_count: usize,
+}
+
+// The parameters of a link policy as stored in the database.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct LimitPolicyParameters {
+    pub rate_kibps: Option<u32>,
+    pub min_rate_kibps: Option<u32>,
+    pub burst_bytes: Option<u32>,
+    pub priority: Option<u8>,
+    pub schedule: Option<LimitScheduleParameters>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct LimitScheduleParameters {
+    pub hours: String,
+    pub rate_kibps: Option<u32>,
+}
+
+// The access policy selected for one address of a subscriber, so subscribers
+// with several addresses have a row for each.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct AccessPolicyRow {
+    pub ip: ipnetwork::IpNetwork,
+    pub subscriber_id: i32,
+    pub policy_id: i32,
+    pub local_ul_policy_kind: i32,
+    pub local_ul_policy_parameters: sqlx::types::Json<LimitPolicyParameters>,
+    pub local_dl_policy_kind: i32,
+    pub local_dl_policy_parameters: sqlx::types::Json<LimitPolicyParameters>,
+    pub backhaul_ul_policy_kind: i32,
+    pub backhaul_ul_policy_parameters: sqlx::types::Json<LimitPolicyParameters>,
+    pub backhaul_dl_policy_kind: i32,
+    pub backhaul_dl_policy_parameters: sqlx::types::Json<LimitPolicyParameters>,
+    pub dscp: Option<i16>,
+    pub dns_redirect: Option<ipnetwork::IpNetwork>,
+}
+
+fn parse_network(ip: &str) -> Result<ipnetwork::IpNetwork, StorageError> {
+    ip.parse()
+        .map_err(|_| StorageError::InvalidAddress(ip.to_string()))
+}
+
+// An access policy row as stored by backends without address types, with
+// addresses as text in CIDR notation.
+#[derive(Debug, Clone, sqlx::FromRow)]
+struct AccessRow {
+    ip: String,
+    subscriber_id: i32,
+    policy_id: i32,
+    local_ul_policy_kind: i32,
+    local_ul_policy_parameters: sqlx::types::Json<LimitPolicyParameters>,
+    local_dl_policy_kind: i32,
+    local_dl_policy_parameters: sqlx::types::Json<LimitPolicyParameters>,
+    backhaul_ul_policy_kind: i32,
+    backhaul_ul_policy_parameters: sqlx::types::Json<LimitPolicyParameters>,
+    backhaul_dl_policy_kind: i32,
+    backhaul_dl_policy_parameters: sqlx::types::Json<LimitPolicyParameters>,
+    dscp: Option<i16>,
+    dns_redirect: Option<String>,
+}
+
+impl TryFrom<AccessRow> for AccessPolicyRow {
+    type Error = StorageError;
+
+    fn try_from(row: AccessRow) -> Result<Self, Self::Error> {
+        Ok(AccessPolicyRow {
+            ip: parse_network(&row.ip)?,
+            subscriber_id: row.subscriber_id,
+            policy_id: row.policy_id,
+            local_ul_policy_kind: row.local_ul_policy_kind,
+            local_ul_policy_parameters: row.local_ul_policy_parameters,
+            local_dl_policy_kind: row.local_dl_policy_kind,
+            local_dl_policy_parameters: row.local_dl_policy_parameters,
+            backhaul_ul_policy_kind: row.backhaul_ul_policy_kind,
+            backhaul_ul_policy_parameters: row.backhaul_ul_policy_parameters,
+            backhaul_dl_policy_kind: row.backhaul_dl_policy_kind,
+            backhaul_dl_policy_parameters: row.backhaul_dl_policy_parameters,
+            dscp: row.dscp,
+            dns_redirect: row.dns_redirect.as_deref().map(parse_network).transpose()?,
+        })
+    }
+}
+
+// The queries of metering, accounting, and enforcement, so that the core of
+// haulage can run against a database other than Postgres, e.g. a local SQLite
+// file on a single small box or an existing MySQL server. The remaining
+// subsystems and commands use Postgres directly.
+#[async_trait]
+pub trait Storage: std::fmt::Debug + Send + Sync {
+    // The subscribers assigned the address or a prefix containing it, since
+    // IPv6 subscribers may be assigned an entire prefix rather than a single
+    // address.
+    async fn subscribers_by_address(
+        &self,
+        ip: std::net::IpAddr,
+    ) -> Result<Vec<SubscriberBalance>, StorageError>;
+
+    // The report interval of the subscriber, or of their policy, overriding
+    // the configured interval.
+    async fn report_interval(
+        &self,
+        subscriber: i32,
+    ) -> Result<Option<std::time::Duration>, StorageError>;
+
+    // The address of each subscriber, with leased addresses taking precedence
+    // over static addresses.
+    async fn address_assignments(&self)
+        -> Result<HashMap<i32, ipnetwork::IpNetwork>, StorageError>;
+
+    async fn subscriber_addresses(
+        &self,
+        subscriber: i32,
+    ) -> Result<Vec<ipnetwork::IpNetwork>, StorageError>;
+
+    // Creates or updates the access policies, keyed by name, returning the id
+    // of each.
+    async fn sync_policies(
+        &self,
+        templates: &[crate::policies::PolicyTemplate],
+    ) -> Result<Vec<i32>, StorageError>;
+
+    // Writes the batch, returning the number of usage records written and the
+    // balance of each debited subscriber found.
+    async fn write_usage(
+        &self,
+        batch: UsageBatch<'_>,
+    ) -> Result<(u64, HashMap<i32, DebitResult>), StorageError>;
+
+    async fn record_event(
+        &self,
+        subscriber: i32,
+        kind: crate::events::EventKind,
+        details: serde_json::Value,
+    ) -> Result<(), StorageError>;
+
+    // Records the first time traffic is seen from a subscriber, which only
+    // happens once over the lifetime of the subscriber regardless of restarts.
+    async fn record_first_seen(
+        &self,
+        subscriber: i32,
+        ip: std::net::IpAddr,
+    ) -> Result<(), StorageError>;
+
+    // The policy for the balance condition of every subscriber. Suspended and
+    // exempt subscribers have their suspended and exempt policies, which take
+    // precedence over any balance condition.
+    async fn all_access_state(&self) -> Result<Vec<AccessPolicyRow>, StorageError>;
+
+    // As all_access_state, but only the subscribers whose policy differs from
+    // the policy last applied.
+    async fn modified_access_state(&self) -> Result<Vec<AccessPolicyRow>, StorageError>;
+
+    // The policy a subscriber should currently have, whether or not it is
+    // already their current policy.
+    async fn subscriber_access_state(
+        &self,
+        imsi: &str,
+    ) -> Result<Vec<AccessPolicyRow>, StorageError>;
+
+    async fn subscriber_access_policy(
+        &self,
+        subscriber: i32,
+        condition: crate::enforcer::SubscriberCondition,
+    ) -> Result<Vec<AccessPolicyRow>, StorageError>;
+
+    // The policy last applied to each zero balance subscriber whose zero
+    // balance policy was not yet in place. Suspended and exempt subscribers
+    // are left out.
+    async fn exhausted_previous_access_state(&self) -> Result<Vec<AccessPolicyRow>, StorageError>;
+
+    // Notes the policy applied to a subscriber, recording an event if it
+    // changed, and returns the previously applied policy.
+    async fn update_current_policy(
+        &self,
+        subscriber: i32,
+        policy: i32,
+    ) -> Result<AccessPolicyRow, StorageError>;
+
+    // Listens for notifications on the channel. Backends without
+    // notifications return None, and are only polled.
+    async fn listen(
+        &self,
+        _channel: &str,
+    ) -> Result<Option<sqlx::postgres::PgListener>, StorageError> {
+        Ok(None)
+    }
+}
@@ -0,0 +1,603 @@
+use std::collections::{HashMap, HashSet};
+
+use async_trait::async_trait;
+use sqlx::migrate::Migrate;
+
+use super::{
+    parse_network, AccessPolicyRow, AccessRow, StorageError, SubscriberBalance, UsageBatch,
+};
+use crate::usage_writer::DebitResult;
+
+// A MySQL database, e.g. for operators who already run MySQL rather than
+// Postgres. As with SQLite, addresses are stored as text and matched in
+// haulage rather than in the queries.
+#[derive(Debug, Clone)]
+pub struct Mysql {
+    db_pool: sqlx::MySqlPool,
+}
+impl Mysql {
+    // Connects to the database, applying any migrations it is missing if
+    // auto_upgrade is set, and otherwise refusing to run against an outdated
+    // schema.
+    pub async fn connect(
+        options: &sqlx::mysql::MySqlConnectOptions,
+        migration_directory: &std::path::Path,
+        auto_upgrade: bool,
+    ) -> Result<Mysql, StorageError> {
+        let db_pool = sqlx::mysql::MySqlPoolOptions::new()
+            .connect_with(options.clone())
+            .await?;
+        let migrator = sqlx::migrate::Migrator::new(migration_directory).await?;
+        if auto_upgrade {
+            migrator.run(&db_pool).await?;
+            return Ok(Mysql { db_pool });
+        }
+
+        // The migrations table does not exist until the first migration.
+        let applied: HashSet<i64> = db_pool
+            .acquire()
+            .await?
+            .list_applied_migrations()
+            .await
+            .unwrap_or_default()
+            .iter()
+            .map(|migration| migration.version)
+            .collect();
+        if migrator
+            .iter()
+            .any(|migration| !applied.contains(&migration.version))
+        {
+            return Err(StorageError::UnappliedMigrations);
+        }
+        Ok(Mysql { db_pool })
+    }
+}
+
+#[async_trait]
+impl super::Storage for Mysql {
+    async fn subscribers_by_address(
+        &self,
+        ip: std::net::IpAddr,
+    ) -> Result<Vec<SubscriberBalance>, StorageError> {
+        let balance_state_query = r#"
+            SELECT `internal_uid`, COALESCE(balance_pools.`data_balance`, subscribers.`data_balance`), `ip`
+            FROM subscribers
+            INNER JOIN static_ips ON static_ips.imsi = subscribers.imsi
+            LEFT JOIN balance_pools ON balance_pools.id = subscribers.balance_pool
+        "#;
+
+        let rows: Vec<(i32, i64, String)> = sqlx::query_as(balance_state_query)
+            .fetch_all(&self.db_pool)
+            .await?;
+        let mut balances = Vec::new();
+        for (subscriber_id, data_balance, assigned) in rows {
+            if parse_network(&assigned)?.contains(ip) {
+                balances.push(SubscriberBalance {
+                    subscriber_id,
+                    data_balance,
+                });
+            }
+        }
+        Ok(balances)
+    }
+
+    async fn report_interval(
+        &self,
+        subscriber: i32,
+    ) -> Result<Option<std::time::Duration>, StorageError> {
+        let interval_query = r#"
+            SELECT COALESCE(subscribers.`report_interval`, access_policies.`report_interval`)
+            FROM subscribers
+            INNER JOIN access_policies ON access_policies.id = subscribers.positive_balance_policy
+            WHERE subscribers.`internal_uid` = ?
+        "#;
+
+        let interval: Option<(Option<i64>,)> = sqlx::query_as(interval_query)
+            .bind(subscriber)
+            .fetch_optional(&self.db_pool)
+            .await?;
+        Ok(interval
+            .and_then(|(interval,)| interval)
+            .map(|seconds| std::time::Duration::from_secs(seconds.max(0) as u64)))
+    }
+
+    async fn address_assignments(
+        &self,
+    ) -> Result<HashMap<i32, ipnetwork::IpNetwork>, StorageError> {
+        let assignment_query = r#"
+            SELECT `internal_uid`, `ip`
+            FROM subscribers
+            INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
+            ORDER BY static_ips.`leased`
+        "#;
+
+        let rows: Vec<(i32, String)> = sqlx::query_as(assignment_query)
+            .fetch_all(&self.db_pool)
+            .await?;
+        rows.iter()
+            .map(|(subscriber, ip)| Ok((*subscriber, parse_network(ip)?)))
+            .collect()
+    }
+
+    async fn subscriber_addresses(
+        &self,
+        subscriber: i32,
+    ) -> Result<Vec<ipnetwork::IpNetwork>, StorageError> {
+        let ip_query = r#"
+            SELECT `ip`
+            FROM subscribers
+            INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
+            WHERE subscribers.internal_uid = ?
+        "#;
+
+        let rows: Vec<(String,)> = sqlx::query_as(ip_query)
+            .bind(subscriber)
+            .fetch_all(&self.db_pool)
+            .await?;
+        rows.iter().map(|(ip,)| parse_network(ip)).collect()
+    }
+
+    async fn sync_policies(
+        &self,
+        templates: &[crate::policies::PolicyTemplate],
+    ) -> Result<Vec<i32>, StorageError> {
+        let mut transaction = self.db_pool.begin().await?;
+
+        // MySQL has no RETURNING, so the id of an updated policy is passed
+        // back through LAST_INSERT_ID.
+        let upsert_query = r#"
+            INSERT INTO access_policies(`name`, `local_ul_policy_kind`, `local_ul_policy_parameters`, `local_dl_policy_kind`, `local_dl_policy_parameters`, `backhaul_ul_policy_kind`, `backhaul_ul_policy_parameters`, `backhaul_dl_policy_kind`, `backhaul_dl_policy_parameters`, `dscp`, `guaranteed_kibps`, `dns_redirect`)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ON DUPLICATE KEY UPDATE
+                `id` = LAST_INSERT_ID(`id`),
+                `local_ul_policy_kind` = VALUES(`local_ul_policy_kind`),
+                `local_ul_policy_parameters` = VALUES(`local_ul_policy_parameters`),
+                `local_dl_policy_kind` = VALUES(`local_dl_policy_kind`),
+                `local_dl_policy_parameters` = VALUES(`local_dl_policy_parameters`),
+                `backhaul_ul_policy_kind` = VALUES(`backhaul_ul_policy_kind`),
+                `backhaul_ul_policy_parameters` = VALUES(`backhaul_ul_policy_parameters`),
+                `backhaul_dl_policy_kind` = VALUES(`backhaul_dl_policy_kind`),
+                `backhaul_dl_policy_parameters` = VALUES(`backhaul_dl_policy_parameters`),
+                `dscp` = VALUES(`dscp`),
+                `guaranteed_kibps` = VALUES(`guaranteed_kibps`),
+                `dns_redirect` = VALUES(`dns_redirect`)
+        "#;
+
+        let mut ids = Vec::new();
+        for template in templates {
+            let id = sqlx::query(upsert_query)
+                .bind(&template.name)
+                .bind(template.local_ul.kind_id())
+                .bind(template.local_ul.parameters().to_string())
+                .bind(template.local_dl.kind_id())
+                .bind(template.local_dl.parameters().to_string())
+                .bind(template.backhaul_ul.kind_id())
+                .bind(template.backhaul_ul.parameters().to_string())
+                .bind(template.backhaul_dl.kind_id())
+                .bind(template.backhaul_dl.parameters().to_string())
+                .bind(template.dscp.map(|dscp| dscp as i16))
+                .bind(template.guaranteed_kibps)
+                .bind(
+                    template
+                        .dns_redirect
+                        .map(|ip| ipnetwork::IpNetwork::from(ip).to_string()),
+                )
+                .execute(&mut transaction)
+                .await?
+                .last_insert_id();
+            ids.push(id as i32);
+        }
+
+        transaction.commit().await?;
+        Ok(ids)
+    }
+
+    async fn write_usage(
+        &self,
+        batch: UsageBatch<'_>,
+    ) -> Result<(u64, HashMap<i32, DebitResult>), StorageError> {
+        let mut transaction = self.db_pool.begin().await?;
+
+        let usage_query = r#"
+            INSERT INTO subscriber_usage(`subscriber`, `start_time`, `end_time`, `ran_bytes_up`, `ran_bytes_down`, `wan_bytes_up`, `wan_bytes_down`, `tcp_bytes`, `udp_bytes`, `icmp_bytes`, `other_bytes`)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        "#;
+        let service_query = r#"
+            INSERT INTO subscriber_service_usage(`subscriber`, `start_time`, `https_bytes`, `http_bytes`, `dns_bytes`, `quic_bytes`, `voip_bytes`, `other_bytes`)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        "#;
+        let mut rows = 0;
+        for (subscriber, record) in batch.records {
+            let usage = &record.usage;
+            rows += sqlx::query(usage_query)
+                .bind(subscriber)
+                .bind(record.start)
+                .bind(record.end)
+                .bind(usage.ran_bytes_up)
+                .bind(usage.ran_bytes_down)
+                .bind(usage.wan_bytes_up)
+                .bind(usage.wan_bytes_down)
+                .bind(usage.tcp_bytes)
+                .bind(usage.udp_bytes)
+                .bind(usage.icmp_bytes)
+                .bind(usage.other_bytes)
+                .execute(&mut transaction)
+                .await?
+                .rows_affected();
+
+            let services = &usage.services;
+            if *services == crate::ServiceBytes::default() {
+                continue;
+            }
+            sqlx::query(service_query)
+                .bind(subscriber)
+                .bind(record.start)
+                .bind(services.https_bytes)
+                .bind(services.http_bytes)
+                .bind(services.dns_bytes)
+                .bind(services.quic_bytes)
+                .bind(services.voip_bytes)
+                .bind(services.other_bytes)
+                .execute(&mut transaction)
+                .await?;
+        }
+
+        let balances = debit_balances(&mut transaction, batch.debits).await?;
+        if !batch.destinations.is_empty() {
+            write_top_destinations(
+                &mut transaction,
+                batch.destinations,
+                batch.destination_count,
+            )
+            .await?;
+        }
+        transaction.commit().await?;
+        Ok((rows, balances))
+    }
+
+    async fn record_event(
+        &self,
+        subscriber: i32,
+        kind: crate::events::EventKind,
+        details: serde_json::Value,
+    ) -> Result<(), StorageError> {
+        let mut connection = self.db_pool.acquire().await?;
+        insert_event(&mut connection, subscriber, kind, details).await?;
+        Ok(())
+    }
+
+    async fn record_first_seen(
+        &self,
+        subscriber: i32,
+        ip: std::net::IpAddr,
+    ) -> Result<(), StorageError> {
+        let mut transaction = self.db_pool.begin().await?;
+
+        let seen_query = r#"
+            SELECT EXISTS (
+                SELECT 1 FROM subscriber_events WHERE `subscriber` = ? AND `kind` = ?
+            )
+        "#;
+        let (seen,): (bool,) = sqlx::query_as(seen_query)
+            .bind(subscriber)
+            .bind(crate::events::EventKind::FirstSeen.as_str())
+            .fetch_one(&mut transaction)
+            .await?;
+
+        if !seen {
+            insert_event(
+                &mut transaction,
+                subscriber,
+                crate::events::EventKind::FirstSeen,
+                serde_json::json!({ "ip": ip }),
+            )
+            .await?;
+        }
+
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn all_access_state(&self) -> Result<Vec<AccessPolicyRow>, StorageError> {
+        let mut transaction = self.db_pool.begin().await?;
+
+        // Zero balance subscribers
+        let ratelimit_state_query = r#"
+            SELECT `internal_uid` AS `subscriber_id`, access_policies.`id` AS `policy_id`, `ip`, `local_ul_policy_kind`, `local_ul_policy_parameters`, `local_dl_policy_kind`, `local_dl_policy_parameters`, `backhaul_ul_policy_kind`, `backhaul_ul_policy_parameters`, `backhaul_dl_policy_kind`, `backhaul_dl_policy_parameters`, access_policies.`dscp`, access_policies.`dns_redirect`
+            FROM subscribers
+            LEFT JOIN balance_pools ON balance_pools.id = subscribers.balance_pool
+            INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
+            INNER JOIN access_policies ON access_policies.id = (CASE WHEN subscribers.suspended THEN subscribers.suspended_policy WHEN subscribers.exempt_until > UTC_TIMESTAMP() THEN subscribers.exempt_policy ELSE subscribers.zero_balance_policy END)
+            WHERE (COALESCE(balance_pools.data_balance, subscribers.data_balance) = 0)
+        "#;
+
+        let mut rows: Vec<AccessRow> = sqlx::query_as(ratelimit_state_query)
+            .fetch_all(&mut transaction)
+            .await?;
+
+        // Positive balance subscribers
+        let ratelimit_state_query = r#"
+            SELECT `internal_uid` AS `subscriber_id`, access_policies.`id` AS `policy_id`, `ip`, `local_ul_policy_kind`, `local_ul_policy_parameters`, `local_dl_policy_kind`, `local_dl_policy_parameters`, `backhaul_ul_policy_kind`, `backhaul_ul_policy_parameters`, `backhaul_dl_policy_kind`, `backhaul_dl_policy_parameters`, access_policies.`dscp`, access_policies.`dns_redirect`
+            FROM subscribers
+            LEFT JOIN balance_pools ON balance_pools.id = subscribers.balance_pool
+            INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
+            INNER JOIN access_policies ON access_policies.id = (CASE WHEN subscribers.suspended THEN subscribers.suspended_policy WHEN subscribers.exempt_until > UTC_TIMESTAMP() THEN subscribers.exempt_policy ELSE COALESCE(subscribers.deprioritized_policy, subscribers.positive_balance_policy) END)
+            WHERE (COALESCE(balance_pools.data_balance, subscribers.data_balance) > 0)
+        "#;
+
+        let positive_balance_rows: Vec<AccessRow> = sqlx::query_as(ratelimit_state_query)
+            .fetch_all(&mut transaction)
+            .await?;
+
+        transaction.commit().await?;
+        rows.extend(positive_balance_rows);
+        rows.into_iter().map(AccessPolicyRow::try_from).collect()
+    }
+
+    async fn modified_access_state(&self) -> Result<Vec<AccessPolicyRow>, StorageError> {
+        let mut transaction = self.db_pool.begin().await?;
+
+        // Zero balance subscribers
+        let ratelimit_state_updated_query = r#"
+            SELECT `internal_uid` AS `subscriber_id`, access_policies.`id` AS `policy_id`, `ip`, `local_ul_policy_kind`, `local_ul_policy_parameters`, `local_dl_policy_kind`, `local_dl_policy_parameters`, `backhaul_ul_policy_kind`, `backhaul_ul_policy_parameters`, `backhaul_dl_policy_kind`, `backhaul_dl_policy_parameters`, access_policies.`dscp`, access_policies.`dns_redirect`
+            FROM subscribers
+            LEFT JOIN balance_pools ON balance_pools.id = subscribers.balance_pool
+            INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
+            INNER JOIN access_policies ON access_policies.id = (CASE WHEN subscribers.suspended THEN subscribers.suspended_policy WHEN subscribers.exempt_until > UTC_TIMESTAMP() THEN subscribers.exempt_policy ELSE subscribers.zero_balance_policy END)
+            WHERE (COALESCE(balance_pools.data_balance, subscribers.data_balance) = 0) AND (access_policies.id != subscribers.current_policy)
+        "#;
+
+        let mut rows: Vec<AccessRow> = sqlx::query_as(ratelimit_state_updated_query)
+            .fetch_all(&mut transaction)
+            .await?;
+
+        // Positive balance subscribers
+        let ratelimit_state_updated_query = r#"
+            SELECT `internal_uid` AS `subscriber_id`, access_policies.`id` AS `policy_id`, `ip`, `local_ul_policy_kind`, `local_ul_policy_parameters`, `local_dl_policy_kind`, `local_dl_policy_parameters`, `backhaul_ul_policy_kind`, `backhaul_ul_policy_parameters`, `backhaul_dl_policy_kind`, `backhaul_dl_policy_parameters`, access_policies.`dscp`, access_policies.`dns_redirect`
+            FROM subscribers
+            LEFT JOIN balance_pools ON balance_pools.id = subscribers.balance_pool
+            INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
+            INNER JOIN access_policies ON access_policies.id = (CASE WHEN subscribers.suspended THEN subscribers.suspended_policy WHEN subscribers.exempt_until > UTC_TIMESTAMP() THEN subscribers.exempt_policy ELSE COALESCE(subscribers.deprioritized_policy, subscribers.positive_balance_policy) END)
+            WHERE (COALESCE(balance_pools.data_balance, subscribers.data_balance) > 0) AND (access_policies.id != subscribers.current_policy)
+        "#;
+
+        let positive_balance_rows: Vec<AccessRow> = sqlx::query_as(ratelimit_state_updated_query)
+            .fetch_all(&mut transaction)
+            .await?;
+
+        transaction.commit().await?;
+        rows.extend(positive_balance_rows);
+        rows.into_iter().map(AccessPolicyRow::try_from).collect()
+    }
+
+    async fn subscriber_access_state(
+        &self,
+        imsi: &str,
+    ) -> Result<Vec<AccessPolicyRow>, StorageError> {
+        let access_state_query = r#"
+            SELECT `internal_uid` AS `subscriber_id`, access_policies.`id` AS `policy_id`, `ip`, `local_ul_policy_kind`, `local_ul_policy_parameters`, `local_dl_policy_kind`, `local_dl_policy_parameters`, `backhaul_ul_policy_kind`, `backhaul_ul_policy_parameters`, `backhaul_dl_policy_kind`, `backhaul_dl_policy_parameters`, access_policies.`dscp`, access_policies.`dns_redirect`
+            FROM subscribers
+            LEFT JOIN balance_pools ON balance_pools.id = subscribers.balance_pool
+            INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
+            INNER JOIN access_policies ON access_policies.id = (CASE WHEN subscribers.suspended THEN subscribers.suspended_policy WHEN subscribers.exempt_until > UTC_TIMESTAMP() THEN subscribers.exempt_policy WHEN COALESCE(balance_pools.data_balance, subscribers.data_balance) > 0 THEN COALESCE(subscribers.deprioritized_policy, subscribers.positive_balance_policy) WHEN COALESCE(balance_pools.data_balance, subscribers.data_balance) = 0 THEN subscribers.zero_balance_policy END)
+            WHERE subscribers.imsi = ?
+        "#;
+
+        let rows: Vec<AccessRow> = sqlx::query_as(access_state_query)
+            .bind(imsi)
+            .fetch_all(&self.db_pool)
+            .await?;
+        rows.into_iter().map(AccessPolicyRow::try_from).collect()
+    }
+
+    async fn subscriber_access_policy(
+        &self,
+        subscriber: i32,
+        condition: crate::enforcer::SubscriberCondition,
+    ) -> Result<Vec<AccessPolicyRow>, StorageError> {
+        let ratelimit_state_query = match condition {
+            crate::enforcer::SubscriberCondition::_PositiveBalance => {
+                r#"
+                    SELECT `internal_uid` AS `subscriber_id`, access_policies.`id` AS `policy_id`, `ip`, `local_ul_policy_kind`, `local_ul_policy_parameters`, `local_dl_policy_kind`, `local_dl_policy_parameters`, `backhaul_ul_policy_kind`, `backhaul_ul_policy_parameters`, `backhaul_dl_policy_kind`, `backhaul_dl_policy_parameters`, access_policies.`dscp`, access_policies.`dns_redirect`
+                    FROM subscribers
+                    INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
+                    INNER JOIN access_policies ON access_policies.id = (CASE WHEN subscribers.suspended THEN subscribers.suspended_policy WHEN subscribers.exempt_until > UTC_TIMESTAMP() THEN subscribers.exempt_policy ELSE COALESCE(subscribers.deprioritized_policy, subscribers.positive_balance_policy) END)
+                    WHERE (internal_uid = ?)
+                "#
+            }
+            crate::enforcer::SubscriberCondition::NoBalance => {
+                r#"
+                    SELECT `internal_uid` AS `subscriber_id`, access_policies.`id` AS `policy_id`, `ip`, `local_ul_policy_kind`, `local_ul_policy_parameters`, `local_dl_policy_kind`, `local_dl_policy_parameters`, `backhaul_ul_policy_kind`, `backhaul_ul_policy_parameters`, `backhaul_dl_policy_kind`, `backhaul_dl_policy_parameters`, access_policies.`dscp`, access_policies.`dns_redirect`
+                    FROM subscribers
+                    INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
+                    INNER JOIN access_policies ON access_policies.id = (CASE WHEN subscribers.suspended THEN subscribers.suspended_policy WHEN subscribers.exempt_until > UTC_TIMESTAMP() THEN subscribers.exempt_policy ELSE subscribers.zero_balance_policy END)
+                    WHERE (internal_uid = ?)
+                "#
+            }
+        };
+
+        let rows: Vec<AccessRow> = sqlx::query_as(ratelimit_state_query)
+            .bind(subscriber)
+            .fetch_all(&self.db_pool)
+            .await?;
+        rows.into_iter().map(AccessPolicyRow::try_from).collect()
+    }
+
+    async fn exhausted_previous_access_state(&self) -> Result<Vec<AccessPolicyRow>, StorageError> {
+        let previous_state_query = r#"
+            SELECT `internal_uid` AS `subscriber_id`, access_policies.`id` AS `policy_id`, `ip`, `local_ul_policy_kind`, `local_ul_policy_parameters`, `local_dl_policy_kind`, `local_dl_policy_parameters`, `backhaul_ul_policy_kind`, `backhaul_ul_policy_parameters`, `backhaul_dl_policy_kind`, `backhaul_dl_policy_parameters`, access_policies.`dscp`, access_policies.`dns_redirect`
+            FROM subscribers
+            LEFT JOIN balance_pools ON balance_pools.id = subscribers.balance_pool
+            INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
+            INNER JOIN access_policies ON access_policies.id = subscribers.current_policy
+            WHERE (COALESCE(balance_pools.data_balance, subscribers.data_balance) = 0) AND NOT subscribers.suspended AND NOT COALESCE(subscribers.exempt_until > UTC_TIMESTAMP(), FALSE) AND subscribers.zero_balance_policy != subscribers.current_policy
+        "#;
+
+        let rows: Vec<AccessRow> = sqlx::query_as(previous_state_query)
+            .fetch_all(&self.db_pool)
+            .await?;
+        rows.into_iter().map(AccessPolicyRow::try_from).collect()
+    }
+
+    async fn update_current_policy(
+        &self,
+        subscriber: i32,
+        policy: i32,
+    ) -> Result<AccessPolicyRow, StorageError> {
+        let mut transaction = self.db_pool.begin().await?;
+
+        let previous_policy_query = r#"
+            SELECT `internal_uid` AS `subscriber_id`, access_policies.`id` AS `policy_id`, `ip`, `local_ul_policy_kind`, `local_ul_policy_parameters`, `local_dl_policy_kind`, `local_dl_policy_parameters`, `backhaul_ul_policy_kind`, `backhaul_ul_policy_parameters`, `backhaul_dl_policy_kind`, `backhaul_dl_policy_parameters`, access_policies.`dscp`, access_policies.`dns_redirect`
+            FROM subscribers
+            INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
+            INNER JOIN access_policies ON access_policies.id = subscribers.current_policy
+            WHERE subscribers.`internal_uid` = ?
+            FOR UPDATE
+        "#;
+        let previous: AccessRow = sqlx::query_as(previous_policy_query)
+            .bind(subscriber)
+            .fetch_one(&mut transaction)
+            .await?;
+
+        sqlx::query(r#"UPDATE subscribers SET `current_policy` = ? WHERE `internal_uid` = ?"#)
+            .bind(policy)
+            .bind(subscriber)
+            .execute(&mut transaction)
+            .await?;
+
+        if previous.policy_id != policy {
+            insert_event(
+                &mut transaction,
+                subscriber,
+                crate::events::EventKind::PolicyChanged,
+                serde_json::json!({ "previous_policy": previous.policy_id, "policy": policy }),
+            )
+            .await?;
+        }
+
+        transaction.commit().await?;
+        previous.try_into()
+    }
+}
+
+// As record_event, on the given connection so that the event can be recorded
+// in the same transaction as the change it describes.
+async fn insert_event(
+    connection: &mut sqlx::MySqlConnection,
+    subscriber: i32,
+    kind: crate::events::EventKind,
+    details: serde_json::Value,
+) -> Result<(), sqlx::Error> {
+    let insert_query = r#"
+        INSERT INTO subscriber_events(`subscriber`, `imsi`, `kind`, `details`)
+        SELECT `internal_uid`, `imsi`, ?, ?
+        FROM subscribers
+        WHERE `internal_uid` = ?
+    "#;
+
+    sqlx::query(insert_query)
+        .bind(kind.as_str())
+        .bind(details.to_string())
+        .bind(subscriber)
+        .execute(connection)
+        .await?;
+    Ok(())
+}
+
+// Debits each subscriber in turn, from their pool if they draw from one, with
+// the same flooring and exemption as the Postgres debit.
+async fn debit_balances(
+    connection: &mut sqlx::MySqlConnection,
+    debits: &HashMap<i32, i64>,
+) -> Result<HashMap<i32, DebitResult>, sqlx::Error> {
+    // MySQL cannot update a table joined in its own subquery, so the pool is
+    // joined directly.
+    let pool_debit_query = r#"
+        UPDATE balance_pools
+        INNER JOIN subscribers ON subscribers.`balance_pool` = balance_pools.`id`
+        SET balance_pools.`data_balance` = GREATEST(balance_pools.`data_balance` - ?, 0)
+        WHERE subscribers.`internal_uid` = ? AND NOT COALESCE(subscribers.`exempt_until` > UTC_TIMESTAMP(), FALSE)
+    "#;
+    let subscriber_debit_query = r#"
+        UPDATE subscribers
+        SET `data_balance` = GREATEST(`data_balance` - ?, 0)
+        WHERE `internal_uid` = ? AND `balance_pool` IS NULL AND NOT COALESCE(`exempt_until` > UTC_TIMESTAMP(), FALSE)
+    "#;
+    let balance_query = r#"
+        SELECT COALESCE(balance_pools.`data_balance`, subscribers.`data_balance`), COALESCE(subscribers.`exempt_until` > UTC_TIMESTAMP(), FALSE)
+        FROM subscribers
+        LEFT JOIN balance_pools ON balance_pools.`id` = subscribers.`balance_pool`
+        WHERE subscribers.`internal_uid` = ?
+    "#;
+
+    let mut balances = HashMap::new();
+    for (subscriber, amount) in debits {
+        for debit_query in [pool_debit_query, subscriber_debit_query] {
+            sqlx::query(debit_query)
+                .bind(amount)
+                .bind(subscriber)
+                .execute(&mut *connection)
+                .await?;
+        }
+        let balance: Option<(i64, bool)> = sqlx::query_as(balance_query)
+            .bind(subscriber)
+            .fetch_optional(&mut *connection)
+            .await?;
+        if let Some((balance, exempt)) = balance {
+            balances.insert(*subscriber, DebitResult { balance, exempt });
+        }
+    }
+    Ok(balances)
+}
+
+// Writes the top destinations of the batch, then trims each interval written
+// to the top destinations overall.
+async fn write_top_destinations(
+    connection: &mut sqlx::MySqlConnection,
+    top: &[&(std::net::IpAddr, crate::reporter::UseRecord)],
+    count: usize,
+) -> Result<(), sqlx::Error> {
+    let insert_query = r#"
+        INSERT INTO destination_usage(`destination`, `start_time`, `end_time`, `bytes_up`, `bytes_down`)
+        VALUES (?, ?, ?, ?, ?)
+    "#;
+    for (destination, record) in top {
+        sqlx::query(insert_query)
+            .bind(destination.to_string())
+            .bind(record.start)
+            .bind(record.end)
+            .bind(record.usage.wan_bytes_up)
+            .bind(record.usage.wan_bytes_down)
+            .execute(&mut *connection)
+            .await?;
+    }
+
+    let mut intervals: Vec<chrono::DateTime<chrono::Utc>> =
+        top.iter().map(|(_, record)| record.end).collect();
+    intervals.sort_unstable();
+    intervals.dedup();
+    // MySQL cannot limit a subquery of the table being deleted from, so the
+    // smallest destinations beyond the count are deleted directly.
+    let count_query = r#"SELECT COUNT(*) FROM destination_usage WHERE `end_time` = ?"#;
+    let trim_query = r#"
+        DELETE FROM destination_usage
+        WHERE `end_time` = ?
+        ORDER BY `bytes_up` + `bytes_down`
+        LIMIT ?
+    "#;
+    for interval in intervals {
+        let (written,): (i64,) = sqlx::query_as(count_query)
+            .bind(interval)
+            .fetch_one(&mut *connection)
+            .await?;
+        let excess = written - count as i64;
+        if excess > 0 {
+            sqlx::query(trim_query)
+                .bind(interval)
+                .bind(excess)
+                .execute(&mut *connection)
+                .await?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,662 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use super::{AccessPolicyRow, StorageError, SubscriberBalance, UsageBatch};
+use crate::reporter::UseRecord;
+use crate::usage_writer::DebitResult;
+
+const COPY_STATEMENT: &str = r#"
+    COPY subscriber_usage("subscriber", "start_time", "end_time", "ran_bytes_up", "ran_bytes_down", "wan_bytes_up", "wan_bytes_down", "tcp_bytes", "udp_bytes", "icmp_bytes", "other_bytes")
+    FROM STDIN
+"#;
+
+const SERVICE_COPY_STATEMENT: &str = r#"
+    COPY subscriber_service_usage("subscriber", "start_time", "https_bytes", "http_bytes", "dns_bytes", "quic_bytes", "voip_bytes", "other_bytes")
+    FROM STDIN
+"#;
+
+const DESTINATION_COPY_STATEMENT: &str = r#"
+    COPY destination_usage("destination", "start_time", "end_time", "bytes_up", "bytes_down")
+    FROM STDIN
+"#;
+
+#[derive(Debug, Clone)]
+pub struct Postgres {
+    db_pool: Arc<sqlx::PgPool>,
+}
+impl Postgres {
+    pub fn new(db_pool: Arc<sqlx::PgPool>) -> Postgres {
+        Postgres { db_pool }
+    }
+}
+
+#[async_trait]
+impl super::Storage for Postgres {
+    async fn subscribers_by_address(
+        &self,
+        ip: std::net::IpAddr,
+    ) -> Result<Vec<SubscriberBalance>, StorageError> {
+        // Subscribers drawing from a pool are limited by the pool's balance.
+        let balance_state_query = r#"
+            SELECT "internal_uid" AS "subscriber_id", COALESCE(balance_pools."data_balance", subscribers."data_balance") AS "data_balance"
+            FROM subscribers
+            INNER JOIN static_ips ON static_ips.imsi = subscribers.imsi
+            LEFT JOIN balance_pools ON balance_pools.id = subscribers.balance_pool
+            WHERE static_ips.ip >>= $1
+        "#;
+
+        Ok(sqlx::query_as(balance_state_query)
+            .bind(ipnetwork::IpNetwork::from(ip))
+            .fetch_all(&*self.db_pool)
+            .await?)
+    }
+
+    async fn report_interval(
+        &self,
+        subscriber: i32,
+    ) -> Result<Option<std::time::Duration>, StorageError> {
+        let interval_query = r#"
+            SELECT COALESCE(subscribers."report_interval", access_policies."report_interval")
+            FROM subscribers
+            INNER JOIN access_policies ON access_policies.id = subscribers.positive_balance_policy
+            WHERE subscribers."internal_uid" = $1
+        "#;
+
+        let interval: Option<(Option<sqlx::postgres::types::PgInterval>,)> =
+            sqlx::query_as(interval_query)
+                .bind(subscriber)
+                .fetch_optional(&*self.db_pool)
+                .await?;
+        Ok(interval
+            .and_then(|(interval,)| interval)
+            .map(interval_duration))
+    }
+
+    async fn address_assignments(
+        &self,
+    ) -> Result<HashMap<i32, ipnetwork::IpNetwork>, StorageError> {
+        // Leased addresses are ordered last so that they take precedence over
+        // any static address of the same subscriber.
+        let assignment_query = r#"
+            SELECT "internal_uid" AS "subscriber_id", "ip"
+            FROM subscribers
+            INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
+            ORDER BY static_ips."leased"
+        "#;
+
+        let rows: Vec<(i32, ipnetwork::IpNetwork)> = sqlx::query_as(assignment_query)
+            .fetch_all(&*self.db_pool)
+            .await?;
+        Ok(rows.into_iter().collect())
+    }
+
+    async fn subscriber_addresses(
+        &self,
+        subscriber: i32,
+    ) -> Result<Vec<ipnetwork::IpNetwork>, StorageError> {
+        let ip_query = r#"
+            SELECT "ip"
+            FROM subscribers
+            INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
+            WHERE (subscribers.internal_uid = $1)
+        "#;
+
+        let rows: Vec<(ipnetwork::IpNetwork,)> = sqlx::query_as(ip_query)
+            .bind(subscriber)
+            .fetch_all(&*self.db_pool)
+            .await?;
+        Ok(rows.into_iter().map(|(ip,)| ip).collect())
+    }
+
+    async fn sync_policies(
+        &self,
+        templates: &[crate::policies::PolicyTemplate],
+    ) -> Result<Vec<i32>, StorageError> {
+        let mut transaction = self.db_pool.begin().await?;
+
+        let upsert_query = r#"
+            INSERT INTO access_policies("name", "local_ul_policy_kind", "local_ul_policy_parameters", "local_dl_policy_kind", "local_dl_policy_parameters", "backhaul_ul_policy_kind", "backhaul_ul_policy_parameters", "backhaul_dl_policy_kind", "backhaul_dl_policy_parameters", "dscp", "guaranteed_kibps", "dns_redirect")
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+            ON CONFLICT ("name") DO UPDATE SET
+                "local_ul_policy_kind" = EXCLUDED."local_ul_policy_kind",
+                "local_ul_policy_parameters" = EXCLUDED."local_ul_policy_parameters",
+                "local_dl_policy_kind" = EXCLUDED."local_dl_policy_kind",
+                "local_dl_policy_parameters" = EXCLUDED."local_dl_policy_parameters",
+                "backhaul_ul_policy_kind" = EXCLUDED."backhaul_ul_policy_kind",
+                "backhaul_ul_policy_parameters" = EXCLUDED."backhaul_ul_policy_parameters",
+                "backhaul_dl_policy_kind" = EXCLUDED."backhaul_dl_policy_kind",
+                "backhaul_dl_policy_parameters" = EXCLUDED."backhaul_dl_policy_parameters",
+                "dscp" = EXCLUDED."dscp",
+                "guaranteed_kibps" = EXCLUDED."guaranteed_kibps",
+                "dns_redirect" = EXCLUDED."dns_redirect"
+            RETURNING "id"
+        "#;
+
+        let mut ids = Vec::new();
+        for template in templates {
+            let (id,): (i32,) = sqlx::query_as(upsert_query)
+                .bind(&template.name)
+                .bind(template.local_ul.kind_id())
+                .bind(template.local_ul.parameters())
+                .bind(template.local_dl.kind_id())
+                .bind(template.local_dl.parameters())
+                .bind(template.backhaul_ul.kind_id())
+                .bind(template.backhaul_ul.parameters())
+                .bind(template.backhaul_dl.kind_id())
+                .bind(template.backhaul_dl.parameters())
+                .bind(template.dscp.map(|dscp| dscp as i16))
+                .bind(template.guaranteed_kibps.map(|rate| rate as i32))
+                .bind(template.dns_redirect.map(ipnetwork::IpNetwork::from))
+                .fetch_one(&mut transaction)
+                .await?;
+            ids.push(id);
+        }
+
+        transaction.commit().await?;
+        Ok(ids)
+    }
+
+    async fn write_usage(
+        &self,
+        batch: UsageBatch<'_>,
+    ) -> Result<(u64, HashMap<i32, DebitResult>), StorageError> {
+        let mut transaction = self.db_pool.begin().await?;
+        let rows = match batch.records.is_empty() {
+            true => 0,
+            false => {
+                let rows = copy_usage_records(&mut transaction, batch.records).await?;
+                copy_service_records(&mut transaction, batch.records).await?;
+                rows
+            }
+        };
+        let balances = debit_balances(&mut transaction, batch.debits).await?;
+        if !batch.destinations.is_empty() {
+            write_top_destinations(
+                &mut transaction,
+                batch.destinations,
+                batch.destination_count,
+            )
+            .await?;
+        }
+        transaction.commit().await?;
+        Ok((rows, balances))
+    }
+
+    async fn record_event(
+        &self,
+        subscriber: i32,
+        kind: crate::events::EventKind,
+        details: serde_json::Value,
+    ) -> Result<(), StorageError> {
+        let mut connection = self.db_pool.acquire().await?;
+        crate::events::record_event(&mut connection, subscriber, kind, details).await?;
+        Ok(())
+    }
+
+    async fn record_first_seen(
+        &self,
+        subscriber: i32,
+        ip: std::net::IpAddr,
+    ) -> Result<(), StorageError> {
+        let mut transaction = self.db_pool.begin().await?;
+
+        let seen_query = r#"
+            SELECT EXISTS (
+                SELECT 1 FROM subscriber_events WHERE "subscriber" = $1 AND "kind" = $2
+            )
+        "#;
+        let (seen,): (bool,) = sqlx::query_as(seen_query)
+            .bind(subscriber)
+            .bind(crate::events::EventKind::FirstSeen.as_str())
+            .fetch_one(&mut transaction)
+            .await?;
+
+        if !seen {
+            crate::events::record_event(
+                &mut transaction,
+                subscriber,
+                crate::events::EventKind::FirstSeen,
+                serde_json::json!({ "ip": ip }),
+            )
+            .await?;
+        }
+
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn all_access_state(&self) -> Result<Vec<AccessPolicyRow>, StorageError> {
+        let mut transaction = self.db_pool.begin().await?;
+
+        // Get the ratelimit state to apply for each condition of subscribers.
+        // Need to return different columns based on the subscriber's account
+        // balance, or the balance of their pool if they draw from one.
+
+        // Zero balance subscribers
+        let ratelimit_state_query = r#"
+            SELECT "internal_uid" AS "subscriber_id", access_policies."id" AS "policy_id", "ip", "local_ul_policy_kind", "local_ul_policy_parameters", "local_dl_policy_kind", "local_dl_policy_parameters", "backhaul_ul_policy_kind", "backhaul_ul_policy_parameters", "backhaul_dl_policy_kind", "backhaul_dl_policy_parameters", access_policies."dscp", access_policies."dns_redirect"
+            FROM subscribers
+            LEFT JOIN balance_pools ON balance_pools.id = subscribers.balance_pool
+            INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
+            INNER JOIN access_policies ON access_policies.id = (CASE WHEN subscribers.suspended THEN subscribers.suspended_policy WHEN subscribers.exempt_until > now() THEN subscribers.exempt_policy ELSE subscribers.zero_balance_policy END)
+            WHERE (COALESCE(balance_pools.data_balance, subscribers.data_balance) = 0)
+        "#;
+
+        let mut rows: Vec<AccessPolicyRow> = sqlx::query_as(ratelimit_state_query)
+            .fetch_all(&mut transaction)
+            .await?;
+
+        // Positive balance subscribers
+        let ratelimit_state_query = r#"
+            SELECT "internal_uid" AS "subscriber_id", access_policies."id" AS "policy_id", "ip", "local_ul_policy_kind", "local_ul_policy_parameters", "local_dl_policy_kind", "local_dl_policy_parameters", "backhaul_ul_policy_kind", "backhaul_ul_policy_parameters", "backhaul_dl_policy_kind", "backhaul_dl_policy_parameters", access_policies."dscp", access_policies."dns_redirect"
+            FROM subscribers
+            LEFT JOIN balance_pools ON balance_pools.id = subscribers.balance_pool
+            INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
+            INNER JOIN access_policies ON access_policies.id = (CASE WHEN subscribers.suspended THEN subscribers.suspended_policy WHEN subscribers.exempt_until > now() THEN subscribers.exempt_policy ELSE COALESCE(subscribers.deprioritized_policy, subscribers.positive_balance_policy) END)
+            WHERE (COALESCE(balance_pools.data_balance, subscribers.data_balance) > 0)
+        "#;
+
+        let positive_balance_rows: Vec<AccessPolicyRow> = sqlx::query_as(ratelimit_state_query)
+            .fetch_all(&mut transaction)
+            .await?;
+
+        transaction.commit().await?;
+        rows.extend(positive_balance_rows);
+        Ok(rows)
+    }
+
+    async fn modified_access_state(&self) -> Result<Vec<AccessPolicyRow>, StorageError> {
+        let mut transaction = self.db_pool.begin().await?;
+
+        // Zero balance subscribers
+        let ratelimit_state_updated_query = r#"
+            SELECT "internal_uid" AS "subscriber_id", access_policies."id" AS "policy_id", "ip", "local_ul_policy_kind", "local_ul_policy_parameters", "local_dl_policy_kind", "local_dl_policy_parameters", "backhaul_ul_policy_kind", "backhaul_ul_policy_parameters", "backhaul_dl_policy_kind", "backhaul_dl_policy_parameters", access_policies."dscp", access_policies."dns_redirect"
+            FROM subscribers
+            LEFT JOIN balance_pools ON balance_pools.id = subscribers.balance_pool
+            INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
+            INNER JOIN access_policies ON access_policies.id = (CASE WHEN subscribers.suspended THEN subscribers.suspended_policy WHEN subscribers.exempt_until > now() THEN subscribers.exempt_policy ELSE subscribers.zero_balance_policy END)
+            WHERE (COALESCE(balance_pools.data_balance, subscribers.data_balance) = 0) AND ((CASE WHEN subscribers.suspended THEN subscribers.suspended_policy WHEN subscribers.exempt_until > now() THEN subscribers.exempt_policy ELSE subscribers.zero_balance_policy END) != subscribers.current_policy)
+        "#;
+
+        let mut rows: Vec<AccessPolicyRow> = sqlx::query_as(ratelimit_state_updated_query)
+            .fetch_all(&mut transaction)
+            .await?;
+
+        // Positive balance subscribers
+        let ratelimit_state_updated_query = r#"
+            SELECT "internal_uid" AS "subscriber_id", access_policies."id" AS "policy_id", "ip", "local_ul_policy_kind", "local_ul_policy_parameters", "local_dl_policy_kind", "local_dl_policy_parameters", "backhaul_ul_policy_kind", "backhaul_ul_policy_parameters", "backhaul_dl_policy_kind", "backhaul_dl_policy_parameters", access_policies."dscp", access_policies."dns_redirect"
+            FROM subscribers
+            LEFT JOIN balance_pools ON balance_pools.id = subscribers.balance_pool
+            INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
+            INNER JOIN access_policies ON access_policies.id = (CASE WHEN subscribers.suspended THEN subscribers.suspended_policy WHEN subscribers.exempt_until > now() THEN subscribers.exempt_policy ELSE COALESCE(subscribers.deprioritized_policy, subscribers.positive_balance_policy) END)
+            WHERE (COALESCE(balance_pools.data_balance, subscribers.data_balance) > 0) AND ((CASE WHEN subscribers.suspended THEN subscribers.suspended_policy WHEN subscribers.exempt_until > now() THEN subscribers.exempt_policy ELSE COALESCE(subscribers.deprioritized_policy, subscribers.positive_balance_policy) END) != subscribers.current_policy)
+        "#;
+
+        let positive_balance_rows: Vec<AccessPolicyRow> =
+            sqlx::query_as(ratelimit_state_updated_query)
+                .fetch_all(&mut transaction)
+                .await?;
+
+        transaction.commit().await?;
+        rows.extend(positive_balance_rows);
+        Ok(rows)
+    }
+
+    async fn subscriber_access_state(
+        &self,
+        imsi: &str,
+    ) -> Result<Vec<AccessPolicyRow>, StorageError> {
+        let access_state_query = r#"
+            SELECT "internal_uid" AS "subscriber_id", access_policies."id" AS "policy_id", "ip", "local_ul_policy_kind", "local_ul_policy_parameters", "local_dl_policy_kind", "local_dl_policy_parameters", "backhaul_ul_policy_kind", "backhaul_ul_policy_parameters", "backhaul_dl_policy_kind", "backhaul_dl_policy_parameters", access_policies."dscp", access_policies."dns_redirect"
+            FROM subscribers
+            LEFT JOIN balance_pools ON balance_pools.id = subscribers.balance_pool
+            INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
+            INNER JOIN access_policies ON access_policies.id = (CASE WHEN subscribers.suspended THEN subscribers.suspended_policy WHEN subscribers.exempt_until > now() THEN subscribers.exempt_policy WHEN COALESCE(balance_pools.data_balance, subscribers.data_balance) > 0 THEN COALESCE(subscribers.deprioritized_policy, subscribers.positive_balance_policy) WHEN COALESCE(balance_pools.data_balance, subscribers.data_balance) = 0 THEN subscribers.zero_balance_policy END)
+            WHERE subscribers.imsi = $1
+        "#;
+
+        Ok(sqlx::query_as(access_state_query)
+            .bind(imsi)
+            .fetch_all(&*self.db_pool)
+            .await?)
+    }
+
+    async fn subscriber_access_policy(
+        &self,
+        subscriber: i32,
+        condition: crate::enforcer::SubscriberCondition,
+    ) -> Result<Vec<AccessPolicyRow>, StorageError> {
+        let ratelimit_state_query = match condition {
+            crate::enforcer::SubscriberCondition::_PositiveBalance => {
+                r#"
+                    SELECT "internal_uid" AS "subscriber_id", "access_policies"."id" AS "policy_id", "ip", "local_ul_policy_kind", "local_ul_policy_parameters", "local_dl_policy_kind", "local_dl_policy_parameters", "backhaul_ul_policy_kind", "backhaul_ul_policy_parameters", "backhaul_dl_policy_kind", "backhaul_dl_policy_parameters", access_policies."dscp", access_policies."dns_redirect"
+                    FROM subscribers
+                    INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
+                    INNER JOIN access_policies ON access_policies.id = (CASE WHEN subscribers.suspended THEN subscribers.suspended_policy WHEN subscribers.exempt_until > now() THEN subscribers.exempt_policy ELSE COALESCE(subscribers.deprioritized_policy, subscribers.positive_balance_policy) END)
+                    WHERE (internal_uid = $1)
+                "#
+            }
+            crate::enforcer::SubscriberCondition::NoBalance => {
+                r#"
+                    SELECT "internal_uid" AS "subscriber_id", "access_policies"."id" AS "policy_id", "ip", "local_ul_policy_kind", "local_ul_policy_parameters", "local_dl_policy_kind", "local_dl_policy_parameters", "backhaul_ul_policy_kind", "backhaul_ul_policy_parameters", "backhaul_dl_policy_kind", "backhaul_dl_policy_parameters", access_policies."dscp", access_policies."dns_redirect"
+                    FROM subscribers
+                    INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
+                    INNER JOIN access_policies ON access_policies.id = (CASE WHEN subscribers.suspended THEN subscribers.suspended_policy WHEN subscribers.exempt_until > now() THEN subscribers.exempt_policy ELSE subscribers.zero_balance_policy END)
+                    WHERE (internal_uid = $1)
+                "#
+            }
+        };
+
+        Ok(sqlx::query_as(ratelimit_state_query)
+            .bind(subscriber)
+            .fetch_all(&*self.db_pool)
+            .await?)
+    }
+
+    async fn exhausted_previous_access_state(&self) -> Result<Vec<AccessPolicyRow>, StorageError> {
+        let previous_state_query = r#"
+            SELECT "internal_uid" AS "subscriber_id", access_policies."id" AS "policy_id", "ip", "local_ul_policy_kind", "local_ul_policy_parameters", "local_dl_policy_kind", "local_dl_policy_parameters", "backhaul_ul_policy_kind", "backhaul_ul_policy_parameters", "backhaul_dl_policy_kind", "backhaul_dl_policy_parameters", access_policies."dscp", access_policies."dns_redirect"
+            FROM subscribers
+            LEFT JOIN balance_pools ON balance_pools.id = subscribers.balance_pool
+            INNER JOIN static_ips ON subscribers.imsi = static_ips.imsi
+            INNER JOIN access_policies ON access_policies.id = subscribers.current_policy
+            WHERE (COALESCE(balance_pools.data_balance, subscribers.data_balance) = 0) AND NOT subscribers.suspended AND NOT COALESCE(subscribers.exempt_until > now(), false) AND subscribers.zero_balance_policy != subscribers.current_policy
+        "#;
+
+        Ok(sqlx::query_as(previous_state_query)
+            .fetch_all(&*self.db_pool)
+            .await?)
+    }
+
+    async fn update_current_policy(
+        &self,
+        subscriber: i32,
+        policy: i32,
+    ) -> Result<AccessPolicyRow, StorageError> {
+        let mut transaction = self.db_pool.begin().await?;
+
+        let subscriber_update_query = r#"
+            UPDATE subscribers
+            SET "current_policy" = $1
+            FROM access_policies, static_ips
+            WHERE ("internal_uid" = $2) AND (subscribers.current_policy = access_policies.id) AND (subscribers.imsi = static_ips.imsi)
+            RETURNING "internal_uid" AS "subscriber_id", access_policies."id" AS "policy_id", "ip", "local_ul_policy_kind", "local_ul_policy_parameters", "local_dl_policy_kind", "local_dl_policy_parameters", "backhaul_ul_policy_kind", "backhaul_ul_policy_parameters", "backhaul_dl_policy_kind", "backhaul_dl_policy_parameters", access_policies."dscp", access_policies."dns_redirect"
+        "#;
+
+        let previous: AccessPolicyRow = sqlx::query_as(subscriber_update_query)
+            .bind(policy)
+            .bind(subscriber)
+            .fetch_one(&mut transaction)
+            .await?;
+
+        // The joined policy is the one applied before this update.
+        if previous.policy_id != policy {
+            crate::events::record_event(
+                &mut transaction,
+                subscriber,
+                crate::events::EventKind::PolicyChanged,
+                serde_json::json!({ "previous_policy": previous.policy_id, "policy": policy }),
+            )
+            .await?;
+        }
+
+        transaction.commit().await?;
+        Ok(previous)
+    }
+
+    async fn listen(
+        &self,
+        channel: &str,
+    ) -> Result<Option<sqlx::postgres::PgListener>, StorageError> {
+        let mut listener = sqlx::postgres::PgListener::connect_with(&self.db_pool).await?;
+        listener.listen(channel).await?;
+        Ok(Some(listener))
+    }
+}
+
+// Months have no fixed length, so are approximated as 30 days. Report
+// intervals of a month or more are not expected in practice.
+fn interval_duration(interval: sqlx::postgres::types::PgInterval) -> std::time::Duration {
+    let days = interval.months as i64 * 30 + interval.days as i64;
+    let micros = days * 24 * 60 * 60 * 1_000_000 + interval.microseconds;
+    std::time::Duration::from_micros(micros.max(0) as u64)
+}
+
+// Decrements the balance of each subscriber, or of their pool if they draw
+// from one, by the given number of bytes, flooring balances at zero, and
+// returns the resulting balances. Subscribers within an exemption window are
+// not charged.
+async fn debit_balances(
+    connection: &mut sqlx::PgConnection,
+    debits: &HashMap<i32, i64>,
+) -> Result<HashMap<i32, DebitResult>, sqlx::Error> {
+    if debits.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let (subscribers, amounts): (Vec<i32>, Vec<i64>) = debits.iter().unzip();
+    let debit_query = r#"
+        WITH debits AS (
+            SELECT subscribers."internal_uid", subscribers."balance_pool", debits.amount,
+                COALESCE(subscribers."exempt_until" > now(), false) AS "exempt"
+            FROM UNNEST($1::INT[], $2::BIGINT[]) AS debits(subscriber, amount)
+            INNER JOIN subscribers ON subscribers."internal_uid" = debits.subscriber
+        ), pool_debits AS (
+            UPDATE balance_pools
+            SET "data_balance" = GREATEST("data_balance" - totals.amount, 0)
+            FROM (
+                SELECT "balance_pool", SUM(amount)::BIGINT AS amount
+                FROM debits
+                WHERE "balance_pool" IS NOT NULL AND NOT "exempt"
+                GROUP BY "balance_pool"
+            ) AS totals
+            WHERE balance_pools."id" = totals."balance_pool"
+            RETURNING balance_pools."id", balance_pools."data_balance"
+        ), subscriber_debits AS (
+            UPDATE subscribers
+            SET "data_balance" = GREATEST(subscribers."data_balance" - debits.amount, 0)
+            FROM debits
+            WHERE subscribers."internal_uid" = debits."internal_uid" AND debits."balance_pool" IS NULL AND NOT debits."exempt"
+            RETURNING subscribers."internal_uid", subscribers."data_balance"
+        )
+        SELECT debits."internal_uid", COALESCE(pool_debits."data_balance", balance_pools."data_balance", subscriber_debits."data_balance", subscribers."data_balance"), debits."exempt"
+        FROM debits
+        INNER JOIN subscribers ON subscribers."internal_uid" = debits."internal_uid"
+        LEFT JOIN balance_pools ON balance_pools."id" = debits."balance_pool"
+        LEFT JOIN pool_debits ON pool_debits."id" = debits."balance_pool"
+        LEFT JOIN subscriber_debits ON subscriber_debits."internal_uid" = debits."internal_uid"
+    "#;
+
+    let rows: Vec<(i32, i64, bool)> = sqlx::query_as(debit_query)
+        .bind(subscribers)
+        .bind(amounts)
+        .fetch_all(connection)
+        .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(subscriber, balance, exempt)| (subscriber, DebitResult { balance, exempt }))
+        .collect())
+}
+
+// Writes the records with a single COPY on the given connection, returning the
+// number of rows written.
+pub async fn copy_usage_records(
+    connection: &mut sqlx::PgConnection,
+    records: &[(i32, UseRecord)],
+) -> Result<u64, sqlx::Error> {
+    let mut copy = connection.copy_in_raw(COPY_STATEMENT).await?;
+    if let Err(e) = copy.send(encode_copy_rows(records).into_bytes()).await {
+        copy.abort(e.to_string()).await?;
+        return Err(e);
+    }
+    copy.finish().await
+}
+
+// Writes the service breakdown of the records with a single COPY on the given
+// connection, skipping records without any RAN bytes.
+async fn copy_service_records(
+    connection: &mut sqlx::PgConnection,
+    records: &[(i32, UseRecord)],
+) -> Result<u64, sqlx::Error> {
+    let encoded = encode_service_rows(records);
+    if encoded.is_empty() {
+        return Ok(0);
+    }
+    let mut copy = connection.copy_in_raw(SERVICE_COPY_STATEMENT).await?;
+    if let Err(e) = copy.send(encoded.into_bytes()).await {
+        copy.abort(e.to_string()).await?;
+        return Err(e);
+    }
+    copy.finish().await
+}
+
+// Writes the top destinations of the batch, then trims each interval written
+// to the top destinations overall, since the records of an interval may be
+// split across flushes.
+async fn write_top_destinations(
+    connection: &mut sqlx::PgConnection,
+    top: &[&(std::net::IpAddr, UseRecord)],
+    count: usize,
+) -> Result<(), sqlx::Error> {
+    let mut copy = connection.copy_in_raw(DESTINATION_COPY_STATEMENT).await?;
+    if let Err(e) = copy.send(encode_destination_rows(top).into_bytes()).await {
+        copy.abort(e.to_string()).await?;
+        return Err(e);
+    }
+    copy.finish().await?;
+
+    let mut intervals: Vec<chrono::DateTime<chrono::Utc>> =
+        top.iter().map(|(_, record)| record.end).collect();
+    intervals.sort_unstable();
+    intervals.dedup();
+    let trim_query = r#"
+        DELETE FROM destination_usage
+        WHERE "end_time" = ANY($1) AND ("destination", "start_time") NOT IN (
+            SELECT ranked."destination", ranked."start_time" FROM (
+                SELECT "destination", "start_time", row_number() OVER (
+                    PARTITION BY "end_time" ORDER BY "bytes_up" + "bytes_down" DESC
+                ) AS rank
+                FROM destination_usage
+                WHERE "end_time" = ANY($1)
+            ) AS ranked
+            WHERE ranked.rank <= $2
+        )
+    "#;
+    sqlx::query(trim_query)
+        .bind(intervals)
+        .bind(count as i64)
+        .execute(connection)
+        .await?;
+    Ok(())
+}
+
+fn encode_destination_rows(destinations: &[&(std::net::IpAddr, UseRecord)]) -> String {
+    let mut encoded = String::new();
+    for (destination, record) in destinations {
+        writeln!(
+            encoded,
+            "{}\t{}\t{}\t{}\t{}",
+            destination,
+            record.start.to_rfc3339(),
+            record.end.to_rfc3339(),
+            record.usage.wan_bytes_up,
+            record.usage.wan_bytes_down,
+        )
+        .expect("Writing to a string cannot fail");
+    }
+    encoded
+}
+
+// Encodes the records in the postgres COPY text format, with one tab separated
+// row per line. None of the fields can contain characters requiring escapes.
+fn encode_copy_rows(records: &[(i32, UseRecord)]) -> String {
+    let mut encoded = String::new();
+    for (subscriber, record) in records {
+        writeln!(
+            encoded,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            subscriber,
+            record.start.to_rfc3339(),
+            record.end.to_rfc3339(),
+            record.usage.ran_bytes_up,
+            record.usage.ran_bytes_down,
+            record.usage.wan_bytes_up,
+            record.usage.wan_bytes_down,
+            record.usage.tcp_bytes,
+            record.usage.udp_bytes,
+            record.usage.icmp_bytes,
+            record.usage.other_bytes,
+        )
+        .expect("Writing to a string cannot fail");
+    }
+    encoded
+}
+
+fn encode_service_rows(records: &[(i32, UseRecord)]) -> String {
+    let mut encoded = String::new();
+    for (subscriber, record) in records {
+        let services = &record.usage.services;
+        if *services == crate::ServiceBytes::default() {
+            continue;
+        }
+        writeln!(
+            encoded,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            subscriber,
+            record.start.to_rfc3339(),
+            services.https_bytes,
+            services.http_bytes,
+            services.dns_bytes,
+            services.quic_bytes,
+            services.voip_bytes,
+            services.other_bytes,
+        )
+        .expect("Writing to a string cannot fail");
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_encode_copy_rows() {
+        let record = UseRecord {
+            start: chrono::Utc.ymd(2022, 5, 13).and_hms(23, 16, 50),
+            end: chrono::Utc.ymd(2022, 5, 13).and_hms(23, 17, 50),
+            usage: crate::NetResourceBundle {
+                ran_bytes_up: 1,
+                ran_bytes_down: 2,
+                wan_bytes_up: 3,
+                wan_bytes_down: 4,
+                tcp_bytes: 2,
+                udp_bytes: 1,
+                icmp_bytes: 0,
+                other_bytes: 0,
+                services: crate::ServiceBytes {
+                    https_bytes: 3,
+                    ..Default::default()
+                },
+            },
+        };
+        assert_eq!(
+            encode_copy_rows(&[(7, record.clone()), (8, record.clone())]),
+            "7\t2022-05-13T23:16:50+00:00\t2022-05-13T23:17:50+00:00\t1\t2\t3\t4\t2\t1\t0\t0\n\
+             8\t2022-05-13T23:16:50+00:00\t2022-05-13T23:17:50+00:00\t1\t2\t3\t4\t2\t1\t0\t0\n"
+        );
+
+        let idle = UseRecord {
+            usage: crate::NetResourceBundle::zeroed(),
+            ..record.clone()
+        };
+        assert_eq!(
+            encode_service_rows(&[(7, record), (8, idle)]),
+            "7\t2022-05-13T23:16:50+00:00\t3\t0\t0\t0\t0\t0\n"
+        );
+    }
+}
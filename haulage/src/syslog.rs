@@ -0,0 +1,427 @@
+use std::fmt::Write;
+
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+
+use crate::reporter::UseRecord;
+
+// How often the subscriber event feed is checked for new enforcement actions.
+const EVENT_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+// The enterprise number reserved for documentation (RFC5612), identifying the
+// structured data elements as haulage's own rather than IANA registered ones.
+const SD_ENTERPRISE: &str = "32473";
+
+#[derive(Error, Debug)]
+pub enum SyslogError {
+    #[error("Syslog connection failed: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Invalid syslog server name: {0}")]
+    InvalidServerName(String),
+    #[error("Invalid syslog CA certificate: {0}")]
+    InvalidCertificate(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    Udp,
+    // TCP and TLS messages are framed with octet counting (RFC6587, RFC5425).
+    Tcp,
+    Tls,
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // The host and port of the syslog server, e.g. logs.example.org:6514
+    pub address: String,
+    pub transport: Transport,
+    pub facility: u8,
+    // A PEM file of additional CAs trusted for TLS, e.g. a site's private CA.
+    pub ca_path: Option<std::path::PathBuf>,
+}
+
+// Returns the numeric code of a named syslog facility.
+pub fn facility_code(name: &str) -> Option<u8> {
+    match name {
+        "user" => Some(1),
+        "daemon" => Some(3),
+        "local0" => Some(16),
+        "local1" => Some(17),
+        "local2" => Some(18),
+        "local3" => Some(19),
+        "local4" => Some(20),
+        "local5" => Some(21),
+        "local6" => Some(22),
+        "local7" => Some(23),
+        _ => None,
+    }
+}
+
+// Forwards usage summaries and enforcement actions to a remote syslog server
+// as RFC5424 messages. Usage records are sent once written to the database,
+// and enforcement actions are read from the subscriber event feed, so that
+// actions taken by the admin commands are forwarded as well. Messages are
+// dropped rather than buffered while the server is unreachable.
+#[derive(Debug)]
+pub struct SyslogExporter {
+    dispatch_channel: tokio::sync::mpsc::Sender<Message>,
+}
+impl SyslogExporter {
+    pub fn new(
+        settings: Settings,
+        db_pool: std::sync::Arc<sqlx::PgPool>,
+        pseudonymizer: Option<std::sync::Arc<crate::privacy::Pseudonymizer>>,
+        stats: std::sync::Arc<crate::stats::Stats>,
+        log: slog::Logger,
+    ) -> SyslogExporter {
+        let (sender, receiver) = tokio::sync::mpsc::channel(1024);
+        tokio::task::spawn(async move {
+            export_messages(receiver, settings, db_pool, pseudonymizer, stats, log).await;
+        });
+        SyslogExporter {
+            dispatch_channel: sender,
+        }
+    }
+    pub fn clone_input_channel(&self) -> tokio::sync::mpsc::Sender<Message> {
+        self.dispatch_channel.clone()
+    }
+}
+
+#[derive(Debug)]
+pub enum Message {
+    Usage { subscriber: i32, record: UseRecord },
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct EventRow {
+    id: i64,
+    time: chrono::DateTime<chrono::Utc>,
+    subscriber: i32,
+    imsi: String,
+    kind: String,
+    details: serde_json::Value,
+}
+
+enum Connection {
+    Udp(tokio::net::UdpSocket),
+    Stream(Box<dyn tokio::io::AsyncWrite + Unpin + Send>),
+}
+
+struct Sender {
+    settings: Settings,
+    hostname: String,
+    connection: Option<Connection>,
+    pseudonymizer: Option<std::sync::Arc<crate::privacy::Pseudonymizer>>,
+    stats: std::sync::Arc<crate::stats::Stats>,
+    log: slog::Logger,
+}
+impl Sender {
+    fn subscriber_label(&self, subscriber: i32) -> String {
+        match &self.pseudonymizer {
+            Some(pseudonymizer) => pseudonymizer.pseudonym("subscriber", &subscriber.to_string()),
+            None => subscriber.to_string(),
+        }
+    }
+
+    // Replaces the identifiers of the subscriber in privacy mode. Event details
+    // may contain addresses and other identifiers, so are omitted entirely.
+    fn pseudonymize(&self, event: &mut EventRow) {
+        if let Some(pseudonymizer) = &self.pseudonymizer {
+            event.imsi = pseudonymizer.pseudonym("imsi", &event.imsi);
+            event.details = serde_json::json!({});
+        }
+    }
+
+    async fn send(&mut self, message: String) {
+        if self.connection.is_none() {
+            match connect(&self.settings).await {
+                Ok(connection) => self.connection = Some(connection),
+                Err(e) => {
+                    slog::warn!(self.log, "Failed to connect to syslog server"; "error" => e.to_string());
+                    self.stats.syslog_send_errors.increment();
+                    return;
+                }
+            }
+        }
+
+        let result = match self.connection.as_mut() {
+            Some(Connection::Udp(socket)) => socket.send(message.as_bytes()).await.map(|_| ()),
+            Some(Connection::Stream(stream)) => stream.write_all(frame(&message).as_bytes()).await,
+            None => unreachable!("Connection established above"),
+        };
+        match result {
+            Ok(()) => self.stats.syslog_messages_sent.increment(),
+            Err(e) => {
+                slog::warn!(self.log, "Failed to send syslog message"; "error" => e.to_string());
+                self.stats.syslog_send_errors.increment();
+                // Reconnect with the next message.
+                self.connection = None;
+            }
+        }
+    }
+}
+
+async fn export_messages(
+    mut chan: tokio::sync::mpsc::Receiver<Message>,
+    settings: Settings,
+    db_pool: std::sync::Arc<sqlx::PgPool>,
+    pseudonymizer: Option<std::sync::Arc<crate::privacy::Pseudonymizer>>,
+    stats: std::sync::Arc<crate::stats::Stats>,
+    log: slog::Logger,
+) {
+    let mut sender = Sender {
+        settings,
+        hostname: local_hostname(),
+        connection: None,
+        pseudonymizer,
+        stats,
+        log,
+    };
+
+    // Only actions taken after startup are forwarded, so the feed position is
+    // initialized to the latest event before any are sent.
+    let mut last_event = None;
+    let mut timer = tokio::time::interval(EVENT_POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = timer.tick() => {
+                let after = match last_event {
+                    Some(id) => id,
+                    None => match latest_event_id(&db_pool).await {
+                        Ok(id) => *last_event.insert(id),
+                        Err(e) => {
+                            slog::warn!(sender.log, "Failed to read the subscriber event feed"; "error" => e.to_string());
+                            continue;
+                        }
+                    },
+                };
+                let events = match query_enforcement_events(&db_pool, after).await {
+                    Ok(events) => events,
+                    Err(e) => {
+                        slog::warn!(sender.log, "Failed to query enforcement events"; "error" => e.to_string());
+                        continue;
+                    }
+                };
+                for mut event in events {
+                    last_event = Some(event.id);
+                    let subscriber = sender.subscriber_label(event.subscriber);
+                    sender.pseudonymize(&mut event);
+                    let message = format_event(sender.settings.facility, &sender.hostname, &subscriber, &event);
+                    sender.send(message).await;
+                }
+            }
+            message = chan.recv() => {
+                match message {
+                    Some(Message::Usage { subscriber, record }) => {
+                        let message = format_usage(
+                            sender.settings.facility,
+                            &sender.hostname,
+                            chrono::Utc::now(),
+                            &sender.subscriber_label(subscriber),
+                            &record,
+                        );
+                        sender.send(message).await;
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+}
+
+async fn latest_event_id(db_pool: &sqlx::PgPool) -> Result<i64, sqlx::Error> {
+    let (id,): (Option<i64>,) = sqlx::query_as(r#"SELECT MAX("id") FROM subscriber_events"#)
+        .fetch_one(db_pool)
+        .await?;
+    Ok(id.unwrap_or(0))
+}
+
+async fn query_enforcement_events(
+    db_pool: &sqlx::PgPool,
+    after: i64,
+) -> Result<Vec<EventRow>, sqlx::Error> {
+    let query = r#"
+        SELECT "id", "time", "subscriber", "imsi", "kind", "details"
+        FROM subscriber_events
+        WHERE "id" > $1 AND "kind" IN ('policy_changed', 'suspended', 'resumed', 'exempted', 'address_collision')
+        ORDER BY "id"
+    "#;
+    sqlx::query_as(query).bind(after).fetch_all(db_pool).await
+}
+
+async fn connect(settings: &Settings) -> Result<Connection, SyslogError> {
+    match settings.transport {
+        Transport::Udp => {
+            let socket = tokio::net::UdpSocket::bind("[::]:0").await?;
+            socket.connect(&settings.address).await?;
+            Ok(Connection::Udp(socket))
+        }
+        Transport::Tcp => {
+            let stream = tokio::net::TcpStream::connect(&settings.address).await?;
+            Ok(Connection::Stream(Box::new(stream)))
+        }
+        Transport::Tls => {
+            let connector = tls_connector(settings.ca_path.as_deref())?;
+            let host = settings
+                .address
+                .rsplit_once(':')
+                .map_or(settings.address.as_str(), |(host, _)| host)
+                .trim_start_matches('[')
+                .trim_end_matches(']');
+            let server_name = tokio_rustls::rustls::ServerName::try_from(host)
+                .map_err(|_| SyslogError::InvalidServerName(host.to_owned()))?;
+            let stream = tokio::net::TcpStream::connect(&settings.address).await?;
+            let stream = connector.connect(server_name, stream).await?;
+            Ok(Connection::Stream(Box::new(stream)))
+        }
+    }
+}
+
+fn tls_connector(
+    ca_path: Option<&std::path::Path>,
+) -> Result<tokio_rustls::TlsConnector, SyslogError> {
+    use tokio_rustls::rustls;
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    if let Some(path) = ca_path {
+        let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+        for certificate in rustls_pemfile::certs(&mut reader)? {
+            roots
+                .add(&rustls::Certificate(certificate))
+                .map_err(|e| SyslogError::InvalidCertificate(e.to_string()))?;
+        }
+    }
+
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(tokio_rustls::TlsConnector::from(std::sync::Arc::new(
+        config,
+    )))
+}
+
+fn local_hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|hostname| hostname.trim().to_owned())
+        .ok()
+        .filter(|hostname| !hostname.is_empty())
+        .unwrap_or_else(|| String::from("-"))
+}
+
+fn format_usage(
+    facility: u8,
+    hostname: &str,
+    now: chrono::DateTime<chrono::Utc>,
+    subscriber: &str,
+    record: &UseRecord,
+) -> String {
+    let mut data = format!(
+        "[usage@{} subscriber=\"{}\"",
+        SD_ENTERPRISE,
+        escape_param(subscriber)
+    );
+    for (name, value) in [
+        ("start", record.start.to_rfc3339()),
+        ("end", record.end.to_rfc3339()),
+        ("ran_bytes_up", record.usage.ran_bytes_up.to_string()),
+        ("ran_bytes_down", record.usage.ran_bytes_down.to_string()),
+        ("wan_bytes_up", record.usage.wan_bytes_up.to_string()),
+        ("wan_bytes_down", record.usage.wan_bytes_down.to_string()),
+    ] {
+        write!(data, " {}=\"{}\"", name, escape_param(&value)).expect("Writing to a string");
+    }
+    data.push(']');
+    header(facility, 6, hostname, now, "usage")
+        + &data
+        + &format!(
+            " subscriber {} used {} bytes",
+            subscriber,
+            record.usage.ran_bytes_up + record.usage.ran_bytes_down
+        )
+}
+
+fn format_event(facility: u8, hostname: &str, subscriber: &str, event: &EventRow) -> String {
+    let data = format!(
+        "[enforcement@{} subscriber=\"{}\" imsi=\"{}\" action=\"{}\" details=\"{}\"]",
+        SD_ENTERPRISE,
+        escape_param(subscriber),
+        escape_param(&event.imsi),
+        escape_param(&event.kind),
+        escape_param(&event.details.to_string()),
+    );
+    header(facility, 5, hostname, event.time, "enforcement")
+        + &data
+        + &format!(" subscriber {} {}", event.imsi, event.kind)
+}
+
+// Builds the message header, up to the structured data. Usage summaries are
+// sent at informational severity and enforcement actions at notice.
+fn header(
+    facility: u8,
+    severity: u8,
+    hostname: &str,
+    time: chrono::DateTime<chrono::Utc>,
+    message_id: &str,
+) -> String {
+    format!(
+        "<{}>1 {} {} haulage {} {} ",
+        facility as u16 * 8 + severity as u16,
+        time.to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+        hostname,
+        std::process::id(),
+        message_id,
+    )
+}
+
+// Escapes the characters RFC5424 reserves within parameter values.
+fn escape_param(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace(']', "\\]")
+}
+
+// Frames a message for a stream transport with its length in octets.
+fn frame(message: &str) -> String {
+    format!("{} {}", message.len(), message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_format_usage() {
+        let record = UseRecord {
+            start: chrono::Utc.ymd(2022, 5, 13).and_hms(23, 16, 50),
+            end: chrono::Utc.ymd(2022, 5, 13).and_hms(23, 17, 50),
+            usage: crate::NetResourceBundle {
+                ran_bytes_up: 1,
+                ran_bytes_down: 2,
+                ..crate::NetResourceBundle::zeroed()
+            },
+        };
+        let message = format_usage(16, "core", record.end, "7", &record);
+        assert!(message.starts_with("<134>1 2022-05-13T23:17:50.000000Z core haulage "));
+        assert!(message.ends_with(
+            " usage [usage@32473 subscriber=\"7\" start=\"2022-05-13T23:16:50+00:00\" \
+             end=\"2022-05-13T23:17:50+00:00\" ran_bytes_up=\"1\" ran_bytes_down=\"2\" \
+             wan_bytes_up=\"0\" wan_bytes_down=\"0\"] subscriber 7 used 3 bytes"
+        ));
+    }
+
+    #[test]
+    fn test_escape_and_frame() {
+        assert_eq!(escape_param(r#"{"a":"]"}"#), r#"{\"a\":\"\]\"}"#);
+        assert_eq!(frame("<134>1 -"), "8 <134>1 -");
+    }
+}
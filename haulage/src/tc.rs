@@ -0,0 +1,614 @@
+use std::os::unix::io::AsRawFd;
+
+use futures_util::{StreamExt, TryStreamExt};
+use netlink_packet_core::{
+    NetlinkMessage, NetlinkPayload, NLM_F_ACK, NLM_F_CREATE, NLM_F_DUMP, NLM_F_EXCL, NLM_F_REQUEST,
+};
+use netlink_packet_route::{tc, RtnlMessage, TcMessage};
+use netlink_packet_utils::{nla::DefaultNla, Parseable};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum TcError {
+    #[error("Failed to open a netlink connection: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Traffic control request failed: {0}")]
+    NetlinkError(#[from] rtnetlink::Error),
+    #[error("Interface {0} not found")]
+    UnknownInterface(String),
+}
+
+// Where `ip netns` keeps the handles of named namespaces.
+const NETNS_RUN_DIR: &str = "/run/netns";
+
+// The parent of a qdisc attached to the interface itself.
+pub const TC_H_ROOT: u32 = tc::constants::TC_H_ROOT;
+
+const ETH_P_ALL: u16 = 0x0003;
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_IPV6: u16 = 0x86DD;
+
+// The offsets of the destination address in the IPv4 and IPv6 headers.
+const IPV4_DST_OFFSET: i32 = 16;
+const IPV6_DST_OFFSET: i32 = 24;
+
+const TCA_HTB_PARMS: u16 = 1;
+const TCA_HTB_INIT: u16 = 2;
+const TCA_FW_CLASSID: u16 = 1;
+const TCA_MATCHALL_CLASSID: u16 = 1;
+const TC_LINKLAYER_ETHERNET: u8 = 1;
+const TC_RED_ECN: u8 = 1;
+const TC_RED_HARDDROP: u8 = 2;
+
+// The kernel schedules in 64ns ticks, in which htb bursts are given.
+const PSCHED_TICK_NS: u64 = 64;
+// The burst tc gives classes without one, at high resolution timers.
+const DEFAULT_BURST_BYTES: u64 = 1600;
+
+// A tc handle, written major:minor in hexadecimal by tc.
+pub fn handle(major: u16, minor: u16) -> u32 {
+    (major as u32) << 16 | minor as u32
+}
+
+// The rates of an htb class in bytes per second, with the bursts in bytes
+// allowed at each. Bursts default as with tc. Classes of lower priority values
+// are offered spare bandwidth first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HtbRate {
+    pub rate: u64,
+    pub ceil: u64,
+    pub burst: Option<u64>,
+    pub cburst: Option<u64>,
+    pub prio: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Qdisc {
+    Htb,
+    // Stochastic fairness between a subscriber's flows, with early drops
+    // signalled by ECN where possible.
+    SfqRed,
+    FqCodel,
+}
+
+// The cumulative counters of a qdisc. The kernel counts drops in packets.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QdiscStats {
+    pub bytes: u64,
+    pub packets: u64,
+    pub drops: u64,
+}
+
+// Manages the queuing disciplines, classes and filters of one interface over
+// rtnetlink, rather than by running `tc` for each change.
+pub struct Tc {
+    handle: rtnetlink::Handle,
+    index: i32,
+}
+
+impl Tc {
+    pub async fn open(interface: &crate::netns::Interface) -> Result<Tc, TcError> {
+        let handle = match interface.namespace() {
+            Some(namespace) => connect_in_namespace(namespace)?,
+            None => {
+                let (connection, handle, _) = rtnetlink::new_connection()?;
+                tokio::task::spawn(connection);
+                handle
+            }
+        };
+        let link = handle
+            .link()
+            .get()
+            .match_name(interface.name.clone())
+            .execute()
+            .try_next()
+            .await;
+        match link {
+            Ok(Some(link)) => Ok(Tc {
+                handle,
+                index: link.header.index as i32,
+            }),
+            Ok(None) | Err(rtnetlink::Error::NetlinkError(_)) => {
+                Err(TcError::UnknownInterface(interface.to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // The handles of the qdiscs on the interface, including the default root
+    // qdisc with handle 0.
+    pub async fn qdisc_handles(&mut self) -> Result<Vec<u32>, TcError> {
+        let responses = self
+            .execute(
+                RtnlMessage::GetQueueDiscipline(TcMessage::default()),
+                NLM_F_DUMP,
+            )
+            .await?;
+        Ok(responses
+            .into_iter()
+            .filter_map(|message| match message {
+                RtnlMessage::NewQueueDiscipline(qdisc) if qdisc.header.index == self.index => {
+                    Some(qdisc.header.handle)
+                }
+                _ => None,
+            })
+            .collect())
+    }
+
+    // The counters of each qdisc on the interface by handle.
+    pub async fn qdisc_stats(
+        &mut self,
+    ) -> Result<std::collections::HashMap<u32, QdiscStats>, TcError> {
+        let responses = self
+            .execute(
+                RtnlMessage::GetQueueDiscipline(TcMessage::default()),
+                NLM_F_DUMP,
+            )
+            .await?;
+        Ok(responses
+            .into_iter()
+            .filter_map(|message| match message {
+                RtnlMessage::NewQueueDiscipline(qdisc) if qdisc.header.index == self.index => {
+                    Some((qdisc.header.handle, qdisc_stats(&qdisc.nlas)))
+                }
+                _ => None,
+            })
+            .collect())
+    }
+
+    // Deletes the root qdisc, and with it every class and filter, restoring
+    // the interface's default qdisc.
+    pub async fn delete_root_qdisc(&mut self) -> Result<(), TcError> {
+        let mut message = self.message(TC_H_ROOT, 0);
+        message.header.handle = 0;
+        self.execute(RtnlMessage::DelQueueDiscipline(message), 0)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn add_qdisc(
+        &mut self,
+        parent: u32,
+        handle: u32,
+        qdisc: Qdisc,
+    ) -> Result<(), TcError> {
+        let mut message = self.message(parent, handle);
+        match qdisc {
+            Qdisc::Htb => {
+                message.nlas.push(tc::Nla::Kind(String::from("htb")));
+                message
+                    .nlas
+                    .push(tc::Nla::Options(vec![tc::TcOpt::Other(DefaultNla::new(
+                        TCA_HTB_INIT,
+                        htb_init(),
+                    ))]));
+            }
+            Qdisc::SfqRed => {
+                message.nlas.push(tc::Nla::Kind(String::from("sfq")));
+                // Unlike most qdiscs, sfq options are a bare struct.
+                message.nlas.push(tc::Nla::Other(DefaultNla::new(
+                    netlink_packet_route::TCA_OPTIONS,
+                    sfq_red_options(),
+                )));
+            }
+            Qdisc::FqCodel => message.nlas.push(tc::Nla::Kind(String::from("fq_codel"))),
+        }
+        self.execute(
+            RtnlMessage::NewQueueDiscipline(message),
+            NLM_F_CREATE | NLM_F_EXCL,
+        )
+        .await
+        .map(|_| ())
+    }
+
+    pub async fn add_htb_class(
+        &mut self,
+        parent: u32,
+        class: u32,
+        rate: HtbRate,
+    ) -> Result<(), TcError> {
+        self.htb_class(parent, class, rate, NLM_F_CREATE | NLM_F_EXCL)
+            .await
+    }
+
+    pub async fn change_htb_class(
+        &mut self,
+        parent: u32,
+        class: u32,
+        rate: HtbRate,
+    ) -> Result<(), TcError> {
+        self.htb_class(parent, class, rate, 0).await
+    }
+
+    async fn htb_class(
+        &mut self,
+        parent: u32,
+        class: u32,
+        rate: HtbRate,
+        flags: u16,
+    ) -> Result<(), TcError> {
+        let mut message = self.message(parent, class);
+        message.nlas.push(tc::Nla::Kind(String::from("htb")));
+        message
+            .nlas
+            .push(tc::Nla::Options(vec![tc::TcOpt::Other(DefaultNla::new(
+                TCA_HTB_PARMS,
+                htb_class_options(&rate),
+            ))]));
+        self.execute(RtnlMessage::NewTrafficClass(message), flags)
+            .await
+            .map(|_| ())
+    }
+
+    // Classifies packets to the given network into a class. Filters for IPv4
+    // and IPv6 cannot share a priority.
+    pub async fn add_u32_dst_filter(
+        &mut self,
+        parent: u32,
+        priority: u16,
+        destination: ipnetwork::IpNetwork,
+        class: u32,
+    ) -> Result<(), TcError> {
+        let mut message = self.message(parent, 0);
+        let (protocol, keys) = u32_dst_keys(destination);
+        message.header.info = filter_info(priority, protocol);
+        message
+            .nlas
+            .push(tc::Nla::Kind(String::from(tc::u32::KIND)));
+        let mut selector = tc::u32::Sel::default();
+        selector.flags = tc::constants::TC_U32_TERMINAL;
+        selector.nkeys = keys.len() as u8;
+        selector.keys = keys;
+        message.nlas.push(tc::Nla::Options(vec![
+            tc::TcOpt::U32(tc::u32::Nla::ClassId(class)),
+            tc::TcOpt::U32(tc::u32::Nla::Sel(selector)),
+        ]));
+        self.add_filter(message).await
+    }
+
+    // Classifies packets carrying a firewall mark into a class.
+    pub async fn add_fw_filter(
+        &mut self,
+        parent: u32,
+        priority: u16,
+        mark: u32,
+        class: u32,
+    ) -> Result<(), TcError> {
+        let mut message = self.message(parent, mark);
+        message.header.info = filter_info(priority, ETH_P_ALL);
+        message.nlas.push(tc::Nla::Kind(String::from("fw")));
+        message
+            .nlas
+            .push(tc::Nla::Options(vec![tc::TcOpt::Other(DefaultNla::new(
+                TCA_FW_CLASSID,
+                class.to_ne_bytes().to_vec(),
+            ))]));
+        self.add_filter(message).await
+    }
+
+    // Classifies all packets not matched by a filter of higher priority.
+    pub async fn add_matchall_filter(
+        &mut self,
+        parent: u32,
+        priority: u16,
+        class: u32,
+    ) -> Result<(), TcError> {
+        let mut message = self.message(parent, 0);
+        message.header.info = filter_info(priority, ETH_P_ALL);
+        message
+            .nlas
+            .push(tc::Nla::Kind(String::from(tc::matchall::KIND)));
+        message
+            .nlas
+            .push(tc::Nla::Options(vec![tc::TcOpt::Other(DefaultNla::new(
+                TCA_MATCHALL_CLASSID,
+                class.to_ne_bytes().to_vec(),
+            ))]));
+        self.add_filter(message).await
+    }
+
+    async fn add_filter(&mut self, message: TcMessage) -> Result<(), TcError> {
+        self.execute(
+            RtnlMessage::NewTrafficFilter(message),
+            NLM_F_CREATE | NLM_F_EXCL,
+        )
+        .await
+        .map(|_| ())
+    }
+
+    fn message(&self, parent: u32, handle: u32) -> TcMessage {
+        let mut message = TcMessage::with_index(self.index);
+        message.header.parent = parent;
+        message.header.handle = handle;
+        message
+    }
+
+    async fn execute(
+        &mut self,
+        message: RtnlMessage,
+        flags: u16,
+    ) -> Result<Vec<RtnlMessage>, TcError> {
+        let mut request = NetlinkMessage::from(message);
+        request.header.flags = NLM_F_REQUEST | NLM_F_ACK | flags;
+        let mut responses = self.handle.request(request)?;
+        let mut messages = Vec::new();
+        while let Some(response) = responses.next().await {
+            match response.payload {
+                NetlinkPayload::Error(e) if e.code.is_some() => {
+                    return Err(rtnetlink::Error::NetlinkError(e).into())
+                }
+                NetlinkPayload::InnerMessage(message) => messages.push(message),
+                _ => {}
+            }
+        }
+        Ok(messages)
+    }
+}
+
+// Reads the counters of a qdisc from TCA_STATS2, falling back to the legacy
+// TCA_STATS of older kernels for counters missing from it.
+fn qdisc_stats(nlas: &[tc::Nla]) -> QdiscStats {
+    let mut basic = None;
+    let mut queue = None;
+    let mut legacy = None;
+    for nla in nlas {
+        match nla {
+            tc::Nla::Stats2(stats) => {
+                for stat in stats {
+                    match stat {
+                        tc::Stats2::StatsBasic(bytes) => {
+                            basic = tc::StatsBasicBuffer::new_checked(bytes.as_slice())
+                                .and_then(|buffer| tc::StatsBasic::parse(&buffer))
+                                .ok();
+                        }
+                        tc::Stats2::StatsQueue(bytes) => {
+                            queue = tc::StatsQueueBuffer::new_checked(bytes.as_slice())
+                                .and_then(|buffer| tc::StatsQueue::parse(&buffer))
+                                .ok();
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            tc::Nla::Stats(stats) => legacy = Some(*stats),
+            _ => {}
+        }
+    }
+    QdiscStats {
+        bytes: basic
+            .map(|basic| basic.bytes)
+            .or(legacy.map(|legacy| legacy.bytes))
+            .unwrap_or_default(),
+        packets: basic
+            .map(|basic| basic.packets)
+            .or(legacy.map(|legacy| legacy.packets))
+            .unwrap_or_default() as u64,
+        drops: queue
+            .map(|queue| queue.drops)
+            .or(legacy.map(|legacy| legacy.drops))
+            .unwrap_or_default() as u64,
+    }
+}
+
+// Netlink sockets stay in the namespace of the thread creating them, so the
+// socket is created on a short-lived thread moved into the namespace rather
+// than moving a runtime thread.
+fn connect_in_namespace(namespace: &str) -> Result<rtnetlink::Handle, TcError> {
+    let namespace = std::fs::File::open(std::path::Path::new(NETNS_RUN_DIR).join(namespace))?;
+    let runtime = tokio::runtime::Handle::current();
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                // Safety: setns only changes the namespace of this thread.
+                if unsafe { libc::setns(namespace.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+                    return Err(std::io::Error::last_os_error().into());
+                }
+                let _runtime = runtime.enter();
+                let (connection, handle, _) = rtnetlink::new_connection()?;
+                tokio::task::spawn(connection);
+                Ok(handle)
+            })
+            .join()
+            .expect("Namespace connection thread panicked")
+    })
+}
+
+// The filter priority and protocol, in network byte order, as tc packs them.
+fn filter_info(priority: u16, protocol: u16) -> u32 {
+    (priority as u32) << 16 | protocol.to_be() as u32
+}
+
+// The protocol and u32 keys matching a destination network. Keys compare 32
+// bit words in network byte order, so IPv6 prefixes take a key for each word
+// of the prefix.
+fn u32_dst_keys(destination: ipnetwork::IpNetwork) -> (u16, Vec<tc::u32::Key>) {
+    let key = |offset: i32, mask: [u8; 4], value: [u8; 4]| {
+        let mut key = tc::u32::Key::default();
+        key.mask = u32::from_ne_bytes(mask);
+        key.val = u32::from_ne_bytes(value) & key.mask;
+        key.off = offset;
+        key
+    };
+    match destination {
+        ipnetwork::IpNetwork::V4(network) => (
+            ETH_P_IP,
+            vec![key(
+                IPV4_DST_OFFSET,
+                network.mask().octets(),
+                network.ip().octets(),
+            )],
+        ),
+        ipnetwork::IpNetwork::V6(network) => {
+            let (mask, address) = (network.mask().octets(), network.ip().octets());
+            let keys = (0..4)
+                .map(|word| word * 4)
+                .filter(|start| mask[*start..*start + 4] != [0; 4])
+                .map(|start| {
+                    key(
+                        IPV6_DST_OFFSET + start as i32,
+                        mask[start..start + 4].try_into().unwrap(),
+                        address[start..start + 4].try_into().unwrap(),
+                    )
+                })
+                .collect();
+            (ETH_P_IPV6, keys)
+        }
+    }
+}
+
+// struct tc_htb_glob, with the defaults of tc.
+fn htb_init() -> Vec<u8> {
+    let version: u32 = 3;
+    let rate2quantum: u32 = 10;
+    let default_class: u32 = 0;
+    let debug: u32 = 0;
+    let direct_packets: u32 = 0;
+    [version, rate2quantum, default_class, debug, direct_packets]
+        .iter()
+        .flat_map(|field| field.to_ne_bytes())
+        .collect()
+}
+
+// struct tc_htb_opt. The link layer is given, so that the kernel needs no
+// rate tables.
+fn htb_class_options(rate: &HtbRate) -> Vec<u8> {
+    let ceil = rate.ceil.max(rate.rate);
+    let burst = rate
+        .burst
+        .unwrap_or(rate.rate / 1_000_000_000 + DEFAULT_BURST_BYTES);
+    let cburst = rate
+        .cburst
+        .unwrap_or(ceil / 1_000_000_000 + DEFAULT_BURST_BYTES);
+
+    let mut options = Vec::with_capacity(44);
+    options.extend(ratespec(rate.rate));
+    options.extend(ratespec(ceil));
+    options.extend(transmit_ticks(rate.rate, burst).to_ne_bytes());
+    options.extend(transmit_ticks(ceil, cburst).to_ne_bytes());
+    // The quantum and level are left to the kernel.
+    options.extend([0u8; 8]);
+    options.extend(rate.prio.to_ne_bytes());
+    options
+}
+
+// struct tc_ratespec, for rates below 4GB/s.
+fn ratespec(rate: u64) -> Vec<u8> {
+    let mut spec = Vec::with_capacity(12);
+    // Cell log, link layer, overhead, cell align and mpu.
+    spec.push(0u8);
+    spec.push(TC_LINKLAYER_ETHERNET);
+    spec.extend(0u16.to_ne_bytes());
+    spec.extend((-1i16).to_ne_bytes());
+    spec.extend(0u16.to_ne_bytes());
+    spec.extend((rate.min(u32::MAX as u64) as u32).to_ne_bytes());
+    spec
+}
+
+// The ticks taken to transmit the given bytes at the rate.
+fn transmit_ticks(rate: u64, bytes: u64) -> u32 {
+    let nanoseconds = bytes * 1_000_000_000 / rate.max(1);
+    (nanoseconds / PSCHED_TICK_NS).min(u32::MAX as u64) as u32
+}
+
+// struct tc_sfq_qopt_v1 for `sfq perturb 30 headdrop probability 0.5
+// redflowlimit 20000 ecn harddrop`, with the red thresholds and weights
+// derived from them as tc derives them.
+fn sfq_red_options() -> Vec<u8> {
+    let perturb_period: i32 = 30;
+    let headdrop: u32 = 1;
+    let flow_limit: u32 = 20000;
+    let qth_max = flow_limit / 4;
+    let qth_min = qth_max / 3;
+    let wlog: u8 = 1;
+    let plog: u8 = 13;
+    let max_p: u32 = 1 << 31;
+
+    let mut options = Vec::with_capacity(72);
+    // The quantum, then perturbation period, limit, divisor and flows,
+    // defaulted by the kernel where zero.
+    options.extend(0u32.to_ne_bytes());
+    options.extend(perturb_period.to_ne_bytes());
+    options.extend([0u8; 12]);
+    // The depth, also defaulted.
+    options.extend(0u32.to_ne_bytes());
+    for field in [headdrop, flow_limit, qth_min, qth_max] {
+        options.extend(field.to_ne_bytes());
+    }
+    options.extend([wlog, plog, 0, TC_RED_ECN | TC_RED_HARDDROP]);
+    options.extend(max_p.to_ne_bytes());
+    // Statistics, only filled in by the kernel.
+    options.extend([0u8; 24]);
+    options
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_options() {
+        assert_eq!(handle(0x9, 0x2001), 0x0009_2001);
+        assert_eq!(filter_info(1, ETH_P_IP), 0x0001_0008);
+
+        let (protocol, keys) = u32_dst_keys("10.45.0.7/32".parse().unwrap());
+        assert_eq!(protocol, ETH_P_IP);
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].val.to_ne_bytes(), [10, 45, 0, 7]);
+        assert_eq!(keys[0].off, 16);
+        // Only the words covered by the prefix are matched.
+        let (protocol, keys) = u32_dst_keys("2001:db8:0:1:5::/64".parse().unwrap());
+        assert_eq!(protocol, ETH_P_IPV6);
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].val.to_ne_bytes(), [0x20, 0x01, 0x0d, 0xb8]);
+        assert_eq!(keys[1].val.to_ne_bytes(), [0, 0, 0, 1]);
+        assert_eq!(keys[1].mask, u32::MAX);
+        assert_eq!(keys[1].off, 28);
+
+        let options = htb_class_options(&HtbRate {
+            rate: 12_500,
+            ceil: 1_000_000_000,
+            burst: None,
+            cburst: Some(131_072),
+            prio: 3,
+        });
+        assert_eq!(options.len(), 44);
+        assert_eq!(options[1], TC_LINKLAYER_ETHERNET);
+        assert_eq!(options[8..12], 12_500u32.to_ne_bytes());
+        assert_eq!(options[20..24], 1_000_000_000u32.to_ne_bytes());
+        // 1600 bytes take 128ms at 12.5kB/s, and 128KiB take 131us at 1GB/s.
+        assert_eq!(options[24..28], 2_000_000u32.to_ne_bytes());
+        assert_eq!(options[28..32], 2048u32.to_ne_bytes());
+        assert_eq!(options[40..44], 3u32.to_ne_bytes());
+
+        let options = sfq_red_options();
+        assert_eq!(options.len(), 72);
+        assert_eq!(options[4..8], 30i32.to_ne_bytes());
+        assert_eq!(options[32..36], 1666u32.to_ne_bytes());
+        assert_eq!(options[36..40], 5000u32.to_ne_bytes());
+    }
+
+    #[test]
+    fn test_qdisc_stats() {
+        let mut basic = 45_000u64.to_ne_bytes().to_vec();
+        basic.extend(30u32.to_ne_bytes());
+        let mut queue = vec![0u8; 8];
+        queue.extend(7u32.to_ne_bytes());
+        queue.extend([0u8; 8]);
+        let nlas = vec![
+            tc::Nla::Kind(String::from("sfq")),
+            tc::Nla::Stats2(vec![
+                tc::Stats2::StatsBasic(basic),
+                tc::Stats2::StatsQueue(queue),
+            ]),
+        ];
+        assert_eq!(
+            qdisc_stats(&nlas),
+            QdiscStats {
+                bytes: 45_000,
+                packets: 30,
+                drops: 7,
+            }
+        );
+        assert_eq!(qdisc_stats(&[]), QdiscStats::default());
+    }
+}
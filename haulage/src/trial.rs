@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::packet_parser::AddressClaim;
+
+#[derive(Error, Debug)]
+pub enum TrialError {
+    #[error("Database operation failed: {0}")]
+    DatabaseError(#[from] sqlx::error::Error),
+    #[error("No access policy named {0}")]
+    UnknownPolicy(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // The access policy of devices with trial balance remaining.
+    pub policy: String,
+    // The access policy once the trial is used up or expires, e.g. a walled
+    // garden reaching only the registration portal.
+    pub exhausted_policy: String,
+    // The bytes granted to each device, once.
+    pub allowance: i64,
+    pub lifetime: std::time::Duration,
+}
+
+// Addresses found to belong to a subscriber are not looked up again for this
+// long, so that the traffic of known subscribers costs a query only rarely.
+const RECHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+// How often expired trials are ended.
+const EXPIRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+// Provisions unknown devices sending traffic from the subscriber subnets as
+// trial subscribers, with a one-time allowance under the trial policy, so
+// that they can get online at a hotspot before registering. Devices are
+// identified by the link layer address claiming their IP address when known,
+// e.g. on ethernet subscriber interfaces, and by their IP address otherwise.
+// Devices which already had a trial are provisioned without balance, leaving
+// them under the exhausted policy until registered.
+#[derive(Debug)]
+pub struct TrialProvisioner {
+    dispatch_channel: tokio::sync::mpsc::Sender<Message>,
+}
+impl TrialProvisioner {
+    pub fn new(
+        settings: Settings,
+        db_pool: std::sync::Arc<sqlx::PgPool>,
+        user_aggregator: tokio::sync::mpsc::Sender<crate::async_aggregator::Message>,
+        user_accounter: tokio::sync::mpsc::Sender<crate::accounter::Message>,
+        log: slog::Logger,
+    ) -> TrialProvisioner {
+        let (sender, receiver) = tokio::sync::mpsc::channel(64);
+        tokio::task::spawn(async move {
+            provision_trials(
+                receiver,
+                settings,
+                db_pool,
+                user_aggregator,
+                user_accounter,
+                log,
+            )
+            .await;
+        });
+        TrialProvisioner {
+            dispatch_channel: sender,
+        }
+    }
+    pub fn clone_input_channel(&self) -> tokio::sync::mpsc::Sender<Message> {
+        self.dispatch_channel.clone()
+    }
+}
+
+pub enum Message {
+    // Addresses in the subscriber subnets which sent traffic.
+    Senders(Vec<std::net::IpAddr>),
+    Claims(Vec<AddressClaim>),
+}
+
+// A trial granted to a device, without balance if the device already had one.
+#[derive(Debug, Clone, PartialEq)]
+struct Grant {
+    imsi: String,
+    allowance: i64,
+}
+
+// When each address was last looked up, so that each is only looked up once
+// per interval however much traffic it sends.
+#[derive(Debug, Default)]
+struct Checked {
+    addresses: HashMap<std::net::IpAddr, std::time::Instant>,
+}
+impl Checked {
+    // Whether the address is due a lookup, marking it looked up if so.
+    fn due(&mut self, ip: std::net::IpAddr, now: std::time::Instant) -> bool {
+        match self.addresses.get(&ip) {
+            Some(checked) if now.duration_since(*checked) < RECHECK_INTERVAL => false,
+            _ => {
+                self.addresses.insert(ip, now);
+                true
+            }
+        }
+    }
+
+    fn expire(&mut self, now: std::time::Instant) {
+        self.addresses
+            .retain(|_, checked| now.duration_since(*checked) < RECHECK_INTERVAL);
+    }
+}
+
+async fn provision_trials(
+    mut chan: tokio::sync::mpsc::Receiver<Message>,
+    settings: Settings,
+    db_pool: std::sync::Arc<sqlx::PgPool>,
+    user_aggregator: tokio::sync::mpsc::Sender<crate::async_aggregator::Message>,
+    user_accounter: tokio::sync::mpsc::Sender<crate::accounter::Message>,
+    log: slog::Logger,
+) {
+    let mut checked = Checked::default();
+    let mut macs: HashMap<std::net::IpAddr, pnet_datalink::MacAddr> = HashMap::new();
+    let mut timer = tokio::time::interval(EXPIRY_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = timer.tick() => {
+                checked.expire(std::time::Instant::now());
+                match expire_trials(&db_pool, &settings).await {
+                    Ok(expired) => {
+                        for imsi in expired {
+                            slog::info!(log, "Trial expired"; "imsi" => imsi);
+                        }
+                    }
+                    Err(e) => slog::error!(log, "Failed to expire trials"; "error" => e.to_string()),
+                }
+            }
+            message = chan.recv() => {
+                match message {
+                    Some(Message::Claims(claims)) => {
+                        for claim in claims {
+                            macs.insert(claim.ip, claim.mac);
+                        }
+                    }
+                    Some(Message::Senders(senders)) => {
+                        let now = std::time::Instant::now();
+                        for ip in senders {
+                            if !checked.due(ip, now) {
+                                continue;
+                            }
+                            let grant = match provision_trial(&db_pool, &settings, ip, macs.get(&ip).copied()).await {
+                                Ok(Some(grant)) => grant,
+                                Ok(None) => continue,
+                                Err(e) => {
+                                    slog::error!(log, "Failed to provision trial"; "ip" => ip.to_string(), "error" => e.to_string());
+                                    continue;
+                                }
+                            };
+                            slog::info!(log, "Provisioned trial subscriber"; "ip" => ip.to_string(), "imsi" => &grant.imsi, "allowance" => grant.allowance);
+
+                            // Workers which started for the address before it
+                            // was provisioned found no subscriber, and are
+                            // replaced with the next report.
+                            user_aggregator
+                                .send(crate::async_aggregator::Message::Forget { id: ip })
+                                .await
+                                .unwrap_or_else(|e| slog::error!(log, "Failed to send to aggregator"; "error" => e.to_string()));
+                            user_accounter
+                                .send(crate::accounter::Message::Forget { ip })
+                                .await
+                                .unwrap_or_else(|e| slog::error!(log, "Failed to send to accounter"; "error" => e.to_string()));
+                        }
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+}
+
+// Provisions a trial subscriber for the address if no subscriber has it,
+// granting the allowance unless the device already had a trial.
+async fn provision_trial(
+    db_pool: &sqlx::PgPool,
+    settings: &Settings,
+    ip: std::net::IpAddr,
+    mac: Option<pnet_datalink::MacAddr>,
+) -> Result<Option<Grant>, TrialError> {
+    let mut transaction = db_pool.begin().await?;
+
+    let subscriber_query = r#"
+        SELECT "internal_uid"
+        FROM static_ips
+        INNER JOIN subscribers ON subscribers.imsi = static_ips.imsi
+        WHERE static_ips.ip >>= $1
+    "#;
+    let existing: Option<(i32,)> = sqlx::query_as(subscriber_query)
+        .bind(ipnetwork::IpNetwork::from(ip))
+        .fetch_optional(&mut transaction)
+        .await?;
+    if existing.is_some() {
+        return Ok(None);
+    }
+
+    let policy = query_policy_id(&mut transaction, &settings.policy).await?;
+    let exhausted_policy = query_policy_id(&mut transaction, &settings.exhausted_policy).await?;
+
+    // Devices with a known link layer address are recognized by it even at
+    // a new IP address.
+    let previous_query = r#"
+        SELECT EXISTS (
+            SELECT 1 FROM trial_grants
+            WHERE CASE WHEN $2::MACADDR IS NULL THEN "ip" = $1 ELSE "mac" = $2::MACADDR END
+        )
+    "#;
+    let (previous,): (bool,) = sqlx::query_as(previous_query)
+        .bind(ipnetwork::IpNetwork::from(ip))
+        .bind(mac.map(|mac| mac.to_string()))
+        .fetch_one(&mut transaction)
+        .await?;
+    let allowance = match previous {
+        true => 0,
+        false => settings.allowance,
+    };
+    let expires = chrono::Utc::now()
+        + chrono::Duration::from_std(settings.lifetime)
+            .unwrap_or_else(|_| chrono::Duration::zero());
+
+    let grant_query = r#"
+        INSERT INTO trial_grants("ip", "mac", "allowance", "expires")
+        VALUES ($1, $2::MACADDR, $3, $4)
+        RETURNING "id"
+    "#;
+    let (grant_id,): (i32,) = sqlx::query_as(grant_query)
+        .bind(ipnetwork::IpNetwork::from(ip))
+        .bind(mac.map(|mac| mac.to_string()))
+        .bind(allowance)
+        .bind(expires)
+        .fetch_one(&mut transaction)
+        .await?;
+    let imsi = trial_imsi(grant_id);
+
+    // The current policy is left for the enforcer to bring up to date.
+    let subscriber_query = r#"
+        INSERT INTO subscribers("imsi", "data_balance", "bridged", "positive_balance_policy", "zero_balance_policy", "current_policy")
+        VALUES ($1, $2, false, $3, $4, $4)
+        RETURNING "internal_uid"
+    "#;
+    let (subscriber,): (i32,) = sqlx::query_as(subscriber_query)
+        .bind(&imsi)
+        .bind(allowance)
+        .bind(policy)
+        .bind(exhausted_policy)
+        .fetch_one(&mut transaction)
+        .await?;
+    sqlx::query(r#"INSERT INTO static_ips("ip", "imsi") VALUES ($1, $2)"#)
+        .bind(ipnetwork::IpNetwork::from(ip))
+        .bind(&imsi)
+        .execute(&mut transaction)
+        .await?;
+    sqlx::query(r#"UPDATE trial_grants SET "imsi" = $1 WHERE "id" = $2"#)
+        .bind(&imsi)
+        .bind(grant_id)
+        .execute(&mut transaction)
+        .await?;
+
+    crate::events::record_event(
+        &mut transaction,
+        subscriber,
+        crate::events::EventKind::TrialGranted,
+        serde_json::json!({
+            "ip": ip,
+            "mac": mac.map(|mac| mac.to_string()),
+            "allowance": allowance,
+            "expires": expires,
+        }),
+    )
+    .await?;
+
+    // Delivered once the subscriber commits.
+    sqlx::query("SELECT pg_notify($1, $2)")
+        .bind(crate::enforcer::APPLY_POLICY_CHANNEL)
+        .bind(&imsi)
+        .execute(&mut transaction)
+        .await?;
+
+    transaction.commit().await?;
+    Ok(Some(Grant { imsi, allowance }))
+}
+
+// Trial subscribers are named for their grant, within the 16 characters of an
+// IMSI, and cannot collide with real IMSIs, which are all digits.
+fn trial_imsi(grant_id: i32) -> String {
+    format!("trial{:011}", grant_id)
+}
+
+// Ends the trials which expired with balance remaining, returning the IMSIs of
+// their subscribers. Subscribers registered since, i.e. moved off the trial
+// policy, keep their balance.
+async fn expire_trials(
+    db_pool: &sqlx::PgPool,
+    settings: &Settings,
+) -> Result<Vec<String>, TrialError> {
+    let mut transaction = db_pool.begin().await?;
+    let policy = query_policy_id(&mut transaction, &settings.policy).await?;
+
+    let expire_query = r#"
+        WITH expired AS (
+            UPDATE trial_grants
+            SET "ended" = true
+            WHERE NOT "ended" AND "expires" <= now()
+            RETURNING "imsi"
+        )
+        UPDATE subscribers
+        SET "data_balance" = 0
+        FROM expired
+        WHERE subscribers."imsi" = expired."imsi"
+            AND subscribers."positive_balance_policy" = $1
+            AND subscribers."data_balance" > 0
+        RETURNING subscribers."internal_uid", subscribers."imsi"
+    "#;
+    let expired: Vec<(i32, String)> = sqlx::query_as(expire_query)
+        .bind(policy)
+        .fetch_all(&mut transaction)
+        .await?;
+
+    for (subscriber, imsi) in &expired {
+        crate::events::record_event(
+            &mut transaction,
+            *subscriber,
+            crate::events::EventKind::TrialExpired,
+            serde_json::json!({}),
+        )
+        .await?;
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(crate::enforcer::APPLY_POLICY_CHANNEL)
+            .bind(imsi)
+            .execute(&mut transaction)
+            .await?;
+    }
+
+    transaction.commit().await?;
+    Ok(expired.into_iter().map(|(_, imsi)| imsi).collect())
+}
+
+async fn query_policy_id(
+    connection: &mut sqlx::PgConnection,
+    name: &str,
+) -> Result<i32, TrialError> {
+    let id: Option<(i32,)> =
+        sqlx::query_as(r#"SELECT "id" FROM access_policies WHERE "name" = $1"#)
+            .bind(name)
+            .fetch_optional(connection)
+            .await?;
+    id.map(|(id,)| id)
+        .ok_or_else(|| TrialError::UnknownPolicy(name.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_addresses_checked_once_per_interval() {
+        let ip: std::net::IpAddr = "10.45.0.9".parse().unwrap();
+        let now = std::time::Instant::now();
+        let mut checked = Checked::default();
+
+        assert!(checked.due(ip, now));
+        assert!(!checked.due(ip, now + std::time::Duration::from_secs(60)));
+        assert!(checked.due(ip, now + RECHECK_INTERVAL));
+
+        checked.expire(now + RECHECK_INTERVAL * 2);
+        assert!(checked.addresses.is_empty());
+        assert_eq!(trial_imsi(42), "trial00000000042");
+    }
+}
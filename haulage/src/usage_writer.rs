@@ -0,0 +1,453 @@
+use std::collections::HashMap;
+
+use crate::reporter::UseRecord;
+
+// Records are written immediately once this many are pending, regardless of
+// the flush interval.
+const FLUSH_THRESHOLD: usize = 5000;
+
+// Records from failed flushes are retried on the next flush, but are spilled
+// to disk, or dropped without a spill file, if the database is unavailable for
+// long enough to exceed this bound.
+const MAX_PENDING: usize = 100_000;
+
+// Failed flushes are retried after a delay doubling up to this bound, rather
+// than with every message received.
+const MIN_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+const MAX_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(30);
+
+// Collects the interval usage records produced by all aggregation workers and
+// writes them in bulk with a single transaction per flush, rather than a
+// transaction per record. Balance debits from the accounting workers are applied in the
+// same transaction, so that a crash cannot record usage without charging it or
+// charge usage without recording it.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub flush_interval: std::time::Duration,
+    // How many destinations are kept per interval.
+    pub destination_count: usize,
+    // Where records are spilled while the database is unavailable, rather
+    // than dropped once too many are pending.
+    pub spill_path: Option<std::path::PathBuf>,
+}
+
+#[derive(Debug)]
+pub struct UsageWriter {
+    dispatch_channel: tokio::sync::mpsc::Sender<Message>,
+}
+impl UsageWriter {
+    pub fn new(
+        settings: Settings,
+        storage: std::sync::Arc<dyn crate::storage::Storage>,
+        syslog: Option<tokio::sync::mpsc::Sender<crate::syslog::Message>>,
+        stats: std::sync::Arc<crate::stats::Stats>,
+        log: slog::Logger,
+    ) -> UsageWriter {
+        let (sender, receiver) = tokio::sync::mpsc::channel(256);
+        tokio::task::spawn(async move {
+            write_records(receiver, settings, storage, syslog, stats, log).await;
+        });
+        UsageWriter {
+            dispatch_channel: sender,
+        }
+    }
+    pub fn clone_input_channel(&self) -> tokio::sync::mpsc::Sender<Message> {
+        self.dispatch_channel.clone()
+    }
+}
+
+#[derive(Debug)]
+pub enum Message {
+    Record {
+        subscriber: i32,
+        record: UseRecord,
+    },
+    // Decrements the subscriber's data balance, replying with the result of
+    // the write or None if the write failed. Debits are normally
+    // written with the next flush, but immediate debits force a flush, e.g.
+    // when a subscriber is about to run out of balance.
+    Debit {
+        subscriber: i32,
+        bytes: i64,
+        immediate: bool,
+        out_channel: tokio::sync::oneshot::Sender<Option<DebitResult>>,
+    },
+    // The traffic exchanged with a remote host, of which only the top
+    // destinations of each interval are kept.
+    Destination {
+        destination: std::net::IpAddr,
+        record: UseRecord,
+    },
+    // Writes out everything pending and stops, replying once done, e.g.
+    // before haulage exits. Records which cannot be written are spilled.
+    Flush {
+        out_channel: tokio::sync::oneshot::Sender<()>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DebitResult {
+    pub balance: i64,
+    // Whether the debit was waived because the subscriber is exempt.
+    pub exempt: bool,
+}
+
+#[derive(Debug, Default)]
+struct PendingDebit {
+    bytes: i64,
+    out_channels: Vec<tokio::sync::oneshot::Sender<Option<DebitResult>>>,
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    records: Vec<(i32, UseRecord)>,
+    debits: HashMap<i32, PendingDebit>,
+    destinations: Vec<(std::net::IpAddr, UseRecord)>,
+}
+impl Pending {
+    fn is_empty(&self) -> bool {
+        self.records.is_empty() && self.debits.is_empty() && self.destinations.is_empty()
+    }
+}
+
+// Usage records written to disk while the database is unavailable, as one
+// JSON line per record, and loaded again to be retried once it is back.
+#[derive(Debug)]
+struct Spill {
+    path: std::path::PathBuf,
+    // Whether the file's records are currently pending, so that the file is
+    // replaced rather than appended to if they fail again.
+    loaded: bool,
+}
+impl Spill {
+    fn new(path: std::path::PathBuf) -> Spill {
+        Spill {
+            path,
+            loaded: false,
+        }
+    }
+
+    fn write(&mut self, records: &[(i32, UseRecord)]) -> std::io::Result<()> {
+        let mut encoded = String::new();
+        for record in records {
+            encoded.push_str(&serde_json::to_string(record)?);
+            encoded.push('\n');
+        }
+        match self.loaded {
+            true => {
+                let partial = self.path.with_extension("partial");
+                std::fs::write(&partial, encoded)?;
+                std::fs::rename(&partial, &self.path)?;
+            }
+            false => {
+                let mut file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?;
+                std::io::Write::write_all(&mut file, encoded.as_bytes())?;
+                file.sync_all()?;
+            }
+        }
+        self.loaded = false;
+        Ok(())
+    }
+
+    // Returns the spilled records if they are not already pending.
+    fn load(&mut self) -> std::io::Result<Vec<(i32, UseRecord)>> {
+        if self.loaded || !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let mut records = Vec::new();
+        for line in std::fs::read_to_string(&self.path)?.lines() {
+            records.push(serde_json::from_str(line)?);
+        }
+        self.loaded = true;
+        Ok(records)
+    }
+
+    // Removes the file once its records are written to the database.
+    fn written(&mut self) -> std::io::Result<()> {
+        if self.loaded {
+            std::fs::remove_file(&self.path)?;
+            self.loaded = false;
+        }
+        Ok(())
+    }
+}
+
+async fn write_records(
+    mut chan: tokio::sync::mpsc::Receiver<Message>,
+    settings: Settings,
+    storage: std::sync::Arc<dyn crate::storage::Storage>,
+    syslog: Option<tokio::sync::mpsc::Sender<crate::syslog::Message>>,
+    stats: std::sync::Arc<crate::stats::Stats>,
+    log: slog::Logger,
+) {
+    let Settings {
+        flush_interval,
+        destination_count,
+        spill_path,
+    } = settings;
+    let mut spill = spill_path.map(Spill::new);
+    let mut pending = Pending::default();
+    let mut flushed = None;
+    let mut timer =
+        tokio::time::interval_at(tokio::time::Instant::now() + flush_interval, flush_interval);
+    let mut retry_delay = MIN_RETRY_DELAY;
+    let mut retry_at: Option<tokio::time::Instant> = None;
+    loop {
+        let flush_now = tokio::select! {
+            _ = timer.tick() => {
+                // Spilled records, e.g. from before a restart, are retried
+                // along with the next flush.
+                let backing_off = retry_at.is_some_and(|at| at > tokio::time::Instant::now());
+                if let (Some(spill), false) = (spill.as_mut(), backing_off) {
+                    match spill.load() {
+                        Ok(records) if !records.is_empty() => {
+                            slog::info!(log, "Loaded spilled usage records"; "records" => records.len());
+                            pending.records.extend(records);
+                        }
+                        Ok(_) => {}
+                        Err(e) => slog::error!(log, "Failed to load spilled usage records"; "error" => e.to_string()),
+                    }
+                }
+                true
+            }
+            message = chan.recv() => {
+                match message {
+                    Some(Message::Record { subscriber, record }) => {
+                        pending.records.push((subscriber, record));
+                        pending.records.len() >= FLUSH_THRESHOLD
+                    }
+                    Some(Message::Debit { subscriber, bytes, immediate, out_channel }) => {
+                        let debit = pending.debits.entry(subscriber).or_default();
+                        debit.bytes += bytes;
+                        debit.out_channels.push(out_channel);
+                        immediate
+                    }
+                    Some(Message::Destination { destination, record }) => {
+                        pending.destinations.push((destination, record));
+                        pending.destinations.len() >= FLUSH_THRESHOLD
+                    }
+                    Some(Message::Flush { out_channel }) => {
+                        flushed = Some(out_channel);
+                        break;
+                    }
+                    None => break,
+                }
+            }
+        };
+
+        let due = retry_at.is_none_or(|at| at <= tokio::time::Instant::now());
+        if flush_now && due && !pending.is_empty() {
+            let written = flush(
+                storage.as_ref(),
+                &mut pending,
+                destination_count,
+                syslog.as_ref(),
+                &stats,
+                &log,
+            )
+            .await;
+            if written {
+                retry_delay = MIN_RETRY_DELAY;
+                retry_at = None;
+                if let Some(Err(e)) = spill.as_mut().map(Spill::written) {
+                    slog::error!(log, "Failed to remove written spill file"; "error" => e.to_string());
+                }
+            } else {
+                retry_at = Some(tokio::time::Instant::now() + retry_delay);
+                retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+                if pending.records.len() > MAX_PENDING {
+                    spill_records(spill.as_mut(), &mut pending, &stats, &log);
+                }
+            }
+        }
+    }
+
+    // Write out any remaining records before exiting, spilling them if the
+    // database is still unavailable.
+    if !pending.is_empty() {
+        let written = flush(
+            storage.as_ref(),
+            &mut pending,
+            destination_count,
+            syslog.as_ref(),
+            &stats,
+            &log,
+        )
+        .await;
+        if written {
+            if let Some(Err(e)) = spill.as_mut().map(Spill::written) {
+                slog::error!(log, "Failed to remove written spill file"; "error" => e.to_string());
+            }
+        } else if !pending.records.is_empty() {
+            spill_records(spill.as_mut(), &mut pending, &stats, &log);
+        }
+    }
+    if let Some(out_channel) = flushed {
+        let _ = out_channel.send(());
+    }
+}
+
+// Moves the pending records to the spill file, or drops them if there is
+// none or it cannot be written.
+fn spill_records(
+    spill: Option<&mut Spill>,
+    pending: &mut Pending,
+    stats: &crate::stats::Stats,
+    log: &slog::Logger,
+) {
+    let result = match spill {
+        Some(spill) => spill.write(&pending.records).map(|_| spill.path.clone()),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "no spill file configured",
+        )),
+    };
+    match result {
+        Ok(path) => {
+            slog::warn!(log, "Spilled unwritten usage records"; "records" => pending.records.len(), "path" => path.display().to_string());
+        }
+        Err(e) => {
+            slog::error!(log, "Dropping unwritten usage records"; "dropped" => pending.records.len(), "error" => e.to_string());
+            stats.usage_record_errors.add(pending.records.len() as u64);
+        }
+    }
+    pending.records.clear();
+}
+
+async fn flush(
+    storage: &dyn crate::storage::Storage,
+    pending: &mut Pending,
+    destination_count: usize,
+    syslog: Option<&tokio::sync::mpsc::Sender<crate::syslog::Message>>,
+    stats: &crate::stats::Stats,
+    log: &slog::Logger,
+) -> bool {
+    let debits: HashMap<i32, i64> = pending
+        .debits
+        .iter()
+        .map(|(subscriber, debit)| (*subscriber, debit.bytes))
+        .collect();
+    let started = std::time::Instant::now();
+    let result = storage
+        .write_usage(crate::storage::UsageBatch {
+            records: &pending.records,
+            debits: &debits,
+            destinations: &top_destinations(&pending.destinations, destination_count),
+            destination_count,
+        })
+        .await;
+
+    match result {
+        Ok((rows, balances)) => {
+            slog::debug!(log, "Wrote usage records"; "rows" => rows, "debits" => balances.len());
+            stats.record_db_write_latency(started.elapsed());
+            stats.usage_flushes.increment();
+            stats.usage_records_written.add(rows);
+            match syslog {
+                // Written records are forwarded without blocking on the
+                // syslog server, and dropped if it falls behind.
+                Some(syslog) => {
+                    for (subscriber, record) in pending.records.drain(..) {
+                        if syslog
+                            .try_send(crate::syslog::Message::Usage { subscriber, record })
+                            .is_err()
+                        {
+                            stats.syslog_send_errors.increment();
+                        }
+                    }
+                }
+                None => pending.records.clear(),
+            }
+            pending.destinations.clear();
+            for (subscriber, debit) in pending.debits.drain() {
+                let balance = balances.get(&subscriber).copied();
+                if balance.is_none() {
+                    slog::warn!(log, "Debited subscriber not found"; "subscriber" => subscriber);
+                }
+                for out_channel in debit.out_channels {
+                    // The requesting worker may have shut down.
+                    let _ = out_channel.send(balance);
+                }
+            }
+            true
+        }
+        Err(e) => {
+            slog::warn!(log, "Failed to write usage records"; "pending" => pending.records.len(), "debits" => pending.debits.len(), "error" => e.to_string());
+            // Debits are retried with the next flush, but the requesters are
+            // told this attempt failed.
+            for debit in pending.debits.values_mut() {
+                for out_channel in debit.out_channels.drain(..) {
+                    let _ = out_channel.send(None);
+                }
+            }
+            if pending.destinations.len() > MAX_PENDING {
+                slog::error!(log, "Dropping unwritten destination records"; "dropped" => pending.destinations.len());
+                pending.destinations.clear();
+            }
+            false
+        }
+    }
+}
+
+// The records ranking among the top count of those ending at the same time,
+// by total bytes.
+fn top_destinations(
+    destinations: &[(std::net::IpAddr, UseRecord)],
+    count: usize,
+) -> Vec<&(std::net::IpAddr, UseRecord)> {
+    let mut intervals: HashMap<chrono::DateTime<chrono::Utc>, Vec<&(std::net::IpAddr, UseRecord)>> =
+        HashMap::new();
+    for destination in destinations {
+        intervals
+            .entry(destination.1.end)
+            .or_default()
+            .push(destination);
+    }
+    let mut top = Vec::new();
+    for (_, mut records) in intervals {
+        records.sort_by_key(|(_, record)| {
+            std::cmp::Reverse(record.usage.wan_bytes_up + record.usage.wan_bytes_down)
+        });
+        records.truncate(count);
+        top.extend(records);
+    }
+    top
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_top_destinations() {
+        let start = chrono::Utc.ymd(2022, 5, 13).and_hms(23, 0, 0);
+        let record = |minutes: i64, bytes: i64| UseRecord {
+            start,
+            end: start + chrono::Duration::minutes(minutes),
+            usage: crate::NetResourceBundle {
+                wan_bytes_down: bytes,
+                ..crate::NetResourceBundle::zeroed()
+            },
+        };
+        let destinations = vec![
+            ("192.0.2.1".parse().unwrap(), record(60, 10)),
+            ("192.0.2.2".parse().unwrap(), record(60, 30)),
+            ("192.0.2.3".parse().unwrap(), record(60, 20)),
+            ("192.0.2.1".parse().unwrap(), record(120, 5)),
+        ];
+
+        // Each interval is ranked separately.
+        let mut top: Vec<String> = top_destinations(&destinations, 2)
+            .iter()
+            .map(|(destination, record)| {
+                format!("{} {}", destination, (record.end - start).num_minutes())
+            })
+            .collect();
+        top.sort();
+        assert_eq!(top, vec!["192.0.2.1 120", "192.0.2.2 60", "192.0.2.3 60"]);
+    }
+}
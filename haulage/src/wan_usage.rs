@@ -0,0 +1,149 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum WanUsageError {
+    #[error("Database operation failed: {0}")]
+    DatabaseError(#[from] sqlx::error::Error),
+}
+
+// The byte counters of one upstream interface. Traffic sent upstream is
+// subscriber uplink, and traffic received is subscriber downlink.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Counters {
+    rx_bytes: u64,
+    tx_bytes: u64,
+}
+
+impl Counters {
+    // Counters restart from zero when an interface is recreated, e.g. when an
+    // LTE modem reconnects, in which case everything counted since is new.
+    fn since(&self, previous: &Counters) -> Counters {
+        let delta = |current: u64, previous: u64| match current >= previous {
+            true => current - previous,
+            false => current,
+        };
+        Counters {
+            rx_bytes: delta(self.rx_bytes, previous.rx_bytes),
+            tx_bytes: delta(self.tx_bytes, previous.tx_bytes),
+        }
+    }
+}
+
+// Records the traffic carried by each upstream interface on the same schedule
+// as subscriber usage. Interfaces whose counters cannot be read, e.g. while a
+// backhaul is down, are skipped until they can be again.
+pub async fn record(
+    interfaces: Vec<crate::netns::Interface>,
+    schedule: crate::clock::Schedule,
+    db_pool: std::sync::Arc<sqlx::PgPool>,
+    log: slog::Logger,
+) {
+    let mut start = chrono::Utc::now();
+    let mut previous = Vec::new();
+    for interface in &interfaces {
+        previous.push(read_counters(interface).await);
+    }
+
+    let mut timer = tokio::time::interval_at(
+        tokio::time::Instant::now() + schedule.first_delay(start),
+        schedule.period,
+    );
+    loop {
+        timer.tick().await;
+        let end = schedule.boundary(chrono::Utc::now());
+        for (interface, previous) in interfaces.iter().zip(previous.iter_mut()) {
+            let current = read_counters(interface).await;
+            let usage = match (current, *previous) {
+                (Some(current), Some(previous)) => current.since(&previous),
+                (None, _) => {
+                    slog::warn!(log, "Unable to read upstream interface counters"; "interface" => interface.to_string());
+                    *previous = None;
+                    continue;
+                }
+                (Some(_), None) => {
+                    *previous = current;
+                    continue;
+                }
+            };
+            *previous = current;
+
+            record_usage(&db_pool, &interface.to_string(), start, end, &usage)
+                .await
+                .unwrap_or_else(|e| slog::error!(log, "Failed to record upstream interface usage"; "interface" => interface.to_string(), "error" => e.to_string()));
+        }
+        start = end;
+    }
+}
+
+async fn read_counters(interface: &crate::netns::Interface) -> Option<Counters> {
+    let mut counters = [0; 2];
+    for (counter, name) in counters.iter_mut().zip(["rx_bytes", "tx_bytes"]) {
+        // Reading within the namespace sees the namespace's own sysfs.
+        let output = interface
+            .command("cat")
+            .arg(format!(
+                "/sys/class/net/{}/statistics/{}",
+                interface.name, name
+            ))
+            .output()
+            .await
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        *counter = String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse()
+            .ok()?;
+    }
+    Some(Counters {
+        rx_bytes: counters[0],
+        tx_bytes: counters[1],
+    })
+}
+
+async fn record_usage(
+    db_pool: &sqlx::PgPool,
+    interface: &str,
+    start: chrono::DateTime<chrono::Utc>,
+    end: chrono::DateTime<chrono::Utc>,
+    usage: &Counters,
+) -> Result<(), WanUsageError> {
+    let insert_query = r#"
+        INSERT INTO wan_usage("interface", "start_time", "end_time", "bytes_up", "bytes_down")
+        VALUES ($1, $2, $3, $4, $5)
+    "#;
+    sqlx::query(insert_query)
+        .bind(interface)
+        .bind(start)
+        .bind(end)
+        .bind(usage.tx_bytes as i64)
+        .bind(usage.rx_bytes as i64)
+        .execute(db_pool)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters_survive_interface_recreation() {
+        let previous = Counters {
+            rx_bytes: 1000,
+            tx_bytes: 500,
+        };
+        let current = Counters {
+            rx_bytes: 1800,
+            tx_bytes: 200,
+        };
+        assert_eq!(
+            current.since(&previous),
+            Counters {
+                rx_bytes: 800,
+                tx_bytes: 200,
+            }
+        );
+    }
+}
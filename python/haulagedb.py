@@ -32,9 +32,14 @@ if command == "help":
 
 with open("/etc/haulage/config.yml") as f:
     conf = yaml.load(f, Loader=yaml.SafeLoader)
-    dbname = conf["custom"]["dbLocation"]
-    db_user = conf["custom"]["dbUser"]
-    db_pass = conf["custom"]["dbPass"]
+    if conf.get("version", 1) == 2:
+        dbname = conf["database"]["name"]
+        db_user = conf["database"]["user"]
+        db_pass = conf["database"]["pass"]
+    else:
+        dbname = conf["custom"]["dbLocation"]
+        db_user = conf["custom"]["dbUser"]
+        db_pass = conf["custom"]["dbPass"]
 
 db = psycopg2.connect(host="127.0.0.1", user=db_user, password=db_pass, dbname=dbname)
 cursor = db.cursor()